@@ -240,7 +240,8 @@ pub fn create_host_mmaps(
         );
     } else if mem_config.mem_share {
         let file_len = ranges.iter().fold(0, |acc, x| acc + x.1);
-        let anon_mem_name = String::from("stratovirt_anon_mem");
+        // memfd_create takes a NUL-terminated name.
+        let anon_mem_name = std::ffi::CString::new("stratovirt_anon_mem").unwrap();
 
         let anon_fd =
             unsafe { libc::syscall(libc::SYS_memfd_create, anon_mem_name.as_ptr(), 0) } as RawFd;
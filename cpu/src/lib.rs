@@ -185,6 +185,33 @@ impl CPU {
         self.arch_cpu.lock().unwrap().set(&self.boot_state);
     }
 
+    /// Rewind this `CPU` to the state right before boot and apply a new boot
+    /// configuration, the `CPU` must not be running.
+    ///
+    /// # Arguments
+    ///
+    /// * `boot` - Boot message from boot_loader.
+    pub fn reset_to_boot_state(&self, boot: &CPUBootConfig) -> Result<()> {
+        self.set_to_boot_state();
+
+        let mut arch_cpu = self.arch_cpu.lock().unwrap();
+        // `set_boot_config` reads the registers back from kvm, so the boot
+        // state has to be written before applying the boot configuration.
+        arch_cpu
+            .reset_vcpu(&self.fd)
+            .with_context(|| format!("Failed to rewind registers of CPU {}", self.id))?;
+        arch_cpu
+            .set_boot_config(&self.fd, boot)
+            .with_context(|| format!("Failed to set boot config of CPU {}", self.id))?;
+        arch_cpu
+            .reset_vcpu(&self.fd)
+            .with_context(|| format!("Failed to reset registers of CPU {}", self.id))?;
+        drop(arch_cpu);
+
+        self.boot_state.lock().unwrap().set(&self.arch_cpu);
+        Ok(())
+    }
+
     /// Get this `CPU`'s ID.
     pub fn id(&self) -> u8 {
         self.id
@@ -415,6 +442,9 @@ impl CPUInterface for CPU {
 
     fn guest_reset(&self) -> Result<()> {
         if let Some(vm) = self.vm.upgrade() {
+            // Park this vcpu until the machine has been reset and resumes it.
+            let (cpu_state, _) = &*self.state;
+            *cpu_state.lock().unwrap() = CpuLifecycleState::Paused;
             vm.lock().unwrap().reset();
         } else {
             return Err(anyhow!(CpuError::NoMachineInterface));
@@ -14,8 +14,12 @@
 use std::mem::size_of;
 
 use kvm_bindings::{
-    kvm_riscv_config, kvm_riscv_core, kvm_riscv_timer, user_regs_struct, KVM_REG_RISCV,
-    KVM_REG_RISCV_CONFIG, KVM_REG_RISCV_CORE, KVM_REG_RISCV_TIMER, KVM_REG_SIZE_U64,
+    __riscv_d_ext_state, __riscv_f_ext_state, kvm_riscv_aia_csr, kvm_riscv_config, kvm_riscv_core,
+    kvm_riscv_csr, kvm_riscv_timer, user_regs_struct, KVM_REG_RISCV, KVM_REG_RISCV_CONFIG,
+    KVM_REG_RISCV_CORE, KVM_REG_RISCV_CSR, KVM_REG_RISCV_CSR_AIA, KVM_REG_RISCV_FP_D,
+    KVM_REG_RISCV_FP_F, KVM_REG_RISCV_ISA_EXT, KVM_REG_RISCV_ISA_SINGLE, KVM_REG_RISCV_SBI_EXT,
+    KVM_REG_RISCV_SBI_SINGLE, KVM_REG_RISCV_TIMER, KVM_REG_SIZE_U32, KVM_REG_SIZE_U64,
+    KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_MAX, KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_MAX,
 };
 use kvm_ioctls::VcpuFd;
 use util::offset_of;
@@ -23,6 +27,15 @@ use vmm_sys_util::errno;
 
 pub type Result<T> = std::result::Result<T, errno::Error>;
 
+/// Number of ISA extension registers saved for a vcpu.
+pub const RISCV_ISA_EXT_NR: usize = 32;
+/// Number of SBI extension registers saved for a vcpu.
+pub const RISCV_SBI_EXT_NR: usize = 16;
+/// Bit of the `D` extension in the isa config register.
+const RISCV_ISA_EXT_D: u64 = 1 << (b'd' - b'a');
+/// Bit of the `F` extension in the isa config register.
+const RISCV_ISA_EXT_F: u64 = 1 << (b'f' - b'a');
+
 /// RISCV cpu config register.
 /// See: https://elixir.bootlin.com/linux/v6.0/source/arch/riscv/include/uapi/asm/kvm.h#L49
 pub enum RISCVConfigRegs {
@@ -271,6 +284,181 @@ impl Into<u64> for RISCVTimerRegs {
     }
 }
 
+/// RISCV cpu supervisor-level csr register.
+/// See: https://elixir.bootlin.com/linux/v6.0/source/arch/riscv/include/uapi/asm/kvm.h#L64
+pub enum RISCVCsrRegs {
+    SSTATUS,
+    SIE,
+    STVEC,
+    SSCRATCH,
+    SEPC,
+    SCAUSE,
+    STVAL,
+    SIP,
+    SATP,
+    SCOUNTEREN,
+}
+
+impl Into<u64> for RISCVCsrRegs {
+    fn into(self) -> u64 {
+        let reg_offset = match self {
+            RISCVCsrRegs::SSTATUS => {
+                offset_of!(kvm_riscv_csr, sstatus)
+            }
+            RISCVCsrRegs::SIE => {
+                offset_of!(kvm_riscv_csr, sie)
+            }
+            RISCVCsrRegs::STVEC => {
+                offset_of!(kvm_riscv_csr, stvec)
+            }
+            RISCVCsrRegs::SSCRATCH => {
+                offset_of!(kvm_riscv_csr, sscratch)
+            }
+            RISCVCsrRegs::SEPC => {
+                offset_of!(kvm_riscv_csr, sepc)
+            }
+            RISCVCsrRegs::SCAUSE => {
+                offset_of!(kvm_riscv_csr, scause)
+            }
+            RISCVCsrRegs::STVAL => {
+                offset_of!(kvm_riscv_csr, stval)
+            }
+            RISCVCsrRegs::SIP => {
+                offset_of!(kvm_riscv_csr, sip)
+            }
+            RISCVCsrRegs::SATP => {
+                offset_of!(kvm_riscv_csr, satp)
+            }
+            RISCVCsrRegs::SCOUNTEREN => {
+                offset_of!(kvm_riscv_csr, scounteren)
+            }
+        };
+
+        // calculate reg_id
+        KVM_REG_RISCV as u64
+            | KVM_REG_SIZE_U64 as u64
+            | u64::from(KVM_REG_RISCV_CSR)
+            | (reg_offset / size_of::<u64>()) as u64
+    }
+}
+
+/// RISCV cpu AIA csr register, only present with the `Ssaia` extension.
+/// See: https://elixir.bootlin.com/linux/v6.6/source/arch/riscv/include/uapi/asm/kvm.h#L92
+pub enum RISCVAiaCsrRegs {
+    SISELECT,
+    IPRIO1,
+    IPRIO2,
+    SIEH,
+    SIPH,
+    IPRIO1H,
+    IPRIO2H,
+}
+
+impl Into<u64> for RISCVAiaCsrRegs {
+    fn into(self) -> u64 {
+        let reg_offset = match self {
+            RISCVAiaCsrRegs::SISELECT => offset_of!(kvm_riscv_aia_csr, siselect),
+            RISCVAiaCsrRegs::IPRIO1 => offset_of!(kvm_riscv_aia_csr, iprio1),
+            RISCVAiaCsrRegs::IPRIO2 => offset_of!(kvm_riscv_aia_csr, iprio2),
+            RISCVAiaCsrRegs::SIEH => offset_of!(kvm_riscv_aia_csr, sieh),
+            RISCVAiaCsrRegs::SIPH => offset_of!(kvm_riscv_aia_csr, siph),
+            RISCVAiaCsrRegs::IPRIO1H => offset_of!(kvm_riscv_aia_csr, iprio1h),
+            RISCVAiaCsrRegs::IPRIO2H => offset_of!(kvm_riscv_aia_csr, iprio2h),
+        };
+
+        // calculate reg_id
+        KVM_REG_RISCV as u64
+            | KVM_REG_SIZE_U64 as u64
+            | u64::from(KVM_REG_RISCV_CSR)
+            | u64::from(KVM_REG_RISCV_CSR_AIA)
+            | (reg_offset / size_of::<u64>()) as u64
+    }
+}
+
+/// RISCV cpu single-precision floating-point register.
+/// See: https://elixir.bootlin.com/linux/v6.0/source/arch/riscv/include/uapi/asm/ptrace.h#L81
+pub enum RISCVFpFRegs {
+    F(usize),
+    FCSR,
+}
+
+impl Into<u64> for RISCVFpFRegs {
+    fn into(self) -> u64 {
+        let reg_offset = match self {
+            RISCVFpFRegs::F(index) => {
+                offset_of!(__riscv_f_ext_state, f) + index * size_of::<u32>()
+            }
+            RISCVFpFRegs::FCSR => {
+                offset_of!(__riscv_f_ext_state, fcsr)
+            }
+        };
+
+        // calculate reg_id
+        KVM_REG_RISCV as u64
+            | KVM_REG_SIZE_U32 as u64
+            | u64::from(KVM_REG_RISCV_FP_F)
+            | (reg_offset / size_of::<u32>()) as u64
+    }
+}
+
+/// RISCV cpu double-precision floating-point register.
+/// See: https://elixir.bootlin.com/linux/v6.0/source/arch/riscv/include/uapi/asm/ptrace.h#L86
+pub enum RISCVFpDRegs {
+    F(usize),
+    FCSR,
+}
+
+impl Into<u64> for RISCVFpDRegs {
+    fn into(self) -> u64 {
+        // The `f` registers are 64 bits wide, while `fcsr` stays 32 bits wide
+        // and is indexed right after the last `f` register.
+        match self {
+            RISCVFpDRegs::F(index) => {
+                let reg_offset = offset_of!(__riscv_d_ext_state, f) + index * size_of::<u64>();
+                KVM_REG_RISCV as u64
+                    | KVM_REG_SIZE_U64 as u64
+                    | u64::from(KVM_REG_RISCV_FP_D)
+                    | (reg_offset / size_of::<u64>()) as u64
+            }
+            RISCVFpDRegs::FCSR => {
+                let reg_offset = offset_of!(__riscv_d_ext_state, fcsr);
+                KVM_REG_RISCV as u64
+                    | KVM_REG_SIZE_U32 as u64
+                    | u64::from(KVM_REG_RISCV_FP_D)
+                    | (reg_offset / size_of::<u64>()) as u64
+            }
+        }
+    }
+}
+
+/// RISCV cpu ISA extension register, the inner value is `KVM_RISCV_ISA_EXT_ID`.
+/// See: https://elixir.bootlin.com/linux/v6.6/source/arch/riscv/include/uapi/asm/kvm.h#L112
+pub struct RISCVIsaExtReg(pub u64);
+
+impl Into<u64> for RISCVIsaExtReg {
+    fn into(self) -> u64 {
+        KVM_REG_RISCV as u64
+            | KVM_REG_SIZE_U64 as u64
+            | u64::from(KVM_REG_RISCV_ISA_EXT)
+            | u64::from(KVM_REG_RISCV_ISA_SINGLE)
+            | self.0
+    }
+}
+
+/// RISCV cpu SBI extension register, the inner value is `KVM_RISCV_SBI_EXT_ID`.
+/// See: https://elixir.bootlin.com/linux/v6.6/source/arch/riscv/include/uapi/asm/kvm.h#L145
+pub struct RISCVSbiExtReg(pub u64);
+
+impl Into<u64> for RISCVSbiExtReg {
+    fn into(self) -> u64 {
+        KVM_REG_RISCV as u64
+            | KVM_REG_SIZE_U64 as u64
+            | u64::from(KVM_REG_RISCV_SBI_EXT)
+            | u64::from(KVM_REG_RISCV_SBI_SINGLE)
+            | self.0
+    }
+}
+
 /// Returns the vcpu's current `config_register`.
 ///
 /// The register state is gotten from `KVM_GET_ONE_REG` api in KVM.
@@ -286,6 +474,52 @@ pub fn get_config_regs(vcpu_fd: &VcpuFd) -> Result<kvm_riscv_config> {
     Ok(config_regs)
 }
 
+/// Returns the vcpu's current `core_register`.
+///
+/// The register state is gotten from `KVM_GET_ONE_REG` api in KVM.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+pub fn get_core_regs(vcpu_fd: &VcpuFd) -> Result<kvm_riscv_core> {
+    let mut core_regs = kvm_riscv_core::default();
+    core_regs.regs.pc = vcpu_fd.get_one_reg(RISCVCoreRegs::PC.into())? as u64;
+    core_regs.regs.ra = vcpu_fd.get_one_reg(RISCVCoreRegs::RA.into())? as u64;
+    core_regs.regs.sp = vcpu_fd.get_one_reg(RISCVCoreRegs::SP.into())? as u64;
+    core_regs.regs.gp = vcpu_fd.get_one_reg(RISCVCoreRegs::GP.into())? as u64;
+    core_regs.regs.tp = vcpu_fd.get_one_reg(RISCVCoreRegs::TP.into())? as u64;
+    core_regs.regs.t0 = vcpu_fd.get_one_reg(RISCVCoreRegs::T0.into())? as u64;
+    core_regs.regs.t1 = vcpu_fd.get_one_reg(RISCVCoreRegs::T1.into())? as u64;
+    core_regs.regs.t2 = vcpu_fd.get_one_reg(RISCVCoreRegs::T2.into())? as u64;
+    core_regs.regs.s0 = vcpu_fd.get_one_reg(RISCVCoreRegs::S0.into())? as u64;
+    core_regs.regs.s1 = vcpu_fd.get_one_reg(RISCVCoreRegs::S1.into())? as u64;
+    core_regs.regs.a0 = vcpu_fd.get_one_reg(RISCVCoreRegs::A0.into())? as u64;
+    core_regs.regs.a1 = vcpu_fd.get_one_reg(RISCVCoreRegs::A1.into())? as u64;
+    core_regs.regs.a2 = vcpu_fd.get_one_reg(RISCVCoreRegs::A2.into())? as u64;
+    core_regs.regs.a3 = vcpu_fd.get_one_reg(RISCVCoreRegs::A3.into())? as u64;
+    core_regs.regs.a4 = vcpu_fd.get_one_reg(RISCVCoreRegs::A4.into())? as u64;
+    core_regs.regs.a5 = vcpu_fd.get_one_reg(RISCVCoreRegs::A5.into())? as u64;
+    core_regs.regs.a6 = vcpu_fd.get_one_reg(RISCVCoreRegs::A6.into())? as u64;
+    core_regs.regs.a7 = vcpu_fd.get_one_reg(RISCVCoreRegs::A7.into())? as u64;
+    core_regs.regs.s2 = vcpu_fd.get_one_reg(RISCVCoreRegs::S2.into())? as u64;
+    core_regs.regs.s3 = vcpu_fd.get_one_reg(RISCVCoreRegs::S3.into())? as u64;
+    core_regs.regs.s4 = vcpu_fd.get_one_reg(RISCVCoreRegs::S4.into())? as u64;
+    core_regs.regs.s5 = vcpu_fd.get_one_reg(RISCVCoreRegs::S5.into())? as u64;
+    core_regs.regs.s6 = vcpu_fd.get_one_reg(RISCVCoreRegs::S6.into())? as u64;
+    core_regs.regs.s7 = vcpu_fd.get_one_reg(RISCVCoreRegs::S7.into())? as u64;
+    core_regs.regs.s8 = vcpu_fd.get_one_reg(RISCVCoreRegs::S8.into())? as u64;
+    core_regs.regs.s9 = vcpu_fd.get_one_reg(RISCVCoreRegs::S9.into())? as u64;
+    core_regs.regs.s10 = vcpu_fd.get_one_reg(RISCVCoreRegs::S10.into())? as u64;
+    core_regs.regs.s11 = vcpu_fd.get_one_reg(RISCVCoreRegs::S11.into())? as u64;
+    core_regs.regs.t3 = vcpu_fd.get_one_reg(RISCVCoreRegs::T3.into())? as u64;
+    core_regs.regs.t4 = vcpu_fd.get_one_reg(RISCVCoreRegs::T4.into())? as u64;
+    core_regs.regs.t5 = vcpu_fd.get_one_reg(RISCVCoreRegs::T5.into())? as u64;
+    core_regs.regs.t6 = vcpu_fd.get_one_reg(RISCVCoreRegs::T6.into())? as u64;
+    core_regs.mode = vcpu_fd.get_one_reg(RISCVCoreRegs::MODE.into())? as u64;
+
+    Ok(core_regs)
+}
+
 /// Sets the vcpu's current "core_register"
 ///
 /// The register state is gotten from `KVM_SET_ONE_REG` api in KVM.
@@ -296,8 +530,169 @@ pub fn get_config_regs(vcpu_fd: &VcpuFd) -> Result<kvm_riscv_config> {
 /// * `core_regs` - kvm_regs state to be written.
 pub fn set_core_regs(vcpu_fd: &VcpuFd, core_regs: kvm_riscv_core) -> Result<()> {
     vcpu_fd.set_one_reg(RISCVCoreRegs::PC.into(), core_regs.regs.pc as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::RA.into(), core_regs.regs.ra as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::SP.into(), core_regs.regs.sp as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::GP.into(), core_regs.regs.gp as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::TP.into(), core_regs.regs.tp as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::T0.into(), core_regs.regs.t0 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::T1.into(), core_regs.regs.t1 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::T2.into(), core_regs.regs.t2 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::S0.into(), core_regs.regs.s0 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::S1.into(), core_regs.regs.s1 as u128)?;
     vcpu_fd.set_one_reg(RISCVCoreRegs::A0.into(), core_regs.regs.a0 as u128)?;
     vcpu_fd.set_one_reg(RISCVCoreRegs::A1.into(), core_regs.regs.a1 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::A2.into(), core_regs.regs.a2 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::A3.into(), core_regs.regs.a3 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::A4.into(), core_regs.regs.a4 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::A5.into(), core_regs.regs.a5 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::A6.into(), core_regs.regs.a6 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::A7.into(), core_regs.regs.a7 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::S2.into(), core_regs.regs.s2 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::S3.into(), core_regs.regs.s3 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::S4.into(), core_regs.regs.s4 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::S5.into(), core_regs.regs.s5 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::S6.into(), core_regs.regs.s6 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::S7.into(), core_regs.regs.s7 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::S8.into(), core_regs.regs.s8 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::S9.into(), core_regs.regs.s9 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::S10.into(), core_regs.regs.s10 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::S11.into(), core_regs.regs.s11 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::T3.into(), core_regs.regs.t3 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::T4.into(), core_regs.regs.t4 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::T5.into(), core_regs.regs.t5 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::T6.into(), core_regs.regs.t6 as u128)?;
+    vcpu_fd.set_one_reg(RISCVCoreRegs::MODE.into(), core_regs.mode as u128)?;
+
+    Ok(())
+}
+
+/// Returns the vcpu's current `csr_register`.
+///
+/// The register state is gotten from `KVM_GET_ONE_REG` api in KVM.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+pub fn get_csr_regs(vcpu_fd: &VcpuFd) -> Result<kvm_riscv_csr> {
+    let mut csr_regs = kvm_riscv_csr::default();
+    csr_regs.sstatus = vcpu_fd.get_one_reg(RISCVCsrRegs::SSTATUS.into())? as u64;
+    csr_regs.sie = vcpu_fd.get_one_reg(RISCVCsrRegs::SIE.into())? as u64;
+    csr_regs.stvec = vcpu_fd.get_one_reg(RISCVCsrRegs::STVEC.into())? as u64;
+    csr_regs.sscratch = vcpu_fd.get_one_reg(RISCVCsrRegs::SSCRATCH.into())? as u64;
+    csr_regs.sepc = vcpu_fd.get_one_reg(RISCVCsrRegs::SEPC.into())? as u64;
+    csr_regs.scause = vcpu_fd.get_one_reg(RISCVCsrRegs::SCAUSE.into())? as u64;
+    csr_regs.stval = vcpu_fd.get_one_reg(RISCVCsrRegs::STVAL.into())? as u64;
+    csr_regs.sip = vcpu_fd.get_one_reg(RISCVCsrRegs::SIP.into())? as u64;
+    csr_regs.satp = vcpu_fd.get_one_reg(RISCVCsrRegs::SATP.into())? as u64;
+    csr_regs.scounteren = vcpu_fd.get_one_reg(RISCVCsrRegs::SCOUNTEREN.into())? as u64;
+
+    Ok(csr_regs)
+}
+
+/// Sets the vcpu's current `csr_register`.
+///
+/// The register state is set by `KVM_SET_ONE_REG` api in KVM.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `csr_regs` - kvm_riscv_csr state to be written.
+pub fn set_csr_regs(vcpu_fd: &VcpuFd, csr_regs: kvm_riscv_csr) -> Result<()> {
+    vcpu_fd.set_one_reg(RISCVCsrRegs::SSTATUS.into(), csr_regs.sstatus as u128)?;
+    vcpu_fd.set_one_reg(RISCVCsrRegs::SIE.into(), csr_regs.sie as u128)?;
+    vcpu_fd.set_one_reg(RISCVCsrRegs::STVEC.into(), csr_regs.stvec as u128)?;
+    vcpu_fd.set_one_reg(RISCVCsrRegs::SSCRATCH.into(), csr_regs.sscratch as u128)?;
+    vcpu_fd.set_one_reg(RISCVCsrRegs::SEPC.into(), csr_regs.sepc as u128)?;
+    vcpu_fd.set_one_reg(RISCVCsrRegs::SCAUSE.into(), csr_regs.scause as u128)?;
+    vcpu_fd.set_one_reg(RISCVCsrRegs::STVAL.into(), csr_regs.stval as u128)?;
+    vcpu_fd.set_one_reg(RISCVCsrRegs::SIP.into(), csr_regs.sip as u128)?;
+    vcpu_fd.set_one_reg(RISCVCsrRegs::SATP.into(), csr_regs.satp as u128)?;
+    vcpu_fd.set_one_reg(RISCVCsrRegs::SCOUNTEREN.into(), csr_regs.scounteren as u128)?;
+
+    Ok(())
+}
+
+/// Returns the vcpu's current AIA csr registers.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+pub fn get_aia_csr_regs(vcpu_fd: &VcpuFd) -> Result<kvm_riscv_aia_csr> {
+    let mut aia_csr = kvm_riscv_aia_csr::default();
+    aia_csr.siselect = vcpu_fd.get_one_reg(RISCVAiaCsrRegs::SISELECT.into())? as u64;
+    aia_csr.iprio1 = vcpu_fd.get_one_reg(RISCVAiaCsrRegs::IPRIO1.into())? as u64;
+    aia_csr.iprio2 = vcpu_fd.get_one_reg(RISCVAiaCsrRegs::IPRIO2.into())? as u64;
+    aia_csr.sieh = vcpu_fd.get_one_reg(RISCVAiaCsrRegs::SIEH.into())? as u64;
+    aia_csr.siph = vcpu_fd.get_one_reg(RISCVAiaCsrRegs::SIPH.into())? as u64;
+    aia_csr.iprio1h = vcpu_fd.get_one_reg(RISCVAiaCsrRegs::IPRIO1H.into())? as u64;
+    aia_csr.iprio2h = vcpu_fd.get_one_reg(RISCVAiaCsrRegs::IPRIO2H.into())? as u64;
+
+    Ok(aia_csr)
+}
+
+/// Sets the vcpu's current AIA csr registers.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `aia_csr` - kvm_riscv_aia_csr state to be written.
+pub fn set_aia_csr_regs(vcpu_fd: &VcpuFd, aia_csr: kvm_riscv_aia_csr) -> Result<()> {
+    vcpu_fd.set_one_reg(RISCVAiaCsrRegs::SISELECT.into(), aia_csr.siselect as u128)?;
+    vcpu_fd.set_one_reg(RISCVAiaCsrRegs::IPRIO1.into(), aia_csr.iprio1 as u128)?;
+    vcpu_fd.set_one_reg(RISCVAiaCsrRegs::IPRIO2.into(), aia_csr.iprio2 as u128)?;
+    vcpu_fd.set_one_reg(RISCVAiaCsrRegs::SIEH.into(), aia_csr.sieh as u128)?;
+    vcpu_fd.set_one_reg(RISCVAiaCsrRegs::SIPH.into(), aia_csr.siph as u128)?;
+    vcpu_fd.set_one_reg(RISCVAiaCsrRegs::IPRIO1H.into(), aia_csr.iprio1h as u128)?;
+    vcpu_fd.set_one_reg(RISCVAiaCsrRegs::IPRIO2H.into(), aia_csr.iprio2h as u128)?;
+
+    Ok(())
+}
+
+/// Returns the vcpu's current floating-point registers.
+///
+/// The double-precision register file is used when the vcpu supports the `D`
+/// extension, otherwise the single-precision one is zero-extended into it.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `isa` - the isa config register of this vcpu.
+pub fn get_fp_regs(vcpu_fd: &VcpuFd, isa: u64) -> Result<__riscv_d_ext_state> {
+    let mut fp_regs = __riscv_d_ext_state::default();
+    if isa & RISCV_ISA_EXT_D != 0 {
+        for (index, reg) in fp_regs.f.iter_mut().enumerate() {
+            *reg = vcpu_fd.get_one_reg(RISCVFpDRegs::F(index).into())? as u64;
+        }
+        fp_regs.fcsr = vcpu_fd.get_one_reg(RISCVFpDRegs::FCSR.into())? as u32;
+    } else if isa & RISCV_ISA_EXT_F != 0 {
+        for (index, reg) in fp_regs.f.iter_mut().enumerate() {
+            *reg = vcpu_fd.get_one_reg(RISCVFpFRegs::F(index).into())? as u32 as u64;
+        }
+        fp_regs.fcsr = vcpu_fd.get_one_reg(RISCVFpFRegs::FCSR.into())? as u32;
+    }
+
+    Ok(fp_regs)
+}
+
+/// Sets the vcpu's current floating-point registers.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `isa` - the isa config register of this vcpu.
+/// * `fp_regs` - floating-point state to be written.
+pub fn set_fp_regs(vcpu_fd: &VcpuFd, isa: u64, fp_regs: __riscv_d_ext_state) -> Result<()> {
+    if isa & RISCV_ISA_EXT_D != 0 {
+        for (index, reg) in fp_regs.f.iter().enumerate() {
+            vcpu_fd.set_one_reg(RISCVFpDRegs::F(index).into(), *reg as u128)?;
+        }
+        vcpu_fd.set_one_reg(RISCVFpDRegs::FCSR.into(), fp_regs.fcsr as u128)?;
+    } else if isa & RISCV_ISA_EXT_F != 0 {
+        for (index, reg) in fp_regs.f.iter().enumerate() {
+            vcpu_fd.set_one_reg(RISCVFpFRegs::F(index).into(), *reg as u32 as u128)?;
+        }
+        vcpu_fd.set_one_reg(RISCVFpFRegs::FCSR.into(), fp_regs.fcsr as u128)?;
+    }
 
     Ok(())
 }
@@ -322,3 +717,118 @@ pub fn get_timer_regs(vcpu_fd: &VcpuFd) -> Result<kvm_riscv_timer> {
 
     Ok(timer_regs)
 }
+
+/// Sets the vcpu's current `timer_register`.
+///
+/// The timer frequency is read-only in KVM, so only `time`, `compare` and
+/// `state` are written back. `compare` must be set before `state` so that an
+/// enabled timer is armed with the restored deadline.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `timer_regs` - kvm_riscv_timer state to be written.
+pub fn set_timer_regs(vcpu_fd: &VcpuFd, timer_regs: kvm_riscv_timer) -> Result<()> {
+    vcpu_fd.set_one_reg(RISCVTimerRegs::TIME.into(), timer_regs.time as u128)?;
+    vcpu_fd.set_one_reg(RISCVTimerRegs::COMPARE.into(), timer_regs.compare as u128)?;
+    vcpu_fd.set_one_reg(RISCVTimerRegs::STATE.into(), timer_regs.state as u128)?;
+
+    Ok(())
+}
+
+/// Returns the vcpu's ISA extension registers and the mask of extensions
+/// known by KVM.
+///
+/// Extensions unknown to the host kernel fail with `ENOENT`/`EINVAL` and are
+/// left out of the returned mask.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+pub fn get_isa_ext_regs(vcpu_fd: &VcpuFd) -> ([u64; RISCV_ISA_EXT_NR], u64) {
+    let mut isa_ext = [0_u64; RISCV_ISA_EXT_NR];
+    let mut mask = 0_u64;
+    for (id, ext) in isa_ext
+        .iter_mut()
+        .enumerate()
+        .take(KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_MAX as usize)
+    {
+        if let Ok(value) = vcpu_fd.get_one_reg(RISCVIsaExtReg(id as u64).into()) {
+            *ext = value as u64;
+            mask |= 1 << id;
+        }
+    }
+
+    (isa_ext, mask)
+}
+
+/// Sets the vcpu's ISA extension registers selected by `mask`.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `isa_ext` - ISA extension registers to be written.
+/// * `mask` - bitmap of the extensions to be written.
+pub fn set_isa_ext_regs(vcpu_fd: &VcpuFd, isa_ext: &[u64], mask: u64) -> Result<()> {
+    for (id, ext) in isa_ext.iter().enumerate() {
+        if mask & (1 << id) == 0 {
+            continue;
+        }
+        // KVM refuses to change extensions once the vcpu has run, skip the
+        // ones which already hold the expected value.
+        let reg_id: u64 = RISCVIsaExtReg(id as u64).into();
+        if vcpu_fd.get_one_reg(reg_id).map_or(false, |v| v as u64 == *ext) {
+            continue;
+        }
+        vcpu_fd.set_one_reg(reg_id, *ext as u128)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the vcpu's SBI extension registers and the mask of extensions
+/// known by KVM.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+pub fn get_sbi_ext_regs(vcpu_fd: &VcpuFd) -> ([u64; RISCV_SBI_EXT_NR], u64) {
+    let mut sbi_ext = [0_u64; RISCV_SBI_EXT_NR];
+    let mut mask = 0_u64;
+    for (id, ext) in sbi_ext
+        .iter_mut()
+        .enumerate()
+        .take(KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_MAX as usize)
+    {
+        if let Ok(value) = vcpu_fd.get_one_reg(RISCVSbiExtReg(id as u64).into()) {
+            *ext = value as u64;
+            mask |= 1 << id;
+        }
+    }
+
+    (sbi_ext, mask)
+}
+
+/// Sets the vcpu's SBI extension registers selected by `mask`.
+///
+/// # Arguments
+///
+/// * `vcpu_fd` - the VcpuFd in KVM mod.
+/// * `sbi_ext` - SBI extension registers to be written.
+/// * `mask` - bitmap of the extensions to be written.
+pub fn set_sbi_ext_regs(vcpu_fd: &VcpuFd, sbi_ext: &[u64], mask: u64) -> Result<()> {
+    for (id, ext) in sbi_ext.iter().enumerate() {
+        if mask & (1 << id) == 0 {
+            continue;
+        }
+        // KVM refuses to change extensions once the vcpu has run, skip the
+        // ones which already hold the expected value.
+        let reg_id: u64 = RISCVSbiExtReg(id as u64).into();
+        if vcpu_fd.get_one_reg(reg_id).map_or(false, |v| v as u64 == *ext) {
+            continue;
+        }
+        vcpu_fd.set_one_reg(reg_id, *ext as u128)?;
+    }
+
+    Ok(())
+}
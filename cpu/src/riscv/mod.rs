@@ -16,17 +16,23 @@ mod core_regs;
 
 pub use self::caps::RISCVCPUCaps;
 use kvm_bindings::{
-    kvm_mp_state, kvm_riscv_config, kvm_riscv_core, kvm_riscv_timer, KVM_MP_STATE_RUNNABLE,
-    KVM_MP_STATE_STOPPED,
+    __riscv_d_ext_state, kvm_mp_state, kvm_riscv_aia_csr, kvm_riscv_config, kvm_riscv_core,
+    kvm_riscv_csr, kvm_riscv_timer, KVM_MP_STATE_RUNNABLE, KVM_MP_STATE_STOPPED,
+    KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_SSAIA,
 };
 use kvm_ioctls::VcpuFd;
 use std::sync::{Arc, Mutex};
 
-use self::core_regs::{get_config_regs, get_timer_regs, set_core_regs};
-use anyhow::{Context, Result};
+use self::core_regs::{
+    get_aia_csr_regs, get_config_regs, get_core_regs, get_csr_regs, get_fp_regs, get_isa_ext_regs,
+    get_sbi_ext_regs, get_timer_regs, set_aia_csr_regs, set_core_regs, set_csr_regs, set_fp_regs,
+    set_isa_ext_regs, set_sbi_ext_regs, set_timer_regs,
+};
+use anyhow::{anyhow, Context, Result};
 
+use crate::CPU;
 use migration::{
-    DeviceStateDesc, FieldDesc,
+    DeviceStateDesc, FieldDesc, MigrationError, MigrationHook, MigrationManager, StateTransfer,
 };
 use migration_derive::{ByteCode, Desc};
 use util::byte_code::ByteCode;
@@ -59,7 +65,7 @@ impl RISCVCPUTopology {
 /// RISCV CPU architect information
 #[repr(C)]
 #[derive(Copy, Clone, Desc, ByteCode)]
-#[desc_version(compat_version = "0.1.0")]
+#[desc_version(current_version = "0.3.0", compat_version = "0.2.0")]
 pub struct RISCVCPUState {
     /// The vcpu id, `0` means primary CPU.
     apic_id: u32,
@@ -67,8 +73,22 @@ pub struct RISCVCPUState {
     config_regs: kvm_riscv_config,
     /// Vcpu core registers.
     core_regs: kvm_riscv_core,
+    /// Vcpu supervisor csr registers.
+    csr_regs: kvm_riscv_csr,
+    /// Vcpu AIA csr registers, only valid with the `Ssaia` extension.
+    aia_csr_regs: kvm_riscv_aia_csr,
+    /// Vcpu floating-point registers, single-precision values are zero-extended.
+    fp_regs: __riscv_d_ext_state,
     /// Vcpu timer registers.
     timer_regs: kvm_riscv_timer,
+    /// Vcpu ISA extension registers, indexed by `KVM_RISCV_ISA_EXT_ID`.
+    isa_ext: [u64; 32],
+    /// Bitmap of the ISA extension registers known by KVM.
+    isa_ext_mask: u64,
+    /// Vcpu SBI extension registers, indexed by `KVM_RISCV_SBI_EXT_ID`.
+    sbi_ext: [u64; 16],
+    /// Bitmap of the SBI extension registers known by KVM.
+    sbi_ext_mask: u64,
     /// Vcpu mpstate register.
     mp_state: kvm_mp_state,
     /// The length of registers
@@ -101,7 +121,16 @@ impl RISCVCPUState {
     pub fn set(&mut self, cpu_state: &Arc<Mutex<RISCVCPUState>>) {
         let locked_cpu_state = cpu_state.lock().unwrap();
         self.apic_id = locked_cpu_state.apic_id;
+        self.config_regs = locked_cpu_state.config_regs;
         self.core_regs = locked_cpu_state.core_regs;
+        self.csr_regs = locked_cpu_state.csr_regs;
+        self.aia_csr_regs = locked_cpu_state.aia_csr_regs;
+        self.fp_regs = locked_cpu_state.fp_regs;
+        self.timer_regs = locked_cpu_state.timer_regs;
+        self.isa_ext = locked_cpu_state.isa_ext;
+        self.isa_ext_mask = locked_cpu_state.isa_ext_mask;
+        self.sbi_ext = locked_cpu_state.sbi_ext;
+        self.sbi_ext_mask = locked_cpu_state.sbi_ext_mask;
         self.mp_state = locked_cpu_state.mp_state;
     }
 
@@ -116,14 +145,50 @@ impl RISCVCPUState {
         vcpu_fd: &Arc<VcpuFd>,
         boot_config: &RISCVCPUBootConfig,
     ) -> Result<()> {
-        self.config_regs = get_config_regs(vcpu_fd)?;
-        self.timer_regs = get_timer_regs(vcpu_fd)?;
+        self.save_state(vcpu_fd)?;
+        self.mp_state = kvm_mp_state {
+            mp_state: if self.apic_id == 0 {
+                KVM_MP_STATE_RUNNABLE
+            } else {
+                KVM_MP_STATE_STOPPED
+            },
+        };
 
         self.set_core_reg(boot_config);
 
         Ok(())
     }
 
+    /// Save register value from `Kvm` into `RISCVCPUState`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd` - Vcpu file descriptor in kvm.
+    pub fn save_state(&mut self, vcpu_fd: &Arc<VcpuFd>) -> Result<()> {
+        self.config_regs = get_config_regs(vcpu_fd)
+            .with_context(|| format!("Failed to get config register for CPU {}", self.apic_id))?;
+        self.core_regs = get_core_regs(vcpu_fd)
+            .with_context(|| format!("Failed to get core register for CPU {}", self.apic_id))?;
+        self.csr_regs = get_csr_regs(vcpu_fd)
+            .with_context(|| format!("Failed to get csr register for CPU {}", self.apic_id))?;
+        self.fp_regs = get_fp_regs(vcpu_fd, self.config_regs.isa)
+            .with_context(|| format!("Failed to get fp register for CPU {}", self.apic_id))?;
+        self.timer_regs = get_timer_regs(vcpu_fd)
+            .with_context(|| format!("Failed to get timer register for CPU {}", self.apic_id))?;
+        (self.isa_ext, self.isa_ext_mask) = get_isa_ext_regs(vcpu_fd);
+        if self.has_aia() {
+            self.aia_csr_regs = get_aia_csr_regs(vcpu_fd).with_context(|| {
+                format!("Failed to get aia csr register for CPU {}", self.apic_id)
+            })?;
+        }
+        (self.sbi_ext, self.sbi_ext_mask) = get_sbi_ext_regs(vcpu_fd);
+        self.mp_state = vcpu_fd
+            .get_mp_state()
+            .with_context(|| format!("Failed to get mpstate for CPU {}", self.apic_id))?;
+
+        Ok(())
+    }
+
     /// Set cpu topology
     ///
     /// # Arguments
@@ -139,8 +204,33 @@ impl RISCVCPUState {
     ///
     /// * `vcpu_fd` - Vcpu file descriptor in kvm.
     pub fn reset_vcpu(&self, vcpu_fd: &Arc<VcpuFd>) -> Result<()> {
+        // Extensions can only be changed before the vcpu first runs, unchanged
+        // ones are skipped so that a running vcpu can be rewound as well.
+        set_isa_ext_regs(vcpu_fd, &self.isa_ext, self.isa_ext_mask).with_context(|| {
+            format!(
+                "Failed to set isa extension register for CPU {}",
+                self.apic_id
+            )
+        })?;
+        set_sbi_ext_regs(vcpu_fd, &self.sbi_ext, self.sbi_ext_mask).with_context(|| {
+            format!(
+                "Failed to set sbi extension register for CPU {}",
+                self.apic_id
+            )
+        })?;
         set_core_regs(vcpu_fd, self.core_regs)
             .with_context(|| format!("Failed to set core register for CPU {}", self.apic_id))?;
+        set_csr_regs(vcpu_fd, self.csr_regs)
+            .with_context(|| format!("Failed to set csr register for CPU {}", self.apic_id))?;
+        if self.has_aia() {
+            set_aia_csr_regs(vcpu_fd, self.aia_csr_regs).with_context(|| {
+                format!("Failed to set aia csr register for CPU {}", self.apic_id)
+            })?;
+        }
+        set_fp_regs(vcpu_fd, self.config_regs.isa, self.fp_regs)
+            .with_context(|| format!("Failed to set fp register for CPU {}", self.apic_id))?;
+        set_timer_regs(vcpu_fd, self.timer_regs)
+            .with_context(|| format!("Failed to set timer register for CPU {}", self.apic_id))?;
         vcpu_fd
             .set_mp_state(self.mp_state)
             .with_context(|| format!("Failed to set mpstate for CPU {}", self.apic_id))?;
@@ -157,6 +247,16 @@ impl RISCVCPUState {
         self.core_regs
     }
 
+    /// Get csr_regs value.
+    pub fn csr_regs(&self) -> kvm_riscv_csr {
+        self.csr_regs
+    }
+
+    /// Get fp_regs value.
+    pub fn fp_regs(&self) -> __riscv_d_ext_state {
+        self.fp_regs
+    }
+
     /// Get timer_regs value.
     pub fn timer_regs(&self) -> kvm_riscv_timer {
         self.timer_regs
@@ -174,8 +274,53 @@ impl RISCVCPUState {
         }
     }
 
+    /// Whether the `Ssaia` extension is enabled for this vcpu.
+    fn has_aia(&self) -> bool {
+        self.isa_ext[KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_SSAIA as usize] != 0
+    }
+
     /// Get the length of registers.
     pub fn get_xlen(&self) -> u64 {
         self.xlen
     }
 }
+
+impl StateTransfer for CPU {
+    fn get_state_vec(&self) -> migration::Result<Vec<u8>> {
+        let mut cpu_state_locked = self.arch_cpu.lock().unwrap();
+        cpu_state_locked
+            .save_state(&self.fd)
+            .with_context(|| "Failed to save cpu state")?;
+
+        Ok(cpu_state_locked.as_bytes().to_vec())
+    }
+
+    fn set_state(&self, state: &[u8]) -> migration::Result<()> {
+        let cpu_state = *RISCVCPUState::from_bytes(state)
+            .ok_or_else(|| anyhow!(MigrationError::FromBytesError("CPU")))?;
+
+        // The vcpu has not run yet, keep its initial registers as boot state
+        // so that the restored guest can still be rebooted.
+        self.boot_state
+            .lock()
+            .unwrap()
+            .save_state(&self.fd)
+            .with_context(|| "Failed to save cpu boot state")?;
+
+        // The registers are written into kvm when the vcpu thread starts.
+        let mut cpu_state_locked = self.arch_cpu.lock().unwrap();
+        *cpu_state_locked = cpu_state;
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&RISCVCPUState::descriptor().name) {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+impl MigrationHook for CPU {}
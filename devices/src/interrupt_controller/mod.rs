@@ -21,8 +21,12 @@ pub use error::InterruptError;
 #[cfg(target_arch = "riscv64")]
 pub use riscv::PLICConfig as InterruptControllerConfig;
 #[cfg(target_arch = "riscv64")]
+pub use riscv::PLICVersion as InterruptControllerVersion;
+#[cfg(target_arch = "riscv64")]
 pub use riscv::InterruptController;
 #[cfg(target_arch = "riscv64")]
 pub use riscv::plic::MAX_DEVICES;
+#[cfg(target_arch = "riscv64")]
+pub use riscv::aia::{KvmAIA, AIA_APLIC_SIZE, AIA_IMSIC_SIZE, AIA_NUM_IDS};
 
 
@@ -0,0 +1,396 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use super::aplic::APLIC_NUM_SOURCES;
+use super::{PLICConfig, PLICDevice};
+use anyhow::{anyhow, bail, Context, Result};
+use hypervisor::kvm::KVM_FDS;
+use kvm_bindings::{
+    kvm_create_device, kvm_device_attr, kvm_device_type_KVM_DEV_TYPE_RISCV_AIA, kvm_msi,
+    KVM_CREATE_DEVICE_TEST, KVM_DEV_RISCV_AIA_ADDR_APLIC, KVM_DEV_RISCV_AIA_ADDR_IMSIC,
+    KVM_DEV_RISCV_AIA_CONFIG_HART_BITS, KVM_DEV_RISCV_AIA_CONFIG_IDS,
+    KVM_DEV_RISCV_AIA_CONFIG_MODE, KVM_DEV_RISCV_AIA_CONFIG_SRCS, KVM_DEV_RISCV_AIA_CTRL_INIT,
+    KVM_DEV_RISCV_AIA_GRP_ADDR, KVM_DEV_RISCV_AIA_GRP_APLIC, KVM_DEV_RISCV_AIA_GRP_CONFIG,
+    KVM_DEV_RISCV_AIA_GRP_CTRL, KVM_DEV_RISCV_AIA_GRP_IMSIC, KVM_DEV_RISCV_AIA_IMSIC_ISEL_BITS,
+    KVM_DEV_RISCV_AIA_MODE_AUTO, KVM_DEV_RISCV_APLIC_SIZE, KVM_DEV_RISCV_IMSIC_SIZE,
+};
+use kvm_ioctls::DeviceFd;
+use migration::{
+    snapshot::AIA_SNAPSHOT_ID, DeviceStateDesc, FieldDesc, MigrationError, MigrationHook,
+    MigrationManager, StateTransfer,
+};
+use migration_derive::{ByteCode, Desc};
+use util::byte_code::ByteCode;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Number of MSI identities implemented by each IMSIC interrupt file.
+pub const AIA_NUM_IDS: u32 = 255;
+/// Maximum number of harts handled by the in-kernel AIA.
+const AIA_MAX_HARTS: u32 = 256;
+/// Size of the in-kernel APLIC region.
+pub const AIA_APLIC_SIZE: u64 = KVM_DEV_RISCV_APLIC_SIZE as u64;
+/// Size of the IMSIC interrupt file of one hart.
+pub const AIA_IMSIC_SIZE: u64 = KVM_DEV_RISCV_IMSIC_SIZE as u64;
+
+// APLIC registers saved across migration, see "The RISC-V Advanced Interrupt
+// Architecture" chapter 4.5.
+const APLIC_DOMAINCFG: u64 = 0x0000;
+const APLIC_SOURCECFG_BASE: u64 = 0x0004;
+const APLIC_SETIP_BASE: u64 = 0x1c00;
+const APLIC_SETIE_BASE: u64 = 0x1e00;
+const APLIC_TARGET_BASE: u64 = 0x3004;
+
+// IMSIC indirectly accessed registers, see chapter 3.8. Only the even
+// `eip`/`eie` registers exist on RV64.
+const IMSIC_EIDELIVERY: u64 = 0x70;
+const IMSIC_EITHRESHOLD: u64 = 0x72;
+const IMSIC_EIP0: u64 = 0x80;
+const IMSIC_EIE0: u64 = 0xc0;
+const IMSIC_EIX_WORDS: usize = (AIA_NUM_IDS as usize + 1) / 64;
+
+const IRQ_WORDS: usize = 32;
+
+/// Status of the in-kernel APLIC and IMSIC registers.
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0")]
+pub struct AIAState {
+    /// Number of interrupt sources.
+    num_sources: u32,
+    /// Number of harts, one IMSIC interrupt file each.
+    num_harts: u32,
+    /// APLIC domain configuration register.
+    domaincfg: u32,
+    /// APLIC source configuration of each interrupt source.
+    sourcecfg: [u32; 1024],
+    /// APLIC target of each interrupt source.
+    target: [u32; 1024],
+    /// APLIC pending interrupt sources bitmap.
+    pending: [u32; 32],
+    /// APLIC enabled interrupt sources bitmap.
+    enabled: [u32; 32],
+    /// `eidelivery` register of each IMSIC.
+    eidelivery: [u64; 256],
+    /// `eithreshold` register of each IMSIC.
+    eithreshold: [u64; 256],
+    /// `eip` registers of each IMSIC, four per hart.
+    eip: [u64; 1024],
+    /// `eie` registers of each IMSIC, four per hart.
+    eie: [u64; 1024],
+}
+
+/// AIA emulated by KVM: one APLIC domain forwarding the wired interrupts as
+/// MSIs to the IMSIC of each hart.
+pub struct KvmAIA {
+    /// The fd of the in-kernel AIA device.
+    fd: Option<DeviceFd>,
+    num_sources: u32,
+    num_harts: u32,
+}
+
+impl PLICDevice for KvmAIA {
+    fn new() -> Self {
+        KvmAIA {
+            fd: None,
+            num_sources: APLIC_NUM_SOURCES,
+            num_harts: 0,
+        }
+    }
+
+    fn kvm_irq_line(&mut self, irq: u32, level: u8) -> Result<()> {
+        self.set_irq_line(irq, level != 0)
+    }
+
+    fn kvm_irq_trigger(&mut self, irq: u32) -> Result<()> {
+        self.set_irq_line(irq, true)?;
+        self.set_irq_line(irq, false)
+    }
+
+    fn register_irqfd(&self, evt: &EventFd, irq: u32) -> Result<bool> {
+        // The kernel routes GSI n to APLIC source n once the AIA is initialized.
+        KVM_FDS
+            .load()
+            .vm_fd
+            .as_ref()
+            .unwrap()
+            .register_irqfd(evt, irq)
+            .with_context(|| format!("Failed to register irqfd for irq {}", irq))?;
+        Ok(true)
+    }
+
+    fn msi_supported(&self) -> bool {
+        true
+    }
+
+    fn signal_msi(&self, addr: u64, data: u32) -> Result<()> {
+        // The address selects the IMSIC interrupt file of the target hart and
+        // the data is the interrupt identity within it.
+        let msi = kvm_msi {
+            address_lo: addr as u32,
+            address_hi: (addr >> 32) as u32,
+            data,
+            ..Default::default()
+        };
+        KVM_FDS
+            .load()
+            .vm_fd
+            .as_ref()
+            .unwrap()
+            .signal_msi(msi)
+            .with_context(|| format!("Failed to signal MSI {:#x} at {:#x}", data, addr))?;
+        Ok(())
+    }
+}
+
+impl KvmAIA {
+    /// Check whether the host kernel is able to emulate the AIA.
+    pub fn is_supported() -> bool {
+        let mut aia_device = kvm_create_device {
+            type_: kvm_device_type_KVM_DEV_TYPE_RISCV_AIA,
+            fd: 0,
+            flags: KVM_CREATE_DEVICE_TEST,
+        };
+        KVM_FDS
+            .load()
+            .vm_fd
+            .as_ref()
+            .map_or(false, |vm_fd| vm_fd.create_device(&mut aia_device).is_ok())
+    }
+
+    pub fn realize(mut self, aia_conf: &PLICConfig) -> Result<Arc<Mutex<Self>>> {
+        if aia_conf.vcpu_count == 0 || aia_conf.vcpu_count > AIA_MAX_HARTS {
+            bail!(
+                "AIA supports 1 to {} harts, {} requested",
+                AIA_MAX_HARTS,
+                aia_conf.vcpu_count
+            );
+        }
+        if aia_conf.imsic_size < aia_conf.vcpu_count as u64 * AIA_IMSIC_SIZE {
+            bail!(
+                "IMSIC region of size {:#x} is too small for {} harts",
+                aia_conf.imsic_size,
+                aia_conf.vcpu_count
+            );
+        }
+        self.num_harts = aia_conf.vcpu_count;
+
+        let mut aia_device = kvm_create_device {
+            type_: kvm_device_type_KVM_DEV_TYPE_RISCV_AIA,
+            fd: 0,
+            flags: 0,
+        };
+        self.fd = Some(
+            KVM_FDS
+                .load()
+                .vm_fd
+                .as_ref()
+                .unwrap()
+                .create_device(&mut aia_device)
+                .with_context(|| "Failed to create in-kernel AIA")?,
+        );
+
+        // Number of bits needed to index the harts of the single group.
+        let hart_bits = u32::BITS - (self.num_harts - 1).leading_zeros();
+        self.set_config(KVM_DEV_RISCV_AIA_CONFIG_MODE, KVM_DEV_RISCV_AIA_MODE_AUTO)?;
+        self.set_config(KVM_DEV_RISCV_AIA_CONFIG_SRCS, self.num_sources)?;
+        self.set_config(KVM_DEV_RISCV_AIA_CONFIG_IDS, AIA_NUM_IDS)?;
+        self.set_config(KVM_DEV_RISCV_AIA_CONFIG_HART_BITS, hart_bits)?;
+
+        self.set_addr(KVM_DEV_RISCV_AIA_ADDR_APLIC as u64, aia_conf.region_base)?;
+        for hart in 0..self.num_harts {
+            self.set_addr(
+                KVM_DEV_RISCV_AIA_ADDR_IMSIC(hart) as u64,
+                aia_conf.imsic_base + hart as u64 * AIA_IMSIC_SIZE,
+            )?;
+        }
+
+        self.access_attr(
+            KVM_DEV_RISCV_AIA_GRP_CTRL,
+            KVM_DEV_RISCV_AIA_CTRL_INIT as u64,
+            0,
+            true,
+        )
+        .with_context(|| "Failed to initialize in-kernel AIA")?;
+
+        let dev = Arc::new(Mutex::new(self));
+        MigrationManager::register_device_instance(
+            AIAState::descriptor(),
+            dev.clone(),
+            AIA_SNAPSHOT_ID,
+        );
+
+        Ok(dev)
+    }
+
+    fn set_irq_line(&self, irq: u32, level: bool) -> Result<()> {
+        if irq == 0 || irq > self.num_sources {
+            bail!("Invalid AIA interrupt source {}", irq);
+        }
+        KVM_FDS
+            .load()
+            .vm_fd
+            .as_ref()
+            .unwrap()
+            .set_irq_line(irq, level)
+            .with_context(|| format!("Failed to set AIA irq {} to {}", irq, level))
+    }
+
+    /// Read or write one attribute of the in-kernel AIA.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - Attribute group of the AIA device.
+    /// * `attr` - Attribute within the group.
+    /// * `addr` - Address of the value in userspace.
+    /// * `write` - Write the value to the device if true, read it otherwise.
+    fn access_attr(&self, group: u32, attr: u64, addr: u64, write: bool) -> Result<()> {
+        let fd = self
+            .fd
+            .as_ref()
+            .ok_or_else(|| anyhow!("In-kernel AIA is not created"))?;
+        let mut attr = kvm_device_attr {
+            group,
+            attr,
+            addr,
+            flags: 0,
+        };
+        if write {
+            fd.set_device_attr(&attr).with_context(|| {
+                format!(
+                    "Failed to set AIA attribute, group {} attr {:#x}",
+                    group, attr.attr
+                )
+            })?;
+        } else {
+            fd.get_device_attr(&mut attr).with_context(|| {
+                format!(
+                    "Failed to get AIA attribute, group {} attr {:#x}",
+                    group, attr.attr
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    fn set_config(&self, attr: u32, mut val: u32) -> Result<()> {
+        self.access_attr(
+            KVM_DEV_RISCV_AIA_GRP_CONFIG,
+            attr as u64,
+            &mut val as *mut u32 as u64,
+            true,
+        )
+    }
+
+    fn set_addr(&self, attr: u64, mut addr: u64) -> Result<()> {
+        self.access_attr(
+            KVM_DEV_RISCV_AIA_GRP_ADDR,
+            attr,
+            &mut addr as *mut u64 as u64,
+            true,
+        )
+    }
+
+    fn access_aplic(&self, offset: u64, val: &mut u32, write: bool) -> Result<()> {
+        self.access_attr(
+            KVM_DEV_RISCV_AIA_GRP_APLIC,
+            offset,
+            val as *mut u32 as u64,
+            write,
+        )
+    }
+
+    fn access_imsic(&self, hart: u32, iselect: u64, val: &mut u64, write: bool) -> Result<()> {
+        let attr = ((hart as u64) << KVM_DEV_RISCV_AIA_IMSIC_ISEL_BITS) | iselect;
+        self.access_attr(
+            KVM_DEV_RISCV_AIA_GRP_IMSIC,
+            attr,
+            val as *mut u64 as u64,
+            write,
+        )
+    }
+
+    /// Read or write all the registers kept in `AIAState`. The domain is
+    /// configured last so that no interrupt is delivered half restored.
+    fn access_state(&self, state: &mut AIAState, write: bool) -> Result<()> {
+        for irq in 1..=self.num_sources as usize {
+            let offset = (irq as u64 - 1) * 4;
+            self.access_aplic(
+                APLIC_SOURCECFG_BASE + offset,
+                &mut state.sourcecfg[irq],
+                write,
+            )?;
+            self.access_aplic(APLIC_TARGET_BASE + offset, &mut state.target[irq], write)?;
+        }
+        for word in 0..IRQ_WORDS {
+            let offset = word as u64 * 4;
+            self.access_aplic(APLIC_SETIP_BASE + offset, &mut state.pending[word], write)?;
+            self.access_aplic(APLIC_SETIE_BASE + offset, &mut state.enabled[word], write)?;
+        }
+
+        for hart in 0..self.num_harts {
+            let h = hart as usize;
+            for word in 0..IMSIC_EIX_WORDS {
+                let idx = h * IMSIC_EIX_WORDS + word;
+                let iselect = word as u64 * 2;
+                self.access_imsic(hart, IMSIC_EIP0 + iselect, &mut state.eip[idx], write)?;
+                self.access_imsic(hart, IMSIC_EIE0 + iselect, &mut state.eie[idx], write)?;
+            }
+            self.access_imsic(hart, IMSIC_EITHRESHOLD, &mut state.eithreshold[h], write)?;
+            self.access_imsic(hart, IMSIC_EIDELIVERY, &mut state.eidelivery[h], write)?;
+        }
+
+        self.access_aplic(APLIC_DOMAINCFG, &mut state.domaincfg, write)
+    }
+}
+
+impl StateTransfer for KvmAIA {
+    fn get_state_vec(&self) -> migration::Result<Vec<u8>> {
+        let mut state = AIAState {
+            num_sources: self.num_sources,
+            num_harts: self.num_harts,
+            ..Default::default()
+        };
+        self.access_state(&mut state, false)?;
+
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::Result<()> {
+        let mut aia_state = *AIAState::from_bytes(state)
+            .ok_or_else(|| anyhow!(MigrationError::FromBytesError("AIA")))?;
+        if aia_state.num_sources != self.num_sources || aia_state.num_harts != self.num_harts {
+            bail!(
+                "AIA mismatch: snapshot has {} sources and {} harts, expected {} and {}",
+                aia_state.num_sources,
+                aia_state.num_harts,
+                self.num_sources,
+                self.num_harts
+            );
+        }
+        self.access_state(&mut aia_state, true)?;
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&AIAState::descriptor().name) {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+impl MigrationHook for KvmAIA {}
@@ -0,0 +1,930 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use super::plic::MAX_DEVICES;
+use super::{PLICConfig, PLICDevice};
+use address_space::GuestAddress;
+use anyhow::{anyhow, bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use kvm_ioctls::VcpuFd;
+use log::error;
+use migration::{
+    snapshot::APLIC_SNAPSHOT_ID, DeviceStateDesc, FieldDesc, MigrationError, MigrationHook,
+    MigrationManager, StateTransfer,
+};
+use migration_derive::{ByteCode, Desc};
+use sysbus::{SysBus, SysBusDevOps, SysBusDevType, SysRes};
+use util::byte_code::ByteCode;
+
+/// Number of interrupt sources, source 0 does not exist.
+pub const APLIC_NUM_SOURCES: u32 = MAX_DEVICES - 1;
+/// Maximum number of harts which can be targeted by the APLIC.
+const APLIC_MAX_HARTS: u32 = 256;
+
+// Domain registers, see "The RISC-V Advanced Interrupt Architecture" chapter 4.5.
+const DOMAINCFG: u32 = 0x0000;
+const SOURCECFG_BASE: u32 = 0x0004;
+const SOURCECFG_LAST: u32 = 0x0ffc;
+const SETIP_BASE: u32 = 0x1c00;
+const SETIP_LAST: u32 = 0x1c7c;
+const SETIPNUM: u32 = 0x1cdc;
+const IN_CLRIP_BASE: u32 = 0x1d00;
+const IN_CLRIP_LAST: u32 = 0x1d7c;
+const CLRIPNUM: u32 = 0x1ddc;
+const SETIE_BASE: u32 = 0x1e00;
+const SETIE_LAST: u32 = 0x1e7c;
+const SETIENUM: u32 = 0x1edc;
+const CLRIE_BASE: u32 = 0x1f00;
+const CLRIE_LAST: u32 = 0x1f7c;
+const CLRIENUM: u32 = 0x1fdc;
+const SETIPNUM_LE: u32 = 0x2000;
+const SETIPNUM_BE: u32 = 0x2004;
+const TARGET_BASE: u32 = 0x3004;
+const TARGET_LAST: u32 = 0x3ffc;
+const BITMAP_SIZE: u32 = 0x80;
+
+// Interrupt delivery control registers, one block per hart.
+const IDC_BASE: u32 = 0x4000;
+const IDC_SIZE: u32 = 0x20;
+const IDC_IDELIVERY: u32 = 0x00;
+const IDC_IFORCE: u32 = 0x04;
+const IDC_ITHRESHOLD: u32 = 0x08;
+const IDC_TOPI: u32 = 0x18;
+const IDC_CLAIMI: u32 = 0x1c;
+
+const DOMAINCFG_RO80: u32 = 0x80 << 24;
+const DOMAINCFG_IE: u32 = 1 << 8;
+
+const SOURCECFG_D: u32 = 1 << 10;
+const SOURCECFG_SM_MASK: u32 = 0x7;
+const SOURCECFG_SM_INACTIVE: u32 = 0;
+const SOURCECFG_SM_DETACHED: u32 = 1;
+const SOURCECFG_SM_EDGE_RISE: u32 = 4;
+const SOURCECFG_SM_EDGE_FALL: u32 = 5;
+const SOURCECFG_SM_LEVEL_HIGH: u32 = 6;
+const SOURCECFG_SM_LEVEL_LOW: u32 = 7;
+
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_HART_MASK: u32 = 0x3fff;
+const TARGET_IPRIO_MASK: u32 = 0xff;
+const TOPI_ID_SHIFT: u32 = 16;
+
+const IRQ_WORDS: usize = (MAX_DEVICES / 32) as usize;
+
+/// Status of the APLIC domain registers.
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0")]
+pub struct APLICState {
+    /// Number of interrupt sources.
+    num_sources: u32,
+    /// Number of harts served by the interrupt delivery controls.
+    num_harts: u32,
+    /// Domain configuration register.
+    domaincfg: u32,
+    /// Source configuration of each interrupt source.
+    sourcecfg: [u32; 1024],
+    /// Target hart and priority of each interrupt source.
+    target: [u32; 1024],
+    /// Pending interrupt sources bitmap.
+    pending: [u32; 32],
+    /// Enabled interrupt sources bitmap.
+    enabled: [u32; 32],
+    /// Raw input level of the interrupt sources.
+    input: [u32; 32],
+    /// `idelivery` register of each hart.
+    idelivery: [u32; 256],
+    /// `iforce` register of each hart.
+    iforce: [u32; 256],
+    /// `ithreshold` register of each hart.
+    ithreshold: [u32; 256],
+}
+
+/// Interrupt delivery control of one hart, used in direct delivery mode.
+#[derive(Clone, Copy, Default)]
+struct APLICIdc {
+    idelivery: u32,
+    iforce: u32,
+    ithreshold: u32,
+}
+
+/// Userspace APLIC, a single machine-level interrupt domain delivering
+/// interrupts directly to the harts.
+pub struct APLIC {
+    ready: bool,
+    num_sources: u32,
+    domaincfg: u32,
+    sourcecfg: [u32; MAX_DEVICES as usize],
+    target: [u32; MAX_DEVICES as usize],
+    pending: [u32; IRQ_WORDS],
+    enabled: [u32; IRQ_WORDS],
+    input: [u32; IRQ_WORDS],
+    idcs: Vec<APLICIdc>,
+    vcpu_fds: Vec<Arc<VcpuFd>>,
+    /// System resource.
+    res: SysRes,
+}
+
+impl PLICDevice for APLIC {
+    fn new() -> Self {
+        APLIC {
+            ready: false,
+            num_sources: APLIC_NUM_SOURCES,
+            domaincfg: DOMAINCFG_RO80,
+            sourcecfg: [0; MAX_DEVICES as usize],
+            target: [0; MAX_DEVICES as usize],
+            pending: [0; IRQ_WORDS],
+            enabled: [0; IRQ_WORDS],
+            input: [0; IRQ_WORDS],
+            idcs: Vec::new(),
+            vcpu_fds: Vec::new(),
+            res: SysRes::default(),
+        }
+    }
+
+    fn kvm_irq_line(&mut self, irq: u32, level: u8) -> Result<()> {
+        self.set_input(irq, level != 0)
+    }
+
+    fn kvm_irq_trigger(&mut self, irq: u32) -> Result<()> {
+        self.check_irq(irq)?;
+        if !self.ready {
+            return Ok(());
+        }
+        // A triggered interrupt carries no level, latch it as pending unless
+        // the source ignores its input wire.
+        match self.source_mode(irq) {
+            SOURCECFG_SM_INACTIVE | SOURCECFG_SM_DETACHED => Ok(()),
+            _ => {
+                set_bit(&mut self.pending, irq, true);
+                self.update_hart(self.target_hart(irq))
+            }
+        }
+    }
+}
+
+impl APLIC {
+    pub fn realize(
+        mut self,
+        vcpu_fds: Vec<Arc<VcpuFd>>,
+        sysbus: &mut SysBus,
+        aplic_conf: &PLICConfig,
+    ) -> Result<Arc<Mutex<Self>>> {
+        if aplic_conf.vcpu_count > APLIC_MAX_HARTS {
+            bail!(
+                "APLIC supports at most {} harts, {} requested",
+                APLIC_MAX_HARTS,
+                aplic_conf.vcpu_count
+            );
+        }
+        self.idcs = vec![APLICIdc::default(); aplic_conf.vcpu_count as usize];
+        self.vcpu_fds = vcpu_fds;
+
+        let region_base = aplic_conf.region_base;
+        let region_size = aplic_conf.region_size;
+        if let Some(res) = self.get_sys_resource() {
+            res.region_base = region_base;
+            res.region_size = region_size;
+            res.irq = 0;
+        }
+
+        self.ready = true;
+        let dev = Arc::new(Mutex::new(self));
+        sysbus
+            .attach_device(&dev, region_base, region_size)
+            .with_context(|| "Failed to attach device")?;
+
+        MigrationManager::register_device_instance(
+            APLICState::descriptor(),
+            dev.clone(),
+            APLIC_SNAPSHOT_ID,
+        );
+
+        Ok(dev)
+    }
+
+    fn check_irq(&self, irq: u32) -> Result<()> {
+        if irq == 0 || irq > self.num_sources {
+            bail!("Invalid APLIC interrupt source {}", irq);
+        }
+        Ok(())
+    }
+
+    fn source_mode(&self, irq: u32) -> u32 {
+        self.sourcecfg[irq as usize] & SOURCECFG_SM_MASK
+    }
+
+    fn target_hart(&self, irq: u32) -> u32 {
+        (self.target[irq as usize] >> TARGET_HART_SHIFT) & TARGET_HART_MASK
+    }
+
+    /// Input value of the source after inversion for the active-low modes.
+    fn rectified_input(&self, irq: u32) -> bool {
+        let input = get_bit(&self.input, irq);
+        match self.source_mode(irq) {
+            SOURCECFG_SM_EDGE_RISE | SOURCECFG_SM_LEVEL_HIGH => input,
+            SOURCECFG_SM_EDGE_FALL | SOURCECFG_SM_LEVEL_LOW => !input,
+            _ => false,
+        }
+    }
+
+    fn set_input(&mut self, irq: u32, level: bool) -> Result<()> {
+        self.check_irq(irq)?;
+        if !self.ready {
+            return Ok(());
+        }
+
+        let old = self.rectified_input(irq);
+        set_bit(&mut self.input, irq, level);
+        let new = self.rectified_input(irq);
+        match self.source_mode(irq) {
+            SOURCECFG_SM_EDGE_RISE | SOURCECFG_SM_EDGE_FALL => {
+                if !old && new {
+                    set_bit(&mut self.pending, irq, true);
+                }
+            }
+            SOURCECFG_SM_LEVEL_HIGH | SOURCECFG_SM_LEVEL_LOW => {
+                set_bit(&mut self.pending, irq, new);
+            }
+            _ => return Ok(()),
+        }
+
+        self.update_hart(self.target_hart(irq))
+    }
+
+    /// Set or clear the pending bit on behalf of the guest.
+    fn set_pending(&mut self, irq: u32, pending: bool) {
+        if irq == 0 || irq > self.num_sources {
+            return;
+        }
+        match self.source_mode(irq) {
+            SOURCECFG_SM_INACTIVE => return,
+            // In direct delivery mode a level-sensitive source can only be
+            // made pending while its input is asserted.
+            SOURCECFG_SM_LEVEL_HIGH | SOURCECFG_SM_LEVEL_LOW => {
+                if pending && !self.rectified_input(irq) {
+                    return;
+                }
+            }
+            _ => (),
+        }
+        set_bit(&mut self.pending, irq, pending);
+    }
+
+    fn set_enabled(&mut self, irq: u32, enabled: bool) {
+        if irq == 0 || irq > self.num_sources {
+            return;
+        }
+        if enabled && self.source_mode(irq) == SOURCECFG_SM_INACTIVE {
+            return;
+        }
+        set_bit(&mut self.enabled, irq, enabled);
+    }
+
+    fn sourcecfg_write(&mut self, irq: u32, val: u32) {
+        if irq == 0 || irq > self.num_sources {
+            return;
+        }
+        // There is no child domain to delegate to, `D` is read-only zero.
+        let mut mode = if val & SOURCECFG_D != 0 {
+            SOURCECFG_SM_INACTIVE
+        } else {
+            val & SOURCECFG_SM_MASK
+        };
+        if mode != SOURCECFG_SM_DETACHED && mode < SOURCECFG_SM_EDGE_RISE {
+            mode = SOURCECFG_SM_INACTIVE;
+        }
+        self.sourcecfg[irq as usize] = mode;
+
+        match mode {
+            SOURCECFG_SM_INACTIVE => {
+                set_bit(&mut self.pending, irq, false);
+                set_bit(&mut self.enabled, irq, false);
+                self.target[irq as usize] = 0;
+            }
+            SOURCECFG_SM_LEVEL_HIGH | SOURCECFG_SM_LEVEL_LOW => {
+                let rectified = self.rectified_input(irq);
+                set_bit(&mut self.pending, irq, rectified);
+            }
+            _ => (),
+        }
+    }
+
+    fn target_write(&mut self, irq: u32, val: u32) {
+        if irq == 0 || irq > self.num_sources || self.source_mode(irq) == SOURCECFG_SM_INACTIVE {
+            return;
+        }
+        let hart = (val >> TARGET_HART_SHIFT) & TARGET_HART_MASK;
+        let mut iprio = val & TARGET_IPRIO_MASK;
+        if iprio == 0 {
+            iprio = 1;
+        }
+        self.target[irq as usize] = (hart << TARGET_HART_SHIFT) | iprio;
+    }
+
+    /// Highest priority pending and enabled interrupt targeting `hart`, in
+    /// the `topi` format. Lower priority numbers win, then lower identities.
+    fn topi(&self, hart: u32) -> u32 {
+        let threshold = self.idcs[hart as usize].ithreshold;
+        let mut best_irq = 0;
+        let mut best_prio = 0;
+        for (word, (pending, enabled)) in self.pending.iter().zip(self.enabled.iter()).enumerate() {
+            let mut bits = pending & enabled;
+            while bits != 0 {
+                let irq = word as u32 * 32 + bits.trailing_zeros();
+                bits &= bits - 1;
+                if irq > self.num_sources || self.target_hart(irq) != hart {
+                    continue;
+                }
+                let prio = self.target[irq as usize] & TARGET_IPRIO_MASK;
+                if threshold != 0 && prio >= threshold {
+                    continue;
+                }
+                if best_irq == 0 || prio < best_prio {
+                    best_irq = irq;
+                    best_prio = prio;
+                }
+            }
+        }
+
+        if best_irq == 0 {
+            return 0;
+        }
+        (best_irq << TOPI_ID_SHIFT) | best_prio
+    }
+
+    fn claimi(&mut self, hart: u32) -> Result<u32> {
+        let topi = self.topi(hart);
+        let irq = topi >> TOPI_ID_SHIFT;
+        if irq == 0 {
+            self.idcs[hart as usize].iforce = 0;
+        } else {
+            set_bit(&mut self.pending, irq, false);
+            // A level-sensitive source which is still asserted becomes
+            // pending again right away.
+            if matches!(
+                self.source_mode(irq),
+                SOURCECFG_SM_LEVEL_HIGH | SOURCECFG_SM_LEVEL_LOW
+            ) && self.rectified_input(irq)
+            {
+                set_bit(&mut self.pending, irq, true);
+            }
+        }
+
+        self.update_hart(hart)?;
+        Ok(topi)
+    }
+
+    /// Whether the external interrupt line of `hart` is asserted.
+    fn irq_line(&self, hart: u32) -> bool {
+        match self.idcs.get(hart as usize) {
+            Some(idc) => {
+                self.domaincfg & DOMAINCFG_IE != 0
+                    && idc.idelivery != 0
+                    && (idc.iforce != 0 || self.topi(hart) != 0)
+            }
+            None => false,
+        }
+    }
+
+    /// Drive the external interrupt line of `hart` according to its state.
+    fn update_hart(&self, hart: u32) -> Result<()> {
+        let vcpu_fd = match self.vcpu_fds.get(hart as usize) {
+            Some(vcpu_fd) => vcpu_fd,
+            None => return Ok(()),
+        };
+
+        if self.irq_line(hart) {
+            vcpu_fd
+                .set_interrupt()
+                .with_context(|| format!("Failed to raise interrupt of hart {}", hart))?;
+        } else {
+            vcpu_fd
+                .unset_interrupt()
+                .with_context(|| format!("Failed to clear interrupt of hart {}", hart))?;
+        }
+        Ok(())
+    }
+
+    fn update_all_harts(&self) -> Result<()> {
+        for hart in 0..self.idcs.len() {
+            self.update_hart(hart as u32)?;
+        }
+        Ok(())
+    }
+
+    fn domain_read(&self, offset: u32) -> u32 {
+        match offset {
+            DOMAINCFG => self.domaincfg,
+            SOURCECFG_BASE..=SOURCECFG_LAST => {
+                self.sourcecfg[((offset - SOURCECFG_BASE) / 4 + 1) as usize]
+            }
+            SETIP_BASE..=SETIP_LAST => self.pending[((offset - SETIP_BASE) / 4) as usize],
+            IN_CLRIP_BASE..=IN_CLRIP_LAST => {
+                let word = (offset - IN_CLRIP_BASE) / 4;
+                let mut val = 0;
+                for bit in 0..32 {
+                    let irq = word * 32 + bit;
+                    if irq != 0 && irq <= self.num_sources && self.rectified_input(irq) {
+                        val |= 1 << bit;
+                    }
+                }
+                val
+            }
+            SETIE_BASE..=SETIE_LAST => self.enabled[((offset - SETIE_BASE) / 4) as usize],
+            TARGET_BASE..=TARGET_LAST => self.target[((offset - TARGET_BASE) / 4 + 1) as usize],
+            _ => 0,
+        }
+    }
+
+    fn domain_write(&mut self, offset: u32, val: u32) -> Result<()> {
+        match offset {
+            DOMAINCFG => {
+                // Only direct delivery mode is emulated, `DM` and `BE` are
+                // read-only zero.
+                self.domaincfg = DOMAINCFG_RO80 | (val & DOMAINCFG_IE);
+            }
+            SOURCECFG_BASE..=SOURCECFG_LAST => {
+                self.sourcecfg_write((offset - SOURCECFG_BASE) / 4 + 1, val);
+            }
+            SETIP_BASE..=SETIP_LAST | IN_CLRIP_BASE..=IN_CLRIP_LAST => {
+                let set = offset < IN_CLRIP_BASE;
+                let word = (offset & (BITMAP_SIZE - 1)) / 4;
+                for bit in 0..32 {
+                    if val & (1 << bit) != 0 {
+                        self.set_pending(word * 32 + bit, set);
+                    }
+                }
+            }
+            SETIE_BASE..=SETIE_LAST | CLRIE_BASE..=CLRIE_LAST => {
+                let set = offset < CLRIE_BASE;
+                let word = (offset & (BITMAP_SIZE - 1)) / 4;
+                for bit in 0..32 {
+                    if val & (1 << bit) != 0 {
+                        self.set_enabled(word * 32 + bit, set);
+                    }
+                }
+            }
+            SETIPNUM | SETIPNUM_LE => self.set_pending(val, true),
+            SETIPNUM_BE => self.set_pending(val.swap_bytes(), true),
+            CLRIPNUM => self.set_pending(val, false),
+            SETIENUM => self.set_enabled(val, true),
+            CLRIENUM => self.set_enabled(val, false),
+            TARGET_BASE..=TARGET_LAST => self.target_write((offset - TARGET_BASE) / 4 + 1, val),
+            _ => return Ok(()),
+        }
+
+        self.update_all_harts()
+    }
+
+    fn idc_read(&mut self, hart: u32, offset: u32) -> Result<u32> {
+        let idc = match self.idcs.get(hart as usize) {
+            Some(idc) => *idc,
+            None => return Ok(0),
+        };
+        let val = match offset {
+            IDC_IDELIVERY => idc.idelivery,
+            IDC_IFORCE => idc.iforce,
+            IDC_ITHRESHOLD => idc.ithreshold,
+            IDC_TOPI => self.topi(hart),
+            IDC_CLAIMI => self.claimi(hart)?,
+            _ => 0,
+        };
+        Ok(val)
+    }
+
+    fn idc_write(&mut self, hart: u32, offset: u32, val: u32) -> Result<()> {
+        let idc = match self.idcs.get_mut(hart as usize) {
+            Some(idc) => idc,
+            None => return Ok(()),
+        };
+        match offset {
+            IDC_IDELIVERY => idc.idelivery = val & 1,
+            IDC_IFORCE => idc.iforce = val & 1,
+            IDC_ITHRESHOLD => idc.ithreshold = val & TARGET_IPRIO_MASK,
+            _ => return Ok(()),
+        }
+
+        self.update_hart(hart)
+    }
+}
+
+fn get_bit(bitmap: &[u32], irq: u32) -> bool {
+    bitmap[(irq / 32) as usize] & (1 << (irq % 32)) != 0
+}
+
+fn set_bit(bitmap: &mut [u32], irq: u32, val: bool) {
+    if val {
+        bitmap[(irq / 32) as usize] |= 1 << (irq % 32);
+    } else {
+        bitmap[(irq / 32) as usize] &= !(1 << (irq % 32));
+    }
+}
+
+impl SysBusDevOps for APLIC {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> bool {
+        if data.len() != 4 {
+            error!(
+                "APLIC only supports 32-bit accesses, got {} bytes",
+                data.len()
+            );
+            return false;
+        }
+
+        let offset = offset as u32 & !0x3;
+        let val = if offset < IDC_BASE {
+            self.domain_read(offset)
+        } else {
+            let hart = (offset - IDC_BASE) / IDC_SIZE;
+            match self.idc_read(hart, (offset - IDC_BASE) % IDC_SIZE) {
+                Ok(val) => val,
+                Err(e) => {
+                    error!("Failed to read APLIC idc register: {:?}", e);
+                    return false;
+                }
+            }
+        };
+        LittleEndian::write_u32(data, val);
+
+        true
+    }
+
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> bool {
+        if data.len() != 4 {
+            error!(
+                "APLIC only supports 32-bit accesses, got {} bytes",
+                data.len()
+            );
+            return false;
+        }
+
+        let offset = offset as u32 & !0x3;
+        let val = LittleEndian::read_u32(data);
+        let ret = if offset < IDC_BASE {
+            self.domain_write(offset, val)
+        } else {
+            let hart = (offset - IDC_BASE) / IDC_SIZE;
+            self.idc_write(hart, (offset - IDC_BASE) % IDC_SIZE, val)
+        };
+        if let Err(e) = ret {
+            error!("Failed to write APLIC register: {:?}", e);
+            return false;
+        }
+
+        true
+    }
+
+    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+        Some(&mut self.res)
+    }
+
+    fn get_type(&self) -> SysBusDevType {
+        SysBusDevType::Aplic
+    }
+
+    fn reset(&mut self) -> sysbus::Result<()> {
+        // The input wires belong to the devices, they are kept as is.
+        self.domaincfg = DOMAINCFG_RO80;
+        self.sourcecfg = [0; MAX_DEVICES as usize];
+        self.target = [0; MAX_DEVICES as usize];
+        self.pending = [0; IRQ_WORDS];
+        self.enabled = [0; IRQ_WORDS];
+        for idc in self.idcs.iter_mut() {
+            *idc = APLICIdc::default();
+        }
+
+        self.update_all_harts()
+    }
+}
+
+impl StateTransfer for APLIC {
+    fn get_state_vec(&self) -> migration::Result<Vec<u8>> {
+        let mut state = APLICState {
+            num_sources: self.num_sources,
+            num_harts: self.idcs.len() as u32,
+            domaincfg: self.domaincfg,
+            sourcecfg: self.sourcecfg,
+            target: self.target,
+            pending: self.pending,
+            enabled: self.enabled,
+            input: self.input,
+            ..Default::default()
+        };
+        for (hart, idc) in self.idcs.iter().enumerate() {
+            state.idelivery[hart] = idc.idelivery;
+            state.iforce[hart] = idc.iforce;
+            state.ithreshold[hart] = idc.ithreshold;
+        }
+
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::Result<()> {
+        let aplic_state = *APLICState::from_bytes(state)
+            .ok_or_else(|| anyhow!(MigrationError::FromBytesError("APLIC")))?;
+        if aplic_state.num_sources != self.num_sources
+            || aplic_state.num_harts != self.idcs.len() as u32
+        {
+            bail!(
+                "APLIC mismatch: snapshot has {} sources and {} harts, expected {} and {}",
+                aplic_state.num_sources,
+                aplic_state.num_harts,
+                self.num_sources,
+                self.idcs.len()
+            );
+        }
+        self.domaincfg = aplic_state.domaincfg;
+        self.sourcecfg = aplic_state.sourcecfg;
+        self.target = aplic_state.target;
+        self.pending = aplic_state.pending;
+        self.enabled = aplic_state.enabled;
+        self.input = aplic_state.input;
+        for (hart, idc) in self.idcs.iter_mut().enumerate() {
+            idc.idelivery = aplic_state.idelivery[hart];
+            idc.iforce = aplic_state.iforce[hart];
+            idc.ithreshold = aplic_state.ithreshold[hart];
+        }
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&APLICState::descriptor().name) {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+impl MigrationHook for APLIC {
+    fn resume(&mut self) -> migration::Result<()> {
+        self.update_all_harts()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE: u32 = 5;
+
+    fn aplic_init(nr_harts: u32) -> APLIC {
+        let mut aplic = APLIC::new();
+        aplic.idcs = vec![APLICIdc::default(); nr_harts as usize];
+        aplic.ready = true;
+        aplic
+    }
+
+    fn mmio_read(aplic: &mut APLIC, offset: u32) -> u32 {
+        let mut data = [0_u8; 4];
+        assert!(aplic.read(&mut data, GuestAddress(0), offset as u64));
+        LittleEndian::read_u32(&data)
+    }
+
+    fn mmio_write(aplic: &mut APLIC, offset: u32, val: u32) {
+        let mut data = [0_u8; 4];
+        LittleEndian::write_u32(&mut data, val);
+        assert!(aplic.write(&data, GuestAddress(0), offset as u64));
+    }
+
+    fn set_sourcecfg(aplic: &mut APLIC, irq: u32, val: u32) {
+        mmio_write(aplic, SOURCECFG_BASE + (irq - 1) * 4, val);
+    }
+
+    fn set_target(aplic: &mut APLIC, irq: u32, hart: u32, iprio: u32) {
+        mmio_write(
+            aplic,
+            TARGET_BASE + (irq - 1) * 4,
+            hart << TARGET_HART_SHIFT | iprio,
+        );
+    }
+
+    fn idc_offset(hart: u32, reg: u32) -> u32 {
+        IDC_BASE + hart * IDC_SIZE + reg
+    }
+
+    /// Enable the domain and the delivery of every hart, then route `irq`
+    /// as an edge-triggered source to `hart`.
+    fn setup_source(aplic: &mut APLIC, irq: u32, hart: u32, iprio: u32) {
+        mmio_write(aplic, DOMAINCFG, DOMAINCFG_IE);
+        for idc in 0..aplic.idcs.len() as u32 {
+            mmio_write(aplic, idc_offset(idc, IDC_IDELIVERY), 1);
+        }
+        set_sourcecfg(aplic, irq, SOURCECFG_SM_EDGE_RISE);
+        set_target(aplic, irq, hart, iprio);
+        mmio_write(aplic, SETIENUM, irq);
+    }
+
+    fn claim(aplic: &mut APLIC, hart: u32) -> u32 {
+        mmio_read(aplic, idc_offset(hart, IDC_CLAIMI)) >> TOPI_ID_SHIFT
+    }
+
+    #[test]
+    fn test_aplic_domaincfg() {
+        let mut aplic = aplic_init(1);
+        assert_eq!(mmio_read(&mut aplic, DOMAINCFG), DOMAINCFG_RO80);
+
+        // Only `IE` is writable, `DM` and `BE` stay zero.
+        mmio_write(&mut aplic, DOMAINCFG, 0xffff_ffff);
+        assert_eq!(
+            mmio_read(&mut aplic, DOMAINCFG),
+            DOMAINCFG_RO80 | DOMAINCFG_IE
+        );
+
+        // Nothing is delivered while the domain is disabled.
+        setup_source(&mut aplic, SOURCE, 0, 1);
+        mmio_write(&mut aplic, DOMAINCFG, 0);
+        aplic.kvm_irq_trigger(SOURCE).unwrap();
+        assert!(!aplic.irq_line(0));
+        mmio_write(&mut aplic, DOMAINCFG, DOMAINCFG_IE);
+        assert!(aplic.irq_line(0));
+
+        // Accesses other than 32 bits are rejected.
+        let mut data = [0_u8; 2];
+        assert!(!aplic.read(&mut data, GuestAddress(0), DOMAINCFG as u64));
+        assert!(!aplic.write(&data, GuestAddress(0), DOMAINCFG as u64));
+    }
+
+    #[test]
+    fn test_aplic_sourcecfg() {
+        let mut aplic = aplic_init(1);
+        let offset = SOURCECFG_BASE + (SOURCE - 1) * 4;
+
+        set_sourcecfg(&mut aplic, SOURCE, SOURCECFG_SM_LEVEL_LOW);
+        assert_eq!(mmio_read(&mut aplic, offset), SOURCECFG_SM_LEVEL_LOW);
+
+        // Reserved modes and delegation to a child domain make it inactive.
+        set_sourcecfg(&mut aplic, SOURCE, 2);
+        assert_eq!(mmio_read(&mut aplic, offset), SOURCECFG_SM_INACTIVE);
+        set_sourcecfg(&mut aplic, SOURCE, SOURCECFG_D | SOURCECFG_SM_EDGE_RISE);
+        assert_eq!(mmio_read(&mut aplic, offset), SOURCECFG_SM_INACTIVE);
+
+        // An inactive source can't be enabled, made pending or targeted.
+        mmio_write(&mut aplic, SETIENUM, SOURCE);
+        mmio_write(&mut aplic, SETIPNUM, SOURCE);
+        set_target(&mut aplic, SOURCE, 0, 3);
+        assert_eq!(mmio_read(&mut aplic, SETIE_BASE), 0);
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE), 0);
+        assert_eq!(mmio_read(&mut aplic, TARGET_BASE + (SOURCE - 1) * 4), 0);
+
+        // A level source with its input asserted is pending as soon as it
+        // is configured, and the input is reported inverted for active-low.
+        aplic.kvm_irq_line(SOURCE, 1).unwrap();
+        set_sourcecfg(&mut aplic, SOURCE, SOURCECFG_SM_LEVEL_HIGH);
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE), 1 << SOURCE);
+        assert_eq!(mmio_read(&mut aplic, IN_CLRIP_BASE), 1 << SOURCE);
+        set_sourcecfg(&mut aplic, SOURCE, SOURCECFG_SM_LEVEL_LOW);
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE), 0);
+        assert_eq!(mmio_read(&mut aplic, IN_CLRIP_BASE), 0);
+
+        // Becoming inactive drops the pending and enabled bits.
+        set_sourcecfg(&mut aplic, SOURCE, SOURCECFG_SM_EDGE_RISE);
+        mmio_write(&mut aplic, SETIENUM, SOURCE);
+        mmio_write(&mut aplic, SETIPNUM, SOURCE);
+        set_sourcecfg(&mut aplic, SOURCE, SOURCECFG_SM_INACTIVE);
+        assert_eq!(mmio_read(&mut aplic, SETIE_BASE), 0);
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE), 0);
+
+        // Source 0 does not exist.
+        assert!(aplic.kvm_irq_trigger(0).is_err());
+        assert!(aplic.kvm_irq_line(APLIC_NUM_SOURCES + 1, 1).is_err());
+    }
+
+    #[test]
+    fn test_aplic_setip_setie_clrip() {
+        let mut aplic = aplic_init(1);
+        set_sourcecfg(&mut aplic, 3, SOURCECFG_SM_EDGE_RISE);
+        set_sourcecfg(&mut aplic, 40, SOURCECFG_SM_EDGE_FALL);
+
+        // Bitmap and number registers act on the same state, bits of
+        // inactive sources are ignored.
+        mmio_write(&mut aplic, SETIP_BASE, 1 << 3 | 1 << 4);
+        mmio_write(&mut aplic, SETIPNUM_LE, 40);
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE), 1 << 3);
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE + 4), 1 << (40 - 32));
+        mmio_write(&mut aplic, IN_CLRIP_BASE + 4, 1 << (40 - 32));
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE + 4), 0);
+        mmio_write(&mut aplic, SETIPNUM_BE, 40_u32.swap_bytes());
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE + 4), 1 << (40 - 32));
+        mmio_write(&mut aplic, CLRIPNUM, 3);
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE), 0);
+
+        mmio_write(&mut aplic, SETIE_BASE, 1 << 3 | 1 << 4);
+        mmio_write(&mut aplic, SETIENUM, 40);
+        assert_eq!(mmio_read(&mut aplic, SETIE_BASE), 1 << 3);
+        assert_eq!(mmio_read(&mut aplic, SETIE_BASE + 4), 1 << (40 - 32));
+        mmio_write(&mut aplic, CLRIE_BASE, 1 << 3);
+        mmio_write(&mut aplic, CLRIENUM, 40);
+        assert_eq!(mmio_read(&mut aplic, SETIE_BASE), 0);
+        assert_eq!(mmio_read(&mut aplic, SETIE_BASE + 4), 0);
+
+        // A level source can only be made pending while its input is
+        // asserted.
+        set_sourcecfg(&mut aplic, SOURCE, SOURCECFG_SM_LEVEL_HIGH);
+        mmio_write(&mut aplic, SETIPNUM, SOURCE);
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE), 0);
+        aplic.kvm_irq_line(SOURCE, 1).unwrap();
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE), 1 << SOURCE);
+        mmio_write(&mut aplic, CLRIPNUM, SOURCE);
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE), 0);
+        mmio_write(&mut aplic, SETIPNUM, SOURCE);
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE), 1 << SOURCE);
+    }
+
+    #[test]
+    fn test_aplic_target_routing() {
+        let mut aplic = aplic_init(4);
+        setup_source(&mut aplic, SOURCE, 2, 1);
+
+        // A zero priority is turned into 1.
+        set_target(&mut aplic, SOURCE, 2, 0);
+        assert_eq!(
+            mmio_read(&mut aplic, TARGET_BASE + (SOURCE - 1) * 4),
+            2 << TARGET_HART_SHIFT | 1
+        );
+
+        aplic.kvm_irq_trigger(SOURCE).unwrap();
+        for hart in 0..4 {
+            assert_eq!(aplic.irq_line(hart), hart == 2);
+        }
+        assert_eq!(claim(&mut aplic, 0), 0);
+        assert_eq!(mmio_read(&mut aplic, SETIP_BASE), 1 << SOURCE);
+
+        // Retargeting a pending source moves the interrupt to the new hart.
+        set_target(&mut aplic, SOURCE, 3, 1);
+        assert!(!aplic.irq_line(2));
+        assert!(aplic.irq_line(3));
+        assert_eq!(claim(&mut aplic, 2), 0);
+        assert_eq!(claim(&mut aplic, 3), SOURCE);
+        assert!(!aplic.irq_line(3));
+
+        // Harts without delivery enabled get no interrupt.
+        mmio_write(&mut aplic, idc_offset(3, IDC_IDELIVERY), 0);
+        aplic.kvm_irq_trigger(SOURCE).unwrap();
+        assert!(!aplic.irq_line(3));
+        assert_eq!(
+            mmio_read(&mut aplic, idc_offset(3, IDC_TOPI)),
+            SOURCE << TOPI_ID_SHIFT | 1
+        );
+    }
+
+    #[test]
+    fn test_aplic_claim_complete() {
+        let mut aplic = aplic_init(2);
+        setup_source(&mut aplic, 3, 1, 5);
+        setup_source(&mut aplic, 40, 1, 2);
+        setup_source(&mut aplic, 41, 1, 2);
+        for irq in [3, 40, 41] {
+            aplic.kvm_irq_trigger(irq).unwrap();
+        }
+
+        // Sources with priority 2 and above are masked by the threshold.
+        mmio_write(&mut aplic, idc_offset(1, IDC_ITHRESHOLD), 2);
+        assert!(!aplic.irq_line(1));
+        assert_eq!(claim(&mut aplic, 1), 0);
+
+        // Lowest priority number first, ties go to the lowest source.
+        mmio_write(&mut aplic, idc_offset(1, IDC_ITHRESHOLD), 0);
+        assert_eq!(
+            mmio_read(&mut aplic, idc_offset(1, IDC_TOPI)),
+            40 << TOPI_ID_SHIFT | 2
+        );
+        assert_eq!(claim(&mut aplic, 1), 40);
+        assert_eq!(claim(&mut aplic, 1), 41);
+        assert!(aplic.irq_line(1));
+        assert_eq!(claim(&mut aplic, 1), 3);
+        assert!(!aplic.irq_line(1));
+        assert_eq!(claim(&mut aplic, 1), 0);
+
+        // A level source still asserted is pending again once claimed.
+        set_sourcecfg(&mut aplic, SOURCE, SOURCECFG_SM_LEVEL_HIGH);
+        set_target(&mut aplic, SOURCE, 1, 1);
+        mmio_write(&mut aplic, SETIENUM, SOURCE);
+        aplic.kvm_irq_line(SOURCE, 1).unwrap();
+        assert_eq!(claim(&mut aplic, 1), SOURCE);
+        assert!(aplic.irq_line(1));
+        aplic.kvm_irq_line(SOURCE, 0).unwrap();
+        assert!(!aplic.irq_line(1));
+        assert_eq!(claim(&mut aplic, 1), 0);
+
+        // `iforce` raises the line until a claim finds nothing pending.
+        mmio_write(&mut aplic, idc_offset(0, IDC_IFORCE), 1);
+        assert!(aplic.irq_line(0));
+        assert_eq!(claim(&mut aplic, 0), 0);
+        assert_eq!(mmio_read(&mut aplic, idc_offset(0, IDC_IFORCE)), 0);
+        assert!(!aplic.irq_line(0));
+    }
+}
@@ -11,17 +11,24 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+pub mod aia;
+pub mod aplic;
 pub mod plic;
+pub use aia::KvmAIA;
+pub use aplic::APLIC;
 pub use plic::PLIC;
 
 use std::sync::{Arc, Mutex};
 use sysbus::SysBus;
 use kvm_ioctls::VcpuFd;
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use vmm_sys_util::eventfd::EventFd;
 
 /// PLIC version type.
 pub enum PLICVersion {
     PLIC,
+    APLIC,
+    AIA,
  }
 
  pub struct PLICConfig {
@@ -31,6 +38,9 @@ pub enum PLICVersion {
     pub vcpu_count: u32,
     pub region_base: u64,
     pub region_size: u64,
+    /// Region of the per-hart IMSIC interrupt files, only used by the AIA.
+    pub imsic_base: u64,
+    pub imsic_size: u64,
 }
 
 pub trait PLICDevice {
@@ -38,9 +48,25 @@ pub trait PLICDevice {
     where
         Self: Sized;
     
-    fn kvm_irq_line(&self, irq: u8, level: u8) -> Result<()>;
+    fn kvm_irq_line(&mut self, irq: u32, level: u8) -> Result<()>;
 
-    fn kvm_irq_trigger(&self, irq: u8) -> Result<()>;
+    fn kvm_irq_trigger(&mut self, irq: u32) -> Result<()>;
+
+    /// Let KVM inject `irq` whenever `evt` is written, returns false when the
+    /// interrupt controller is emulated in userspace and can't do so.
+    fn register_irqfd(&self, _evt: &EventFd, _irq: u32) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Whether the interrupt controller accepts message signaled interrupts.
+    fn msi_supported(&self) -> bool {
+        false
+    }
+
+    /// Deliver the MSI writing `data` at guest physical address `addr`.
+    fn signal_msi(&self, _addr: u64, _data: u32) -> Result<()> {
+        bail!("MSI is not supported by the interrupt controller");
+    }
 }
 
 /// A wrapper around creating and using a interrupt controller.
@@ -57,6 +83,18 @@ impl InterruptController {
                     plic: plic,
                 }
             },
+            Some(PLICVersion::APLIC) => {
+                let aplic = APLIC::new().realize(vcpu_fds, sysbus, config)?;
+                InterruptController {
+                    plic: aplic,
+                }
+            },
+            Some(PLICVersion::AIA) => {
+                let aia = KvmAIA::new().realize(config)?;
+                InterruptController {
+                    plic: aia,
+                }
+            },
             None => {
                 let plic = PLIC::new().realize(vcpu_fds, sysbus, config)?;
                 InterruptController {
@@ -67,15 +105,26 @@ impl InterruptController {
         Ok(intc)
     }
 
-    pub fn kvm_irq_line(&self, irq: u8, level: u8) -> Result<()> {
+    pub fn kvm_irq_line(&self, irq: u32, level: u8) -> Result<()> {
         self.plic.lock().unwrap().kvm_irq_line(irq, level)?;
         Ok(())
     }
 
-    pub fn kvm_irq_trigger(&self, irq: u8) -> Result<()> {
+    pub fn kvm_irq_trigger(&self, irq: u32) -> Result<()> {
         self.plic.lock().unwrap().kvm_irq_trigger(irq)?;
         Ok(())
     }
 
+    pub fn register_irqfd(&self, evt: &EventFd, irq: u32) -> Result<bool> {
+        self.plic.lock().unwrap().register_irqfd(evt, irq)
+    }
+
+    pub fn msi_supported(&self) -> bool {
+        self.plic.lock().unwrap().msi_supported()
+    }
+
+    pub fn signal_msi(&self, addr: u64, data: u32) -> Result<()> {
+        self.plic.lock().unwrap().signal_msi(addr, data)
+    }
 }
 
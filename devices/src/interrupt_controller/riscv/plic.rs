@@ -13,19 +13,29 @@
 
 use std::sync::{Arc, Mutex};
 
-use anyhow::{anyhow, Context, Result};
-use sysbus::{SysBus, SysBusDevOps, SysBusDevType, SysRes};
+use super::{PLICConfig, PLICDevice};
 use address_space::GuestAddress;
+use anyhow::{anyhow, bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
 use kvm_ioctls::VcpuFd;
-use super::{PLICConfig, PLICDevice};
-use log::{debug, error};
+use log::error;
+use migration::{
+    snapshot::PLIC_SNAPSHOT_ID, DeviceStateDesc, FieldDesc, MigrationError, MigrationHook,
+    MigrationManager, StateTransfer,
+};
+use migration_derive::{ByteCode, Desc};
+use sysbus::{SysBus, SysBusDevOps, SysBusDevType, SysRes};
+use util::byte_code::ByteCode;
 
 pub const MAX_DEVICES: u32 = 1024;
-const MAX_CONTEXTS: u32 = 15872; 
+const MAX_CONTEXTS: u32 = 15872;
+const MAX_PRIORITY: u8 = 0xf;
 
 const PRIORITY_BASE: u32 = 0;
-const PRIORITY_PER_ID: u32 =4;
- 
+const PRIORITY_PER_ID: u32 = 4;
+
+const PENDING_BASE: u32 = 0x1000;
+
 const ENABLE_BASE: u32 = 0x2000;
 const ENABLE_PER_HART: u32 = 0x80;
 
@@ -34,48 +44,101 @@ const CONTEXT_PER_HART: u32 = 0x1000;
 const CONTEXT_THRESHOLD: u32 = 0;
 const CONTEXT_CLAIM: u32 = 4;
 
-const REG_SIZE: u32 = 0x0100_0000; 
+const REG_SIZE: u32 = 0x0100_0000;
 
+const IRQ_WORDS: usize = (MAX_DEVICES / 32) as usize;
 
-#[derive(Clone,Debug)]
-struct PLICContext{
+/// Status of the PLIC registers shared by all contexts.
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(current_version = "0.2.0", compat_version = "0.2.0")]
+pub struct PLICState {
+    /// Number of interrupt sources.
+    num_irq: u32,
+    /// Number of contexts, two for each hart.
+    num_context: u32,
+    /// Priority of each interrupt source.
+    irq_priority: [u8; 1024],
+    /// Pending interrupt sources bitmap.
+    irq_pending: [u32; 32],
+    /// Claimed interrupt sources bitmap, waiting for completion.
+    irq_claimed: [u32; 32],
+    /// Input level of the level-triggered interrupt sources.
+    irq_level: [u32; 32],
+}
+
+/// Status of the registers of one PLIC context.
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(current_version = "0.2.0", compat_version = "0.2.0")]
+pub struct PLICContextState {
+    /// Index of the context.
     num: u32,
+    /// Priority threshold of the context.
     irq_priority_threshold: u8,
-    vcpu_fd: Arc<VcpuFd>,
-    irq_enable: [u32; (MAX_DEVICES/32) as usize],
-    irq_pending: [u32; (MAX_DEVICES/32) as usize],
-    irq_pending_priority: [u8; MAX_DEVICES as usize],
-    irq_claimed: [u32; (MAX_DEVICES/32) as usize],
-    irq_autoclear: [u32; (MAX_DEVICES/32) as usize],
+    /// Enabled interrupt sources bitmap.
+    irq_enable: [u32; 32],
 }
 
+/// Target of the interrupts, the even contexts of a hart are the machine-mode
+/// ones and the odd contexts the supervisor-mode ones.
+struct PLICContext {
+    num: u32,
+    irq_priority_threshold: u8,
+    irq_enable: [u32; IRQ_WORDS],
+    /// Vcpu whose supervisor external interrupt is driven by the context, None
+    /// for the machine-mode contexts which are not exposed to the guest.
+    vcpu_fd: Option<Arc<VcpuFd>>,
+    /// Deliverable source with the highest priority among the enabled ones,
+    /// 0 if there is none.
+    best_irq: u32,
+    /// Level of the external interrupt line of the context.
+    irq_line: bool,
+}
 
 impl PLICContext {
-    fn new(vcpu_fd:Arc<VcpuFd>) -> Self {
+    fn new(num: u32, vcpu_fd: Option<Arc<VcpuFd>>) -> Self {
         Self {
-           num: 0,
-           irq_priority_threshold: 0,
-           vcpu_fd: vcpu_fd,
-           irq_enable: [0; (MAX_DEVICES/32) as usize],
-           irq_pending: [0; (MAX_DEVICES/32) as usize],
-           irq_pending_priority: [0; MAX_DEVICES as usize],
-           irq_claimed: [0; (MAX_DEVICES/32) as usize],
-           irq_autoclear: [0; (MAX_DEVICES/32) as usize]
+            num,
+            irq_priority_threshold: 0,
+            irq_enable: [0; IRQ_WORDS],
+            vcpu_fd,
+            best_irq: 0,
+            irq_line: false,
         }
     }
-}
 
+    fn is_enabled(&self, irq: u32) -> bool {
+        get_bit(&self.irq_enable, irq)
+    }
+
+    /// Drive the external interrupt line of the vcpu, if any.
+    fn set_line(&mut self, line: bool) -> Result<()> {
+        self.irq_line = line;
+        if let Some(vcpu_fd) = &self.vcpu_fd {
+            let ret = if line {
+                vcpu_fd.set_interrupt()
+            } else {
+                vcpu_fd.unset_interrupt()
+            };
+            ret.with_context(|| format!("Failed to update interrupt of context {}", self.num))?;
+        }
+        Ok(())
+    }
+}
 
 pub struct PLIC {
     ready: bool,
     num_irq: u32,
-    num_irq_word:u32,
-    max_prio:u32,
-
-    num_context:u32,
-    contexts:Vec<Arc<Mutex<PLICContext>>>,
-
+    num_irq_word: u32,
+    num_context: u32,
+    contexts: Vec<Arc<Mutex<PLICContext>>>,
     irq_priority: [u8; MAX_DEVICES as usize],
+    /// Pending bits are global, the source is delivered to every context that
+    /// enabled it and the first one to claim it wins.
+    irq_pending: [u32; IRQ_WORDS],
+    irq_claimed: [u32; IRQ_WORDS],
+    irq_level: [u32; IRQ_WORDS],
     /// System resource.
     res: SysRes,
 }
@@ -85,54 +148,37 @@ impl PLICDevice for PLIC {
         PLIC {
             ready: false,
             num_irq: MAX_DEVICES,
-            num_irq_word: MAX_DEVICES,
-            max_prio: (1 << PRIORITY_PER_ID) - 1,
-            num_context: MAX_CONTEXTS,
-            contexts: Vec::<Arc<Mutex<PLICContext>>>::new(),
+            num_irq_word: MAX_DEVICES / 32,
+            num_context: 0,
+            contexts: Vec::new(),
             irq_priority: [0; MAX_DEVICES as usize],
-            /// System resource.
+            irq_pending: [0; IRQ_WORDS],
+            irq_claimed: [0; IRQ_WORDS],
+            irq_level: [0; IRQ_WORDS],
             res: SysRes::default(),
         }
-        
     }
 
-    fn kvm_irq_line(&self, irq: u8, level: u8) -> Result<()> {
-        self.plic_irq_trig(irq, level, false)?;
-        Ok(())
+    fn kvm_irq_line(&mut self, irq: u32, level: u8) -> Result<()> {
+        self.plic_irq_trig(irq, level != 0, false)
     }
 
-    fn kvm_irq_trigger(&self, irq: u8) -> Result<()> {
-        self.plic_irq_trig(irq, 1, true)?;
-        Ok(())
+    fn kvm_irq_trigger(&mut self, irq: u32) -> Result<()> {
+        self.plic_irq_trig(irq, true, true)
     }
 }
 
 impl PLIC {
-    pub fn realize(mut self, 
+    pub fn realize(
+        mut self,
         vcpu_fds: Vec<Arc<VcpuFd>>,
         sysbus: &mut SysBus,
         plic_conf: &PLICConfig,
-    ) -> Result<Arc<Mutex<Self>>> {    
+    ) -> Result<Arc<Mutex<Self>>> {
+        self.init_contexts(plic_conf.vcpu_count, &vcpu_fds)?;
 
-        self.num_irq_word = self.num_irq / 32;
-        if self.num_irq_word * 32 < self.num_irq{
-            self.num_irq_word += 1;
-        }
-
-        self.num_context = plic_conf.vcpu_count * 2;
-        
-        let mut contexts = Vec::<Arc<Mutex<PLICContext>>>::new();
-        for i in 0..self.num_context {
-            let vcpu_fd = vcpu_fds[(i / 2) as usize].clone();
-            let mut context = PLICContext::new(vcpu_fd);
-            context.num = i;
-            contexts.push(Arc::new(Mutex::new(context)));
-        }
-        self.contexts = contexts;
-       
         let region_base = plic_conf.region_base;
         let region_size = plic_conf.region_size;
-
         if let Some(res) = self.get_sys_resource() {
             res.region_base = region_base;
             res.region_size = region_size;
@@ -140,288 +186,377 @@ impl PLIC {
         }
 
         self.ready = true;
+        let contexts = self.contexts.clone();
         let dev = Arc::new(Mutex::new(self));
-        sysbus.attach_device(&dev, region_base, region_size).with_context(|| "Failed to attach device")?;
+        sysbus
+            .attach_device(&dev, region_base, region_size)
+            .with_context(|| "Failed to attach device")?;
+
+        MigrationManager::register_device_instance(
+            PLICState::descriptor(),
+            dev.clone(),
+            PLIC_SNAPSHOT_ID,
+        );
+        for (i, context) in contexts.into_iter().enumerate() {
+            MigrationManager::register_device_instance(
+                PLICContextState::descriptor(),
+                context,
+                &format!("{}_{}", PLIC_SNAPSHOT_ID, i),
+            );
+        }
 
         Ok(dev)
     }
 
-    fn context_best_pending_irq(&self, context: &Arc<Mutex<PLICContext>>) -> Result<u32> {
-        let mut best_irq_prio = 0;
-        let mut best_irq:u32 = 0;
-        let mut i = 0;
-        while i < self.num_irq_word {
-            let context= context.lock().unwrap();
-            if context.irq_pending[i as usize] == 0 {
-                i += 1;
-                continue;
-            }
-
-            let mut j = 0;
-            while j < 32 {
-                let irq = i * 32 + j;
-                if (self.num_irq <= irq) ||
-                ((context.irq_pending[i as usize] & (1 << j) ) == 0) ||
-                ((context.irq_claimed[i as usize] & (1 << j)) != 0) {
-                    j += 1;
-                    continue;
-                }
-                if (best_irq == 0) || (best_irq_prio < context.irq_pending_priority[irq as usize]) {
-                    best_irq = irq;
-                    best_irq_prio = context.irq_pending_priority[irq as usize];
-                }
-                j += 1;
-            }
-            i += 1;
+    fn init_contexts(&mut self, vcpu_count: u32, vcpu_fds: &[Arc<VcpuFd>]) -> Result<()> {
+        self.num_context = vcpu_count * 2;
+        if self.num_context > MAX_CONTEXTS {
+            bail!(
+                "PLIC supports at most {} harts, {} requested",
+                MAX_CONTEXTS / 2,
+                vcpu_count
+            );
         }
 
-        Ok(best_irq)
+        self.contexts = (0..self.num_context)
+            .map(|i| {
+                let vcpu_fd = if i % 2 == 1 {
+                    vcpu_fds.get((i / 2) as usize).cloned()
+                } else {
+                    None
+                };
+                Arc::new(Mutex::new(PLICContext::new(i, vcpu_fd)))
+            })
+            .collect();
+        Ok(())
     }
 
-    fn context_irq_update(&self, context: &Arc<Mutex<PLICContext>>) -> Result<()> {
-        let vcpu_fd = context.lock().unwrap().vcpu_fd.clone();
-        let best_irq = self.context_best_pending_irq(context)?;
-        if best_irq > 0 {
-            vcpu_fd.set_interrupt();
-        } 
-        else {
-            vcpu_fd.unset_interrupt();
-        };
-        
+    fn check_irq(&self, irq: u32) -> Result<()> {
+        if irq == 0 || irq >= self.num_irq {
+            bail!("Invalid PLIC interrupt source {}", irq);
+        }
         Ok(())
     }
 
-    pub fn plic_irq_trig(&self, irq: u8, level: u8, edge: bool) -> Result<()> {
-        let mut irq_marked = false;
-        
-        if !self.ready {return Ok(());}
-
-        let irq_prio = self.irq_priority[irq as usize];
-        let irq_word = (irq / 32) as usize;
-        let irq_mask = 1 << (irq % 32);
-
-        let mut i = 0;
-        while i < self.num_context {
-            let mut context = self.contexts[i as usize].lock().unwrap();
-            if (context.irq_enable[irq_word] & irq_mask) != 0 {
-                if level != 0 {
-                    context.irq_pending[irq_word] |= irq_mask;
-                    context.irq_pending_priority[irq as usize] = irq_prio;
-                    if edge{
-                        context.irq_autoclear[irq_word] |= irq_mask;
-                    }
-                }
-                else {
-                    context.irq_pending[irq_word] &= !irq_mask;
-                    context.irq_pending_priority[irq as usize] = 0;
-                    context.irq_claimed[irq_word] &= !irq_mask;
-                    context.irq_autoclear[irq_word] &= !irq_mask;
+    /// Whether the source can be delivered: pending and not in service.
+    fn irq_deliverable(&self, irq: u32) -> bool {
+        get_bit(&self.irq_pending, irq) && !get_bit(&self.irq_claimed, irq)
+    }
+
+    /// Higher priority wins, ties are broken in favor of the lowest ID.
+    /// Sources with priority 0 are never delivered.
+    fn irq_better(&self, irq: u32, than: u32) -> bool {
+        let prio = self.irq_priority[irq as usize];
+        prio > 0
+            && (than == 0
+                || prio > self.irq_priority[than as usize]
+                || (prio == self.irq_priority[than as usize] && irq < than))
+    }
+
+    /// Look for the best deliverable source of the context, only the words
+    /// holding deliverable enabled sources are visited.
+    fn context_best_irq(&self, context: &PLICContext) -> u32 {
+        let mut best_irq = 0;
+        for word in 0..self.num_irq_word as usize {
+            let mut bits =
+                self.irq_pending[word] & !self.irq_claimed[word] & context.irq_enable[word];
+            while bits != 0 {
+                let irq = word as u32 * 32 + bits.trailing_zeros();
+                bits &= bits - 1;
+                if self.irq_better(irq, best_irq) {
+                    best_irq = irq;
                 }
-                self.context_irq_update(&Arc::new(Mutex::new(context.clone())))?;
-                irq_marked = true;
             }
-            if irq_marked {break;}
-            i += 1;
         }
+        best_irq
+    }
 
+    /// Update the interrupt line of the context after its best source or its
+    /// threshold changed, the vcpu is only kicked when the line flips.
+    fn context_update_line(&self, context: &mut PLICContext) -> Result<()> {
+        let line = context.best_irq != 0
+            && self.irq_priority[context.best_irq as usize] > context.irq_priority_threshold;
+        if line != context.irq_line {
+            context.set_line(line)?;
+        }
         Ok(())
     }
 
-    fn context_irq_claim(&self, context: &Arc<Mutex<PLICContext>>) -> Result<u32> {
-        let vcpu_fd = context.lock().unwrap().vcpu_fd.clone();
-        let best_irq = self.context_best_pending_irq(context)?;
-        let best_irq_word = best_irq / 32;
-        let best_irq_mask = 1 << (best_irq % 32);
+    fn context_rescan(&self, context: &mut PLICContext) -> Result<()> {
+        context.best_irq = self.context_best_irq(context);
+        self.context_update_line(context)
+    }
 
-        vcpu_fd.unset_interrupt();
+    fn update_all_contexts(&self) -> Result<()> {
+        for context in self.contexts.iter() {
+            self.context_rescan(&mut context.lock().unwrap())?;
+        }
+        Ok(())
+    }
 
-        let mut context = context.lock().unwrap();
-        if best_irq > 0 {
-            if (context.irq_autoclear[best_irq_word as usize] & best_irq_mask) != 0 {
-                context.irq_pending[best_irq_word as usize] &= !best_irq_mask;
-                context.irq_pending_priority[best_irq as usize] = 0;
-                context.irq_claimed[best_irq_word as usize] &= !best_irq_mask;
-                context.irq_autoclear[best_irq_word as usize] &= !best_irq_mask;
+    /// The source became deliverable, it can only replace the best source of
+    /// the contexts which enabled it.
+    fn irq_raised(&self, irq: u32) -> Result<()> {
+        for context in self.contexts.iter() {
+            let mut locked_context = context.lock().unwrap();
+            if locked_context.is_enabled(irq) && self.irq_better(irq, locked_context.best_irq) {
+                locked_context.best_irq = irq;
+                self.context_update_line(&mut locked_context)?;
             }
-            else {
-                context.irq_claimed[best_irq_word as usize] |= best_irq_mask;
+        }
+        Ok(())
+    }
+
+    /// The source is no longer deliverable, the contexts which selected it
+    /// look for another one.
+    fn irq_lowered(&self, irq: u32) -> Result<()> {
+        for context in self.contexts.iter() {
+            let mut locked_context = context.lock().unwrap();
+            if locked_context.best_irq == irq {
+                self.context_rescan(&mut locked_context)?;
             }
         }
+        Ok(())
+    }
 
-        self.context_irq_update(&Arc::new(Mutex::new(context.clone())))?;
-        Ok(best_irq)
+    fn set_pending(&mut self, irq: u32, pending: bool) -> Result<()> {
+        let old = self.irq_deliverable(irq);
+        set_bit(&mut self.irq_pending, irq, pending);
+        match (old, self.irq_deliverable(irq)) {
+            (false, true) => self.irq_raised(irq),
+            (true, false) => self.irq_lowered(irq),
+            _ => Ok(()),
+        }
     }
 
-    fn priority_read(&self, offset: u32, data: &mut [u8]) -> Result<()> {
-        let irq:u32 = offset >> 2;
-        if (irq == 0) || (irq >= self.num_irq)  {return Ok(());}
-        data[0] = self.irq_priority[irq as usize];
+    /// Interrupt gateway of the sources. Edge-triggered requests are latched
+    /// as pending, level-triggered ones follow the input level but are held
+    /// back while the source is in service.
+    pub fn plic_irq_trig(&mut self, irq: u32, level: bool, edge: bool) -> Result<()> {
+        self.check_irq(irq)?;
+        if !self.ready {
+            return Ok(());
+        }
+
+        if edge {
+            if level {
+                self.set_pending(irq, true)?;
+            }
+            return Ok(());
+        }
+
+        set_bit(&mut self.irq_level, irq, level);
+        if !level || !get_bit(&self.irq_claimed, irq) {
+            self.set_pending(irq, level)?;
+        }
         Ok(())
     }
 
-    fn priority_write(&mut self, offset: u32, data: &[u8]) -> Result<()> {
-        let irq:u32 = offset >> 2;
-        if (irq == 0) || (irq >= self.num_irq)  {return Ok(());}
-        let mut val= data[0];
-        val &= (1 << PRIORITY_PER_ID) - 1;
-        self.irq_priority[irq as usize] = val ;
-        Ok(())
+    fn context_claim(&mut self, cntx: u32) -> Result<u32> {
+        let irq = {
+            let context = self.contexts[cntx as usize].lock().unwrap();
+            if context.irq_line {
+                context.best_irq
+            } else {
+                0
+            }
+        };
+        if irq == 0 {
+            return Ok(0);
+        }
+
+        set_bit(&mut self.irq_pending, irq, false);
+        set_bit(&mut self.irq_claimed, irq, true);
+        self.irq_lowered(irq)?;
+        Ok(irq)
     }
 
-    fn context_enable_read(&self, context: &Arc<Mutex<PLICContext>>, offset: u32, data: &mut [u8]) -> Result<()> {
-        let irq_word:u32 = offset >> 2;
-        if self.num_irq_word < irq_word   {return Ok(());}
-        data[0] = context.lock().unwrap().irq_enable[irq_word as usize] as u8;
+    fn context_complete(&mut self, cntx: u32, irq: u32) -> Result<()> {
+        // Completions of sources which are not enabled for the context or
+        // not in service are silently ignored.
+        if irq == 0 || irq >= self.num_irq || !get_bit(&self.irq_claimed, irq) {
+            return Ok(());
+        }
+        if !self.contexts[cntx as usize].lock().unwrap().is_enabled(irq) {
+            return Ok(());
+        }
+
+        set_bit(&mut self.irq_claimed, irq, false);
+        if get_bit(&self.irq_level, irq) {
+            set_bit(&mut self.irq_pending, irq, true);
+        }
+        if self.irq_deliverable(irq) {
+            self.irq_raised(irq)?;
+        }
         Ok(())
     }
 
-    fn context_enable_write(&self, context: &Arc<Mutex<PLICContext>>, offset: u32, data: &[u8]) -> Result<()> {
-        let irq_word:u32 = offset >> 2;
+    fn priority_read(&self, offset: u32) -> u32 {
+        let irq = offset / PRIORITY_PER_ID;
+        if irq == 0 || irq >= self.num_irq {
+            return 0;
+        }
+        self.irq_priority[irq as usize] as u32
+    }
 
-        if self.num_irq_word < irq_word  {return Ok(());}
+    fn priority_write(&mut self, offset: u32, val: u32) -> Result<()> {
+        let irq = offset / PRIORITY_PER_ID;
+        if irq == 0 || irq >= self.num_irq {
+            return Ok(());
+        }
+        self.irq_priority[irq as usize] = val as u8 & MAX_PRIORITY;
+        // Changing the priorities is rare, simply look at every context again.
+        self.update_all_contexts()
+    }
 
-        let mut context = context.lock().unwrap();
+    fn pending_read(&self, offset: u32) -> u32 {
+        let word = offset / 4;
+        if word >= self.num_irq_word {
+            return 0;
+        }
+        self.irq_pending[word as usize]
+    }
 
-        let old_val:u32 = context.irq_enable[irq_word as usize];
-        let mut new_val= data[0] as u32;
-        if irq_word == 0 {
-            new_val &= !0x1;
+    fn context_enable_read(&self, cntx: u32, offset: u32) -> u32 {
+        let word = offset / 4;
+        if word >= self.num_irq_word {
+            return 0;
         }
-        context.irq_enable[irq_word as usize] = new_val;
+        self.contexts[cntx as usize].lock().unwrap().irq_enable[word as usize]
+    }
 
-        let xor_val:u32 = old_val ^ new_val;
-        let mut i = 0;
-        while i < 32 {
-            let irq = irq_word * 32 + i;
-            let irq_mask:u32 = 1 << i;
-            let irq_prio:u8 = self.irq_priority[irq as usize];
-            if (xor_val & irq_mask) == 0 {
-                i += 1;
-                continue;
-            }
-            if (new_val & irq_mask) == 0{
-                context.irq_pending[irq_word as usize] &= !irq_mask;
-                context.irq_pending_priority[irq as usize] = 0;
-                context.irq_claimed[irq_word as usize] &= !irq_mask;
-            }
-            i += 1;
+    fn context_enable_write(&self, cntx: u32, offset: u32, mut val: u32) -> Result<()> {
+        let word = offset / 4;
+        if word >= self.num_irq_word {
+            return Ok(());
+        }
+        // Source 0 does not exist.
+        if word == 0 {
+            val &= !0x1;
         }
 
-        self.context_irq_update(&Arc::new(Mutex::new(context.clone())))?;
-        Ok(())
+        let mut context = self.contexts[cntx as usize].lock().unwrap();
+        context.irq_enable[word as usize] = val;
+        self.context_rescan(&mut context)
     }
 
-    fn context_read(&self, context: &Arc<Mutex<PLICContext>>, offset: u32, data: &mut [u8]) -> Result<()> {
+    fn context_read(&mut self, cntx: u32, offset: u32) -> Result<u32> {
         match offset {
-            CONTEXT_THRESHOLD=> {
-                data[0] = context.lock().unwrap().irq_priority_threshold;
-            } 
-            CONTEXT_CLAIM => {
-                data[0] = self.context_irq_claim(context).unwrap() as u8;
-            }
-            _ => ()
+            CONTEXT_THRESHOLD => Ok(self.contexts[cntx as usize]
+                .lock()
+                .unwrap()
+                .irq_priority_threshold as u32),
+            CONTEXT_CLAIM => self.context_claim(cntx),
+            _ => Ok(0),
         }
-        Ok(())
     }
- 
-    fn context_write(&self, context: &Arc<Mutex<PLICContext>>, offset: u32, data: &[u8]) -> Result<()> {
-        let mut irq_update = false;
+
+    fn context_write(&mut self, cntx: u32, offset: u32, val: u32) -> Result<()> {
         match offset {
             CONTEXT_THRESHOLD => {
-                let mut val= data[0] as u32;
-                val &= (1 << PRIORITY_PER_ID) - 1;
-                if val <= self.max_prio {
-                    context.lock().unwrap().irq_priority_threshold = val as u8;
-                }
-                else {
-                    irq_update = true;
-                }
-            }
-            CONTEXT_CLAIM =>{
-                let val= data[0] as u32;
-                let irq_word = val / 32;
-                let irq_mask = 1 << (val % 32);
-                if (val < self.num_irq) && 
-                    ((context.lock().unwrap().irq_enable[irq_word as usize] & irq_mask) != 0) {
-                            context.lock().unwrap().irq_claimed[irq_word as usize] &= !irq_mask;
-                            irq_update = true;
-                }
+                let mut context = self.contexts[cntx as usize].lock().unwrap();
+                context.irq_priority_threshold = val as u8 & MAX_PRIORITY;
+                self.context_update_line(&mut context)
             }
-            _ =>{
-                irq_update = true;
-            }
-        }
-        if irq_update {
-            self.context_irq_update(context)?;
+            CONTEXT_CLAIM => self.context_complete(cntx, val),
+            _ => Ok(()),
         }
-        Ok(())
     }
+}
+
+fn get_bit(bitmap: &[u32], irq: u32) -> bool {
+    bitmap[(irq / 32) as usize] & (1 << (irq % 32)) != 0
+}
 
+fn set_bit(bitmap: &mut [u32], irq: u32, val: bool) {
+    if val {
+        bitmap[(irq / 32) as usize] |= 1 << (irq % 32);
+    } else {
+        bitmap[(irq / 32) as usize] &= !(1 << (irq % 32));
+    }
 }
 
 impl SysBusDevOps for PLIC {
     fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> bool {
-        let mut addr = offset as u32;
-        addr &= !0x3;
-        if PRIORITY_BASE <= addr && addr < ENABLE_BASE {
-            if self.priority_read(addr, data).is_err() {
-                error!("Failed to read priority register");
-                return false;
-            }
+        if data.len() != 4 {
+            error!(
+                "PLIC only supports 32-bit accesses, got {} bytes",
+                data.len()
+            );
+            return false;
         }
-        else if ENABLE_BASE <= addr && addr < CONTEXT_BASE {
-            let cntx:u32 = (addr - ENABLE_BASE) / ENABLE_PER_HART;
-            addr -= cntx * ENABLE_PER_HART + ENABLE_BASE;
-            if cntx < self.num_context  {
-                if self.context_enable_read(self.contexts.get(cntx as usize).unwrap(), addr, data).is_err() {
-                    error!("Failed to read enable register");
-                    return false;
+
+        let addr = offset as u32 & !0x3;
+        let val = if addr < PENDING_BASE {
+            self.priority_read(addr - PRIORITY_BASE)
+        } else if addr < ENABLE_BASE {
+            self.pending_read(addr - PENDING_BASE)
+        } else if addr < CONTEXT_BASE {
+            let cntx = (addr - ENABLE_BASE) / ENABLE_PER_HART;
+            if cntx >= self.num_context {
+                0
+            } else {
+                self.context_enable_read(cntx, (addr - ENABLE_BASE) % ENABLE_PER_HART)
+            }
+        } else if addr < REG_SIZE {
+            let cntx = (addr - CONTEXT_BASE) / CONTEXT_PER_HART;
+            if cntx >= self.num_context {
+                0
+            } else {
+                match self.context_read(cntx, (addr - CONTEXT_BASE) % CONTEXT_PER_HART) {
+                    Ok(val) => val,
+                    Err(e) => {
+                        error!("Failed to read PLIC context {}: {:?}", cntx, e);
+                        return false;
+                    }
                 }
-            } 
-        }
-        else if CONTEXT_BASE <= addr && addr < REG_SIZE {
-            let cntx:u32 = (addr - CONTEXT_BASE) / CONTEXT_PER_HART;
-            addr -= cntx * CONTEXT_PER_HART + CONTEXT_BASE;
-            if cntx < self.num_context {
-               if self.context_read(self.contexts.get(cntx as usize).unwrap(), addr, data).is_err() {
-                    error!("Failed to read context");
-                    return false;
-               }
-            } 
-        }
-        
+            }
+        } else {
+            0
+        };
+        LittleEndian::write_u32(data, val);
+
         true
     }
 
     fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> bool {
-        let mut addr = offset as u32;
-        addr &= !0x3;
-        if PRIORITY_BASE <= addr && addr < ENABLE_BASE {
-            if self.priority_write(addr, data).is_err() {
-                error!("Failed to write priority register");
-                return false;
-            }
+        if data.len() != 4 {
+            error!(
+                "PLIC only supports 32-bit accesses, got {} bytes",
+                data.len()
+            );
+            return false;
         }
-        else if ENABLE_BASE <= addr && addr < CONTEXT_BASE {
-            let cntx:u32 = (addr - ENABLE_BASE) / ENABLE_PER_HART;
-            addr -= cntx * ENABLE_PER_HART + ENABLE_BASE;
-            if cntx < self.num_context {
-                if self.context_enable_write(self.contexts.get(cntx as usize).unwrap(), addr, data).is_err() {
-                    error!("Failed to write enable register");
-                    return false;
-                }
-            } 
-        }
-        else if CONTEXT_BASE <= addr && addr < REG_SIZE {
-            let cntx:u32 = (addr - CONTEXT_BASE) / CONTEXT_PER_HART;
-            addr -= cntx * CONTEXT_PER_HART + CONTEXT_BASE;
-            if cntx < self.num_context {
-                if self.context_write(self.contexts.get(cntx as usize).unwrap(), addr, data).is_err() {
-                    error!("Failed to write context");
-                    return false;
-                }
-            } 
+
+        let addr = offset as u32 & !0x3;
+        let val = LittleEndian::read_u32(data);
+        let ret = if addr < PENDING_BASE {
+            self.priority_write(addr - PRIORITY_BASE, val)
+        } else if addr < ENABLE_BASE {
+            // The pending bits are read-only.
+            Ok(())
+        } else if addr < CONTEXT_BASE {
+            let cntx = (addr - ENABLE_BASE) / ENABLE_PER_HART;
+            if cntx >= self.num_context {
+                Ok(())
+            } else {
+                self.context_enable_write(cntx, (addr - ENABLE_BASE) % ENABLE_PER_HART, val)
+            }
+        } else if addr < REG_SIZE {
+            let cntx = (addr - CONTEXT_BASE) / CONTEXT_PER_HART;
+            if cntx >= self.num_context {
+                Ok(())
+            } else {
+                self.context_write(cntx, (addr - CONTEXT_BASE) % CONTEXT_PER_HART, val)
+            }
+        } else {
+            Ok(())
+        };
+        if let Err(e) = ret {
+            error!("Failed to write PLIC register: {:?}", e);
+            return false;
         }
+
         true
     }
 
@@ -432,5 +567,320 @@ impl SysBusDevOps for PLIC {
     fn get_type(&self) -> SysBusDevType {
         SysBusDevType::Plic
     }
+
+    fn reset(&mut self) -> sysbus::Result<()> {
+        // The input wires belong to the devices, the level-triggered sources
+        // which are still asserted are pending again.
+        self.irq_priority = [0; MAX_DEVICES as usize];
+        self.irq_pending = self.irq_level;
+        self.irq_claimed = [0; IRQ_WORDS];
+        for context in self.contexts.iter() {
+            let mut locked_context = context.lock().unwrap();
+            locked_context.irq_priority_threshold = 0;
+            locked_context.irq_enable = [0; IRQ_WORDS];
+            locked_context.best_irq = 0;
+            locked_context.set_line(false)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl StateTransfer for PLIC {
+    fn get_state_vec(&self) -> migration::Result<Vec<u8>> {
+        let state = PLICState {
+            num_irq: self.num_irq,
+            num_context: self.num_context,
+            irq_priority: self.irq_priority,
+            irq_pending: self.irq_pending,
+            irq_claimed: self.irq_claimed,
+            irq_level: self.irq_level,
+        };
+
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::Result<()> {
+        let plic_state = *PLICState::from_bytes(state)
+            .ok_or_else(|| anyhow!(MigrationError::FromBytesError("PLIC")))?;
+        if plic_state.num_irq != self.num_irq || plic_state.num_context != self.num_context {
+            bail!(
+                "PLIC mismatch: snapshot has {} sources and {} contexts, expected {} and {}",
+                plic_state.num_irq,
+                plic_state.num_context,
+                self.num_irq,
+                self.num_context
+            );
+        }
+        self.irq_priority = plic_state.irq_priority;
+        self.irq_pending = plic_state.irq_pending;
+        self.irq_claimed = plic_state.irq_claimed;
+        self.irq_level = plic_state.irq_level;
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&PLICState::descriptor().name) {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+impl MigrationHook for PLIC {
+    fn resume(&mut self) -> migration::Result<()> {
+        // Contexts are restored separately, compute their best source once
+        // all of them are in place and drive every line as the vcpus may
+        // have been restored with a stale external interrupt.
+        for context in self.contexts.iter() {
+            let mut locked_context = context.lock().unwrap();
+            locked_context.best_irq = self.context_best_irq(&locked_context);
+            let line = locked_context.best_irq != 0
+                && self.irq_priority[locked_context.best_irq as usize]
+                    > locked_context.irq_priority_threshold;
+            locked_context.set_line(line)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl StateTransfer for PLICContext {
+    fn get_state_vec(&self) -> migration::Result<Vec<u8>> {
+        let state = PLICContextState {
+            num: self.num,
+            irq_priority_threshold: self.irq_priority_threshold,
+            irq_enable: self.irq_enable,
+        };
+
+        Ok(state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::Result<()> {
+        let context_state = *PLICContextState::from_bytes(state)
+            .ok_or_else(|| anyhow!(MigrationError::FromBytesError("PLIC_CONTEXT")))?;
+        if context_state.num != self.num {
+            bail!(
+                "PLIC context mismatch: snapshot has context {}, expected {}",
+                context_state.num,
+                self.num
+            );
+        }
+        self.irq_priority_threshold = context_state.irq_priority_threshold;
+        self.irq_enable = context_state.irq_enable;
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&PLICContextState::descriptor().name)
+        {
+            alias
+        } else {
+            !0
+        }
+    }
 }
 
+impl MigrationHook for PLICContext {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE: u32 = 5;
+
+    fn plic_init(nr_harts: u32) -> PLIC {
+        let mut plic = PLIC::new();
+        plic.init_contexts(nr_harts, &[]).unwrap();
+        plic.ready = true;
+        plic
+    }
+
+    fn mmio_read(plic: &mut PLIC, offset: u32) -> u32 {
+        let mut data = [0_u8; 4];
+        assert!(plic.read(&mut data, GuestAddress(0), offset as u64));
+        LittleEndian::read_u32(&data)
+    }
+
+    fn mmio_write(plic: &mut PLIC, offset: u32, val: u32) {
+        let mut data = [0_u8; 4];
+        LittleEndian::write_u32(&mut data, val);
+        assert!(plic.write(&data, GuestAddress(0), offset as u64));
+    }
+
+    fn set_priority(plic: &mut PLIC, irq: u32, prio: u32) {
+        mmio_write(plic, PRIORITY_BASE + irq * PRIORITY_PER_ID, prio);
+    }
+
+    fn enable(plic: &mut PLIC, cntx: u32, irq: u32) {
+        let offset = ENABLE_BASE + cntx * ENABLE_PER_HART + irq / 32 * 4;
+        let val = mmio_read(plic, offset) | 1 << (irq % 32);
+        mmio_write(plic, offset, val);
+    }
+
+    fn set_threshold(plic: &mut PLIC, cntx: u32, threshold: u32) {
+        let offset = CONTEXT_BASE + cntx * CONTEXT_PER_HART + CONTEXT_THRESHOLD;
+        mmio_write(plic, offset, threshold);
+    }
+
+    fn claim(plic: &mut PLIC, cntx: u32) -> u32 {
+        mmio_read(plic, CONTEXT_BASE + cntx * CONTEXT_PER_HART + CONTEXT_CLAIM)
+    }
+
+    fn complete(plic: &mut PLIC, cntx: u32, irq: u32) {
+        let offset = CONTEXT_BASE + cntx * CONTEXT_PER_HART + CONTEXT_CLAIM;
+        mmio_write(plic, offset, irq);
+    }
+
+    fn line(plic: &PLIC, cntx: u32) -> bool {
+        plic.contexts[cntx as usize].lock().unwrap().irq_line
+    }
+
+    #[test]
+    fn test_plic_claim_race() {
+        let mut plic = plic_init(2);
+        set_priority(&mut plic, SOURCE, 1);
+        enable(&mut plic, 1, SOURCE);
+        enable(&mut plic, 3, SOURCE);
+
+        plic.kvm_irq_trigger(SOURCE).unwrap();
+        assert!(line(&plic, 1));
+        assert!(line(&plic, 3));
+        assert!(!line(&plic, 0));
+        assert_eq!(mmio_read(&mut plic, PENDING_BASE), 1 << SOURCE);
+
+        // The second hart loses the race and gets nothing to handle.
+        assert_eq!(claim(&mut plic, 3), SOURCE);
+        assert!(!line(&plic, 1));
+        assert!(!line(&plic, 3));
+        assert_eq!(claim(&mut plic, 1), 0);
+        assert_eq!(mmio_read(&mut plic, PENDING_BASE), 0);
+
+        // A new edge is held back until the source is completed.
+        plic.kvm_irq_trigger(SOURCE).unwrap();
+        assert!(!line(&plic, 1));
+        assert_eq!(claim(&mut plic, 1), 0);
+        complete(&mut plic, 3, SOURCE);
+        assert!(line(&plic, 1));
+        assert!(line(&plic, 3));
+        assert_eq!(claim(&mut plic, 1), SOURCE);
+        assert_eq!(claim(&mut plic, 3), 0);
+        complete(&mut plic, 1, SOURCE);
+        assert!(!line(&plic, 1));
+        assert!(!line(&plic, 3));
+    }
+
+    #[test]
+    fn test_plic_target_other_hart() {
+        let mut plic = plic_init(4);
+        set_priority(&mut plic, SOURCE, 1);
+        enable(&mut plic, 5, SOURCE);
+
+        plic.kvm_irq_trigger(SOURCE).unwrap();
+        for cntx in 0..8 {
+            assert_eq!(line(&plic, cntx), cntx == 5);
+        }
+        assert_eq!(claim(&mut plic, 1), 0);
+        assert_eq!(claim(&mut plic, 5), SOURCE);
+
+        // Completion from a context which did not enable the source is ignored.
+        complete(&mut plic, 1, SOURCE);
+        plic.kvm_irq_trigger(SOURCE).unwrap();
+        assert!(!line(&plic, 5));
+        complete(&mut plic, 5, SOURCE);
+        assert!(line(&plic, 5));
+    }
+
+    #[test]
+    fn test_plic_priority_and_threshold() {
+        let mut plic = plic_init(2);
+        set_priority(&mut plic, 3, 2);
+        set_priority(&mut plic, 40, 5);
+        set_priority(&mut plic, 41, 5);
+        for irq in [3, 40, 41] {
+            enable(&mut plic, 1, irq);
+            enable(&mut plic, 3, irq);
+            plic.kvm_irq_trigger(irq).unwrap();
+        }
+
+        // Hart 1 masks everything up to priority 5.
+        set_threshold(&mut plic, 3, 5);
+        assert!(!line(&plic, 3));
+        assert_eq!(claim(&mut plic, 3), 0);
+
+        // Highest priority first, ties go to the lowest source.
+        assert_eq!(claim(&mut plic, 1), 40);
+        assert_eq!(claim(&mut plic, 1), 41);
+        assert!(line(&plic, 1));
+        set_threshold(&mut plic, 1, 2);
+        assert!(!line(&plic, 1));
+        assert_eq!(claim(&mut plic, 1), 0);
+        set_threshold(&mut plic, 3, 1);
+        assert!(line(&plic, 3));
+        assert_eq!(claim(&mut plic, 3), 3);
+        assert_eq!(mmio_read(&mut plic, PENDING_BASE), 0);
+
+        // Priority 0 never interrupts.
+        set_threshold(&mut plic, 1, 0);
+        complete(&mut plic, 3, 3);
+        plic.kvm_irq_trigger(3).unwrap();
+        assert!(line(&plic, 1));
+        set_priority(&mut plic, 3, 0);
+        assert!(!line(&plic, 1));
+        assert_eq!(claim(&mut plic, 1), 0);
+    }
+
+    #[test]
+    fn test_plic_level_source() {
+        let mut plic = plic_init(2);
+        set_priority(&mut plic, SOURCE, 1);
+        enable(&mut plic, 1, SOURCE);
+        enable(&mut plic, 3, SOURCE);
+
+        plic.kvm_irq_line(SOURCE, 1).unwrap();
+        assert_eq!(claim(&mut plic, 1), SOURCE);
+        assert_eq!(claim(&mut plic, 3), 0);
+
+        // Still asserted on completion, the source is pending again.
+        complete(&mut plic, 1, SOURCE);
+        assert!(line(&plic, 1));
+        assert!(line(&plic, 3));
+        assert_eq!(claim(&mut plic, 3), SOURCE);
+
+        // Deasserted while in service, nothing is left after completion.
+        plic.kvm_irq_line(SOURCE, 0).unwrap();
+        complete(&mut plic, 3, SOURCE);
+        assert!(!line(&plic, 1));
+        assert!(!line(&plic, 3));
+        assert_eq!(claim(&mut plic, 1), 0);
+
+        // Deasserted before being claimed, the request is withdrawn.
+        plic.kvm_irq_line(SOURCE, 1).unwrap();
+        assert!(line(&plic, 1));
+        plic.kvm_irq_line(SOURCE, 0).unwrap();
+        assert!(!line(&plic, 1));
+        assert_eq!(claim(&mut plic, 1), 0);
+    }
+
+    #[test]
+    fn test_plic_enable_rescan() {
+        let mut plic = plic_init(1);
+        set_priority(&mut plic, 7, 1);
+        set_priority(&mut plic, 100, 3);
+        plic.kvm_irq_trigger(7).unwrap();
+        plic.kvm_irq_trigger(100).unwrap();
+        assert!(!line(&plic, 1));
+
+        enable(&mut plic, 1, 7);
+        assert!(line(&plic, 1));
+        enable(&mut plic, 1, 100);
+        mmio_write(&mut plic, ENABLE_BASE + ENABLE_PER_HART, 0);
+        assert!(line(&plic, 1));
+        assert_eq!(claim(&mut plic, 1), 100);
+        assert_eq!(claim(&mut plic, 1), 0);
+        assert_eq!(mmio_read(&mut plic, PENDING_BASE), 1 << 7);
+    }
+}
@@ -1075,7 +1075,7 @@ mod test {
         let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
         #[cfg(target_arch = "x86_64")]
         let sys_io = AddressSpace::new(Region::init_container_region(1 << 16)).unwrap();
-        let free_irqs: (i32, i32) = (IRQ_BASE, IRQ_MAX);
+        let free_irqs: (u32, u32) = (IRQ_BASE, IRQ_MAX);
         let mmio_region: (u64, u64) = (0x0A00_0000, 0x1000_0000);
         SysBus::new(
             #[cfg(target_arch = "x86_64")]
@@ -192,7 +192,7 @@ impl Serial {
             if irq_chip
                 .lock()
                 .unwrap()
-                .kvm_irq_line(self.get_sys_resource().unwrap().irq as u8, 1)
+                .kvm_irq_line(self.get_sys_resource().unwrap().irq, 1)
                 .is_err()
             {
                 error!("serial: failed to update iir.");
@@ -202,7 +202,7 @@ impl Serial {
             if irq_chip
                 .lock()
                 .unwrap()
-                .kvm_irq_line(self.get_sys_resource().unwrap().irq as u8, 0)
+                .kvm_irq_line(self.get_sys_resource().unwrap().irq, 0)
                 .is_err()
             {
                 error!("serial: failed to update iir.");
@@ -398,6 +398,12 @@ impl SysBusDevOps for Serial {
     fn get_type(&self) -> SysBusDevType {
         SysBusDevType::Serial
     }
+
+    fn reset(&mut self) -> sysbus::Result<()> {
+        self.rbr.clear();
+        self.state = SerialState::new();
+        Ok(())
+    }
 }
 
 impl StateTransfer for Serial {
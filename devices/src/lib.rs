@@ -22,6 +22,7 @@ pub mod legacy;
 
 #[cfg(target_arch = "riscv64")]
 pub use interrupt_controller::{
-     InterruptController, InterruptControllerConfig, MAX_DEVICES
+     InterruptController, InterruptControllerConfig, InterruptControllerVersion, KvmAIA,
+     AIA_APLIC_SIZE, AIA_IMSIC_SIZE, AIA_NUM_IDS, MAX_DEVICES,
 };
 pub use legacy::error::LegacyError as LegacyErrs;
\ No newline at end of file
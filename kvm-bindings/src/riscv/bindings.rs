@@ -66,6 +66,48 @@ pub const KVM_REG_RISCV_CSR: u32 = 50331648;
 pub const KVM_REG_RISCV_TIMER: u32 = 67108864;
 pub const KVM_REG_RISCV_FP_F: u32 = 83886080;
 pub const KVM_REG_RISCV_FP_D: u32 = 100663296;
+pub const KVM_REG_RISCV_ISA_EXT: u32 = 117440512;
+pub const KVM_REG_RISCV_SBI_EXT: u32 = 134217728;
+pub const KVM_REG_RISCV_SUBTYPE_MASK: u32 = 16711680;
+pub const KVM_REG_RISCV_SUBTYPE_SHIFT: u32 = 16;
+pub const KVM_REG_RISCV_ISA_SINGLE: u32 = 0;
+pub const KVM_REG_RISCV_SBI_SINGLE: u32 = 0;
+pub const KVM_REG_RISCV_CSR_GENERAL: u32 = 0;
+pub const KVM_REG_RISCV_CSR_AIA: u32 = 65536;
+pub const KVM_DEV_RISCV_APLIC_ALIGN: u32 = 4096;
+pub const KVM_DEV_RISCV_APLIC_SIZE: u32 = 16384;
+pub const KVM_DEV_RISCV_APLIC_MAX_HARTS: u32 = 16384;
+pub const KVM_DEV_RISCV_IMSIC_ALIGN: u32 = 4096;
+pub const KVM_DEV_RISCV_IMSIC_SIZE: u32 = 4096;
+pub const KVM_DEV_RISCV_AIA_GRP_CONFIG: u32 = 0;
+pub const KVM_DEV_RISCV_AIA_CONFIG_MODE: u32 = 0;
+pub const KVM_DEV_RISCV_AIA_CONFIG_IDS: u32 = 1;
+pub const KVM_DEV_RISCV_AIA_CONFIG_SRCS: u32 = 2;
+pub const KVM_DEV_RISCV_AIA_CONFIG_GROUP_BITS: u32 = 3;
+pub const KVM_DEV_RISCV_AIA_CONFIG_GROUP_SHIFT: u32 = 4;
+pub const KVM_DEV_RISCV_AIA_CONFIG_HART_BITS: u32 = 5;
+pub const KVM_DEV_RISCV_AIA_CONFIG_GUEST_BITS: u32 = 6;
+pub const KVM_DEV_RISCV_AIA_MODE_EMUL: u32 = 0;
+pub const KVM_DEV_RISCV_AIA_MODE_HWACCEL: u32 = 1;
+pub const KVM_DEV_RISCV_AIA_MODE_AUTO: u32 = 2;
+pub const KVM_DEV_RISCV_AIA_IDS_MIN: u32 = 63;
+pub const KVM_DEV_RISCV_AIA_IDS_MAX: u32 = 2048;
+pub const KVM_DEV_RISCV_AIA_SRCS_MAX: u32 = 1024;
+pub const KVM_DEV_RISCV_AIA_GROUP_BITS_MAX: u32 = 8;
+pub const KVM_DEV_RISCV_AIA_GROUP_SHIFT_MIN: u32 = 24;
+pub const KVM_DEV_RISCV_AIA_GROUP_SHIFT_MAX: u32 = 56;
+pub const KVM_DEV_RISCV_AIA_HART_BITS_MAX: u32 = 16;
+pub const KVM_DEV_RISCV_AIA_GUEST_BITS_MAX: u32 = 8;
+pub const KVM_DEV_RISCV_AIA_GRP_ADDR: u32 = 1;
+pub const KVM_DEV_RISCV_AIA_ADDR_APLIC: u32 = 0;
+pub const KVM_DEV_RISCV_AIA_ADDR_MAX: u32 = 16385;
+pub const KVM_DEV_RISCV_AIA_GRP_CTRL: u32 = 2;
+pub const KVM_DEV_RISCV_AIA_CTRL_INIT: u32 = 0;
+pub const KVM_DEV_RISCV_AIA_GRP_APLIC: u32 = 3;
+pub const KVM_DEV_RISCV_AIA_GRP_IMSIC: u32 = 4;
+pub const KVM_DEV_RISCV_AIA_IMSIC_ISEL_BITS: u32 = 12;
+pub const KVM_DEV_RISCV_AIA_IMSIC_ISEL_MASK: u32 = 4095;
+pub const KVM_NR_IRQCHIPS: u32 = 1;
 pub const KVM_API_VERSION: u32 = 12;
 pub const KVM_TRC_SHIFT: u32 = 16;
 pub const KVM_TRC_ENTRYEXIT: u32 = 65536;
@@ -1359,6 +1401,50 @@ pub struct kvm_riscv_csr {
     pub satp: ::std::os::raw::c_ulong,
     pub scounteren: ::std::os::raw::c_ulong,
 }
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct kvm_riscv_aia_csr {
+    pub siselect: ::std::os::raw::c_ulong,
+    pub iprio1: ::std::os::raw::c_ulong,
+    pub iprio2: ::std::os::raw::c_ulong,
+    pub sieh: ::std::os::raw::c_ulong,
+    pub siph: ::std::os::raw::c_ulong,
+    pub iprio1h: ::std::os::raw::c_ulong,
+    pub iprio2h: ::std::os::raw::c_ulong,
+}
+#[test]
+fn bindgen_test_layout_kvm_riscv_aia_csr() {
+    assert_eq!(
+        ::std::mem::size_of::<kvm_riscv_aia_csr>(),
+        56usize,
+        concat!("Size of: ", stringify!(kvm_riscv_aia_csr))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<kvm_riscv_aia_csr>(),
+        8usize,
+        concat!("Alignment of ", stringify!(kvm_riscv_aia_csr))
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<kvm_riscv_aia_csr>())).siselect as *const _ as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(kvm_riscv_aia_csr),
+            "::",
+            stringify!(siselect)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::std::ptr::null::<kvm_riscv_aia_csr>())).iprio2h as *const _ as usize },
+        48usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(kvm_riscv_aia_csr),
+            "::",
+            stringify!(iprio2h)
+        )
+    );
+}
 #[test]
 fn bindgen_test_layout_kvm_riscv_csr() {
     assert_eq!(
@@ -1533,6 +1619,45 @@ fn bindgen_test_layout_kvm_riscv_timer() {
         )
     );
 }
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_A: KVM_RISCV_ISA_EXT_ID = 0;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_C: KVM_RISCV_ISA_EXT_ID = 1;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_D: KVM_RISCV_ISA_EXT_ID = 2;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_F: KVM_RISCV_ISA_EXT_ID = 3;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_H: KVM_RISCV_ISA_EXT_ID = 4;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_I: KVM_RISCV_ISA_EXT_ID = 5;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_M: KVM_RISCV_ISA_EXT_ID = 6;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_SVPBMT: KVM_RISCV_ISA_EXT_ID = 7;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_SSTC: KVM_RISCV_ISA_EXT_ID = 8;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_SVINVAL: KVM_RISCV_ISA_EXT_ID = 9;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZIHINTPAUSE: KVM_RISCV_ISA_EXT_ID = 10;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZICBOM: KVM_RISCV_ISA_EXT_ID = 11;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZICBOZ: KVM_RISCV_ISA_EXT_ID = 12;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZBB: KVM_RISCV_ISA_EXT_ID = 13;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_SSAIA: KVM_RISCV_ISA_EXT_ID = 14;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_V: KVM_RISCV_ISA_EXT_ID = 15;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_SVNAPOT: KVM_RISCV_ISA_EXT_ID = 16;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZBA: KVM_RISCV_ISA_EXT_ID = 17;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZBS: KVM_RISCV_ISA_EXT_ID = 18;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZICNTR: KVM_RISCV_ISA_EXT_ID = 19;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZICSR: KVM_RISCV_ISA_EXT_ID = 20;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZIFENCEI: KVM_RISCV_ISA_EXT_ID = 21;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZIHPM: KVM_RISCV_ISA_EXT_ID = 22;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_SMSTATEEN: KVM_RISCV_ISA_EXT_ID = 23;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZICOND: KVM_RISCV_ISA_EXT_ID = 24;
+pub const KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_MAX: KVM_RISCV_ISA_EXT_ID = 25;
+pub type KVM_RISCV_ISA_EXT_ID = ::std::os::raw::c_uint;
+pub const KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_V01: KVM_RISCV_SBI_EXT_ID = 0;
+pub const KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_TIME: KVM_RISCV_SBI_EXT_ID = 1;
+pub const KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_IPI: KVM_RISCV_SBI_EXT_ID = 2;
+pub const KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_RFENCE: KVM_RISCV_SBI_EXT_ID = 3;
+pub const KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_SRST: KVM_RISCV_SBI_EXT_ID = 4;
+pub const KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_HSM: KVM_RISCV_SBI_EXT_ID = 5;
+pub const KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_PMU: KVM_RISCV_SBI_EXT_ID = 6;
+pub const KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_EXPERIMENTAL: KVM_RISCV_SBI_EXT_ID = 7;
+pub const KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_VENDOR: KVM_RISCV_SBI_EXT_ID = 8;
+pub const KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_DBCN: KVM_RISCV_SBI_EXT_ID = 9;
+pub const KVM_RISCV_SBI_EXT_ID_KVM_RISCV_SBI_EXT_MAX: KVM_RISCV_SBI_EXT_ID = 10;
+pub type KVM_RISCV_SBI_EXT_ID = ::std::os::raw::c_uint;
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, PartialEq)]
 pub struct kvm_user_trace_setup {
@@ -8227,7 +8352,8 @@ pub const kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3: kvm_device_type = 7;
 pub const kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS: kvm_device_type = 8;
 pub const kvm_device_type_KVM_DEV_TYPE_XIVE: kvm_device_type = 9;
 pub const kvm_device_type_KVM_DEV_TYPE_ARM_PV_TIME: kvm_device_type = 10;
-pub const kvm_device_type_KVM_DEV_TYPE_MAX: kvm_device_type = 11;
+pub const kvm_device_type_KVM_DEV_TYPE_RISCV_AIA: kvm_device_type = 11;
+pub const kvm_device_type_KVM_DEV_TYPE_MAX: kvm_device_type = 12;
 pub type kvm_device_type = ::std::os::raw::c_uint;
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, PartialEq)]
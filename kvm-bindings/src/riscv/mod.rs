@@ -7,3 +7,10 @@
 pub mod bindings;
 
 pub use self::bindings::*;
+
+/// Address attribute of the IMSIC of `hart` in `KVM_DEV_RISCV_AIA_GRP_ADDR`,
+/// which is a function-like macro in the kernel header and not generated by bindgen.
+#[allow(non_snake_case)]
+pub const fn KVM_DEV_RISCV_AIA_ADDR_IMSIC(hart: u32) -> u32 {
+    1 + hart
+}
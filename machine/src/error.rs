@@ -35,6 +35,11 @@ pub enum MachineError {
         #[from]
         source: super::micro_vm::error::MicroVmError,
     },
+    #[error("StandardVm")]
+    StandardVm {
+        #[from]
+        source: super::standard_vm::error::StandardVmError,
+    },
     #[error("Util")]
     Util {
         #[from]
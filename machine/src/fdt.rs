@@ -0,0 +1,439 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// Modifications made by China Telecom Co.,Ltd:
+// - Modify device tree generation for risc-v architecture
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Device tree nodes shared by the riscv64 machines.
+
+use std::sync::Arc;
+
+use cpu::CPU;
+use devices::{AIA_APLIC_SIZE, AIA_IMSIC_SIZE, AIA_NUM_IDS, MAX_DEVICES};
+use machine_manager::config::{BootSource, IrqChipType};
+use pci::PCI_INTX_NUM;
+use sysbus::{SysBus, SysBusDevType, SysRes};
+use util::device_tree::{self, FdtBuilder};
+
+use crate::mem_layout::{LayoutEntryType, MEM_LAYOUT};
+
+/// Trait that helps to generate all nodes in device-tree.
+pub(crate) trait CompileFDTHelper {
+    /// Function that helps to generate cpu nodes.
+    fn generate_cpu_nodes(&self, fdt: &mut FdtBuilder) -> util::Result<()>;
+    /// Function that helps to generate memory nodes.
+    fn generate_memory_node(&self, fdt: &mut FdtBuilder) -> util::Result<()>;
+    /// Function that helps to generate devices' nodes.
+    fn generate_devices_node(&self, fdt: &mut FdtBuilder) -> util::Result<()>;
+    /// Function that helps to generate the chosen node.
+    fn generate_chosen_node(&self, fdt: &mut FdtBuilder) -> util::Result<()>;
+}
+
+fn generate_plic_device_node(
+    fdt: &mut FdtBuilder,
+    res: &SysRes,
+    nr_vcpu: usize,
+) -> util::Result<()> {
+    let region_base = res.region_base;
+    let region_size = res.region_size;
+    let node = format!("interrupt-controller@{:x}", region_base);
+    let intc_node_dep = fdt.begin_node(&node)?;
+    fdt.set_property_string("compatible", "riscv,plic0")?;
+    fdt.set_property("interrupt-controller", &Vec::new())?;
+    fdt.set_property_u32("#interrupt-cells", 0x1)?;
+    fdt.set_property_u32("#address-cells", 0x0)?;
+    fdt.set_property_u32("phandle", device_tree::PLIC_PHANDLE)?;
+    fdt.set_property_u32("riscv,ndev", MAX_DEVICES - 1)?;
+    fdt.set_property_array_u64("reg", &[region_base, region_size])?;
+
+    let num_context = nr_vcpu * 2;
+    let mut irq_cells = Vec::new();
+    let mut i: u32 = 0;
+    while i < (num_context / 2) as u32 {
+        irq_cells.push(device_tree::INCT_PHANDLE_START + i);
+        irq_cells.push(0xffff_ffff);
+        irq_cells.push(device_tree::INCT_PHANDLE_START + i);
+        irq_cells.push(9);
+        i += 1;
+    }
+
+    let irq_cells = irq_cells.as_slice();
+    fdt.set_property_array_u32("interrupts-extended", irq_cells)?;
+
+    fdt.end_node(intc_node_dep)?;
+
+    Ok(())
+}
+
+fn generate_aplic_device_node(
+    fdt: &mut FdtBuilder,
+    res: &SysRes,
+    nr_vcpu: usize,
+) -> util::Result<()> {
+    let node = format!("interrupt-controller@{:x}", res.region_base);
+    let intc_node_dep = fdt.begin_node(&node)?;
+    fdt.set_property_string("compatible", "riscv,aplic")?;
+    fdt.set_property("interrupt-controller", &Vec::new())?;
+    fdt.set_property_u32("#interrupt-cells", 0x2)?;
+    fdt.set_property_u32("#address-cells", 0x0)?;
+    fdt.set_property_u32("phandle", device_tree::APLIC_PHANDLE)?;
+    fdt.set_property_u32("riscv,num-sources", MAX_DEVICES - 1)?;
+    fdt.set_property_array_u64("reg", &[res.region_base, res.region_size])?;
+
+    // Interrupts are delivered directly as supervisor external interrupts.
+    let mut irq_cells = Vec::new();
+    for cpu_index in 0..nr_vcpu as u32 {
+        irq_cells.push(device_tree::INCT_PHANDLE_START + cpu_index);
+        irq_cells.push(9);
+    }
+    fdt.set_property_array_u32("interrupts-extended", &irq_cells)?;
+
+    fdt.end_node(intc_node_dep)?;
+
+    Ok(())
+}
+
+// Function that helps to generate the in-kernel AIA nodes in device-tree, the
+// APLIC forwards the wired interrupts as MSIs to the IMSIC of each hart.
+//
+// # Arguments
+//
+// * `fdt` - Flatted device-tree blob where the nodes will be filled into.
+// * `nr_vcpu` - Number of vcpus, each one owns an IMSIC interrupt file.
+fn generate_aia_device_nodes(fdt: &mut FdtBuilder, nr_vcpu: usize) -> util::Result<()> {
+    let imsic_base = MEM_LAYOUT[LayoutEntryType::Imsic as usize].0;
+    let imsic_size = nr_vcpu as u64 * AIA_IMSIC_SIZE;
+    let node = format!("imsics@{:x}", imsic_base);
+    let imsic_node_dep = fdt.begin_node(&node)?;
+    fdt.set_property_string("compatible", "riscv,imsics")?;
+    fdt.set_property("interrupt-controller", &Vec::new())?;
+    fdt.set_property_u32("#interrupt-cells", 0x0)?;
+    fdt.set_property("msi-controller", &Vec::new())?;
+    fdt.set_property_u32("#msi-cells", 0x0)?;
+    fdt.set_property_u32("riscv,num-ids", AIA_NUM_IDS)?;
+    fdt.set_property_array_u64("reg", &[imsic_base, imsic_size])?;
+    let mut irq_cells = Vec::new();
+    for cpu_index in 0..nr_vcpu as u32 {
+        irq_cells.push(device_tree::INCT_PHANDLE_START + cpu_index);
+        irq_cells.push(9);
+    }
+    fdt.set_property_array_u32("interrupts-extended", &irq_cells)?;
+    fdt.set_property_u32("phandle", device_tree::IMSIC_PHANDLE)?;
+    fdt.end_node(imsic_node_dep)?;
+
+    let aplic_base = MEM_LAYOUT[LayoutEntryType::Plic as usize].0;
+    let node = format!("aplic@{:x}", aplic_base);
+    let aplic_node_dep = fdt.begin_node(&node)?;
+    fdt.set_property_string("compatible", "riscv,aplic")?;
+    fdt.set_property("interrupt-controller", &Vec::new())?;
+    fdt.set_property_u32("#interrupt-cells", 0x2)?;
+    fdt.set_property_u32("#address-cells", 0x0)?;
+    fdt.set_property_u32("msi-parent", device_tree::IMSIC_PHANDLE)?;
+    fdt.set_property_u32("riscv,num-sources", MAX_DEVICES - 1)?;
+    fdt.set_property_array_u64("reg", &[aplic_base, AIA_APLIC_SIZE])?;
+    fdt.set_property_u32("phandle", device_tree::APLIC_PHANDLE)?;
+    fdt.end_node(aplic_node_dep)?;
+
+    Ok(())
+}
+
+// Function that helps to set the interrupt of a device node, the APLIC takes
+// the trigger type as second cell.
+//
+// # Arguments
+//
+// * `fdt` - Flatted device-tree blob where the properties will be filled into.
+// * `irqchip` - Interrupt controller of the machine.
+// * `irq` - Interrupt source of the device.
+// * `irq_type` - Trigger type of the interrupt source.
+fn set_interrupt_property(
+    fdt: &mut FdtBuilder,
+    irqchip: IrqChipType,
+    irq: u32,
+    irq_type: u32,
+) -> util::Result<()> {
+    match irqchip {
+        IrqChipType::Plic => {
+            fdt.set_property_u32("interrupt-parent", device_tree::PLIC_PHANDLE)?;
+            fdt.set_property_u32("interrupts", irq)?;
+        }
+        IrqChipType::Aplic | IrqChipType::Aia => {
+            fdt.set_property_u32("interrupt-parent", device_tree::APLIC_PHANDLE)?;
+            fdt.set_property_array_u32("interrupts", &[irq, irq_type])?;
+        }
+    }
+    Ok(())
+}
+
+// Function that helps to generate serial node in device-tree.
+//
+// # Arguments
+//
+// * `dev_info` - Device resource info of serial device.
+// * `fdt` - Flatted device-tree blob where serial node will be filled into.
+fn generate_serial_device_node(
+    fdt: &mut FdtBuilder,
+    res: &SysRes,
+    irqchip: IrqChipType,
+) -> util::Result<()> {
+    let node = format!("uart@{:x}", res.region_base);
+    let serial_node_dep = fdt.begin_node(&node)?;
+    fdt.set_property_string("compatible", "ns16550a")?;
+    fdt.set_property_array_u64("reg", &[res.region_base, res.region_size])?;
+    fdt.set_property_u32("clock-frequency", 3686400)?;
+    set_interrupt_property(fdt, irqchip, res.irq, device_tree::IRQ_TYPE_LEVEL_HIGH)?;
+    fdt.end_node(serial_node_dep)?;
+    Ok(())
+}
+
+// Function that helps to generate Virtio-Mmio device's node in device-tree.
+//
+// # Arguments
+//
+// * `dev_info` - Device resource info of Virtio-Mmio device.
+// * `fdt` - Flatted device-tree blob where node will be filled into.
+fn generate_virtio_devices_node(
+    fdt: &mut FdtBuilder,
+    res: &SysRes,
+    irqchip: IrqChipType,
+) -> util::Result<()> {
+    let node = format!("virtio_mmio@{:x}", res.region_base);
+    let virtio_node_dep = fdt.begin_node(&node)?;
+    fdt.set_property_string("compatible", "virtio,mmio")?;
+    fdt.set_property_array_u64("reg", &[res.region_base, res.region_size])?;
+    set_interrupt_property(fdt, irqchip, res.irq, device_tree::IRQ_TYPE_EDGE_RISING)?;
+    fdt.end_node(virtio_node_dep)?;
+    Ok(())
+}
+
+// Function that helps to generate cpu nodes in device-tree.
+//
+// # Arguments
+//
+// * `fdt` - Flatted device-tree blob where the nodes will be filled into.
+// * `cpus` - vCPUs of the machine.
+pub(crate) fn generate_cpu_nodes(fdt: &mut FdtBuilder, cpus: &[Arc<CPU>]) -> util::Result<()> {
+    let node = "cpus";
+
+    let cpus_node_dep = fdt.begin_node(node)?;
+    fdt.set_property_u32("#address-cells", 0x02)?;
+    fdt.set_property_u32("#size-cells", 0x0)?;
+    let frequency = cpus[0].arch().lock().unwrap().timer_regs().frequency;
+    fdt.set_property_u32("timebase-frequency", frequency as u32)?;
+
+    let nr_vcpus = cpus.len();
+    for cpu_index in 0..nr_vcpus {
+        let node = format!("cpu@{:x}", cpu_index);
+        let cpu_node_dep = fdt.begin_node(&node)?;
+        fdt.set_property_u32("phandle", cpu_index as u32 + device_tree::CPU_PHANDLE_START)?;
+        fdt.set_property_string("device_type", "cpu")?;
+        fdt.set_property_string("compatible", "riscv")?;
+
+        let xlen = cpus[cpu_index]
+            .arch()
+            .lock()
+            .unwrap()
+            .get_xlen()
+            .to_string();
+        let mut isa = format!("rv{}", xlen);
+        let valid_isa_order = String::from("IEMAFDQCLBJTPVNSUHKORWXYZG");
+        for char in valid_isa_order.chars() {
+            let index = char as u32 - 'A' as u32;
+            let cpu_isa = cpus[cpu_index].arch().lock().unwrap().config_regs().isa;
+            if (cpu_isa & (1 << index) as u64) > 0 {
+                let tmp = char::from('a' as u8 + index as u8);
+                isa = format!("{}{}", isa, tmp);
+            }
+        }
+
+        fdt.set_property_string("riscv,isa", &isa)?;
+
+        fdt.set_property_u64("reg", cpu_index as u64)?;
+
+        let node = "interrupt-controller";
+        let interrupt_controller = fdt.begin_node(node)?;
+        fdt.set_property_string("compatible", "riscv,cpu-intc")?;
+        fdt.set_property_u32("#interrupt-cells", 1)?;
+        fdt.set_property("interrupt-controller", &Vec::new())?;
+        fdt.set_property_u32(
+            "phandle",
+            cpu_index as u32 + device_tree::INCT_PHANDLE_START,
+        )?;
+        fdt.end_node(interrupt_controller)?;
+
+        fdt.end_node(cpu_node_dep)?;
+    }
+
+    fdt.end_node(cpus_node_dep)?;
+
+    Ok(())
+}
+
+// Function that helps to generate memory node in device-tree.
+//
+// # Arguments
+//
+// * `fdt` - Flatted device-tree blob where the node will be filled into.
+// * `mem_base` - Start address of the guest RAM.
+// * `mem_size` - Size of the guest RAM.
+pub(crate) fn generate_memory_node(
+    fdt: &mut FdtBuilder,
+    mem_base: u64,
+    mem_size: u64,
+) -> util::Result<()> {
+    let node = "memory";
+    let memory_node_dep = fdt.begin_node(node)?;
+    fdt.set_property_string("device_type", "memory")?;
+    fdt.set_property_array_u64("reg", &[mem_base, mem_size])?;
+    fdt.end_node(memory_node_dep)?;
+
+    Ok(())
+}
+
+// Function that helps to generate the nodes of the interrupt controller and
+// of the devices attached to the system bus.
+//
+// # Arguments
+//
+// * `fdt` - Flatted device-tree blob where the nodes will be filled into.
+// * `sysbus` - System bus of the machine.
+// * `irqchip` - Interrupt controller of the machine.
+// * `nr_vcpu` - Number of vcpus.
+pub(crate) fn generate_sysbus_devices_nodes(
+    fdt: &mut FdtBuilder,
+    sysbus: &SysBus,
+    irqchip: IrqChipType,
+    nr_vcpu: usize,
+) -> util::Result<()> {
+    // The in-kernel AIA is not attached to the system bus.
+    if irqchip == IrqChipType::Aia {
+        generate_aia_device_nodes(fdt, nr_vcpu)?;
+    }
+    for dev in sysbus.devices.iter() {
+        let mut locked_dev = dev.lock().unwrap();
+        let dev_type = locked_dev.get_type();
+        let sys_res = locked_dev.get_sys_resource().unwrap();
+        match dev_type {
+            SysBusDevType::Plic => generate_plic_device_node(fdt, sys_res, nr_vcpu)?,
+            SysBusDevType::Aplic => generate_aplic_device_node(fdt, sys_res, nr_vcpu)?,
+            SysBusDevType::Serial => generate_serial_device_node(fdt, sys_res, irqchip)?,
+            SysBusDevType::VirtioMmio => generate_virtio_devices_node(fdt, sys_res, irqchip)?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// Function that helps to generate the node of the generic ECAM PCIe host
+// bridge in device-tree. The INTx pin of a function in slot S is swizzled onto
+// line (S + pin) % 4 of the host bridge, which is described by interrupt-map.
+//
+// # Arguments
+//
+// * `fdt` - Flatted device-tree blob where the node will be filled into.
+// * `ecam` - Base and size of the ECAM region.
+// * `mmio` - Base and size of the MMIO window the BARs are allocated from.
+// * `intx_irqs` - Interrupt sources of the INTA# to INTD# lines.
+// * `irqchip` - Interrupt controller of the machine.
+pub(crate) fn generate_pci_host_node(
+    fdt: &mut FdtBuilder,
+    ecam: (u64, u64),
+    mmio: (u64, u64),
+    intx_irqs: [u32; PCI_INTX_NUM],
+    irqchip: IrqChipType,
+) -> util::Result<()> {
+    let (ecam_base, ecam_size) = ecam;
+    let (mmio_base, mmio_size) = mmio;
+    let nr_buses = (ecam_size >> 20) as u32;
+
+    let node = format!("pcie@{:x}", ecam_base);
+    let pci_node_dep = fdt.begin_node(&node)?;
+    fdt.set_property_string("compatible", "pci-host-ecam-generic")?;
+    fdt.set_property_string("device_type", "pci")?;
+    fdt.set_property_array_u64("reg", &[ecam_base, ecam_size])?;
+    fdt.set_property_array_u32("bus-range", &[0, nr_buses - 1])?;
+    fdt.set_property_u32("linux,pci-domain", 0)?;
+    fdt.set_property_u32("#address-cells", 3)?;
+    fdt.set_property_u32("#size-cells", 2)?;
+    fdt.set_property("dma-coherent", &Vec::new())?;
+    fdt.set_property_array_u32(
+        "ranges",
+        &[
+            device_tree::FDT_PCI_RANGE_MMIO,
+            (mmio_base >> 32) as u32,
+            mmio_base as u32,
+            (mmio_base >> 32) as u32,
+            mmio_base as u32,
+            (mmio_size >> 32) as u32,
+            mmio_size as u32,
+        ],
+    )?;
+
+    fdt.set_property_u32("#interrupt-cells", 1)?;
+    let (intc_phandle, intc_cells) = match irqchip {
+        IrqChipType::Plic => (device_tree::PLIC_PHANDLE, 1),
+        IrqChipType::Aplic | IrqChipType::Aia => (device_tree::APLIC_PHANDLE, 2),
+    };
+    let mut irq_map = Vec::new();
+    for slot in 0..PCI_INTX_NUM as u32 {
+        for pin in 0..PCI_INTX_NUM as u32 {
+            let line = ((slot + pin) as usize) % PCI_INTX_NUM;
+            // Child unit address and interrupt specifier, pins start from INTA# = 1.
+            irq_map.extend_from_slice(&[slot << 11, 0, 0, pin + 1]);
+            irq_map.push(intc_phandle);
+            irq_map.push(intx_irqs[line]);
+            if intc_cells == 2 {
+                irq_map.push(device_tree::IRQ_TYPE_LEVEL_HIGH);
+            }
+        }
+    }
+    fdt.set_property_array_u32("interrupt-map", &irq_map)?;
+    fdt.set_property_array_u32("interrupt-map-mask", &[0x3 << 11, 0, 0, 0x7])?;
+    // MSI-X messages of the functions are written to the IMSIC.
+    if irqchip == IrqChipType::Aia {
+        fdt.set_property_u32("msi-parent", device_tree::IMSIC_PHANDLE)?;
+    }
+    fdt.end_node(pci_node_dep)?;
+
+    Ok(())
+}
+
+// Function that helps to generate the chosen node in device-tree.
+//
+// # Arguments
+//
+// * `fdt` - Flatted device-tree blob where the node will be filled into.
+// * `boot_source` - Kernel command line and initrd of the guest.
+pub(crate) fn generate_chosen_node(
+    fdt: &mut FdtBuilder,
+    boot_source: &BootSource,
+) -> util::Result<()> {
+    let node = "chosen";
+
+    let chosen_node_dep = fdt.begin_node(node)?;
+    let cmdline = &boot_source.kernel_cmdline.to_string();
+    fdt.set_property_string("bootargs", cmdline.as_str())?;
+
+    match &boot_source.initrd {
+        Some(initrd) => {
+            fdt.set_property_u64("linux,initrd-start", initrd.initrd_addr)?;
+            fdt.set_property_u64("linux,initrd-end", initrd.initrd_addr + initrd.initrd_size)?;
+        }
+        None => {}
+    }
+    fdt.end_node(chosen_node_dep)?;
+
+    Ok(())
+}
@@ -17,51 +17,79 @@
 // See the Mulan PSL v2 for more details.
 
 pub mod error;
+#[cfg(target_arch = "riscv64")]
+mod fdt;
+mod mem_layout;
 mod micro_vm;
+mod standard_vm;
 
 pub use crate::error::MachineError;
 use std::collections::{BTreeMap, HashMap};
 use std::fs::{remove_file, File};
 use std::net::TcpListener;
 use std::ops::Deref;
-use std::os::unix::{io::AsRawFd, net::UnixListener};
+use std::os::unix::{
+    io::{AsRawFd, RawFd},
+    net::UnixListener,
+};
 use std::rc::Rc;
 use std::sync::{Arc, Barrier, Condvar, Mutex, Weak};
 
 use kvm_ioctls::VcpuFd;
+use log::error;
 use util::file::{lock_file, unlock_file};
 use util::loop_context::read_fd;
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 
 pub use micro_vm::LightMachine;
+pub use standard_vm::StdMachine;
 
 use address_space::{
-    create_host_mmaps, set_host_memory_policy, AddressSpace, KvmMemoryListener, Region,
+    create_host_mmaps, set_host_memory_policy, AddressSpace, GuestAddress, KvmMemoryListener,
+    Region,
 };
 pub use anyhow::Result;
 use anyhow::{anyhow, bail, Context};
+use boot_loader::{load_linux, BootLoaderConfig};
 use cpu::{ArchCPU, CPUBootConfig, CPUInterface, CPUTopology, CPU};
 use devices::legacy::FwCfgOps;
 #[cfg(target_arch = "riscv64")]
-use devices::InterruptController;
+use devices::{
+    InterruptController, InterruptControllerConfig, InterruptControllerVersion, KvmAIA,
+    AIA_APLIC_SIZE,
+};
 use hypervisor::kvm::KVM_FDS;
+#[cfg(target_arch = "riscv64")]
+use machine_manager::config::IrqChipType;
 use machine_manager::config::{
-    parse_device_id, 
-    parse_virtconsole, parse_virtio_serial, Incoming,
-    MachineMemConfig, MigrateMode, SerialConfig, VmConfig, DriveFile
+    is_nbd_uri, parse_device_id, parse_discard, parse_incoming_uri, parse_virtconsole,
+    parse_virtio_serial, AioEngine, BootSource, DiskFormat, DriveConfig, DriveFile, Incoming,
+    IoErrorPolicy, MachineMemConfig, MigrateMode, PciBdf, SerialConfig, VmConfig, WriteZeroesState,
 };
 use machine_manager::{
     event_loop::EventLoop,
-    machine::{KvmVmState, MachineInterface},
+    machine::{KvmVmState, MachineInterface, MachineLifecycle},
+    qmp::{qmp_schema, QmpChannel, Response},
 };
+use mem_layout::{LayoutEntryType, MEM_LAYOUT};
 use migration::{MigrationManager, MigrationStatus};
+use pci::{PciBus, PciDevOps};
 use sysbus::SysBus;
 
+#[cfg(target_arch = "riscv64")]
+use fdt::CompileFDTHelper;
+#[cfg(target_arch = "riscv64")]
+use util::device_tree::FdtBuilder;
 use util::{
     arg_parser,
     loop_context::{EventNotifier, NotifierCallback, NotifierOperation},
+    throttle::{ThrottleConfig, ThrottleLimit},
+};
+use virtio::{
+    block_devices, cancel_block_job, complete_block_job, find_block_device, start_commit_job,
+    start_copy_job, Block, Console, SyncMode, VirtioConsoleState, VirtioDevice, VirtioMmioDevice,
+    VirtioMmioState, VirtioPciDevice,
 };
-use virtio::{Console, VirtioConsoleState, VirtioDevice, VirtioMmioDevice, VirtioMmioState};
 
 pub trait MachineOps {
     /// Calculate the ranges of memory according to architecture.
@@ -76,7 +104,32 @@ pub trait MachineOps {
     /// On x86_64, there is a gap ranged from (4G - 768M) to 4G, which will be skipped.
     fn arch_ram_ranges(&self, mem_size: u64) -> Vec<(u64, u64)>;
 
-    fn load_boot_source(&self, fwcfg: Option<&Arc<Mutex<dyn FwCfgOps>>>) -> Result<CPUBootConfig>;
+    /// Load the kernel and initrd into guest memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `fwcfg` - The firmware configuration device, if any.
+    fn load_boot_source(&self, fwcfg: Option<&Arc<Mutex<dyn FwCfgOps>>>) -> Result<CPUBootConfig> {
+        let mut boot_source = self.get_boot_source().lock().unwrap();
+        let initrd = boot_source.initrd.as_ref().map(|b| b.initrd_file.clone());
+
+        let bootloader_config = BootLoaderConfig {
+            kernel: boot_source.kernel_file.clone(),
+            initrd,
+            mem_start: MEM_LAYOUT[LayoutEntryType::Mem as usize].0,
+        };
+        let layout = load_linux(&bootloader_config, self.get_sys_mem(), fwcfg)
+            .with_context(|| anyhow!(MachineError::LoadKernErr))?;
+        if let Some(rd) = &mut boot_source.initrd {
+            rd.initrd_addr = layout.initrd_start;
+            rd.initrd_size = layout.initrd_size;
+        }
+
+        Ok(CPUBootConfig {
+            fdt_addr: layout.dtb_start,
+            boot_pc: layout.boot_pc,
+        })
+    }
 
     /// Init I/O & memory address space and mmap guest memory.
     ///
@@ -156,6 +209,8 @@ pub trait MachineOps {
                 vm.clone(),
             ));
             cpus.push(cpu.clone());
+
+            MigrationManager::register_cpu_instance(ArchCPU::descriptor(), cpu, vcpu_id);
         }
 
         if let Some(boot_config) = boot_cfg {
@@ -205,10 +260,30 @@ pub trait MachineOps {
     ///
     /// * `vm_config` - VM configuration.
     /// * `cfg_args` - Device configuration args.
-    fn add_virtio_mmio_block(&mut self, _vm_config: &mut VmConfig, _cfg_args: &str) -> Result<()> {
+    fn add_virtio_mmio_block(
+        &mut self,
+        _vm_config: &mut VmConfig,
+        _cfg_args: &str,
+        _irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> Result<()> {
         bail!("Virtio mmio devices Not supported!");
     }
 
+    /// Add vhost-user block device on virtio mmio.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_config` - VM configuration.
+    /// * `cfg_args` - Device configuration args.
+    fn add_vhost_user_blk_device(
+        &mut self,
+        _vm_config: &mut VmConfig,
+        _cfg_args: &str,
+        _irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> Result<()> {
+        bail!("Vhost-user mmio block devices not supported");
+    }
+
     fn realize_virtio_mmio_device(
         &mut self,
         _dev: VirtioMmioDevice,
@@ -216,12 +291,16 @@ pub trait MachineOps {
         bail!("Virtio mmio devices not supported");
     }
 
-    fn get_sys_mem(&mut self) -> &Arc<AddressSpace>;
+    fn get_sys_mem(&self) -> &Arc<AddressSpace>;
+
+    fn get_boot_source(&self) -> &Arc<Mutex<BootSource>>;
 
     fn get_vm_config(&self) -> Arc<Mutex<VmConfig>>;
 
     fn get_vm_state(&self) -> &Arc<(Mutex<KvmVmState>, Condvar)>;
 
+    fn get_cpus(&self) -> &[Arc<CPU>];
+
     /// Get migration mode and path from VM config. There are four modes in total:
     /// Tcp, Unix, File and Unknown.
     fn get_migrate_info(&self) -> Incoming;
@@ -241,6 +320,45 @@ pub trait MachineOps {
         bail!("Virtio mmio device Not supported!");
     }
 
+    /// Add virtio pci block device.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_config` - VM configuration.
+    /// * `cfg_args` - Device configuration args.
+    fn add_virtio_pci_blk(
+        &mut self,
+        _vm_config: &mut VmConfig,
+        _cfg_args: &str,
+        _irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> Result<()> {
+        bail!("Virtio pci devices not supported");
+    }
+
+    /// Add virtio pci net device.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_config` - VM configuration.
+    /// * `cfg_args` - Device configuration args.
+    fn add_virtio_pci_net(
+        &mut self,
+        _vm_config: &mut VmConfig,
+        _cfg_args: &str,
+        _irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> Result<()> {
+        bail!("Virtio pci devices not supported");
+    }
+
+    /// Get the devfn of a pci device and the bus it is plugged into.
+    ///
+    /// # Arguments
+    ///
+    /// * `bdf` - Bus and address of the device.
+    fn get_devfn_and_parent_bus(&mut self, _bdf: &PciBdf) -> Result<(u8, Weak<Mutex<PciBus>>)> {
+        bail!("No pci host bridge");
+    }
+
     /// Add console device.
     ///
     /// # Arguments
@@ -266,27 +384,23 @@ pub trait MachineOps {
                     &device_cfg.id,
                 );
             } else {
-                // let virtio_serial_info = if let Some(serial_info) = &vm_config.virtio_serial {
-                //     serial_info
-                // } else {
-                //     bail!("No virtio-serial-pci device configured for virtconsole");
-                // };
-                // // Reasonable, because for virtio-serial-pci device, the bdf has been checked.
-                // let bdf = virtio_serial_info.pci_bdf.clone().unwrap();
-                // let multi_func = virtio_serial_info.multifunction;
-                // let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
-                // let sys_mem = self.get_sys_mem().clone();
-                // let virtio_pci_device = VirtioPciDevice::new(
-                //     device_cfg.id.clone(),
-                //     devfn,
-                //     sys_mem,
-                //     console.clone(),
-                //     parent_bus,
-                //     multi_func,
-                // );
-                // virtio_pci_device
-                //     .realize()
-                //     .with_context(|| "Failed  to add virtio pci console device")?;
+                // Reasonable, because for virtio-serial-pci device, the bdf has been checked.
+                let bdf = serial.pci_bdf.clone().unwrap();
+                let multi_func = serial.multifunction;
+                let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
+                let sys_mem = self.get_sys_mem().clone();
+                let virtio_pci_device = VirtioPciDevice::new(
+                    device_cfg.id.clone(),
+                    devfn,
+                    sys_mem,
+                    console.clone(),
+                    parent_bus,
+                    multi_func,
+                    irq_chip,
+                );
+                virtio_pci_device
+                    .realize()
+                    .with_context(|| "Failed to add virtio pci console device")?;
             }
         } else {
             bail!("No virtio-serial-bus specified");
@@ -305,7 +419,14 @@ pub trait MachineOps {
         Ok(())
     }
 
-    fn get_sys_bus(&mut self) -> &SysBus;
+    fn get_sys_bus(&self) -> &SysBus;
+
+    /// Reset all devices attached to the system bus, including virtio-mmio
+    /// transports, the PCIe host bridge with the devices behind it and the
+    /// interrupt controller.
+    fn reset_all_devices(&self) -> Result<()> {
+        reset_sysbus_devices(self.get_sys_bus())
+    }
 
     
     /// Add peripheral devices.
@@ -334,11 +455,30 @@ pub trait MachineOps {
             //    .with_context(|| format!("Failed to check device id: config {}", cfg_args))?;
             match dev.0.as_str() {
                 "virtio-blk-device" => {
-                    self.add_virtio_mmio_block(vm_config, cfg_args)?;
+                    self.add_virtio_mmio_block(vm_config, cfg_args, #[cfg(target_arch = "riscv64")] irq_chip.clone())?;
+                }
+                "vhost-user-blk-device" => {
+                    self.add_vhost_user_blk_device(vm_config, cfg_args, #[cfg(target_arch = "riscv64")] irq_chip.clone())?;
                 }
                 "virtio-net-device" => {
                     self.add_virtio_mmio_net(vm_config, cfg_args, #[cfg(target_arch = "riscv64")] irq_chip.clone())?;
                 }
+                "virtio-blk-pci" => {
+                    self.add_virtio_pci_blk(
+                        vm_config,
+                        cfg_args,
+                        #[cfg(target_arch = "riscv64")]
+                        irq_chip.clone(),
+                    )?;
+                }
+                "virtio-net-pci" => {
+                    self.add_virtio_pci_net(
+                        vm_config,
+                        cfg_args,
+                        #[cfg(target_arch = "riscv64")]
+                        irq_chip.clone(),
+                    )?;
+                }
                 "virtio-serial-device" | "virtio-serial-pci" => {
                     self.add_virtio_serial(vm_config, cfg_args)?;
                 }
@@ -379,6 +519,70 @@ pub trait MachineOps {
         Ok(())
     }
 
+    /// Register event notifier for the reset requests of the vCPUs, the
+    /// machine is reset in the main loop as vCPUs can't pause themselves.
+    ///
+    /// # Arguments
+    ///
+    /// * `reset_req` - Eventfd written by the vCPUs to request a reset.
+    /// * `vm` - The machine to reset.
+    #[cfg(target_arch = "riscv64")]
+    fn register_reset_event(&self, reset_req: Arc<EventFd>, vm: Arc<Mutex<Self>>) -> Result<()>
+    where
+        Self: MachineLifecycle + CompileFDTHelper + Sized + 'static,
+    {
+        let reset_req_fd = reset_req.as_raw_fd();
+        let reset_req_handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            read_fd(reset_req_fd);
+            if let Err(e) = Self::handle_reset_request(&vm) {
+                error!("Failed to reset vm: {:?}", e);
+            }
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            reset_req_fd,
+            None,
+            EventSet::IN,
+            vec![reset_req_handler],
+        );
+        trace_eventnotifier(&notifier);
+
+        EventLoop::update_event(vec![notifier], None)
+            .with_context(|| anyhow!(MachineError::RegNotifierErr))?;
+        Ok(())
+    }
+
+    /// Rewind the machine to the state right before boot: the kernel, initrd
+    /// and device tree are loaded again, vCPUs and devices are reset.
+    #[cfg(target_arch = "riscv64")]
+    fn handle_reset_request(vm: &Arc<Mutex<Self>>) -> Result<()>
+    where
+        Self: MachineLifecycle + CompileFDTHelper + Sized,
+    {
+        let cpus = vm.lock().unwrap().get_cpus().to_vec();
+        reset_with_cpus_paused(
+            &cpus,
+            || {
+                let locked_vm = vm.lock().unwrap();
+                let boot_config = locked_vm.load_boot_source(None)?;
+                load_fdt(&*locked_vm, &boot_config)?;
+                for (cpu_index, cpu) in cpus.iter().enumerate() {
+                    cpu.reset_to_boot_state(&boot_config)
+                        .with_context(|| format!("Failed to reset vcpu{}", cpu_index))?;
+                }
+                locked_vm.reset_all_devices()?;
+
+                // A guest paused from QMP stays paused until it is resumed.
+                let running = *locked_vm.get_vm_state().0.lock().unwrap() == KvmVmState::Running;
+                Ok(running)
+            },
+            || {
+                vm.lock().unwrap().destroy();
+            },
+        )
+    }
+
     /// Get the drive backend files.
     fn get_drive_files(&self) -> Arc<Mutex<HashMap<String, DriveFile>>>;
 
@@ -394,6 +598,9 @@ pub trait MachineOps {
         let files = self.get_drive_files();
         let mut drive_files = files.lock().unwrap();
         VmConfig::add_drive_file(&mut drive_files, path, read_only, direct)?;
+        if is_nbd_uri(path) {
+            return Ok(());
+        }
 
         // Lock the added file if VM is running.
         let drive_file = drive_files.get_mut(path).unwrap();
@@ -415,6 +622,269 @@ pub trait MachineOps {
         VmConfig::remove_drive_file(&mut drive_files, path)
     }
 
+    /// Take an external snapshot of a block device, it then writes to a qcow2
+    /// overlay backed by its current image.
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - The block device.
+    /// * `args` - The overlay, created unless the mode is "existing".
+    fn snapshot_block_device(
+        &self,
+        block: &Arc<Mutex<Block>>,
+        args: &qmp_schema::BlockdevSnapshotSyncArgument,
+    ) -> Result<()> {
+        let format = args.format.as_deref().unwrap_or("qcow2");
+        if format != "qcow2" {
+            bail!("Unsupported snapshot format {}", format);
+        }
+        let existing = match args.mode.as_deref().unwrap_or("absolute-paths") {
+            "absolute-paths" => false,
+            "existing" => true,
+            mode => bail!("Invalid snapshot mode {}", mode),
+        };
+
+        let mut block = block.lock().unwrap();
+        let direct = block
+            .query_info()
+            .inserted
+            .map_or(true, |image| image.cache.direct);
+        if !existing {
+            block.create_overlay(&args.snapshot_file)?;
+        }
+        self.register_drive_file(&args.snapshot_file, false, direct)?;
+        if let Err(e) = block.snapshot(&args.snapshot_file) {
+            self.unregister_drive_file(&args.snapshot_file)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Start a mirror or backup job copying a block device into another image,
+    /// which is a drive file while the job uses it.
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - The block device.
+    /// * `mirror` - True for a mirror job, false for a backup job.
+    /// * `args` - The target, created unless the mode is "existing".
+    fn start_block_copy_job(
+        &self,
+        block: &Arc<Mutex<Block>>,
+        mirror: bool,
+        args: qmp_schema::DriveMirrorArgument,
+    ) -> Result<()> {
+        let sync = args
+            .sync
+            .parse::<SyncMode>()
+            .map_err(|_| anyhow!("Invalid sync mode {}", args.sync))?;
+        let existing = match args.mode.as_deref().unwrap_or("absolute-paths") {
+            "absolute-paths" => false,
+            "existing" => true,
+            mode => bail!("Invalid target mode {}", mode),
+        };
+
+        let (format, direct) = {
+            let locked_block = block.lock().unwrap();
+            let image = locked_block
+                .query_info()
+                .inserted
+                .with_context(|| format!("No image file for block device {}", args.device))?;
+            let format = args.format.as_deref().unwrap_or(&image.drv);
+            let format = format
+                .parse::<DiskFormat>()
+                .map_err(|_| anyhow!("Invalid target format {}", format))?;
+            if !existing {
+                locked_block.create_target(&args.target, format, sync == SyncMode::Top)?;
+            }
+            (format, image.cache.direct)
+        };
+        self.register_drive_file(&args.target, false, direct)?;
+        let target = (args.target.as_str(), format);
+        if let Err(e) = start_copy_job(block, mirror, args.job_id, target, sync, args.speed) {
+            self.unregister_drive_file(&args.target)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Parse the options of a drive added by `blockdev-add`.
+    fn parse_blockdev_config(
+        &self,
+        args: Box<qmp_schema::BlockDevAddArgument>,
+    ) -> Result<DriveConfig> {
+        let driver = args.driver.as_deref().unwrap_or("raw");
+        let format = driver
+            .parse::<DiskFormat>()
+            .map_err(|_| anyhow!("Unsupported block driver {}", driver))?;
+        let discard = args
+            .discard
+            .as_deref()
+            .map(parse_discard)
+            .transpose()?
+            .unwrap_or(false);
+        let detect_zeroes = args.detect_zeroes.as_deref().unwrap_or("off");
+        let write_zeroes = detect_zeroes
+            .parse::<WriteZeroesState>()
+            .map_err(|_| anyhow!("Invalid detect-zeroes {}", detect_zeroes))?;
+        let werror_policy = args.werror.as_deref().unwrap_or("report");
+        let werror = werror_policy
+            .parse::<IoErrorPolicy>()
+            .map_err(|_| anyhow!("Invalid werror {}", werror_policy))?;
+        let rerror_policy = args.rerror.as_deref().unwrap_or("report");
+        let rerror = rerror_policy
+            .parse::<IoErrorPolicy>()
+            .map_err(|_| anyhow!("Invalid rerror {}", rerror_policy))?;
+        let direct = args.cache.and_then(|cache| cache.direct).unwrap_or(true);
+        let aio = match args.aio.as_deref() {
+            Some(aio) => aio
+                .parse::<AioEngine>()
+                .map_err(|_| anyhow!("Invalid aio {}", aio))?,
+            None if direct => AioEngine::Native,
+            None => AioEngine::Off,
+        };
+
+        Ok(DriveConfig {
+            id: args.node_name,
+            path_on_host: args.file.filename,
+            read_only: args.read_only.unwrap_or(false),
+            direct,
+            throttle: ThrottleConfig {
+                iops_total: ThrottleLimit {
+                    avg: args.iops.unwrap_or(0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            aio,
+            format,
+            discard,
+            write_zeroes,
+            werror,
+            rerror,
+        })
+    }
+
+    /// Handle the QMP command `block_resize`.
+    fn qmp_block_resize(&self, device: String, size: u64) -> Response {
+        match find_qmp_block_device(&device) {
+            Ok(block) => qmp_response(block.lock().unwrap().resize(size)),
+            Err(response) => response,
+        }
+    }
+
+    /// Handle the QMP command `block_set_io_throttle`.
+    fn qmp_block_set_io_throttle(
+        &self,
+        args: Box<qmp_schema::BlockSetIoThrottleArgument>,
+    ) -> Response {
+        match find_qmp_block_device(&args.device) {
+            Ok(block) => {
+                let config = ThrottleConfig::from(&args.throttle);
+                qmp_response(block.lock().unwrap().set_io_throttle(config))
+            }
+            Err(response) => response,
+        }
+    }
+
+    /// Handle the QMP command `blockdev-snapshot-sync`.
+    fn qmp_blockdev_snapshot_sync(
+        &self,
+        args: qmp_schema::BlockdevSnapshotSyncArgument,
+    ) -> Response {
+        match find_qmp_block_device(&args.device) {
+            Ok(block) => qmp_response(self.snapshot_block_device(&block, &args)),
+            Err(response) => response,
+        }
+    }
+
+    /// Handle the QMP command `block-commit`.
+    fn qmp_block_commit(&self, args: qmp_schema::BlockCommitArgument) -> Response {
+        match find_qmp_block_device(&args.device) {
+            Ok(block) => qmp_response(start_commit_job(&block, args.job_id, args.speed)),
+            Err(response) => response,
+        }
+    }
+
+    /// Handle the QMP command `drive-mirror`.
+    fn qmp_drive_mirror(&self, args: qmp_schema::DriveMirrorArgument) -> Response {
+        match find_qmp_block_device(&args.device) {
+            Ok(block) => qmp_response(self.start_block_copy_job(&block, true, args)),
+            Err(response) => response,
+        }
+    }
+
+    /// Handle the QMP command `drive-backup`.
+    fn qmp_drive_backup(&self, args: qmp_schema::DriveBackupArgument) -> Response {
+        let block = match find_qmp_block_device(&args.device) {
+            Ok(block) => block,
+            Err(response) => return response,
+        };
+        let args = qmp_schema::DriveMirrorArgument {
+            device: args.device,
+            target: args.target,
+            format: args.format,
+            sync: args.sync,
+            mode: args.mode,
+            job_id: args.job_id,
+            speed: args.speed,
+        };
+        qmp_response(self.start_block_copy_job(&block, false, args))
+    }
+
+    /// Handle the QMP command `block-job-cancel`.
+    fn qmp_block_job_cancel(&self, device: String) -> Response {
+        match cancel_block_job(&device) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::DeviceNotActive(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    /// Handle the QMP command `block-job-complete`.
+    fn qmp_block_job_complete(&self, device: String) -> Response {
+        qmp_response(complete_block_job(&device))
+    }
+
+    /// Handle the QMP command `getfd`.
+    fn qmp_getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> Response {
+        if let Some(fd) = if_fd {
+            QmpChannel::set_fd(fd_name, fd);
+            Response::create_empty_response()
+        } else {
+            let err_resp =
+                qmp_schema::QmpErrorClass::GenericError("Invalid SCM message".to_string());
+            Response::create_error_response(err_resp, None)
+        }
+    }
+
+    /// Handle the QMP command `migrate`.
+    fn qmp_migrate(&self, uri: String) -> Response {
+        match parse_incoming_uri(&uri) {
+            Ok((MigrateMode::File, path)) => migration::snapshot(path),
+            Ok((MigrateMode::Unix, path)) => migration::migration_unix_mode(path),
+            Ok((MigrateMode::Tcp, path)) => migration::migration_tcp_mode(path),
+            _ => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("Invalid uri: {}", uri)),
+                None,
+            ),
+        }
+    }
+
+    /// Handle the QMP command `query-named-block-nodes`.
+    fn qmp_query_named_block_nodes(&self) -> Response {
+        let nodes: Vec<qmp_schema::BlockDeviceInfo> = block_devices()
+            .iter()
+            .filter_map(|block| block.lock().unwrap().query_info().inserted)
+            .collect();
+        Response::create_response(serde_json::to_value(&nodes).unwrap(), None)
+    }
+
     /// Active drive backend files. i.e., Apply lock.
     fn active_drive_files(&self) -> Result<()> {
         for drive_file in self.get_drive_files().lock().unwrap().values_mut() {
@@ -525,6 +995,10 @@ pub trait MachineOps {
     /// * `vm_state` - Vm kvm vm state.
     fn vm_resume(&self, cpus: &[Arc<CPU>], vm_state: &mut KvmVmState) -> Result<()> {
         self.active_drive_files()?;
+        // Submit the block requests held on IO error again.
+        for block in block_devices() {
+            block.lock().unwrap().retry_failed_requests()?;
+        }
 
         for (cpu_index, cpu) in cpus.iter().enumerate() {
             if let Err(e) = cpu.resume() {
@@ -636,6 +1110,59 @@ pub fn vm_run(
     Ok(())
 }
 
+/// Create the interrupt controller selected by `-machine irqchip`, the
+/// in-kernel AIA is picked by default when the host supports it.
+///
+/// # Arguments
+///
+/// * `vm_config` - VM configuration, the selected controller is recorded in it
+///   since the device tree is generated according to it.
+/// * `sysbus` - System bus the emulated controllers are attached to.
+/// * `vcpu_fds` - File descriptors of the vcpus.
+/// * `vcpu_count` - The number of vcpus.
+#[cfg(target_arch = "riscv64")]
+fn create_interrupt_controller(
+    vm_config: &Arc<Mutex<VmConfig>>,
+    sysbus: &mut SysBus,
+    vcpu_fds: Vec<Arc<VcpuFd>>,
+    vcpu_count: u32,
+) -> Result<Arc<Mutex<InterruptController>>> {
+    let mut locked_config = vm_config.lock().unwrap();
+    let irqchip = match locked_config.machine_config.irqchip {
+        Some(IrqChipType::Aia) if !KvmAIA::is_supported() => {
+            bail!("In-kernel AIA is not supported by the host");
+        }
+        Some(irqchip) => irqchip,
+        None if KvmAIA::is_supported() => IrqChipType::Aia,
+        None => IrqChipType::Plic,
+    };
+    locked_config.machine_config.irqchip = Some(irqchip);
+    drop(locked_config);
+
+    let (version, region_size) = match irqchip {
+        IrqChipType::Plic => (
+            InterruptControllerVersion::PLIC,
+            MEM_LAYOUT[LayoutEntryType::Plic as usize].1,
+        ),
+        IrqChipType::Aplic => (
+            InterruptControllerVersion::APLIC,
+            MEM_LAYOUT[LayoutEntryType::Plic as usize].1,
+        ),
+        IrqChipType::Aia => (InterruptControllerVersion::AIA, AIA_APLIC_SIZE),
+    };
+    // All the interrupt controllers live in the same slot of the memory layout.
+    let intc_conf = InterruptControllerConfig {
+        version: Some(version),
+        vcpu_count,
+        region_base: MEM_LAYOUT[LayoutEntryType::Plic as usize].0,
+        region_size,
+        imsic_base: MEM_LAYOUT[LayoutEntryType::Imsic as usize].0,
+        imsic_size: MEM_LAYOUT[LayoutEntryType::Imsic as usize].1,
+    };
+
+    let irq_chip = InterruptController::new(vcpu_fds, sysbus, &intc_conf)?;
+    Ok(Arc::new(Mutex::new(irq_chip)))
+}
 
 /// Start incoming migration from destination.
 fn start_incoming_migration(vm: &Arc<Mutex<dyn MachineOps + Send + Sync>>) -> Result<()> {
@@ -691,7 +1218,250 @@ fn start_incoming_migration(vm: &Arc<Mutex<dyn MachineOps + Send + Sync>>) -> Re
     Ok(())
 }
 
+/// Generate the flattened device tree of `machine`.
+#[cfg(target_arch = "riscv64")]
+fn generate_fdt_node<T: CompileFDTHelper>(machine: &T, fdt: &mut FdtBuilder) -> util::Result<()> {
+    let node_dep = fdt.begin_node("")?;
+
+    fdt.set_property_string("compatible", "linux,dummy-virt")?;
+    fdt.set_property_u32("#address-cells", 0x2)?;
+    fdt.set_property_u32("#size-cells", 0x2)?;
+
+    machine.generate_cpu_nodes(fdt)?;
+    machine.generate_memory_node(fdt)?;
+    machine.generate_devices_node(fdt)?;
+    machine.generate_chosen_node(fdt)?;
+
+    fdt.end_node(node_dep)?;
+
+    Ok(())
+}
+
+/// Generate the flattened device tree of `machine` and write it into guest
+/// memory.
+///
+/// # Arguments
+///
+/// * `machine` - The machine described by the device tree.
+/// * `boot_cfg` - Boot message generated by reading boot source to guest memory.
+#[cfg(target_arch = "riscv64")]
+fn load_fdt<T: MachineOps + CompileFDTHelper>(machine: &T, boot_cfg: &CPUBootConfig) -> Result<()> {
+    let mut fdt_helper = FdtBuilder::new();
+    generate_fdt_node(machine, &mut fdt_helper)
+        .with_context(|| anyhow!(MachineError::GenFdtErr))?;
+    let fdt_vec = fdt_helper.finish()?;
+    machine
+        .get_sys_mem()
+        .write(
+            &mut fdt_vec.as_slice(),
+            GuestAddress(boot_cfg.fdt_addr as u64),
+            fdt_vec.len() as u64,
+        )
+        .with_context(|| anyhow!(MachineError::WrtFdtErr(boot_cfg.fdt_addr, fdt_vec.len())))?;
+    Ok(())
+}
+
+/// Find the block device `id` for a QMP command, or the error response.
+fn find_qmp_block_device(id: &str) -> std::result::Result<Arc<Mutex<Block>>, Response> {
+    find_block_device(id).ok_or_else(|| {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::DeviceNotFound(format!("Block device {} not found", id)),
+            None,
+        )
+    })
+}
+
+/// Convert the result of a QMP command without return value to its response.
+fn qmp_response(result: Result<()>) -> Response {
+    match result {
+        Ok(()) => Response::create_empty_response(),
+        Err(ref e) => {
+            error!("{:?}", e);
+            Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            )
+        }
+    }
+}
+
+/// Reset the devices attached to `sysbus`.
+fn reset_sysbus_devices(sysbus: &SysBus) -> Result<()> {
+    for (index, dev) in sysbus.devices.iter().enumerate() {
+        dev.lock()
+            .unwrap()
+            .reset()
+            .with_context(|| format!("Failed to reset sysbus device {}", index))?;
+    }
+    Ok(())
+}
+
+/// Run `reset` with all the vCPUs paused, as vCPUs may be waiting for the
+/// machine lock to finish a mmio access. `reset` returns whether the vCPUs
+/// should run again.
+///
+/// If pausing fails, the vCPUs already paused are resumed and the guest goes
+/// on. If `reset` or resuming fails, the guest is left half reset and can not
+/// go on, so the machine is shut down by `shutdown`.
+fn reset_with_cpus_paused<C: CPUInterface>(
+    cpus: &[Arc<C>],
+    reset: impl FnOnce() -> Result<bool>,
+    shutdown: impl FnOnce(),
+) -> Result<()> {
+    for (cpu_index, cpu) in cpus.iter().enumerate() {
+        if let Err(e) = cpu.pause() {
+            for (index, paused) in cpus[..cpu_index].iter().enumerate() {
+                if let Err(e) = paused.resume() {
+                    error!("Failed to resume vcpu{}: {:?}", index, e);
+                }
+            }
+            return Err(e).with_context(|| format!("Failed to pause vcpu{}", cpu_index));
+        }
+    }
+
+    let result = reset().and_then(|running| {
+        if running {
+            for (cpu_index, cpu) in cpus.iter().enumerate() {
+                cpu.resume()
+                    .with_context(|| format!("Failed to resume vcpu{}", cpu_index))?;
+            }
+        }
+        Ok(())
+    });
+    if result.is_err() {
+        shutdown();
+    }
+    result
+}
+
 /// Description of the trace for eventnotifier.
 fn trace_eventnotifier(eventnotifier: &EventNotifier) {
     util::ftrace!(trace_eventnotifier, "{:#?}", eventnotifier);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Default)]
+    struct TestCpu {
+        paused: AtomicBool,
+        fail_pause: bool,
+    }
+
+    impl CPUInterface for TestCpu {
+        fn realize(&self, _boot: &CPUBootConfig, _topology: &CPUTopology) -> Result<()> {
+            Ok(())
+        }
+
+        fn start(_cpu: Arc<Self>, _thread_barrier: Arc<Barrier>, _paused: bool) -> Result<()> {
+            Ok(())
+        }
+
+        fn kick(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn pause(&self) -> Result<()> {
+            if self.fail_pause {
+                bail!("Test pause failure");
+            }
+            self.paused.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn resume(&self) -> Result<()> {
+            self.paused.store(false, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn destroy(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn reset(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn guest_shutdown(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn guest_reset(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn kvm_vcpu_exec(&self) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    fn test_cpus(fail_pause: &[bool]) -> Vec<Arc<TestCpu>> {
+        fail_pause
+            .iter()
+            .map(|fail_pause| {
+                Arc::new(TestCpu {
+                    fail_pause: *fail_pause,
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    fn is_paused(cpus: &[Arc<TestCpu>]) -> Vec<bool> {
+        cpus.iter()
+            .map(|cpu| cpu.paused.load(Ordering::SeqCst))
+            .collect()
+    }
+
+    #[test]
+    fn test_reset_with_cpus_paused() {
+        // Successful reset of a running guest resumes the vCPUs.
+        let cpus = test_cpus(&[false, false]);
+        let mut shutdown = false;
+        let ret = reset_with_cpus_paused(
+            &cpus,
+            || {
+                assert_eq!(is_paused(&cpus), vec![true, true]);
+                Ok(true)
+            },
+            || shutdown = true,
+        );
+        assert!(ret.is_ok());
+        assert!(!shutdown);
+        assert_eq!(is_paused(&cpus), vec![false, false]);
+
+        // A guest paused from QMP stays paused.
+        let ret = reset_with_cpus_paused(&cpus, || Ok(false), || shutdown = true);
+        assert!(ret.is_ok());
+        assert!(!shutdown);
+        assert_eq!(is_paused(&cpus), vec![true, true]);
+    }
+
+    #[test]
+    fn test_reset_with_cpus_paused_error() {
+        // Failing to pause resumes the vCPUs already paused, nothing is reset.
+        let cpus = test_cpus(&[false, true]);
+        let mut reset = false;
+        let mut shutdown = false;
+        let ret = reset_with_cpus_paused(
+            &cpus,
+            || {
+                reset = true;
+                Ok(true)
+            },
+            || shutdown = true,
+        );
+        assert!(ret.is_err());
+        assert!(!reset);
+        assert!(!shutdown);
+        assert_eq!(is_paused(&cpus), vec![false, false]);
+
+        // Failing to reset shuts the machine down instead of leaving it paused.
+        let cpus = test_cpus(&[false, false]);
+        let ret = reset_with_cpus_paused(&cpus, || bail!("Test reset failure"), || shutdown = true);
+        assert!(ret.is_err());
+        assert!(shutdown);
+    }
+}
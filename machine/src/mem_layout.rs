@@ -14,16 +14,22 @@
 /// The type of memory layout entry on riscv64
 #[repr(usize)]
 pub enum LayoutEntryType {
+    Imsic,
     Plic,
     Uart,
     Mmio,
+    PcieEcam,
+    PcieMmio,
     Mem,
 }
 /// Layout of riscv64
 pub const MEM_LAYOUT: &[(u64, u64)] = &[
+    (0x0800_0000, 0x0400_0000),    // Imsic
     (0x0c00_0000, 0x0400_0000),    // Plic 
     (0x1000_0000, 0x0000_0100),    // Uart
     (0x1000_1000, 0x0000_1000),    // Mmio
+    (0x3000_0000, 0x1000_0000),    // PcieEcam
+    (0x4000_0000, 0x4000_0000),    // PcieMmio
     (0x8000_0000, 0x80_0000_0000), // Mem
 ];
 
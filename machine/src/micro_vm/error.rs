@@ -34,8 +34,8 @@ pub enum MicroVmError {
         #[from]
         source: std::ffi::NulError,
     },
-    #[error("A maximum of {0} {1} replaceable devices are supported.")]
-    RplDevLmtErr(String, usize),
+    #[error("A maximum of {0} replaceable devices are supported.")]
+    RplDevLmtErr(usize),
     #[error("The device type is {0}, but the target config is not for this type.")]
     DevTypeErr(String),
     #[error("{0}: failed to update config.")]
@@ -18,9 +18,6 @@
 
 pub mod error;
 pub use error::MicroVmError;
-use util::aio::AioEngine;
-
-mod mem_layout;
 
 use super::Result as MachineResult;
 use log::error;
@@ -34,15 +31,15 @@ use std::vec::Vec;
 use vmm_sys_util::eventfd::EventFd;
 
 use address_space::{AddressSpace, GuestAddress, Region};
-use boot_loader::{load_linux, BootLoaderConfig};
-use cpu::{CPUBootConfig, CPUTopology, CpuLifecycleState, CpuTopology, CPU};
-use devices::legacy::{FwCfgOps, Serial};
+use cpu::{CPUTopology, CpuTopology, CPU};
+use devices::legacy::Serial;
 #[cfg(target_arch = "riscv64")]
-use devices::{InterruptController, InterruptControllerConfig, MAX_DEVICES};
+use devices::InterruptController;
 use hypervisor::kvm::KVM_FDS;
 use kvm_ioctls::VcpuFd;
 use machine_manager::config::{
-    parse_blk, parse_incoming_uri, parse_net, BlkDevConfig, Incoming, MigrateMode,
+    get_chardev_socket_path, parse_blk, parse_net, parse_vhost_user_blk_device, BlkDevConfig,
+    HotplugSlotType, Incoming, MigrateMode, UserNetConfig, VirtioConsole, VsockConfig,
 };
 use machine_manager::event;
 use machine_manager::machine::{
@@ -53,24 +50,31 @@ use machine_manager::{
     config::{BootSource, ConfigCheck, NetworkInterfaceConfig, SerialConfig, VmConfig, DEFAULT_VIRTQUEUE_SIZE, DriveFile},
     qmp::{qmp_schema, QmpChannel, Response},
 };
-use mem_layout::{LayoutEntryType, MEM_LAYOUT};
-use migration::{MigrationManager, MigrationStatus};
-use sysbus::{SysBus, SysBusDevType, SysRes, IRQ_BASE, IRQ_MAX};
-use util::device_tree::{self, CompileFDT, FdtBuilder};
-use util::{loop_context::EventLoopManager, set_termi_canon_mode};
+use migration::{DeviceStateDesc, MigrationManager, MigrationStatus};
+use sysbus::{SysBus, IRQ_BASE, IRQ_MAX};
+use util::device_tree::FdtBuilder;
+use util::loop_context::EventLoopManager;
+use util::set_termi_canon_mode;
 use virtio::{
-    create_tap, Block, BlockState, Net, VhostKern, VirtioDevice, VirtioMmioDevice,
-    VirtioMmioState, VirtioNetState,
+    block_devices, block_jobs, create_tap, device_has_job, register_block_device, Block,
+    BlockState, Console, Net, VhostKern, VhostUser, VirtioConsoleState, VirtioDevice,
+    VirtioMmioDevice, VirtioMmioState, VirtioNetState, VIRTIO_TYPE_BLOCK, VIRTIO_TYPE_CONSOLE,
+    VIRTIO_TYPE_NET, VIRTIO_TYPE_VSOCK,
 };
 
-use super::{error::MachineError, MachineOps};
+#[cfg(target_arch = "riscv64")]
+use super::{
+    create_interrupt_controller,
+    fdt::{self, CompileFDTHelper},
+    load_fdt,
+};
+use super::{
+    error::MachineError,
+    mem_layout::{LayoutEntryType, MEM_LAYOUT},
+    MachineOps,
+};
 use anyhow::{anyhow, bail, Context, Result};
 
-// The replaceable block device maximum count.
-const MMIO_REPLACEABLE_BLK_NR: usize = 1;
-// The replaceable network device maximum count.
-const MMIO_REPLACEABLE_NET_NR: usize = 1;
-
 // The config of replaceable device.
 #[derive(Debug)]
 struct MmioReplaceableConfig {
@@ -80,37 +84,45 @@ struct MmioReplaceableConfig {
     dev_config: Arc<dyn ConfigCheck>,
 }
 
-// The device information of replaceable device.
+// The device information of a replaceable slot.
 struct MmioReplaceableDevInfo {
-    // The related MMIO device.
-    device: Arc<Mutex<dyn VirtioDevice>>,
-    // Device id.
+    // The virtio-mmio transport of the slot.
+    transport: Arc<Mutex<VirtioMmioDevice>>,
+    // Type of the devices the slot accepts.
+    slot_type: HotplugSlotType,
+    // Base address of the transport.
+    addr: u64,
+    // Id of the plugged device.
     id: String,
-    // Identify if this device is be used.
+    // Driver of the plugged device.
+    driver: String,
+    // Migration descriptor of the plugged device, if it supports migration.
+    state_desc: Option<DeviceStateDesc>,
+    // Identify if the plugged device must be unrealized when it is unplugged.
+    unrealize: bool,
+    // Identify if this slot is be used.
     used: bool,
 }
 
 impl fmt::Debug for MmioReplaceableDevInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("MmioReplaceableDevInfo")
-            .field("device_type", &self.device.lock().unwrap().device_type())
+            .field("slot_type", &self.slot_type)
+            .field("addr", &self.addr)
             .field("id", &self.id)
+            .field("driver", &self.driver)
             .field("used", &self.used)
             .finish()
     }
 }
 
-// The gather of config, info and count of all replaceable devices.
+// The gather of config and info of all replaceable devices.
 #[derive(Debug)]
 struct MmioReplaceableInfo {
     // The arrays of all replaceable configs.
     configs: Arc<Mutex<Vec<MmioReplaceableConfig>>>,
-    // The arrays of all replaceable device information.
+    // The arrays of all replaceable slots.
     devices: Arc<Mutex<Vec<MmioReplaceableDevInfo>>>,
-    // The count of block device which is plugin.
-    block_count: usize,
-    // The count of network device which is plugin.
-    net_count: usize,
 }
 
 impl MmioReplaceableInfo {
@@ -118,12 +130,46 @@ impl MmioReplaceableInfo {
         MmioReplaceableInfo {
             configs: Arc::new(Mutex::new(Vec::new())),
             devices: Arc::new(Mutex::new(Vec::new())),
-            block_count: 0_usize,
-            net_count: 0_usize,
         }
     }
 }
 
+/// Get the type of the slots `driver` can be plugged into.
+fn slot_type_of(driver: &str) -> Result<HotplugSlotType> {
+    if driver.contains("net") {
+        Ok(HotplugSlotType::Net)
+    } else if driver.contains("blk") {
+        Ok(HotplugSlotType::Blk)
+    } else if driver.contains("vsock") {
+        Ok(HotplugSlotType::Vsock)
+    } else if driver.contains("console") {
+        Ok(HotplugSlotType::Console)
+    } else {
+        bail!("Unsupported replaceable device type.");
+    }
+}
+
+/// Check if the device configured by `dev_config` releases its backend in
+/// `unrealize`, such as the mac address of net or the vhost-user connection.
+fn need_unrealize(dev_config: &Arc<dyn ConfigCheck>) -> bool {
+    let cfg_any = dev_config.as_any();
+    cfg_any.downcast_ref::<BlkDevConfig>().is_some()
+        || cfg_any.downcast_ref::<NetworkInterfaceConfig>().is_some()
+}
+
+/// Close the fds handed over by `netdev_add` which are not taken by any device.
+fn close_netdev_fds(dev_config: &Arc<dyn ConfigCheck>) {
+    let cfg = match dev_config.as_any().downcast_ref::<NetworkInterfaceConfig>() {
+        Some(cfg) => cfg,
+        None => return,
+    };
+    let fds = cfg.tap_fds.iter().chain(cfg.vhost_fds.iter()).flatten();
+    for fd in fds {
+        // SAFETY: The fd is owned by the netdev and nobody uses it anymore.
+        unsafe { libc::close(*fd) };
+    }
+}
+
 /// A wrapper around creating and using a kvm-based micro VM.
 pub struct LightMachine {
     // `vCPU` topology, support sockets, cores, threads.
@@ -142,6 +188,8 @@ pub struct LightMachine {
     boot_source: Arc<Mutex<BootSource>>,
     // VM power button, handle VM `Shutdown` event.
     power_button: Arc<EventFd>,
+    // Reset request, handle VM `Reset` event.
+    reset_req: Arc<EventFd>,
     // All configuration information of virtual machine.
     vm_config: Arc<Mutex<VmConfig>>,
     // Drive backend files.
@@ -157,7 +205,7 @@ impl LightMachine {
     pub fn new(vm_config: &VmConfig) -> MachineResult<Self> {
         let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value()))
             .with_context(|| anyhow!(MachineError::CrtMemSpaceErr))?;
-        let free_irqs: (i32, i32) = (IRQ_BASE, IRQ_MAX);
+        let free_irqs: (u32, u32) = (IRQ_BASE, IRQ_MAX);
         let mmio_region: (u64, u64) = (
             MEM_LAYOUT[LayoutEntryType::Mmio as usize].0,
             MEM_LAYOUT[LayoutEntryType::Mmio as usize + 1].0,
@@ -174,6 +222,10 @@ impl LightMachine {
             Arc::new(EventFd::new(libc::EFD_NONBLOCK).with_context(|| {
                 anyhow!(MachineError::InitEventFdErr("power_button".to_string()))
             })?);
+        let reset_req = Arc::new(
+            EventFd::new(libc::EFD_NONBLOCK)
+                .with_context(|| anyhow!(MachineError::InitEventFdErr("reset_req".to_string())))?,
+        );
 
         Ok(LightMachine {
             cpu_topo: CpuTopology::new(
@@ -192,105 +244,264 @@ impl LightMachine {
             boot_source: Arc::new(Mutex::new(vm_config.clone().boot_source)),
             vm_state,
             power_button,
+            reset_req,
             vm_config: Arc::new(Mutex::new(vm_config.clone())),
             drive_files: Arc::new(Mutex::new(vm_config.init_drive_files()?)),
         })
     }
 
-    fn create_replaceable_devices(&mut self, 
-        #[cfg(target_arch = "riscv64")]
-        irq_chip: Arc<Mutex<InterruptController>>,
+    fn create_replaceable_devices(
+        &mut self,
+        slot_types: &[HotplugSlotType],
+        #[cfg(target_arch = "riscv64")] irq_chip: Arc<Mutex<InterruptController>>,
     ) -> Result<()> {
-        let mut rpl_devs: Vec<VirtioMmioDevice> = Vec::new();
-        for id in 0..MMIO_REPLACEABLE_BLK_NR {
-            let block = Arc::new(Mutex::new(Block::new(
-                BlkDevConfig::default(),
-                self.get_drive_files(),
-            )));
-            let virtio_mmio = VirtioMmioDevice::new(&self.sys_mem, block.clone(), #[cfg(target_arch = "riscv64")] irq_chip.clone());
-            rpl_devs.push(virtio_mmio);
+        for slot_type in slot_types {
+            self.create_replaceable_slot(
+                *slot_type,
+                #[cfg(target_arch = "riscv64")]
+                irq_chip.clone(),
+            )?;
+        }
+        Ok(())
+    }
 
+    /// Create an empty slot for devices of `slot_type`, return its index.
+    fn create_replaceable_slot(
+        &mut self,
+        slot_type: HotplugSlotType,
+        #[cfg(target_arch = "riscv64")] irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> Result<usize> {
+        let device_type = match slot_type {
+            HotplugSlotType::Blk => VIRTIO_TYPE_BLOCK,
+            HotplugSlotType::Net => VIRTIO_TYPE_NET,
+            HotplugSlotType::Console => VIRTIO_TYPE_CONSOLE,
+            HotplugSlotType::Vsock => VIRTIO_TYPE_VSOCK,
+        };
+        let region_base = self.sysbus.min_free_base;
+        let region_size = MEM_LAYOUT[LayoutEntryType::Mmio as usize].1;
+        let virtio_mmio = VirtioMmioDevice::new_replaceable(
+            &self.sys_mem,
+            device_type,
+            #[cfg(target_arch = "riscv64")]
+            irq_chip,
+        )?;
+        let transport = VirtioMmioDevice::realize(
+            virtio_mmio,
+            &mut self.sysbus,
+            region_base,
+            region_size,
+            #[cfg(target_arch = "x86_64")]
+            &self.boot_source,
+        )
+        .with_context(|| anyhow!(MicroVmError::RlzVirtioMmioErr))?;
+        self.sysbus.min_free_base += region_size;
+
+        let mut replaceable_devices = self.replaceable_info.devices.lock().unwrap();
+        let slot = replaceable_devices.len();
+        MigrationManager::register_transport_instance(
+            VirtioMmioState::descriptor(),
+            transport.clone(),
+            &slot.to_string(),
+        );
+        replaceable_devices.push(MmioReplaceableDevInfo {
+            transport,
+            slot_type,
+            addr: region_base,
+            id: "".to_string(),
+            driver: "".to_string(),
+            state_desc: None,
+            unrealize: false,
+            used: false,
+        });
+        Ok(slot)
+    }
+
+    /// Create the virtio device for `dev_config` and register its migration
+    /// state under the slot index.
+    fn create_replaceable_device(
+        &self,
+        driver: &str,
+        dev_config: &Arc<dyn ConfigCheck>,
+        slot: usize,
+    ) -> Result<(Arc<Mutex<dyn VirtioDevice>>, Option<DeviceStateDesc>)> {
+        let cfg_any = dev_config.as_any();
+        let id = slot.to_string();
+        if driver.contains("net") {
+            let cfg = cfg_any
+                .downcast_ref::<NetworkInterfaceConfig>()
+                .ok_or_else(|| anyhow!(MicroVmError::DevTypeErr("net".to_string())))?;
+            if cfg.vhost_type.as_deref() == Some("vhost-user") {
+                self.check_mem_share(driver)?;
+                let net = Arc::new(Mutex::new(VhostUser::Net::new(cfg, &self.sys_mem)));
+                return Ok((net, None));
+            }
+            if cfg.vhost_type.is_some() {
+                let net = Arc::new(Mutex::new(VhostKern::Net::new(cfg, &self.sys_mem)));
+                return Ok((net, None));
+            }
+            let net = Arc::new(Mutex::new(Net::new(cfg.clone())));
+            MigrationManager::register_device_instance(
+                VirtioNetState::descriptor(),
+                net.clone(),
+                &id,
+            );
+            Ok((net, Some(VirtioNetState::descriptor())))
+        } else if driver.starts_with("vhost-user-blk") {
+            let mut cfg = cfg_any
+                .downcast_ref::<BlkDevConfig>()
+                .ok_or_else(|| anyhow!(MicroVmError::DevTypeErr("blk".to_string())))?
+                .clone();
+            self.check_mem_share(driver)?;
+            cfg.queues = 1;
+            let block = Arc::new(Mutex::new(VhostUser::Block::new(&cfg, &self.sys_mem)));
+            Ok((block, None))
+        } else if driver.contains("blk") {
+            let mut cfg = cfg_any
+                .downcast_ref::<BlkDevConfig>()
+                .ok_or_else(|| anyhow!(MicroVmError::DevTypeErr("blk".to_string())))?
+                .clone();
+            // microvm type block device don't support multiple queue.
+            cfg.queues = 1;
+            let block = Arc::new(Mutex::new(Block::new(cfg.clone(), self.get_drive_files())));
+            register_block_device(&cfg.id, &block);
             MigrationManager::register_device_instance(
                 BlockState::descriptor(),
-                block,
-                &id.to_string(),
+                block.clone(),
+                &id,
             );
-        }
-        for id in 0..MMIO_REPLACEABLE_NET_NR {
-            let net = Arc::new(Mutex::new(Net::default()));
-            let virtio_mmio = VirtioMmioDevice::new(&self.sys_mem, net.clone(), #[cfg(target_arch = "riscv64")] irq_chip.clone());
-            rpl_devs.push(virtio_mmio);
-
+            Ok((block, Some(BlockState::descriptor())))
+        } else if driver.contains("vsock") {
+            let cfg = cfg_any
+                .downcast_ref::<VsockConfig>()
+                .ok_or_else(|| anyhow!(MicroVmError::DevTypeErr("vsock".to_string())))?;
+            let vsock = Arc::new(Mutex::new(VhostKern::Vsock::new(cfg, &self.sys_mem)));
             MigrationManager::register_device_instance(
-                VirtioNetState::descriptor(),
-                net,
-                &id.to_string(),
+                VhostKern::VsockState::descriptor(),
+                vsock.clone(),
+                &id,
+            );
+            Ok((vsock, Some(VhostKern::VsockState::descriptor())))
+        } else if driver.contains("console") {
+            let cfg = cfg_any
+                .downcast_ref::<VirtioConsole>()
+                .ok_or_else(|| anyhow!(MicroVmError::DevTypeErr("console".to_string())))?;
+            let console = Arc::new(Mutex::new(Console::new(cfg.clone())));
+            MigrationManager::register_device_instance(
+                VirtioConsoleState::descriptor(),
+                console.clone(),
+                &id,
             );
+            Ok((console, Some(VirtioConsoleState::descriptor())))
+        } else {
+            bail!("Unsupported replaceable device type.");
         }
+    }
 
-        let mut region_base = self.sysbus.min_free_base;
-        let region_size = MEM_LAYOUT[LayoutEntryType::Mmio as usize].1;
-        for (id, dev) in rpl_devs.into_iter().enumerate() {
-            self.replaceable_info
-                .devices
-                .lock()
-                .unwrap()
-                .push(MmioReplaceableDevInfo {
-                    device: dev.device.clone(),
-                    id: id.to_string(),
-                    used: false,
-                });
-
-            MigrationManager::register_transport_instance(
-                VirtioMmioState::descriptor(),
-                VirtioMmioDevice::realize(
-                    dev,
-                    &mut self.sysbus,
-                    region_base,
-                    MEM_LAYOUT[LayoutEntryType::Mmio as usize].1,
-                    #[cfg(target_arch = "x86_64")]
-                    &self.boot_source,
-                )
-                .with_context(|| anyhow!(MicroVmError::RlzVirtioMmioErr))?,
-                &id.to_string(),
+    /// Vhost-user backends map guest memory through the fds of the memory
+    /// regions, which only exist for shared memory.
+    fn check_mem_share(&self, driver: &str) -> Result<()> {
+        if !self
+            .vm_config
+            .lock()
+            .unwrap()
+            .machine_config
+            .mem_config
+            .mem_share
+        {
+            bail!(
+                "{} requires shared guest memory, use -machine mem-share=on",
+                driver
             );
-            region_base += region_size;
         }
-        self.sysbus.min_free_base = region_base;
         Ok(())
     }
 
-    fn fill_replaceable_device(
-        &mut self,
+    /// Plug a device into `slot`, or into the first free slot of its type if
+    /// `slot` is None.
+    fn plug_replaceable_device(
+        &self,
         id: &str,
+        driver: &str,
         dev_config: Arc<dyn ConfigCheck>,
-        index: usize,
+        slot: Option<usize>,
     ) -> Result<()> {
+        let slot_type = slot_type_of(driver)?;
         let mut replaceable_devices = self.replaceable_info.devices.lock().unwrap();
-        if let Some(device_info) = replaceable_devices.get_mut(index) {
-            if device_info.used {
-                bail!("{}: index {} is already used.", id, index);
+        let limit = replaceable_devices.len();
+        let index = match slot {
+            Some(slot) if slot >= limit => {
+                return Err(anyhow!(MicroVmError::RplDevLmtErr(limit)));
             }
-
-            device_info.id = id.to_string();
-            device_info.used = true;
-            device_info
-                .device
-                .lock()
-                .unwrap()
-                .update_config(Some(dev_config.clone()))
-                .with_context(|| anyhow!(MicroVmError::UpdCfgErr(id.to_string())))?;
+            Some(slot) => {
+                if replaceable_devices[slot].slot_type != slot_type {
+                    bail!(
+                        "The slot {} is for {} devices.",
+                        slot,
+                        replaceable_devices[slot].slot_type
+                    );
+                }
+                if replaceable_devices[slot].used {
+                    bail!("The slot {} is occupied already.", slot);
+                }
+                slot
+            }
+            None => replaceable_devices
+                .iter()
+                .position(|device_info| !device_info.used && device_info.slot_type == slot_type)
+                .ok_or_else(|| anyhow!("No free slot for {} devices.", slot_type))?,
+        };
+        if replaceable_devices
+            .iter()
+            .any(|device_info| device_info.id == id)
+        {
+            bail!("Device {} is already plugged.", id);
         }
 
-        self.add_replaceable_config(id, dev_config)?;
+        let unrealize = need_unrealize(&dev_config);
+        let (device, state_desc) = self.create_replaceable_device(driver, &dev_config, index)?;
+        let device_info = &mut replaceable_devices[index];
+        if let Err(e) = device_info
+            .transport
+            .lock()
+            .unwrap()
+            .replace_device(Some(device))
+        {
+            if let Some(desc) = state_desc {
+                MigrationManager::unregister_device_instance(desc, &index.to_string());
+            }
+            return Err(e).with_context(|| anyhow!(MicroVmError::UpdCfgErr(id.to_string())));
+        }
+        device_info.id = id.to_string();
+        device_info.driver = driver.to_string();
+        device_info.state_desc = state_desc;
+        device_info.unrealize = unrealize;
+        device_info.used = true;
         Ok(())
     }
 
+    /// Plug a cold-plugged device into a slot of its own, the slots reserved
+    /// for hotplug stay free.
+    fn fill_replaceable_device(
+        &mut self,
+        id: &str,
+        driver: &str,
+        dev_config: Arc<dyn ConfigCheck>,
+        #[cfg(target_arch = "riscv64")] irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> Result<()> {
+        let slot = self.create_replaceable_slot(
+            slot_type_of(driver)?,
+            #[cfg(target_arch = "riscv64")]
+            irq_chip,
+        )?;
+        self.add_replaceable_config(id, dev_config.clone())?;
+        self.plug_replaceable_device(id, driver, dev_config, Some(slot))
+    }
+
     fn add_replaceable_config(&self, id: &str, dev_config: Arc<dyn ConfigCheck>) -> Result<()> {
         let mut configs_lock = self.replaceable_info.configs.lock().unwrap();
-        let limit = MMIO_REPLACEABLE_BLK_NR + MMIO_REPLACEABLE_NET_NR;
+        let limit = self.replaceable_info.devices.lock().unwrap().len();
         if configs_lock.len() >= limit {
-            return Err(anyhow!(MicroVmError::RplDevLmtErr("".to_string(), limit)));
+            return Err(anyhow!(MicroVmError::RplDevLmtErr(limit)));
         }
 
         for config in configs_lock.iter() {
@@ -309,101 +520,179 @@ impl LightMachine {
         Ok(())
     }
 
-    fn add_replaceable_device(&self, id: &str, driver: &str, slot: usize) -> Result<()> {
-        // Find the configuration by id.
-        let configs_lock = self.replaceable_info.configs.lock().unwrap();
-        let mut dev_config = None;
-        for config in configs_lock.iter() {
-            if config.id == id {
-                dev_config = Some(config.dev_config.clone());
-            }
-        }
-        if dev_config.is_none() {
-            bail!("Failed to find device configuration.");
-        }
-
-        // Sanity check for config, driver and slot.
-        let cfg_any = dev_config.as_ref().unwrap().as_any();
-        let index = if driver.contains("net") {
-            if slot >= MMIO_REPLACEABLE_NET_NR {
-                return Err(anyhow!(MicroVmError::RplDevLmtErr(
-                    "net".to_string(),
-                    MMIO_REPLACEABLE_NET_NR
-                )));
-            }
-            if cfg_any.downcast_ref::<NetworkInterfaceConfig>().is_none() {
-                return Err(anyhow!(MicroVmError::DevTypeErr("net".to_string())));
-            }
-            slot + MMIO_REPLACEABLE_BLK_NR
-        } else if driver.contains("blk") {
-            if slot >= MMIO_REPLACEABLE_BLK_NR {
-                return Err(anyhow!(MicroVmError::RplDevLmtErr(
-                    "block".to_string(),
-                    MMIO_REPLACEABLE_BLK_NR
-                )));
-            }
-            if cfg_any.downcast_ref::<BlkDevConfig>().is_none() {
-                return Err(anyhow!(MicroVmError::DevTypeErr("blk".to_string())));
-            }
-            slot
+    fn add_replaceable_device(
+        &self,
+        args: &qmp_schema::DeviceAddArgument,
+        slot: Option<usize>,
+    ) -> Result<()> {
+        let driver = args.driver.as_str();
+        let dev_config: Arc<dyn ConfigCheck> = if driver.contains("vsock") {
+            let guest_cid = args
+                .guest_cid
+                .ok_or_else(|| anyhow!("guest-cid is missing for {}", driver))?;
+            let config = VsockConfig {
+                id: args.id.clone(),
+                guest_cid,
+                vhost_fd: None,
+            };
+            config.check()?;
+            Arc::new(config)
+        } else if driver.contains("console") {
+            let chardev_id = args
+                .chardev
+                .as_ref()
+                .ok_or_else(|| anyhow!("chardev is missing for {}", driver))?;
+            // Taken from the VM config only once the device is plugged.
+            let chardev = self
+                .vm_config
+                .lock()
+                .unwrap()
+                .chardev
+                .get(chardev_id)
+                .cloned()
+                .ok_or_else(|| anyhow!("Chardev {:?} not found or is in use", chardev_id))?;
+            let config = VirtioConsole {
+                id: args.id.clone(),
+                chardev,
+            };
+            config.check()?;
+            Arc::new(config)
+        } else if driver.starts_with("vhost-user-blk") {
+            let chardev_id = args
+                .chardev
+                .as_ref()
+                .ok_or_else(|| anyhow!("chardev is missing for {}", driver))?;
+            let socket_path =
+                get_chardev_socket_path(chardev_id, &mut self.vm_config.lock().unwrap())?;
+            let config = BlkDevConfig {
+                id: args.id.clone(),
+                chardev: Some(chardev_id.clone()),
+                socket_path: Some(socket_path),
+                ..Default::default()
+            };
+            config.check()?;
+            Arc::new(config)
         } else {
-            bail!("Unsupported replaceable device type.");
+            // Find the configuration by id.
+            let configs_lock = self.replaceable_info.configs.lock().unwrap();
+            configs_lock
+                .iter()
+                .find(|config| config.id == args.id)
+                .map(|config| config.dev_config.clone())
+                .ok_or_else(|| anyhow!("Failed to find device configuration."))?
         };
 
-        // Find the replaceable device and replace it.
-        let mut replaceable_devices = self.replaceable_info.devices.lock().unwrap();
-        if let Some(device_info) = replaceable_devices.get_mut(index) {
-            if device_info.used {
-                bail!("The slot {} is occupied already.", slot);
-            }
-
-            device_info.id = id.to_string();
-            device_info.used = true;
-            device_info
-                .device
+        self.plug_replaceable_device(&args.id, driver, dev_config.clone(), slot)?;
+        if let Some(console) = dev_config.as_any().downcast_ref::<VirtioConsole>() {
+            self.vm_config
                 .lock()
                 .unwrap()
-                .update_config(dev_config)
-                .with_context(|| anyhow!(MicroVmError::UpdCfgErr(id.to_string())))?;
+                .chardev
+                .remove(&console.chardev.id);
         }
         Ok(())
     }
 
-    fn del_replaceable_device(&self, id: &str) -> Result<String> {
-        // find the index of configuration by name and remove it
-        let mut is_exist = false;
+    /// Remove the configuration with `id`, and release the drive file of it.
+    fn del_replaceable_config(&self, id: &str) -> Result<Option<Arc<dyn ConfigCheck>>> {
         let mut configs_lock = self.replaceable_info.configs.lock().unwrap();
-        for (index, config) in configs_lock.iter().enumerate() {
-            if config.id == id {
-                if let Some(blkconf) = config.dev_config.as_any().downcast_ref::<BlkDevConfig>() {
-                    self.unregister_drive_file(&blkconf.path_on_host)?;
-                }
-                configs_lock.remove(index);
-                is_exist = true;
-                break;
+        let index = match configs_lock.iter().position(|config| config.id == id) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let dev_config = configs_lock[index].dev_config.clone();
+        if let Some(blkconf) = dev_config.as_any().downcast_ref::<BlkDevConfig>() {
+            if blkconf.socket_path.is_none() {
+                self.unregister_drive_file(&blkconf.path_on_host)?;
             }
         }
+        configs_lock.remove(index);
+        Ok(Some(dev_config))
+    }
 
-        // set the status of the device to 'unused'
+    /// Empty the slot of the device with `id`, return false if no device is found.
+    fn unplug_replaceable_device(&self, id: &str) -> Result<bool> {
         let mut replaceable_devices = self.replaceable_info.devices.lock().unwrap();
-        for device_info in replaceable_devices.iter_mut() {
-            if device_info.id == id {
-                device_info.id = "".to_string();
-                device_info.used = false;
-                device_info
-                    .device
-                    .lock()
-                    .unwrap()
-                    .update_config(None)
-                    .with_context(|| anyhow!(MicroVmError::UpdCfgErr(id.to_string())))?;
-            }
+        let (index, device_info) = match replaceable_devices
+            .iter_mut()
+            .enumerate()
+            .find(|(_, device_info)| device_info.used && device_info.id == id)
+        {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+
+        let device = device_info.transport.lock().unwrap().device.clone();
+        // Deactivating the device unregisters its notifiers from the iothread.
+        device_info
+            .transport
+            .lock()
+            .unwrap()
+            .replace_device(None)
+            .with_context(|| anyhow!(MicroVmError::UpdCfgErr(id.to_string())))?;
+        // The vhost-user client keeps the backend connection and its
+        // socket event until the device is unrealized.
+        if device_info.unrealize {
+            device
+                .lock()
+                .unwrap()
+                .unrealize()
+                .with_context(|| anyhow!(MicroVmError::UpdCfgErr(id.to_string())))?;
+        }
+        if let Some(desc) = device_info.state_desc.take() {
+            MigrationManager::unregister_device_instance(desc, &index.to_string());
         }
+        device_info.id = "".to_string();
+        device_info.driver = "".to_string();
+        device_info.unrealize = false;
+        device_info.used = false;
+        Ok(true)
+    }
 
-        if !is_exist {
+    fn del_replaceable_device(&self, id: &str) -> Result<String> {
+        if device_has_job(id) {
+            bail!("Block device {} has a block job running", id);
+        }
+        // The tap and vhost fds are closed when the last reference of the
+        // unplugged device is dropped.
+        let unplugged = self.unplug_replaceable_device(id)?;
+        let dev_config = self.del_replaceable_config(id)?;
+        if !unplugged && dev_config.is_none() {
             bail!("Device {} not found", id);
         }
         Ok(id.to_string())
     }
+
+    /// Delete the backend with `id`, which is refused while a device uses it.
+    /// Return the configuration of the backend.
+    fn del_replaceable_backend<T: 'static>(&self, id: &str) -> Result<Arc<dyn ConfigCheck>> {
+        let is_backend = self
+            .replaceable_info
+            .configs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|config| config.id == id && config.dev_config.as_any().is::<T>());
+        if !is_backend {
+            bail!("Backend {} not found", id);
+        }
+        if self
+            .replaceable_info
+            .devices
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|device_info| device_info.used && device_info.id == id)
+        {
+            bail!("Backend {} is in use, delete its device first", id);
+        }
+        if device_has_job(id) {
+            bail!("Backend {} has a block job running", id);
+        }
+
+        self.del_replaceable_config(id)?
+            .with_context(|| format!("Backend {} not found", id))
+    }
 }
 
 impl MachineOps for LightMachine {
@@ -435,41 +724,7 @@ impl MachineOps for LightMachine {
         vcpu_fds: Vec<Arc<VcpuFd>>,
         vcpu_count: u32,
     ) -> MachineResult<Arc<Mutex<InterruptController>>> {
-        let intc_conf = InterruptControllerConfig {
-            version: None,
-            vcpu_count,
-            region_base: MEM_LAYOUT[LayoutEntryType::Plic as usize].0,
-            region_size: MEM_LAYOUT[LayoutEntryType::Plic as usize].1,
-        };
-
-        let irq_chip = InterruptController::new(vcpu_fds, &mut self.sysbus, &intc_conf)?;
-        Ok(Arc::new(Mutex::new(irq_chip)))
-    }
-
-    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
-    fn load_boot_source(
-        &self,
-        fwcfg: Option<&Arc<Mutex<dyn FwCfgOps>>>,
-    ) -> MachineResult<CPUBootConfig> {
-        let mut boot_source = self.boot_source.lock().unwrap();
-        let initrd = boot_source.initrd.as_ref().map(|b| b.initrd_file.clone());
-
-        let bootloader_config = BootLoaderConfig {
-            kernel: boot_source.kernel_file.clone(),
-            initrd,
-            mem_start: MEM_LAYOUT[LayoutEntryType::Mem as usize].0,
-        };
-        let layout = load_linux(&bootloader_config, &self.sys_mem, fwcfg)
-            .with_context(|| anyhow!(MachineError::LoadKernErr))?;
-        if let Some(rd) = &mut boot_source.initrd {
-            rd.initrd_addr = layout.initrd_start;
-            rd.initrd_size = layout.initrd_size;
-        }
-
-        Ok(CPUBootConfig {
-            fdt_addr: layout.dtb_start,
-            boot_pc: layout.boot_pc,
-        })
+        create_interrupt_controller(&self.vm_config, &mut self.sysbus, vcpu_fds, vcpu_count)
     }
 
     fn realize_virtio_mmio_device(
@@ -489,10 +744,14 @@ impl MachineOps for LightMachine {
         Ok(realized_virtio_mmio_device)
     }
 
-    fn get_sys_mem(&mut self) -> &Arc<AddressSpace> {
+    fn get_sys_mem(&self) -> &Arc<AddressSpace> {
         &self.sys_mem
     }
 
+    fn get_boot_source(&self) -> &Arc<Mutex<BootSource>> {
+        &self.boot_source
+    }
+
     fn get_vm_config(&self) -> Arc<Mutex<VmConfig>> {
         self.vm_config.clone()
     }
@@ -501,6 +760,10 @@ impl MachineOps for LightMachine {
         &self.vm_state
     }
 
+    fn get_cpus(&self) -> &[Arc<CPU>] {
+        &self.cpus
+    }
+
     fn get_migrate_info(&self) -> Incoming {
         if let Some((mode, path)) = self.get_vm_config().lock().unwrap().incoming.as_ref() {
             return (*mode, path.to_string());
@@ -509,7 +772,7 @@ impl MachineOps for LightMachine {
         (MigrateMode::Unknown, String::new())
     }
 
-    fn get_sys_bus(&mut self) -> &SysBus {
+    fn get_sys_bus(&self) -> &SysBus {
         &self.sysbus
     }
 
@@ -544,20 +807,18 @@ impl MachineOps for LightMachine {
         irq_chip: Arc<Mutex<InterruptController>>,
     ) -> MachineResult<()> {
         let device_cfg = parse_net(vm_config, cfg_args)?;
-        if device_cfg.vhost_type.is_some() {
+        if device_cfg.vhost_type.as_deref() == Some("vhost-kernel") {
             let net = Arc::new(Mutex::new(VhostKern::Net::new(&device_cfg, &self.sys_mem)));
             let device = VirtioMmioDevice::new(&self.sys_mem, net, #[cfg(target_arch = "riscv64")] irq_chip.clone());
             self.realize_virtio_mmio_device(device)?;
         } else {
-            let index = MMIO_REPLACEABLE_BLK_NR + self.replaceable_info.net_count;
-            if index >= MMIO_REPLACEABLE_BLK_NR + MMIO_REPLACEABLE_NET_NR {
-                bail!(
-                    "A maximum of {} net replaceable devices are supported.",
-                    MMIO_REPLACEABLE_NET_NR
-                );
-            }
-            self.fill_replaceable_device(&device_cfg.id, Arc::new(device_cfg.clone()), index)?;
-            self.replaceable_info.net_count += 1;
+            self.fill_replaceable_device(
+                &device_cfg.id,
+                "virtio-net-device",
+                Arc::new(device_cfg.clone()),
+                #[cfg(target_arch = "riscv64")]
+                irq_chip,
+            )?;
         }
         Ok(())
     }
@@ -566,17 +827,35 @@ impl MachineOps for LightMachine {
         &mut self,
         vm_config: &mut VmConfig,
         cfg_args: &str,
+        #[cfg(target_arch = "riscv64")]
+        irq_chip: Arc<Mutex<InterruptController>>,
     ) -> MachineResult<()> {
         let device_cfg = parse_blk(vm_config, cfg_args, None)?;
-        if self.replaceable_info.block_count >= MMIO_REPLACEABLE_BLK_NR {
-            bail!(
-                "A maximum of {} block replaceable devices are supported.",
-                MMIO_REPLACEABLE_BLK_NR
-            );
-        }
-        let index = self.replaceable_info.block_count;
-        self.fill_replaceable_device(&device_cfg.id, Arc::new(device_cfg.clone()), index)?;
-        self.replaceable_info.block_count += 1;
+        self.fill_replaceable_device(
+            &device_cfg.id,
+            "virtio-blk-device",
+            Arc::new(device_cfg.clone()),
+            #[cfg(target_arch = "riscv64")]
+            irq_chip,
+        )?;
+        Ok(())
+    }
+
+    fn add_vhost_user_blk_device(
+        &mut self,
+        vm_config: &mut VmConfig,
+        cfg_args: &str,
+        #[cfg(target_arch = "riscv64")]
+        irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> MachineResult<()> {
+        let device_cfg = parse_vhost_user_blk_device(vm_config, cfg_args)?;
+        self.fill_replaceable_device(
+            &device_cfg.id,
+            "vhost-user-blk-device",
+            Arc::new(device_cfg.clone()),
+            #[cfg(target_arch = "riscv64")]
+            irq_chip,
+        )?;
         Ok(())
     }
 
@@ -623,17 +902,20 @@ impl MachineOps for LightMachine {
         )?;
 
         locked_vm
-            .create_replaceable_devices(#[cfg(target_arch = "riscv64")] irq_chip.clone())
+            .create_replaceable_devices(
+                &vm_config.machine_config.hotplug_slots,
+                #[cfg(target_arch = "riscv64")]
+                irq_chip.clone(),
+            )
             .with_context(|| "Failed to create replaceable devices.")?;
         locked_vm.add_devices(vm_config, #[cfg(target_arch = "riscv64")] irq_chip.clone())?;
         trace_replaceable_info(&locked_vm.replaceable_info);
 
-        let boot_config = Some(locked_vm.load_boot_source(None)?);
-        // if migrate_info.0 == MigrateMode::Unknown {
-        //     Some(locked_vm.load_boot_source(None)?)
-        // } else {
-        //     None
-        // };
+        let boot_config = if migrate_info.0 == MigrateMode::Unknown {
+            Some(locked_vm.load_boot_source(None)?)
+        } else {
+            None
+        };
 
         let topology = CPUTopology::new().set_topology((
             vm_config.machine_config.nr_threads,
@@ -659,25 +941,20 @@ impl MachineOps for LightMachine {
         )?);
 
         if let Some(boot_cfg) = boot_config {
-            let mut fdt_helper = FdtBuilder::new();
-            locked_vm
-                .generate_fdt_node(&mut fdt_helper)
-                .with_context(|| anyhow!(MachineError::GenFdtErr))?;
-            let fdt_vec = fdt_helper.finish()?;
-            locked_vm
-                .sys_mem
-                .write(
-                    &mut fdt_vec.as_slice(),
-                    GuestAddress(boot_cfg.fdt_addr as u64),
-                    fdt_vec.len() as u64,
-                )
-                .with_context(|| {
-                    anyhow!(MachineError::WrtFdtErr(boot_cfg.fdt_addr, fdt_vec.len()))
-                })?;
+            load_fdt(&*locked_vm, &boot_cfg)?;
         }
         locked_vm
             .register_power_event(locked_vm.power_button.clone())
             .with_context(|| anyhow!(MachineError::InitEventFdErr("power_button".to_string())))?;
+        locked_vm
+            .register_reset_event(locked_vm.reset_req.clone(), vm.clone())
+            .with_context(|| anyhow!(MachineError::InitEventFdErr("reset_req".to_string())))?;
+
+        MigrationManager::register_vm_config(locked_vm.get_vm_config());
+        MigrationManager::register_vm_instance(vm.clone());
+        if let Err(e) = MigrationManager::set_status(MigrationStatus::Setup) {
+            bail!("Failed to set migration status {}", e);
+        }
 
         Ok(())
     }
@@ -725,13 +1002,12 @@ impl MachineLifecycle for LightMachine {
 
 
     fn reset(&mut self) -> bool {
-        // For micro vm, the reboot command is equivalent to the shutdown command.
-        for cpu in self.cpus.iter() {
-            let (cpu_state, _) = cpu.state();
-            *cpu_state.lock().unwrap() = CpuLifecycleState::Stopped;
+        // The reset is done in the main loop, vCPUs can't pause themselves.
+        if self.reset_req.write(1).is_err() {
+            error!("Micro vm write reset request failed");
+            return false;
         }
-
-        self.destroy()
+        true
     }
 
     fn notify_lifecycle(&self, old: KvmVmState, new: KvmVmState) -> bool {
@@ -858,6 +1134,28 @@ impl DeviceInterface for LightMachine {
         Response::create_response(hotplug_vec.into(), None)
     }
 
+    fn query_hotplug_slots(&self) -> Response {
+        let mut slot_vec: Vec<serde_json::Value> = Vec::new();
+        let replaceable_devices = self.replaceable_info.devices.lock().unwrap();
+        for (slot, device_info) in replaceable_devices.iter().enumerate() {
+            let (id, driver) = if device_info.used {
+                (Some(device_info.id.clone()), Some(device_info.driver.clone()))
+            } else {
+                (None, None)
+            };
+            let hotplug_slot = qmp_schema::HotplugSlot {
+                slot,
+                slot_type: device_info.slot_type.to_string(),
+                addr: format!("0x{:x}", device_info.addr),
+                used: device_info.used,
+                id,
+                driver,
+            };
+            slot_vec.push(serde_json::to_value(hotplug_slot).unwrap());
+        }
+        Response::create_response(slot_vec.into(), None)
+    }
+
     fn balloon(&self, value: u64) -> Response {
         // if qmp_balloon(value) {
         //     return Response::create_empty_response();
@@ -884,19 +1182,19 @@ impl DeviceInterface for LightMachine {
     }
 
     fn device_add(&mut self, args: Box<qmp_schema::DeviceAddArgument>) -> Response {
-        // get slot of bus by addr or lun
-        let mut slot = 0;
-        if let Some(addr) = args.addr {
+        // get slot of bus by addr or lun, take the first free slot otherwise
+        let mut slot = None;
+        if let Some(addr) = args.addr.as_ref() {
             let slot_str = addr.as_str().trim_start_matches("0x");
 
             if let Ok(n) = usize::from_str_radix(slot_str, 16) {
-                slot = n;
+                slot = Some(n);
             }
         } else if let Some(lun) = args.lun {
-            slot = lun + 1;
+            slot = Some(lun + 1);
         }
 
-        match self.add_replaceable_device(&args.id, &args.driver, slot) {
+        match self.add_replaceable_device(&args, slot) {
             Ok(()) => Response::create_empty_response(),
             Err(ref e) => {
                 error!("{:?}", e);
@@ -931,35 +1229,38 @@ impl DeviceInterface for LightMachine {
     }
 
     fn blockdev_add(&self, args: Box<qmp_schema::BlockDevAddArgument>) -> Response {
-        let read_only = args.read_only.unwrap_or(false);
-        let direct = if let Some(cache) = args.cache {
-            match cache.direct {
-                Some(direct) => direct,
-                _ => true,
+        let drive = match self.parse_blockdev_config(args) {
+            Ok(drive) => drive,
+            Err(ref e) => {
+                error!("{:?}", e);
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                );
             }
-        } else {
-            true
         };
-
+        let read_only = drive.read_only;
+        let direct = drive.direct;
         let config = BlkDevConfig {
-            id: args.node_name.clone(),
-            path_on_host: args.file.filename,
+            id: drive.id.clone(),
+            path_on_host: drive.path_on_host,
             read_only,
             direct,
             serial_num: None,
             iothread: None,
-            iops: None,
+            throttle: drive.throttle,
             queues: 1,
             boot_index: None,
             chardev: None,
             socket_path: None,
-            // TODO Add aio option by qmp, now we set it based on "direct".
-            aio: if direct {
-                AioEngine::Native
-            } else {
-                AioEngine::Off
-            },
+            aio: drive.aio,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            format: drive.format,
+            discard: drive.discard,
+            write_zeroes: drive.write_zeroes,
+            werror: drive.werror,
+            rerror: drive.rerror,
+            packed: false,
         };
         if let Err(e) = config.check() {
             error!("{:?}", e);
@@ -968,10 +1269,21 @@ impl DeviceInterface for LightMachine {
                 None,
             );
         }
-        match self.add_replaceable_config(&args.node_name, Arc::new(config)) {
+        let path = config.path_on_host.clone();
+        if let Err(e) = self.register_drive_file(&path, read_only, direct) {
+            error!("{:?}", e);
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
+        match self.add_replaceable_config(&drive.id, Arc::new(config)) {
             Ok(()) => Response::create_empty_response(),
             Err(ref e) => {
                 error!("{:?}", e);
+                if let Err(e) = self.unregister_drive_file(&path) {
+                    error!("{:?}", e);
+                }
                 Response::create_error_response(
                     qmp_schema::QmpErrorClass::GenericError(e.to_string()),
                     None,
@@ -980,11 +1292,78 @@ impl DeviceInterface for LightMachine {
         }
     }
 
-    fn blockdev_del(&self, _node_name: String) -> Response {
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::GenericError("blockdev_del not support yet".to_string()),
-            None,
-        )
+    fn blockdev_del(&self, node_name: String) -> Response {
+        match self.del_replaceable_backend::<BlkDevConfig>(&node_name) {
+            Ok(_) => {
+                let blockdev_del_event = qmp_schema::BlockdevDeleted { node_name };
+                event!(BlockdevDeleted; blockdev_del_event);
+
+                Response::create_empty_response()
+            }
+            Err(ref e) => {
+                error!("Failed to delete blockdev: {:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_resize(&self, device: String, size: u64) -> Response {
+        self.qmp_block_resize(device, size)
+    }
+
+    fn block_set_io_throttle(&self, args: Box<qmp_schema::BlockSetIoThrottleArgument>) -> Response {
+        self.qmp_block_set_io_throttle(args)
+    }
+
+    fn blockdev_snapshot_sync(&self, args: qmp_schema::BlockdevSnapshotSyncArgument) -> Response {
+        self.qmp_blockdev_snapshot_sync(args)
+    }
+
+    fn block_commit(&self, args: qmp_schema::BlockCommitArgument) -> Response {
+        self.qmp_block_commit(args)
+    }
+
+    fn drive_mirror(&self, args: qmp_schema::DriveMirrorArgument) -> Response {
+        self.qmp_drive_mirror(args)
+    }
+
+    fn drive_backup(&self, args: qmp_schema::DriveBackupArgument) -> Response {
+        self.qmp_drive_backup(args)
+    }
+
+    fn block_job_cancel(&self, device: String) -> Response {
+        self.qmp_block_job_cancel(device)
+    }
+
+    fn block_job_complete(&self, device: String) -> Response {
+        self.qmp_block_job_complete(device)
+    }
+
+    fn query_block(&self) -> Response {
+        let blocks: Vec<qmp_schema::BlockInfo> = block_devices()
+            .iter()
+            .map(|block| block.lock().unwrap().query_info())
+            .collect();
+        Response::create_response(serde_json::to_value(&blocks).unwrap(), None)
+    }
+
+    fn query_named_block_nodes(&self) -> Response {
+        self.qmp_query_named_block_nodes()
+    }
+
+    fn query_blockstats(&self) -> Response {
+        let stats: Vec<qmp_schema::BlockStats> = block_devices()
+            .iter()
+            .map(|block| block.lock().unwrap().query_stats())
+            .collect();
+        Response::create_response(serde_json::to_value(&stats).unwrap(), None)
+    }
+
+    fn query_block_jobs(&self) -> Response {
+        Response::create_response(serde_json::to_value(block_jobs()).unwrap(), None)
     }
 
     fn netdev_add(&mut self, args: Box<qmp_schema::NetDevAddArgument>) -> Response {
@@ -1000,9 +1379,34 @@ impl DeviceInterface for LightMachine {
             mq: false,
             socket_path: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            user: None,
+            packed: false,
         };
 
-        if let Some(fds) = args.fds {
+        if args.net_type.as_deref() == Some("vhost-user") {
+            let socket_path = args
+                .chardev
+                .as_ref()
+                .with_context(|| "Chardev is not set for vhost-user netdev")
+                .and_then(|chardev| {
+                    get_chardev_socket_path(chardev, &mut self.vm_config.lock().unwrap())
+                });
+            match socket_path {
+                Ok(path) => {
+                    config.vhost_type = Some("vhost-user".to_string());
+                    config.socket_path = Some(path);
+                }
+                Err(ref e) => {
+                    error!("{:?}", e);
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                        None,
+                    );
+                }
+            }
+        } else if args.net_type.as_deref() == Some("user") {
+            config.user = Some(UserNetConfig::default());
+        } else if let Some(fds) = args.fds {
             let netdev_fd = if fds.contains(':') {
                 let col: Vec<_> = fds.split(':').collect();
                 String::from(col[col.len() - 1])
@@ -1055,11 +1459,23 @@ impl DeviceInterface for LightMachine {
         }
     }
 
-    fn netdev_del(&mut self, _node_name: String) -> Response {
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::GenericError("netdev_del not support yet".to_string()),
-            None,
-        )
+    fn netdev_del(&mut self, id: String) -> Response {
+        match self.del_replaceable_backend::<NetworkInterfaceConfig>(&id) {
+            Ok(dev_config) => {
+                close_netdev_fds(&dev_config);
+                let netdev_del_event = qmp_schema::NetdevDeleted { id };
+                event!(NetdevDeleted; netdev_del_event);
+
+                Response::create_empty_response()
+            }
+            Err(ref e) => {
+                error!("Failed to delete netdev: {:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
     }
 
     fn chardev_add(&mut self, _args: qmp_schema::CharDevAddArgument) -> Response {
@@ -1081,39 +1497,22 @@ impl DeviceInterface for LightMachine {
     }
 
     fn getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> Response {
-        if let Some(fd) = if_fd {
-            QmpChannel::set_fd(fd_name, fd);
-            Response::create_empty_response()
-        } else {
-            let err_resp =
-                qmp_schema::QmpErrorClass::GenericError("Invalid SCM message".to_string());
-            Response::create_error_response(err_resp, None)
-        }
+        self.qmp_getfd(fd_name, if_fd)
     }
 }
 
 impl MigrateInterface for LightMachine {
     fn migrate(&self, uri: String) -> Response {
-        match parse_incoming_uri(&uri) {
-            Ok((MigrateMode::File, path)) => migration::snapshot(path),
-            Ok((MigrateMode::Unix, _)) | Ok((MigrateMode::Tcp, _)) => {
-                Response::create_error_response(
-                    qmp_schema::QmpErrorClass::GenericError(
-                        "MicroVM does not support migration".to_string(),
-                    ),
-                    None,
-                )
-            }
-            _ => Response::create_error_response(
-                qmp_schema::QmpErrorClass::GenericError(format!("Invalid uri: {}", uri)),
-                None,
-            ),
-        }
+        self.qmp_migrate(uri)
     }
 
     fn query_migrate(&self) -> Response {
         migration::query_migrate()
     }
+
+    fn cancel_migrate(&self) -> Response {
+        migration::cancel_migrate()
+    }
 }
 
 impl MachineInterface for LightMachine {}
@@ -1131,172 +1530,16 @@ impl EventLoopManager for LightMachine {
     }
 }
 
-#[cfg(target_arch = "riscv64")]
-fn generate_plic_device_node(
-    fdt: &mut FdtBuilder,
-    res: &SysRes,
-    nr_vcpu: usize,
-) -> util::Result<()> {
-    let region_base = res.region_base;
-    let region_size = res.region_size;
-    let node = format!("interrupt-controller@{:x}", region_base);
-    let intc_node_dep = fdt.begin_node(&node)?;
-    fdt.set_property_string("compatible", "riscv,plic0")?;
-    fdt.set_property("interrupt-controller", &Vec::new())?;
-    fdt.set_property_u32("#interrupt-cells", 0x1)?;
-    fdt.set_property_u32("phandle", device_tree::PLIC_PHANDLE)?;
-    fdt.set_property_u32("riscv,ndev", 10)?;
-    // fdt.set_property_u32("riscv,ndev", MAX_DEVICES - 1)?;
-    fdt.set_property_array_u64("reg", &[region_base, region_size])?;
-
-    let num_context = nr_vcpu * 2;
-    let mut irq_cells = Vec::new();
-    let mut i: u32 = 0;
-    while i < (num_context / 2) as u32 {
-        irq_cells.push(device_tree::INCT_PHANDLE_START + i);
-        irq_cells.push(0xffff_ffff);
-        irq_cells.push(device_tree::INCT_PHANDLE_START + i);
-        irq_cells.push(9);
-        i += 1;
-    }
-
-    let irq_cells = irq_cells.as_slice();
-    fdt.set_property_array_u32("interrupts-extended", irq_cells)?;
-
-    fdt.end_node(intc_node_dep)?;
-
-    Ok(())
-}
-
-// Function that helps to generate serial node in device-tree.
-//
-// # Arguments
-//
-// * `dev_info` - Device resource info of serial device.
-// * `fdt` - Flatted device-tree blob where serial node will be filled into.
-#[cfg(target_arch = "riscv64")]
-fn generate_serial_device_node(fdt: &mut FdtBuilder, res: &SysRes) -> util::Result<()> {
-    let node = format!("uart@{:x}", res.region_base);
-    let serial_node_dep = fdt.begin_node(&node)?;
-    fdt.set_property_string("compatible", "ns16550a")?;
-    fdt.set_property_array_u64("reg", &[res.region_base, res.region_size])?;
-    fdt.set_property_u32("clock-frequency", 3686400)?;
-    fdt.set_property_u32("interrupt-parent", device_tree::PLIC_PHANDLE)?;
-    fdt.set_property_u32("interrupts", res.irq as u32)?;
-    fdt.end_node(serial_node_dep)?;
-    Ok(())
-}
-
-// Function that helps to generate Virtio-Mmio device's node in device-tree.
-//
-// # Arguments
-//
-// * `dev_info` - Device resource info of Virtio-Mmio device.
-// * `fdt` - Flatted device-tree blob where node will be filled into.
-#[cfg(target_arch = "riscv64")]
-fn generate_virtio_devices_node(fdt: &mut FdtBuilder, res: &SysRes) -> util::Result<()> {
-    let node = format!("virtio_mmio@{:x}", res.region_base);
-    let virtio_node_dep = fdt.begin_node(&node)?;
-    fdt.set_property_string("compatible", "virtio,mmio")?;
-    fdt.set_property_u32("interrupt-parent", device_tree::PLIC_PHANDLE)?;
-    fdt.set_property_array_u64("reg", &[res.region_base, res.region_size])?;
-    fdt.set_property_u32("interrupts", res.irq as u32)?;
-    fdt.end_node(virtio_node_dep)?;
-    Ok(())
-}
-
-/// Trait that helps to generate all nodes in device-tree.
-#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
-trait CompileFDTHelper {
-    /// Function that helps to generate cpu nodes.
-    fn generate_cpu_nodes(&self, fdt: &mut FdtBuilder) -> util::Result<()>;
-    /// Function that helps to generate memory nodes.
-    fn generate_memory_node(&self, fdt: &mut FdtBuilder) -> util::Result<()>;
-    /// Function that helps to generate Virtio-mmio devices' nodes.
-    fn generate_devices_node(&self, fdt: &mut FdtBuilder) -> util::Result<()>;
-    /// Function that helps to generate the chosen node.
-    fn generate_chosen_node(&self, fdt: &mut FdtBuilder) -> util::Result<()>;
-}
-
 #[cfg(target_arch = "riscv64")]
 impl CompileFDTHelper for LightMachine {
     fn generate_cpu_nodes(&self, fdt: &mut FdtBuilder) -> util::Result<()> {
-        let node = "cpus";
-
-        let cpus = &self.cpus;
-        let cpus_node_dep = fdt.begin_node(node)?;
-        fdt.set_property_u32("#address-cells", 0x02)?;
-        fdt.set_property_u32("#size-cells", 0x0)?;
-        let frequency = cpus[0].arch().lock().unwrap().timer_regs().frequency;
-        fdt.set_property_u32("timebase-frequency", frequency as u32)?;
-
-        let nr_vcpus = cpus.len();
-        for cpu_index in 0..nr_vcpus {
-            let node = format!("cpu@{:x}", cpu_index);
-            let cpu_node_dep = fdt.begin_node(&node)?;
-            fdt.set_property_u32(
-                "phandle",
-                cpu_index as u32 + device_tree::CPU_PHANDLE_START,
-            )?;
-            fdt.set_property_string("device_type", "cpu")?;
-            fdt.set_property_string("compatible", "riscv")?;
-
-            let xlen = self.cpus[cpu_index]
-                .arch()
-                .lock()
-                .unwrap()
-                .get_xlen()
-                .to_string();
-            let mut isa = format!("rv{}", xlen);
-            let valid_isa_order = String::from("IEMAFDQCLBJTPVNSUHKORWXYZG");
-            for char in valid_isa_order.chars() {
-                let index = char as u32 - 'A' as u32;
-                let cpu_isa = self.cpus[cpu_index]
-                    .arch()
-                    .lock()
-                    .unwrap()
-                    .config_regs()
-                    .isa;
-                if (cpu_isa & (1 << index) as u64) > 0 {
-                    let tmp = char::from('a' as u8 + index as u8);
-                    isa = format!("{}{}", isa, tmp);
-                }
-            }
-
-            fdt.set_property_string("riscv,isa", &isa)?;
-
-            fdt.set_property_u64("reg", cpu_index as u64)?;
-
-            let node = "interrupt-controller";
-            let interrupt_controller = fdt.begin_node(node)?;
-            fdt.set_property_string("compatible", "riscv,cpu-intc")?;
-            fdt.set_property_u32("#interrupt-cells", 1)?;
-            fdt.set_property("interrupt-controller", &Vec::new())?;
-            fdt.set_property_u32(
-                "phandle",
-                cpu_index as u32 + device_tree::INCT_PHANDLE_START,
-            )?;
-            fdt.end_node(interrupt_controller)?;
-
-            fdt.end_node(cpu_node_dep)?;
-        }
-
-        fdt.end_node(cpus_node_dep)?;
-
-        Ok(())
+        fdt::generate_cpu_nodes(fdt, &self.cpus)
     }
 
     fn generate_memory_node(&self, fdt: &mut FdtBuilder) -> util::Result<()> {
         let mem_base = MEM_LAYOUT[LayoutEntryType::Mem as usize].0;
-        let mem_size = self.sys_mem.memory_end_address().raw_value()
-            - MEM_LAYOUT[LayoutEntryType::Mem as usize].0;
-        let node = "memory";
-        let memory_node_dep = fdt.begin_node(node)?;
-        fdt.set_property_string("device_type", "memory")?;
-        fdt.set_property_array_u64("reg", &[mem_base, mem_size as u64])?;
-        fdt.end_node(memory_node_dep)?;
-
-        Ok(())
+        let mem_size = self.sys_mem.memory_end_address().raw_value() - mem_base;
+        fdt::generate_memory_node(fdt, mem_base, mem_size)
     }
 
     fn generate_devices_node(&self, fdt: &mut FdtBuilder) -> util::Result<()> {
@@ -1307,59 +1550,20 @@ impl CompileFDTHelper for LightMachine {
         fdt.set_property_u32("#size-cells", 0x2)?;
         fdt.set_property("ranges", &Vec::new())?;
 
-        for dev in self.sysbus.devices.iter() {
-            let mut locked_dev = dev.lock().unwrap();
-            let dev_type = locked_dev.get_type();
-            let sys_res = locked_dev.get_sys_resource().unwrap();
-            match dev_type {
-                SysBusDevType::Plic => generate_plic_device_node(fdt, sys_res, self.cpus.len())?,
-                SysBusDevType::Serial => generate_serial_device_node(fdt, sys_res)?,
-                SysBusDevType::VirtioMmio => generate_virtio_devices_node(fdt, sys_res)?,
-                _ => (),
-            }
-        }
+        let irqchip = self
+            .vm_config
+            .lock()
+            .unwrap()
+            .machine_config
+            .irqchip
+            .unwrap_or_default();
+        fdt::generate_sysbus_devices_nodes(fdt, &self.sysbus, irqchip, self.cpus.len())?;
         fdt.end_node(smb_node_dep)?;
         Ok(())
     }
 
     fn generate_chosen_node(&self, fdt: &mut FdtBuilder) -> util::Result<()> {
-        let node = "chosen";
-        let boot_source = self.boot_source.lock().unwrap();
-
-        let chosen_node_dep = fdt.begin_node(node)?;
-        let cmdline = &boot_source.kernel_cmdline.to_string();
-        fdt.set_property_string("bootargs", cmdline.as_str())?;
-
-        match &boot_source.initrd {
-            Some(initrd) => {
-                fdt.set_property_u64("linux,initrd-start", initrd.initrd_addr)?;
-                fdt.set_property_u64("linux,initrd-end", initrd.initrd_addr + initrd.initrd_size)?;
-            }
-            None => {}
-        }
-        fdt.end_node(chosen_node_dep)?;
-
-        Ok(())
-    }
-}
-
-#[cfg(target_arch = "riscv64")]
-impl device_tree::CompileFDT for LightMachine {
-    fn generate_fdt_node(&self, fdt: &mut FdtBuilder) -> util::Result<()> {
-        let node_dep = fdt.begin_node("")?;
-
-        fdt.set_property_string("compatible", "linux,dummy-virt")?;
-        fdt.set_property_u32("#address-cells", 0x2)?;
-        fdt.set_property_u32("#size-cells", 0x2)?;
-
-        self.generate_cpu_nodes(fdt)?;
-        self.generate_memory_node(fdt)?;
-        self.generate_devices_node(fdt)?;
-        self.generate_chosen_node(fdt)?;
-
-        fdt.end_node(node_dep)?;
-
-        Ok(())
+        fdt::generate_chosen_node(fdt, &self.boot_source.lock().unwrap())
     }
 }
 
@@ -1383,3 +1587,208 @@ fn trace_vm_state(vm_state: &Arc<(Mutex<KvmVmState>, Condvar)>) {
 fn trace_mmio_replaceable_config(config: &MmioReplaceableConfig) {
     util::ftrace!(trace_mmio_replaceable_config, "{:#?}", config);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use machine_manager::config::{DiskFormat, IrqChipType};
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+    use virtio::{cancel_block_job, start_copy_job, SyncMode};
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_slot_type_of() {
+        assert_eq!(slot_type_of("virtio-net-device").unwrap(), HotplugSlotType::Net);
+        assert_eq!(slot_type_of("virtio-blk-device").unwrap(), HotplugSlotType::Blk);
+        assert_eq!(slot_type_of("vhost-user-blk-device").unwrap(), HotplugSlotType::Blk);
+        assert_eq!(slot_type_of("vhost-vsock-device").unwrap(), HotplugSlotType::Vsock);
+        assert_eq!(slot_type_of("virtio-console-device").unwrap(), HotplugSlotType::Console);
+        assert!(slot_type_of("virtio-rng-device").is_err());
+    }
+
+    fn test_machine() -> LightMachine {
+        let mut vm_config = VmConfig::default();
+        // The APLIC is emulated in userspace, no vCPU is needed.
+        vm_config.machine_config.irqchip = Some(IrqChipType::Aplic);
+        let mut vm = LightMachine::new(&vm_config).unwrap();
+        #[cfg(target_arch = "riscv64")]
+        let irq_chip =
+            create_interrupt_controller(&vm.vm_config, &mut vm.sysbus, Vec::new(), 1).unwrap();
+        vm.create_replaceable_devices(
+            &[HotplugSlotType::Blk, HotplugSlotType::Net],
+            #[cfg(target_arch = "riscv64")]
+            irq_chip,
+        )
+        .unwrap();
+        vm
+    }
+
+    /// Mark `slot` used by the device `id`, plugging a real device would need
+    /// its backend on the host.
+    fn mark_plugged(vm: &LightMachine, slot: usize, id: &str) {
+        let mut replaceable_devices = vm.replaceable_info.devices.lock().unwrap();
+        replaceable_devices[slot].id = id.to_string();
+        replaceable_devices[slot].driver = "virtio-net-device".to_string();
+        replaceable_devices[slot].used = true;
+    }
+
+    fn has_config(vm: &LightMachine, id: &str) -> bool {
+        vm.replaceable_info
+            .configs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|config| config.id == id)
+    }
+
+    #[test]
+    fn test_del_replaceable_net_backend() {
+        let vm = test_machine();
+        let net_cfg = NetworkInterfaceConfig {
+            id: "net0".to_string(),
+            ..Default::default()
+        };
+        vm.add_replaceable_config("net0", Arc::new(net_cfg.clone()))
+            .unwrap();
+        mark_plugged(&vm, 1, "net0");
+
+        // The backend of a plugged device is refused, whatever its type.
+        assert!(vm
+            .del_replaceable_backend::<NetworkInterfaceConfig>("net0")
+            .is_err());
+        assert!(vm.del_replaceable_backend::<BlkDevConfig>("net0").is_err());
+        assert!(has_config(&vm, "net0"));
+
+        // Unplugging the device frees the slot.
+        assert!(vm.unplug_replaceable_device("net0").unwrap());
+        let replaceable_devices = vm.replaceable_info.devices.lock().unwrap();
+        assert!(!replaceable_devices[1].used);
+        assert!(replaceable_devices[1].id.is_empty());
+        drop(replaceable_devices);
+        assert!(!vm.unplug_replaceable_device("net0").unwrap());
+
+        // Then the backend can be deleted, and its id reused.
+        let dev_config = vm
+            .del_replaceable_backend::<NetworkInterfaceConfig>("net0")
+            .unwrap();
+        assert!(dev_config.as_any().is::<NetworkInterfaceConfig>());
+        assert!(!has_config(&vm, "net0"));
+        assert!(vm
+            .del_replaceable_backend::<NetworkInterfaceConfig>("net0")
+            .is_err());
+        vm.add_replaceable_config("net0", Arc::new(net_cfg))
+            .unwrap();
+        assert!(has_config(&vm, "net0"));
+    }
+
+    #[test]
+    fn test_del_replaceable_blk_backend() {
+        let vm = test_machine();
+        let path = std::env::temp_dir().join("test_del_replaceable_blk_backend.img");
+        let path = path.to_str().unwrap().to_string();
+        std::fs::File::create(&path)
+            .unwrap()
+            .set_len(0x10_0000)
+            .unwrap();
+        vm.register_drive_file(&path, false, false).unwrap();
+        let blk_cfg = BlkDevConfig {
+            id: "drive0".to_string(),
+            path_on_host: path.clone(),
+            direct: false,
+            ..Default::default()
+        };
+        vm.add_replaceable_config("drive0", Arc::new(blk_cfg))
+            .unwrap();
+
+        assert!(vm
+            .del_replaceable_backend::<NetworkInterfaceConfig>("drive0")
+            .is_err());
+        assert!(vm.drive_files.lock().unwrap().contains_key(&path));
+
+        // Deleting the backend releases its drive file.
+        vm.del_replaceable_backend::<BlkDevConfig>("drive0")
+            .unwrap();
+        assert!(!has_config(&vm, "drive0"));
+        assert!(!vm.drive_files.lock().unwrap().contains_key(&path));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_del_device_with_block_job() {
+        let mut vm = test_machine();
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(4 << 16).unwrap();
+        let path = image.as_path().to_str().unwrap().to_string();
+        let target = TempFile::new().unwrap();
+        let target_path = target.as_path().to_str().unwrap().to_string();
+        vm.register_drive_file(&path, false, false).unwrap();
+        let blk_cfg = BlkDevConfig {
+            id: "drive-job".to_string(),
+            path_on_host: path,
+            direct: false,
+            ..Default::default()
+        };
+        vm.add_replaceable_config("drive-job", Arc::new(blk_cfg.clone()))
+            .unwrap();
+        let block = Arc::new(Mutex::new(Block::new(blk_cfg, vm.drive_files.clone())));
+        block.lock().unwrap().realize().unwrap();
+        block
+            .lock()
+            .unwrap()
+            .create_target(&target_path, DiskFormat::Raw, false)
+            .unwrap();
+        vm.register_drive_file(&target_path, false, false).unwrap();
+
+        // Slow enough to be still running while the device is deleted.
+        let target_image = (target_path.as_str(), DiskFormat::Raw);
+        let id = Some("backup-del".to_string());
+        start_copy_job(&block, false, id, target_image, SyncMode::Full, 64 << 10).unwrap();
+        assert!(vm.del_replaceable_device("drive-job").is_err());
+        assert_ne!(
+            vm.device_del("drive-job".to_string()),
+            Response::create_empty_response()
+        );
+        assert_ne!(
+            vm.blockdev_del("drive-job".to_string()),
+            Response::create_empty_response()
+        );
+        assert!(has_config(&vm, "drive-job"));
+
+        cancel_block_job("backup-del").unwrap();
+        while device_has_job("drive-job") {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        vm.del_replaceable_backend::<BlkDevConfig>("drive-job")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_close_netdev_fds() {
+        // The write ends of pipes stand for the tap and vhost fds, the read
+        // ends see the end of file once all of them are closed.
+        let mut read_ends = Vec::new();
+        let mut write_ends = Vec::new();
+        for _ in 0..3 {
+            let mut fds = [0; 2];
+            // SAFETY: `fds` has room for the two fds of the pipe.
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            // SAFETY: The read end is owned by nothing else.
+            read_ends.push(unsafe { File::from_raw_fd(fds[0]) });
+            write_ends.push(fds[1]);
+        }
+        let net_cfg = NetworkInterfaceConfig {
+            id: "net0".to_string(),
+            tap_fds: Some(write_ends[..2].to_vec()),
+            vhost_fds: Some(write_ends[2..].to_vec()),
+            ..Default::default()
+        };
+        close_netdev_fds(&(Arc::new(net_cfg) as Arc<dyn ConfigCheck>));
+
+        for mut read_end in read_ends {
+            let mut buf = [0_u8; 1];
+            assert_eq!(read_end.read(&mut buf).unwrap(), 0);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StandardVmError {
+    #[error("Pci")]
+    Pci {
+        #[from]
+        source: pci::error::PciError,
+    },
+    #[error("SysBus")]
+    SysBus {
+        #[from]
+        source: sysbus::error::SysBusError,
+    },
+    #[error("Failed to init PCIe host.")]
+    InitPciHostErr,
+    #[error("Failed to assign BARs of PCI devices.")]
+    AssignBarErr,
+    #[error("Failed to realize virtio mmio.")]
+    RlzVirtioMmioErr,
+}
@@ -0,0 +1,890 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+pub mod error;
+pub use error::StandardVmError;
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::vec::Vec;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::error;
+use vmm_sys_util::eventfd::EventFd;
+
+use address_space::{AddressSpace, GuestAddress, Region};
+use cpu::{CPUTopology, CpuTopology, CPU};
+use devices::legacy::Serial;
+#[cfg(target_arch = "riscv64")]
+use devices::InterruptController;
+use hypervisor::kvm::KVM_FDS;
+use kvm_ioctls::VcpuFd;
+use machine_manager::config::{
+    get_multi_function, get_netdev_config, get_pci_bdf, parse_blk, parse_net, BootSource,
+    ConfigCheck, DriveFile, Incoming, MigrateMode, PciBdf, SerialConfig, VmConfig,
+};
+use machine_manager::event;
+use machine_manager::machine::{
+    DeviceInterface, KvmVmState, MachineAddressInterface, MachineExternalInterface,
+    MachineInterface, MachineLifecycle, MigrateInterface,
+};
+use machine_manager::qmp::{qmp_schema, Response};
+use migration::{MigrationManager, MigrationStatus};
+use pci::{pci_devfn, PciBus, PciDevOps, PciHost, PCI_INTX_NUM};
+use sysbus::{SysBus, SysBusDevOps, IRQ_BASE, IRQ_MAX};
+use util::device_tree::FdtBuilder;
+use util::loop_context::EventLoopManager;
+use util::set_termi_canon_mode;
+use virtio::{
+    block_devices, block_jobs, register_block_device, Block, Net, VhostKern, VirtioDevice,
+    VirtioMmioDevice, VirtioPciDevice,
+};
+
+use super::Result as MachineResult;
+#[cfg(target_arch = "riscv64")]
+use super::{
+    create_interrupt_controller,
+    fdt::{self, CompileFDTHelper},
+    load_fdt,
+};
+use super::{
+    error::MachineError,
+    mem_layout::{LayoutEntryType, MEM_LAYOUT},
+    reset_sysbus_devices, MachineOps,
+};
+
+/// A wrapper around creating and using a kvm-based standard VM, the devices
+/// are plugged into a generic ECAM PCIe host bridge.
+pub struct StdMachine {
+    // `vCPU` topology, support sockets, cores, threads.
+    cpu_topo: CpuTopology,
+    // `vCPU` devices.
+    cpus: Vec<Arc<CPU>>,
+    // Memory address space.
+    sys_mem: Arc<AddressSpace>,
+    // System bus.
+    sysbus: SysBus,
+    // PCIe host bridge, created along with the interrupt controller.
+    pci_host: Option<Arc<Mutex<PciHost>>>,
+    // Interrupt controller, kept for the devices added through QMP.
+    #[cfg(target_arch = "riscv64")]
+    irq_chip: Option<Arc<Mutex<InterruptController>>>,
+    // VM running state.
+    vm_state: Arc<(Mutex<KvmVmState>, Condvar)>,
+    // Vm boot_source config.
+    boot_source: Arc<Mutex<BootSource>>,
+    // VM power button, handle VM `Shutdown` event.
+    power_button: Arc<EventFd>,
+    // Reset request, handle VM `Reset` event.
+    reset_req: Arc<EventFd>,
+    // All configuration information of virtual machine.
+    vm_config: Arc<Mutex<VmConfig>>,
+    // Drive backend files.
+    drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+}
+
+impl StdMachine {
+    /// Constructs a new `StdMachine`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_config` - Represents the configuration for VM.
+    pub fn new(vm_config: &VmConfig) -> MachineResult<Self> {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value()))
+            .with_context(|| anyhow!(MachineError::CrtMemSpaceErr))?;
+        let free_irqs: (u32, u32) = (IRQ_BASE, IRQ_MAX);
+        let mmio_region: (u64, u64) = (
+            MEM_LAYOUT[LayoutEntryType::Mmio as usize].0,
+            MEM_LAYOUT[LayoutEntryType::Mmio as usize + 1].0,
+        );
+        let sysbus = SysBus::new(&sys_mem, free_irqs, mmio_region);
+
+        // Machine state init
+        let vm_state = Arc::new((Mutex::new(KvmVmState::Created), Condvar::new()));
+        let power_button =
+            Arc::new(EventFd::new(libc::EFD_NONBLOCK).with_context(|| {
+                anyhow!(MachineError::InitEventFdErr("power_button".to_string()))
+            })?);
+        let reset_req = Arc::new(
+            EventFd::new(libc::EFD_NONBLOCK)
+                .with_context(|| anyhow!(MachineError::InitEventFdErr("reset_req".to_string())))?,
+        );
+
+        Ok(StdMachine {
+            cpu_topo: CpuTopology::new(
+                vm_config.machine_config.nr_cpus,
+                vm_config.machine_config.nr_sockets,
+                vm_config.machine_config.nr_dies,
+                vm_config.machine_config.nr_clusters,
+                vm_config.machine_config.nr_cores,
+                vm_config.machine_config.nr_threads,
+                vm_config.machine_config.max_cpus,
+            ),
+            cpus: Vec::new(),
+            sys_mem,
+            sysbus,
+            pci_host: None,
+            #[cfg(target_arch = "riscv64")]
+            irq_chip: None,
+            boot_source: Arc::new(Mutex::new(vm_config.clone().boot_source)),
+            vm_state,
+            power_button,
+            reset_req,
+            vm_config: Arc::new(Mutex::new(vm_config.clone())),
+            drive_files: Arc::new(Mutex::new(vm_config.init_drive_files()?)),
+        })
+    }
+
+    /// Create the PCIe host bridge, its ECAM region is attached to the system
+    /// bus and its INTx lines take four interrupt sources.
+    #[cfg(target_arch = "riscv64")]
+    fn init_pci_host(&mut self, irq_chip: Arc<Mutex<InterruptController>>) -> Result<()> {
+        let (ecam_base, ecam_size) = MEM_LAYOUT[LayoutEntryType::PcieEcam as usize];
+        let intx_base = self
+            .sysbus
+            .alloc_irqs(PCI_INTX_NUM as u32)
+            .map_err(|e| anyhow!(StandardVmError::from(e)))?;
+        let intx_irqs: [u32; PCI_INTX_NUM] = std::array::from_fn(|line| intx_base + line as u32);
+
+        let pci_host = Arc::new(Mutex::new(PciHost::new(
+            self.sys_mem.root(),
+            ecam_size,
+            intx_irqs,
+            irq_chip,
+        )));
+        let mut locked_host = pci_host.lock().unwrap();
+        locked_host.realize_host_bridge()?;
+        locked_host.set_sys_resource(&mut self.sysbus, ecam_base, ecam_size)?;
+        drop(locked_host);
+        self.sysbus.attach_device(&pci_host, ecam_base, ecam_size)?;
+        self.pci_host = Some(pci_host);
+        Ok(())
+    }
+
+    /// Program the BARs of the PCI devices in the MMIO window of the host
+    /// bridge, the guest is free to move them afterwards.
+    fn assign_pci_bars(&self) -> Result<()> {
+        if let Some(pci_host) = &self.pci_host {
+            let root_bus = pci_host.lock().unwrap().root_bus.clone();
+            PciBus::assign_bars(&root_bus, MEM_LAYOUT[LayoutEntryType::PcieMmio as usize])
+                .with_context(|| anyhow!(StandardVmError::AssignBarErr))?;
+        }
+        Ok(())
+    }
+
+    /// Plug a virtio-pci device added through QMP. Its BARs are left
+    /// unassigned, the guest programs them when it rescans the bus.
+    fn plug_virtio_pci_device(&mut self, args: &qmp_schema::DeviceAddArgument) -> Result<()> {
+        let irq_chip = self
+            .irq_chip
+            .clone()
+            .with_context(|| "Interrupt controller is not initialized")?;
+        let vm_config = self.get_vm_config();
+        let mut locked_config = vm_config.lock().unwrap();
+        match args.driver.as_str() {
+            "virtio-blk-pci" => locked_config.add_blk_device_config(args),
+            "virtio-net-pci" => locked_config.add_net_device_config(args),
+            _ => bail!("Unsupported device: {}", args.driver),
+        }
+        let cfg_args = locked_config.devices.last().unwrap().1.clone();
+        let ret = if args.driver == "virtio-blk-pci" {
+            self.add_virtio_pci_blk(&mut locked_config, &cfg_args, irq_chip)
+        } else {
+            self.add_virtio_pci_net(&mut locked_config, &cfg_args, irq_chip)
+        };
+        if ret.is_err() {
+            locked_config.devices.pop();
+        }
+        ret
+    }
+}
+
+impl MachineOps for StdMachine {
+    fn arch_ram_ranges(&self, mem_size: u64) -> Vec<(u64, u64)> {
+        let mem_start = MEM_LAYOUT[LayoutEntryType::Mem as usize].0;
+        vec![(mem_start, mem_size)]
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    fn init_interrupt_controller(
+        &mut self,
+        vcpu_fds: Vec<Arc<VcpuFd>>,
+        vcpu_count: u32,
+    ) -> MachineResult<Arc<Mutex<InterruptController>>> {
+        create_interrupt_controller(&self.vm_config, &mut self.sysbus, vcpu_fds, vcpu_count)
+    }
+
+    fn realize_virtio_mmio_device(
+        &mut self,
+        dev: VirtioMmioDevice,
+    ) -> MachineResult<Arc<Mutex<VirtioMmioDevice>>> {
+        let region_base = self.sysbus.min_free_base;
+        let region_size = MEM_LAYOUT[LayoutEntryType::Mmio as usize].1;
+        let realized_virtio_mmio_device =
+            VirtioMmioDevice::realize(dev, &mut self.sysbus, region_base, region_size)
+                .with_context(|| anyhow!(StandardVmError::RlzVirtioMmioErr))?;
+        self.sysbus.min_free_base += region_size;
+        Ok(realized_virtio_mmio_device)
+    }
+
+    fn get_sys_mem(&self) -> &Arc<AddressSpace> {
+        &self.sys_mem
+    }
+
+    fn get_boot_source(&self) -> &Arc<Mutex<BootSource>> {
+        &self.boot_source
+    }
+
+    fn get_vm_config(&self) -> Arc<Mutex<VmConfig>> {
+        self.vm_config.clone()
+    }
+
+    fn get_vm_state(&self) -> &Arc<(Mutex<KvmVmState>, Condvar)> {
+        &self.vm_state
+    }
+
+    fn get_cpus(&self) -> &[Arc<CPU>] {
+        &self.cpus
+    }
+
+    fn get_migrate_info(&self) -> Incoming {
+        if let Some((mode, path)) = self.get_vm_config().lock().unwrap().incoming.as_ref() {
+            return (*mode, path.to_string());
+        }
+
+        (MigrateMode::Unknown, String::new())
+    }
+
+    fn get_sys_bus(&self) -> &SysBus {
+        &self.sysbus
+    }
+
+    /// Reset all devices, the BARs of the PCI devices are programmed again as
+    /// the guest finds them where they were at boot.
+    fn reset_all_devices(&self) -> Result<()> {
+        reset_sysbus_devices(&self.sysbus)?;
+        self.assign_pci_bars()
+    }
+
+    fn add_serial_device(
+        &mut self,
+        config: &SerialConfig,
+        #[cfg(target_arch = "riscv64")] irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> MachineResult<()> {
+        let region_base: u64 = MEM_LAYOUT[LayoutEntryType::Uart as usize].0;
+        let region_size: u64 = MEM_LAYOUT[LayoutEntryType::Uart as usize].1;
+
+        let serial = Serial::new(
+            config.clone(),
+            #[cfg(target_arch = "riscv64")]
+            irq_chip,
+        );
+        serial
+            .realize(
+                &mut self.sysbus,
+                region_base,
+                region_size,
+                &self.boot_source,
+            )
+            .with_context(|| "Failed to realize serial device.")?;
+        Ok(())
+    }
+
+    fn get_drive_files(&self) -> Arc<Mutex<HashMap<String, DriveFile>>> {
+        self.drive_files.clone()
+    }
+
+    fn get_devfn_and_parent_bus(
+        &mut self,
+        bdf: &PciBdf,
+    ) -> MachineResult<(u8, Weak<Mutex<PciBus>>)> {
+        let pci_host = self.pci_host.as_ref().with_context(|| "No PCI host")?;
+        let root_bus = pci_host.lock().unwrap().root_bus.clone();
+        // There is no PCI bridge, all the devices are plugged into the root bus.
+        let bus_name = root_bus.lock().unwrap().name.clone();
+        if bdf.bus != bus_name {
+            bail!("PCI bus {} not found, only {} exists", bdf.bus, bus_name);
+        }
+        Ok((pci_devfn(bdf.addr.0, bdf.addr.1), Arc::downgrade(&root_bus)))
+    }
+
+    fn add_virtio_pci_blk(
+        &mut self,
+        vm_config: &mut VmConfig,
+        cfg_args: &str,
+        irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> MachineResult<()> {
+        let bdf = get_pci_bdf(cfg_args)?;
+        let multi_func = get_multi_function(cfg_args)?;
+        let device_cfg = parse_blk(vm_config, cfg_args, None)?;
+        let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
+        let block = Arc::new(Mutex::new(Block::new(
+            device_cfg.clone(),
+            self.get_drive_files(),
+        )));
+        register_block_device(&device_cfg.id, &block);
+        VirtioPciDevice::new(
+            device_cfg.id,
+            devfn,
+            self.sys_mem.clone(),
+            block,
+            parent_bus,
+            multi_func,
+            irq_chip,
+        )
+        .realize()
+        .with_context(|| "Failed to add virtio pci block device")?;
+        Ok(())
+    }
+
+    fn add_virtio_pci_net(
+        &mut self,
+        vm_config: &mut VmConfig,
+        cfg_args: &str,
+        irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> MachineResult<()> {
+        let bdf = get_pci_bdf(cfg_args)?;
+        let multi_func = get_multi_function(cfg_args)?;
+        let device_cfg = parse_net(vm_config, cfg_args)?;
+        let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
+        let net: Arc<Mutex<dyn VirtioDevice>> = if device_cfg.vhost_type.is_some() {
+            Arc::new(Mutex::new(VhostKern::Net::new(&device_cfg, &self.sys_mem)))
+        } else {
+            Arc::new(Mutex::new(Net::new(device_cfg.clone())))
+        };
+        VirtioPciDevice::new(
+            device_cfg.id,
+            devfn,
+            self.sys_mem.clone(),
+            net,
+            parent_bus,
+            multi_func,
+            irq_chip,
+        )
+        .realize()
+        .with_context(|| "Failed to add virtio pci net device")?;
+        Ok(())
+    }
+
+    fn realize(vm: &Arc<Mutex<Self>>, vm_config: &mut VmConfig) -> MachineResult<()> {
+        let mut locked_vm = vm.lock().unwrap();
+
+        locked_vm.init_memory(
+            &vm_config.machine_config.mem_config,
+            &locked_vm.sys_mem,
+            vm_config.machine_config.nr_cpus,
+        )?;
+
+        let migrate_info = locked_vm.get_migrate_info();
+
+        let mut vcpu_fds = vec![];
+        for vcpu_id in 0..vm_config.machine_config.nr_cpus {
+            vcpu_fds.push(Arc::new(
+                KVM_FDS
+                    .load()
+                    .vm_fd
+                    .as_ref()
+                    .unwrap()
+                    .create_vcpu(vcpu_id as u64)?,
+            ));
+        }
+
+        #[cfg(target_arch = "riscv64")]
+        let irq_chip = locked_vm.init_interrupt_controller(
+            vcpu_fds.clone(),
+            u32::from(vm_config.machine_config.nr_cpus),
+        )?;
+
+        #[cfg(target_arch = "riscv64")]
+        locked_vm
+            .init_pci_host(irq_chip.clone())
+            .with_context(|| anyhow!(StandardVmError::InitPciHostErr))?;
+        #[cfg(target_arch = "riscv64")]
+        {
+            locked_vm.irq_chip = Some(irq_chip.clone());
+        }
+        locked_vm.add_devices(
+            vm_config,
+            #[cfg(target_arch = "riscv64")]
+            irq_chip,
+        )?;
+
+        let boot_config = if migrate_info.0 == MigrateMode::Unknown {
+            locked_vm.assign_pci_bars()?;
+            Some(locked_vm.load_boot_source(None)?)
+        } else {
+            None
+        };
+
+        let topology = CPUTopology::new().set_topology((
+            vm_config.machine_config.nr_threads,
+            vm_config.machine_config.nr_cores,
+            vm_config.machine_config.nr_dies,
+        ));
+
+        locked_vm.cpus.extend(<Self as MachineOps>::init_vcpu(
+            vm.clone(),
+            vm_config.machine_config.nr_cpus,
+            &topology,
+            &vcpu_fds,
+            &boot_config,
+        )?);
+
+        if let Some(boot_cfg) = boot_config {
+            load_fdt(&*locked_vm, &boot_cfg)?;
+        }
+        locked_vm
+            .register_power_event(locked_vm.power_button.clone())
+            .with_context(|| anyhow!(MachineError::InitEventFdErr("power_button".to_string())))?;
+        locked_vm
+            .register_reset_event(locked_vm.reset_req.clone(), vm.clone())
+            .with_context(|| anyhow!(MachineError::InitEventFdErr("reset_req".to_string())))?;
+
+        MigrationManager::register_vm_config(locked_vm.get_vm_config());
+        MigrationManager::register_vm_instance(vm.clone());
+        if let Err(e) = MigrationManager::set_status(MigrationStatus::Setup) {
+            bail!("Failed to set migration status {}", e);
+        }
+
+        Ok(())
+    }
+
+    fn run(&self, paused: bool) -> MachineResult<()> {
+        self.vm_start(paused, &self.cpus, &mut self.vm_state.0.lock().unwrap())
+    }
+}
+
+impl MachineLifecycle for StdMachine {
+    fn pause(&self) -> bool {
+        if self.notify_lifecycle(KvmVmState::Running, KvmVmState::Paused) {
+            event!(Stop);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn resume(&self) -> bool {
+        if !self.notify_lifecycle(KvmVmState::Paused, KvmVmState::Running) {
+            return false;
+        }
+
+        event!(Resume);
+        true
+    }
+
+    fn destroy(&self) -> bool {
+        let vmstate = {
+            let state = self.vm_state.deref().0.lock().unwrap();
+            *state
+        };
+
+        if !self.notify_lifecycle(vmstate, KvmVmState::Shutdown) {
+            return false;
+        }
+
+        if self.power_button.write(1).is_err() {
+            error!("Standard vm write power button failed");
+            return false;
+        }
+        true
+    }
+
+    fn reset(&mut self) -> bool {
+        // The reset is done in the main loop, vCPUs can't pause themselves.
+        if self.reset_req.write(1).is_err() {
+            error!("Standard vm write reset request failed");
+            return false;
+        }
+        true
+    }
+
+    fn notify_lifecycle(&self, old: KvmVmState, new: KvmVmState) -> bool {
+        self.vm_state_transfer(&self.cpus, &mut self.vm_state.0.lock().unwrap(), old, new)
+            .is_ok()
+    }
+}
+
+impl MachineAddressInterface for StdMachine {
+    fn mmio_read(&self, addr: u64, mut data: &mut [u8]) -> bool {
+        let length = data.len() as u64;
+        self.sys_mem
+            .read(&mut data, GuestAddress(addr), length)
+            .is_ok()
+    }
+
+    fn mmio_write(&self, addr: u64, mut data: &[u8]) -> bool {
+        let count = data.len() as u64;
+        self.sys_mem
+            .write(&mut data, GuestAddress(addr), count)
+            .is_ok()
+    }
+}
+
+impl DeviceInterface for StdMachine {
+    fn query_status(&self) -> Response {
+        let vmstate = self.vm_state.deref().0.lock().unwrap();
+        let qmp_state = match *vmstate {
+            KvmVmState::Running => qmp_schema::StatusInfo {
+                singlestep: false,
+                running: true,
+                status: qmp_schema::RunState::running,
+            },
+            KvmVmState::Paused => qmp_schema::StatusInfo {
+                singlestep: false,
+                running: false,
+                status: qmp_schema::RunState::paused,
+            },
+            _ => Default::default(),
+        };
+
+        Response::create_response(serde_json::to_value(&qmp_state).unwrap(), None)
+    }
+
+    fn query_cpus(&self) -> Response {
+        let mut cpu_vec: Vec<serde_json::Value> = Vec::new();
+        for cpu_index in 0..self.cpu_topo.max_cpus {
+            if self.cpu_topo.get_mask(cpu_index as usize) == 1 {
+                let thread_id = self.cpus[cpu_index as usize].tid();
+                let cpu_instance = self.cpu_topo.get_topo_instance_for_qmp(cpu_index as usize);
+                let cpu_common = qmp_schema::CpuInfoCommon {
+                    current: true,
+                    qom_path: String::from("/machine/unattached/device[")
+                        + &cpu_index.to_string()
+                        + "]",
+                    halted: false,
+                    props: Some(cpu_instance),
+                    CPU: cpu_index as isize,
+                    thread_id: thread_id as isize,
+                };
+                #[cfg(target_arch = "riscv64")]
+                {
+                    let cpu_info = qmp_schema::CpuInfo::RISCV {
+                        common: cpu_common,
+                        arm: qmp_schema::CpuInfoRISCV {},
+                    };
+                    cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
+                }
+            }
+        }
+        Response::create_response(cpu_vec.into(), None)
+    }
+
+    fn query_hotpluggable_cpus(&self) -> Response {
+        let mut hotplug_vec: Vec<serde_json::Value> = Vec::new();
+        #[cfg(target_arch = "riscv64")]
+        let cpu_type = String::from("host-riscv64-cpu");
+
+        for cpu_index in 0..self.cpu_topo.max_cpus {
+            let cpu_instance = self.cpu_topo.get_topo_instance_for_qmp(cpu_index as usize);
+            let qom_path = if self.cpu_topo.get_mask(cpu_index as usize) == 0 {
+                None
+            } else {
+                Some(String::from("/machine/unattached/device[") + &cpu_index.to_string() + "]")
+            };
+            let hotpluggable_cpu = qmp_schema::HotpluggableCPU {
+                type_: cpu_type.clone(),
+                vcpus_count: 1,
+                props: cpu_instance,
+                qom_path,
+            };
+            hotplug_vec.push(serde_json::to_value(hotpluggable_cpu).unwrap());
+        }
+        Response::create_response(hotplug_vec.into(), None)
+    }
+
+    fn query_hotplug_slots(&self) -> Response {
+        // Virtio-mmio hotplug slots only exist on the micro VM.
+        Response::create_response(serde_json::Value::Array(Vec::new()), None)
+    }
+
+    fn balloon(&self, _value: u64) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::DeviceNotActive(
+                "No balloon device has been activated".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn query_balloon(&self) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::DeviceNotActive(
+                "No balloon device has been activated".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn device_add(&mut self, args: Box<qmp_schema::DeviceAddArgument>) -> Response {
+        match self.plug_virtio_pci_device(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{:?}", e);
+                error!("Failed to add device: id {}, type {}", args.id, args.driver);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn device_del(&mut self, _device_id: String) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "device_del not supported yet for standard VM".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn blockdev_add(&self, args: Box<qmp_schema::BlockDevAddArgument>) -> Response {
+        let config = match self.parse_blockdev_config(args) {
+            Ok(config) => config,
+            Err(ref e) => {
+                error!("{:?}", e);
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                );
+            }
+        };
+        let read_only = config.read_only;
+        let direct = config.direct;
+        if let Err(e) = config.check() {
+            error!("{:?}", e);
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
+        let path = config.path_on_host.clone();
+        if let Err(e) = self.register_drive_file(&path, read_only, direct) {
+            error!("{:?}", e);
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
+        // The drive is picked up by the virtio-blk-pci device added next.
+        let mut locked_config = self.vm_config.lock().unwrap();
+        match locked_config.add_drive_with_config(config) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{:?}", e);
+                if let Err(e) = self.unregister_drive_file(&path) {
+                    error!("{:?}", e);
+                }
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn blockdev_del(&self, _node_name: String) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("blockdev_del not support yet".to_string()),
+            None,
+        )
+    }
+
+    fn block_resize(&self, device: String, size: u64) -> Response {
+        self.qmp_block_resize(device, size)
+    }
+
+    fn block_set_io_throttle(&self, args: Box<qmp_schema::BlockSetIoThrottleArgument>) -> Response {
+        self.qmp_block_set_io_throttle(args)
+    }
+
+    fn blockdev_snapshot_sync(&self, args: qmp_schema::BlockdevSnapshotSyncArgument) -> Response {
+        self.qmp_blockdev_snapshot_sync(args)
+    }
+
+    fn block_commit(&self, args: qmp_schema::BlockCommitArgument) -> Response {
+        self.qmp_block_commit(args)
+    }
+
+    fn drive_mirror(&self, args: qmp_schema::DriveMirrorArgument) -> Response {
+        self.qmp_drive_mirror(args)
+    }
+
+    fn drive_backup(&self, args: qmp_schema::DriveBackupArgument) -> Response {
+        self.qmp_drive_backup(args)
+    }
+
+    fn block_job_cancel(&self, device: String) -> Response {
+        self.qmp_block_job_cancel(device)
+    }
+
+    fn block_job_complete(&self, device: String) -> Response {
+        self.qmp_block_job_complete(device)
+    }
+
+    fn query_block(&self) -> Response {
+        let blocks: Vec<qmp_schema::BlockInfo> = block_devices()
+            .iter()
+            .map(|block| block.lock().unwrap().query_info())
+            .collect();
+        Response::create_response(serde_json::to_value(&blocks).unwrap(), None)
+    }
+
+    fn query_named_block_nodes(&self) -> Response {
+        self.qmp_query_named_block_nodes()
+    }
+
+    fn query_blockstats(&self) -> Response {
+        let stats: Vec<qmp_schema::BlockStats> = block_devices()
+            .iter()
+            .map(|block| block.lock().unwrap().query_stats())
+            .collect();
+        Response::create_response(serde_json::to_value(&stats).unwrap(), None)
+    }
+
+    fn query_block_jobs(&self) -> Response {
+        Response::create_response(serde_json::to_value(block_jobs()).unwrap(), None)
+    }
+
+    fn netdev_add(&mut self, args: Box<qmp_schema::NetDevAddArgument>) -> Response {
+        let config = match get_netdev_config(args) {
+            Ok(config) => config,
+            Err(ref e) => {
+                error!("{:?}", e);
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                );
+            }
+        };
+        let mut locked_config = self.vm_config.lock().unwrap();
+        match locked_config.add_netdev_with_config(config) {
+            Ok(()) => Response::create_empty_response(),
+            Err(ref e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn netdev_del(&mut self, _node_name: String) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("netdev_del not support yet".to_string()),
+            None,
+        )
+    }
+
+    fn chardev_add(&mut self, _args: qmp_schema::CharDevAddArgument) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "chardev_add not supported yet for standard VM".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn chardev_remove(&mut self, _id: String) -> Response {
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "chardev_remove not supported yet for standard VM".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> Response {
+        self.qmp_getfd(fd_name, if_fd)
+    }
+}
+
+impl MigrateInterface for StdMachine {
+    fn migrate(&self, uri: String) -> Response {
+        self.qmp_migrate(uri)
+    }
+
+    fn query_migrate(&self) -> Response {
+        migration::query_migrate()
+    }
+
+    fn cancel_migrate(&self) -> Response {
+        migration::cancel_migrate()
+    }
+}
+
+impl MachineInterface for StdMachine {}
+impl MachineExternalInterface for StdMachine {}
+
+impl EventLoopManager for StdMachine {
+    fn loop_should_exit(&self) -> bool {
+        let vmstate = self.vm_state.deref().0.lock().unwrap();
+        *vmstate == KvmVmState::Shutdown
+    }
+
+    fn loop_cleanup(&self) -> util::Result<()> {
+        set_termi_canon_mode().with_context(|| "Failed to set terminal to canonical mode")?;
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "riscv64")]
+impl CompileFDTHelper for StdMachine {
+    fn generate_cpu_nodes(&self, fdt: &mut FdtBuilder) -> util::Result<()> {
+        fdt::generate_cpu_nodes(fdt, &self.cpus)
+    }
+
+    fn generate_memory_node(&self, fdt: &mut FdtBuilder) -> util::Result<()> {
+        let mem_base = MEM_LAYOUT[LayoutEntryType::Mem as usize].0;
+        let mem_size = self.sys_mem.memory_end_address().raw_value() - mem_base;
+        fdt::generate_memory_node(fdt, mem_base, mem_size)
+    }
+
+    fn generate_devices_node(&self, fdt: &mut FdtBuilder) -> util::Result<()> {
+        let node = "soc";
+        let smb_node_dep = fdt.begin_node(node)?;
+        fdt.set_property_string("compatible", "simple-bus")?;
+        fdt.set_property_u32("#address-cells", 0x02)?;
+        fdt.set_property_u32("#size-cells", 0x2)?;
+        fdt.set_property("ranges", &Vec::new())?;
+
+        let irqchip = self
+            .vm_config
+            .lock()
+            .unwrap()
+            .machine_config
+            .irqchip
+            .unwrap_or_default();
+        fdt::generate_sysbus_devices_nodes(fdt, &self.sysbus, irqchip, self.cpus.len())?;
+        if let Some(pci_host) = &self.pci_host {
+            let intx_irqs = pci_host.lock().unwrap().intx_irqs();
+            fdt::generate_pci_host_node(
+                fdt,
+                MEM_LAYOUT[LayoutEntryType::PcieEcam as usize],
+                MEM_LAYOUT[LayoutEntryType::PcieMmio as usize],
+                intx_irqs,
+                irqchip,
+            )?;
+        }
+        fdt.end_node(smb_node_dep)?;
+        Ok(())
+    }
+
+    fn generate_chosen_node(&self, fdt: &mut FdtBuilder) -> util::Result<()> {
+        fdt::generate_chosen_node(fdt, &self.boot_source.lock().unwrap())
+    }
+}
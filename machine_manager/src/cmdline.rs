@@ -86,10 +86,12 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
         .arg(
             Arg::with_name("machine")
             .long("machine")
-            .value_name("[type=]<name>[,dump_guest_core=on|off][,mem-share=on|off]")
-            .help("'type' selects emulated machine type and set properties. \
+            .value_name("[type=]<name>[,dump_guest_core=on|off][,mem-share=on|off][,irqchip=plic|aplic|aia][,hotplug-slots=<type>[:<type>...]]")
+            .help("'type' selects emulated machine type (microvm or virt) and set properties. \
                    'dump_guest_core' includes guest memory in a core dump. \
-                   'mem-share' sets guest memory is shareable.")
+                   'mem-share' sets guest memory is shareable. \
+                   'irqchip' selects the interrupt controller, 'aia' if the host supports it, 'plic' otherwise. \
+                   'hotplug-slots' lists the types (blk, net, console or vsock) of the virtio-mmio slots for hot-plugged devices, 'blk:net' by default.")
             .takes_value(true),
         )
         .arg(
@@ -184,7 +186,7 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .multiple(true)
             .long("drive")
             .value_name("<parameters>")
-            .help("\n\t\tset block drive image: -drive id=<drive_id>,file=<path_on_host>[,readonly=on|off][,direct=on|off][,throttling.iops-total=<200>]; \
+            .help("\n\t\tset block drive image: -drive id=<drive_id>,file=<path_on_host>[,readonly=on|off][,direct=on|off][,throttling.iops-total=<200>][,throttling.bps-total=<bytes>][,throttling.<limit>-max=<rate>][,throttling.<limit>-max-length=<secs>][,werror=report|ignore|stop|enospc][,rerror=report|ignore|stop|enospc]; \
                    \n\t\tset pflash drive image: -drive file=<pflash_path>,if=pflash,unit=0|1[,readonly=true|false]; \
                    \n\t\tset scsi drive image: -drive id=<drive-scsi0-0-0-0>,file=<path_on_host>[,readonly=true|false]")
             .takes_values(true),
@@ -194,9 +196,10 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .multiple(true)
             .long("netdev")
             .value_name(
-                "tap,id=<str>,ifname=<tap_name>[,queue=<N>]",
+                "tap,id=<str>,ifname=<tap_name>[,queue=<N>] \
+                | user,id=<str>[,net=<addr>[/<prefix>]][,host=<addr>][,dns=<addr>][,dhcpstart=<addr>][,hostfwd=tcp:[<hostaddr>]:<hostport>-[<guestaddr>]:<guestport>]",
             )
-            .help("configure a host TAP network with ID 'str'")
+            .help("configure a host TAP network or a user-mode network with ID 'str'")
             .takes_values(true),
         )
         .arg(
@@ -215,6 +218,7 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .help("\n\t\tadd virtio mmio block: -device virtio-blk-device,id=<blk_id>,drive=<drive_id>[,iothread=<iothread1>][,serial=<serial_num>]; \
                    \n\t\tadd virtio pci block: -device virtio-blk-pci,id=<blk_id>,drive=<drive_id>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off][,iothread=<iothread1>][,serial=<serial_num>][,num-queues=<N>][,bootindex=<N>]; \
                    \n\t\tadd vhost user pci block: -device vhost-user-blk-pci,id=<blk_id>,chardev=<chardev_id>,bus=<pcie.0>,addr=<0x3>[,num-queues=<N>][,bootindex=<N>]; \
+                   \n\t\tadd vhost user mmio block: -device vhost-user-blk-device,id=<blk_id>,chardev=<chardev_id>[,queue-size=<N>]; \
                    \n\t\tadd virtio mmio net: -device virtio-net-device,id=<net_id>,netdev=<netdev_id>[,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>]; \
                    \n\t\tadd virtio pci net: -device virtio-net-pci,id=<net_id>,netdev=<netdev_id>,bus=<pcie.0>,addr=<0x2>[,multifunction=on|off][,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>][,mq=on|off]; \
                    \n\t\tadd vhost mmio net: -device virtio-net-device,id=<net_id>,netdev=<netdev_id>[,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>]; \
@@ -41,6 +41,19 @@ pub struct VirtioConsole {
     pub chardev: ChardevConfig,
 }
 
+impl ConfigCheck for VirtioConsole {
+    fn check(&self) -> Result<()> {
+        if self.id.len() > MAX_STRING_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "console id".to_string(),
+                MAX_STRING_LENGTH
+            )));
+        }
+
+        self.chardev.check()
+    }
+}
+
 /// Config structure for character device.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChardevConfig {
@@ -13,6 +13,7 @@
 use std::fs::{metadata, File};
 use std::os::linux::fs::MetadataExt;
 use std::path::Path;
+use std::str::FromStr;
 
 use anyhow::{anyhow, bail, Result};
 use log::error;
@@ -25,8 +26,18 @@ use crate::config::{
 };
 use crate::qmp::qmp_schema;
 use util::aio::{aio_probe, AioEngine};
+use util::throttle::{ThrottleConfig, ThrottleLimit};
 const MAX_SERIAL_NUM: usize = 20;
 const MAX_IOPS: u64 = 1_000_000;
+/// Limits of the `throttling.*` options of drive.
+const THROTTLE_LIMITS: [&str; 6] = [
+    "bps-total",
+    "bps-read",
+    "bps-write",
+    "iops-total",
+    "iops-read",
+    "iops-write",
+];
 const MAX_UNIT_ID: usize = 2;
 
 // Seg_max = queue_size - 2. So, size of each virtqueue for virtio-blk should be larger than 2.
@@ -61,13 +72,21 @@ pub struct BlkDevConfig {
     pub direct: bool,
     pub serial_num: Option<String>,
     pub iothread: Option<String>,
-    pub iops: Option<u64>,
+    pub throttle: ThrottleConfig,
     pub queues: u16,
     pub boot_index: Option<u8>,
     pub chardev: Option<String>,
     pub socket_path: Option<String>,
     pub aio: AioEngine,
     pub queue_size: u16,
+    pub format: DiskFormat,
+    pub discard: bool,
+    pub write_zeroes: WriteZeroesState,
+    pub werror: IoErrorPolicy,
+    pub rerror: IoErrorPolicy,
+    /// Offer the packed virtqueue layout, the device can't be migrated once
+    /// the guest uses it.
+    pub packed: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -86,13 +105,280 @@ impl Default for BlkDevConfig {
             direct: true,
             serial_num: None,
             iothread: None,
-            iops: None,
+            throttle: ThrottleConfig::default(),
             queues: 1,
             boot_index: None,
             chardev: None,
             socket_path: None,
             aio: AioEngine::Native,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            format: DiskFormat::Raw,
+            discard: false,
+            write_zeroes: WriteZeroesState::Off,
+            werror: IoErrorPolicy::Report,
+            rerror: IoErrorPolicy::Report,
+            packed: false,
+        }
+    }
+}
+
+/// Format of the image file of a drive.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum DiskFormat {
+    Raw,
+    Qcow2,
+}
+
+impl FromStr for DiskFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(DiskFormat::Raw),
+            "qcow2" => Ok(DiskFormat::Qcow2),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for DiskFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                DiskFormat::Raw => "raw",
+                DiskFormat::Qcow2 => "qcow2",
+            }
+        )
+    }
+}
+
+/// Detection of write requests with zeroed data, which are turned into
+/// write zeroes requests.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum WriteZeroesState {
+    Off,
+    On,
+    Unmap,
+}
+
+impl FromStr for WriteZeroesState {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(WriteZeroesState::Off),
+            "on" => Ok(WriteZeroesState::On),
+            "unmap" => Ok(WriteZeroesState::Unmap),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for WriteZeroesState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                WriteZeroesState::Off => "off",
+                WriteZeroesState::On => "on",
+                WriteZeroesState::Unmap => "unmap",
+            }
+        )
+    }
+}
+
+/// Action taken when a request of a drive fails on the host.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum IoErrorPolicy {
+    /// Report the error to the guest.
+    Report,
+    /// Ignore the error and complete the request successfully.
+    Ignore,
+    /// Hold the request and stop the VM until it is resumed.
+    Stop,
+    /// Stop the VM if the host runs out of space, report other errors.
+    Enospc,
+}
+
+impl FromStr for IoErrorPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(IoErrorPolicy::Report),
+            "ignore" => Ok(IoErrorPolicy::Ignore),
+            "stop" => Ok(IoErrorPolicy::Stop),
+            "enospc" => Ok(IoErrorPolicy::Enospc),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for IoErrorPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                IoErrorPolicy::Report => "report",
+                IoErrorPolicy::Ignore => "ignore",
+                IoErrorPolicy::Stop => "stop",
+                IoErrorPolicy::Enospc => "enospc",
+            }
+        )
+    }
+}
+
+/// Parse the `discard` option of drive, return whether discard requests
+/// are passed down to the image file.
+pub fn parse_discard(discard: &str) -> Result<bool> {
+    match discard {
+        "unmap" | "on" => Ok(true),
+        "ignore" | "off" => Ok(false),
+        _ => Err(anyhow!(ConfigError::InvalidParam(
+            discard.to_string(),
+            "discard".to_string()
+        ))),
+    }
+}
+
+/// Default port of NBD servers.
+const NBD_DEFAULT_PORT: u16 = 10809;
+
+/// Server exporting an NBD drive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NbdServer {
+    /// TCP address as `host:port`.
+    Tcp(String),
+    /// Path of the unix socket.
+    Unix(String),
+}
+
+/// Export of an NBD server, given as drive file by `nbd://host[:port]/export`
+/// or `nbd+unix:///export?socket=path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NbdUri {
+    pub server: NbdServer,
+    /// Name of the export, may be empty for the default export.
+    pub export: String,
+}
+
+/// Check whether the drive file is the URI of an NBD export.
+pub fn is_nbd_uri(path: &str) -> bool {
+    path.starts_with("nbd:") || path.starts_with("nbd+")
+}
+
+impl FromStr for NbdUri {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!(ConfigError::InvalidParam(s.to_string(), "file".to_string()));
+        let (scheme, rest) = s.split_once("://").ok_or_else(invalid)?;
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path, None),
+        };
+        let export = path.strip_prefix('/').unwrap_or(path).to_string();
+
+        let server = match scheme {
+            "nbd" | "nbd+tcp" => {
+                if authority.is_empty() || query.is_some() {
+                    return Err(invalid());
+                }
+                // The port follows the closing bracket of IPv6 addresses.
+                let host_end = authority.rfind(']').unwrap_or(0);
+                if authority[host_end..].contains(':') {
+                    NbdServer::Tcp(authority.to_string())
+                } else {
+                    NbdServer::Tcp(format!("{}:{}", authority, NBD_DEFAULT_PORT))
+                }
+            }
+            "nbd+unix" => {
+                let socket = query
+                    .and_then(|query| query.strip_prefix("socket="))
+                    .filter(|socket| !socket.is_empty());
+                match socket {
+                    Some(socket) if authority.is_empty() => NbdServer::Unix(socket.to_string()),
+                    _ => return Err(invalid()),
+                }
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(NbdUri { server, export })
+    }
+}
+
+/// Check the IO limits of drive.
+pub fn check_throttle(throttle: &ThrottleConfig) -> Result<()> {
+    for limit in [
+        &throttle.iops_total,
+        &throttle.iops_read,
+        &throttle.iops_write,
+    ] {
+        if limit.avg > MAX_IOPS || limit.max > MAX_IOPS {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "iops of block device".to_string(),
+                0,
+                true,
+                MAX_IOPS,
+                true,
+            )));
+        }
+    }
+    throttle.check()
+}
+
+fn throttle_limit(avg: u64, max: Option<u64>, max_length: Option<u64>) -> ThrottleLimit {
+    ThrottleLimit {
+        avg,
+        max: max.unwrap_or(0),
+        max_length: max_length.unwrap_or(1),
+    }
+}
+
+impl From<&qmp_schema::IoThrottle> for ThrottleConfig {
+    fn from(args: &qmp_schema::IoThrottle) -> Self {
+        ThrottleConfig {
+            bps_total: throttle_limit(args.bps, args.bps_max, args.bps_max_length),
+            bps_read: throttle_limit(args.bps_rd, args.bps_rd_max, args.bps_rd_max_length),
+            bps_write: throttle_limit(args.bps_wr, args.bps_wr_max, args.bps_wr_max_length),
+            iops_total: throttle_limit(args.iops, args.iops_max, args.iops_max_length),
+            iops_read: throttle_limit(args.iops_rd, args.iops_rd_max, args.iops_rd_max_length),
+            iops_write: throttle_limit(args.iops_wr, args.iops_wr_max, args.iops_wr_max_length),
+            iops_size: args.iops_size.unwrap_or(0),
+        }
+    }
+}
+
+impl From<&ThrottleConfig> for qmp_schema::IoThrottle {
+    fn from(config: &ThrottleConfig) -> Self {
+        // Burst options are only reported if burst is allowed.
+        let max = |limit: &ThrottleLimit| (limit.max != 0).then_some(limit.max);
+        let max_length = |limit: &ThrottleLimit| (limit.max != 0).then_some(limit.max_length);
+        qmp_schema::IoThrottle {
+            bps: config.bps_total.avg,
+            bps_rd: config.bps_read.avg,
+            bps_wr: config.bps_write.avg,
+            iops: config.iops_total.avg,
+            iops_rd: config.iops_read.avg,
+            iops_wr: config.iops_write.avg,
+            bps_max: max(&config.bps_total),
+            bps_rd_max: max(&config.bps_read),
+            bps_wr_max: max(&config.bps_write),
+            iops_max: max(&config.iops_total),
+            iops_rd_max: max(&config.iops_read),
+            iops_wr_max: max(&config.iops_write),
+            bps_max_length: max_length(&config.bps_total),
+            bps_rd_max_length: max_length(&config.bps_read),
+            bps_wr_max_length: max_length(&config.bps_write),
+            iops_max_length: max_length(&config.iops_total),
+            iops_rd_max_length: max_length(&config.iops_read),
+            iops_wr_max_length: max_length(&config.iops_write),
+            iops_size: (config.iops_size != 0).then_some(config.iops_size),
         }
     }
 }
@@ -106,8 +392,13 @@ pub struct DriveConfig {
     pub path_on_host: String,
     pub read_only: bool,
     pub direct: bool,
-    pub iops: Option<u64>,
+    pub throttle: ThrottleConfig,
     pub aio: AioEngine,
+    pub format: DiskFormat,
+    pub discard: bool,
+    pub write_zeroes: WriteZeroesState,
+    pub werror: IoErrorPolicy,
+    pub rerror: IoErrorPolicy,
 }
 
 impl Default for DriveConfig {
@@ -117,8 +408,13 @@ impl Default for DriveConfig {
             path_on_host: "".to_string(),
             read_only: false,
             direct: true,
-            iops: None,
+            throttle: ThrottleConfig::default(),
             aio: AioEngine::Native,
+            format: DiskFormat::Raw,
+            discard: false,
+            write_zeroes: WriteZeroesState::Off,
+            werror: IoErrorPolicy::Report,
+            rerror: IoErrorPolicy::Report,
         }
     }
 }
@@ -126,6 +422,10 @@ impl Default for DriveConfig {
 impl DriveConfig {
     /// Check whether the drive file path on the host is valid.
     pub fn check_path(&self) -> Result<()> {
+        // The URI of an NBD export is checked with the config.
+        if is_nbd_uri(&self.path_on_host) {
+            return Ok(());
+        }
         let blk = Path::new(&self.path_on_host);
         match metadata(blk) {
             Ok(meta) => {
@@ -177,15 +477,16 @@ impl ConfigCheck for DriveConfig {
                 MAX_PATH_LENGTH,
             )));
         }
-        if self.iops.is_some() && self.iops.unwrap() > MAX_IOPS {
-            return Err(anyhow!(ConfigError::IllegalValue(
-                "iops of block device".to_string(),
-                0,
-                true,
-                MAX_IOPS,
-                true,
-            )));
+        if is_nbd_uri(&self.path_on_host) {
+            self.path_on_host.parse::<NbdUri>()?;
+            if self.format != DiskFormat::Raw {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    "format".to_string(),
+                    "NBD export should be used with format raw".to_string(),
+                )));
+            }
         }
+        check_throttle(&self.throttle)?;
         if self.aio != AioEngine::Off {
             if self.aio == AioEngine::Native && !self.direct {
                 return Err(anyhow!(ConfigError::InvalidParam(
@@ -200,6 +501,12 @@ impl ConfigCheck for DriveConfig {
                 "low performance expected when use sync io with \"direct\" on".to_string(),
             )));
         }
+        if self.write_zeroes == WriteZeroesState::Unmap && !self.discard {
+            return Err(anyhow!(ConfigError::InvalidParam(
+                "detect-zeroes".to_string(),
+                "\"unmap\" should be used with \"discard=unmap\"".to_string(),
+            )));
+        }
         Ok(())
     }
 }
@@ -254,8 +561,10 @@ impl ConfigCheck for BlkDevConfig {
         let fake_drive = DriveConfig {
             path_on_host: self.path_on_host.clone(),
             direct: self.direct,
-            iops: self.iops,
+            throttle: self.throttle,
             aio: self.aio,
+            discard: self.discard,
+            write_zeroes: self.write_zeroes,
             ..Default::default()
         };
         fake_drive.check()?;
@@ -268,13 +577,42 @@ impl ConfigCheck for BlkDevConfig {
     }
 }
 
+/// Parse one limit of the `throttling.*` options of drive.
+fn parse_throttle_limit(cmd_parser: &CmdParser, name: &str) -> Result<ThrottleLimit> {
+    let mut limit = ThrottleLimit::default();
+    if let Some(avg) = cmd_parser.get_value::<u64>(&format!("throttling.{}", name))? {
+        limit.avg = avg;
+    }
+    if let Some(max) = cmd_parser.get_value::<u64>(&format!("throttling.{}-max", name))? {
+        limit.max = max;
+    }
+    if let Some(max_length) =
+        cmd_parser.get_value::<u64>(&format!("throttling.{}-max-length", name))?
+    {
+        limit.max_length = max_length;
+    }
+    Ok(limit)
+}
+
+fn parse_throttle(cmd_parser: &CmdParser) -> Result<ThrottleConfig> {
+    Ok(ThrottleConfig {
+        bps_total: parse_throttle_limit(cmd_parser, "bps-total")?,
+        bps_read: parse_throttle_limit(cmd_parser, "bps-read")?,
+        bps_write: parse_throttle_limit(cmd_parser, "bps-write")?,
+        iops_total: parse_throttle_limit(cmd_parser, "iops-total")?,
+        iops_read: parse_throttle_limit(cmd_parser, "iops-read")?,
+        iops_write: parse_throttle_limit(cmd_parser, "iops-write")?,
+        iops_size: cmd_parser
+            .get_value::<u64>("throttling.iops-size")?
+            .unwrap_or(0),
+    })
+}
+
 fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
     let mut drive = DriveConfig::default();
 
-    if let Some(format) = cmd_parser.get_value::<String>("format")? {
-        if format.ne("raw") {
-            bail!("Only \'raw\' type of block is supported");
-        }
+    if let Some(format) = cmd_parser.get_value::<DiskFormat>("format")? {
+        drive.format = format;
     }
 
     if let Some(id) = cmd_parser.get_value::<String>("id")? {
@@ -295,7 +633,7 @@ fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
     if let Some(direct) = cmd_parser.get_value::<ExBool>("direct")? {
         drive.direct = direct.into();
     }
-    drive.iops = cmd_parser.get_value::<u64>("throttling.iops-total")?;
+    drive.throttle = parse_throttle(&cmd_parser)?;
     drive.aio = cmd_parser.get_value::<AioEngine>("aio")?.unwrap_or({
         if drive.direct {
             AioEngine::Native
@@ -303,6 +641,18 @@ fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
             AioEngine::Off
         }
     });
+    if let Some(discard) = cmd_parser.get_value::<String>("discard")? {
+        drive.discard = parse_discard(&discard)?;
+    }
+    if let Some(write_zeroes) = cmd_parser.get_value::<WriteZeroesState>("detect-zeroes")? {
+        drive.write_zeroes = write_zeroes;
+    }
+    if let Some(werror) = cmd_parser.get_value::<IoErrorPolicy>("werror")? {
+        drive.werror = werror;
+    }
+    if let Some(rerror) = cmd_parser.get_value::<IoErrorPolicy>("rerror")? {
+        drive.rerror = rerror;
+    }
     drive.check()?;
     #[cfg(not(test))]
     drive.check_path()?;
@@ -326,7 +676,8 @@ pub fn parse_blk(
         .push("serial")
         .push("iothread")
         .push("num-queues")
-        .push("queue-size");
+        .push("queue-size")
+        .push("packed");
 
     cmd_parser.parse(drive_config)?;
 
@@ -367,12 +718,21 @@ pub fn parse_blk(
         blkdevcfg.queue_size = queue_size;
     }
 
+    if let Some(packed) = cmd_parser.get_value::<ExBool>("packed")? {
+        blkdevcfg.packed = packed.inner;
+    }
+
     if let Some(drive_arg) = &vm_config.drives.remove(&blkdrive) {
         blkdevcfg.path_on_host = drive_arg.path_on_host.clone();
         blkdevcfg.read_only = drive_arg.read_only;
         blkdevcfg.direct = drive_arg.direct;
-        blkdevcfg.iops = drive_arg.iops;
+        blkdevcfg.throttle = drive_arg.throttle;
         blkdevcfg.aio = drive_arg.aio;
+        blkdevcfg.format = drive_arg.format;
+        blkdevcfg.discard = drive_arg.discard;
+        blkdevcfg.write_zeroes = drive_arg.write_zeroes;
+        blkdevcfg.werror = drive_arg.werror;
+        blkdevcfg.rerror = drive_arg.rerror;
     } else {
         bail!("No drive configured matched for blk device");
     }
@@ -438,6 +798,45 @@ pub fn parse_vhost_user_blk_pci(
     Ok(blkdevcfg)
 }
 
+pub fn parse_vhost_user_blk_device(
+    vm_config: &mut VmConfig,
+    drive_config: &str,
+) -> Result<BlkDevConfig> {
+    let mut cmd_parser = CmdParser::new("vhost-user-blk-device");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("chardev")
+        .push("queue-size");
+
+    cmd_parser.parse(drive_config)?;
+
+    let mut blkdevcfg = BlkDevConfig::default();
+
+    if let Some(id) = cmd_parser.get_value::<String>("id")? {
+        blkdevcfg.id = id;
+    } else {
+        bail!("No id configured for blk device");
+    }
+
+    if let Some(chardev) = cmd_parser.get_value::<String>("chardev")? {
+        blkdevcfg.socket_path = Some(get_chardev_socket_path(&chardev, vm_config)?);
+        blkdevcfg.chardev = Some(chardev);
+    } else {
+        return Err(anyhow!(ConfigError::FieldIsMissing(
+            "chardev",
+            "vhost-user-blk-device"
+        )));
+    };
+
+    if let Some(size) = cmd_parser.get_value::<u16>("queue-size")? {
+        blkdevcfg.queue_size = size;
+    }
+
+    blkdevcfg.check()?;
+    Ok(blkdevcfg)
+}
+
 /// Config struct for `pflash`.
 /// Contains pflash device's attr.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -504,8 +903,18 @@ impl VmConfig {
             .push("direct")
             .push("format")
             .push("if")
-            .push("throttling.iops-total")
-            .push("aio");
+            .push("aio")
+            .push("discard")
+            .push("detect-zeroes")
+            .push("werror")
+            .push("rerror")
+            .push("throttling.iops-size");
+        for name in THROTTLE_LIMITS {
+            cmd_parser
+                .push(&format!("throttling.{}", name))
+                .push(&format!("throttling.{}-max", name))
+                .push(&format!("throttling.{}-max-length", name));
+        }
 
         cmd_parser.parse(block_config)?;
         let drive_cfg = parse_drive(cmd_parser)?;
@@ -672,6 +1081,34 @@ mod tests {
         assert_eq!(blk_device_config.read_only, false);
         assert_eq!(blk_device_config.serial_num, Some(String::from("111111")));
         assert_eq!(blk_device_config.queues, 4);
+        assert_eq!(blk_device_config.throttle.iops_total.avg, 200);
+        assert_eq!(blk_device_config.throttle.bps_total.avg, 0);
+        assert!(!blk_device_config.packed);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive(
+                "id=rootfs,file=/path/to/rootfs,throttling.bps-read=1048576,\
+                throttling.bps-read-max=2097152,throttling.bps-read-max-length=10,\
+                throttling.iops-write=100,throttling.iops-size=4096"
+            )
+            .is_ok());
+        let throttle = vm_config.drives.get("rootfs").unwrap().throttle;
+        assert_eq!(throttle.bps_read.avg, 1 << 20);
+        assert_eq!(throttle.bps_read.max, 2 << 20);
+        assert_eq!(throttle.bps_read.max_length, 10);
+        assert_eq!(throttle.iops_write.avg, 100);
+        assert_eq!(throttle.iops_write.max_length, 1);
+        assert_eq!(throttle.iops_size, 4096);
+
+        // Burst length needs the burst rate.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive(
+                "id=rootfs,file=/path/to/rootfs,throttling.bps-total=1024,\
+                throttling.bps-total-max-length=10"
+            )
+            .is_err());
 
         let mut vm_config = VmConfig::default();
         assert!(vm_config
@@ -683,6 +1120,79 @@ mod tests {
             None,
         );
         assert!(blk_cfg_res.is_err()); // Can not find drive named "rootfs1".
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,format=qcow2,direct=off,aio=off")
+            .is_ok());
+        let blk_cfg_res = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=rootfs,id=rootfs",
+            None,
+        );
+        assert!(blk_cfg_res.is_ok());
+        assert_eq!(blk_cfg_res.unwrap().format, DiskFormat::Qcow2);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,format=vmdk")
+            .is_err());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,discard=unmap,detect-zeroes=unmap")
+            .is_ok());
+        let blk_cfg_res = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=rootfs,id=rootfs",
+            None,
+        );
+        assert!(blk_cfg_res.is_ok());
+        let blk_device_config = blk_cfg_res.unwrap();
+        assert!(blk_device_config.discard);
+        assert_eq!(blk_device_config.write_zeroes, WriteZeroesState::Unmap);
+
+        // Unmapping zeroed writes requires discard.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,detect-zeroes=unmap")
+            .is_err());
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,discard=trim")
+            .is_err());
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,discard=ignore,detect-zeroes=on")
+            .is_ok());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,werror=stop,rerror=ignore")
+            .is_ok());
+        let blk_cfg_res = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=rootfs,id=rootfs",
+            None,
+        );
+        assert!(blk_cfg_res.is_ok());
+        let blk_device_config = blk_cfg_res.unwrap();
+        assert_eq!(blk_device_config.werror, IoErrorPolicy::Stop);
+        assert_eq!(blk_device_config.rerror, IoErrorPolicy::Ignore);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,werror=enospc")
+            .is_ok());
+        let blk_device_config = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=rootfs,id=rootfs",
+            None,
+        )
+        .unwrap();
+        assert_eq!(blk_device_config.werror, IoErrorPolicy::Enospc);
+        assert_eq!(blk_device_config.rerror, IoErrorPolicy::Report);
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,werror=retry")
+            .is_err());
     }
 
     #[test]
@@ -719,6 +1229,39 @@ mod tests {
         let blk_cfg =
             "virtio-blk-pci,id=blk1,bus=pcie.0,addr=0x1.0x2,drive=rootfs,multifunction=on";
         assert!(parse_blk(&mut vm_config, blk_cfg, None).is_ok());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs")
+            .is_ok());
+        let blk_cfg = "virtio-blk-pci,id=blk1,bus=pcie.0,addr=0x1.0x2,drive=rootfs,packed=on";
+        assert!(parse_blk(&mut vm_config, blk_cfg, None).unwrap().packed);
+    }
+
+    #[test]
+    fn test_vhost_user_blk_device_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_chardev("socket,id=spdk0,path=/path/to/vhost.sock")
+            .is_ok());
+        let blk_cfg = "vhost-user-blk-device,id=blk0,chardev=spdk0,queue-size=256";
+        let blk_cfg_res = parse_vhost_user_blk_device(&mut vm_config, blk_cfg);
+        assert!(blk_cfg_res.is_ok());
+        let blk_cfg_res = blk_cfg_res.unwrap();
+        assert_eq!(blk_cfg_res.id, "blk0");
+        assert_eq!(blk_cfg_res.chardev, Some(String::from("spdk0")));
+        assert_eq!(
+            blk_cfg_res.socket_path,
+            Some(String::from("/path/to/vhost.sock"))
+        );
+        assert_eq!(blk_cfg_res.queue_size, 256);
+
+        // chardev "spdk0" has been removed.
+        assert!(parse_vhost_user_blk_device(&mut vm_config, blk_cfg).is_err());
+        // chardev is mandatory.
+        assert!(
+            parse_vhost_user_blk_device(&mut vm_config, "vhost-user-blk-device,id=blk1").is_err()
+        );
     }
 
     #[test]
@@ -793,15 +1336,55 @@ mod tests {
         assert!(drive_conf.check().is_err());
 
         let mut drive_conf = DriveConfig::default();
-        drive_conf.iops = Some(MAX_IOPS);
+        drive_conf.throttle.iops_total.avg = MAX_IOPS;
         assert!(drive_conf.check().is_ok());
 
         let mut drive_conf = DriveConfig::default();
-        drive_conf.iops = None;
+        drive_conf.throttle.iops_total.avg = 0;
         assert!(drive_conf.check().is_ok());
 
         // Overflow
-        drive_conf.iops = Some(MAX_IOPS + 1);
+        drive_conf.throttle.iops_total.avg = MAX_IOPS + 1;
+        assert!(drive_conf.check().is_err());
+
+        // Total and read/write limits are exclusive.
+        let mut drive_conf = DriveConfig::default();
+        drive_conf.throttle.bps_total.avg = 1 << 20;
+        drive_conf.throttle.bps_read.avg = 1 << 20;
+        assert!(drive_conf.check().is_err());
+    }
+
+    #[test]
+    fn test_nbd_uri() {
+        let uri = "nbd://192.168.0.1/vol0".parse::<NbdUri>().unwrap();
+        assert_eq!(uri.server, NbdServer::Tcp("192.168.0.1:10809".to_string()));
+        assert_eq!(uri.export, "vol0");
+        let uri = "nbd://[::1]:10810".parse::<NbdUri>().unwrap();
+        assert_eq!(uri.server, NbdServer::Tcp("[::1]:10810".to_string()));
+        assert_eq!(uri.export, "");
+        let uri = "nbd+unix:///vol1?socket=/run/nbd.sock"
+            .parse::<NbdUri>()
+            .unwrap();
+        assert_eq!(uri.server, NbdServer::Unix("/run/nbd.sock".to_string()));
+        assert_eq!(uri.export, "vol1");
+
+        assert!("nbd:///vol0".parse::<NbdUri>().is_err());
+        assert!("nbd://host/vol0?socket=/run/nbd.sock"
+            .parse::<NbdUri>()
+            .is_err());
+        assert!("nbd+unix:///vol1".parse::<NbdUri>().is_err());
+        assert!("nbd+unix://host/vol1?socket=/run/nbd.sock"
+            .parse::<NbdUri>()
+            .is_err());
+        assert!("nbd+ssh://host/vol0".parse::<NbdUri>().is_err());
+
+        // NBD exports are raw disks.
+        let mut drive_conf = DriveConfig {
+            path_on_host: "nbd://host/vol0".to_string(),
+            ..Default::default()
+        };
+        assert!(drive_conf.check().is_ok());
+        drive_conf.format = DiskFormat::Qcow2;
         assert!(drive_conf.check().is_err());
     }
 
@@ -10,6 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::fmt;
 use std::str::FromStr;
 
 use anyhow::{anyhow, bail, Context, Result};
@@ -32,6 +33,7 @@ const MAX_NR_CPUS: u64 = 254;
 const MIN_NR_CPUS: u64 = 1;
 const MAX_MEMSIZE: u64 = 549_755_813_888;
 const MIN_MEMSIZE: u64 = 134_217_728;
+const MAX_HOTPLUG_SLOTS: usize = 32;
 pub const M: u64 = 1024 * 1024;
 pub const G: u64 = 1024 * 1024 * 1024;
 
@@ -39,6 +41,7 @@ pub const G: u64 = 1024 * 1024 * 1024;
 pub enum MachineType {
     None,
     MicroVm,
+    StandardVm,
 }
 
 impl FromStr for MachineType {
@@ -48,11 +51,76 @@ impl FromStr for MachineType {
         match s.to_lowercase().as_str() {
             "none" => Ok(MachineType::None),
             "microvm" => Ok(MachineType::MicroVm),
+            #[cfg(target_arch = "riscv64")]
+            "virt" => Ok(MachineType::StandardVm),
             _ => Err(()),
         }
     }
 }
 
+/// Interrupt controller emulated for the guest.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IrqChipType {
+    Plic,
+    Aplic,
+    /// APLIC and IMSIC emulated by the host kernel.
+    Aia,
+}
+
+impl Default for IrqChipType {
+    fn default() -> Self {
+        IrqChipType::Plic
+    }
+}
+
+impl FromStr for IrqChipType {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plic" => Ok(IrqChipType::Plic),
+            "aplic" => Ok(IrqChipType::Aplic),
+            "aia" => Ok(IrqChipType::Aia),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Type of the devices a hotplug slot of microvm accepts.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HotplugSlotType {
+    Blk,
+    Net,
+    Console,
+    Vsock,
+}
+
+impl FromStr for HotplugSlotType {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "blk" => Ok(HotplugSlotType::Blk),
+            "net" => Ok(HotplugSlotType::Net),
+            "console" => Ok(HotplugSlotType::Console),
+            "vsock" => Ok(HotplugSlotType::Vsock),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for HotplugSlotType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HotplugSlotType::Blk => "blk",
+            HotplugSlotType::Net => "net",
+            HotplugSlotType::Console => "console",
+            HotplugSlotType::Vsock => "vsock",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[repr(u32)]
 #[derive(PartialEq, Eq)]
 pub enum HostMemPolicy {
@@ -149,6 +217,10 @@ pub struct MachineConfig {
     pub max_cpus: u8,
     pub mem_config: MachineMemConfig,
     pub cpu_config: CpuConfig,
+    /// Interrupt controller, chosen by the machine according to the host if None.
+    pub irqchip: Option<IrqChipType>,
+    /// Types of the virtio-mmio transports reserved for hot-plugged devices.
+    pub hotplug_slots: Vec<HotplugSlotType>,
 }
 
 impl Default for MachineConfig {
@@ -165,6 +237,8 @@ impl Default for MachineConfig {
             max_cpus: DEFAULT_MAX_CPUS,
             mem_config: MachineMemConfig::default(),
             cpu_config: CpuConfig::default(),
+            irqchip: None,
+            hotplug_slots: vec![HotplugSlotType::Blk, HotplugSlotType::Net],
         }
     }
 }
@@ -175,6 +249,15 @@ impl ConfigCheck for MachineConfig {
             bail!("Memory size must >= 128MiB and <= 512GiB, default unit: MiB, current memory size: {:?} bytes",
             &self.mem_config.mem_size);
         }
+        if self.hotplug_slots.len() > MAX_HOTPLUG_SLOTS {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "hotplug-slots of machine".to_string(),
+                0,
+                true,
+                MAX_HOTPLUG_SLOTS as u64,
+                true,
+            )));
+        }
 
         Ok(())
     }
@@ -194,7 +277,9 @@ impl VmConfig {
             .push("accel")
             .push("usb")
             .push("dump-guest-core")
-            .push("mem-share");
+            .push("mem-share")
+            .push("irqchip")
+            .push("hotplug-slots");
         cmd_parser.parse(mach_config)?;
 
 
@@ -226,6 +311,21 @@ impl VmConfig {
         if let Some(mem_share) = cmd_parser.get_value::<ExBool>("mem-share")? {
             self.machine_config.mem_config.mem_share = mem_share.into();
         }
+        if let Some(irqchip) = cmd_parser
+            .get_value::<IrqChipType>("irqchip")
+            .with_context(|| "Only \'plic\', \'aplic\' and \'aia\' are supported for \'irqchip\'")?
+        {
+            self.machine_config.irqchip = Some(irqchip);
+        }
+        if let Some(slots) = cmd_parser.get_value::<String>("hotplug-slots")? {
+            self.machine_config.hotplug_slots = slots
+                .split(':')
+                .map(|slot| slot.parse::<HotplugSlotType>())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|_| {
+                    anyhow!("Only \'blk\', \'net\', \'console\' and \'vsock\' are supported for \'hotplug-slots\'")
+                })?;
+        }
 
         Ok(())
     }
@@ -624,6 +724,8 @@ mod tests {
             max_cpus: MIN_NR_CPUS as u8,
             mem_config: memory_config,
             cpu_config: CpuConfig::default(),
+            irqchip: Some(IrqChipType::Plic),
+            hotplug_slots: vec![HotplugSlotType::Blk, HotplugSlotType::Net],
         };
         assert!(machine_config.check().is_ok());
 
@@ -639,6 +741,9 @@ mod tests {
         machine_config.mem_config.mem_size = MIN_MEMSIZE;
 
         assert!(machine_config.check().is_ok());
+
+        machine_config.hotplug_slots = vec![HotplugSlotType::Blk; MAX_HOTPLUG_SLOTS + 1];
+        assert!(machine_config.check().is_err());
     }
 
     #[test]
@@ -782,7 +887,7 @@ mod tests {
             assert!(machine_type.is_err());
         }
 
-        #[cfg(target_arch = "aarch64")]
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
         {
             let test_string = "virt";
             let machine_type = MachineType::from_str(test_string);
@@ -854,6 +959,52 @@ mod tests {
         let machine_cfg_ret = vm_config.add_machine(memory_cfg_str);
         assert!(machine_cfg_ret.is_err());
 
+        let mut vm_config = VmConfig::default();
+        let machine_cfg_ret = vm_config.add_machine("microvm");
+        assert!(machine_cfg_ret.is_ok());
+        assert_eq!(vm_config.machine_config.irqchip, None);
+
+        let mut vm_config = VmConfig::default();
+        let machine_cfg_ret = vm_config.add_machine("microvm,irqchip=aplic");
+        assert!(machine_cfg_ret.is_ok());
+        assert_eq!(vm_config.machine_config.irqchip, Some(IrqChipType::Aplic));
+
+        let mut vm_config = VmConfig::default();
+        let machine_cfg_ret = vm_config.add_machine("microvm,irqchip=aia");
+        assert!(machine_cfg_ret.is_ok());
+        assert_eq!(vm_config.machine_config.irqchip, Some(IrqChipType::Aia));
+
+        let mut vm_config = VmConfig::default();
+        let machine_cfg_ret = vm_config.add_machine("microvm,irqchip=gic");
+        assert!(machine_cfg_ret.is_err());
+
+        let mut vm_config = VmConfig::default();
+        assert_eq!(
+            vm_config.machine_config.hotplug_slots,
+            vec![HotplugSlotType::Blk, HotplugSlotType::Net]
+        );
+        let machine_cfg_ret = vm_config.add_machine("microvm,hotplug-slots=net:blk:net:vsock");
+        assert!(machine_cfg_ret.is_ok());
+        assert_eq!(
+            vm_config.machine_config.hotplug_slots,
+            vec![
+                HotplugSlotType::Net,
+                HotplugSlotType::Blk,
+                HotplugSlotType::Net,
+                HotplugSlotType::Vsock
+            ]
+        );
+
+        let mut vm_config = VmConfig::default();
+        let slots = vec!["console"; MAX_HOTPLUG_SLOTS + 1].join(":");
+        let machine_cfg_ret = vm_config.add_machine(&format!("microvm,hotplug-slots={}", slots));
+        assert!(machine_cfg_ret.is_ok());
+        assert!(vm_config.machine_config.check().is_err());
+
+        let mut vm_config = VmConfig::default();
+        let machine_cfg_ret = vm_config.add_machine("microvm,hotplug-slots=blk:rng");
+        assert!(machine_cfg_ret.is_err());
+
         #[cfg(target_arch = "aarch64")]
         {
             let mut vm_config = VmConfig::default();
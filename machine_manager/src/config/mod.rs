@@ -115,9 +115,11 @@ impl VmConfig {
             )));
         }
         if self.boot_source.kernel_file.is_none()
-            && self.machine_config.mach_type == MachineType::MicroVm
+            && self.machine_config.mach_type != MachineType::None
         {
-            bail!("kernel file is required for microvm machine type, which is not provided");
+            bail!(
+                "kernel file is required for microvm and virt machine types, which is not provided"
+            );
         }
 
         if self.boot_source.initrd.is_none()
@@ -245,6 +247,10 @@ impl VmConfig {
         read_only: bool,
         direct: bool,
     ) -> Result<()> {
+        // The block device connects to the NBD server itself.
+        if is_nbd_uri(path) {
+            return Ok(());
+        }
         if let Some(drive_file) = drive_files.get_mut(path) {
             if drive_file.read_only && read_only {
                 // File can be shared with read_only.
@@ -288,6 +294,9 @@ impl VmConfig {
         drive_files: &mut HashMap<String, DriveFile>,
         path: &str,
     ) -> Result<()> {
+        if is_nbd_uri(path) {
+            return Ok(());
+        }
         if let Some(drive_file) = drive_files.get_mut(path) {
             drive_file.count -= 1;
             if drive_file.count == 0 {
@@ -10,7 +10,10 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use anyhow::{anyhow, bail, Result};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
 
 use super::{error::ConfigError, pci_args_check};
@@ -26,6 +29,181 @@ const MAC_ADDRESS_LENGTH: usize = 17;
 /// Max virtqueue size of each virtqueue.
 pub const MAX_QUEUE_SIZE_NET: u16 = 4096;
 
+/// Port forwarding rule of the user-mode network, in the format of
+/// "tcp:[hostaddr]:hostport-[guestaddr]:guestport".
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostFwd {
+    /// Address of the host to listen on, 0.0.0.0 if not set.
+    pub host_addr: Ipv4Addr,
+    pub host_port: u16,
+    /// Address of the guest, the address allocated by DHCP if not set.
+    pub guest_addr: Option<Ipv4Addr>,
+    pub guest_port: u16,
+}
+
+impl FromStr for HostFwd {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (protocol, rule) = s.split_once(':').unwrap_or(("tcp", s));
+        if !protocol.is_empty() && protocol != "tcp" {
+            bail!(
+                "Unsupported protocol {} of hostfwd, only tcp is supported",
+                protocol
+            );
+        }
+        let (host, guest) = rule
+            .split_once('-')
+            .with_context(|| format!("Invalid hostfwd rule {}", s))?;
+        let (host_addr, host_port) = parse_fwd_addr(host)?;
+        let (guest_addr, guest_port) = parse_fwd_addr(guest)?;
+        if guest_port == 0 {
+            bail!("Guest port of hostfwd {} is not set", s);
+        }
+
+        Ok(HostFwd {
+            host_addr: host_addr.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            host_port,
+            guest_addr,
+            guest_port,
+        })
+    }
+}
+
+/// Parse "[addr]:port" of the hostfwd rule.
+fn parse_fwd_addr(s: &str) -> Result<(Option<Ipv4Addr>, u16)> {
+    let (addr, port) = s
+        .rsplit_once(':')
+        .with_context(|| format!("Invalid address {} of hostfwd", s))?;
+    let addr = match addr {
+        "" => None,
+        addr => Some(
+            addr.parse::<Ipv4Addr>()
+                .with_context(|| format!("Invalid address {} of hostfwd", addr))?,
+        ),
+    };
+    let port = port
+        .parse::<u16>()
+        .with_context(|| format!("Invalid port {} of hostfwd", port))?;
+    Ok((addr, port))
+}
+
+/// Config struct for the user-mode network, which is a TCP/IP stack in the
+/// userspace and needs no tap device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserNetConfig {
+    /// Address of the virtual network.
+    pub net: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Address of the gateway, which is also the host seen by the guest.
+    pub host: Ipv4Addr,
+    /// Address of the DNS server, the queries are forwarded to the resolver
+    /// of the host.
+    pub dns: Ipv4Addr,
+    /// Address allocated to the guest by DHCP.
+    pub dhcp_start: Ipv4Addr,
+    pub hostfwds: Vec<HostFwd>,
+}
+
+impl Default for UserNetConfig {
+    fn default() -> Self {
+        UserNetConfig {
+            net: Ipv4Addr::new(10, 0, 2, 0),
+            prefix_len: 24,
+            host: Ipv4Addr::new(10, 0, 2, 2),
+            dns: Ipv4Addr::new(10, 0, 2, 3),
+            dhcp_start: Ipv4Addr::new(10, 0, 2, 15),
+            hostfwds: Vec::new(),
+        }
+    }
+}
+
+impl UserNetConfig {
+    /// Netmask of the virtual network.
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(!(u32::MAX >> self.prefix_len))
+    }
+
+    /// Check if `addr` is a unicast address of the virtual network.
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = u32::from(self.netmask());
+        let addr = u32::from(addr);
+        addr & mask == u32::from(self.net) && addr & !mask != 0 && addr & !mask != !mask
+    }
+}
+
+impl ConfigCheck for UserNetConfig {
+    fn check(&self) -> Result<()> {
+        if !(8..=30).contains(&self.prefix_len) {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "prefix length of user network".to_string(),
+                8,
+                true,
+                30,
+                true,
+            )));
+        }
+        if u32::from(self.net) & !u32::from(self.netmask()) != 0 {
+            bail!("Invalid user network {}/{}", self.net, self.prefix_len);
+        }
+        for (name, addr) in [
+            ("host", self.host),
+            ("dns", self.dns),
+            ("dhcpstart", self.dhcp_start),
+        ] {
+            if !self.contains(addr) {
+                bail!("The {} address {} is not in the user network", name, addr);
+            }
+        }
+        if self.host == self.dns || self.dhcp_start == self.host || self.dhcp_start == self.dns {
+            bail!("The host, dns and dhcpstart addresses of user network must be different");
+        }
+        for fwd in self.hostfwds.iter() {
+            if let Some(addr) = fwd.guest_addr {
+                if !self.contains(addr) {
+                    bail!(
+                        "The guest address {} of hostfwd is not in the user network",
+                        addr
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse the "net=addr[/prefix_len]" of the user-mode network.
+fn parse_user_net_addr(s: &str) -> Result<(Ipv4Addr, u8)> {
+    let (addr, prefix_len) = s.split_once('/').unwrap_or((s, "24"));
+    let addr = addr
+        .parse::<Ipv4Addr>()
+        .with_context(|| format!("Invalid address {} of user network", addr))?;
+    let prefix_len = prefix_len
+        .parse::<u8>()
+        .with_context(|| format!("Invalid prefix length {} of user network", prefix_len))?;
+    Ok((addr, prefix_len))
+}
+
+fn parse_user_netdev(cmd_parser: &CmdParser) -> Result<UserNetConfig> {
+    let mut user = UserNetConfig::default();
+    if let Some(net) = cmd_parser.get_value::<String>("net")? {
+        (user.net, user.prefix_len) = parse_user_net_addr(&net)?;
+    }
+    for (name, addr) in [
+        ("host", &mut user.host),
+        ("dns", &mut user.dns),
+        ("dhcpstart", &mut user.dhcp_start),
+    ] {
+        if let Some(value) = cmd_parser.get_value::<String>(name)? {
+            *addr = value
+                .parse::<Ipv4Addr>()
+                .with_context(|| format!("Invalid {} address {}", name, value))?;
+        }
+    }
+    Ok(user)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetDevcfg {
     pub id: String,
@@ -35,6 +213,7 @@ pub struct NetDevcfg {
     pub ifname: String,
     pub queues: u16,
     pub chardev: Option<String>,
+    pub user: Option<UserNetConfig>,
 }
 
 impl Default for NetDevcfg {
@@ -47,6 +226,7 @@ impl Default for NetDevcfg {
             ifname: "".to_string(),
             queues: 2,
             chardev: None,
+            user: None,
         }
     }
 }
@@ -83,6 +263,13 @@ impl ConfigCheck for NetDevcfg {
             )));
         }
 
+        if let Some(user) = self.user.as_ref() {
+            if self.queues != 2 {
+                bail!("User network supports only one queue pair");
+            }
+            user.check()?;
+        }
+
         Ok(())
     }
 }
@@ -104,6 +291,11 @@ pub struct NetworkInterfaceConfig {
     pub socket_path: Option<String>,
     /// All queues of a net device have the same queue size now.
     pub queue_size: u16,
+    /// Config of the user-mode network backend.
+    pub user: Option<UserNetConfig>,
+    /// Offer the packed virtqueue layout, the device can't be migrated once
+    /// the guest uses it.
+    pub packed: bool,
 }
 
 impl Default for NetworkInterfaceConfig {
@@ -120,6 +312,8 @@ impl Default for NetworkInterfaceConfig {
             mq: false,
             socket_path: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            user: None,
+            packed: false,
         }
     }
 }
@@ -200,7 +394,7 @@ fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
     } else {
         "".to_string()
     };
-    if netdev_type.ne("tap") && netdev_type.ne("vhost-user") {
+    if netdev_type.ne("tap") && netdev_type.ne("vhost-user") && netdev_type.ne("user") {
         bail!("Unsupported netdev type: {:?}", &netdev_type);
     }
     if let Some(net_id) = cmd_parser.get_value::<String>("id")? {
@@ -270,7 +464,12 @@ fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
     if net.vhost_fds.is_some() && net.vhost_type.is_none() {
         bail!("Argument \'vhostfd\' is not needed for virtio-net device");
     }
-    if net.tap_fds.is_none() && net.ifname.eq("") && netdev_type.ne("vhost-user") {
+    if netdev_type.eq("user") {
+        if net.tap_fds.is_some() || !net.ifname.is_empty() || net.vhost_type.is_some() {
+            bail!("User netdev does not support tap or vhost options");
+        }
+        net.user = Some(parse_user_netdev(&cmd_parser)?);
+    } else if net.tap_fds.is_none() && net.ifname.eq("") && netdev_type.ne("vhost-user") {
         bail!("Tap device is missing, use \'ifname\' or \'fd\' to configure a tap device");
     }
 
@@ -292,7 +491,8 @@ pub fn parse_net(vm_config: &mut VmConfig, net_config: &str) -> Result<NetworkIn
         .push("multifunction")
         .push("mac")
         .push("iothread")
-        .push("queue-size");
+        .push("queue-size")
+        .push("packed");
 
     cmd_parser.parse(net_config)?;
     pci_args_check(&cmd_parser)?;
@@ -317,6 +517,9 @@ pub fn parse_net(vm_config: &mut VmConfig, net_config: &str) -> Result<NetworkIn
     if let Some(queue_size) = cmd_parser.get_value::<u16>("queue-size")? {
         netdevinterfacecfg.queue_size = queue_size;
     }
+    if let Some(packed) = cmd_parser.get_value::<ExBool>("packed")? {
+        netdevinterfacecfg.packed = packed.inner;
+    }
 
     if let Some(netcfg) = &vm_config.netdevs.remove(&netdev) {
         netdevinterfacecfg.id = netid;
@@ -325,6 +528,7 @@ pub fn parse_net(vm_config: &mut VmConfig, net_config: &str) -> Result<NetworkIn
         netdevinterfacecfg.vhost_fds = netcfg.vhost_fds.clone();
         netdevinterfacecfg.vhost_type = netcfg.vhost_type.clone();
         netdevinterfacecfg.queues = netcfg.queues;
+        netdevinterfacecfg.user = netcfg.user.clone();
         if let Some(chardev) = &netcfg.chardev {
             netdevinterfacecfg.socket_path = Some(get_chardev_socket_path(chardev, vm_config)?);
         }
@@ -350,6 +554,7 @@ pub fn get_netdev_config(args: Box<qmp_schema::NetDevAddArgument>) -> Result<Net
         ifname: String::new(),
         queues,
         chardev: args.chardev,
+        user: None,
     };
 
     if let Some(fds) = args.fds {
@@ -411,7 +616,10 @@ pub fn get_netdev_config(args: Box<qmp_schema::NetDevAddArgument>) -> Result<Net
     if config.vhost_fds.is_some() && config.vhost_type.is_none() {
         bail!("Argument \'vhostfd\' is not needed for virtio-net device");
     }
-    if config.tap_fds.is_none() && config.ifname.eq("") && netdev_type.ne("vhost-user") {
+    if netdev_type.eq("user") {
+        config.user = Some(UserNetConfig::default());
+        config.check()?;
+    } else if config.tap_fds.is_none() && config.ifname.eq("") && netdev_type.ne("vhost-user") {
         bail!("Tap device is missing, use \'ifname\' or \'fd\' to configure a tap device");
     }
 
@@ -431,10 +639,29 @@ impl VmConfig {
             .push("vhostfd")
             .push("vhostfds")
             .push("queues")
-            .push("chardev");
-
-        cmd_parser.parse(netdev_config)?;
-        let drive_cfg = parse_netdev(cmd_parser)?;
+            .push("chardev")
+            .push("net")
+            .push("host")
+            .push("dns")
+            .push("dhcpstart");
+
+        // The "hostfwd" may be repeated, take the rules out before parsing.
+        let (hostfwds, params): (Vec<&str>, Vec<&str>) = netdev_config
+            .split(',')
+            .partition(|param| param.starts_with("hostfwd="));
+        cmd_parser.parse(&params.join(","))?;
+        let mut drive_cfg = parse_netdev(cmd_parser)?;
+        if !hostfwds.is_empty() {
+            let user = drive_cfg
+                .user
+                .as_mut()
+                .with_context(|| "Argument \'hostfwd\' is only supported by user netdev")?;
+            for hostfwd in hostfwds {
+                user.hostfwds
+                    .push(hostfwd.trim_start_matches("hostfwd=").parse::<HostFwd>()?);
+            }
+            user.check()?;
+        }
         self.add_netdev_with_config(drive_cfg)
     }
 
@@ -554,6 +781,15 @@ mod tests {
         assert!(network_configs.tap_fds.is_none());
         assert!(network_configs.vhost_type.is_none());
         assert!(network_configs.vhost_fds.is_none());
+        assert!(!network_configs.packed);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_netdev("tap,id=eth0,ifname=tap0").is_ok());
+        let net_cfg_res = parse_net(
+            &mut vm_config,
+            "virtio-net-device,id=net0,netdev=eth0,packed=on",
+        );
+        assert!(net_cfg_res.unwrap().packed);
 
         let mut vm_config = VmConfig::default();
         assert!(vm_config
@@ -752,6 +988,71 @@ mod tests {
             .is_err());
     }
 
+    #[test]
+    fn test_user_netdev_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_netdev("user,id=netdev0").is_ok());
+        let user = vm_config.netdevs.get("netdev0").unwrap().user.clone();
+        assert_eq!(user, Some(UserNetConfig::default()));
+
+        assert!(vm_config
+            .add_netdev(
+                "user,id=netdev1,net=192.168.76.0/24,host=192.168.76.2,dns=192.168.76.3,\
+                 dhcpstart=192.168.76.9,hostfwd=tcp::2222-:22,\
+                 hostfwd=tcp:127.0.0.1:8080-192.168.76.9:80"
+            )
+            .is_ok());
+        let user = vm_config
+            .netdevs
+            .get("netdev1")
+            .unwrap()
+            .user
+            .clone()
+            .unwrap();
+        assert_eq!(user.net, Ipv4Addr::new(192, 168, 76, 0));
+        assert_eq!(user.netmask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(user.dhcp_start, Ipv4Addr::new(192, 168, 76, 9));
+        assert_eq!(
+            user.hostfwds,
+            vec![
+                HostFwd {
+                    host_addr: Ipv4Addr::UNSPECIFIED,
+                    host_port: 2222,
+                    guest_addr: None,
+                    guest_port: 22,
+                },
+                HostFwd {
+                    host_addr: Ipv4Addr::LOCALHOST,
+                    host_port: 8080,
+                    guest_addr: Some(Ipv4Addr::new(192, 168, 76, 9)),
+                    guest_port: 80,
+                },
+            ]
+        );
+
+        let net_cfg =
+            parse_net(&mut vm_config, "virtio-net-device,id=net0,netdev=netdev0").unwrap();
+        assert!(net_cfg.user.is_some());
+
+        assert!(vm_config.add_netdev("user,id=netdev2,ifname=tap0").is_err());
+        assert!(vm_config.add_netdev("user,id=netdev3,queues=2").is_err());
+        assert!(vm_config
+            .add_netdev("user,id=netdev4,host=10.0.3.2")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("user,id=netdev5,net=10.0.2.1/24")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("user,id=netdev6,hostfwd=udp::5353-:53")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("user,id=netdev7,hostfwd=tcp::2222-10.0.3.15:22")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("tap,id=netdev8,ifname=tap0,hostfwd=tcp::2222-:22")
+            .is_err());
+    }
+
     #[test]
     fn test_add_netdev_with_config() {
         let mut vm_config = VmConfig::default();
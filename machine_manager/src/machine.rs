@@ -17,8 +17,10 @@ use once_cell::sync::Lazy;
 use strum::VariantNames;
 
 use crate::qmp::qmp_schema::{
-    BlockDevAddArgument, CharDevAddArgument, ChardevInfo, Cmd, CmdLine, DeviceAddArgument,
-    DeviceProps, Events, GicCap, IothreadInfo, KvmInfo, MachineInfo, MigrateCapabilities,
+    BlockCommitArgument, BlockDevAddArgument, BlockDeviceInfo, BlockInfo, BlockJobInfo,
+    BlockSetIoThrottleArgument, BlockStats, BlockdevSnapshotSyncArgument, CharDevAddArgument,
+    ChardevInfo, Cmd, CmdLine, DeviceAddArgument, DeviceProps, DriveBackupArgument,
+    DriveMirrorArgument, Events, GicCap, IothreadInfo, KvmInfo, MachineInfo, MigrateCapabilities,
     NetDevAddArgument, PropList, QmpCommand, QmpEvent, Target, TypeLists,
 };
 use crate::qmp::{Response, Version};
@@ -149,6 +151,9 @@ pub trait DeviceInterface {
     /// Query each `hotpluggable_cpus`'s topology info and hotplug message.
     fn query_hotpluggable_cpus(&self) -> Response;
 
+    /// Query the slots reserved for replaceable devices and what is plugged into them.
+    fn query_hotplug_slots(&self) -> Response;
+
     /// Add a device with configuration.
     fn device_add(&mut self, args: Box<DeviceAddArgument>) -> Response;
 
@@ -161,6 +166,31 @@ pub trait DeviceInterface {
     /// Delete a block device.
     fn blockdev_del(&self, node_name: String) -> Response;
 
+    /// Resize the image of a block device.
+    fn block_resize(&self, device: String, size: u64) -> Response;
+
+    /// Change the IO limits of a block device.
+    fn block_set_io_throttle(&self, args: Box<BlockSetIoThrottleArgument>) -> Response;
+
+    /// Switch a block device onto a new overlay of its image.
+    fn blockdev_snapshot_sync(&self, args: BlockdevSnapshotSyncArgument) -> Response;
+
+    /// Start a job merging the overlay of a block device into its backing image.
+    fn block_commit(&self, args: BlockCommitArgument) -> Response;
+
+    /// Start a job copying a block device to another image, which the device
+    /// is then switched onto.
+    fn drive_mirror(&self, args: DriveMirrorArgument) -> Response;
+
+    /// Start a job copying a block device as it is now to another image.
+    fn drive_backup(&self, args: DriveBackupArgument) -> Response;
+
+    /// Cancel a block job.
+    fn block_job_cancel(&self, device: String) -> Response;
+
+    /// Switch the device of a ready mirror job onto the target.
+    fn block_job_complete(&self, device: String) -> Response;
+
     /// Create a new network device.
     fn netdev_add(&mut self, args: Box<NetDevAddArgument>) -> Response;
 
@@ -257,7 +287,7 @@ pub trait DeviceInterface {
         };
         #[cfg(target_arch = "x86_64")]
         vec_machine.push(machine_info);
-        #[cfg(target_arch = "aarch64")]
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
         let machine_info = MachineInfo {
             hotplug: false,
             name: "virt".to_string(),
@@ -265,7 +295,7 @@ pub trait DeviceInterface {
             cpu_max: 255,
             deprecated: false,
         };
-        #[cfg(target_arch = "aarch64")]
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
         vec_machine.push(machine_info);
         Response::create_response(serde_json::to_value(&vec_machine).unwrap(), None)
     }
@@ -379,23 +409,22 @@ pub trait DeviceInterface {
     }
 
     fn query_block(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
+        let vec_cmd: Vec<BlockInfo> = Vec::new();
         Response::create_response(serde_json::to_value(&vec_cmd).unwrap(), None)
     }
 
     fn query_named_block_nodes(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
+        let vec_cmd: Vec<BlockDeviceInfo> = Vec::new();
         Response::create_response(serde_json::to_value(&vec_cmd).unwrap(), None)
     }
 
     fn query_blockstats(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
+        let vec_cmd: Vec<BlockStats> = Vec::new();
         Response::create_response(serde_json::to_value(&vec_cmd).unwrap(), None)
     }
 
     fn query_block_jobs(&self) -> Response {
-        // Fix me: qmp command call, return none temporarily.
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
+        let vec_cmd: Vec<BlockJobInfo> = Vec::new();
         Response::create_response(serde_json::to_value(&vec_cmd).unwrap(), None)
     }
 
@@ -450,16 +450,25 @@ fn qmp_command_exec(
         (query_cpus, query_cpus),
         (query_balloon, query_balloon),
         (list_type, list_type),
-        (query_hotpluggable_cpus, query_hotpluggable_cpus);
+        (query_hotpluggable_cpus, query_hotpluggable_cpus),
+        (query_hotplug_slots, query_hotplug_slots);
         (device_list_properties, device_list_properties, typename),
         (device_del, device_del, id),
         (blockdev_del, blockdev_del, node_name),
+        (block_resize, block_resize, device, size),
+        (block_job_cancel, block_job_cancel, device),
+        (block_job_complete, block_job_complete, device),
         (netdev_del, netdev_del, id),
         (chardev_remove, chardev_remove, id),
         (balloon, balloon, value),
         (migrate, migrate, uri);
         (device_add, device_add),
         (blockdev_add, blockdev_add),
+        (block_set_io_throttle, block_set_io_throttle),
+        (blockdev_snapshot_sync, blockdev_snapshot_sync),
+        (block_commit, block_commit),
+        (drive_mirror, drive_mirror),
+        (drive_backup, drive_backup),
         (netdev_add, netdev_add),
         (chardev_add, chardev_add)
     );
@@ -661,6 +670,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_qmp_backend_deleted_event_msg() {
+        let event_json = r#"{"event":"BLOCKDEV_DELETED","data":{"node-name":"drive-0"},"timestamp":{"seconds":1575531524,"microseconds":91519}}"#;
+        let qmp_event: schema::QmpEvent = serde_json::from_str(event_json).unwrap();
+        match qmp_event {
+            schema::QmpEvent::BlockdevDeleted { data, .. } => {
+                assert_eq!(data.node_name, "drive-0");
+            }
+            _ => panic!("Unexpected event"),
+        }
+
+        let event_json = r#"{"event":"NETDEV_DELETED","data":{"id":"netdev-0"},"timestamp":{"seconds":1575531524,"microseconds":91519}}"#;
+        let qmp_event: schema::QmpEvent = serde_json::from_str(event_json).unwrap();
+        match qmp_event {
+            schema::QmpEvent::NetdevDeleted { data, .. } => {
+                assert_eq!(data.id, "netdev-0");
+            }
+            _ => panic!("Unexpected event"),
+        }
+    }
+
     // Environment Preparation for UnixSocket
     fn prepare_unix_socket_environment(socket_id: &str) -> (UnixListener, UnixStream, UnixStream) {
         let socket_name: String = format!("test_{}.sock", socket_id);
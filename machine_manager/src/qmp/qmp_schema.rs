@@ -120,6 +120,14 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "query-hotplug-slots")]
+    #[strum(serialize = "query-hotplug-slots")]
+    query_hotplug_slots {
+        #[serde(default)]
+        arguments: query_hotplug_slots,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-cpus")]
     #[strum(serialize = "query-cpus")]
     query_cpus {
@@ -152,6 +160,23 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    block_resize {
+        arguments: block_resize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    block_set_io_throttle {
+        arguments: Box<block_set_io_throttle>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "blockdev-snapshot-sync")]
+    #[strum(serialize = "blockdev-snapshot-sync")]
+    blockdev_snapshot_sync {
+        arguments: blockdev_snapshot_sync,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "balloon")]
     balloon {
         #[serde(default)]
@@ -246,11 +271,38 @@ pub enum QmpCommand {
     #[serde(rename = "block-commit")]
     #[strum(serialize = "block-commit")]
     block_commit {
-        #[serde(default)]
         arguments: block_commit,
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "drive-mirror")]
+    #[strum(serialize = "drive-mirror")]
+    drive_mirror {
+        arguments: drive_mirror,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "drive-backup")]
+    #[strum(serialize = "drive-backup")]
+    drive_backup {
+        arguments: drive_backup,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-job-cancel")]
+    #[strum(serialize = "block-job-cancel")]
+    block_job_cancel {
+        arguments: block_job_cancel,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-job-complete")]
+    #[strum(serialize = "block-job-complete")]
+    block_job_complete {
+        arguments: block_job_complete,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-tpm-models")]
     query_tpm_models {
         #[serde(default)]
@@ -513,6 +565,10 @@ pub struct device_add {
     pub queues: Option<u16>,
     pub boot_index: Option<u8>,
     pub sysfsdev: Option<String>,
+    #[serde(rename = "chardev")]
+    pub chardev: Option<String>,
+    #[serde(rename = "guest-cid")]
+    pub guest_cid: Option<u64>,
 }
 
 pub type DeviceAddArgument = device_add;
@@ -548,6 +604,7 @@ pub struct CacheOptions {
 /// * `file` - the backend file information.
 /// * `cache` - if use direct io.
 /// * `read_only` - if readonly.
+/// * `aio` - the aio engine, "native" with direct io and "off" otherwise by default.
 ///
 /// Additional arguments depend on the type.
 ///
@@ -574,6 +631,11 @@ pub struct blockdev_add {
     pub driver: Option<String>,
     pub backing: Option<String>,
     pub discard: Option<String>,
+    #[serde(rename = "detect-zeroes")]
+    pub detect_zeroes: Option<String>,
+    pub aio: Option<String>,
+    pub werror: Option<String>,
+    pub rerror: Option<String>,
     pub id: Option<String>,
     pub options: Option<String>,
     #[serde(rename = "throttling.iops-total")]
@@ -791,6 +853,156 @@ impl Command for blockdev_del {
     }
 }
 
+/// block_resize
+///
+/// Resize the image of a block device, the guest is notified of the new size.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `size` - The new size of the image in bytes, aligned to 512 bytes.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block_resize",
+///      "arguments": { "device": "drive-0", "size": 1073741824 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_resize {
+    pub device: String,
+    pub size: u64,
+}
+
+impl Command for block_resize {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block_set_io_throttle
+///
+/// Change the IO limits of a running block device.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `bps`, `bps_rd`, `bps_wr` - Total, read and write bytes per second, 0 means no limit.
+/// * `iops`, `iops_rd`, `iops_wr` - Total, read and write operations per second, 0 means no limit.
+/// * `*_max` - Burst rate of the limit.
+/// * `*_max_length` - Seconds the burst rate can last, 1 by default.
+/// * `iops_size` - Size of one operation in bytes, larger IOs count as several operations.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block_set_io_throttle",
+///      "arguments": { "device": "drive-0", "bps": 1048576, "bps_rd": 0, "bps_wr": 0,
+///                     "iops": 0, "iops_rd": 0, "iops_wr": 0,
+///                     "bps_max": 2097152, "bps_max_length": 60 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct block_set_io_throttle {
+    pub device: String,
+    #[serde(flatten)]
+    pub throttle: IoThrottle,
+}
+
+pub type BlockSetIoThrottleArgument = block_set_io_throttle;
+
+impl Command for block_set_io_throttle {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// blockdev-snapshot-sync
+///
+/// Take an external snapshot of a running block device: its current image is
+/// frozen as the backing file of a new qcow2 overlay, which the device writes
+/// to from then on.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `snapshot-file` - Path of the overlay.
+/// * `format` - Format of the overlay, only "qcow2" is supported.
+/// * `mode` - "absolute-paths" to create the overlay, the default, or
+///   "existing" to use an overlay already backed by the current image.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "blockdev-snapshot-sync",
+///      "arguments": { "device": "drive-0",
+///                     "snapshot-file": "/path/to/overlay.qcow2",
+///                     "format": "qcow2" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct blockdev_snapshot_sync {
+    pub device: String,
+    #[serde(rename = "snapshot-file")]
+    pub snapshot_file: String,
+    pub format: Option<String>,
+    pub mode: Option<String>,
+}
+
+pub type BlockdevSnapshotSyncArgument = blockdev_snapshot_sync;
+
+impl Command for blockdev_snapshot_sync {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// IO limits of a block device, rates are in bytes or operations per second.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct IoThrottle {
+    pub bps: u64,
+    pub bps_rd: u64,
+    pub bps_wr: u64,
+    pub iops: u64,
+    pub iops_rd: u64,
+    pub iops_wr: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_rd_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_wr_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_rd_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_wr_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_max_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_rd_max_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bps_wr_max_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_max_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_rd_max_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_wr_max_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iops_size: Option<u64>,
+}
+
 /// netdev_del
 ///
 /// Remove a network backend.
@@ -893,6 +1105,55 @@ pub struct CpuInstanceProperties {
     pub core_id: Option<isize>,
 }
 
+/// query-hotplug-slots:
+///
+/// # Returns
+///
+/// A list of the virtio-mmio slots reserved for replaceable devices.
+///
+/// # Examples
+///
+/// For microvm machine type started with -machine microvm,hotplug-slots=blk:net:
+/// ```text
+/// -> { "execute": "query-hotplug-slots" }
+/// <- {"return": [
+///      {
+///         "slot": 0, "type": "blk", "addr": "0x10001000", "used": true,
+///         "id": "drive-0", "driver": "virtio-blk-device"
+///      },
+///      {
+///         "slot": 1, "type": "net", "addr": "0x10002000", "used": false
+///      }
+///    ]}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_hotplug_slots {}
+
+impl Command for query_hotplug_slots {
+    type Res = Vec<HotplugSlot>;
+
+    fn back(self) -> Vec<HotplugSlot> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct HotplugSlot {
+    #[serde(rename = "slot")]
+    pub slot: usize,
+    #[serde(rename = "type")]
+    pub slot_type: String,
+    #[serde(rename = "addr")]
+    pub addr: String,
+    #[serde(rename = "used")]
+    pub used: bool,
+    #[serde(rename = "id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "driver", default, skip_serializing_if = "Option::is_none")]
+    pub driver: Option<String>,
+}
+
 /// query-cpus:
 ///
 /// This command causes vCPU threads to exit to userspace, which causes
@@ -1130,6 +1391,27 @@ impl Command for cancel_migrate {
 pub struct MigrationInfo {
     #[serde(rename = "status", default, skip_serializing_if = "Option::is_none")]
     pub status: Option<String>,
+    #[serde(
+        rename = "total-time",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub total_time: Option<u64>,
+    #[serde(rename = "ram", default, skip_serializing_if = "Option::is_none")]
+    pub ram: Option<MigrationRamInfo>,
+}
+
+/// Guest memory statistics of the current migration, in bytes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MigrationRamInfo {
+    #[serde(rename = "transferred")]
+    pub transferred: u64,
+    #[serde(rename = "remaining")]
+    pub remaining: u64,
+    #[serde(rename = "total")]
+    pub total: u64,
+    #[serde(rename = "dirty-sync-count")]
+    pub dirty_sync_count: u64,
 }
 
 /// getfd
@@ -1233,6 +1515,160 @@ pub struct DeviceDeleted {
     pub path: String,
 }
 
+/// BlockIoError
+///
+/// Emitted when a disk I/O error occurs.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_IO_ERROR",
+///      "data": { "device": "drive-0", "operation": "write",
+///                "action": "stop", "nospace": true,
+///                "reason": "No space left on device" },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockIoError {
+    /// Device name.
+    pub device: String,
+    /// I/O operation, "read" or "write".
+    pub operation: String,
+    /// Action that has been taken, "ignore", "report" or "stop".
+    pub action: String,
+    /// Whether the error is caused by lack of space on the host.
+    pub nospace: bool,
+    /// Human readable description of the error.
+    pub reason: String,
+}
+
+/// BlockJobCompleted
+///
+/// Emitted when a block job has completed, or failed with `error`.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_JOB_COMPLETED",
+///      "data": { "type": "commit", "device": "drive-0", "len": 1073741824,
+///                "offset": 1073741824, "speed": 0 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockJobCompleted {
+    /// Type of the job.
+    #[serde(rename = "type")]
+    pub job_type: String,
+    /// The id of the job.
+    pub device: String,
+    /// Bytes to be copied by the job.
+    pub len: u64,
+    /// Bytes copied by the job.
+    pub offset: u64,
+    /// Rate limit of the job, bytes per second.
+    pub speed: u64,
+    /// Why the job failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// BlockJobCancelled
+///
+/// Emitted when a block job has been cancelled.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_JOB_CANCELLED",
+///      "data": { "type": "mirror", "device": "drive-0", "len": 1073741824,
+///                "offset": 134217728, "speed": 0 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockJobCancelled {
+    /// Type of the job.
+    #[serde(rename = "type")]
+    pub job_type: String,
+    /// The id of the job.
+    pub device: String,
+    /// Bytes to be copied by the job.
+    pub len: u64,
+    /// Bytes copied by the job.
+    pub offset: u64,
+    /// Rate limit of the job, bytes per second.
+    pub speed: u64,
+}
+
+/// BlockJobReady
+///
+/// Emitted when a mirror job has copied the disk, it can be completed then.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_JOB_READY",
+///      "data": { "type": "mirror", "device": "drive-0", "len": 1073741824,
+///                "offset": 1073741824, "speed": 0 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockJobReady {
+    /// Type of the job.
+    #[serde(rename = "type")]
+    pub job_type: String,
+    /// The id of the job.
+    pub device: String,
+    /// Bytes to be copied by the job.
+    pub len: u64,
+    /// Bytes copied by the job.
+    pub offset: u64,
+    /// Rate limit of the job, bytes per second.
+    pub speed: u64,
+}
+
+/// NetdevDeleted
+///
+/// Emitted when a network backend has been deleted and its resources are
+/// released. At this point, it's safe to reuse the specified netdev ID.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "NETDEV_DELETED",
+///      "data": { "id": "netdev-0" },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct NetdevDeleted {
+    /// The id of the netdev.
+    pub id: String,
+}
+
+/// BlockdevDeleted
+///
+/// Emitted when a block backend has been deleted and its image is released.
+/// At this point, it's safe to reuse the specified node name.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCKDEV_DELETED",
+///      "data": { "node-name": "drive-0" },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockdevDeleted {
+    /// The node name of the block backend.
+    #[serde(rename = "node-name")]
+    pub node_name: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, EnumIter, EnumVariantNames, EnumString)]
 #[serde(tag = "event")]
 pub enum QmpEvent {
@@ -1265,6 +1701,36 @@ pub enum QmpEvent {
         data: BalloonInfo,
         timestamp: TimeStamp,
     },
+    #[serde(rename = "BLOCK_IO_ERROR")]
+    BlockIoError {
+        data: BlockIoError,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCK_JOB_COMPLETED")]
+    BlockJobCompleted {
+        data: BlockJobCompleted,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCK_JOB_CANCELLED")]
+    BlockJobCancelled {
+        data: BlockJobCancelled,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCK_JOB_READY")]
+    BlockJobReady {
+        data: BlockJobReady,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "NETDEV_DELETED")]
+    NetdevDeleted {
+        data: NetdevDeleted,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCKDEV_DELETED")]
+    BlockdevDeleted {
+        data: BlockdevDeleted,
+        timestamp: TimeStamp,
+    },
 }
 
 /// query-balloon:
@@ -1563,13 +2029,198 @@ impl Command for device_list_properties {
     }
 }
 
+/// block-commit
+///
+/// Merge the active overlay of a block device into its backing image in a
+/// background job. The device is switched onto the backing image when the
+/// job completes, which is reported by the `BLOCK_JOB_COMPLETED` event.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `job-id` - The id of the job, the id of the device by default.
+/// * `speed` - Bytes copied per second at most, 0 means no limit.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-commit",
+///      "arguments": { "device": "drive-0", "speed": 104857600 } }
+/// <- { "return": {} }
+/// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct block_commit {}
+#[serde(deny_unknown_fields)]
+pub struct block_commit {
+    pub device: String,
+    #[serde(rename = "job-id", default)]
+    pub job_id: Option<String>,
+    #[serde(default)]
+    pub speed: u64,
+}
+
+pub type BlockCommitArgument = block_commit;
 
 impl Command for block_commit {
-    type Res = Vec<DeviceProps>;
+    type Res = Empty;
 
-    fn back(self) -> Vec<DeviceProps> {
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// drive-mirror
+///
+/// Copy a block device to another image in a background job, the guest
+/// writes are copied too. The job is ready once the images are in sync,
+/// reported by the `BLOCK_JOB_READY` event, and then switches the device onto
+/// the target on `block-job-complete`.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `target` - Path of the target image.
+/// * `format` - Format of the target, "raw" or "qcow2", the format of the
+///   device by default.
+/// * `sync` - "full" to copy the whole disk, or "top" to copy only the active
+///   qcow2 image, the target is then backed by its backing image.
+/// * `mode` - "absolute-paths" to create the target, the default, or
+///   "existing" to use an image already created.
+/// * `job-id` - The id of the job, the id of the device by default.
+/// * `speed` - Bytes copied per second at most, 0 means no limit.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "drive-mirror",
+///      "arguments": { "device": "drive-0", "target": "/path/to/target.img",
+///                     "sync": "full", "format": "raw" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct drive_mirror {
+    pub device: String,
+    pub target: String,
+    pub format: Option<String>,
+    pub sync: String,
+    pub mode: Option<String>,
+    #[serde(rename = "job-id", default)]
+    pub job_id: Option<String>,
+    #[serde(default)]
+    pub speed: u64,
+}
+
+pub type DriveMirrorArgument = drive_mirror;
+
+impl Command for drive_mirror {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// drive-backup
+///
+/// Copy a block device as it is when the command runs to another image in a
+/// background job. The data the guest overwrites meanwhile is copied before
+/// the writes. The job completes by itself, reported by the
+/// `BLOCK_JOB_COMPLETED` event.
+///
+/// # Arguments
+///
+/// Same as `drive-mirror`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "drive-backup",
+///      "arguments": { "device": "drive-0", "target": "/path/to/backup.qcow2",
+///                     "sync": "full", "format": "qcow2" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct drive_backup {
+    pub device: String,
+    pub target: String,
+    pub format: Option<String>,
+    pub sync: String,
+    pub mode: Option<String>,
+    #[serde(rename = "job-id", default)]
+    pub job_id: Option<String>,
+    #[serde(default)]
+    pub speed: u64,
+}
+
+pub type DriveBackupArgument = drive_backup;
+
+impl Command for drive_backup {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-job-cancel
+///
+/// Stop a block job, the device stays on its image. Reported by the
+/// `BLOCK_JOB_CANCELLED` event.
+///
+/// # Arguments
+///
+/// * `device` - The id of the job.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-job-cancel", "arguments": { "device": "drive-0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_job_cancel {
+    pub device: String,
+}
+
+pub type BlockJobCancelArgument = block_job_cancel;
+
+impl Command for block_job_cancel {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-job-complete
+///
+/// Switch the device of a ready mirror job onto the target, the job then
+/// completes.
+///
+/// # Arguments
+///
+/// * `device` - The id of the job.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-job-complete", "arguments": { "device": "drive-0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_job_complete {
+    pub device: String,
+}
+
+pub type BlockJobCompleteArgument = block_job_complete;
+
+impl Command for block_job_complete {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
         Default::default()
     }
 }
@@ -1786,34 +2437,81 @@ impl Command for qom_get {
 ///
 /// ```text
 /// -> { "execute": "query-block" }
-/// <- {"return":[]}
+/// <- {"return":[{"device":"drive-0","locked":false,"removable":false,
+///     "inserted":{"file":"/path/to/block","ro":false,"drv":"raw",
+///     "detect_zeroes":"off","bps":0,"bps_rd":0,"bps_wr":0,"iops":0,"iops_rd":0,
+///     "iops_wr":0,"cache":{"direct":true},"aio":"native",
+///     "image":{"filename":"/path/to/block","format":"raw","virtual-size":1073741824}}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_block {}
 
 impl Command for query_block {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockInfo> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockInfo {
+    pub device: String,
+    pub locked: bool,
+    pub removable: bool,
+    /// The image of the block device, none if no image is inserted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inserted: Option<BlockDeviceInfo>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDeviceInfo {
+    pub file: String,
+    pub ro: bool,
+    pub drv: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backing_file: Option<String>,
+    pub detect_zeroes: String,
+    #[serde(flatten)]
+    pub throttle: IoThrottle,
+    pub cache: BlockdevCacheInfo,
+    pub aio: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iothread: Option<String>,
+    pub image: ImageInfo,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockdevCacheInfo {
+    pub direct: bool,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct ImageInfo {
+    pub filename: String,
+    pub format: String,
+    #[serde(rename = "virtual-size")]
+    pub virtual_size: u64,
+}
+
 /// Query named block node.
 ///
 /// # Example
 ///
 /// ```text
 /// -> { "execute": "query-named-block-nodes" }
-/// <- {"return":[]}
+/// <- {"return":[{"file":"/path/to/block","ro":false,"drv":"raw",
+///     "detect_zeroes":"off","bps":0,"bps_rd":0,"bps_wr":0,"iops":0,"iops_rd":0,
+///     "iops_wr":0,"cache":{"direct":true},"aio":"native",
+///     "image":{"filename":"/path/to/block","format":"raw","virtual-size":1073741824}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_named_block_nodes {}
 
 impl Command for query_named_block_nodes {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockDeviceInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockDeviceInfo> {
         Default::default()
     }
 }
@@ -1824,38 +2522,93 @@ impl Command for query_named_block_nodes {
 ///
 /// ```text
 /// -> { "execute": "query-blockstats" }
-/// <- {"return":[]}
+/// <- {"return":[{"device":"drive-0","stats":{"rd_bytes":4096,"wr_bytes":0,
+///     "unmap_bytes":0,"rd_operations":1,"wr_operations":0,"flush_operations":0,
+///     "unmap_operations":0,"rd_merged":0,"wr_merged":0,"unmap_merged":0,
+///     "failed_rd_operations":0,"failed_wr_operations":0,"failed_flush_operations":0,
+///     "failed_unmap_operations":0,"rd_total_time_ns":102400,"wr_total_time_ns":0,
+///     "flush_total_time_ns":0,"unmap_total_time_ns":0,"idle_time_ns":5102400}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_blockstats {}
 
 impl Command for query_blockstats {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockStats>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockStats> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockStats {
+    pub device: String,
+    pub stats: BlockDeviceStats,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDeviceStats {
+    pub rd_bytes: u64,
+    pub wr_bytes: u64,
+    pub unmap_bytes: u64,
+    pub rd_operations: u64,
+    pub wr_operations: u64,
+    pub flush_operations: u64,
+    pub unmap_operations: u64,
+    pub rd_merged: u64,
+    pub wr_merged: u64,
+    pub unmap_merged: u64,
+    pub failed_rd_operations: u64,
+    pub failed_wr_operations: u64,
+    pub failed_flush_operations: u64,
+    pub failed_unmap_operations: u64,
+    pub rd_total_time_ns: u64,
+    pub wr_total_time_ns: u64,
+    pub flush_total_time_ns: u64,
+    pub unmap_total_time_ns: u64,
+    /// Time since the last IO, none if there's no IO yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_time_ns: Option<u64>,
+}
+
 /// Query jobs of blocks.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-block_jobs" }
-/// <- {"return":[]}
+/// -> { "execute": "query-block-jobs" }
+/// <- { "return": [ { "type": "commit", "device": "drive-0", "len": 1073741824,
+///                    "offset": 536870912, "busy": true, "paused": false,
+///                    "speed": 0, "io-status": "ok", "ready": false } ] }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_block_jobs {}
 
 impl Command for query_block_jobs {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockJobInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockJobInfo> {
         Default::default()
     }
 }
 
+/// Progress of a block job, `offset` grows to `len` as the data is copied.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockJobInfo {
+    #[serde(rename = "type")]
+    pub job_type: String,
+    /// The id of the job.
+    pub device: String,
+    pub len: u64,
+    pub offset: u64,
+    pub busy: bool,
+    pub paused: bool,
+    pub speed: u64,
+    #[serde(rename = "io-status")]
+    pub io_status: String,
+    pub ready: bool,
+}
+
 /// Query capabilities of gic.
 ///
 /// # Example
@@ -2028,6 +2781,19 @@ mod tests {
         let ret_msg = r#"invalid type: string "isdf", expected struct query_hotpluggable_cpus"#;
         assert!(err_msg == ret_msg);
 
+        // qmp: query-hotplug-slots.
+        let json_msg = r#"
+        {
+            "execute": "query-hotplug-slots"
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let ret_msg = r#"ok"#;
+        assert!(err_msg == ret_msg);
+
         // qmp: query-cpus.
         let json_msg = r#"
         {
@@ -2230,7 +2996,8 @@ mod tests {
                 "cache": {
                     "direct": true
                 },
-                "read-only": false
+                "read-only": false,
+                "aio": "native"
             }
         }
         "#;
@@ -2349,5 +3116,184 @@ mod tests {
         };
         let part_msg = r#"ok"#;
         assert!(err_msg.contains(part_msg));
+
+        // block_resize
+        let json_msg = r#"
+        {
+            "execute": "block_resize",
+            "arguments": {
+                "device": "drive-0",
+                "size": 1073741824
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        // block_set_io_throttle
+        let json_msg = r#"
+        {
+            "execute": "block_set_io_throttle",
+            "arguments": {
+                "device": "drive-0",
+                "bps": 1048576,
+                "bps_rd": 0,
+                "bps_wr": 0,
+                "iops": 0,
+                "iops_rd": 0,
+                "iops_wr": 0,
+                "bps_max": 2097152,
+                "bps_max_length": 60
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        // block_set_io_throttle requires all the average rates
+        let json_msg = r#"
+        {
+            "execute": "block_set_io_throttle",
+            "arguments": {
+                "device": "drive-0",
+                "bps": 1048576
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"missing field"#;
+        assert!(err_msg.contains(part_msg));
+
+        // blockdev-snapshot-sync
+        let json_msg = r#"
+        {
+            "execute": "blockdev-snapshot-sync",
+            "arguments": {
+                "device": "drive-0",
+                "snapshot-file": "/path/to/overlay.qcow2",
+                "format": "qcow2"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        // block-commit
+        let json_msg = r#"
+        {
+            "execute": "block-commit",
+            "arguments": {
+                "device": "drive-0",
+                "job-id": "commit-0",
+                "speed": 104857600
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        // block-commit requires the device
+        let json_msg = r#"
+        {
+            "execute": "block-commit",
+            "arguments": {
+                "speed": 104857600
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"missing field `device`"#;
+        assert!(err_msg.contains(part_msg));
+
+        // drive-mirror
+        let json_msg = r#"
+        {
+            "execute": "drive-mirror",
+            "arguments": {
+                "device": "drive-0",
+                "target": "/path/to/target.img",
+                "sync": "full",
+                "format": "raw",
+                "speed": 104857600
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        // drive-backup requires the sync mode
+        let json_msg = r#"
+        {
+            "execute": "drive-backup",
+            "arguments": {
+                "device": "drive-0",
+                "target": "/path/to/backup.qcow2"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"missing field `sync`"#;
+        assert!(err_msg.contains(part_msg));
+
+        // block-job-cancel
+        let json_msg = r#"
+        {
+            "execute": "block-job-cancel",
+            "arguments": {
+                "device": "mirror-0"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
+
+        // block-job-complete
+        let json_msg = r#"
+        {
+            "execute": "block-job-complete",
+            "arguments": {
+                "device": "mirror-0"
+            }
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
     }
 }
@@ -14,8 +14,9 @@ use std::collections::{hash_map::DefaultHasher, HashMap};
 use std::hash::{Hash, Hasher};
 use std::io::{Read, Write};
 use std::mem::size_of;
+use std::time::Instant;
 
-use crate::manager::{Instance, MIGRATION_MANAGER};
+use crate::manager::{Instance, MigrationProgress, MIGRATION_MANAGER};
 use crate::protocol::{
     DeviceStateDesc, FileFormat, MigrationHeader, MigrationStatus, VersionCheck, HEADER_LENGTH,
 };
@@ -193,6 +194,20 @@ impl MigrationManager {
         let mut status = MIGRATION_MANAGER.status.write().unwrap();
         *status = status.transfer(new_status)?;
 
+        let mut progress = MIGRATION_MANAGER.progress.write().unwrap();
+        match new_status {
+            MigrationStatus::Active => {
+                *progress = MigrationProgress {
+                    start_time: Some(Instant::now()),
+                    ..Default::default()
+                };
+            }
+            MigrationStatus::Completed | MigrationStatus::Failed | MigrationStatus::Canceled => {
+                progress.total_time = Some(progress.elapsed_time());
+            }
+            _ => {}
+        }
+
         Ok(())
     }
 
@@ -219,11 +234,17 @@ pub trait Lifecycle {
 
     /// Resume devices during migration.
     fn resume() -> Result<()> {
-        let locked_devices = &MIGRATION_MANAGER.vmm.read().unwrap().devices;
-        for (_, device) in locked_devices.iter() {
+        let locked_vmm = MIGRATION_MANAGER.vmm.read().unwrap();
+        for (_, device) in locked_vmm.devices.iter() {
             device.lock().unwrap().resume()?;
         }
 
+        // Transports activate their backend devices, so they are resumed
+        // after all device states are in place.
+        for (_, transport) in locked_vmm.transports.iter() {
+            transport.lock().unwrap().resume()?;
+        }
+
         Ok(())
     }
 }
@@ -27,6 +27,7 @@ use anyhow::anyhow;
 pub use anyhow::Result;
 use log::error;
 use machine_manager::qmp::{qmp_schema, Response};
+use manager::MIGRATION_MANAGER;
 pub use manager::{MigrationHook, MigrationManager};
 pub use protocol::{DeviceStateDesc, FieldDesc, MemBlock, MigrationStatus, StateTransfer};
 pub mod error;
@@ -145,8 +146,22 @@ pub fn migration_tcp_mode(path: String) -> Response {
 /// Query the current migration status.
 pub fn query_migrate() -> Response {
     let status_str = MigrationManager::status().to_string();
+    let progress = MIGRATION_MANAGER.progress.read().unwrap();
+    let (total_time, ram) = if progress.start_time.is_some() {
+        let ram = qmp_schema::MigrationRamInfo {
+            transferred: progress.transferred,
+            remaining: progress.remaining,
+            total: progress.total,
+            dirty_sync_count: progress.dirty_sync_count,
+        };
+        (Some(progress.elapsed_time()), Some(ram))
+    } else {
+        (None, None)
+    };
     let migration_info = qmp_schema::MigrationInfo {
         status: Some(status_str),
+        total_time,
+        ram,
     };
 
     Response::create_response(serde_json::to_value(migration_info).unwrap(), None)
@@ -154,6 +169,13 @@ pub fn query_migrate() -> Response {
 
 /// Cancel the current migration.
 pub fn cancel_migrate() -> Response {
+    if !MigrationManager::is_active() {
+        return Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("No migration is in progress".to_string()),
+            None,
+        );
+    }
+
     if let Err(e) = MigrationManager::set_status(MigrationStatus::Canceled) {
         return Response::create_error_response(
             qmp_schema::QmpErrorClass::GenericError(e.to_string()),
@@ -35,6 +35,7 @@ pub(crate) static MIGRATION_MANAGER: Lazy<MigrationManager> = Lazy::new(|| Migra
     status: Arc::new(RwLock::new(MigrationStatus::None)),
     vmm_bitmaps: Arc::new(RwLock::new(HashMap::new())),
     limit: Arc::new(RwLock::new(MigrationLimit::default())),
+    progress: Arc::new(RwLock::new(MigrationProgress::default())),
 });
 
 /// A hook for `Device` to save device state to `Write` object and load device
@@ -199,6 +200,34 @@ impl Default for MigrationLimit {
     }
 }
 
+/// Progress of the current migration.
+#[derive(Default)]
+pub struct MigrationProgress {
+    /// Start time of the migration.
+    pub start_time: Option<Instant>,
+    /// Elapsed milliseconds of a finished migration.
+    pub total_time: Option<u64>,
+    /// Size of the guest memory in bytes.
+    pub total: u64,
+    /// Memory bytes transferred so far.
+    pub transferred: u64,
+    /// Dirty memory bytes found by the last synchronization and not sent yet.
+    pub remaining: u64,
+    /// Number of dirty memory synchronizations.
+    pub dirty_sync_count: u64,
+}
+
+impl MigrationProgress {
+    /// Milliseconds elapsed since the migration started.
+    pub fn elapsed_time(&self) -> u64 {
+        match (self.total_time, self.start_time) {
+            (Some(total_time), _) => total_time,
+            (None, Some(start_time)) => start_time.elapsed().as_millis() as u64,
+            (None, None) => 0,
+        }
+    }
+}
+
 /// This structure is to manage all resource during migration.
 /// It is also the only way to call on `MIGRATION_MANAGER`.
 pub struct MigrationManager {
@@ -212,6 +241,8 @@ pub struct MigrationManager {
     pub vmm_bitmaps: Arc<RwLock<HashMap<u32, DirtyBitmap>>>,
     /// Limiting elements of migration.
     pub limit: Arc<RwLock<MigrationLimit>>,
+    /// Progress of migration.
+    pub progress: Arc<RwLock<MigrationProgress>>,
 }
 
 impl MigrationManager {
@@ -28,6 +28,9 @@ use hypervisor::kvm::KVM_FDS;
 use machine_manager::config::{get_pci_bdf, PciBdf, VmConfig};
 use util::unix::host_page_size;
 
+/// Size of the memory blocks used to send the entire VM memory.
+const MEMORY_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
 impl MigrationManager {
     /// Start VM live migration at source VM.
     ///
@@ -67,9 +70,10 @@ impl MigrationManager {
 
         // Check whether the migration is canceled.
         if Self::is_canceled() {
-            // Cancel the migration of source and destination.
+            // Cancel the migration of source and destination. The VM is not
+            // paused yet, so recovering only stops logging dirty pages.
             Self::cancel_migration(fd).with_context(|| "Failed to cancel migration")?;
-            return Ok(());
+            return Self::recover_from_migration();
         }
 
         // Pause virtual machine.
@@ -78,6 +82,12 @@ impl MigrationManager {
         // Send remaining virtual machine dirty memory.
         Self::send_dirty_memory(fd).with_context(|| "Failed to send dirty memory")?;
 
+        // The migration can still be canceled until the vm state is sent.
+        if Self::is_canceled() {
+            Self::cancel_migration(fd).with_context(|| "Failed to cancel migration")?;
+            return Self::recover_from_migration();
+        }
+
         // Stop logging dirty pages.
         Self::stop_dirty_log().with_context(|| "Failed to stop logging dirty page")?;
 
@@ -339,6 +349,8 @@ impl MigrationManager {
                 )?;
             }
         }
+        MIGRATION_MANAGER.progress.write().unwrap().transferred +=
+            blocks.iter().map(|block| block.len).sum::<u64>();
 
         Response::send_msg(fd, TransStatus::Ok)?;
 
@@ -378,11 +390,21 @@ impl MigrationManager {
             return Err(anyhow!(MigrationError::ResponseErr));
         }
 
+        let sent = blocks.iter().map(|block| block.len).sum::<u64>();
+        let mut progress = MIGRATION_MANAGER.progress.write().unwrap();
+        progress.transferred += sent;
+        progress.remaining = progress.remaining.saturating_sub(sent);
+
         Ok(())
     }
 
     /// Send entire VM memory data to destination VM.
     ///
+    /// # Notes
+    ///
+    /// Memory is sent in chunks of `MEMORY_CHUNK_SIZE`, so that a canceled
+    /// migration stops without waiting for the whole guest memory.
+    ///
     /// # Arguments
     ///
     /// * `fd` - The fd implements `Read` and `Write` trait object.
@@ -393,13 +415,30 @@ impl MigrationManager {
         let mut blocks: Vec<MemBlock> = Vec::new();
         let slots = KVM_FDS.load().get_mem_slots();
         for (_, slot) in slots.lock().unwrap().iter() {
-            blocks.push(MemBlock {
-                gpa: slot.guest_phys_addr,
-                len: slot.memory_size,
-            });
+            let mut offset = 0;
+            while offset < slot.memory_size {
+                let len = std::cmp::min(MEMORY_CHUNK_SIZE, slot.memory_size - offset);
+                blocks.push(MemBlock {
+                    gpa: slot.guest_phys_addr + offset,
+                    len,
+                });
+                offset += len;
+            }
         }
 
-        Self::send_memory(fd, blocks)?;
+        let total = blocks.iter().map(|block| block.len).sum::<u64>();
+        {
+            let mut progress = MIGRATION_MANAGER.progress.write().unwrap();
+            progress.total = total;
+            progress.remaining = total;
+        }
+
+        for block in blocks {
+            if !Self::is_active() {
+                break;
+            }
+            Self::send_memory(fd, vec![block])?;
+        }
 
         Ok(())
     }
@@ -420,6 +459,12 @@ impl MigrationManager {
             blocks.extend(sub_blocks);
         }
 
+        {
+            let mut progress = MIGRATION_MANAGER.progress.write().unwrap();
+            progress.dirty_sync_count += 1;
+            progress.remaining = blocks.iter().map(|block| block.len).sum::<u64>();
+        }
+
         if blocks.is_empty() {
             return Ok(false);
         }
@@ -563,12 +608,15 @@ impl MigrationManager {
         Ok(())
     }
 
-    /// Recover the virtual machine if migration is failed.
+    /// Recover the virtual machine if migration is failed or canceled.
     pub fn recover_from_migration() -> Result<()> {
         if let Some(locked_vm) = &MIGRATION_MANAGER.vmm.read().unwrap().vm {
             locked_vm.lock().unwrap().resume();
         }
 
+        // Dirty page tracking slows down the guest, stop it if it is still on.
+        Self::stop_dirty_log().with_context(|| "Failed to stop logging dirty page")?;
+
         Ok(())
     }
 }
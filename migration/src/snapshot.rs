@@ -27,6 +27,9 @@ pub const GICV3_SNAPSHOT_ID: &str = "gicv3";
 pub const GICV3_ITS_SNAPSHOT_ID: &str = "gicv3_its";
 pub const PL011_SNAPSHOT_ID: &str = "pl011";
 pub const PL031_SNAPSHOT_ID: &str = "pl031";
+pub const PLIC_SNAPSHOT_ID: &str = "plic";
+pub const APLIC_SNAPSHOT_ID: &str = "aplic";
+pub const AIA_SNAPSHOT_ID: &str = "aia";
 
 /// The suffix used for snapshot memory storage.
 const MEMORY_PATH_SUFFIX: &str = "memory";
@@ -205,6 +208,15 @@ impl MigrationManager {
                 .with_context(|| "Failed to save device state")?;
         }
 
+        // Save transports state.
+        for (id, transport) in locked_vmm.transports.iter() {
+            transport
+                .lock()
+                .unwrap()
+                .save_device(*id, fd)
+                .with_context(|| "Failed to save transport state")?;
+        }
+
         #[cfg(target_arch = "aarch64")]
         {
             // Save GICv3 device state.
@@ -267,6 +279,18 @@ impl MigrationManager {
             }
         }
 
+        // Restore transports state.
+        for _ in 0..locked_vmm.transports.len() {
+            let (transport_data, id) = Self::check_vm_state(fd, &snap_desc_db)?;
+            if let Some(transport) = locked_vmm.transports.get(&id) {
+                transport
+                    .lock()
+                    .unwrap()
+                    .restore_mut_device(&transport_data)
+                    .with_context(|| "Failed to restore transport state")?;
+            }
+        }
+
         #[cfg(target_arch = "aarch64")]
         {
             // Restore GIC group state.
@@ -0,0 +1,171 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use address_space::Region;
+use anyhow::{bail, Context, Result};
+use util::num_ops::round_up;
+
+use crate::config::{BAR_0, BAR_NUM_MAX_FOR_ENDPOINT, COMMAND, COMMAND_MEMORY_SPACE};
+use crate::{PciDevOps, PciError, PciIntxState};
+
+const BAR_IO_SPACE: u32 = 0x1;
+const BAR_MEM_TYPE_MASK: u32 = 0x6;
+const BAR_MEM_64BIT: u32 = 0x4;
+const BAR_MEM_ADDR_MASK: u64 = !0xf;
+
+/// PCI bus, the functions on it are indexed by their device/function number.
+pub struct PciBus {
+    /// Name of the bus.
+    pub name: String,
+    /// Functions attached to the bus.
+    pub devices: BTreeMap<u8, Arc<Mutex<dyn PciDevOps>>>,
+    /// Memory region the BARs of the functions are mapped into.
+    pub mem_region: Region,
+    /// Legacy interrupt lines the functions are routed to.
+    pub intx_state: Option<Arc<Mutex<PciIntxState>>>,
+}
+
+impl PciBus {
+    /// Construct a new `PciBus`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the bus.
+    /// * `mem_region` - Memory region the BARs of the functions are mapped into.
+    /// * `intx_state` - Legacy interrupt lines the functions are routed to.
+    pub fn new(
+        name: String,
+        mem_region: Region,
+        intx_state: Option<Arc<Mutex<PciIntxState>>>,
+    ) -> Self {
+        PciBus {
+            name,
+            devices: BTreeMap::new(),
+            mem_region,
+            intx_state,
+        }
+    }
+
+    /// Get the function `devfn` of the bus.
+    pub fn get_device(&self, devfn: u8) -> Option<Arc<Mutex<dyn PciDevOps>>> {
+        self.devices.get(&devfn).cloned()
+    }
+
+    /// Attach a function to the bus.
+    ///
+    /// # Arguments
+    ///
+    /// * `devfn` - Device/function number of the function.
+    /// * `dev` - The function.
+    pub fn attach_device(&mut self, devfn: u8, dev: Arc<Mutex<dyn PciDevOps>>) -> Result<()> {
+        if self.devices.contains_key(&devfn) {
+            bail!(PciError::DevfnInUse(devfn, self.name.clone()));
+        }
+        self.devices.insert(devfn, dev);
+        Ok(())
+    }
+
+    /// Reset all the functions of the bus.
+    pub fn reset(bus: &Arc<Mutex<PciBus>>) -> Result<()> {
+        // The functions lock their parent bus when they unmap their BARs.
+        let devices: Vec<_> = bus.lock().unwrap().devices.values().cloned().collect();
+        for dev in devices {
+            let mut locked_dev = dev.lock().unwrap();
+            let name = locked_dev.name();
+            locked_dev
+                .reset()
+                .with_context(|| format!("Failed to reset PCI device {}", name))?;
+        }
+        Ok(())
+    }
+
+    /// Program the memory BARs of all the functions of the bus and enable
+    /// their memory decoding, as firmware does before booting the guest.
+    /// The BARs are sized through the configuration space and are packed
+    /// from the largest to the smallest to keep them naturally aligned.
+    ///
+    /// # Arguments
+    ///
+    /// * `bus` - The bus.
+    /// * `window` - Base and size of the MMIO window of the host bridge.
+    pub fn assign_bars(bus: &Arc<Mutex<PciBus>>, window: (u64, u64)) -> Result<()> {
+        let devices: Vec<_> = bus.lock().unwrap().devices.values().cloned().collect();
+
+        let mut bars = Vec::new();
+        for dev in devices.iter() {
+            let mut locked_dev = dev.lock().unwrap();
+            let mut id = 0;
+            while id < BAR_NUM_MAX_FOR_ENDPOINT as usize {
+                let (size, is_64bit) = size_bar(&mut *locked_dev, id);
+                if size != 0 {
+                    bars.push((dev.clone(), id, size, is_64bit));
+                }
+                id += if is_64bit { 2 } else { 1 };
+            }
+        }
+        bars.sort_by(|a, b| b.2.cmp(&a.2));
+
+        let window_end = window.0 + window.1;
+        let mut next_addr = window.0;
+        for (dev, id, size, is_64bit) in bars.iter() {
+            let addr = round_up(next_addr, *size)
+                .filter(|addr| addr + size <= window_end)
+                .ok_or(PciError::BarSpaceExhausted(*size))?;
+            let offset = BAR_0 + id * 4;
+            let mut locked_dev = dev.lock().unwrap();
+            locked_dev.write_config(offset, &(addr as u32).to_le_bytes());
+            if *is_64bit {
+                locked_dev.write_config(offset + 4, &((addr >> 32) as u32).to_le_bytes());
+            }
+            next_addr = addr + size;
+        }
+
+        for dev in devices.iter() {
+            let mut locked_dev = dev.lock().unwrap();
+            let mut command = [0_u8; 2];
+            locked_dev.read_config(COMMAND, &mut command);
+            let command = u16::from_le_bytes(command) | COMMAND_MEMORY_SPACE;
+            locked_dev.write_config(COMMAND, &command.to_le_bytes());
+        }
+        Ok(())
+    }
+}
+
+// Size the BAR `id` of a function by writing all ones to it, returns the size
+// of the BAR, 0 if it isn't implemented or decodes I/O space, and whether it
+// is a 64-bit BAR.
+fn size_bar(dev: &mut dyn PciDevOps, id: usize) -> (u64, bool) {
+    let offset = BAR_0 + id * 4;
+    let read_reg = |dev: &mut dyn PciDevOps, offset: usize| -> u32 {
+        let mut data = [0_u8; 4];
+        dev.read_config(offset, &mut data);
+        u32::from_le_bytes(data)
+    };
+
+    dev.write_config(offset, &u32::MAX.to_le_bytes());
+    let low = read_reg(dev, offset);
+    if low == 0 || low & BAR_IO_SPACE != 0 {
+        return (0, false);
+    }
+    let is_64bit = low & BAR_MEM_TYPE_MASK == BAR_MEM_64BIT;
+    let mask = if is_64bit {
+        dev.write_config(offset + 4, &u32::MAX.to_le_bytes());
+        (u64::from(read_reg(dev, offset + 4)) << 32) | u64::from(low)
+    } else {
+        u64::from(low) | 0xffff_ffff_0000_0000
+    };
+    ((!(mask & BAR_MEM_ADDR_MASK)).wrapping_add(1), is_64bit)
+}
@@ -0,0 +1,642 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use address_space::Region;
+use anyhow::{bail, Context, Result};
+use log::error;
+
+use crate::intx::Intx;
+use crate::msix::{Msix, MSIX_CAP_SIZE};
+use crate::{
+    le_read_u16, le_read_u32, le_read_u64, le_write_u16, le_write_u32, le_write_u64,
+    ranges_overlap, PciError,
+};
+
+/// Size in bytes of the configuration space of a PCI function.
+pub const PCI_CONFIG_SPACE_SIZE: usize = 256;
+/// Size in bytes of the configuration space of a PCIe function.
+pub const PCIE_CONFIG_SPACE_SIZE: usize = 4096;
+/// End of the standard header, capabilities are placed after it.
+pub const PCI_CONFIG_HEAD_END: usize = 64;
+
+const REG_SIZE: usize = 4;
+
+// Registers of the standard header.
+pub const VENDOR_ID: usize = 0x00;
+pub const DEVICE_ID: usize = 0x02;
+pub const COMMAND: usize = 0x04;
+pub const STATUS: usize = 0x06;
+pub const REVISION_ID: usize = 0x08;
+pub const CLASS_PI: usize = 0x09;
+pub const SUB_CLASS_CODE: usize = 0x0a;
+pub const CACHE_LINE_SIZE: usize = 0x0c;
+pub const HEADER_TYPE: usize = 0x0e;
+pub const BAR_0: usize = 0x10;
+pub const SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+pub const SUBSYSTEM_ID: usize = 0x2e;
+pub const CAP_LIST: usize = 0x34;
+pub const INTERRUPT_LINE: usize = 0x3c;
+pub const INTERRUPT_PIN: usize = 0x3d;
+
+// Bits of the command register.
+pub const COMMAND_IO_SPACE: u16 = 0x0001;
+pub const COMMAND_MEMORY_SPACE: u16 = 0x0002;
+pub const COMMAND_BUS_MASTER: u16 = 0x0004;
+pub const COMMAND_SERR_ENABLE: u16 = 0x0100;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 0x0400;
+
+// Bits of the status register.
+pub const STATUS_INTERRUPT: u16 = 0x0008;
+pub const STATUS_CAP_LIST: u16 = 0x0010;
+const STATUS_MASTER_PARITY_ERROR: u16 = 0x0100;
+const STATUS_SIG_TARGET_ABORT: u16 = 0x0800;
+const STATUS_RECV_TARGET_ABORT: u16 = 0x1000;
+const STATUS_RECV_MASTER_ABORT: u16 = 0x2000;
+const STATUS_SIG_SYS_ERROR: u16 = 0x4000;
+const STATUS_DETECT_PARITY_ERROR: u16 = 0x8000;
+
+// Values of the header type register.
+pub const HEADER_TYPE_ENDPOINT: u8 = 0x00;
+pub const HEADER_TYPE_BRIDGE: u8 = 0x01;
+pub const HEADER_TYPE_MULTIFUNC: u8 = 0x80;
+
+// Capability IDs.
+pub const CAP_ID_VNDR: u8 = 0x09;
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+/// Value of the interrupt pin register for INTA#.
+pub const INTERRUPT_PIN_INTA: u8 = 0x01;
+
+/// Class code of a host bridge.
+pub const CLASS_CODE_HOST_BRIDGE: u16 = 0x0600;
+
+/// Number of BARs of a type 0 header.
+pub const BAR_NUM_MAX_FOR_ENDPOINT: u8 = 6;
+/// Address of a BAR which isn't decoded.
+pub const BAR_SPACE_UNMAPPED: u64 = u64::MAX;
+/// Smallest memory BAR allowed by the PCI specification.
+const MIN_BAR_SIZE: u64 = 16;
+
+const BAR_MEM_64BIT: u8 = 0x04;
+const BAR_PREFETCH: u8 = 0x08;
+const BAR_MEM_ADDR_MASK: u64 = !0xf;
+
+/// Type of the space decoded by a BAR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionType {
+    Mem32Bit,
+    Mem64Bit,
+}
+
+/// Base address register of a function.
+#[derive(Clone, Debug)]
+pub struct Bar {
+    region_type: RegionType,
+    prefetchable: bool,
+    /// Address the region is mapped at, `BAR_SPACE_UNMAPPED` if it isn't.
+    address: u64,
+    /// Size of the region, 0 if the BAR isn't implemented.
+    pub size: u64,
+    region: Option<Region>,
+}
+
+impl Default for Bar {
+    fn default() -> Self {
+        Bar {
+            region_type: RegionType::Mem32Bit,
+            prefetchable: false,
+            address: BAR_SPACE_UNMAPPED,
+            size: 0,
+            region: None,
+        }
+    }
+}
+
+/// Configuration space of a PCI function.
+pub struct PciConfig {
+    /// Content of the configuration space.
+    pub config: Vec<u8>,
+    /// Bits which are writable by the guest.
+    pub write_mask: Vec<u8>,
+    /// Bits which are cleared when the guest writes 1 to them.
+    pub write_clear_mask: Vec<u8>,
+    /// Base address registers.
+    pub bars: Vec<Bar>,
+    /// Legacy interrupt pin of the function, if it has one.
+    pub intx: Option<Arc<Mutex<Intx>>>,
+    /// MSI-X state of the function, if it has the capability.
+    pub msix: Option<Arc<Mutex<Msix>>>,
+    /// Offset the next capability is placed at.
+    next_cap_offset: usize,
+}
+
+impl PciConfig {
+    /// Construct a new `PciConfig`.
+    ///
+    /// # Arguments
+    ///
+    /// * `config_size` - Size of the configuration space.
+    /// * `nr_bar` - Number of BARs of the header.
+    pub fn new(config_size: usize, nr_bar: u8) -> Self {
+        PciConfig {
+            config: vec![0; config_size],
+            write_mask: vec![0; config_size],
+            write_clear_mask: vec![0; config_size],
+            bars: vec![Bar::default(); nr_bar as usize],
+            intx: None,
+            msix: None,
+            next_cap_offset: PCI_CONFIG_HEAD_END,
+        }
+    }
+
+    /// Init the writable bits shared by all functions.
+    pub fn init_common_write_mask(&mut self) -> Result<()> {
+        self.write_mask[CACHE_LINE_SIZE] = 0xff;
+        self.write_mask[INTERRUPT_LINE] = 0xff;
+        le_write_u16(
+            &mut self.write_mask,
+            COMMAND,
+            COMMAND_MEMORY_SPACE
+                | COMMAND_BUS_MASTER
+                | COMMAND_SERR_ENABLE
+                | COMMAND_INTERRUPT_DISABLE,
+        )
+    }
+
+    /// Init the write-1-to-clear bits shared by all functions.
+    pub fn init_common_write_clear_mask(&mut self) -> Result<()> {
+        le_write_u16(
+            &mut self.write_clear_mask,
+            STATUS,
+            STATUS_MASTER_PARITY_ERROR
+                | STATUS_SIG_TARGET_ABORT
+                | STATUS_RECV_TARGET_ABORT
+                | STATUS_RECV_MASTER_ABORT
+                | STATUS_SIG_SYS_ERROR
+                | STATUS_DETECT_PARITY_ERROR,
+        )
+    }
+
+    /// Read the configuration space, accesses beyond its end read as zero.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset in the configuration space.
+    /// * `buf` - Buffer the data is read into.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        let size = buf.len();
+        if offset + size > self.config.len() {
+            error!(
+                "Failed to read config space: offset 0x{:x}, size {}",
+                offset, size
+            );
+            buf.fill(0);
+            return;
+        }
+        buf.copy_from_slice(&self.config[offset..offset + size]);
+
+        // The interrupt status follows the pin, which the function may drive
+        // without holding its configuration space.
+        if let Some(intx) = &self.intx {
+            if ranges_overlap(offset, size, STATUS, 1) && intx.lock().unwrap().level() == 1 {
+                buf[STATUS - offset] |= STATUS_INTERRUPT as u8;
+            }
+        }
+    }
+
+    /// Write the configuration space according to the write masks, then
+    /// remap the BARs if the guest touched them or the memory decoding.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset in the configuration space.
+    /// * `data` - Data to write.
+    /// * `mem_region` - Memory region the BARs are mapped into.
+    pub fn write(&mut self, offset: usize, data: &[u8], mem_region: Option<&Region>) {
+        let size = data.len();
+        if offset + size > self.config.len() {
+            error!(
+                "Failed to write config space: offset 0x{:x}, size {}",
+                offset, size
+            );
+            return;
+        }
+
+        for (i, byte) in data.iter().enumerate() {
+            let wmask = self.write_mask[offset + i];
+            let cmask = self.write_clear_mask[offset + i];
+            let old = self.config[offset + i];
+            self.config[offset + i] = ((old & !wmask) | (byte & wmask)) & !(byte & cmask);
+        }
+
+        if ranges_overlap(offset, size, COMMAND, 2) {
+            self.update_intx();
+        }
+        if let Some(msix) = &self.msix {
+            let mut locked_msix = msix.lock().unwrap();
+            if ranges_overlap(offset, size, locked_msix.cap_offset(), MSIX_CAP_SIZE) {
+                locked_msix.write_config(&self.config);
+            }
+        }
+        if let Some(region) = mem_region {
+            if ranges_overlap(offset, size, COMMAND, 1)
+                || ranges_overlap(offset, size, BAR_0, REG_SIZE * self.bars.len())
+            {
+                if let Err(e) = self.update_bar_mapping(region) {
+                    error!("Failed to update BAR mapping: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Reset the registers shared by all functions: decoding and bus
+    /// mastering are disabled, which unmaps the BARs, INTx is deasserted and
+    /// MSI-X is disabled with all its vectors masked.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_region` - Memory region the BARs are mapped into.
+    pub fn reset_common_regs(&mut self, mem_region: Option<&Region>) -> Result<()> {
+        let command = le_read_u16(&self.config, COMMAND)?;
+        let command_mask = le_read_u16(&self.write_mask, COMMAND)?;
+        le_write_u16(&mut self.config, COMMAND, command & !command_mask)?;
+        let status = le_read_u16(&self.config, STATUS)?;
+        let status_mask = le_read_u16(&self.write_clear_mask, STATUS)?;
+        le_write_u16(
+            &mut self.config,
+            STATUS,
+            status & !(status_mask | STATUS_INTERRUPT),
+        )?;
+        self.config[CACHE_LINE_SIZE] = 0;
+        self.config[INTERRUPT_LINE] = 0;
+
+        for id in 0..self.bars.len() {
+            if self.bars[id].size != 0 {
+                self.write_bar_flags(id)?;
+            }
+        }
+        if let Some(region) = mem_region {
+            self.update_bar_mapping(region)?;
+        }
+        if let Some(intx) = &self.intx {
+            intx.lock().unwrap().notify(0);
+        }
+        self.update_intx();
+        if let Some(msix) = &self.msix {
+            msix.lock().unwrap().reset(&mut self.config)?;
+        }
+        Ok(())
+    }
+
+    /// Add a capability at the head of the capability list.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Capability ID.
+    /// * `size` - Size of the capability, including its ID and next pointer.
+    ///
+    /// Returns the offset of the capability in the configuration space.
+    pub fn add_pci_cap(&mut self, id: u8, size: usize) -> Result<usize> {
+        let offset = self.next_cap_offset;
+        // Capabilities live in the first 256 bytes, the extended ones after
+        // them aren't supported.
+        if offset + size > PCI_CONFIG_SPACE_SIZE.min(self.config.len()) {
+            bail!(PciError::CapSpaceExhausted(id));
+        }
+
+        self.config[offset] = id;
+        self.config[offset + 1] = self.config[CAP_LIST];
+        self.config[CAP_LIST] = offset as u8;
+        let status = le_read_u16(&self.config, STATUS)?;
+        le_write_u16(&mut self.config, STATUS, status | STATUS_CAP_LIST)?;
+        self.next_cap_offset = (offset + size + REG_SIZE - 1) & !(REG_SIZE - 1);
+        Ok(offset)
+    }
+
+    /// Register a memory BAR, it is mapped once the guest programs its
+    /// address and enables memory decoding.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Index of the BAR, a 64-bit BAR also takes `id` + 1.
+    /// * `region` - Region decoded by the BAR.
+    /// * `region_type` - Whether the BAR is 32-bit or 64-bit wide.
+    /// * `prefetchable` - Whether the region is prefetchable.
+    /// * `size` - Size of the region, a power of 2.
+    pub fn register_bar(
+        &mut self,
+        id: usize,
+        region: Region,
+        region_type: RegionType,
+        prefetchable: bool,
+        size: u64,
+    ) -> Result<()> {
+        let nr_regs = match region_type {
+            RegionType::Mem32Bit => 1,
+            RegionType::Mem64Bit => 2,
+        };
+        if id + nr_regs > self.bars.len() {
+            bail!(PciError::InvalidBarId(id, self.bars.len()));
+        }
+        if size < MIN_BAR_SIZE || !size.is_power_of_two() {
+            bail!(PciError::InvalidBarSize(id, size));
+        }
+        if region_type == RegionType::Mem32Bit && size > u64::from(u32::MAX) {
+            bail!(PciError::InvalidBarSize(id, size));
+        }
+
+        self.bars[id] = Bar {
+            region_type,
+            prefetchable,
+            address: BAR_SPACE_UNMAPPED,
+            size,
+            region: Some(region),
+        };
+        let offset = BAR_0 + id * REG_SIZE;
+        match region_type {
+            RegionType::Mem32Bit => {
+                le_write_u32(&mut self.write_mask, offset, !(size - 1) as u32)?;
+            }
+            RegionType::Mem64Bit => {
+                le_write_u64(&mut self.write_mask, offset, !(size - 1))?;
+            }
+        }
+        self.write_bar_flags(id)
+    }
+
+    /// Address the BAR is decoded at, `BAR_SPACE_UNMAPPED` if memory
+    /// decoding is disabled or the BAR isn't programmed.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Index of the BAR.
+    pub fn get_bar_address(&self, id: usize) -> u64 {
+        let bar = &self.bars[id];
+        let command = le_read_u16(&self.config, COMMAND).unwrap();
+        if bar.size == 0 || command & COMMAND_MEMORY_SPACE == 0 {
+            return BAR_SPACE_UNMAPPED;
+        }
+
+        let offset = BAR_0 + id * REG_SIZE;
+        let (address, sizing_pattern) = match bar.region_type {
+            RegionType::Mem32Bit => (
+                u64::from(le_read_u32(&self.config, offset).unwrap()),
+                u64::from(!(bar.size - 1) as u32),
+            ),
+            RegionType::Mem64Bit => (le_read_u64(&self.config, offset).unwrap(), !(bar.size - 1)),
+        };
+        let address = address & BAR_MEM_ADDR_MASK;
+        // The guest writes all ones to a BAR to find out its size, the
+        // resulting address is never meant to be decoded.
+        if address == 0 || address == sizing_pattern {
+            return BAR_SPACE_UNMAPPED;
+        }
+        address
+    }
+
+    /// Map every BAR at the address programmed by the guest, or unmap it.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_region` - Memory region the BARs are mapped into.
+    pub fn update_bar_mapping(&mut self, mem_region: &Region) -> Result<()> {
+        for id in 0..self.bars.len() {
+            if self.bars[id].size == 0 {
+                continue;
+            }
+            let new_address = self.get_bar_address(id);
+            if self.bars[id].address == new_address {
+                continue;
+            }
+
+            let region = self.bars[id].region.clone().unwrap();
+            if self.bars[id].address != BAR_SPACE_UNMAPPED {
+                mem_region
+                    .delete_subregion(&region)
+                    .with_context(|| format!("Failed to unmap BAR {}", id))?;
+                self.bars[id].address = BAR_SPACE_UNMAPPED;
+            }
+            if new_address != BAR_SPACE_UNMAPPED {
+                mem_region
+                    .add_subregion(region, new_address)
+                    .with_context(|| format!("Failed to map BAR {} at 0x{:x}", id, new_address))?;
+                self.bars[id].address = new_address;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the level of the INTx pin, the interrupt is delivered unless the
+    /// guest disabled it in the command register.
+    ///
+    /// # Arguments
+    ///
+    /// * `level` - 1 to assert the pin, 0 to deassert it.
+    pub fn set_intx_level(&self, level: u8) {
+        if let Some(intx) = &self.intx {
+            intx.lock().unwrap().notify(level);
+        }
+    }
+
+    fn update_intx(&mut self) {
+        let command = le_read_u16(&self.config, COMMAND).unwrap();
+        if let Some(intx) = &self.intx {
+            intx.lock()
+                .unwrap()
+                .set_disabled(command & COMMAND_INTERRUPT_DISABLE != 0);
+        }
+    }
+
+    fn write_bar_flags(&mut self, id: usize) -> Result<()> {
+        let bar = &self.bars[id];
+        let mut flags: u8 = 0;
+        if bar.region_type == RegionType::Mem64Bit {
+            flags |= BAR_MEM_64BIT;
+        }
+        if bar.prefetchable {
+            flags |= BAR_PREFETCH;
+        }
+
+        let offset = BAR_0 + id * REG_SIZE;
+        match bar.region_type {
+            RegionType::Mem32Bit => le_write_u32(&mut self.config, offset, u32::from(flags)),
+            RegionType::Mem64Bit => le_write_u64(&mut self.config, offset, u64::from(flags)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use address_space::{GuestAddress, RegionOps};
+    use std::sync::Arc;
+
+    fn test_region(size: u64) -> Region {
+        let ops = RegionOps {
+            read: Arc::new(|_: &mut [u8], _: GuestAddress, _: u64| -> bool { true }),
+            write: Arc::new(|_: &[u8], _: GuestAddress, _: u64| -> bool { true }),
+        };
+        Region::init_io_region(size, ops)
+    }
+
+    fn write_u32_config(config: &mut PciConfig, offset: usize, value: u32, mem: &Region) {
+        config.write(offset, &value.to_le_bytes(), Some(mem));
+    }
+
+    #[test]
+    fn test_write_mask() {
+        let mut config = PciConfig::new(PCI_CONFIG_SPACE_SIZE, BAR_NUM_MAX_FOR_ENDPOINT);
+        config.init_common_write_mask().unwrap();
+        config.init_common_write_clear_mask().unwrap();
+        le_write_u16(&mut config.config, VENDOR_ID, 0x1b36).unwrap();
+
+        // Read-only registers are left untouched.
+        config.write(VENDOR_ID, &[0xff, 0xff], None);
+        assert_eq!(le_read_u16(&config.config, VENDOR_ID).unwrap(), 0x1b36);
+
+        // Only the writable bits of the command register are set.
+        config.write(COMMAND, &[0xff, 0xff], None);
+        assert_eq!(
+            le_read_u16(&config.config, COMMAND).unwrap(),
+            COMMAND_MEMORY_SPACE
+                | COMMAND_BUS_MASTER
+                | COMMAND_SERR_ENABLE
+                | COMMAND_INTERRUPT_DISABLE
+        );
+
+        // Error bits of the status register are write-1-to-clear.
+        le_write_u16(
+            &mut config.config,
+            STATUS,
+            STATUS_CAP_LIST | STATUS_RECV_MASTER_ABORT | STATUS_SIG_SYS_ERROR,
+        )
+        .unwrap();
+        config.write(STATUS, &STATUS_SIG_SYS_ERROR.to_le_bytes(), None);
+        assert_eq!(
+            le_read_u16(&config.config, STATUS).unwrap(),
+            STATUS_CAP_LIST | STATUS_RECV_MASTER_ABORT
+        );
+
+        // Out of range accesses are ignored.
+        config.write(PCI_CONFIG_SPACE_SIZE - 2, &[0xff; 4], None);
+        let mut buf = [0xff_u8; 4];
+        config.read(PCI_CONFIG_SPACE_SIZE - 2, &mut buf);
+        assert_eq!(buf, [0; 4]);
+    }
+
+    #[test]
+    fn test_add_pci_cap() {
+        let mut config = PciConfig::new(PCIE_CONFIG_SPACE_SIZE, BAR_NUM_MAX_FOR_ENDPOINT);
+        assert_eq!(
+            config.add_pci_cap(CAP_ID_VNDR, 14).unwrap(),
+            PCI_CONFIG_HEAD_END
+        );
+        // The next capability starts on a dword boundary.
+        assert_eq!(config.add_pci_cap(CAP_ID_MSIX, 12).unwrap(), 0x50);
+        assert_eq!(config.config[CAP_LIST], 0x50);
+        assert_eq!(config.config[0x50], CAP_ID_MSIX);
+        assert_eq!(config.config[0x51], PCI_CONFIG_HEAD_END as u8);
+        assert_eq!(config.config[PCI_CONFIG_HEAD_END], CAP_ID_VNDR);
+        assert_eq!(config.config[PCI_CONFIG_HEAD_END + 1], 0);
+        assert_ne!(
+            le_read_u16(&config.config, STATUS).unwrap() & STATUS_CAP_LIST,
+            0
+        );
+
+        // Capabilities don't spill into the extended configuration space.
+        assert!(config.add_pci_cap(CAP_ID_VNDR, 0xa8).is_err());
+        assert_eq!(config.add_pci_cap(CAP_ID_VNDR, 0xa4).unwrap(), 0x5c);
+    }
+
+    #[test]
+    fn test_register_bar() {
+        let mut config = PciConfig::new(PCI_CONFIG_SPACE_SIZE, BAR_NUM_MAX_FOR_ENDPOINT);
+        assert!(config
+            .register_bar(0, test_region(0x1000), RegionType::Mem32Bit, false, 0x1000)
+            .is_ok());
+        assert!(config
+            .register_bar(2, test_region(0x4000), RegionType::Mem64Bit, true, 0x4000)
+            .is_ok());
+        assert_eq!(le_read_u32(&config.config, BAR_0).unwrap(), 0);
+        assert_eq!(
+            le_read_u32(&config.config, BAR_0 + 2 * REG_SIZE).unwrap(),
+            u32::from(BAR_MEM_64BIT | BAR_PREFETCH)
+        );
+
+        // A 64-bit BAR can't start at the last register.
+        assert!(config
+            .register_bar(5, test_region(0x1000), RegionType::Mem64Bit, false, 0x1000)
+            .is_err());
+        assert!(config
+            .register_bar(6, test_region(0x1000), RegionType::Mem32Bit, false, 0x1000)
+            .is_err());
+        assert!(config
+            .register_bar(1, test_region(0x1800), RegionType::Mem32Bit, false, 0x1800)
+            .is_err());
+        assert!(config
+            .register_bar(1, test_region(0x8), RegionType::Mem32Bit, false, 0x8)
+            .is_err());
+    }
+
+    #[test]
+    fn test_bar_mapping() {
+        let mem = Region::init_container_region(u64::max_value());
+        let mut config = PciConfig::new(PCI_CONFIG_SPACE_SIZE, BAR_NUM_MAX_FOR_ENDPOINT);
+        config.init_common_write_mask().unwrap();
+        let region32 = test_region(0x1000);
+        let region64 = test_region(0x4000);
+        config
+            .register_bar(0, region32.clone(), RegionType::Mem32Bit, false, 0x1000)
+            .unwrap();
+        config
+            .register_bar(2, region64.clone(), RegionType::Mem64Bit, true, 0x4000)
+            .unwrap();
+
+        // Sizing the BARs.
+        write_u32_config(&mut config, BAR_0, 0xffff_ffff, &mem);
+        assert_eq!(le_read_u32(&config.config, BAR_0).unwrap(), 0xffff_f000);
+        write_u32_config(&mut config, BAR_0 + 2 * REG_SIZE, 0xffff_ffff, &mem);
+        write_u32_config(&mut config, BAR_0 + 3 * REG_SIZE, 0xffff_ffff, &mem);
+        assert_eq!(
+            le_read_u64(&config.config, BAR_0 + 2 * REG_SIZE).unwrap(),
+            0xffff_ffff_ffff_c000 | u64::from(BAR_MEM_64BIT | BAR_PREFETCH)
+        );
+
+        // Nothing is decoded while memory decoding is disabled.
+        write_u32_config(&mut config, BAR_0, 0x4000_0000, &mem);
+        write_u32_config(&mut config, BAR_0 + 2 * REG_SIZE, 0x4001_0000, &mem);
+        write_u32_config(&mut config, BAR_0 + 3 * REG_SIZE, 0x1, &mem);
+        assert_eq!(config.get_bar_address(0), BAR_SPACE_UNMAPPED);
+        assert_eq!(config.bars[0].address, BAR_SPACE_UNMAPPED);
+
+        config.write(COMMAND, &COMMAND_MEMORY_SPACE.to_le_bytes(), Some(&mem));
+        assert_eq!(config.bars[0].address, 0x4000_0000);
+        assert_eq!(region32.offset(), GuestAddress(0x4000_0000));
+        assert_eq!(config.bars[2].address, 0x1_4001_0000);
+        assert_eq!(region64.offset(), GuestAddress(0x1_4001_0000));
+
+        // Moving a BAR remaps its region.
+        write_u32_config(&mut config, BAR_0, 0x5000_0000, &mem);
+        assert_eq!(config.bars[0].address, 0x5000_0000);
+        assert_eq!(region32.offset(), GuestAddress(0x5000_0000));
+
+        // Reset disables decoding and clears the addresses.
+        config.reset_common_regs(Some(&mem)).unwrap();
+        assert_eq!(config.bars[0].address, BAR_SPACE_UNMAPPED);
+        assert_eq!(config.bars[2].address, BAR_SPACE_UNMAPPED);
+        assert_eq!(le_read_u32(&config.config, BAR_0).unwrap(), 0);
+        assert!(mem.delete_subregion(&region32).is_err());
+    }
+}
@@ -0,0 +1,35 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PciError {
+    #[error("AddressSpace")]
+    AddressSpace {
+        #[from]
+        source: address_space::error::AddressSpaceError,
+    },
+    #[error("Invalid PCI configuration, key:{0}, value:{1}")]
+    InvalidConf(String, String),
+    #[error("BAR {0} is out of range, the function has {1} BARs")]
+    InvalidBarId(usize, usize),
+    #[error("Size 0x{1:x} of BAR {0} is not a power of 2 of at least 16 bytes")]
+    InvalidBarSize(usize, u64),
+    #[error("Devfn 0x{0:x} of bus {1} is already in use")]
+    DevfnInUse(u8, String),
+    #[error("No space left in the PCI MMIO window for a BAR of size 0x{0:x}")]
+    BarSpaceExhausted(u64),
+    #[error("No space left in the configuration space for capability 0x{0:x}")]
+    CapSpaceExhausted(u8),
+}
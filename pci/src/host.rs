@@ -0,0 +1,191 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex, Weak};
+
+use address_space::{GuestAddress, Region};
+use anyhow::Result;
+use devices::InterruptController;
+use sysbus::{SysBusDevOps, SysBusDevType, SysRes};
+
+use crate::config::{
+    PciConfig, CLASS_CODE_HOST_BRIDGE, DEVICE_ID, HEADER_TYPE, HEADER_TYPE_ENDPOINT,
+    PCIE_CONFIG_SPACE_SIZE, SUB_CLASS_CODE, VENDOR_ID,
+};
+use crate::{le_write_u16, pci_devfn, PciBus, PciDevOps, PciIntxState, PCI_INTX_NUM};
+
+const PCI_VENDOR_ID_REDHAT: u16 = 0x1b36;
+const PCI_DEVICE_ID_REDHAT_PCIE_HOST: u16 = 0x0008;
+
+// Layout of an ECAM address: bus[27:20], devfn[19:12], register[11:0].
+const ECAM_BUS_SHIFT: u64 = 20;
+const ECAM_DEVFN_SHIFT: u64 = 12;
+const ECAM_REG_MASK: u64 = 0xfff;
+
+/// Generic PCIe host bridge, the configuration space of the functions is
+/// accessed through its ECAM region.
+pub struct PciHost {
+    /// Root bus of the host bridge.
+    pub root_bus: Arc<Mutex<PciBus>>,
+    /// Number of buses decoded by the ECAM region.
+    nr_buses: u64,
+    /// ECAM region of the host bridge.
+    res: SysRes,
+}
+
+impl PciHost {
+    /// Construct a new `PciHost`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sys_mem` - Memory region the BARs are mapped into.
+    /// * `ecam_size` - Size of the ECAM region, 1MiB per bus.
+    /// * `intx_irqs` - Interrupt sources the INTA# to INTD# lines are wired to.
+    /// * `irq_chip` - Interrupt controller of the machine.
+    pub fn new(
+        sys_mem: &Region,
+        ecam_size: u64,
+        intx_irqs: [u32; PCI_INTX_NUM],
+        irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> Self {
+        let intx_state = Arc::new(Mutex::new(PciIntxState::new(intx_irqs, irq_chip)));
+        let root_bus = PciBus::new("pcie.0".to_string(), sys_mem.clone(), Some(intx_state));
+        PciHost {
+            root_bus: Arc::new(Mutex::new(root_bus)),
+            nr_buses: ecam_size >> ECAM_BUS_SHIFT,
+            res: SysRes::default(),
+        }
+    }
+
+    /// Number of buses decoded by the ECAM region.
+    pub fn nr_buses(&self) -> u64 {
+        self.nr_buses
+    }
+
+    /// Interrupt sources the INTA# to INTD# lines are wired to.
+    pub fn intx_irqs(&self) -> [u32; PCI_INTX_NUM] {
+        let root_bus = self.root_bus.lock().unwrap();
+        // The root bus is always created with its INTx state.
+        root_bus.intx_state.as_ref().unwrap().lock().unwrap().irqs()
+    }
+
+    /// Realize the host bridge function 00:00.0 of the root bus.
+    pub fn realize_host_bridge(&self) -> Result<()> {
+        PciHostBridge::new(Arc::downgrade(&self.root_bus)).realize()
+    }
+
+    fn find_device(&self, offset: u64) -> Option<Arc<Mutex<dyn PciDevOps>>> {
+        let bus_num = offset >> ECAM_BUS_SHIFT;
+        // There is no PCI bridge, all the functions are on the root bus.
+        if bus_num != 0 {
+            return None;
+        }
+        let devfn = ((offset >> ECAM_DEVFN_SHIFT) & 0xff) as u8;
+        self.root_bus.lock().unwrap().get_device(devfn)
+    }
+}
+
+impl SysBusDevOps for PciHost {
+    fn read(&mut self, data: &mut [u8], _base: GuestAddress, offset: u64) -> bool {
+        match self.find_device(offset) {
+            Some(dev) => {
+                let reg = (offset & ECAM_REG_MASK) as usize;
+                dev.lock().unwrap().read_config(reg, data);
+            }
+            // Reading a missing function returns all ones.
+            None => data.fill(0xff),
+        }
+        true
+    }
+
+    fn write(&mut self, data: &[u8], _base: GuestAddress, offset: u64) -> bool {
+        if let Some(dev) = self.find_device(offset) {
+            let reg = (offset & ECAM_REG_MASK) as usize;
+            dev.lock().unwrap().write_config(reg, data);
+        }
+        true
+    }
+
+    fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+        Some(&mut self.res)
+    }
+
+    fn get_type(&self) -> SysBusDevType {
+        SysBusDevType::PciHost
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        PciBus::reset(&self.root_bus)
+    }
+}
+
+/// The function 00:00.0 identifying the host bridge to the guest.
+struct PciHostBridge {
+    config: PciConfig,
+    parent_bus: Weak<Mutex<PciBus>>,
+}
+
+impl PciHostBridge {
+    fn new(parent_bus: Weak<Mutex<PciBus>>) -> Self {
+        PciHostBridge {
+            config: PciConfig::new(PCIE_CONFIG_SPACE_SIZE, 0),
+            parent_bus,
+        }
+    }
+}
+
+impl PciDevOps for PciHostBridge {
+    fn init_write_mask(&mut self) -> Result<()> {
+        self.config.init_common_write_mask()
+    }
+
+    fn init_write_clear_mask(&mut self) -> Result<()> {
+        self.config.init_common_write_clear_mask()
+    }
+
+    fn realize(mut self) -> Result<()> {
+        self.init_write_mask()?;
+        self.init_write_clear_mask()?;
+
+        let config = &mut self.config.config;
+        le_write_u16(config, VENDOR_ID, PCI_VENDOR_ID_REDHAT)?;
+        le_write_u16(config, DEVICE_ID, PCI_DEVICE_ID_REDHAT_PCIE_HOST)?;
+        le_write_u16(config, SUB_CLASS_CODE, CLASS_CODE_HOST_BRIDGE)?;
+        config[HEADER_TYPE] = HEADER_TYPE_ENDPOINT;
+
+        let parent_bus = self.parent_bus.upgrade().unwrap();
+        let devfn = pci_devfn(0, 0);
+        let dev = Arc::new(Mutex::new(self));
+        parent_bus.lock().unwrap().attach_device(devfn, dev)
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        self.config.read(offset, data);
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        self.config.write(offset, data, None);
+    }
+
+    fn name(&self) -> String {
+        "pcie-host-bridge".to_string()
+    }
+
+    fn devfn(&self) -> Option<u8> {
+        Some(pci_devfn(0, 0))
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.config.reset_common_regs(None)
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use devices::InterruptController;
+use log::error;
+
+use crate::pci_slot;
+
+/// Number of legacy interrupt lines of the host bridge, INTA# to INTD#.
+pub const PCI_INTX_NUM: usize = 4;
+
+/// The legacy interrupt lines of the host bridge. The pins of all functions
+/// are swizzled onto them, so a line stays raised while any function asserts it.
+pub struct PciIntxState {
+    /// Interrupt controller source of each line.
+    irqs: [u32; PCI_INTX_NUM],
+    /// Number of functions asserting each line.
+    asserted: [u32; PCI_INTX_NUM],
+    irq_chip: Arc<Mutex<InterruptController>>,
+}
+
+impl PciIntxState {
+    pub fn new(irqs: [u32; PCI_INTX_NUM], irq_chip: Arc<Mutex<InterruptController>>) -> Self {
+        PciIntxState {
+            irqs,
+            asserted: [0; PCI_INTX_NUM],
+            irq_chip,
+        }
+    }
+
+    /// Interrupt controller source of each line.
+    pub fn irqs(&self) -> [u32; PCI_INTX_NUM] {
+        self.irqs
+    }
+
+    fn change_level(&mut self, line: usize, asserted: bool) {
+        let old_count = self.asserted[line];
+        self.asserted[line] = if asserted {
+            old_count + 1
+        } else {
+            old_count.saturating_sub(1)
+        };
+
+        let level = match (old_count, self.asserted[line]) {
+            (0, 1) => 1,
+            (1, 0) => 0,
+            _ => return,
+        };
+        if let Err(e) = self
+            .irq_chip
+            .lock()
+            .unwrap()
+            .kvm_irq_line(self.irqs[line], level)
+        {
+            error!(
+                "Failed to set level {} of PCI INTx line {}: {:?}",
+                level, line, e
+            );
+        }
+    }
+}
+
+/// Host bridge line the INTx `pin` of the function `devfn` is swizzled onto.
+fn intx_line(devfn: u8, pin: u8) -> usize {
+    (pci_slot(devfn) as usize + pin as usize) % PCI_INTX_NUM
+}
+
+/// The INTx pin of a PCI function.
+pub struct Intx {
+    /// Host bridge line the pin is routed to.
+    line: usize,
+    /// Level of the pin as driven by the function.
+    level: u8,
+    /// Whether the guest disabled INTx in the command register.
+    disabled: bool,
+    /// Whether the pin currently raises its host bridge line.
+    asserted: bool,
+    state: Arc<Mutex<PciIntxState>>,
+}
+
+impl Intx {
+    /// Construct the INTx pin of a function on the root bus, routed to
+    /// line (slot + pin) % 4 as described in the device tree interrupt-map.
+    ///
+    /// # Arguments
+    ///
+    /// * `devfn` - Device/function number of the function.
+    /// * `pin` - Interrupt pin of the function, 0 for INTA#.
+    /// * `state` - Legacy interrupt lines of the host bridge.
+    pub fn new(devfn: u8, pin: u8, state: Arc<Mutex<PciIntxState>>) -> Self {
+        Intx {
+            line: intx_line(devfn, pin),
+            level: 0,
+            disabled: false,
+            asserted: false,
+            state,
+        }
+    }
+
+    /// Change the level of the pin, the interrupt is delivered unless the
+    /// guest disabled it in the command register.
+    pub fn notify(&mut self, level: u8) {
+        self.level = u8::from(level != 0);
+        self.update();
+    }
+
+    /// Level of the pin as driven by the function.
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    pub(crate) fn set_disabled(&mut self, disabled: bool) {
+        self.disabled = disabled;
+        self.update();
+    }
+
+    fn update(&mut self) {
+        let asserted = self.level == 1 && !self.disabled;
+        if self.asserted == asserted {
+            return;
+        }
+        self.asserted = asserted;
+        self.state.lock().unwrap().change_level(self.line, asserted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci_devfn;
+
+    #[test]
+    fn test_intx_swizzle() {
+        assert_eq!(intx_line(pci_devfn(0, 0), 0), 0);
+        assert_eq!(intx_line(pci_devfn(1, 0), 0), 1);
+        assert_eq!(intx_line(pci_devfn(1, 3), 0), 1);
+        assert_eq!(intx_line(pci_devfn(3, 2), 1), 0);
+        assert_eq!(intx_line(pci_devfn(6, 0), 3), 1);
+        assert_eq!(intx_line(pci_devfn(31, 0), 3), 2);
+    }
+}
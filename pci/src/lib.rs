@@ -0,0 +1,178 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Interfaces for simulating PCI devices.
+//!
+//! This crate simulates:
+//! - PCI configuration space, capabilities and BARs
+//! - MSI-X
+//! - PCI bus
+//! - generic ECAM PCIe host bridge, with legacy INTx routing
+
+mod bus;
+pub mod config;
+pub mod error;
+mod host;
+mod intx;
+pub mod msix;
+
+pub use bus::PciBus;
+pub use error::PciError;
+pub use host::PciHost;
+pub use intx::{Intx, PciIntxState, PCI_INTX_NUM};
+pub use msix::{init_msix, Msix};
+
+use anyhow::{bail, Result};
+use byteorder::{ByteOrder, LittleEndian};
+
+macro_rules! le_write {
+    ($name: ident, $func: ident, $type: tt) => {
+        pub fn $name(buf: &mut [u8], offset: usize, data: $type) -> Result<()> {
+            let data_len: usize = std::mem::size_of::<$type>();
+            let buf_len: usize = buf.len();
+            if offset + data_len > buf_len {
+                bail!(PciError::InvalidConf(
+                    format!("{} offset 0x{:x}", stringify!($name), offset),
+                    format!("buf len 0x{:x}", buf_len)
+                ));
+            }
+            LittleEndian::$func(&mut buf[offset..(offset + data_len)], data);
+            Ok(())
+        }
+    };
+}
+
+le_write!(le_write_u16, write_u16, u16);
+le_write!(le_write_u32, write_u32, u32);
+le_write!(le_write_u64, write_u64, u64);
+
+macro_rules! le_read {
+    ($name: ident, $func: ident, $type: tt) => {
+        pub fn $name(buf: &[u8], offset: usize) -> Result<$type> {
+            let data_len: usize = std::mem::size_of::<$type>();
+            let buf_len: usize = buf.len();
+            if offset + data_len > buf_len {
+                bail!(PciError::InvalidConf(
+                    format!("{} offset 0x{:x}", stringify!($name), offset),
+                    format!("buf len 0x{:x}", buf_len)
+                ));
+            }
+            Ok(LittleEndian::$func(&buf[offset..(offset + data_len)]))
+        }
+    };
+}
+
+le_read!(le_read_u16, read_u16, u16);
+le_read!(le_read_u32, read_u32, u32);
+le_read!(le_read_u64, read_u64, u64);
+
+/// Compose the device/function number of a PCI function.
+pub fn pci_devfn(slot: u8, func: u8) -> u8 {
+    ((slot & 0x1f) << 3) | (func & 0x07)
+}
+
+/// Slot number of the device/function number.
+pub fn pci_slot(devfn: u8) -> u8 {
+    (devfn >> 3) & 0x1f
+}
+
+/// Function number of the device/function number.
+pub fn pci_func(devfn: u8) -> u8 {
+    devfn & 0x07
+}
+
+/// Check if the range [`start`, `start` + `size`) overlaps the range
+/// [`range_start`, `range_start` + `range_size`).
+pub fn ranges_overlap(start: usize, size: usize, range_start: usize, range_size: usize) -> bool {
+    start < range_start + range_size && range_start < start + size
+}
+
+/// Operations for PCI functions.
+pub trait PciDevOps: Send {
+    /// Init the writable bits of the configuration space.
+    fn init_write_mask(&mut self) -> Result<()>;
+
+    /// Init the write-1-to-clear bits of the configuration space.
+    fn init_write_clear_mask(&mut self) -> Result<()>;
+
+    /// Realize the function and attach it to its parent bus.
+    fn realize(self) -> Result<()>;
+
+    /// Read the configuration space.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset in the configuration space.
+    /// * `data` - Buffer the data is read into, its length is the access size.
+    fn read_config(&self, offset: usize, data: &mut [u8]);
+
+    /// Write the configuration space.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset in the configuration space.
+    /// * `data` - Data to write, its length is the access size.
+    fn write_config(&mut self, offset: usize, data: &[u8]);
+
+    /// Name of the function.
+    fn name(&self) -> String;
+
+    /// Device/function number of the function on its parent bus.
+    fn devfn(&self) -> Option<u8> {
+        None
+    }
+
+    /// Reset the function to its power-on state.
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_le_write_read() {
+        let mut buf = [0_u8; 8];
+        assert!(le_write_u16(&mut buf, 0, 0x1234).is_ok());
+        assert_eq!(buf[..2], [0x34, 0x12]);
+        assert!(le_write_u32(&mut buf, 4, 0x1234_5678).is_ok());
+        assert_eq!(le_read_u32(&buf, 4).unwrap(), 0x1234_5678);
+        assert!(le_write_u64(&mut buf, 0, 0x1122_3344_5566_7788).is_ok());
+        assert_eq!(le_read_u64(&buf, 0).unwrap(), 0x1122_3344_5566_7788);
+
+        assert!(le_write_u32(&mut buf, 6, 0).is_err());
+        assert!(le_read_u16(&buf, 7).is_err());
+    }
+
+    #[test]
+    fn test_devfn() {
+        let devfn = pci_devfn(0x1f, 0x7);
+        assert_eq!(devfn, 0xff);
+        assert_eq!(pci_slot(devfn), 0x1f);
+        assert_eq!(pci_func(devfn), 0x7);
+
+        let devfn = pci_devfn(3, 1);
+        assert_eq!(pci_slot(devfn), 3);
+        assert_eq!(pci_func(devfn), 1);
+    }
+
+    #[test]
+    fn test_ranges_overlap() {
+        assert!(ranges_overlap(0x10, 4, 0x10, 24));
+        assert!(ranges_overlap(0x0e, 4, 0x10, 24));
+        assert!(!ranges_overlap(0x0c, 4, 0x10, 24));
+        assert!(!ranges_overlap(0x28, 4, 0x10, 24));
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use address_space::{GuestAddress, Region, RegionOps};
+use anyhow::{bail, Result};
+use devices::InterruptController;
+use log::error;
+
+use crate::config::{PciConfig, RegionType, CAP_ID_MSIX};
+use crate::{le_read_u16, le_read_u32, le_read_u64, le_write_u16, le_write_u32, PciError};
+
+/// Size of the MSI-X capability.
+pub const MSIX_CAP_SIZE: usize = 12;
+const MSIX_CAP_CONTROL: usize = 0x02;
+const MSIX_CAP_TABLE: usize = 0x04;
+const MSIX_CAP_PBA: usize = 0x08;
+
+// Bits of the message control register.
+const MSIX_CAP_ENABLE: u16 = 0x8000;
+const MSIX_CAP_FUNC_MASK: u16 = 0x4000;
+/// Largest number of vectors of a function.
+pub const MSIX_TABLE_SIZE_MAX: u16 = 0x800;
+
+// Layout of an entry of the MSI-X table.
+const MSIX_TABLE_ENTRY_SIZE: usize = 16;
+const MSIX_MSG_ADDR: usize = 0x00;
+const MSIX_MSG_DATA: usize = 0x08;
+const MSIX_MSG_VECTOR_CTL: usize = 0x0c;
+const MSIX_MSG_MASK_BIT: u32 = 0x01;
+
+/// Smallest size of the BAR holding the MSI-X table, so that it doesn't
+/// share a page with anything else.
+const MSIX_BAR_SIZE_MIN: u64 = 0x1000;
+
+/// MSI-X state of a PCI function: the message table and pending bits, which
+/// live in a BAR, and the enable and function mask bits of the capability.
+pub struct Msix {
+    /// MSI-X table, 16 bytes per vector.
+    table: Vec<u8>,
+    /// Pending bit array, a bit per vector.
+    pba: Vec<u8>,
+    /// Offset of the capability in the configuration space.
+    cap_offset: usize,
+    enabled: bool,
+    func_masked: bool,
+    irq_chip: Arc<Mutex<InterruptController>>,
+}
+
+impl Msix {
+    fn new(vector_nr: u16, cap_offset: usize, irq_chip: Arc<Mutex<InterruptController>>) -> Self {
+        let mut msix = Msix {
+            table: vec![0; vector_nr as usize * MSIX_TABLE_ENTRY_SIZE],
+            pba: vec![0; (vector_nr as usize + 63) / 64 * 8],
+            cap_offset,
+            enabled: false,
+            func_masked: false,
+            irq_chip,
+        };
+        msix.reset_table();
+        msix
+    }
+
+    /// Offset of the capability in the configuration space.
+    pub fn cap_offset(&self) -> usize {
+        self.cap_offset
+    }
+
+    /// Whether the guest enabled MSI-X, INTx isn't used once it has.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn vector_nr(&self) -> u16 {
+        (self.table.len() / MSIX_TABLE_ENTRY_SIZE) as u16
+    }
+
+    fn is_vector_masked(&self, vector: u16) -> bool {
+        let offset = vector as usize * MSIX_TABLE_ENTRY_SIZE + MSIX_MSG_VECTOR_CTL;
+        self.func_masked || le_read_u32(&self.table, offset).unwrap() & MSIX_MSG_MASK_BIT != 0
+    }
+
+    fn is_vector_pending(&self, vector: u16) -> bool {
+        self.pba[vector as usize / 8] & (1 << (vector % 8)) != 0
+    }
+
+    fn set_pending(&mut self, vector: u16, pending: bool) {
+        if pending {
+            self.pba[vector as usize / 8] |= 1 << (vector % 8);
+        } else {
+            self.pba[vector as usize / 8] &= !(1 << (vector % 8));
+        }
+    }
+
+    fn send_msg(&self, vector: u16) {
+        let entry = vector as usize * MSIX_TABLE_ENTRY_SIZE;
+        let addr = le_read_u64(&self.table, entry + MSIX_MSG_ADDR).unwrap();
+        let data = le_read_u32(&self.table, entry + MSIX_MSG_DATA).unwrap();
+        if let Err(e) = self.irq_chip.lock().unwrap().signal_msi(addr, data) {
+            error!("Failed to send MSI-X vector {}: {:?}", vector, e);
+        }
+    }
+
+    /// Send the message of a vector, or mark it pending while it is masked.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - Index of the vector in the MSI-X table.
+    pub fn notify(&mut self, vector: u16) {
+        if vector >= self.vector_nr() {
+            error!(
+                "MSI-X vector {} is out of range, the function has {} vectors",
+                vector,
+                self.vector_nr()
+            );
+            return;
+        }
+        if self.is_vector_masked(vector) {
+            self.set_pending(vector, true);
+            return;
+        }
+        self.send_msg(vector);
+    }
+
+    /// Send the pending messages of the vectors which got unmasked.
+    fn deliver_pending(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        for vector in 0..self.vector_nr() {
+            if self.is_vector_pending(vector) && !self.is_vector_masked(vector) {
+                self.set_pending(vector, false);
+                self.send_msg(vector);
+            }
+        }
+    }
+
+    /// Update the enable and function mask bits after the guest wrote the
+    /// capability.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Content of the configuration space.
+    pub fn write_config(&mut self, config: &[u8]) {
+        let control = le_read_u16(config, self.cap_offset + MSIX_CAP_CONTROL).unwrap();
+        self.enabled = control & MSIX_CAP_ENABLE != 0;
+        self.func_masked = control & MSIX_CAP_FUNC_MASK != 0;
+        self.deliver_pending();
+    }
+
+    fn read_table(&self, data: &mut [u8], offset: u64) -> bool {
+        let offset = offset as usize;
+        if offset + data.len() > self.table.len() {
+            error!("Failed to read MSI-X table: offset 0x{:x}", offset);
+            return false;
+        }
+        data.copy_from_slice(&self.table[offset..offset + data.len()]);
+        true
+    }
+
+    fn write_table(&mut self, data: &[u8], offset: u64) -> bool {
+        let offset = offset as usize;
+        if offset + data.len() > self.table.len() {
+            error!("Failed to write MSI-X table: offset 0x{:x}", offset);
+            return false;
+        }
+        self.table[offset..offset + data.len()].copy_from_slice(data);
+        // Only the mask bit of the vector control register is writable.
+        let ctl = offset / MSIX_TABLE_ENTRY_SIZE * MSIX_TABLE_ENTRY_SIZE + MSIX_MSG_VECTOR_CTL;
+        let value = le_read_u32(&self.table, ctl).unwrap();
+        le_write_u32(&mut self.table, ctl, value & MSIX_MSG_MASK_BIT).unwrap();
+        self.deliver_pending();
+        true
+    }
+
+    fn read_pba(&self, data: &mut [u8], offset: u64) -> bool {
+        let offset = offset as usize;
+        if offset + data.len() > self.pba.len() {
+            error!("Failed to read MSI-X PBA: offset 0x{:x}", offset);
+            return false;
+        }
+        data.copy_from_slice(&self.pba[offset..offset + data.len()]);
+        true
+    }
+
+    fn reset_table(&mut self) {
+        self.table.fill(0);
+        for vector in 0..self.vector_nr() as usize {
+            let ctl = vector * MSIX_TABLE_ENTRY_SIZE + MSIX_MSG_VECTOR_CTL;
+            le_write_u32(&mut self.table, ctl, MSIX_MSG_MASK_BIT).unwrap();
+        }
+        self.pba.fill(0);
+    }
+
+    /// Disable MSI-X and mask all the vectors.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Content of the configuration space.
+    pub fn reset(&mut self, config: &mut [u8]) -> Result<()> {
+        let offset = self.cap_offset + MSIX_CAP_CONTROL;
+        let control = le_read_u16(config, offset)?;
+        le_write_u16(
+            config,
+            offset,
+            control & !(MSIX_CAP_ENABLE | MSIX_CAP_FUNC_MASK),
+        )?;
+        self.enabled = false;
+        self.func_masked = false;
+        self.reset_table();
+        Ok(())
+    }
+}
+
+/// Add the MSI-X capability to a function, with the table and the pending
+/// bit array in a dedicated 32-bit BAR.
+///
+/// # Arguments
+///
+/// * `bar_id` - Index of the BAR holding the table and the pending bits.
+/// * `vector_nr` - Number of vectors.
+/// * `config` - Configuration space of the function.
+/// * `irq_chip` - Interrupt controller the messages are sent to.
+pub fn init_msix(
+    bar_id: usize,
+    vector_nr: u16,
+    config: &mut PciConfig,
+    irq_chip: Arc<Mutex<InterruptController>>,
+) -> Result<()> {
+    if vector_nr == 0 || vector_nr > MSIX_TABLE_SIZE_MAX {
+        bail!(PciError::InvalidConf(
+            "MSI-X vectors".to_string(),
+            vector_nr.to_string()
+        ));
+    }
+
+    let offset = config.add_pci_cap(CAP_ID_MSIX, MSIX_CAP_SIZE)?;
+    let table_size = vector_nr as u64 * MSIX_TABLE_ENTRY_SIZE as u64;
+    let pba_size = (vector_nr as u64 + 63) / 64 * 8;
+    let bar_size = (table_size + pba_size)
+        .next_power_of_two()
+        .max(MSIX_BAR_SIZE_MIN);
+    // The table is at the start of the BAR, the pending bits right after it.
+    le_write_u16(&mut config.config, offset + MSIX_CAP_CONTROL, vector_nr - 1)?;
+    le_write_u32(&mut config.config, offset + MSIX_CAP_TABLE, bar_id as u32)?;
+    le_write_u32(
+        &mut config.config,
+        offset + MSIX_CAP_PBA,
+        table_size as u32 | bar_id as u32,
+    )?;
+    le_write_u16(
+        &mut config.write_mask,
+        offset + MSIX_CAP_CONTROL,
+        MSIX_CAP_ENABLE | MSIX_CAP_FUNC_MASK,
+    )?;
+
+    let msix = Arc::new(Mutex::new(Msix::new(vector_nr, offset, irq_chip)));
+    let cloned_msix = msix.clone();
+    let table_read = move |data: &mut [u8], _: GuestAddress, offset: u64| -> bool {
+        cloned_msix.lock().unwrap().read_table(data, offset)
+    };
+    let cloned_msix = msix.clone();
+    let table_write = move |data: &[u8], _: GuestAddress, offset: u64| -> bool {
+        cloned_msix.lock().unwrap().write_table(data, offset)
+    };
+    let table_ops = RegionOps {
+        read: Arc::new(table_read),
+        write: Arc::new(table_write),
+    };
+
+    let cloned_msix = msix.clone();
+    let pba_read = move |data: &mut [u8], _: GuestAddress, offset: u64| -> bool {
+        cloned_msix.lock().unwrap().read_pba(data, offset)
+    };
+    // The pending bits are read-only.
+    let pba_write = |_: &[u8], _: GuestAddress, _: u64| -> bool { true };
+    let pba_ops = RegionOps {
+        read: Arc::new(pba_read),
+        write: Arc::new(pba_write),
+    };
+
+    let region = Region::init_container_region(bar_size);
+    region.add_subregion(Region::init_io_region(table_size, table_ops), 0)?;
+    region.add_subregion(Region::init_io_region(pba_size, pba_ops), table_size)?;
+    config.register_bar(bar_id, region, RegionType::Mem32Bit, false, bar_size)?;
+    config.msix = Some(msix);
+    Ok(())
+}
@@ -22,7 +22,7 @@ use std::sync::{Arc, Mutex};
 
 use anyhow::{bail, Context, Result};
 use log::{error, info};
-use machine::{LightMachine, MachineOps};
+use machine::{LightMachine, MachineOps, StdMachine};
 use machine_manager::{
     cmdline::{check_api_channel, create_args_parser, create_vmconfig},
     config::MachineType,
@@ -190,6 +190,19 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
             }
             vm
         }
+        MachineType::StandardVm => {
+            let vm = Arc::new(Mutex::new(
+                StdMachine::new(vm_config).with_context(|| "Failed to init StandardVM")?,
+            ));
+            MachineOps::realize(&vm, vm_config)
+                .with_context(|| "Failed to realize standard VM.")?;
+            EventLoop::set_manager(vm.clone(), None);
+
+            for listener in listeners {
+                sockets.push(Socket::from_unix_listener(listener, Some(vm.clone())));
+            }
+            vm
+        }
         MachineType::None => {
             let vm = Arc::new(Mutex::new(
                 LightMachine::new(vm_config).with_context(|| "Failed to init NoneVM")?,
@@ -24,6 +24,8 @@ pub enum SysBusError {
         #[from]
         source: hypervisor::error::HypervisorError,
     },
+    #[error("No free IRQ left, lines {0} to {1} are all in use")]
+    IrqExhausted(u32, u32),
     #[error("KvmIoctl")]
     KvmIoctl {
         #[from]
@@ -26,15 +26,15 @@ use vmm_sys_util::eventfd::EventFd;
 
 // According to the PLIC document, IRQ number 0 is not used
 #[cfg(target_arch = "riscv64")]
-pub const IRQ_BASE: i32 = 1;
+pub const IRQ_BASE: u32 = 1;
 #[cfg(target_arch = "riscv64")]
-pub const IRQ_MAX: i32 = 1024;
+pub const IRQ_MAX: u32 = 1023;
 
 pub struct SysBus {
     pub sys_mem: Arc<AddressSpace>,
     pub devices: Vec<Arc<Mutex<dyn SysBusDevOps>>>,
-    pub free_irqs: (i32, i32),
-    pub min_free_irq: i32,
+    pub free_irqs: (u32, u32),
+    pub min_free_irq: u32,
     pub mmio_region: (u64, u64),
     pub min_free_base: u64,
 }
@@ -57,7 +57,7 @@ impl fmt::Debug for SysBus {
 impl SysBus {
     pub fn new(
         sys_mem: &Arc<AddressSpace>,
-        free_irqs: (i32, i32),
+        free_irqs: (u32, u32),
         mmio_region: (u64, u64),
     ) -> Self {
         Self {
@@ -127,6 +127,19 @@ impl SysBus {
         Ok(())
     }
 
+    /// Allocate `count` consecutive interrupt lines, returns the first one.
+    pub fn alloc_irqs(&mut self, count: u32) -> Result<u32> {
+        let irq = self.min_free_irq;
+        if irq + count - 1 > self.free_irqs.1 {
+            bail!(SysBusError::IrqExhausted(
+                self.free_irqs.0,
+                self.free_irqs.1
+            ));
+        }
+        self.min_free_irq = irq + count;
+        Ok(irq)
+    }
+
     pub fn attach_dynamic_device<T: 'static + SysBusDevOps>(
         &mut self,
         dev: &Arc<Mutex<T>>,
@@ -140,7 +153,8 @@ impl SysBus {
 pub struct SysRes {
     pub region_base: u64,
     pub region_size: u64,
-    pub irq: i32,
+    /// Interrupt line of the device, 0 if it has none.
+    pub irq: u32,
 }
 
 impl Default for SysRes {
@@ -148,7 +162,7 @@ impl Default for SysRes {
         Self {
             region_base: 0,
             region_size: 0,
-            irq: -1,
+            irq: 0,
         }
     }
 }
@@ -161,6 +175,9 @@ pub enum SysBusDevType {
     VirtioMmio,
     #[cfg(target_arch = "riscv64")]
     Plic,
+    #[cfg(target_arch = "riscv64")]
+    Aplic,
+    PciHost,
     FwCfg,
     Ramfb,
     Others,
@@ -194,20 +211,12 @@ pub trait SysBusDevOps: Send {
         None
     }
 
-    fn set_irq(&mut self, sysbus: &mut SysBus) -> Result<i32> {
-        let irq = sysbus.min_free_irq;
-        if irq > sysbus.free_irqs.1 {
-            bail!("IRQ number exhausted.");
+    fn set_irq(&mut self, sysbus: &mut SysBus) -> Result<u32> {
+        if self.interrupt_evt().is_none() {
+            return Ok(0);
         }
 
-        match self.interrupt_evt() {
-            None => Ok(-1_i32),
-            Some(evt) => {
-          //      KVM_FDS.load().register_irqfd(evt, irq as u32)?;
-                sysbus.min_free_irq = irq + 1;
-                Ok(irq)
-            }
-        }
+        sysbus.alloc_irqs(1)
     }
 
     fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
@@ -249,3 +258,79 @@ pub trait SysBusDevOps: Send {
 //         scope.aml_bytes()
 //     }
 // }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestDevice {
+        interrupt_evt: Option<EventFd>,
+        res: SysRes,
+    }
+
+    impl TestDevice {
+        fn new(with_irq: bool) -> Self {
+            TestDevice {
+                interrupt_evt: with_irq.then(|| EventFd::new(0).unwrap()),
+                res: SysRes::default(),
+            }
+        }
+    }
+
+    impl SysBusDevOps for TestDevice {
+        fn read(&mut self, _data: &mut [u8], _base: GuestAddress, _offset: u64) -> bool {
+            true
+        }
+
+        fn write(&mut self, _data: &[u8], _base: GuestAddress, _offset: u64) -> bool {
+            true
+        }
+
+        fn interrupt_evt(&self) -> Option<&EventFd> {
+            self.interrupt_evt.as_ref()
+        }
+
+        fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
+            Some(&mut self.res)
+        }
+    }
+
+    #[test]
+    fn test_irq_allocation() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let mut sysbus = SysBus::new(&sys_mem, (300, 301), (0x1000_0000, 0x2000_0000));
+
+        // Lines beyond 255 are handed out, devices without interrupt get none.
+        let mut dev = TestDevice::new(false);
+        dev.set_sys_resource(&mut sysbus, 0x1000_0000, 0x1000)
+            .unwrap();
+        assert_eq!(dev.res.irq, 0);
+        for irq in 300..=301 {
+            let mut dev = TestDevice::new(true);
+            dev.set_sys_resource(&mut sysbus, 0x1000_0000, 0x1000)
+                .unwrap();
+            assert_eq!(dev.res.irq, irq);
+        }
+
+        let mut dev = TestDevice::new(true);
+        let err = dev
+            .set_sys_resource(&mut sysbus, 0x1000_0000, 0x1000)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SysBusError>(),
+            Some(SysBusError::IrqExhausted(300, 301))
+        ));
+        assert_eq!(sysbus.min_free_irq, 302);
+    }
+
+    #[test]
+    fn test_alloc_irqs() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let mut sysbus = SysBus::new(&sys_mem, (1, 8), (0x1000_0000, 0x2000_0000));
+
+        assert_eq!(sysbus.alloc_irqs(4).unwrap(), 1);
+        assert_eq!(sysbus.alloc_irqs(4).unwrap(), 5);
+        assert!(sysbus.alloc_irqs(1).is_err());
+        assert_eq!(sysbus.min_free_irq, 9);
+    }
+}
@@ -65,6 +65,20 @@ impl FromStr for AioEngine {
     }
 }
 
+impl std::fmt::Display for AioEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                AioEngine::Off => AIO_OFF,
+                AioEngine::Native => AIO_NATIVE,
+                AioEngine::IoUring => AIO_IOURING,
+            }
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Iovec {
     pub iov_base: u64,
@@ -91,8 +105,11 @@ pub enum OpCode {
     Preadv = 1,
     Pwritev = 2,
     Fdsync = 3,
+    Discard = 4,
+    WriteZeroes = 5,
 }
 
+#[derive(Clone)]
 pub struct AioCb<T: Clone> {
     pub direct: bool,
     pub req_align: u32,
@@ -158,7 +175,7 @@ impl<T: Clone + 'static> Aio<T> {
         self.engine
     }
 
-    pub fn submit_request(&mut self, mut cb: AioCb<T>) -> Result<()> {
+    pub fn submit_request(&mut self, cb: AioCb<T>) -> Result<()> {
         if self.request_misaligned(&cb) {
             let max_len = round_down(cb.nbytes + cb.req_align as u64 * 2, cb.req_align as u64)
                 .ok_or_else(|| anyhow!("Failed to round down request length."))?;
@@ -170,14 +187,14 @@ impl<T: Clone + 'static> Aio<T> {
                 unsafe { libc::memalign(host_page_size() as usize, buff_len as usize) };
             if bounce_buffer.is_null() {
                 error!("Failed to alloc memory for misaligned read/write.");
-                return (self.complete_func)(&cb, -1);
+                return (self.complete_func)(&cb, -(libc::ENOMEM as i64));
             }
 
-            let res = match self.handle_misaligned_rw(&mut cb, bounce_buffer, buff_len) {
+            let res = match self.handle_misaligned_rw(&cb, bounce_buffer, buff_len) {
                 Ok(()) => 0,
                 Err(e) => {
                     error!("{:?}", e);
-                    -1
+                    -(libc::EIO as i64)
                 }
             };
 
@@ -201,6 +218,14 @@ impl<T: Clone + 'static> Aio<T> {
                     self.flush_sync(cb)
                 }
             }
+            OpCode::Discard | OpCode::WriteZeroes => {
+                // Libaio has no fallocate command, do it synchronously.
+                if self.engine == AioEngine::IoUring {
+                    self.rw_async(cb)
+                } else {
+                    self.fallocate_sync(cb)
+                }
+            }
             OpCode::Noop => Err(anyhow!("Aio opcode is not specified.")),
         }
     }
@@ -213,6 +238,19 @@ impl<T: Clone + 'static> Aio<T> {
         }
     }
 
+    /// Wait until all the queued and in flight requests are completed.
+    pub fn drain_request(&mut self) -> Result<()> {
+        if self.ctx.is_none() {
+            return Ok(());
+        }
+        self.process_list()?;
+        while self.aio_in_queue.len > 0 || self.aio_in_flight.len > 0 {
+            self.handle_complete()?;
+            std::thread::yield_now();
+        }
+        Ok(())
+    }
+
     pub fn handle_complete(&mut self) -> Result<bool> {
         let mut done = false;
         if self.ctx.is_none() {
@@ -223,15 +261,29 @@ impl<T: Clone + 'static> Aio<T> {
             // SAFETY: evt.data is specified by submit and not dropped at other place.
             unsafe {
                 let node = evt.user_data as *mut CbNode<T>;
-                let res = if (evt.status == 0) && (evt.res == (*node).value.nbytes as i64) {
+                let cb = &(*node).value;
+                // Fallocate returns 0 on success instead of the bytes processed.
+                let expect = match cb.opcode {
+                    OpCode::Discard | OpCode::WriteZeroes => 0,
+                    _ => cb.nbytes as i64,
+                };
+                let res = if (evt.status == 0) && (evt.res == expect) {
                     done = true;
                     evt.res
+                } else if cb.opcode == OpCode::WriteZeroes && evt.res == -(libc::EOPNOTSUPP as i64)
+                {
+                    // The file system can not zero range, punch hole instead.
+                    raw_discard(cb.file_fd, cb.offset, cb.nbytes)
                 } else {
                     error!(
                         "Async IO request failed, status {} res {}",
                         evt.status, evt.res
                     );
-                    -1
+                    if evt.res < 0 {
+                        evt.res
+                    } else {
+                        short_rw_errno(cb.opcode)
+                    }
                 };
 
                 (self.complete_func)(&(*node).value, res)?;
@@ -284,7 +336,7 @@ impl<T: Clone + 'static> Aio<T> {
             if is_err {
                 // Fail one request, retry the rest.
                 if let Some(node) = self.aio_in_queue.pop_tail() {
-                    (self.complete_func)(&(node).value, -1)?;
+                    (self.complete_func)(&(node).value, -(libc::EIO as i64))?;
                 }
             } else if nr == 0 {
                 // If can't submit any request, break the loop
@@ -311,13 +363,13 @@ impl<T: Clone + 'static> Aio<T> {
         let mut ret = match cb.opcode {
             OpCode::Preadv => raw_readv(cb.file_fd, &cb.iovec, cb.offset),
             OpCode::Pwritev => raw_writev(cb.file_fd, &cb.iovec, cb.offset),
-            _ => -1,
+            _ => -(libc::EINVAL as i64),
         };
         if ret < 0 {
             error!("Failed to do sync read/write.");
         } else if ret as u64 != cb.nbytes {
             error!("Incomplete sync read/write.");
-            ret = -1;
+            ret = short_rw_errno(cb.opcode);
         }
         (self.complete_func)(&cb, ret)
     }
@@ -341,7 +393,7 @@ impl<T: Clone + 'static> Aio<T> {
 
     fn handle_misaligned_rw(
         &mut self,
-        cb: &mut AioCb<T>,
+        cb: &AioCb<T>,
         bounce_buffer: *mut c_void,
         buffer_len: u64,
     ) -> Result<()> {
@@ -354,7 +406,9 @@ impl<T: Clone + 'static> Aio<T> {
         match cb.opcode {
             OpCode::Preadv => {
                 let mut offset = offset_align;
-                let mut iovecs = &mut cb.iovec[..];
+                // Keep the iovec of the request intact, it may be submitted again.
+                let mut iovec = cb.iovec.clone();
+                let mut iovecs = &mut iovec[..];
                 loop {
                     // Step1: Read file to bounce buffer.
                     let nbytes = cmp::min(high_align - offset, buffer_len);
@@ -416,7 +470,8 @@ impl<T: Clone + 'static> Aio<T> {
                 let need_tail = !tail_loaded && (high_align > high);
 
                 let mut offset = offset_align;
-                let mut iovecs = &mut cb.iovec[..];
+                let mut iovec = cb.iovec.clone();
+                let mut iovecs = &mut iovec[..];
                 loop {
                     // Step1: Load iovec to bounce buffer.
                     let nbytes = cmp::min(high_align - offset, buffer_len);
@@ -488,6 +543,27 @@ impl<T: Clone + 'static> Aio<T> {
         }
         (self.complete_func)(&cb, ret)
     }
+
+    fn fallocate_sync(&mut self, cb: AioCb<T>) -> Result<()> {
+        let ret = match cb.opcode {
+            OpCode::Discard => raw_discard(cb.file_fd, cb.offset, cb.nbytes),
+            OpCode::WriteZeroes => raw_write_zeroes(cb.file_fd, cb.offset, cb.nbytes),
+            _ => -(libc::EINVAL as i64),
+        };
+        if ret < 0 {
+            error!("Failed to do sync discard/write zeroes.");
+        }
+        (self.complete_func)(&cb, ret)
+    }
+}
+
+/// Errno of a request which completes fewer bytes than expected. A short
+/// write means the host runs out of space.
+fn short_rw_errno(opcode: OpCode) -> i64 {
+    match opcode {
+        OpCode::Pwritev => -(libc::ENOSPC as i64),
+        _ => -(libc::EIO as i64),
+    }
 }
 
 pub fn mem_from_buf(buf: &[u8], hva: u64) -> Result<()> {
@@ -11,7 +11,9 @@
 // See the Mulan PSL v2 for more details.
 
 use super::Iovec;
-use libc::{c_int, c_void, fdatasync, iovec, off_t, pread, preadv, pwrite, pwritev, size_t};
+use libc::{
+    c_int, c_void, fallocate, fdatasync, iovec, off_t, pread, preadv, pwrite, pwritev, size_t,
+};
 use log::error;
 use std::os::unix::io::RawFd;
 
@@ -32,13 +34,12 @@ pub fn raw_read(fd: RawFd, buf: u64, size: usize, offset: usize) -> i64 {
         }
     }
     if ret < 0 {
+        let err = errno::errno().0;
         error!(
             "Failed to pread: buf{}, size{}, offset{}, errno{}.",
-            buf,
-            size,
-            offset,
-            errno::errno().0
+            buf, size, offset, err
         );
+        return -(err as i64);
     }
     ret
 }
@@ -60,11 +61,9 @@ pub fn raw_readv(fd: RawFd, iovec: &[Iovec], offset: usize) -> i64 {
         }
     }
     if ret < 0 {
-        error!(
-            "Failed to preadv: offset{}, errno{}.",
-            offset,
-            errno::errno().0,
-        );
+        let err = errno::errno().0;
+        error!("Failed to preadv: offset{}, errno{}.", offset, err);
+        return -(err as i64);
     }
     ret
 }
@@ -86,13 +85,12 @@ pub fn raw_write(fd: RawFd, buf: u64, size: usize, offset: usize) -> i64 {
         }
     }
     if ret < 0 {
+        let err = errno::errno().0;
         error!(
             "Failed to pwrite: buf{}, size{}, offset{}, errno{}.",
-            buf,
-            size,
-            offset,
-            errno::errno().0,
+            buf, size, offset, err
         );
+        return -(err as i64);
     }
     ret
 }
@@ -114,11 +112,9 @@ pub fn raw_writev(fd: RawFd, iovec: &[Iovec], offset: usize) -> i64 {
         }
     }
     if ret < 0 {
-        error!(
-            "Failed to pwritev: offset{}, errno{}.",
-            offset,
-            errno::errno().0,
-        );
+        let err = errno::errno().0;
+        error!("Failed to pwritev: offset{}, errno{}.", offset, err);
+        return -(err as i64);
     }
     ret
 }
@@ -127,7 +123,62 @@ pub fn raw_datasync(fd: RawFd) -> i64 {
     // SAFETY: fd is valid.
     let ret = unsafe { i64::from(fdatasync(fd)) };
     if ret < 0 {
-        error!("Failed to fdatasync: errno{}.", errno::errno().0);
+        let err = errno::errno().0;
+        error!("Failed to fdatasync: errno{}.", err);
+        return -(err as i64);
+    }
+    ret
+}
+
+fn raw_fallocate(fd: RawFd, mode: c_int, offset: usize, size: u64) -> i64 {
+    let mut ret;
+    loop {
+        // SAFETY: fd is valid.
+        ret = unsafe { i64::from(fallocate(fd as c_int, mode, offset as off_t, size as off_t)) };
+        if !(ret < 0 && (errno::errno().0 == libc::EINTR || errno::errno().0 == libc::EAGAIN)) {
+            break;
+        }
+    }
+    if ret < 0 {
+        return -(errno::errno().0 as i64);
+    }
+    ret
+}
+
+/// Deallocate the range of file, the range reads as zeroes afterwards.
+pub fn raw_discard(fd: RawFd, offset: usize, size: u64) -> i64 {
+    let ret = raw_fallocate(
+        fd,
+        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+        offset,
+        size,
+    );
+    if ret < 0 {
+        error!(
+            "Failed to punch hole: offset{}, size{}, errno{}.",
+            offset, size, -ret
+        );
+    }
+    ret
+}
+
+/// Zero the range of file. Fall back to punching hole if the file system
+/// can not zero range.
+pub fn raw_write_zeroes(fd: RawFd, offset: usize, size: u64) -> i64 {
+    let ret = raw_fallocate(
+        fd,
+        libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+        offset,
+        size,
+    );
+    if ret == -(libc::EOPNOTSUPP as i64) {
+        return raw_discard(fd, offset, size);
+    }
+    if ret < 0 {
+        error!(
+            "Failed to zero range: offset{}, size{}, errno{}.",
+            offset, size, -ret
+        );
     }
     ret
 }
@@ -71,6 +71,18 @@ impl<T: Clone> AioContext<T> for IoUringContext {
                     .build()
                     .flags(squeue::Flags::ASYNC)
                     .user_data(data),
+                OpCode::Discard => opcode::Fallocate::new(fd, cb.nbytes as libc::off_t)
+                    .offset(offset)
+                    .mode(libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE)
+                    .build()
+                    .flags(squeue::Flags::ASYNC)
+                    .user_data(data),
+                OpCode::WriteZeroes => opcode::Fallocate::new(fd, cb.nbytes as libc::off_t)
+                    .offset(offset)
+                    .mode(libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE)
+                    .build()
+                    .flags(squeue::Flags::ASYNC)
+                    .user_data(data),
                 _ => {
                     bail!("Invalid entry code");
                 }
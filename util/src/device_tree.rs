@@ -17,6 +17,9 @@ use anyhow::{anyhow, Context, Result};
 use byteorder::{BigEndian, ByteOrder};
 
 pub const PLIC_PHANDLE: u32 = 1;
+pub const APLIC_PHANDLE: u32 = 1;
+// Above the per-cpu phandles, which grow with the number of vcpus.
+pub const IMSIC_PHANDLE: u32 = 0x8000;
 pub const INCT_PHANDLE_START: u32 = 2;
 pub const CLK_PHANDLE: u32 = 1;
 pub const GIC_PHANDLE: u32 = 2;
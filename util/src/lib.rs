@@ -38,6 +38,7 @@ pub mod pixman;
 pub mod syscall;
 pub mod tap;
 pub mod test_helper;
+pub mod throttle;
 pub mod time;
 pub mod trace;
 pub mod unix;
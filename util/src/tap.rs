@@ -46,6 +46,8 @@ pub struct IfReq {
 
 pub struct Tap {
     pub file: File,
+    /// Identify if the file is a tun/tap device, which accepts the TUN ioctls.
+    tun: bool,
 }
 
 impl Tap {
@@ -111,10 +113,22 @@ impl Tap {
             bail!("Needs multiqueue, but no kernel support for IFF_MULTI_QUEUE available");
         }
 
-        Ok(Tap { file })
+        Ok(Tap { file, tun: true })
+    }
+
+    /// Wrap a file which carries the frames with the vnet header but is not a
+    /// tun/tap device, such as one end of a socket pair. It supports no offload.
+    pub fn from_file(file: File) -> Self {
+        Tap { file, tun: false }
     }
 
     pub fn set_offload(&self, flags: u32) -> Result<()> {
+        if !self.tun {
+            if flags != 0 {
+                bail!("Offload is not supported by the backend");
+            }
+            return Ok(());
+        }
         let ret = unsafe { ioctl_with_val(&self.file, TUNSETOFFLOAD(), flags as libc::c_ulong) };
         if ret < 0 {
             return Err(anyhow!("ioctl TUNSETOFFLOAD failed.".to_string()));
@@ -124,6 +138,9 @@ impl Tap {
     }
 
     pub fn set_hdr_size(&self, len: u32) -> Result<()> {
+        if !self.tun {
+            bail!("Failed to set hdr size, the backend is not a tap device");
+        }
         let ret = unsafe { ioctl_with_ref(&self.file, TUNSETVNETHDRSZ(), &len) };
         if ret < 0 {
             return Err(anyhow!("ioctl TUNSETVNETHDRSZ failed.".to_string()));
@@ -133,6 +150,9 @@ impl Tap {
     }
 
     pub fn has_ufo(&self) -> bool {
+        if !self.tun {
+            return false;
+        }
         let flags = TUN_F_CSUM | TUN_F_UFO;
         (unsafe { ioctl_with_val(&self.file, TUNSETOFFLOAD(), flags as libc::c_ulong) }) >= 0
     }
@@ -154,6 +174,7 @@ impl Clone for Tap {
     fn clone(&self) -> Self {
         Tap {
             file: self.file.try_clone().unwrap(),
+            tun: self.tun,
         }
     }
 }
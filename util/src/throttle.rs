@@ -0,0 +1,443 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! IO throttling of block devices.
+//!
+//! Every limit is a leaky bucket which is filled by the IOs and leaks at the
+//! average rate. A bucket may also allow bursts at the max rate for a number of
+//! seconds, which is tracked by a second, smaller bucket leaking at the max rate.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use log::error;
+use serde::{Deserialize, Serialize};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::loop_context::EventLoopContext;
+use crate::time::NANOSECONDS_PER_SECOND;
+
+/// Max value of a rate, in bytes or operations per second.
+pub const THROTTLE_VALUE_MAX: u64 = 1_000_000_000_000_000;
+/// Number of limits in a throttle config.
+const THROTTLE_LIMIT_NUM: usize = 6;
+
+/// Limit of one kind of IO, in bytes or operations per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThrottleLimit {
+    /// Average rate, 0 means no limit.
+    pub avg: u64,
+    /// Burst rate, 0 means IO can not burst above the average rate.
+    pub max: u64,
+    /// Seconds the burst rate can last.
+    pub max_length: u64,
+}
+
+impl Default for ThrottleLimit {
+    fn default() -> Self {
+        ThrottleLimit {
+            avg: 0,
+            max: 0,
+            max_length: 1,
+        }
+    }
+}
+
+impl ThrottleLimit {
+    fn check(&self, name: &str) -> Result<()> {
+        if self.avg > THROTTLE_VALUE_MAX || self.max > THROTTLE_VALUE_MAX {
+            bail!("{} limit should not exceed {}", name, THROTTLE_VALUE_MAX);
+        }
+        if self.max != 0 && self.avg == 0 {
+            bail!("{}-max requires {} to be set", name, name);
+        }
+        if self.max != 0 && self.max < self.avg {
+            bail!("{}-max should not be less than {}", name, name);
+        }
+        if self.max_length == 0 {
+            bail!("{}-max-length should be at least 1", name);
+        }
+        if self.max_length > 1 && self.max == 0 {
+            bail!("{}-max-length requires {}-max to be set", name, name);
+        }
+        if self.max.checked_mul(self.max_length).is_none() {
+            bail!("{}-max multiplied by {}-max-length overflows", name, name);
+        }
+        Ok(())
+    }
+}
+
+/// IO limits of a block device.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThrottleConfig {
+    pub bps_total: ThrottleLimit,
+    pub bps_read: ThrottleLimit,
+    pub bps_write: ThrottleLimit,
+    pub iops_total: ThrottleLimit,
+    pub iops_read: ThrottleLimit,
+    pub iops_write: ThrottleLimit,
+    /// Size of one operation in bytes, larger IOs are counted as several operations.
+    /// 0 means every IO is one operation.
+    pub iops_size: u64,
+}
+
+impl ThrottleConfig {
+    /// Limits with their names, bps limits first.
+    fn limits(&self) -> [(&'static str, &ThrottleLimit); THROTTLE_LIMIT_NUM] {
+        [
+            ("bps-total", &self.bps_total),
+            ("bps-read", &self.bps_read),
+            ("bps-write", &self.bps_write),
+            ("iops-total", &self.iops_total),
+            ("iops-read", &self.iops_read),
+            ("iops-write", &self.iops_write),
+        ]
+    }
+
+    /// Whether any limit is set.
+    pub fn is_enabled(&self) -> bool {
+        self.limits().iter().any(|(_, limit)| limit.avg != 0)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.bps_total.avg != 0 && (self.bps_read.avg != 0 || self.bps_write.avg != 0) {
+            bail!("bps-total can not be used with bps-read or bps-write");
+        }
+        if self.iops_total.avg != 0 && (self.iops_read.avg != 0 || self.iops_write.avg != 0) {
+            bail!("iops-total can not be used with iops-read or iops-write");
+        }
+        for (name, limit) in self.limits().iter() {
+            limit.check(name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Water levels of the leaky bucket of a limit.
+#[derive(Default, Debug, Clone, Copy)]
+struct Bucket {
+    level: f64,
+    burst_level: f64,
+}
+
+impl Bucket {
+    fn leak(&mut self, limit: &ThrottleLimit, secs: f64) {
+        self.level = (self.level - limit.avg as f64 * secs).max(0.0);
+        if limit.max_length > 1 {
+            self.burst_level = (self.burst_level - limit.max as f64 * secs).max(0.0);
+        }
+    }
+
+    fn fill(&mut self, limit: &ThrottleLimit, units: f64) {
+        self.level += units;
+        if limit.max_length > 1 {
+            self.burst_level += units;
+        }
+    }
+
+    /// Nanoseconds until the bucket leaks enough to accept IO.
+    fn wait_ns(&self, limit: &ThrottleLimit) -> u64 {
+        if limit.avg == 0 {
+            return 0;
+        }
+        // Without burst, allow the IO of 1/10 second at once.
+        let (bucket_size, burst_bucket_size) = if limit.max == 0 {
+            (limit.avg as f64 / 10.0, 0.0)
+        } else {
+            (
+                limit.max as f64 * limit.max_length as f64,
+                limit.max as f64 / 10.0,
+            )
+        };
+
+        let extra = self.level - bucket_size;
+        if extra > 0.0 {
+            return (extra * NANOSECONDS_PER_SECOND as f64 / limit.avg as f64) as u64;
+        }
+        if limit.max_length > 1 {
+            let extra = self.burst_level - burst_bucket_size;
+            if extra > 0.0 {
+                return (extra * NANOSECONDS_PER_SECOND as f64 / limit.max as f64) as u64;
+            }
+        }
+        0
+    }
+}
+
+/// Throttle state of a block device, shared by all its IO handlers.
+pub struct ThrottleState {
+    config: ThrottleConfig,
+    /// Buckets in the order of `ThrottleConfig::limits`.
+    buckets: [Bucket; THROTTLE_LIMIT_NUM],
+    /// Last time the buckets leaked.
+    prev_time: Instant,
+}
+
+impl ThrottleState {
+    pub fn new(config: ThrottleConfig) -> Self {
+        ThrottleState {
+            config,
+            buckets: [Bucket::default(); THROTTLE_LIMIT_NUM],
+            prev_time: Instant::now(),
+        }
+    }
+
+    pub fn config(&self) -> &ThrottleConfig {
+        &self.config
+    }
+
+    /// Change the limits, the buckets are emptied.
+    pub fn set_config(&mut self, config: ThrottleConfig) {
+        self.config = config;
+        self.buckets = [Bucket::default(); THROTTLE_LIMIT_NUM];
+        self.prev_time = Instant::now();
+    }
+
+    /// Indexes of the buckets affected by IO in the direction.
+    fn bucket_indexes(is_write: bool) -> [usize; 4] {
+        if is_write {
+            [0, 2, 3, 5]
+        } else {
+            [0, 1, 3, 4]
+        }
+    }
+
+    fn leak(&mut self, now: Instant) {
+        let secs = now.saturating_duration_since(self.prev_time).as_secs_f64();
+        self.prev_time = now;
+        let limits = self.config.limits();
+        for (bucket, (_, limit)) in self.buckets.iter_mut().zip(limits.iter()) {
+            bucket.leak(limit, secs);
+        }
+    }
+
+    fn wait_ns(&self, is_write: bool) -> u64 {
+        let limits = self.config.limits();
+        Self::bucket_indexes(is_write)
+            .iter()
+            .map(|&i| self.buckets[i].wait_ns(limits[i].1))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Nanoseconds to wait before IO in the direction can be issued, 0 if it can
+    /// be issued now.
+    pub fn compute_wait(&mut self, is_write: bool) -> u64 {
+        if !self.config.is_enabled() {
+            return 0;
+        }
+        self.leak(Instant::now());
+        self.wait_ns(is_write)
+    }
+
+    /// Account the issued IO of `bytes` in the direction.
+    pub fn account(&mut self, is_write: bool, bytes: u64) {
+        if !self.config.is_enabled() {
+            return;
+        }
+        let ops = if self.config.iops_size != 0 && bytes > self.config.iops_size {
+            bytes as f64 / self.config.iops_size as f64
+        } else {
+            1.0
+        };
+        let limits = self.config.limits();
+        for i in Self::bucket_indexes(is_write) {
+            // The first half of the limits are bps.
+            let units = if i < THROTTLE_LIMIT_NUM / 2 {
+                bytes as f64
+            } else {
+                ops
+            };
+            self.buckets[i].fill(limits[i].1, units);
+        }
+    }
+}
+
+/// Throttle used by one IO handler. When IO is throttled, a timer is started to
+/// wake up the IO handler once IO can be issued again.
+pub struct Throttle {
+    state: Arc<Mutex<ThrottleState>>,
+    /// Indicate whether the timer started.
+    timer_started: bool,
+    /// Written by the timer, it should be listened by the IO thread.
+    timer_wakeup: Arc<EventFd>,
+}
+
+impl Throttle {
+    pub fn new(state: Arc<Mutex<ThrottleState>>) -> Result<Self> {
+        Ok(Throttle {
+            state,
+            timer_started: false,
+            timer_wakeup: Arc::new(EventFd::new(libc::EFD_NONBLOCK)?),
+        })
+    }
+
+    /// Return true if IO in the direction must not be issued now, the caller should
+    /// retry when the wakeup event is triggered.
+    ///
+    /// # Arguments
+    ///
+    /// * `loop_context` - used for delay function call.
+    /// * `is_write` - the direction of IO.
+    pub fn throttled(&mut self, loop_context: &mut EventLoopContext, is_write: bool) -> bool {
+        if self.timer_started {
+            return true;
+        }
+
+        let wait_ns = self.state.lock().unwrap().compute_wait(is_write);
+        if wait_ns == 0 {
+            return false;
+        }
+
+        let wakeup_clone = self.timer_wakeup.clone();
+        let func = Box::new(move || {
+            wakeup_clone
+                .write(1)
+                .unwrap_or_else(|e| error!("Throttle send event to device failed {:?}", e));
+        });
+        loop_context.delay_call(func, wait_ns);
+        self.timer_started = true;
+
+        true
+    }
+
+    /// Account the issued IO of `bytes` in the direction.
+    pub fn account(&mut self, is_write: bool, bytes: u64) {
+        self.state.lock().unwrap().account(is_write, bytes);
+    }
+
+    /// Whether IO is waiting for the timer.
+    pub fn timer_started(&self) -> bool {
+        self.timer_started
+    }
+
+    /// Clear the timer state.
+    pub fn clear_timer(&mut self) {
+        self.timer_started = false;
+    }
+
+    /// Get raw fd of wakeup event.
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.timer_wakeup.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(avg: u64, max: u64, max_length: u64) -> ThrottleLimit {
+        ThrottleLimit {
+            avg,
+            max,
+            max_length,
+        }
+    }
+
+    #[test]
+    fn test_throttle_config_check() {
+        let config = ThrottleConfig::default();
+        assert!(!config.is_enabled());
+        assert!(config.check().is_ok());
+
+        let config = ThrottleConfig {
+            bps_total: limit(1 << 20, 2 << 20, 10),
+            iops_read: limit(100, 0, 1),
+            iops_write: limit(200, 0, 1),
+            ..Default::default()
+        };
+        assert!(config.is_enabled());
+        assert!(config.check().is_ok());
+
+        // Total and read/write limits are exclusive.
+        let mut bad = config;
+        bad.iops_total = limit(100, 0, 1);
+        assert!(bad.check().is_err());
+
+        // Burst below the average.
+        let mut bad = config;
+        bad.bps_total.max = 1 << 10;
+        assert!(bad.check().is_err());
+
+        // Burst without average.
+        let mut bad = config;
+        bad.bps_read = limit(0, 1 << 20, 1);
+        assert!(bad.check().is_err());
+
+        // Burst length without burst.
+        let mut bad = config;
+        bad.iops_read.max_length = 2;
+        assert!(bad.check().is_err());
+
+        let mut bad = config;
+        bad.iops_read.max_length = 0;
+        assert!(bad.check().is_err());
+
+        let mut bad = config;
+        bad.bps_total.avg = THROTTLE_VALUE_MAX + 1;
+        assert!(bad.check().is_err());
+    }
+
+    #[test]
+    fn test_throttle_iops() {
+        let mut config = ThrottleConfig {
+            iops_write: limit(100, 0, 1),
+            ..Default::default()
+        };
+        let mut state = ThrottleState::new(config);
+        let start = state.prev_time;
+
+        // 1/10 second of IO is allowed at once.
+        for _ in 0..11 {
+            assert_eq!(state.wait_ns(true), 0);
+            state.account(true, 512);
+        }
+        assert_eq!(state.wait_ns(true), 10_000_000);
+        // Reads are not limited.
+        assert_eq!(state.wait_ns(false), 0);
+
+        state.leak(start + std::time::Duration::from_millis(10));
+        assert_eq!(state.wait_ns(true), 0);
+
+        // Large IO is split into operations of iops_size.
+        config.iops_size = 4096;
+        state.set_config(config);
+        state.account(true, 4096 * 11);
+        assert_eq!(state.wait_ns(true), 10_000_000);
+    }
+
+    #[test]
+    fn test_throttle_bps_burst() {
+        let config = ThrottleConfig {
+            bps_total: limit(1000, 10000, 2),
+            ..Default::default()
+        };
+        let mut state = ThrottleState::new(config);
+        let start = state.prev_time;
+
+        // The burst bucket limits IO to 1/10 second at the max rate.
+        state.account(false, 1000);
+        assert_eq!(state.wait_ns(false), 0);
+        state.account(true, 1000);
+        assert_eq!(state.wait_ns(true), 100_000_000);
+
+        state.leak(start + std::time::Duration::from_millis(100));
+        assert_eq!(state.wait_ns(true), 0);
+
+        // After max * max_length bytes of burst, IO goes at the average rate.
+        state.account(true, 19100);
+        assert_eq!(state.wait_ns(true), 1_000_000_000);
+    }
+}
@@ -16,46 +16,61 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::cell::{Cell, RefCell};
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::io::{Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
 use std::time::Instant;
 
 use super::{
     iov_discard_back, iov_discard_front, iov_to_buf, report_virtio_error, virtio_has_feature,
     Element, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VirtioTrace,
-    VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX,
-    VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
-    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
-    VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_TYPE_BLOCK,
+    VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO,
+    VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR,
+    VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH,
+    VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES,
+    VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC,
+    VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1, VIRTIO_TYPE_BLOCK,
 };
+use crate::block_job::{device_has_job, WriteFilter};
+use crate::nbd::{NbdClient, NbdExport};
+use crate::qcow2::{ClusterCopy, DiskRange, Qcow2Image};
 use crate::VirtioError;
 use address_space::{AddressSpace, GuestAddress};
 use anyhow::{anyhow, bail, Context, Result};
 use byteorder::{ByteOrder, LittleEndian};
 use log::{error, warn};
-use machine_manager::config::{BlkDevConfig, ConfigCheck, DriveFile, VmConfig};
+use machine_manager::config::{
+    check_throttle, is_nbd_uri, BlkDevConfig, ConfigCheck, DiskFormat, DriveFile, IoErrorPolicy,
+    NbdUri, VmConfig, WriteZeroesState,
+};
+use machine_manager::event;
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper, EventLoop};
+use machine_manager::qmp::{qmp_schema, QmpChannel};
+use migration::general::Lifecycle;
 use migration::{
     migration::Migratable, DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager,
     StateTransfer,
 };
 use migration_derive::{ByteCode, Desc};
+use once_cell::sync::Lazy;
 use util::aio::{iov_from_buf_direct, raw_datasync, Aio, AioCb, AioEngine, Iovec, OpCode};
 use util::byte_code::ByteCode;
-use util::leak_bucket::LeakBucket;
 use util::loop_context::{
     read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
 };
 use util::num_ops::read_u32;
 use util::offset_of;
+use util::throttle::{Throttle, ThrottleConfig, ThrottleState};
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 /// Number of virtqueues.
 const QUEUE_NUM_BLK: usize = 1;
@@ -73,16 +88,73 @@ const MAX_NUM_MERGE_IOVS: usize = 1024;
 const MAX_NUM_MERGE_BYTES: u64 = i32::MAX as u64;
 /// Max time for every round of process queue.
 const MAX_MILLIS_TIME_PROCESS_QUEUE: u16 = 100;
+/// Max number sectors of a discard or write zeroes request.
+const MAX_REQUEST_SECTORS: u32 = (i32::MAX as u32) >> SECTOR_SHIFT;
+
+/// Block devices by device id, used by the QMP commands on block devices.
+static BLOCK_DEVICES: Lazy<Mutex<HashMap<String, Weak<Mutex<Block>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Register the block device to be found by its id.
+pub fn register_block_device(id: &str, block: &Arc<Mutex<Block>>) {
+    let mut devices = BLOCK_DEVICES.lock().unwrap();
+    // Drop the devices which have been removed from the VM.
+    devices.retain(|_, dev| dev.strong_count() > 0);
+    devices.insert(id.to_string(), Arc::downgrade(block));
+}
+
+/// Find the block device with `id`.
+pub fn find_block_device(id: &str) -> Option<Arc<Mutex<Block>>> {
+    BLOCK_DEVICES
+        .lock()
+        .unwrap()
+        .get(id)
+        .and_then(|dev| dev.upgrade())
+}
 
-type SenderConfig = (
-    Option<Arc<File>>,
-    u32,
-    u32,
-    u64,
-    Option<String>,
-    bool,
-    AioEngine,
-);
+/// All the block devices of the VM, sorted by id.
+pub fn block_devices() -> Vec<Arc<Mutex<Block>>> {
+    let mut devices: Vec<(String, Arc<Mutex<Block>>)> = BLOCK_DEVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(id, dev)| dev.upgrade().map(|dev| (id.clone(), dev)))
+        .collect();
+    devices.sort_by(|a, b| a.0.cmp(&b.0));
+    devices.into_iter().map(|(_, dev)| dev).collect()
+}
+
+/// Config of the device sent to the IO handlers when it's updated.
+struct SenderConfig {
+    /// The image file opened by the block device.
+    image: Option<Arc<File>>,
+    /// The align requirement of request(offset/len).
+    req_align: u32,
+    /// The align requirement of buffer(iova_base).
+    buf_align: u32,
+    /// The number of sectors of the disk image.
+    disk_sectors: u64,
+    /// Serial number of the block device.
+    serial_num: Option<String>,
+    /// If use direct access io.
+    direct: bool,
+    /// Aio engine of the image file.
+    aio: AioEngine,
+    /// Mapping of the image if it is in qcow2 format.
+    qcow2: Option<Arc<Mutex<Qcow2Image>>>,
+    /// Pass discard requests down to the image file.
+    discard: bool,
+    /// Detect and convert zeroed writes into write zeroes requests.
+    write_zeroes: WriteZeroesState,
+    /// Id of the block device, reported in BLOCK_IO_ERROR events.
+    device: String,
+    /// Error policy of write, flush, discard and write zeroes requests.
+    werror: IoErrorPolicy,
+    /// Error policy of read requests.
+    rerror: IoErrorPolicy,
+    /// NBD export backing the device.
+    nbd: Option<NbdExport>,
+}
 
 fn get_serial_num_config(serial_num: &str) -> Vec<u8> {
     let mut id_bytes = vec![0; VIRTIO_BLK_ID_BYTES as usize];
@@ -103,6 +175,151 @@ struct RequestOutHeader {
 
 impl ByteCode for RequestOutHeader {}
 
+/// Segment of discard and write zeroes requests.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct DiscardWriteZeroesSeg {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+impl ByteCode for DiscardWriteZeroesSeg {}
+
+/// Completion state shared by the host IOs a request is split into.
+struct SplitIo {
+    /// Number of host IOs not completed yet.
+    pending: Cell<usize>,
+    /// Status reported to the guest once all of them completed.
+    status: Cell<u8>,
+}
+
+/// Action taken on a failed request with error policy `policy`.
+fn io_error_action(policy: IoErrorPolicy, nospace: bool) -> IoErrorPolicy {
+    match policy {
+        IoErrorPolicy::Enospc if nospace => IoErrorPolicy::Stop,
+        IoErrorPolicy::Enospc => IoErrorPolicy::Report,
+        _ => policy,
+    }
+}
+
+/// Handling of the failed requests of an IO handler.
+struct IoErrorHandling {
+    /// Id of the block device, reported in BLOCK_IO_ERROR events.
+    device: RefCell<String>,
+    /// Error policy of write, flush, discard and write zeroes requests.
+    werror: Cell<IoErrorPolicy>,
+    /// Error policy of read requests.
+    rerror: Cell<IoErrorPolicy>,
+    /// Requests held by the `stop` action, submitted again when the VM resumes.
+    held: RefCell<Vec<AioCb<AioCompleteCb>>>,
+}
+
+impl IoErrorHandling {
+    fn new(device: &str, werror: IoErrorPolicy, rerror: IoErrorPolicy) -> Self {
+        IoErrorHandling {
+            device: RefCell::new(device.to_string()),
+            werror: Cell::new(werror),
+            rerror: Cell::new(rerror),
+            held: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Take the action of the error policy on the request failed with `ret`. Return
+    /// the status reported to the guest, or None if the request is held.
+    fn handle_error(&self, aiocb: &AioCb<AioCompleteCb>, ret: i64) -> Option<u8> {
+        let is_write = aiocb.opcode != OpCode::Preadv;
+        let policy = if is_write {
+            self.werror.get()
+        } else {
+            self.rerror.get()
+        };
+        let nospace = ret == -(libc::ENOSPC as i64);
+        let action = io_error_action(policy, nospace);
+        if QmpChannel::is_connected() {
+            let io_error = qmp_schema::BlockIoError {
+                device: self.device.borrow().clone(),
+                operation: if is_write { "write" } else { "read" }.to_string(),
+                action: action.to_string(),
+                nospace,
+                reason: std::io::Error::from_raw_os_error(-ret as i32).to_string(),
+            };
+            event!(BlockIoError; io_error);
+        }
+
+        match action {
+            IoErrorPolicy::Ignore => Some(VIRTIO_BLK_S_OK),
+            IoErrorPolicy::Stop => {
+                self.held.borrow_mut().push(aiocb.clone());
+                if let Err(e) = MigrationManager::pause() {
+                    error!("Failed to stop VM on block IO error: {:?}", e);
+                }
+                None
+            }
+            _ => Some(VIRTIO_BLK_S_IOERR),
+        }
+    }
+}
+
+/// Accounting of one type of IO requests.
+#[derive(Default, Debug, Clone, Copy)]
+struct IoAccount {
+    /// Bytes transferred by the completed requests.
+    bytes: u64,
+    /// Number of completed requests.
+    operations: u64,
+    /// Number of failed requests.
+    failed_operations: u64,
+    /// Number of requests merged into another one.
+    merged: u64,
+    /// Sum of the latency of completed requests.
+    total_time_ns: u64,
+}
+
+/// IO statistics of a block device, shared by all its IO handlers.
+#[derive(Default, Debug)]
+pub struct BlockIoStats {
+    read: IoAccount,
+    write: IoAccount,
+    flush: IoAccount,
+    unmap: IoAccount,
+    /// Time of the last completed request.
+    last_access: Option<Instant>,
+}
+
+impl BlockIoStats {
+    /// Account the merged request list headed by `req`, which completes with `status`
+    /// after `latency_ns` nanoseconds.
+    fn account(&mut self, req: &Request, status: u8, latency_ns: u64) {
+        let account = match req.out_header.request_type {
+            VIRTIO_BLK_T_IN => &mut self.read,
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_WRITE_ZEROES => &mut self.write,
+            VIRTIO_BLK_T_FLUSH => &mut self.flush,
+            VIRTIO_BLK_T_DISCARD => &mut self.unmap,
+            _ => return,
+        };
+
+        let mut reqs = 0_u64;
+        let mut bytes = 0_u64;
+        let mut next = Some(req);
+        while let Some(req_raw) = next {
+            reqs += 1;
+            bytes += req_raw.data_len;
+            next = req_raw.next.as_ref().as_ref();
+        }
+
+        if status == VIRTIO_BLK_S_OK {
+            account.operations += reqs;
+            account.bytes += bytes;
+            account.total_time_ns += latency_ns * reqs;
+        } else {
+            account.failed_operations += reqs;
+        }
+        account.merged += reqs - 1;
+        self.last_access = Some(Instant::now());
+    }
+}
+
 #[derive(Clone)]
 pub struct AioCompleteCb {
     queue: Arc<Mutex<Queue>>,
@@ -111,6 +328,22 @@ pub struct AioCompleteCb {
     req: Rc<Request>,
     interrupt_cb: Arc<VirtioInterrupt>,
     driver_features: u64,
+    /// Set if the request is split into several host IOs.
+    split: Option<Rc<SplitIo>>,
+    /// IO statistics of the block device.
+    stats: Arc<Mutex<BlockIoStats>>,
+    /// Time when the request is fetched from the virtqueue.
+    start: Instant,
+    /// Handling of the request if it fails.
+    errors: Rc<IoErrorHandling>,
+    /// Counter of the host requests in flight on the image, if the request
+    /// is counted.
+    in_flight: Option<Arc<AtomicUsize>>,
+    /// The block job tracking the range of the request once it is written.
+    filter: Option<Arc<WriteFilter>>,
+    /// If the write zeroes sent to the NBD server may deallocate the range,
+    /// kept for sending it again once held on error.
+    unmap: bool,
 }
 
 impl AioCompleteCb {
@@ -120,6 +353,8 @@ impl AioCompleteCb {
         req: Rc<Request>,
         interrupt_cb: Arc<VirtioInterrupt>,
         driver_features: u64,
+        stats: Arc<Mutex<BlockIoStats>>,
+        errors: Rc<IoErrorHandling>,
     ) -> Self {
         AioCompleteCb {
             queue,
@@ -127,10 +362,23 @@ impl AioCompleteCb {
             req,
             interrupt_cb,
             driver_features,
+            split: None,
+            stats,
+            start: Instant::now(),
+            errors,
+            in_flight: None,
+            filter: None,
+            unmap: false,
         }
     }
 
     fn complete_request(&self, status: u8) -> Result<()> {
+        let latency_ns = self.start.elapsed().as_nanos() as u64;
+        self.stats
+            .lock()
+            .unwrap()
+            .account(&self.req, status, latency_ns);
+
         let mut req = Some(self.req.as_ref());
         while let Some(req_raw) = req {
             self.complete_one_request(req_raw, status)?;
@@ -180,6 +428,8 @@ struct Request {
     data_len: u64,
     in_len: u32,
     in_header: GuestAddress,
+    /// Write zeroes request allows deallocating the sectors.
+    unmap: bool,
     /// Point to the next merged Request.
     next: Box<Option<Request>>,
 }
@@ -227,6 +477,7 @@ impl Request {
             data_len: 0,
             in_len: 0,
             in_header,
+            unmap: false,
             next: Box::new(None),
         };
 
@@ -264,6 +515,9 @@ impl Request {
                     }
                 }
             }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                request.parse_discard_write_zeroes(handler, elem, status)?;
+            }
             VIRTIO_BLK_T_FLUSH => (),
             others => {
                 error!("Request type {} is not supported for block", others);
@@ -278,6 +532,67 @@ impl Request {
         Ok(request)
     }
 
+    /// Parse the segment of discard and write zeroes request. The request then
+    /// covers the sectors of the segment, like reads and writes.
+    fn parse_discard_write_zeroes(
+        &mut self,
+        handler: &BlockIoHandler,
+        elem: &mut Element,
+        status: &mut u8,
+    ) -> Result<()> {
+        let request_type = self.out_header.request_type;
+        let (feature, valid_flags) = match request_type {
+            VIRTIO_BLK_T_DISCARD => (VIRTIO_BLK_F_DISCARD, 0),
+            _ => (
+                VIRTIO_BLK_F_WRITE_ZEROES,
+                VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+            ),
+        };
+        if !virtio_has_feature(handler.driver_features, feature) {
+            error!("Request type {} is not supported for block", request_type);
+            *status = VIRTIO_BLK_S_UNSUPP;
+            return Ok(());
+        }
+
+        let data_iovec =
+            iov_discard_front(&mut elem.out_iovec, size_of::<RequestOutHeader>() as u64)
+                .with_context(|| "Empty data for block request")?;
+        let data_len: u64 = data_iovec.iter().map(|iov| u64::from(iov.len)).sum();
+        // Only one segment is allowed by max_discard_seg and max_write_zeroes_seg.
+        if data_len != size_of::<DiscardWriteZeroesSeg>() as u64 {
+            error!("Invalid segments for block request: length {}", data_len);
+            *status = VIRTIO_BLK_S_UNSUPP;
+            return Ok(());
+        }
+        let mut segment = DiscardWriteZeroesSeg::default();
+        iov_to_buf(&handler.mem_space, data_iovec, segment.as_mut_bytes())?;
+        let sector = LittleEndian::read_u64(segment.sector.as_bytes());
+        let num_sectors = LittleEndian::read_u32(segment.num_sectors.as_bytes());
+        let flags = LittleEndian::read_u32(segment.flags.as_bytes());
+
+        if flags & !valid_flags != 0 {
+            error!(
+                "Invalid flags {:#x} for block request type {}",
+                flags, request_type
+            );
+            *status = VIRTIO_BLK_S_UNSUPP;
+            return Ok(());
+        }
+        if num_sectors > MAX_REQUEST_SECTORS {
+            error!(
+                "Too many sectors {} for block request type {}",
+                num_sectors, request_type
+            );
+            *status = VIRTIO_BLK_S_IOERR;
+            return Ok(());
+        }
+
+        self.out_header.sector = sector;
+        self.data_len = u64::from(num_sectors) << SECTOR_SHIFT;
+        self.unmap = flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+        Ok(())
+    }
+
     fn execute(
         &self,
         iohandler: &mut BlockIoHandler,
@@ -309,26 +624,89 @@ impl Request {
             }
         }
 
-        let aio = &mut iohandler.aio;
-        let serial_num = &iohandler.serial_num;
+        // Locked until the request is submitted, so a block job holding the
+        // guest requests sees all those in flight.
+        let filter = iohandler.filter.clone();
+        let mut filter_state = filter.lock();
+        if filter_state.holds(iohandler.disk_image.as_ref()) {
+            // Executed again once the block job updates the handler.
+            iohandler.deferred.push(aiocb.iocompletecb);
+            return Ok(());
+        }
+        if matches!(
+            request_type,
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES
+        ) {
+            let (offset, len) = self.disk_range();
+            if filter_state.holds_write(offset, len) {
+                // Executed again once the block job copies the data overwritten.
+                iohandler.deferred.push(aiocb.iocompletecb);
+                return Ok(());
+            }
+            aiocb.iocompletecb.filter = filter_state.before_write().then(|| filter.clone());
+        }
+
+        if iohandler.nbd.is_some() && request_type != VIRTIO_BLK_T_GET_ID {
+            return self.execute_nbd(iohandler, aiocb);
+        }
+        if let Some(qcow2) = iohandler.qcow2.clone() {
+            match request_type {
+                VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+                    return self.execute_qcow2(iohandler, &qcow2, aiocb);
+                }
+                VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                    return self.execute_qcow2_zeroes(iohandler, &qcow2, aiocb);
+                }
+                _ => {}
+            }
+        }
+
+        let discard = iohandler.discard;
+        let write_zeroes = iohandler.write_zeroes;
         match request_type {
             VIRTIO_BLK_T_IN => {
                 aiocb.opcode = OpCode::Preadv;
-                aio.submit_request(aiocb)
+                iohandler
+                    .submit_request(aiocb)
                     .with_context(|| "Failed to process block request for reading")?;
             }
             VIRTIO_BLK_T_OUT => {
-                aiocb.opcode = OpCode::Pwritev;
-                aio.submit_request(aiocb)
+                aiocb.opcode = match write_zeroes {
+                    WriteZeroesState::Off => OpCode::Pwritev,
+                    _ if !iov_is_zero(&aiocb.iovec) => OpCode::Pwritev,
+                    WriteZeroesState::On => OpCode::WriteZeroes,
+                    WriteZeroesState::Unmap => OpCode::Discard,
+                };
+                iohandler
+                    .submit_request(aiocb)
                     .with_context(|| "Failed to process block request for writing")?;
             }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                if self.data_len == 0 {
+                    return aiocb.iocompletecb.complete_request(VIRTIO_BLK_S_OK);
+                }
+                aiocb.nbytes = self.data_len;
+                // Punching hole also zeroes the range, so it's used if unmap is allowed.
+                aiocb.opcode = if request_type == VIRTIO_BLK_T_DISCARD || (self.unmap && discard) {
+                    OpCode::Discard
+                } else {
+                    OpCode::WriteZeroes
+                };
+                iohandler
+                    .submit_request(aiocb)
+                    .with_context(|| "Failed to process block request for discard/write zeroes")?;
+            }
             VIRTIO_BLK_T_FLUSH => {
                 aiocb.opcode = OpCode::Fdsync;
-                aio.submit_request(aiocb)
+                iohandler
+                    .submit_request(aiocb)
                     .with_context(|| "Failed to process block request for flushing")?;
             }
             VIRTIO_BLK_T_GET_ID => {
-                let serial = serial_num.clone().unwrap_or_else(|| String::from(""));
+                let serial = iohandler
+                    .serial_num
+                    .clone()
+                    .unwrap_or_else(|| String::from(""));
                 let serial_vec = get_serial_num_config(&serial);
                 let status = iov_from_buf_direct(&self.iovec, &serial_vec).map_or_else(
                     |e| {
@@ -345,9 +723,194 @@ impl Request {
         Ok(())
     }
 
+    /// Send the request to the NBD server backing the device.
+    fn execute_nbd(
+        &self,
+        iohandler: &mut BlockIoHandler,
+        mut aiocb: AioCb<AioCompleteCb>,
+    ) -> Result<()> {
+        let request_type = self.out_header.request_type;
+        aiocb.opcode = match request_type {
+            VIRTIO_BLK_T_IN => OpCode::Preadv,
+            VIRTIO_BLK_T_OUT => OpCode::Pwritev,
+            VIRTIO_BLK_T_FLUSH => OpCode::Fdsync,
+            VIRTIO_BLK_T_DISCARD => OpCode::Discard,
+            VIRTIO_BLK_T_WRITE_ZEROES => OpCode::WriteZeroes,
+            // The illegal request type has been handled in method new().
+            _ => return Ok(()),
+        };
+        if matches!(
+            request_type,
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES
+        ) {
+            if self.data_len == 0 {
+                return aiocb.iocompletecb.complete_request(VIRTIO_BLK_S_OK);
+            }
+            aiocb.nbytes = self.data_len;
+        }
+        aiocb.iocompletecb.unmap = self.unmap && iohandler.discard;
+        iohandler
+            .submit_nbd_request(aiocb)
+            .with_context(|| "Failed to send block request to NBD server")
+    }
+
+    /// Discard or zero a range of a qcow2 image. Mostly the metadata is
+    /// updated, so it's done synchronously.
+    fn execute_qcow2_zeroes(
+        &self,
+        iohandler: &mut BlockIoHandler,
+        qcow2: &Arc<Mutex<Qcow2Image>>,
+        aiocb: AioCb<AioCompleteCb>,
+    ) -> Result<()> {
+        let mut image = qcow2.lock().unwrap();
+        if image.frozen() {
+            // Executed again once the block job updates the handler.
+            iohandler.deferred.push(aiocb.iocompletecb);
+            return Ok(());
+        }
+        let offset = aiocb.offset as u64;
+        let discard = self.out_header.request_type == VIRTIO_BLK_T_DISCARD;
+        // Discards leave the clusters partially covered unchanged.
+        let ready = if discard {
+            Ok(!image.copying(offset, self.data_len))
+        } else {
+            iohandler.ready_to_write(qcow2, &mut image, offset, self.data_len)
+        };
+        let result = match ready {
+            Ok(false) => {
+                // Executed again once the clusters are copied.
+                iohandler.deferred.push(aiocb.iocompletecb);
+                return Ok(());
+            }
+            Ok(true) if discard => image.discard(offset, self.data_len),
+            Ok(true) => image.write_zeroes(offset, self.data_len, self.unmap && iohandler.discard),
+            Err(e) => Err(e),
+        };
+        drop(image);
+        let status = match result {
+            Ok(()) => VIRTIO_BLK_S_OK,
+            Err(e) => {
+                error!("Failed to discard or zero qcow2 image, {:?}", e);
+                VIRTIO_BLK_S_IOERR
+            }
+        };
+        aiocb.iocompletecb.complete_request(status)
+    }
+
+    /// Submit a read or write of a qcow2 image, split into the host ranges
+    /// the request maps onto.
+    fn execute_qcow2(
+        &self,
+        iohandler: &mut BlockIoHandler,
+        qcow2: &Arc<Mutex<Qcow2Image>>,
+        mut aiocb: AioCb<AioCompleteCb>,
+    ) -> Result<()> {
+        let mut image = qcow2.lock().unwrap();
+        if image.frozen() {
+            // Executed again once the block job updates the handler.
+            iohandler.deferred.push(aiocb.iocompletecb);
+            return Ok(());
+        }
+        let (offset, len) = (aiocb.offset as u64, aiocb.nbytes);
+        let (mapped, opcode) = match self.out_header.request_type {
+            VIRTIO_BLK_T_IN => (image.map_read(offset, len), OpCode::Preadv),
+            _ => match iohandler.ready_to_write(qcow2, &mut image, offset, len) {
+                Ok(false) => {
+                    // Executed again once the clusters are copied.
+                    iohandler.deferred.push(aiocb.iocompletecb);
+                    return Ok(());
+                }
+                ready => (
+                    ready.and_then(|_| image.map_write(offset, len)),
+                    OpCode::Pwritev,
+                ),
+            },
+        };
+        let ranges = match mapped {
+            Ok(ranges) => ranges,
+            Err(e) => {
+                error!("Failed to map block request on qcow2 image, {:?}", e);
+                return aiocb.iocompletecb.complete_request(VIRTIO_BLK_S_IOERR);
+            }
+        };
+
+        let split = Rc::new(SplitIo {
+            pending: Cell::new(0),
+            status: Cell::new(VIRTIO_BLK_S_OK),
+        });
+        let mut completecb = aiocb.iocompletecb.clone();
+        completecb.split = Some(split.clone());
+        completecb.in_flight = Some(iohandler.in_flight.clone());
+        let mut iovec: VecDeque<Iovec> = aiocb.iovec.drain(..).collect();
+        let mut host_reqs = Vec::new();
+        for range in ranges {
+            let range_iovec = iov_split_front(&mut iovec, range.len());
+            match range {
+                DiskRange::Data {
+                    fd,
+                    direct,
+                    offset,
+                    len,
+                } => host_reqs.push(AioCb {
+                    direct,
+                    req_align: aiocb.req_align,
+                    buf_align: aiocb.buf_align,
+                    file_fd: fd,
+                    opcode,
+                    iovec: range_iovec,
+                    offset: offset as usize,
+                    nbytes: len,
+                    user_data: 0,
+                    iocompletecb: completecb.clone(),
+                }),
+                // Only reads are mapped onto zero ranges.
+                DiskRange::Zero { .. } => {
+                    for iov in range_iovec {
+                        // SAFETY: the iovec is mapped from guest memory in Request::new().
+                        unsafe {
+                            std::ptr::write_bytes(iov.iov_base as *mut u8, 0, iov.iov_len as usize)
+                        };
+                    }
+                }
+            }
+        }
+
+        if host_reqs.is_empty() {
+            drop(image);
+            return aiocb.iocompletecb.complete_request(VIRTIO_BLK_S_OK);
+        }
+        split.pending.set(host_reqs.len());
+        // Counted before the image is unlocked, so a block job freezing the
+        // image sees all the requests it has to wait for.
+        iohandler
+            .in_flight
+            .fetch_add(host_reqs.len(), Ordering::SeqCst);
+        drop(image);
+        for host_req in host_reqs {
+            iohandler
+                .aio
+                .submit_request(host_req)
+                .with_context(|| "Failed to process block request on qcow2 image")?;
+        }
+        Ok(())
+    }
+
+    /// Direction of the request for IO throttling, true for write. None if the
+    /// request is not throttled.
+    fn throttle_direction(&self) -> Option<bool> {
+        match self.out_header.request_type {
+            VIRTIO_BLK_T_IN => Some(false),
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_WRITE_ZEROES => Some(true),
+            _ => None,
+        }
+    }
+
     fn io_range_valid(&self, disk_sectors: u64) -> bool {
         match self.out_header.request_type {
-            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+            VIRTIO_BLK_T_IN
+            | VIRTIO_BLK_T_OUT
+            | VIRTIO_BLK_T_DISCARD
+            | VIRTIO_BLK_T_WRITE_ZEROES => {
                 if self.data_len % SECTOR_SIZE != 0 {
                     error!("Failed to process block request with size not aligned to 512B");
                     return false;
@@ -370,11 +933,56 @@ impl Request {
         }
     }
 
+    /// Offset and length in bytes of the disk range the merged requests access.
+    fn disk_range(&self) -> (u64, u64) {
+        let mut len = 0;
+        let mut req = Some(self);
+        while let Some(req_raw) = req {
+            len += req_raw.data_len;
+            req = req_raw.next.as_ref().as_ref();
+        }
+        (self.out_header.sector << SECTOR_SHIFT, len)
+    }
+
     fn get_req_sector_num(&self) -> u64 {
         self.data_len / SECTOR_SIZE
     }
 }
 
+/// Check whether the data of `iovec` is all zero.
+fn iov_is_zero(iovec: &[Iovec]) -> bool {
+    iovec.iter().all(|iov| {
+        // SAFETY: the iovec is mapped from guest memory in Request::new().
+        let buf =
+            unsafe { std::slice::from_raw_parts(iov.iov_base as *const u8, iov.iov_len as usize) };
+        buf.iter().all(|&b| b == 0)
+    })
+}
+
+/// Take `len` bytes off the front of `iovec`.
+fn iov_split_front(iovec: &mut VecDeque<Iovec>, mut len: u64) -> Vec<Iovec> {
+    let mut front = Vec::new();
+    while len > 0 {
+        let iov = match iovec.front_mut() {
+            Some(iov) => iov,
+            None => break,
+        };
+        if iov.iov_len <= len {
+            len -= iov.iov_len;
+            front.push(iovec.pop_front().unwrap());
+        } else {
+            front.push(Iovec {
+                iov_base: iov.iov_base,
+                iov_len: len,
+            });
+            iov.iov_base += len;
+            iov.iov_len -= len;
+            len = 0;
+        }
+    }
+    front
+}
+
 /// Control block of Block IO.
 struct BlockIoHandler {
     /// The virtqueue.
@@ -385,6 +993,8 @@ struct BlockIoHandler {
     mem_space: Arc<AddressSpace>,
     /// The image file opened by the block device.
     disk_image: Option<Arc<File>>,
+    /// Mapping of the image if it is in qcow2 format.
+    qcow2: Option<Arc<Mutex<Qcow2Image>>>,
     /// The align requirement of request(offset/len).
     pub req_align: u32,
     /// The align requirement of buffer(iova_base).
@@ -395,6 +1005,10 @@ struct BlockIoHandler {
     serial_num: Option<String>,
     /// If use direct access io.
     direct: bool,
+    /// Pass discard requests down to the image file.
+    discard: bool,
+    /// Detect and convert zeroed writes into write zeroes requests.
+    write_zeroes: WriteZeroesState,
     /// Aio context.
     aio: Box<Aio<AioCompleteCb>>,
     /// Bit mask of features negotiated by the backend and the frontend.
@@ -409,8 +1023,30 @@ struct BlockIoHandler {
     interrupt_cb: Arc<VirtioInterrupt>,
     /// thread name of io handler
     iothread: Option<String>,
-    /// Using the leak bucket to implement IO limits
-    leak_bucket: Option<LeakBucket>,
+    /// IO limits of the block device.
+    throttle: Throttle,
+    /// IO statistics of the block device.
+    stats: Arc<Mutex<BlockIoStats>>,
+    /// Error policies and the requests held on error.
+    errors: Rc<IoErrorHandling>,
+    /// Eventfd to submit the held requests again.
+    retry_evt: Arc<EventFd>,
+    /// Number of host requests in flight on the image.
+    in_flight: Arc<AtomicUsize>,
+    /// Requests deferred while the image is frozen by a block job, or while
+    /// the qcow2 clusters they write are copied.
+    deferred: Vec<AioCompleteCb>,
+    /// Eventfd to execute the deferred requests again.
+    deferred_evt: Arc<EventFd>,
+    /// Sends the qcow2 clusters to copy to the copy thread, which is spawned
+    /// on the first copy.
+    copier: Option<Sender<(Arc<Mutex<Qcow2Image>>, ClusterCopy)>>,
+    /// Hook of the block job on the guest writes.
+    filter: Arc<WriteFilter>,
+    /// Client of the NBD export backing the device.
+    nbd: Option<NbdClient<AioCompleteCb>>,
+    /// Eventfd for the requests answered by the NBD server.
+    nbd_evt: Arc<EventFd>,
 }
 
 impl BlockIoHandler {
@@ -473,26 +1109,30 @@ impl BlockIoHandler {
                 break;
             }
 
-            // limit io operations if iops is configured
-            if let Some(lb) = self.leak_bucket.as_mut() {
-                if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
-                    if lb.throttled(ctx, 1_u64) {
-                        queue.vring.push_back();
-                        break;
-                    }
-                };
-            }
-
             // Init and put valid request into request queue.
             let mut status = VIRTIO_BLK_S_OK;
             let req = Request::new(self, &mut elem, &mut status)?;
-            if status != VIRTIO_BLK_S_OK {
+            if status == VIRTIO_BLK_S_OK {
+                // Limit IO if throttling is configured, the request is fetched again
+                // once the throttle timer expires.
+                if let Some(is_write) = req.throttle_direction() {
+                    if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
+                        if self.throttle.throttled(ctx, is_write) {
+                            queue.vring.push_back();
+                            break;
+                        }
+                    }
+                    self.throttle.account(is_write, req.data_len);
+                }
+            } else {
                 let aiocompletecb = AioCompleteCb::new(
                     self.queue.clone(),
                     self.mem_space.clone(),
                     Rc::new(req),
                     self.interrupt_cb.clone(),
                     self.driver_features,
+                    self.stats.clone(),
+                    self.errors.clone(),
                 );
                 // unlock queue, because it will be hold below.
                 drop(queue);
@@ -513,38 +1153,141 @@ impl BlockIoHandler {
 
         let merge_req_queue = self.merge_req_queue(req_queue);
         for req in merge_req_queue.into_iter() {
-            let req_rc = Rc::new(req);
             let aiocompletecb = AioCompleteCb::new(
                 self.queue.clone(),
                 self.mem_space.clone(),
-                req_rc.clone(),
+                Rc::new(req),
                 self.interrupt_cb.clone(),
                 self.driver_features,
+                self.stats.clone(),
+                self.errors.clone(),
             );
-            if let Some(disk_img) = self.disk_image.as_ref() {
-                let aiocb = AioCb {
-                    direct: self.direct,
-                    req_align: self.req_align,
-                    buf_align: self.buf_align,
-                    file_fd: disk_img.as_raw_fd(),
-                    opcode: OpCode::Noop,
-                    iovec: Vec::new(),
-                    offset: (req_rc.out_header.sector << SECTOR_SHIFT) as usize,
-                    nbytes: 0,
-                    user_data: 0,
-                    iocompletecb: aiocompletecb,
-                };
-                req_rc.execute(self, aiocb)?;
-            } else {
-                warn!("Failed to execute block request, disk_img not specified");
-                aiocompletecb.complete_request(VIRTIO_BLK_S_IOERR)?;
-            }
+            self.execute_request(aiocompletecb)?;
         }
         self.aio.flush_request()?;
 
         Ok(done)
     }
 
+    fn execute_request(&mut self, aiocompletecb: AioCompleteCb) -> Result<()> {
+        let req_rc = aiocompletecb.req.clone();
+        let file_fd = match self.disk_image.as_ref() {
+            Some(disk_img) => Some(disk_img.as_raw_fd()),
+            // Requests to the NBD server have no file.
+            None if self.nbd.is_some() => Some(-1),
+            None => None,
+        };
+        if let Some(file_fd) = file_fd {
+            let aiocb = AioCb {
+                direct: self.direct,
+                req_align: self.req_align,
+                buf_align: self.buf_align,
+                file_fd,
+                opcode: OpCode::Noop,
+                iovec: Vec::new(),
+                offset: (req_rc.out_header.sector << SECTOR_SHIFT) as usize,
+                nbytes: 0,
+                user_data: 0,
+                iocompletecb: aiocompletecb,
+            };
+            req_rc.execute(self, aiocb)
+        } else {
+            warn!("Failed to execute block request, disk_img not specified");
+            aiocompletecb.complete_request(VIRTIO_BLK_S_IOERR)
+        }
+    }
+
+    /// Submit a host request of the raw image, counted in flight.
+    fn submit_request(&mut self, mut aiocb: AioCb<AioCompleteCb>) -> Result<()> {
+        aiocb.iocompletecb.in_flight = Some(self.in_flight.clone());
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        self.aio.submit_request(aiocb)
+    }
+
+    /// Send a request to the NBD server, write zeroes may deallocate the range
+    /// if the unmap flag of the request is set.
+    fn submit_nbd_request(&mut self, aiocb: AioCb<AioCompleteCb>) -> Result<()> {
+        // Writes are done with FUA instead of flushing the image file, if the
+        // driver does not accept FLUSH feature.
+        let fua = !virtio_has_feature(self.driver_features, VIRTIO_BLK_F_FLUSH);
+        let unmap = aiocb.iocompletecb.unmap;
+        match self.nbd.as_mut() {
+            Some(nbd) => nbd.submit_request(aiocb, fua, unmap),
+            None => aiocb.iocompletecb.complete_request(VIRTIO_BLK_S_IOERR),
+        }
+    }
+
+    /// Serve the requests by a client of `export`, unless it is the current one.
+    fn set_nbd_export(&mut self, export: Option<NbdExport>) -> Result<()> {
+        if self.nbd.as_ref().map(|nbd| nbd.export()) == export.as_ref() {
+            return Ok(());
+        }
+        if let Some(mut nbd) = self.nbd.take() {
+            nbd.close()?;
+        }
+        self.nbd = export
+            .map(|export| {
+                NbdClient::new(export, Arc::new(Self::complete_func), self.nbd_evt.clone())
+            })
+            .transpose()?;
+        Ok(())
+    }
+
+    /// Execute the requests deferred by a block job or by the cluster copies
+    /// on the current image.
+    fn execute_deferred_requests(&mut self) -> Result<()> {
+        for aiocompletecb in std::mem::take(&mut self.deferred) {
+            self.execute_request(aiocompletecb)?;
+        }
+        self.aio.flush_request()
+    }
+
+    /// Check if a guest write of `len` bytes at `offset` can be mapped on the
+    /// qcow2 image now. Otherwise it waits for the clusters being copied, or
+    /// for the copies of the clusters it partially writes, which are started
+    /// here and run by the copy thread so the iothread doesn't wait for them.
+    fn ready_to_write(
+        &mut self,
+        qcow2: &Arc<Mutex<Qcow2Image>>,
+        image: &mut Qcow2Image,
+        offset: u64,
+        len: u64,
+    ) -> Result<bool> {
+        if image.copying(offset, len) {
+            return Ok(false);
+        }
+        let copies = image.start_copies(offset, len)?;
+        if copies.is_empty() {
+            return Ok(true);
+        }
+        if self.copier.is_none() {
+            let (sender, receiver) = channel();
+            let in_flight = self.in_flight.clone();
+            let deferred_evt = self.deferred_evt.clone();
+            let spawned = thread::Builder::new()
+                .name("qcow2-copy".to_string())
+                .spawn(move || run_cluster_copies(&receiver, &in_flight, &deferred_evt));
+            if let Err(e) = spawned {
+                for copy in copies {
+                    image.end_copy(copy, false)?;
+                }
+                return Err(e).with_context(|| "Failed to spawn qcow2 copy thread");
+            }
+            self.copier = Some(sender);
+        }
+        for copy in copies {
+            // Counted before the image is unlocked, so a block job freezing
+            // the image waits for the copy.
+            self.in_flight.fetch_add(1, Ordering::SeqCst);
+            let sent = self.copier.as_ref().unwrap().send((qcow2.clone(), copy));
+            if let Err(SendError((_, copy))) = sent {
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                image.end_copy(copy, false)?;
+            }
+        }
+        Ok(false)
+    }
+
     fn process_queue_suppress_notify(&mut self) -> Result<bool> {
         let mut done = false;
         let start_time = Instant::now();
@@ -584,12 +1327,8 @@ impl BlockIoHandler {
             )?;
 
             // See whether we have been throttled.
-            if let Some(lb) = self.leak_bucket.as_mut() {
-                if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
-                    if lb.throttled(ctx, 0) {
-                        break;
-                    }
-                }
+            if self.throttle.timer_started() {
+                break;
             }
         }
         Ok(done)
@@ -609,27 +1348,69 @@ impl BlockIoHandler {
     }
 
     fn complete_func(aiocb: &AioCb<AioCompleteCb>, ret: i64) -> Result<()> {
+        let complete_cb = &aiocb.iocompletecb;
         let mut status = if ret < 0 {
-            VIRTIO_BLK_S_IOERR
+            match complete_cb.errors.handle_error(aiocb, ret) {
+                Some(status) => status,
+                None => return Ok(()),
+            }
         } else {
             VIRTIO_BLK_S_OK
         };
+        // Uncounted after the block job sees the written range, as it waits
+        // for the requests in flight to get all the ranges.
+        let uncount = || {
+            if let Some(in_flight) = complete_cb.in_flight.as_ref() {
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            }
+        };
 
-        let complete_cb = &aiocb.iocompletecb;
         // When driver does not accept FLUSH feature, the device must be of
         // writethrough cache type, so flush data before updating used ring.
         if !virtio_has_feature(complete_cb.driver_features, VIRTIO_BLK_F_FLUSH)
-            && aiocb.opcode == OpCode::Pwritev
+            && aiocb.opcode != OpCode::Preadv
+            && aiocb.opcode != OpCode::Fdsync
             && ret >= 0
+            && aiocb.file_fd >= 0
             && raw_datasync(aiocb.file_fd) < 0
         {
             error!("Failed to flush data before send response to guest.");
             status = VIRTIO_BLK_S_IOERR;
         }
 
+        if let Some(split) = complete_cb.split.as_ref() {
+            if status != VIRTIO_BLK_S_OK {
+                split.status.set(status);
+            }
+            split.pending.set(split.pending.get() - 1);
+            if split.pending.get() != 0 {
+                uncount();
+                return Ok(());
+            }
+            status = split.status.get();
+        }
+
+        if let Some(filter) = complete_cb.filter.as_ref() {
+            let (offset, len) = complete_cb.req.disk_range();
+            filter.written(offset, len);
+        }
+        uncount();
         complete_cb.complete_request(status)
     }
 
+    /// Submit the requests held by the `stop` error policy again.
+    fn retry_held_requests(&mut self) -> Result<()> {
+        let held = self.errors.held.take();
+        for aiocb in held {
+            if self.nbd.is_some() {
+                self.submit_nbd_request(aiocb)?;
+            } else {
+                self.aio.submit_request(aiocb)?;
+            }
+        }
+        self.aio.flush_request()
+    }
+
     fn aio_complete_handler(&mut self) -> Result<bool> {
         self.aio.handle_complete().map_err(|e| {
             report_virtio_error(
@@ -641,30 +1422,85 @@ impl BlockIoHandler {
         })
     }
 
+    fn nbd_complete_handler(&mut self) -> Result<bool> {
+        let nbd = match self.nbd.as_mut() {
+            Some(nbd) => nbd,
+            None => return Ok(false),
+        };
+        nbd.handle_complete().map_err(|e| {
+            report_virtio_error(
+                self.interrupt_cb.clone(),
+                self.driver_features,
+                &self.device_broken,
+            );
+            e
+        })
+    }
+
     fn update_evt_handler(&mut self) {
+        // The requests in flight may use the files replaced below.
+        if let Err(e) = self.aio.drain_request() {
+            error!("Failed to drain block IO for updating handler {:?}", e);
+            report_virtio_error(
+                self.interrupt_cb.clone(),
+                self.driver_features,
+                &self.device_broken,
+            );
+            return;
+        }
+
+        let mut config = self.receiver.recv();
+        // Only the latest config matters if several are sent at once.
+        while let Ok(latest) = self.receiver.try_recv() {
+            config = Ok(latest);
+        }
         let aio_engine;
-        match self.receiver.recv() {
-            Ok((image, req_align, buf_align, disk_sectors, serial_num, direct, aio)) => {
-                self.disk_sectors = disk_sectors;
-                self.disk_image = image;
-                self.req_align = req_align;
-                self.buf_align = buf_align;
-                self.serial_num = serial_num;
-                self.direct = direct;
-                aio_engine = aio;
+        let nbd_export;
+        match config {
+            Ok(config) => {
+                self.disk_sectors = config.disk_sectors;
+                self.disk_image = config.image;
+                self.qcow2 = config.qcow2;
+                self.req_align = config.req_align;
+                self.buf_align = config.buf_align;
+                self.serial_num = config.serial_num;
+                self.direct = config.direct;
+                self.discard = config.discard;
+                self.write_zeroes = config.write_zeroes;
+                self.errors.device.replace(config.device);
+                self.errors.werror.set(config.werror);
+                self.errors.rerror.set(config.rerror);
+                nbd_export = config.nbd;
+                aio_engine = config.aio;
             }
             Err(e) => {
                 error!("Failed to receive config in updating handler {:?}", e);
                 self.disk_sectors = 0;
                 self.disk_image = None;
+                self.qcow2 = None;
                 self.req_align = 1;
                 self.buf_align = 1;
                 self.serial_num = None;
                 self.direct = true;
+                self.discard = false;
+                self.write_zeroes = WriteZeroesState::Off;
+                self.errors.werror.set(IoErrorPolicy::Report);
+                self.errors.rerror.set(IoErrorPolicy::Report);
+                nbd_export = None;
                 aio_engine = AioEngine::Native;
             }
         };
 
+        if let Err(e) = self.set_nbd_export(nbd_export) {
+            error!("Failed to set NBD client in updating handler {:?}", e);
+            report_virtio_error(
+                self.interrupt_cb.clone(),
+                self.driver_features,
+                &self.device_broken,
+            );
+            return;
+        }
+
         if self.aio.get_engine() != aio_engine {
             match Aio::new(Arc::new(Self::complete_func), aio_engine) {
                 Ok(aio) => {
@@ -696,13 +1532,47 @@ impl BlockIoHandler {
             return;
         }
 
+        if let Err(ref e) = self.execute_deferred_requests() {
+            error!("Failed to handle deferred block IO {:?}", e);
+        }
         if let Err(ref e) = self.process_queue() {
             error!("Failed to handle block IO for updating handler {:?}", e);
         }
     }
 }
 
-fn build_event_notifier(
+/// Copy the qcow2 clusters sent by an IO handler until it is dropped.
+fn run_cluster_copies(
+    copies: &Receiver<(Arc<Mutex<Qcow2Image>>, ClusterCopy)>,
+    in_flight: &AtomicUsize,
+    deferred_evt: &EventFd,
+) {
+    while let Ok((qcow2, copy)) = copies.recv() {
+        let copied = match copy.run() {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Failed to copy qcow2 cluster before writing it, {:?}", e);
+                false
+            }
+        };
+        if let Err(e) = qcow2.lock().unwrap().end_copy(copy, copied) {
+            error!("Failed to map copied qcow2 cluster, {:?}", e);
+        }
+        in_flight.fetch_sub(1, Ordering::SeqCst);
+        if let Err(e) = deferred_evt.write(1) {
+            error!("Failed to notify the end of qcow2 cluster copy, {:?}", e);
+        }
+    }
+}
+
+impl Drop for BlockIoHandler {
+    fn drop(&mut self) {
+        // The held requests refer to the error handling, release them to break the cycle.
+        self.errors.held.borrow_mut().clear();
+    }
+}
+
+fn build_event_notifier(
     fd: RawFd,
     handlers: Vec<Rc<NotifierCallback>>,
     handler_poll: Option<Box<NotifierCallback>>,
@@ -779,25 +1649,44 @@ impl EventNotifierHelper for BlockIoHandler {
             Some(handler_iopoll),
         ));
 
-        // Register timer event notifier for IO limits
-        if let Some(lb) = handler_raw.leak_bucket.as_ref() {
-            let h_clone = handler.clone();
-            let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
-                read_fd(fd);
-                let mut h_lock = h_clone.lock().unwrap();
-                if h_lock.device_broken.load(Ordering::SeqCst) {
-                    return None;
-                }
-                if let Some(lb) = h_lock.leak_bucket.as_mut() {
-                    lb.clear_timer();
-                }
-                if let Err(ref e) = h_lock.process_queue() {
-                    error!("Failed to handle block IO {:?}", e);
-                }
-                None
-            });
-            notifiers.push(build_event_notifier(lb.as_raw_fd(), vec![h], None));
-        }
+        // Register timer event notifier for IO limits, which may be set at runtime.
+        let h_clone = handler.clone();
+        let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            let mut h_lock = h_clone.lock().unwrap();
+            if h_lock.device_broken.load(Ordering::SeqCst) {
+                return None;
+            }
+            h_lock.throttle.clear_timer();
+            if let Err(ref e) = h_lock.process_queue() {
+                error!("Failed to handle block IO {:?}", e);
+            }
+            None
+        });
+        notifiers.push(build_event_notifier(
+            handler_raw.throttle.as_raw_fd(),
+            vec![h],
+            None,
+        ));
+
+        // Register event notifier for retrying the requests held on error.
+        let h_clone = handler.clone();
+        let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            let mut h_lock = h_clone.lock().unwrap();
+            if h_lock.device_broken.load(Ordering::SeqCst) {
+                return None;
+            }
+            if let Err(ref e) = h_lock.retry_held_requests() {
+                error!("Failed to retry block IO {:?}", e);
+            }
+            None
+        });
+        notifiers.push(build_event_notifier(
+            handler_raw.retry_evt.as_raw_fd(),
+            vec![h],
+            None,
+        ));
 
         // Register event notifier for aio.
         let h_clone = handler.clone();
@@ -841,6 +1730,44 @@ impl EventNotifierHelper for BlockIoHandler {
             Some(handler_iopoll),
         ));
 
+        // Register event notifier for the requests answered by the NBD server.
+        let h_clone = handler.clone();
+        let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            let mut h_lock = h_clone.lock().unwrap();
+            if h_lock.device_broken.load(Ordering::SeqCst) {
+                return None;
+            }
+            if let Err(ref e) = h_lock.nbd_complete_handler() {
+                error!("Failed to handle NBD replies {:?}", e);
+            }
+            None
+        });
+        notifiers.push(build_event_notifier(
+            handler_raw.nbd_evt.as_raw_fd(),
+            vec![h],
+            None,
+        ));
+
+        // Register event notifier for executing the deferred requests again.
+        let h_clone = handler.clone();
+        let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            let mut h_lock = h_clone.lock().unwrap();
+            if h_lock.device_broken.load(Ordering::SeqCst) {
+                return None;
+            }
+            if let Err(ref e) = h_lock.execute_deferred_requests() {
+                error!("Failed to handle deferred block IO {:?}", e);
+            }
+            None
+        });
+        notifiers.push(build_event_notifier(
+            handler_raw.deferred_evt.as_raw_fd(),
+            vec![h],
+            None,
+        ));
+
         notifiers
     }
 }
@@ -921,6 +1848,8 @@ pub struct Block {
     blk_cfg: BlkDevConfig,
     /// Image file opened.
     disk_image: Option<Arc<File>>,
+    /// Mapping of the image if it is in qcow2 format.
+    qcow2: Option<Arc<Mutex<Qcow2Image>>>,
     /// The align requirement of request(offset/len).
     pub req_align: u32,
     /// The align requirement of buffer(iova_base).
@@ -935,12 +1864,81 @@ pub struct Block {
     senders: Vec<Sender<SenderConfig>>,
     /// Eventfd for config space update.
     update_evts: Vec<Arc<EventFd>>,
+    /// Eventfd for retrying the requests held on error.
+    retry_evts: Vec<Arc<EventFd>>,
+    /// Eventfd for executing the deferred requests again.
+    deferred_evts: Vec<Arc<EventFd>>,
     /// Eventfd for device deactivate.
     deactivate_evts: Vec<RawFd>,
     /// Device is broken or not.
     broken: Arc<AtomicBool>,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    /// IO statistics of the block device.
+    stats: Arc<Mutex<BlockIoStats>>,
+    /// Throttle state shared by the IO handlers.
+    throttle: Arc<Mutex<ThrottleState>>,
+    /// Number of host requests in flight on the image.
+    in_flight: Arc<AtomicUsize>,
+    /// Hook of the block job on the guest writes, shared by the IO handlers.
+    pub(crate) filter: Arc<WriteFilter>,
+    /// The NBD export backing the device.
+    nbd: Option<NbdExport>,
+}
+
+/// An image file opened for the block device.
+pub(crate) struct DiskImage {
+    file: Arc<File>,
+    qcow2: Option<Arc<Mutex<Qcow2Image>>>,
+    /// Size of the disk seen by the guest, in bytes.
+    size: u64,
+    req_align: u32,
+    buf_align: u32,
+}
+
+impl DiskImage {
+    pub(crate) fn qcow2(&self) -> Option<&Arc<Mutex<Qcow2Image>>> {
+        self.qcow2.as_ref()
+    }
+}
+
+/// Images of a commit job: the active qcow2 overlay of the device, and its
+/// backing image opened for writing.
+pub(crate) struct CommitImages {
+    pub(crate) top: Arc<Mutex<Qcow2Image>>,
+    pub(crate) base: DiskImage,
+    /// The drive file the backing image is opened from.
+    pub(crate) base_path: String,
+    pub(crate) base_format: DiskFormat,
+    /// Number of host requests in flight on the overlay.
+    pub(crate) in_flight: Arc<AtomicUsize>,
+}
+
+/// Images of a mirror or backup job: the current image of the device, and the
+/// target opened for writing.
+pub(crate) struct CopyImages {
+    /// The current image, identifying the guest requests a job holds.
+    pub(crate) file: Arc<File>,
+    pub(crate) qcow2: Option<Arc<Mutex<Qcow2Image>>>,
+    /// The drive file of the current image.
+    pub(crate) path: String,
+    /// Size of the disk, in bytes.
+    pub(crate) size: u64,
+    pub(crate) target: DiskImage,
+    /// The drive file the target is opened from.
+    pub(crate) target_path: String,
+    pub(crate) target_format: DiskFormat,
+    /// Number of host requests in flight on the current image.
+    pub(crate) in_flight: Arc<AtomicUsize>,
+    pub(crate) filter: Arc<WriteFilter>,
+}
+
+/// Check that `backing` names the same file as `path`.
+fn same_file(backing: &str, path: &str) -> bool {
+    match (std::fs::canonicalize(backing), std::fs::canonicalize(path)) {
+        (Ok(backing), Ok(path)) => backing == path,
+        _ => false,
+    }
 }
 
 impl Block {
@@ -951,6 +1949,7 @@ impl Block {
         Self {
             blk_cfg,
             disk_image: None,
+            qcow2: None,
             req_align: 1,
             buf_align: 1,
             disk_sectors: 0,
@@ -958,12 +1957,61 @@ impl Block {
             interrupt_cb: None,
             senders: Vec::new(),
             update_evts: Vec::new(),
+            retry_evts: Vec::new(),
+            deferred_evts: Vec::new(),
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
             drive_files,
+            stats: Arc::new(Mutex::new(BlockIoStats::default())),
+            throttle: Arc::new(Mutex::new(ThrottleState::new(ThrottleConfig::default()))),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            filter: Arc::new(WriteFilter::default()),
+            nbd: None,
         }
     }
 
+    pub(crate) fn id(&self) -> &str {
+        &self.blk_cfg.id
+    }
+
+    /// Open the drive file at `path` as the image of the device.
+    fn open_image(&self, path: &str, format: DiskFormat) -> Result<DiskImage> {
+        let drive_files = self.drive_files.lock().unwrap();
+        let mut file = VmConfig::fetch_drive_file(&drive_files, path)?;
+        let (req_align, buf_align) = VmConfig::fetch_drive_align(&drive_files, path)?;
+        let disk_size = file
+            .seek(SeekFrom::End(0))
+            .with_context(|| "Failed to seek the end for block")?;
+        let file = Arc::new(file);
+        let (size, qcow2) = match format {
+            DiskFormat::Raw => (disk_size, None),
+            DiskFormat::Qcow2 => {
+                let qcow2 = Qcow2Image::open(
+                    path,
+                    file.clone(),
+                    self.blk_cfg.read_only,
+                    self.blk_cfg.direct,
+                )?;
+                (qcow2.virtual_size(), Some(Arc::new(Mutex::new(qcow2))))
+            }
+        };
+        Ok(DiskImage {
+            file,
+            qcow2,
+            size,
+            req_align,
+            buf_align,
+        })
+    }
+
+    fn set_image(&mut self, image: DiskImage) {
+        self.disk_image = Some(image.file);
+        self.qcow2 = image.qcow2;
+        self.disk_sectors = image.size >> SECTOR_SHIFT;
+        self.req_align = image.req_align;
+        self.buf_align = image.buf_align;
+    }
+
     fn build_device_config_space(&mut self) {
         // capacity: 64bits
         let num_sectors = DUMMY_IMG_SIZE >> SECTOR_SHIFT;
@@ -971,6 +2019,437 @@ impl Block {
         // seg_max = queue_size - 2: 32bits
         self.state.config_space.seg_max = self.queue_size() as u32 - 2;
     }
+
+    /// Offer discard and write zeroes, which are done by fallocate on raw image
+    /// files and by updating the L2 tables of qcow2 images. NBD exports
+    /// support them if the server does.
+    fn build_discard_config_space(&mut self) {
+        let config = &mut self.state.config_space;
+        config.max_discard_sectors = 0;
+        config.max_discard_seg = 0;
+        config.discard_sector_alignment = 0;
+        config.max_write_zeroes_sectors = 0;
+        config.max_write_zeroes_seg = 0;
+        config.write_zeroes_may_unmap = 0;
+        if self.blk_cfg.read_only {
+            return;
+        }
+        let (can_discard, can_write_zeroes) = match self.nbd.as_ref() {
+            Some(export) => (export.can_trim(), export.can_write_zeroes()),
+            None => (true, true),
+        };
+
+        if self.blk_cfg.discard && can_discard {
+            self.state.device_features |= 1_u64 << VIRTIO_BLK_F_DISCARD;
+            config.max_discard_sectors = MAX_REQUEST_SECTORS;
+            config.max_discard_seg = 1;
+            config.discard_sector_alignment = 1;
+        }
+        if !can_write_zeroes {
+            return;
+        }
+        self.state.device_features |= 1_u64 << VIRTIO_BLK_F_WRITE_ZEROES;
+        config.max_write_zeroes_sectors = MAX_REQUEST_SECTORS;
+        config.max_write_zeroes_seg = 1;
+        config.write_zeroes_may_unmap = u8::from(self.blk_cfg.discard);
+    }
+
+    /// Resize the image file to `size` bytes, and notify the guest of the new capacity.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        if size % SECTOR_SIZE != 0 {
+            bail!("Size {} is not aligned to {} bytes", size, SECTOR_SIZE);
+        }
+        if self.blk_cfg.read_only {
+            bail!("Block device {} is read-only", self.blk_cfg.id);
+        }
+        if self.qcow2.is_some() {
+            bail!("Resizing qcow2 image is not supported");
+        }
+        if device_has_job(&self.blk_cfg.id) {
+            bail!("Block device {} has a block job running", self.blk_cfg.id);
+        }
+        let disk_image = self
+            .disk_image
+            .as_ref()
+            .with_context(|| format!("No image file for block device {}", self.blk_cfg.id))?;
+
+        let file_type = disk_image
+            .metadata()
+            .with_context(|| "Failed to get metadata of image file")?
+            .file_type();
+        if file_type.is_block_device() {
+            // Host block device can not be truncated, it must have been resized on host.
+            let mut file: &File = disk_image;
+            let disk_size = file
+                .seek(SeekFrom::End(0))
+                .with_context(|| "Failed to seek the end for block")?;
+            if disk_size != size {
+                bail!(
+                    "Size of host block device is {}, it can not be resized to {}",
+                    disk_size,
+                    size
+                );
+            }
+        } else {
+            disk_image
+                .set_len(size)
+                .with_context(|| format!("Failed to resize image file to {}", size))?;
+        }
+
+        self.disk_sectors = size >> SECTOR_SHIFT;
+        self.state.config_space.capacity = self.disk_sectors;
+        self.update_handlers()
+    }
+
+    /// Create a qcow2 overlay at `path`, backed by the current image.
+    pub fn create_overlay(&self, path: &str) -> Result<()> {
+        if self.blk_cfg.read_only {
+            bail!("Block device {} is read-only", self.blk_cfg.id);
+        }
+        if self.blk_cfg.path_on_host.is_empty() {
+            bail!("No image file for block device {}", self.blk_cfg.id);
+        }
+        // Named by absolute path, the overlay may be in another directory.
+        let backing = std::fs::canonicalize(&self.blk_cfg.path_on_host).with_context(|| {
+            format!(
+                "Failed to resolve the path of {}",
+                self.blk_cfg.path_on_host
+            )
+        })?;
+        Qcow2Image::create(
+            path,
+            self.disk_sectors << SECTOR_SHIFT,
+            Some((&backing.to_string_lossy(), self.blk_cfg.format)),
+        )
+    }
+
+    /// Switch the device onto the qcow2 overlay at `path`, which must be backed
+    /// by the current image and be added to the drive files.
+    pub fn snapshot(&mut self, path: &str) -> Result<()> {
+        if self.blk_cfg.read_only {
+            bail!("Block device {} is read-only", self.blk_cfg.id);
+        }
+        if device_has_job(&self.blk_cfg.id) {
+            bail!("Block device {} has a block job running", self.blk_cfg.id);
+        }
+        // Hold the guest requests, so the overlay opens the current image with
+        // all its clusters mapped.
+        if let Some(qcow2) = self.qcow2.as_ref() {
+            qcow2.lock().unwrap().set_frozen(true);
+        }
+        let result = self.open_image(path, DiskFormat::Qcow2).and_then(|image| {
+            let backing = image.qcow2.as_ref().unwrap().lock().unwrap().backing_file();
+            match backing {
+                Some((backing, _)) if same_file(&backing, &self.blk_cfg.path_on_host) => {}
+                _ => bail!(
+                    "Overlay {} is not backed by the image of block device {}",
+                    path,
+                    self.blk_cfg.id
+                ),
+            }
+            if image.size != self.disk_sectors << SECTOR_SHIFT {
+                bail!(
+                    "Size of overlay {} is {}, but the disk is {} bytes",
+                    path,
+                    image.size,
+                    self.disk_sectors << SECTOR_SHIFT
+                );
+            }
+            Ok(image)
+        });
+        match result {
+            Ok(image) => {
+                self.set_image(image);
+                self.blk_cfg.path_on_host = path.to_string();
+                self.blk_cfg.format = DiskFormat::Qcow2;
+            }
+            Err(e) => {
+                if let Some(qcow2) = self.qcow2.as_ref() {
+                    qcow2.lock().unwrap().set_frozen(false);
+                }
+                self.update_handlers()?;
+                return Err(e);
+            }
+        }
+        self.update_handlers()
+    }
+
+    /// Get the images to commit the active overlay into its backing image.
+    pub(crate) fn prepare_commit(&self) -> Result<CommitImages> {
+        if self.blk_cfg.read_only {
+            bail!("Block device {} is read-only", self.blk_cfg.id);
+        }
+        let top = self
+            .qcow2
+            .clone()
+            .with_context(|| format!("Block device {} has no overlay", self.blk_cfg.id))?;
+        let (backing, base_format) =
+            top.lock().unwrap().backing_file().with_context(|| {
+                format!("Block device {} has no backing image", self.blk_cfg.id)
+            })?;
+        // The backing image may be named differently than its drive file.
+        let base_path = self
+            .drive_files
+            .lock()
+            .unwrap()
+            .keys()
+            .find(|path| same_file(&backing, path))
+            .cloned()
+            .with_context(|| format!("Backing image {} is not opened by the VM", backing))?;
+        let base = self.open_image(&base_path, base_format)?;
+        Ok(CommitImages {
+            top,
+            base,
+            base_path,
+            base_format,
+            in_flight: self.in_flight.clone(),
+        })
+    }
+
+    /// Create an image at `path` for a mirror or backup job to copy the disk
+    /// into. If only the `top` qcow2 image is copied, the target is backed by
+    /// its backing image.
+    pub fn create_target(&self, path: &str, format: DiskFormat, top: bool) -> Result<()> {
+        let size = self.disk_sectors << SECTOR_SHIFT;
+        let backing = match self.qcow2.as_ref() {
+            Some(qcow2) if top => qcow2.lock().unwrap().backing_file(),
+            _ => None,
+        };
+        match format {
+            DiskFormat::Raw => {
+                if let Some((backing, _)) = backing {
+                    bail!("Raw image {} can not be backed by {}", path, backing);
+                }
+                let file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to create {}", path))?;
+                file.set_len(size)
+                    .with_context(|| format!("Failed to resize {} to {}", path, size))
+            }
+            DiskFormat::Qcow2 => {
+                let backing = match backing {
+                    Some((backing, format)) => {
+                        let backing = std::fs::canonicalize(&backing).with_context(|| {
+                            format!("Failed to resolve the path of {}", backing)
+                        })?;
+                        Some((backing.to_string_lossy().to_string(), format))
+                    }
+                    None => None,
+                };
+                Qcow2Image::create(
+                    path,
+                    size,
+                    backing
+                        .as_ref()
+                        .map(|(backing, format)| (backing.as_str(), *format)),
+                )
+            }
+        }
+    }
+
+    /// Get the images to copy the disk into the target at `path`, which must
+    /// be added to the drive files.
+    pub(crate) fn prepare_copy(&self, path: &str, format: DiskFormat) -> Result<CopyImages> {
+        let file = self
+            .disk_image
+            .clone()
+            .with_context(|| format!("No image file for block device {}", self.blk_cfg.id))?;
+        if self.blk_cfg.read_only {
+            bail!("Block device {} is read-only", self.blk_cfg.id);
+        }
+        if same_file(path, &self.blk_cfg.path_on_host) {
+            bail!(
+                "Target {} is the image of block device {}",
+                path,
+                self.blk_cfg.id
+            );
+        }
+        let size = self.disk_sectors << SECTOR_SHIFT;
+        let target = self.open_image(path, format)?;
+        if target.size != size {
+            bail!(
+                "Size of target {} is {}, but the disk is {} bytes",
+                path,
+                target.size,
+                size
+            );
+        }
+        Ok(CopyImages {
+            file,
+            qcow2: self.qcow2.clone(),
+            path: self.blk_cfg.path_on_host.clone(),
+            size,
+            target,
+            target_path: path.to_string(),
+            target_format: format,
+            in_flight: self.in_flight.clone(),
+            filter: self.filter.clone(),
+        })
+    }
+
+    /// Release the drive file at `path`, which a block job has stopped using.
+    pub(crate) fn release_drive_file(&self, path: &str) -> Result<()> {
+        VmConfig::remove_drive_file(&mut self.drive_files.lock().unwrap(), path)
+    }
+
+    /// Switch the device onto the image a block job has copied the disk into,
+    /// the previous image is then released.
+    pub(crate) fn pivot(
+        &mut self,
+        path: String,
+        format: DiskFormat,
+        image: DiskImage,
+    ) -> Result<()> {
+        let previous = std::mem::replace(&mut self.blk_cfg.path_on_host, path);
+        self.blk_cfg.format = format;
+        self.set_image(image);
+        self.update_handlers()?;
+        self.release_drive_file(&previous)
+    }
+
+    /// Send the image to the IO handlers, which then raise config change interrupt.
+    pub(crate) fn update_handlers(&self) -> Result<()> {
+        for sender in &self.senders {
+            sender
+                .send(SenderConfig {
+                    image: self.disk_image.clone(),
+                    req_align: self.req_align,
+                    buf_align: self.buf_align,
+                    disk_sectors: self.disk_sectors,
+                    serial_num: self.blk_cfg.serial_num.clone(),
+                    direct: self.blk_cfg.direct,
+                    aio: self.blk_cfg.aio,
+                    qcow2: self.qcow2.clone(),
+                    discard: self.blk_cfg.discard,
+                    write_zeroes: self.blk_cfg.write_zeroes,
+                    device: self.blk_cfg.id.clone(),
+                    werror: self.blk_cfg.werror,
+                    rerror: self.blk_cfg.rerror,
+                    nbd: self.nbd.clone(),
+                })
+                .with_context(|| anyhow!(VirtioError::ChannelSend("image fd".to_string())))?;
+        }
+        for update_evt in &self.update_evts {
+            update_evt
+                .write(1)
+                .with_context(|| anyhow!(VirtioError::EventFdWrite))?;
+        }
+
+        Ok(())
+    }
+
+    /// Submit the requests held on error again, called when the VM resumes.
+    pub fn retry_failed_requests(&self) -> Result<()> {
+        for retry_evt in &self.retry_evts {
+            retry_evt
+                .write(1)
+                .with_context(|| anyhow!(VirtioError::EventFdWrite))?;
+        }
+        Ok(())
+    }
+
+    /// Let the IO handlers execute the requests they deferred again, those
+    /// still held are deferred once more.
+    pub(crate) fn resume_deferred_requests(&self) -> Result<()> {
+        for deferred_evt in &self.deferred_evts {
+            deferred_evt
+                .write(1)
+                .with_context(|| anyhow!(VirtioError::EventFdWrite))?;
+        }
+        Ok(())
+    }
+
+    /// Change the IO limits, the IO handlers apply them to the following requests.
+    pub fn set_io_throttle(&mut self, config: ThrottleConfig) -> Result<()> {
+        check_throttle(&config)?;
+        self.blk_cfg.throttle = config;
+        self.throttle.lock().unwrap().set_config(config);
+        Ok(())
+    }
+
+    /// Information of the block device and its drive, for QMP.
+    pub fn query_info(&self) -> qmp_schema::BlockInfo {
+        let inserted = if self.blk_cfg.path_on_host.is_empty() {
+            None
+        } else {
+            let format = self.blk_cfg.format.to_string();
+            Some(qmp_schema::BlockDeviceInfo {
+                file: self.blk_cfg.path_on_host.clone(),
+                ro: self.blk_cfg.read_only,
+                drv: format.clone(),
+                backing_file: self
+                    .qcow2
+                    .as_ref()
+                    .and_then(|qcow2| qcow2.lock().unwrap().backing_file())
+                    .map(|(path, _)| path),
+                detect_zeroes: self.blk_cfg.write_zeroes.to_string(),
+                throttle: qmp_schema::IoThrottle::from(&self.blk_cfg.throttle),
+                cache: qmp_schema::BlockdevCacheInfo {
+                    direct: self.blk_cfg.direct,
+                },
+                aio: self.blk_cfg.aio.to_string(),
+                iothread: self.blk_cfg.iothread.clone(),
+                image: qmp_schema::ImageInfo {
+                    filename: self.blk_cfg.path_on_host.clone(),
+                    format,
+                    virtual_size: self.disk_sectors << SECTOR_SHIFT,
+                },
+            })
+        };
+
+        qmp_schema::BlockInfo {
+            device: self.blk_cfg.id.clone(),
+            locked: false,
+            removable: false,
+            inserted,
+        }
+    }
+
+    /// IO statistics of the block device, for QMP.
+    pub fn query_stats(&self) -> qmp_schema::BlockStats {
+        let stats = self.stats.lock().unwrap();
+        qmp_schema::BlockStats {
+            device: self.blk_cfg.id.clone(),
+            stats: qmp_schema::BlockDeviceStats {
+                rd_bytes: stats.read.bytes,
+                wr_bytes: stats.write.bytes,
+                unmap_bytes: stats.unmap.bytes,
+                rd_operations: stats.read.operations,
+                wr_operations: stats.write.operations,
+                flush_operations: stats.flush.operations,
+                unmap_operations: stats.unmap.operations,
+                rd_merged: stats.read.merged,
+                wr_merged: stats.write.merged,
+                unmap_merged: stats.unmap.merged,
+                failed_rd_operations: stats.read.failed_operations,
+                failed_wr_operations: stats.write.failed_operations,
+                failed_flush_operations: stats.flush.failed_operations,
+                failed_unmap_operations: stats.unmap.failed_operations,
+                rd_total_time_ns: stats.read.total_time_ns,
+                wr_total_time_ns: stats.write.total_time_ns,
+                flush_total_time_ns: stats.flush.total_time_ns,
+                unmap_total_time_ns: stats.unmap.total_time_ns,
+                idle_time_ns: stats
+                    .last_access
+                    .map(|time| time.elapsed().as_nanos() as u64),
+            },
+        }
+    }
+
+    /// Get the length of config space, fields of discard and write zeroes only
+    /// exist if the features are offered.
+    fn get_config_len(&self) -> u64 {
+        if virtio_has_feature(self.state.device_features, VIRTIO_BLK_F_WRITE_ZEROES) {
+            size_of::<VirtioBlkConfig>() as u64
+        } else if virtio_has_feature(self.state.device_features, VIRTIO_BLK_F_DISCARD) {
+            offset_of!(VirtioBlkConfig, max_write_zeroes_sectors) as u64
+        } else {
+            offset_of!(VirtioBlkConfig, max_discard_sectors) as u64
+        }
+    }
 }
 
 impl VirtioDevice for Block {
@@ -993,8 +2472,15 @@ impl VirtioDevice for Block {
         self.state.device_features |= 1_u64 << VIRTIO_F_RING_INDIRECT_DESC;
         self.state.device_features |= 1_u64 << VIRTIO_BLK_F_SEG_MAX;
         self.state.device_features |= 1_u64 << VIRTIO_F_RING_EVENT_IDX;
+        if self.blk_cfg.packed {
+            self.state.device_features |= 1_u64 << VIRTIO_F_RING_PACKED;
+        }
 
         self.build_device_config_space();
+        self.throttle
+            .lock()
+            .unwrap()
+            .set_config(self.blk_cfg.throttle);
 
         if self.blk_cfg.queues > 1 {
             self.state.device_features |= 1_u64 << VIRTIO_BLK_F_MQ;
@@ -1002,23 +2488,25 @@ impl VirtioDevice for Block {
         }
 
         self.disk_image = None;
+        self.qcow2 = None;
+        self.nbd = None;
         self.disk_sectors = DUMMY_IMG_SIZE >> SECTOR_SHIFT;
         self.req_align = 1;
         self.buf_align = 1;
-        if !self.blk_cfg.path_on_host.is_empty() {
-            let drive_files = self.drive_files.lock().unwrap();
-            let mut file = VmConfig::fetch_drive_file(&drive_files, &self.blk_cfg.path_on_host)?;
-            let alignments = VmConfig::fetch_drive_align(&drive_files, &self.blk_cfg.path_on_host)?;
-            let disk_size = file
-                .seek(SeekFrom::End(0))
-                .with_context(|| "Failed to seek the end for block")?;
-
-            self.disk_image = Some(Arc::new(file));
-            self.disk_sectors = disk_size >> SECTOR_SHIFT;
-            self.req_align = alignments.0;
-            self.buf_align = alignments.1;
+        if is_nbd_uri(&self.blk_cfg.path_on_host) {
+            let uri = self.blk_cfg.path_on_host.parse::<NbdUri>()?;
+            let export = NbdExport::open(&uri)?;
+            if export.read_only() && !self.blk_cfg.read_only {
+                bail!("NBD export {} is read-only", self.blk_cfg.path_on_host);
+            }
+            self.disk_sectors = export.size >> SECTOR_SHIFT;
+            self.nbd = Some(export);
+        } else if !self.blk_cfg.path_on_host.is_empty() {
+            let image = self.open_image(&self.blk_cfg.path_on_host, self.blk_cfg.format)?;
+            self.set_image(image);
         }
         self.state.config_space.capacity = self.disk_sectors;
+        self.build_discard_config_space();
 
         Ok(())
     }
@@ -1060,8 +2548,7 @@ impl VirtioDevice for Block {
 
     /// Read data of config from guest.
     fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
-        // F_DISCARD is not supported for now, so related config does not exist.
-        let config_len = offset_of!(VirtioBlkConfig, max_discard_sectors) as u64;
+        let config_len = self.get_config_len();
         let read_end = offset as usize + data.len();
         if offset
             .checked_add(data.len() as u64)
@@ -1079,8 +2566,7 @@ impl VirtioDevice for Block {
 
     /// Write data to config from guest.
     fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
-        // F_DISCARD is not supported for now, so related config does not exist.
-        let config_len = offset_of!(VirtioBlkConfig, max_discard_sectors) as u64;
+        let config_len = self.get_config_len();
         if offset
             .checked_add(data.len() as u64)
             .filter(|&end| end <= config_len)
@@ -1111,19 +2597,36 @@ impl VirtioDevice for Block {
             }
             let (sender, receiver) = channel();
             let update_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
+            let retry_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
+            let nbd_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
+            let deferred_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
             let aio = Box::new(Aio::new(
                 Arc::new(BlockIoHandler::complete_func),
                 self.blk_cfg.aio,
             )?);
+            let nbd = self
+                .nbd
+                .clone()
+                .map(|export| {
+                    NbdClient::new(
+                        export,
+                        Arc::new(BlockIoHandler::complete_func),
+                        nbd_evt.clone(),
+                    )
+                })
+                .transpose()?;
             let handler = BlockIoHandler {
                 queue: queue.clone(),
                 queue_evt,
                 mem_space: mem_space.clone(),
                 disk_image: self.disk_image.clone(),
+                qcow2: self.qcow2.clone(),
                 req_align: self.req_align,
                 buf_align: self.buf_align,
                 disk_sectors: self.disk_sectors,
                 direct: self.blk_cfg.direct,
+                discard: self.blk_cfg.discard,
+                write_zeroes: self.blk_cfg.write_zeroes,
                 serial_num: self.blk_cfg.serial_num.clone(),
                 aio,
                 driver_features: self.state.driver_features,
@@ -1132,10 +2635,21 @@ impl VirtioDevice for Block {
                 device_broken: self.broken.clone(),
                 interrupt_cb: interrupt_cb.clone(),
                 iothread: self.blk_cfg.iothread.clone(),
-                leak_bucket: match self.blk_cfg.iops {
-                    Some(iops) => Some(LeakBucket::new(iops)?),
-                    None => None,
-                },
+                throttle: Throttle::new(self.throttle.clone())?,
+                stats: self.stats.clone(),
+                errors: Rc::new(IoErrorHandling::new(
+                    &self.blk_cfg.id,
+                    self.blk_cfg.werror,
+                    self.blk_cfg.rerror,
+                )),
+                retry_evt: retry_evt.clone(),
+                in_flight: self.in_flight.clone(),
+                deferred: Vec::new(),
+                deferred_evt: deferred_evt.clone(),
+                copier: None,
+                filter: self.filter.clone(),
+                nbd,
+                nbd_evt,
             };
 
             let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
@@ -1145,6 +2659,8 @@ impl VirtioDevice for Block {
                 &mut self.deactivate_evts,
             )?;
             self.update_evts.push(update_evt);
+            self.retry_evts.push(retry_evt);
+            self.deferred_evts.push(deferred_evt);
             self.senders.push(sender);
         }
         self.broken.store(false, Ordering::SeqCst);
@@ -1155,6 +2671,8 @@ impl VirtioDevice for Block {
     fn deactivate(&mut self) -> Result<()> {
         unregister_event_helper(self.blk_cfg.iothread.as_ref(), &mut self.deactivate_evts)?;
         self.update_evts.clear();
+        self.retry_evts.clear();
+        self.deferred_evts.clear();
         self.senders.clear();
         Ok(())
     }
@@ -1173,27 +2691,7 @@ impl VirtioDevice for Block {
         }
 
         self.realize()?;
-
-        for sender in &self.senders {
-            sender
-                .send((
-                    self.disk_image.clone(),
-                    self.req_align,
-                    self.buf_align,
-                    self.disk_sectors,
-                    self.blk_cfg.serial_num.clone(),
-                    self.blk_cfg.direct,
-                    self.blk_cfg.aio,
-                ))
-                .with_context(|| anyhow!(VirtioError::ChannelSend("image fd".to_string())))?;
-        }
-        for update_evt in &self.update_evts {
-            update_evt
-                .write(1)
-                .with_context(|| anyhow!(VirtioError::EventFdWrite))?;
-        }
-
-        Ok(())
+        self.update_handlers()
     }
 }
 
@@ -1251,6 +2749,7 @@ mod tests {
             Block {
                 blk_cfg: Default::default(),
                 disk_image: None,
+                qcow2: None,
                 req_align: 1,
                 buf_align: 1,
                 disk_sectors: 0,
@@ -1258,9 +2757,16 @@ mod tests {
                 interrupt_cb: None,
                 senders: Vec::new(),
                 update_evts: Vec::new(),
+                retry_evts: Vec::new(),
+                deferred_evts: Vec::new(),
                 deactivate_evts: Vec::new(),
                 broken: Arc::new(AtomicBool::new(false)),
                 drive_files: Arc::new(Mutex::new(HashMap::new())),
+                stats: Arc::new(Mutex::new(BlockIoStats::default())),
+                throttle: Arc::new(Mutex::new(ThrottleState::new(ThrottleConfig::default()))),
+                in_flight: Arc::new(AtomicUsize::new(0)),
+                filter: Arc::new(WriteFilter::default()),
+                nbd: None,
             }
         }
     }
@@ -1321,6 +2827,367 @@ mod tests {
         assert_eq!(block.device_type(), VIRTIO_TYPE_BLOCK);
         assert_eq!(block.queue_num(), QUEUE_NUM_BLK);
         assert_eq!(block.queue_size(), DEFAULT_VIRTQUEUE_SIZE);
+
+        // A raw file is not a valid qcow2 image.
+        block.blk_cfg.format = DiskFormat::Qcow2;
+        assert!(block.realize().is_err());
+    }
+
+    #[test]
+    fn test_iov_split_front() {
+        let mut iovec: VecDeque<Iovec> = vec![
+            Iovec {
+                iov_base: 0x1000,
+                iov_len: 512,
+            },
+            Iovec {
+                iov_base: 0x3000,
+                iov_len: 1024,
+            },
+        ]
+        .into();
+
+        let front = iov_split_front(&mut iovec, 1024);
+        assert_eq!(front.len(), 2);
+        assert_eq!((front[0].iov_base, front[0].iov_len), (0x1000, 512));
+        assert_eq!((front[1].iov_base, front[1].iov_len), (0x3000, 512));
+        assert_eq!(iovec.len(), 1);
+        assert_eq!((iovec[0].iov_base, iovec[0].iov_len), (0x3200, 512));
+
+        let front = iov_split_front(&mut iovec, 1024);
+        assert_eq!(front.len(), 1);
+        assert!(iovec.is_empty());
+    }
+
+    #[test]
+    fn test_block_resize() {
+        let mut block = Block::default();
+        block.blk_cfg.id = "blk-resize".to_string();
+        block.blk_cfg.direct = false;
+        let f = TempFile::new().unwrap();
+        block.blk_cfg.path_on_host = f.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(
+            &mut block.drive_files.lock().unwrap(),
+            &block.blk_cfg.path_on_host,
+            block.blk_cfg.read_only,
+            block.blk_cfg.direct,
+        )
+        .unwrap();
+        block.realize().unwrap();
+        assert_eq!(block.disk_sectors, 0);
+
+        let block = Arc::new(Mutex::new(block));
+        register_block_device("blk-resize", &block);
+        let found = find_block_device("blk-resize").unwrap();
+        assert!(Arc::ptr_eq(&found, &block));
+        drop(found);
+
+        let mut locked_block = block.lock().unwrap();
+        assert!(locked_block.resize(1000).is_err());
+        locked_block.resize(1 << 20).unwrap();
+        assert_eq!(locked_block.disk_sectors, (1 << 20) >> SECTOR_SHIFT);
+        let capacity = locked_block.state.config_space.capacity;
+        assert_eq!(capacity, (1 << 20) >> SECTOR_SHIFT);
+        assert_eq!(f.as_file().metadata().unwrap().len(), 1 << 20);
+
+        locked_block.blk_cfg.read_only = true;
+        assert!(locked_block.resize(1 << 21).is_err());
+        drop(locked_block);
+
+        // The device is gone once the VM drops it.
+        drop(block);
+        assert!(find_block_device("blk-resize").is_none());
+    }
+
+    fn stats_request(request_type: u32, data_len: u64, next: Option<Request>) -> Request {
+        Request {
+            desc_index: 0,
+            out_header: RequestOutHeader {
+                request_type,
+                io_prio: 0,
+                sector: 0,
+            },
+            iovec: Vec::new(),
+            data_len,
+            in_len: 1,
+            in_header: GuestAddress(0),
+            unmap: false,
+            next: Box::new(next),
+        }
+    }
+
+    #[test]
+    fn test_block_io_stats() {
+        let mut stats = BlockIoStats::default();
+        assert!(stats.last_access.is_none());
+
+        // Two reads merged into one request.
+        let read = stats_request(
+            VIRTIO_BLK_T_IN,
+            4096,
+            Some(stats_request(VIRTIO_BLK_T_IN, 512, None)),
+        );
+        stats.account(&read, VIRTIO_BLK_S_OK, 100);
+        assert_eq!(stats.read.operations, 2);
+        assert_eq!(stats.read.bytes, 4608);
+        assert_eq!(stats.read.merged, 1);
+        assert_eq!(stats.read.total_time_ns, 200);
+        assert!(stats.last_access.is_some());
+
+        let write = stats_request(VIRTIO_BLK_T_OUT, 512, None);
+        stats.account(&write, VIRTIO_BLK_S_IOERR, 100);
+        let write_zeroes = stats_request(VIRTIO_BLK_T_WRITE_ZEROES, 1024, None);
+        stats.account(&write_zeroes, VIRTIO_BLK_S_OK, 50);
+        assert_eq!(stats.write.operations, 1);
+        assert_eq!(stats.write.failed_operations, 1);
+        assert_eq!(stats.write.bytes, 1024);
+        assert_eq!(stats.write.merged, 0);
+        assert_eq!(stats.write.total_time_ns, 50);
+
+        stats.account(
+            &stats_request(VIRTIO_BLK_T_FLUSH, 0, None),
+            VIRTIO_BLK_S_OK,
+            10,
+        );
+        stats.account(
+            &stats_request(VIRTIO_BLK_T_DISCARD, 8192, None),
+            VIRTIO_BLK_S_UNSUPP,
+            10,
+        );
+        assert_eq!(stats.flush.operations, 1);
+        assert_eq!(stats.unmap.operations, 0);
+        assert_eq!(stats.unmap.failed_operations, 1);
+
+        // Get id requests are not accounted.
+        stats.account(
+            &stats_request(VIRTIO_BLK_T_GET_ID, 20, None),
+            VIRTIO_BLK_S_OK,
+            10,
+        );
+        assert_eq!(stats.read.operations, 2);
+    }
+
+    #[test]
+    fn test_block_query() {
+        let mut block = Block::default();
+        block.blk_cfg.id = "blk-query".to_string();
+        let info = block.query_info();
+        assert_eq!(info.device, "blk-query");
+        assert!(info.inserted.is_none());
+
+        block.blk_cfg.direct = false;
+        block.blk_cfg.throttle.iops_total.avg = 100;
+        block.blk_cfg.iothread = Some("iothread0".to_string());
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(1 << 20).unwrap();
+        block.blk_cfg.path_on_host = f.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(
+            &mut block.drive_files.lock().unwrap(),
+            &block.blk_cfg.path_on_host,
+            block.blk_cfg.read_only,
+            block.blk_cfg.direct,
+        )
+        .unwrap();
+        block.realize().unwrap();
+
+        let inserted = block.query_info().inserted.unwrap();
+        assert_eq!(inserted.file, block.blk_cfg.path_on_host);
+        assert!(!inserted.ro);
+        assert_eq!(inserted.drv, "raw");
+        assert_eq!(inserted.detect_zeroes, "off");
+        assert_eq!(inserted.throttle.iops, 100);
+        assert_eq!(inserted.throttle.bps, 0);
+        assert!(inserted.throttle.iops_max.is_none());
+        assert!(!inserted.cache.direct);
+        assert_eq!(inserted.aio, block.blk_cfg.aio.to_string());
+        assert_eq!(inserted.iothread, Some("iothread0".to_string()));
+        assert_eq!(inserted.image.virtual_size, 1 << 20);
+
+        let stats = block.query_stats();
+        assert_eq!(stats.device, "blk-query");
+        assert_eq!(stats.stats.rd_operations, 0);
+        assert!(stats.stats.idle_time_ns.is_none());
+
+        block.stats.lock().unwrap().account(
+            &stats_request(VIRTIO_BLK_T_OUT, 512, None),
+            VIRTIO_BLK_S_OK,
+            10,
+        );
+        let stats = block.query_stats();
+        assert_eq!(stats.stats.wr_operations, 1);
+        assert_eq!(stats.stats.wr_bytes, 512);
+        assert!(stats.stats.idle_time_ns.is_some());
+    }
+
+    #[test]
+    fn test_block_set_io_throttle() {
+        let mut block = Block::default();
+        let mut config = ThrottleConfig::default();
+        config.bps_write.avg = 1 << 20;
+        config.bps_write.max = 2 << 20;
+        config.bps_write.max_length = 10;
+        block.set_io_throttle(config).unwrap();
+        assert_eq!(block.blk_cfg.throttle, config);
+        assert_eq!(*block.throttle.lock().unwrap().config(), config);
+
+        let info = block.query_info();
+        assert!(info.inserted.is_none());
+        block.blk_cfg.path_on_host = "/path/to/block".to_string();
+        let throttle = block.query_info().inserted.unwrap().throttle;
+        assert_eq!(throttle.bps_wr, 1 << 20);
+        assert_eq!(throttle.bps_wr_max, Some(2 << 20));
+        assert_eq!(throttle.bps_wr_max_length, Some(10));
+        assert!(throttle.bps_max.is_none());
+
+        // Invalid limits are refused and the old ones are kept.
+        let mut bad = config;
+        bad.bps_total.avg = 1 << 20;
+        assert!(block.set_io_throttle(bad).is_err());
+        assert_eq!(block.blk_cfg.throttle, config);
+        assert_eq!(*block.throttle.lock().unwrap().config(), config);
+    }
+
+    #[test]
+    fn test_io_error_policy() {
+        assert_eq!(
+            io_error_action(IoErrorPolicy::Enospc, true),
+            IoErrorPolicy::Stop
+        );
+        assert_eq!(
+            io_error_action(IoErrorPolicy::Enospc, false),
+            IoErrorPolicy::Report
+        );
+        assert_eq!(
+            io_error_action(IoErrorPolicy::Ignore, true),
+            IoErrorPolicy::Ignore
+        );
+
+        let errors = Rc::new(IoErrorHandling::new(
+            "blk0",
+            IoErrorPolicy::Enospc,
+            IoErrorPolicy::Ignore,
+        ));
+        let interrupt_cb = Arc::new(Box::new(
+            |_: &VirtioInterruptType, _: Option<&Queue>, _: bool| Ok(()),
+        ) as VirtioInterrupt);
+        let queue_config = QueueConfig::new(DEFAULT_VIRTQUEUE_SIZE);
+        let aiocb = |opcode| AioCb {
+            direct: false,
+            req_align: 1,
+            buf_align: 1,
+            file_fd: -1,
+            opcode,
+            iovec: Vec::new(),
+            offset: 0,
+            nbytes: 512,
+            user_data: 0,
+            iocompletecb: AioCompleteCb::new(
+                Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap())),
+                address_space_init(),
+                Rc::new(stats_request(VIRTIO_BLK_T_OUT, 512, None)),
+                interrupt_cb.clone(),
+                0,
+                Arc::new(Mutex::new(BlockIoStats::default())),
+                errors.clone(),
+            ),
+        };
+
+        // Writes failed for lack of space are held.
+        let write = aiocb(OpCode::Pwritev);
+        assert!(errors
+            .handle_error(&write, -(libc::ENOSPC as i64))
+            .is_none());
+        assert_eq!(errors.held.borrow().len(), 1);
+        assert_eq!(
+            errors.handle_error(&write, -(libc::EIO as i64)),
+            Some(VIRTIO_BLK_S_IOERR)
+        );
+        let read = aiocb(OpCode::Preadv);
+        assert_eq!(
+            errors.handle_error(&read, -(libc::EIO as i64)),
+            Some(VIRTIO_BLK_S_OK)
+        );
+        assert_eq!(errors.held.borrow().len(), 1);
+
+        // Write zeroes sent to the NBD server are held with their unmap flag.
+        let mut write_zeroes = aiocb(OpCode::WriteZeroes);
+        write_zeroes.iocompletecb.unmap = true;
+        assert!(errors
+            .handle_error(&write_zeroes, -(libc::ENOSPC as i64))
+            .is_none());
+        assert!(errors.held.borrow()[1].iocompletecb.unmap);
+        errors.held.borrow_mut().clear();
+    }
+
+    #[test]
+    fn test_iov_is_zero() {
+        let mut buf = vec![0_u8; 1024];
+        let iovec = vec![
+            Iovec {
+                iov_base: buf.as_ptr() as u64,
+                iov_len: 512,
+            },
+            Iovec {
+                iov_base: buf.as_ptr() as u64 + 512,
+                iov_len: 512,
+            },
+        ];
+        assert!(iov_is_zero(&iovec));
+        buf[1023] = 1;
+        assert!(!iov_is_zero(&iovec));
+        assert!(iov_is_zero(&iovec[..1]));
+    }
+
+    // Test discard and write zeroes are offered with their config space, except for
+    // read-only or qcow2 images.
+    #[test]
+    fn test_discard_write_zeroes_config() {
+        let max_discard_offset = offset_of!(VirtioBlkConfig, max_discard_sectors) as u64;
+        let max_write_zeroes_offset = offset_of!(VirtioBlkConfig, max_write_zeroes_sectors) as u64;
+        let mut data = [0_u8; 4];
+
+        let mut block = Block::default();
+        block.blk_cfg.discard = true;
+        block.realize().unwrap();
+        assert!(virtio_has_feature(
+            block.state.device_features,
+            VIRTIO_BLK_F_DISCARD
+        ));
+        assert!(virtio_has_feature(
+            block.state.device_features,
+            VIRTIO_BLK_F_WRITE_ZEROES
+        ));
+        block.read_config(max_discard_offset, &mut data).unwrap();
+        assert_eq!(u32::from_le_bytes(data), MAX_REQUEST_SECTORS);
+        block
+            .read_config(max_write_zeroes_offset, &mut data)
+            .unwrap();
+        assert_eq!(u32::from_le_bytes(data), MAX_REQUEST_SECTORS);
+        assert_eq!(block.state.config_space.write_zeroes_may_unmap, 1);
+
+        // Write zeroes is offered without discard.
+        block.blk_cfg.discard = false;
+        block.realize().unwrap();
+        assert!(!virtio_has_feature(
+            block.state.device_features,
+            VIRTIO_BLK_F_DISCARD
+        ));
+        block.read_config(max_discard_offset, &mut data).unwrap();
+        assert_eq!(u32::from_le_bytes(data), 0);
+        assert_eq!(block.state.config_space.write_zeroes_may_unmap, 0);
+
+        block.blk_cfg.read_only = true;
+        block.blk_cfg.discard = true;
+        block.realize().unwrap();
+        assert!(!virtio_has_feature(
+            block.state.device_features,
+            VIRTIO_BLK_F_DISCARD
+        ));
+        assert!(!virtio_has_feature(
+            block.state.device_features,
+            VIRTIO_BLK_F_WRITE_ZEROES
+        ));
+        assert!(block.read_config(max_discard_offset, &mut data).is_err());
     }
 
     // Test `write_config` and `read_config`. The main contests include: compare expect data and
@@ -1445,7 +3312,7 @@ mod tests {
 
         // config iothread and iops
         block.blk_cfg.iothread = Some(thread_name);
-        block.blk_cfg.iops = Some(100);
+        block.blk_cfg.throttle.iops_total.avg = 100;
 
         VmConfig::add_drive_file(
             &mut block.drive_files.lock().unwrap(),
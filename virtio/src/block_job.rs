@@ -0,0 +1,1025 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Block jobs, which copy the data of block devices in background threads.
+//!
+//! A commit job merges the active qcow2 overlay of a device into its backing
+//! image while the guest keeps running. The clusters allocated in the overlay
+//! are copied first, recording the clusters the guest writes meanwhile. Those
+//! are copied again with the guest requests held, then the device is switched
+//! onto the backing image.
+//!
+//! A mirror job copies a device to another image, and copies again the
+//! clusters the guest writes, as reported by the `WriteFilter` of the device.
+//! Once completed, the last clusters are copied with the guest requests held,
+//! then the device is switched onto the target.
+//!
+//! A backup job copies a device as it is when the job starts. Before a guest
+//! write is submitted, the clusters it overwrites are copied if they are not
+//! yet.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use log::error;
+use machine_manager::config::DiskFormat;
+use machine_manager::event;
+use machine_manager::qmp::{qmp_schema, QmpChannel};
+use once_cell::sync::Lazy;
+
+use crate::block::{Block, CommitImages, CopyImages};
+use crate::qcow2::Qcow2Image;
+
+/// Clusters a mirror or backup job copies on a raw image, in bytes.
+const RAW_CLUSTER_SIZE: u64 = 1 << 16;
+
+/// Running block jobs by job id.
+static BLOCK_JOBS: Lazy<Mutex<HashMap<String, Arc<BlockJob>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A block job running on a block device.
+struct BlockJob {
+    id: String,
+    job_type: String,
+    /// Id of the block device.
+    device: String,
+    /// Bytes to be copied, it grows if the guest writes the copied data again.
+    len: AtomicU64,
+    /// Bytes copied.
+    offset: AtomicU64,
+    /// Bytes copied per second at most, 0 means no limit.
+    speed: u64,
+    /// Set by `block-job-cancel`, the job stops as soon as it sees it.
+    cancelling: AtomicBool,
+    /// Set once a mirror job has copied the disk.
+    ready: AtomicBool,
+    /// Set by `block-job-complete`, a ready mirror job then switches the
+    /// device onto the target.
+    completing: AtomicBool,
+}
+
+impl BlockJob {
+    fn new(id: String, job_type: &str, device: String, speed: u64) -> Self {
+        BlockJob {
+            id,
+            job_type: job_type.to_string(),
+            device,
+            len: AtomicU64::new(0),
+            offset: AtomicU64::new(0),
+            speed,
+            cancelling: AtomicBool::new(false),
+            ready: AtomicBool::new(false),
+            completing: AtomicBool::new(false),
+        }
+    }
+
+    fn info(&self) -> qmp_schema::BlockJobInfo {
+        qmp_schema::BlockJobInfo {
+            job_type: self.job_type.clone(),
+            device: self.id.clone(),
+            len: self.len.load(Ordering::SeqCst),
+            offset: self.offset.load(Ordering::SeqCst),
+            busy: true,
+            paused: false,
+            speed: self.speed,
+            io_status: "ok".to_string(),
+            ready: self.ready.load(Ordering::SeqCst),
+        }
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancelling.load(Ordering::SeqCst) {
+            bail!("Block job {} is cancelled", self.id);
+        }
+        Ok(())
+    }
+
+    /// Sleep to keep the copying under `speed`.
+    fn throttle(&self, start: Instant) {
+        if self.speed == 0 {
+            return;
+        }
+        let expected =
+            Duration::from_secs_f64(self.offset.load(Ordering::SeqCst) as f64 / self.speed as f64);
+        if let Some(ahead) = expected.checked_sub(start.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+
+    fn completed(&self, error: Option<String>) {
+        if QmpChannel::is_connected() {
+            let completed = qmp_schema::BlockJobCompleted {
+                job_type: self.job_type.clone(),
+                device: self.id.clone(),
+                len: self.len.load(Ordering::SeqCst),
+                offset: self.offset.load(Ordering::SeqCst),
+                speed: self.speed,
+                error,
+            };
+            event!(BlockJobCompleted; completed);
+        }
+    }
+
+    fn cancelled(&self) {
+        if QmpChannel::is_connected() {
+            let cancelled = qmp_schema::BlockJobCancelled {
+                job_type: self.job_type.clone(),
+                device: self.id.clone(),
+                len: self.len.load(Ordering::SeqCst),
+                offset: self.offset.load(Ordering::SeqCst),
+                speed: self.speed,
+            };
+            event!(BlockJobCancelled; cancelled);
+        }
+    }
+
+    fn set_ready(&self) {
+        if self.ready.swap(true, Ordering::SeqCst) || !QmpChannel::is_connected() {
+            return;
+        }
+        let ready = qmp_schema::BlockJobReady {
+            job_type: self.job_type.clone(),
+            device: self.id.clone(),
+            len: self.len.load(Ordering::SeqCst),
+            offset: self.offset.load(Ordering::SeqCst),
+            speed: self.speed,
+        };
+        event!(BlockJobReady; ready);
+    }
+}
+
+/// Progress of the running block jobs, sorted by job id.
+pub fn block_jobs() -> Vec<qmp_schema::BlockJobInfo> {
+    let mut jobs: Vec<qmp_schema::BlockJobInfo> = BLOCK_JOBS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|job| job.info())
+        .collect();
+    jobs.sort_by(|a, b| a.device.cmp(&b.device));
+    jobs
+}
+
+/// If a block job is running on the block device `device`.
+pub fn device_has_job(device: &str) -> bool {
+    BLOCK_JOBS
+        .lock()
+        .unwrap()
+        .values()
+        .any(|job| job.device == device)
+}
+
+fn find_job(id: &str) -> Result<Arc<BlockJob>> {
+    BLOCK_JOBS
+        .lock()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .with_context(|| format!("Block job {} not found", id))
+}
+
+/// Stop the block job `id`, the device stays on its image.
+pub fn cancel_block_job(id: &str) -> Result<()> {
+    find_job(id)?.cancelling.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Switch the device of the ready mirror job `id` onto the target.
+pub fn complete_block_job(id: &str) -> Result<()> {
+    let job = find_job(id)?;
+    if job.job_type != "mirror" {
+        bail!("Block job {} can not be completed", id);
+    }
+    if !job.ready.load(Ordering::SeqCst) {
+        bail!("Block job {} is not ready", id);
+    }
+    job.completing.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Register the job and run it in a new thread, which reports the end of the
+/// job by QMP event.
+fn spawn_job<F>(job: BlockJob, run: F) -> Result<()>
+where
+    F: FnOnce(&Arc<BlockJob>) -> Result<()> + Send + 'static,
+{
+    let id = job.id.clone();
+    let job = Arc::new(job);
+    {
+        let mut jobs = BLOCK_JOBS.lock().unwrap();
+        if jobs.contains_key(&id) {
+            bail!("Block job {} already exists", id);
+        }
+        jobs.insert(id.clone(), job.clone());
+    }
+
+    let spawned = thread::Builder::new()
+        .name(format!("{} {}", job.job_type, id))
+        .spawn(move || {
+            let result = run(&job);
+            BLOCK_JOBS.lock().unwrap().remove(&job.id);
+            match result {
+                Err(_) if job.cancelling.load(Ordering::SeqCst) => job.cancelled(),
+                Err(e) => {
+                    error!("Block job {} failed: {:?}", job.id, e);
+                    job.completed(Some(e.to_string()));
+                }
+                Ok(()) => job.completed(None),
+            }
+        });
+    if let Err(e) = spawned {
+        BLOCK_JOBS.lock().unwrap().remove(&id);
+        bail!("Failed to create thread for block job {}: {:?}", id, e);
+    }
+    Ok(())
+}
+
+/// Start a job committing the active overlay of the block device into its
+/// backing image, the device is switched onto the backing image when done.
+///
+/// # Arguments
+///
+/// * `block` - The block device.
+/// * `job_id` - Id of the job, the id of the device by default.
+/// * `speed` - Bytes copied per second at most, 0 means no limit.
+pub fn start_commit_job(
+    block: &Arc<Mutex<Block>>,
+    job_id: Option<String>,
+    speed: u64,
+) -> Result<()> {
+    let (device, images) = {
+        let locked_block = block.lock().unwrap();
+        let device = locked_block.id().to_string();
+        if device_has_job(&device) {
+            bail!("Block device {} has a block job running", device);
+        }
+        (device, locked_block.prepare_commit()?)
+    };
+    let id = job_id.unwrap_or_else(|| device.clone());
+    let block = block.clone();
+    spawn_job(BlockJob::new(id, "commit", device, speed), move |job| {
+        run_commit(job, &block, images)
+    })
+}
+
+/// Which data of the disk a mirror or backup job copies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// The whole disk.
+    Full,
+    /// The clusters allocated in the active qcow2 image, the target is backed
+    /// by its backing image.
+    Top,
+}
+
+impl FromStr for SyncMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(SyncMode::Full),
+            "top" => Ok(SyncMode::Top),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Start a mirror or backup job copying the block device into `target`,
+/// which must be added to the drive files. The target is released unless a
+/// mirror job switches the device onto it.
+///
+/// # Arguments
+///
+/// * `block` - The block device.
+/// * `mirror` - True for a mirror job, false for a backup job.
+/// * `job_id` - Id of the job, the id of the device by default.
+/// * `target` - Path of the target image, and its format.
+/// * `sync` - Which data of the disk is copied.
+/// * `speed` - Bytes copied per second at most, 0 means no limit.
+pub fn start_copy_job(
+    block: &Arc<Mutex<Block>>,
+    mirror: bool,
+    job_id: Option<String>,
+    target: (&str, DiskFormat),
+    sync: SyncMode,
+    speed: u64,
+) -> Result<()> {
+    let (device, images) = {
+        let locked_block = block.lock().unwrap();
+        let device = locked_block.id().to_string();
+        if device_has_job(&device) {
+            bail!("Block device {} has a block job running", device);
+        }
+        (device, locked_block.prepare_copy(target.0, target.1)?)
+    };
+    let id = job_id.unwrap_or_else(|| device.clone());
+    let job_type = if mirror { "mirror" } else { "backup" };
+    let block = block.clone();
+    spawn_job(BlockJob::new(id, job_type, device, speed), move |job| {
+        if mirror {
+            run_mirror(job, &block, images, sync)
+        } else {
+            run_backup(job, &block, images, sync)
+        }
+    })
+}
+
+/// An image a block job reads or writes synchronously.
+enum JobImage {
+    /// Buffered handle of a raw image, the drive file may use O_DIRECT.
+    Raw(File),
+    Qcow2(Arc<Mutex<Qcow2Image>>),
+}
+
+impl JobImage {
+    fn open(path: &str, qcow2: Option<&Arc<Mutex<Qcow2Image>>>, write: bool) -> Result<Self> {
+        match qcow2 {
+            Some(qcow2) => Ok(JobImage::Qcow2(qcow2.clone())),
+            None => {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(write)
+                    .open(path)
+                    .with_context(|| format!("Failed to open {}", path))?;
+                Ok(JobImage::Raw(file))
+            }
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        match self {
+            JobImage::Raw(file) => file
+                .read_exact_at(buf, offset)
+                .with_context(|| "Failed to read the image"),
+            JobImage::Qcow2(qcow2) => qcow2.lock().unwrap().read_at(offset, buf),
+        }
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        match self {
+            JobImage::Raw(file) => file
+                .write_all_at(buf, offset)
+                .with_context(|| "Failed to write the image"),
+            JobImage::Qcow2(qcow2) => qcow2.lock().unwrap().write_at(offset, buf),
+        }
+    }
+
+    fn sync(&self) -> Result<()> {
+        match self {
+            JobImage::Raw(file) => file.sync_data().with_context(|| "Failed to sync the image"),
+            JobImage::Qcow2(qcow2) => qcow2.lock().unwrap().sync(),
+        }
+    }
+}
+
+/// Copy `len` bytes at `offset` of the disk from `source` to `target`.
+fn copy_range(source: &JobImage, target: &JobImage, offset: u64, len: u64) -> Result<()> {
+    let mut buf = vec![0_u8; len as usize];
+    source.read_at(offset, &mut buf)?;
+    target.write_at(offset, &buf)
+}
+
+/// Hook of the block job on the guest writes of a block device, consulted by
+/// the IO handlers before submitting each request.
+#[derive(Default)]
+pub(crate) struct WriteFilter {
+    state: Mutex<FilterState>,
+    /// Clusters a mirror job has to copy, the guest writes are added once
+    /// completed. Locked apart from the state, as a write may complete while
+    /// the state is locked for submitting it.
+    dirty: Mutex<Option<DirtyClusters>>,
+}
+
+#[derive(Default)]
+pub(crate) struct FilterState {
+    /// The guest requests on this image are held, while a block job waits for
+    /// those in flight or switches the device away from it.
+    frozen: Option<Weak<File>>,
+    hook: Option<WriteHook>,
+}
+
+enum WriteHook {
+    /// The guest writes are recorded in the dirty clusters.
+    Mirror,
+    Backup(BackupState),
+}
+
+impl WriteFilter {
+    pub(crate) fn lock(&self) -> MutexGuard<FilterState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Called once the guest write of `len` bytes at `offset` completes.
+    pub(crate) fn written(&self, offset: u64, len: u64) {
+        if let Some(dirty) = self.dirty.lock().unwrap().as_mut() {
+            dirty.mark(offset, len);
+        }
+    }
+}
+
+impl FilterState {
+    /// If the guest requests on `image` are held.
+    pub(crate) fn holds(&self, image: Option<&Arc<File>>) -> bool {
+        match (self.frozen.as_ref(), image) {
+            (Some(frozen), Some(image)) => std::ptr::eq(frozen.as_ptr(), Arc::as_ptr(image)),
+            _ => false,
+        }
+    }
+
+    /// If the guest write of `len` bytes at `offset` is held until the block
+    /// job copies the data it overwrites.
+    pub(crate) fn holds_write(&mut self, offset: u64, len: u64) -> bool {
+        match self.hook.as_mut() {
+            Some(WriteHook::Backup(backup)) => backup.holds_write(offset, len),
+            _ => false,
+        }
+    }
+
+    /// Called before a guest write is submitted. Returns true if
+    /// `WriteFilter::written` is to be called once it completes.
+    pub(crate) fn before_write(&self) -> bool {
+        matches!(self.hook, Some(WriteHook::Mirror))
+    }
+}
+
+struct DirtyClusters {
+    cluster_size: u64,
+    /// Guest offsets of the clusters.
+    clusters: BTreeSet<u64>,
+}
+
+impl DirtyClusters {
+    fn mark(&mut self, offset: u64, len: u64) {
+        let mut cluster = offset - offset % self.cluster_size;
+        while cluster < offset + len {
+            self.clusters.insert(cluster);
+            cluster += self.cluster_size;
+        }
+    }
+}
+
+/// The clusters a backup job copies, by index. They are copied by the job
+/// thread, the guest writes overwriting them are held until they are.
+struct BackupState {
+    cluster_size: u64,
+    /// Clusters not copied yet.
+    pending: Vec<bool>,
+    /// Next cluster copied in order.
+    next: usize,
+    /// Clusters not copied yet which guest writes wait for, copied first.
+    urgent: BTreeSet<usize>,
+}
+
+impl BackupState {
+    /// Take the next cluster to copy, and if guest writes wait for it.
+    fn next_cluster(&mut self) -> Option<(usize, bool)> {
+        if let Some(&index) = self.urgent.iter().next() {
+            return Some((index, true));
+        }
+        while self.next < self.pending.len() {
+            let index = self.next;
+            self.next += 1;
+            if self.pending[index] {
+                return Some((index, false));
+            }
+        }
+        None
+    }
+
+    /// Mark the cluster at `index` copied, returns true if guest writes wait
+    /// for it.
+    fn copied(&mut self, index: usize) -> bool {
+        self.pending[index] = false;
+        self.urgent.remove(&index)
+    }
+
+    fn holds_write(&mut self, offset: u64, len: u64) -> bool {
+        if len == 0 {
+            return false;
+        }
+        let first = (offset / self.cluster_size) as usize;
+        let last = ((offset + len - 1) / self.cluster_size) as usize;
+        let mut held = false;
+        for index in first..=last.min(self.pending.len() - 1) {
+            if self.pending[index] {
+                self.urgent.insert(index);
+                held = true;
+            }
+        }
+        held
+    }
+}
+
+/// Hold the guest requests on the overlay, and wait for those in flight.
+fn quiesce(top: &Mutex<Qcow2Image>, in_flight: &AtomicUsize) {
+    top.lock().unwrap().set_frozen(true);
+    while in_flight.load(Ordering::SeqCst) != 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Hold the guest requests on the current image of the device, and wait for
+/// those in flight.
+fn freeze(images: &CopyImages) {
+    images.filter.lock().frozen = Some(Arc::downgrade(&images.file));
+    while images.in_flight.load(Ordering::SeqCst) != 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Remove the hook of the job, and let the handlers execute the guest
+/// requests held.
+fn remove_hook(block: &Mutex<Block>, filter: &WriteFilter) -> Result<()> {
+    let mut state = filter.lock();
+    let hook = state.hook.take();
+    *filter.dirty.lock().unwrap() = None;
+    if state.frozen.take().is_some() {
+        drop(state);
+        block.lock().unwrap().update_handlers()?;
+    } else if matches!(hook, Some(WriteHook::Backup(_))) {
+        drop(state);
+        block.lock().unwrap().resume_deferred_requests()?;
+    }
+    Ok(())
+}
+
+/// Guest offsets of the clusters a mirror or backup job copies.
+fn clusters_to_copy(images: &CopyImages, cluster_size: u64, sync: SyncMode) -> Result<Vec<u64>> {
+    match (images.qcow2.as_ref(), sync) {
+        (Some(qcow2), SyncMode::Top) => qcow2.lock().unwrap().allocated_clusters(),
+        _ => Ok((0..images.size).step_by(cluster_size as usize).collect()),
+    }
+}
+
+fn run_commit(job: &BlockJob, block: &Mutex<Block>, images: CommitImages) -> Result<()> {
+    if let Err(e) = copy_overlay(job, block, &images) {
+        let mut top = images.top.lock().unwrap();
+        top.track_dirty(false);
+        if top.frozen() {
+            top.set_frozen(false);
+            drop(top);
+            // Let the handlers execute the requests held meanwhile.
+            block.lock().unwrap().update_handlers()?;
+        }
+        return Err(e);
+    }
+
+    let CommitImages {
+        base,
+        base_path,
+        base_format,
+        ..
+    } = images;
+    block.lock().unwrap().pivot(base_path, base_format, base)
+}
+
+/// Copy the overlay into the backing image, the overlay is left frozen with
+/// all its data copied.
+fn copy_overlay(job: &BlockJob, block: &Mutex<Block>, images: &CommitImages) -> Result<()> {
+    let target = JobImage::open(&images.base_path, images.base.qcow2(), true)?;
+    let (cluster_size, size) = {
+        let top = images.top.lock().unwrap();
+        (top.cluster_size(), top.virtual_size())
+    };
+    let mut buf = vec![0_u8; cluster_size as usize];
+    let mut copy_cluster = |offset: u64| -> Result<()> {
+        let len = cluster_size.min(size - offset) as usize;
+        images
+            .top
+            .lock()
+            .unwrap()
+            .read_at(offset, &mut buf[..len])?;
+        target.write_at(offset, &buf[..len])?;
+        job.offset.fetch_add(len as u64, Ordering::SeqCst);
+        Ok(())
+    };
+
+    // The writes mapped before the clusters are recorded must land before
+    // the clusters are copied.
+    quiesce(&images.top, &images.in_flight);
+    let clusters = {
+        let mut top = images.top.lock().unwrap();
+        top.track_dirty(true);
+        top.set_frozen(false);
+        top.allocated_clusters()?
+    };
+    block.lock().unwrap().update_handlers()?;
+
+    job.len
+        .store(clusters.len() as u64 * cluster_size, Ordering::SeqCst);
+    let start = Instant::now();
+    for offset in clusters {
+        job.check_cancelled()?;
+        copy_cluster(offset)?;
+        job.throttle(start);
+    }
+
+    // Clusters written meanwhile are copied again with the guest requests
+    // held, they are executed on the backing image after the switch.
+    quiesce(&images.top, &images.in_flight);
+    let dirty = images.top.lock().unwrap().take_dirty();
+    job.len
+        .fetch_add(dirty.len() as u64 * cluster_size, Ordering::SeqCst);
+    for offset in dirty {
+        copy_cluster(offset)?;
+    }
+    target.sync()
+}
+
+fn run_mirror(
+    job: &BlockJob,
+    block: &Mutex<Block>,
+    images: CopyImages,
+    sync: SyncMode,
+) -> Result<()> {
+    if let Err(e) = mirror_disk(job, block, &images, sync) {
+        remove_hook(block, &images.filter)?;
+        block
+            .lock()
+            .unwrap()
+            .release_drive_file(&images.target_path)?;
+        return Err(e);
+    }
+
+    // The guest requests stay held on the previous image, the handlers
+    // execute them on the target once switched.
+    images.filter.lock().hook = None;
+    *images.filter.dirty.lock().unwrap() = None;
+    let CopyImages {
+        target,
+        target_path,
+        target_format,
+        ..
+    } = images;
+    block
+        .lock()
+        .unwrap()
+        .pivot(target_path, target_format, target)
+}
+
+/// Copy the disk into the target until the job is completed, the disk is
+/// left frozen with all its data copied.
+fn mirror_disk(
+    job: &BlockJob,
+    block: &Mutex<Block>,
+    images: &CopyImages,
+    sync: SyncMode,
+) -> Result<()> {
+    let source = JobImage::open(&images.path, images.qcow2.as_ref(), false)?;
+    let target = JobImage::open(&images.target_path, images.target.qcow2(), true)?;
+    let cluster_size = images.qcow2.as_ref().map_or(RAW_CLUSTER_SIZE, |qcow2| {
+        qcow2.lock().unwrap().cluster_size()
+    });
+    let copy_cluster = |offset: u64| -> Result<()> {
+        let len = cluster_size.min(images.size - offset);
+        copy_range(&source, &target, offset, len)?;
+        job.offset.fetch_add(len, Ordering::SeqCst);
+        Ok(())
+    };
+    // Take the first dirty cluster, before it is copied so that the guest
+    // writes completed meanwhile make it dirty again.
+    let take_dirty = || -> Option<u64> {
+        let mut dirty = images.filter.dirty.lock().unwrap();
+        let clusters = &mut dirty.as_mut()?.clusters;
+        let next = clusters.iter().next().copied();
+        if let Some(offset) = next {
+            clusters.remove(&offset);
+        }
+        let remaining = clusters.len() as u64 * cluster_size;
+        job.len.store(
+            job.offset.load(Ordering::SeqCst) + remaining,
+            Ordering::SeqCst,
+        );
+        next
+    };
+
+    // The writes submitted before the hook is set must land before the
+    // clusters are copied.
+    freeze(images);
+    let clusters = clusters_to_copy(images, cluster_size, sync)?;
+    *images.filter.dirty.lock().unwrap() = Some(DirtyClusters {
+        cluster_size,
+        clusters: clusters.into_iter().collect(),
+    });
+    images.filter.lock().hook = Some(WriteHook::Mirror);
+    thaw(block, &images.filter)?;
+
+    let start = Instant::now();
+    loop {
+        job.check_cancelled()?;
+        if let Some(offset) = take_dirty() {
+            copy_cluster(offset)?;
+            job.throttle(start);
+            continue;
+        }
+        if !job.ready.load(Ordering::SeqCst) {
+            target.sync()?;
+            job.set_ready();
+        }
+        if job.completing.load(Ordering::SeqCst) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Clusters written lately are copied with the guest requests held, they
+    // are executed on the target after the switch.
+    freeze(images);
+    while let Some(offset) = take_dirty() {
+        copy_cluster(offset)?;
+    }
+    target.sync()
+}
+
+/// Let the handlers execute the guest requests held by `freeze`.
+fn thaw(block: &Mutex<Block>, filter: &WriteFilter) -> Result<()> {
+    filter.lock().frozen = None;
+    block.lock().unwrap().update_handlers()
+}
+
+fn run_backup(
+    job: &Arc<BlockJob>,
+    block: &Mutex<Block>,
+    images: CopyImages,
+    sync: SyncMode,
+) -> Result<()> {
+    let result = backup_disk(job, block, &images, sync);
+    remove_hook(block, &images.filter)?;
+    block
+        .lock()
+        .unwrap()
+        .release_drive_file(&images.target_path)?;
+    result
+}
+
+fn backup_disk(
+    job: &BlockJob,
+    block: &Mutex<Block>,
+    images: &CopyImages,
+    sync: SyncMode,
+) -> Result<()> {
+    let source = JobImage::open(&images.path, images.qcow2.as_ref(), false)?;
+    let target = JobImage::open(&images.target_path, images.target.qcow2(), true)?;
+    let cluster_size = images.qcow2.as_ref().map_or(RAW_CLUSTER_SIZE, |qcow2| {
+        qcow2.lock().unwrap().cluster_size()
+    });
+    let count = ((images.size + cluster_size - 1) / cluster_size) as usize;
+
+    // The disk is copied as it is once the writes in flight land.
+    freeze(images);
+    let mut pending = vec![false; count];
+    let mut len = 0;
+    for offset in clusters_to_copy(images, cluster_size, sync)? {
+        pending[(offset / cluster_size) as usize] = true;
+        len += cluster_size.min(images.size - offset);
+    }
+    job.len.store(len, Ordering::SeqCst);
+    images.filter.lock().hook = Some(WriteHook::Backup(BackupState {
+        cluster_size,
+        pending,
+        next: 0,
+        urgent: BTreeSet::new(),
+    }));
+    thaw(block, &images.filter)?;
+
+    // The clusters are copied without the hook locked, the guest writes
+    // overwriting them are held meanwhile.
+    let start = Instant::now();
+    loop {
+        job.check_cancelled()?;
+        let (index, urgent) = match with_backup(job, &images.filter, BackupState::next_cluster)? {
+            Some(next) => next,
+            None => break,
+        };
+        let offset = index as u64 * cluster_size;
+        let len = cluster_size.min(images.size - offset);
+        copy_range(&source, &target, offset, len)?;
+        job.offset.fetch_add(len, Ordering::SeqCst);
+        if with_backup(job, &images.filter, |backup| backup.copied(index))? {
+            // Let the handlers execute the guest writes held for the cluster.
+            block.lock().unwrap().resume_deferred_requests()?;
+        }
+        if !urgent {
+            job.throttle(start);
+        }
+    }
+    target.sync()
+}
+
+/// Run `f` on the state of the backup job hooked on the guest writes.
+fn with_backup<T>(
+    job: &BlockJob,
+    filter: &WriteFilter,
+    f: impl FnOnce(&mut BackupState) -> T,
+) -> Result<T> {
+    match filter.lock().hook.as_mut() {
+        Some(WriteHook::Backup(backup)) => Ok(f(backup)),
+        _ => bail!("Block job {} lost its hook on the guest writes", job.id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VirtioDevice;
+    use machine_manager::config::{BlkDevConfig, DriveFile, VmConfig};
+    use vmm_sys_util::tempfile::TempFile;
+
+    type DriveFiles = Arc<Mutex<HashMap<String, DriveFile>>>;
+
+    /// Tests run in parallel, so each uses its own device.
+    fn open_block(
+        id: &str,
+        path: &str,
+        format: DiskFormat,
+        files: &[&str],
+    ) -> (Arc<Mutex<Block>>, DriveFiles) {
+        let mut drive_files = HashMap::new();
+        for file in files {
+            VmConfig::add_drive_file(&mut drive_files, file, false, false).unwrap();
+        }
+        let config = BlkDevConfig {
+            id: id.to_string(),
+            path_on_host: path.to_string(),
+            format,
+            ..Default::default()
+        };
+        let drive_files = Arc::new(Mutex::new(drive_files));
+        let block = Arc::new(Mutex::new(Block::new(config, drive_files.clone())));
+        block.lock().unwrap().realize().unwrap();
+        (block, drive_files)
+    }
+
+    fn job_info(id: &str) -> Option<qmp_schema::BlockJobInfo> {
+        block_jobs().into_iter().find(|job| job.device == id)
+    }
+
+    fn wait_job_done(id: &str) {
+        while job_info(id).is_some() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_commit_job() {
+        let base = TempFile::new().unwrap();
+        let base_path = base.as_path().to_str().unwrap().to_string();
+        base.as_file().set_len(4 << 20).unwrap();
+        base.as_file().write_all_at(&[0x5a_u8; 4096], 0).unwrap();
+        let overlay = TempFile::new().unwrap();
+        let path = overlay.as_path().to_str().unwrap().to_string();
+        Qcow2Image::create(&path, 4 << 20, Some((&base_path, DiskFormat::Raw))).unwrap();
+        let data = Arc::new(overlay.as_file().try_clone().unwrap());
+        let mut top = Qcow2Image::open(&path, data, false, false).unwrap();
+        top.write_at(512, &[0xa5_u8; 1024]).unwrap();
+        top.write_at((3 << 20) + 4096, &[0xff_u8; 4096]).unwrap();
+        drop(top);
+        let (block, _) = open_block(
+            "drive-commit",
+            &path,
+            DiskFormat::Qcow2,
+            &[&path, &base_path],
+        );
+
+        start_commit_job(&block, Some("commit-0".to_string()), 0).unwrap();
+        assert!(start_commit_job(&block, None, 0).is_err());
+        wait_job_done("commit-0");
+
+        // The device is switched onto the base, and the overlay released.
+        let info = block.lock().unwrap().query_info().inserted.unwrap();
+        assert_eq!(info.file, base_path);
+        assert_eq!(info.drv, "raw");
+        assert!(!device_has_job("drive-commit"));
+        let mut buf = vec![0_u8; 4096];
+        base.as_file().read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0x5a));
+        assert!(buf[512..1536].iter().all(|&b| b == 0xa5));
+        assert!(buf[1536..].iter().all(|&b| b == 0x5a));
+        base.as_file()
+            .read_exact_at(&mut buf, (3 << 20) + 4096)
+            .unwrap();
+        assert!(buf.iter().all(|&b| b == 0xff));
+
+        // Nothing left to commit.
+        assert!(start_commit_job(&block, None, 0).is_err());
+    }
+
+    #[test]
+    fn test_mirror_job() {
+        let source = TempFile::new().unwrap();
+        let path = source.as_path().to_str().unwrap().to_string();
+        source
+            .as_file()
+            .write_all_at(&[0x11_u8; 4 << 16], 0)
+            .unwrap();
+        let target = TempFile::new().unwrap();
+        let target_path = target.as_path().to_str().unwrap().to_string();
+        let (block, drive_files) = open_block("drive-mirror", &path, DiskFormat::Raw, &[&path]);
+        block
+            .lock()
+            .unwrap()
+            .create_target(&target_path, DiskFormat::Raw, false)
+            .unwrap();
+        VmConfig::add_drive_file(&mut drive_files.lock().unwrap(), &target_path, false, false)
+            .unwrap();
+        let filter = block.lock().unwrap().filter.clone();
+
+        let target_image = (target_path.as_str(), DiskFormat::Raw);
+        let id = Some("mirror-0".to_string());
+        start_copy_job(&block, true, id, target_image, SyncMode::Full, 0).unwrap();
+        assert!(cancel_block_job("mirror-1").is_err());
+        while !job_info("mirror-0").unwrap().ready {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(complete_block_job("mirror-1").is_err());
+
+        // A guest write after the disk is copied is mirrored too.
+        assert!(!filter.lock().holds_write(1 << 16, 4096));
+        assert!(filter.lock().before_write());
+        source
+            .as_file()
+            .write_all_at(&[0x22_u8; 4096], 1 << 16)
+            .unwrap();
+        filter.written(1 << 16, 4096);
+        complete_block_job("mirror-0").unwrap();
+        wait_job_done("mirror-0");
+
+        let info = block.lock().unwrap().query_info().inserted.unwrap();
+        assert_eq!(info.file, target_path);
+        assert!(!drive_files.lock().unwrap().contains_key(&path));
+        let mut buf = vec![0_u8; 4 << 16];
+        target.as_file().read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf[..1 << 16].iter().all(|&b| b == 0x11));
+        assert!(buf[1 << 16..(1 << 16) + 4096].iter().all(|&b| b == 0x22));
+        assert!(buf[(1 << 16) + 4096..].iter().all(|&b| b == 0x11));
+    }
+
+    #[test]
+    fn test_backup_job() {
+        let source = TempFile::new().unwrap();
+        let path = source.as_path().to_str().unwrap().to_string();
+        source
+            .as_file()
+            .write_all_at(&[0x11_u8; 4 << 16], 0)
+            .unwrap();
+        let target = TempFile::new().unwrap();
+        let target_path = target.as_path().to_str().unwrap().to_string();
+        let (block, drive_files) = open_block("drive-backup", &path, DiskFormat::Raw, &[&path]);
+        block
+            .lock()
+            .unwrap()
+            .create_target(&target_path, DiskFormat::Raw, false)
+            .unwrap();
+        VmConfig::add_drive_file(&mut drive_files.lock().unwrap(), &target_path, false, false)
+            .unwrap();
+        let filter = block.lock().unwrap().filter.clone();
+
+        // Slow enough for the guest write to come before the last cluster
+        // is copied in order, the job copies it first once the write is held.
+        let target_image = (target_path.as_str(), DiskFormat::Raw);
+        let id = Some("backup-0".to_string());
+        start_copy_job(&block, false, id, target_image, SyncMode::Full, 128 << 10).unwrap();
+        assert!(complete_block_job("backup-0").is_err());
+        while filter.lock().hook.is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(filter.lock().holds_write(3 << 16, 4096));
+        while filter.lock().holds_write(3 << 16, 4096) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!filter.lock().before_write());
+        assert!(job_info("backup-0").is_some());
+        source
+            .as_file()
+            .write_all_at(&[0x22_u8; 4096], 3 << 16)
+            .unwrap();
+        wait_job_done("backup-0");
+
+        // The backup has the data before the write, and the device stays on
+        // its image.
+        assert!(filter.lock().hook.is_none());
+        let info = block.lock().unwrap().query_info().inserted.unwrap();
+        assert_eq!(info.file, path);
+        assert!(!drive_files.lock().unwrap().contains_key(&target_path));
+        let mut buf = vec![0_u8; 4 << 16];
+        target.as_file().read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x11));
+    }
+}
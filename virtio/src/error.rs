@@ -73,4 +73,6 @@ pub enum VirtioError {
     DevStatErr(u32),
     #[error("Unsupported mmio register at offset 0x{0:x}.")]
     MmioRegErr(u64),
+    #[error("Unsupported virtio-pci common config register at offset 0x{0:x}.")]
+    PciRegErr(u64),
 }
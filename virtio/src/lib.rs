@@ -28,14 +28,23 @@
 
 
 mod block;
+mod block_job;
 mod console;
 pub mod error;
+mod nbd;
 mod net;
+mod qcow2;
+mod usernet;
 pub mod vhost;
 mod virtio_mmio;
+mod virtio_pci;
 mod virtqueue;
 pub use anyhow::Result;
-pub use block::{Block, BlockState};
+pub use block::{block_devices, find_block_device, register_block_device, Block, BlockState};
+pub use block_job::{
+    block_jobs, cancel_block_job, complete_block_job, device_has_job, start_commit_job,
+    start_copy_job, SyncMode,
+};
 pub use console::{Console, VirtioConsoleState};
 pub use error::VirtioError;
 pub use error::*;
@@ -46,6 +55,7 @@ pub use virtqueue::*;
 pub use vhost::kernel as VhostKern;
 pub use vhost::user as VhostUser;
 pub use virtio_mmio::{VirtioMmioDevice, VirtioMmioState};
+pub use virtio_pci::VirtioPciDevice;
 
 use std::cmp;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -219,6 +229,12 @@ pub const VIRTIO_BLK_T_OUT: u32 = 1;
 pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
 /// Device id
 pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+/// Discard.
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
+/// Write zeroes.
+pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+/// Write zeroes may deallocate the sectors.
+pub const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
 /// Device id length
 pub const VIRTIO_BLK_ID_BYTES: u32 = 20;
 /// Success
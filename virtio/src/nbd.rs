@@ -0,0 +1,1336 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Client of the NBD protocol, so a block device can be backed by an export
+//! of an NBD server.
+//!
+//! The requests of a block IO handler are sent by a thread of its client, and
+//! the replies are read by another one, which writes the data read into the
+//! guest memory. The handler is then notified by an eventfd to complete the
+//! requests in its event loop. If the connection is lost, the client connects
+//! again and sends the requests not answered yet. The requests fail with EIO
+//! while the server can't be reached for long, and when the server doesn't
+//! answer them in time.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::{error, warn};
+use machine_manager::config::{NbdServer, NbdUri};
+use util::aio::{
+    iov_discard_front_direct, iov_from_buf_direct, iov_to_buf_direct, AioCb, AioCompleteFunc,
+    Iovec, OpCode,
+};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Magic of the server greeting.
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+/// Magic of the newstyle negotiation and of the options.
+const NBD_OPTS_MAGIC: u64 = 0x4948_4156_454f_5054;
+/// Magic of the replies to options.
+const NBD_REP_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const NBD_OPT_GO: u32 = 7;
+const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+const NBD_INFO_EXPORT: u16 = 0;
+
+// Transmission flags of an export.
+const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_FUA: u16 = 1 << 3;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+const NBD_REPLY_TYPE_NONE: u16 = 0;
+const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const NBD_REPLY_TYPE_ERROR_BIT: u16 = 1 << 15;
+
+/// Max length of a request, longer block requests are split.
+const NBD_MAX_REQUEST_LEN: u64 = 32 << 20;
+/// Max length of the data of an option reply or of an error chunk.
+const NBD_MAX_REPLY_DATA: u32 = 64 << 10;
+/// Time to wait for the server during the handshake.
+const NBD_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay between the attempts to connect again.
+#[cfg(not(test))]
+const NBD_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+#[cfg(test)]
+const NBD_RECONNECT_DELAY: Duration = Duration::from_millis(10);
+/// Failed attempts to connect again before the requests fail, until connected.
+const NBD_RECONNECT_ATTEMPTS: u32 = 10;
+/// Time the server has to answer a request before the connection is dropped.
+#[cfg(not(test))]
+const NBD_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+#[cfg(test)]
+const NBD_REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Socket connected to an NBD server.
+enum NbdStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl NbdStream {
+    fn connect(server: &NbdServer) -> Result<Self> {
+        match server {
+            NbdServer::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)
+                    .with_context(|| format!("Failed to connect to NBD server {}", addr))?;
+                stream.set_nodelay(true)?;
+                Ok(NbdStream::Tcp(stream))
+            }
+            NbdServer::Unix(path) => UnixStream::connect(path)
+                .map(NbdStream::Unix)
+                .with_context(|| format!("Failed to connect to NBD server {}", path)),
+        }
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(match self {
+            NbdStream::Tcp(stream) => NbdStream::Tcp(stream.try_clone()?),
+            NbdStream::Unix(stream) => NbdStream::Unix(stream.try_clone()?),
+        })
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        match self {
+            NbdStream::Tcp(stream) => stream.set_read_timeout(timeout)?,
+            NbdStream::Unix(stream) => stream.set_read_timeout(timeout)?,
+        }
+        Ok(())
+    }
+
+    /// Close the connection, which wakes up the thread reading it.
+    fn shutdown(&self) {
+        let _ = match self {
+            NbdStream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            NbdStream::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for NbdStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            NbdStream::Tcp(stream) => stream.read(buf),
+            NbdStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for NbdStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            NbdStream::Tcp(stream) => stream.write(buf),
+            NbdStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            NbdStream::Tcp(stream) => stream.flush(),
+            NbdStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Export of an NBD server backing a block device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct NbdExport {
+    pub(crate) uri: NbdUri,
+    /// Size of the export, in bytes.
+    pub(crate) size: u64,
+    /// Transmission flags of the export.
+    flags: u16,
+}
+
+impl NbdExport {
+    /// Connect to the server to get the export at `uri`.
+    pub(crate) fn open(uri: &NbdUri) -> Result<Self> {
+        let mut conn = NbdConnection::connect(uri)?;
+        disconnect(&mut conn.stream);
+        Ok(conn.export)
+    }
+
+    fn has_flag(&self, flag: u16) -> bool {
+        self.flags & NBD_FLAG_HAS_FLAGS != 0 && self.flags & flag != 0
+    }
+
+    pub(crate) fn read_only(&self) -> bool {
+        self.has_flag(NBD_FLAG_READ_ONLY)
+    }
+
+    pub(crate) fn can_trim(&self) -> bool {
+        self.has_flag(NBD_FLAG_SEND_TRIM)
+    }
+
+    pub(crate) fn can_write_zeroes(&self) -> bool {
+        self.has_flag(NBD_FLAG_SEND_WRITE_ZEROES)
+    }
+}
+
+/// Connection to an NBD server, which has negotiated the export.
+struct NbdConnection {
+    stream: NbdStream,
+    export: NbdExport,
+    /// The server sends structured replies.
+    structured: bool,
+}
+
+impl NbdConnection {
+    fn connect(uri: &NbdUri) -> Result<Self> {
+        let mut stream = NbdStream::connect(&uri.server)?;
+        stream.set_read_timeout(Some(NBD_HANDSHAKE_TIMEOUT))?;
+        let (size, flags, structured) = handshake(&mut stream, &uri.export)
+            .with_context(|| format!("Failed to negotiate with NBD server {:?}", uri.server))?;
+        stream.set_read_timeout(None)?;
+        Ok(NbdConnection {
+            stream,
+            export: NbdExport {
+                uri: uri.clone(),
+                size,
+                flags,
+            },
+            structured,
+        })
+    }
+}
+
+/// Negotiate the export `name` by the fixed newstyle handshake, return its
+/// size, its transmission flags and whether structured replies are used.
+fn handshake<S: Read + Write>(stream: &mut S, name: &str) -> Result<(u64, u16, bool)> {
+    let mut greeting = [0_u8; 18];
+    stream.read_exact(&mut greeting)?;
+    if BigEndian::read_u64(&greeting[0..8]) != NBD_MAGIC {
+        bail!("Not an NBD server");
+    }
+    if BigEndian::read_u64(&greeting[8..16]) != NBD_OPTS_MAGIC {
+        bail!("Oldstyle negotiation is not supported");
+    }
+    let flags = BigEndian::read_u16(&greeting[16..18]);
+    if flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+        bail!("Fixed newstyle negotiation is not supported by the server");
+    }
+    let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+    if flags & NBD_FLAG_NO_ZEROES != 0 {
+        client_flags |= NBD_FLAG_C_NO_ZEROES;
+    }
+    stream.write_all(&client_flags.to_be_bytes())?;
+
+    send_option(stream, NBD_OPT_STRUCTURED_REPLY, &[])?;
+    let (reply, _) = read_option_reply(stream, NBD_OPT_STRUCTURED_REPLY)?;
+    // Simple replies are used if the server refuses.
+    let structured = reply == NBD_REP_ACK;
+
+    let mut data = Vec::with_capacity(name.len() + 6);
+    data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    data.extend_from_slice(name.as_bytes());
+    // No information is requested, the server sends the export info anyway.
+    data.extend_from_slice(&0_u16.to_be_bytes());
+    send_option(stream, NBD_OPT_GO, &data)?;
+    let mut export = None;
+    loop {
+        let (reply, data) = read_option_reply(stream, NBD_OPT_GO)?;
+        match reply {
+            NBD_REP_ACK => break,
+            NBD_REP_INFO => {
+                if data.len() >= 12 && BigEndian::read_u16(&data[0..2]) == NBD_INFO_EXPORT {
+                    export = Some((
+                        BigEndian::read_u64(&data[2..10]),
+                        BigEndian::read_u16(&data[10..12]),
+                    ));
+                }
+            }
+            _ if reply & NBD_REP_FLAG_ERROR != 0 => bail!(
+                "Export \"{}\" is refused: {}",
+                name,
+                String::from_utf8_lossy(&data)
+            ),
+            _ => bail!("Unexpected reply {} to option {}", reply, NBD_OPT_GO),
+        }
+    }
+    let (size, flags) = export.with_context(|| format!("No info of export \"{}\"", name))?;
+    Ok((size, flags, structured))
+}
+
+fn send_option<S: Write>(stream: &mut S, option: u32, data: &[u8]) -> Result<()> {
+    let mut header = [0_u8; 16];
+    BigEndian::write_u64(&mut header[0..8], NBD_OPTS_MAGIC);
+    BigEndian::write_u32(&mut header[8..12], option);
+    BigEndian::write_u32(&mut header[12..16], data.len() as u32);
+    stream.write_all(&header)?;
+    stream.write_all(data)?;
+    Ok(())
+}
+
+/// Read the reply to `option`, return its type and data.
+fn read_option_reply<S: Read>(stream: &mut S, option: u32) -> Result<(u32, Vec<u8>)> {
+    let mut header = [0_u8; 20];
+    stream.read_exact(&mut header)?;
+    if BigEndian::read_u64(&header[0..8]) != NBD_REP_MAGIC
+        || BigEndian::read_u32(&header[8..12]) != option
+    {
+        bail!("Invalid reply to option {}", option);
+    }
+    let len = BigEndian::read_u32(&header[16..20]);
+    if len > NBD_MAX_REPLY_DATA {
+        bail!("Reply to option {} is too long: {}", option, len);
+    }
+    let mut data = vec![0; len as usize];
+    stream.read_exact(&mut data)?;
+    Ok((BigEndian::read_u32(&header[12..16]), data))
+}
+
+/// Tell the server the client disconnects, and close the connection.
+fn disconnect(stream: &mut NbdStream) {
+    let _ = send_request(
+        stream,
+        0,
+        &NbdRequest {
+            command: NBD_CMD_DISC,
+            ..Default::default()
+        },
+    );
+    stream.shutdown();
+}
+
+/// A request sent to the server, until it is answered.
+#[derive(Clone, Default)]
+struct NbdRequest {
+    command: u16,
+    flags: u16,
+    offset: u64,
+    len: u32,
+    /// Guest memory the data is read into or written from.
+    iovec: Vec<Iovec>,
+    /// Number of the connection the request is sent on, 0 if not sent yet.
+    generation: u64,
+    /// When the request was sent on the connection.
+    sent: Option<Instant>,
+}
+
+fn send_request<S: Write>(stream: &mut S, handle: u64, req: &NbdRequest) -> Result<()> {
+    let mut header = [0_u8; 28];
+    BigEndian::write_u32(&mut header[0..4], NBD_REQUEST_MAGIC);
+    BigEndian::write_u16(&mut header[4..6], req.flags);
+    BigEndian::write_u16(&mut header[6..8], req.command);
+    BigEndian::write_u64(&mut header[8..16], handle);
+    BigEndian::write_u64(&mut header[16..24], req.offset);
+    BigEndian::write_u32(&mut header[24..28], req.len);
+    stream.write_all(&header)?;
+    if req.command == NBD_CMD_WRITE {
+        let mut data = vec![0; req.len as usize];
+        iov_to_buf_direct(&req.iovec, &mut data)?;
+        stream.write_all(&data)?;
+    }
+    Ok(())
+}
+
+/// The `len` bytes of `iovec` from byte `skip`.
+fn iov_range(iovec: &[Iovec], skip: u64, mut len: u64) -> Vec<Iovec> {
+    let mut iovec = iovec.to_vec();
+    let mut range = Vec::new();
+    if let Some(iovec) = iov_discard_front_direct(&mut iovec, skip) {
+        for iov in iovec.iter() {
+            if len == 0 {
+                break;
+            }
+            let iov_len = cmp::min(iov.iov_len, len);
+            range.push(Iovec {
+                iov_base: iov.iov_base,
+                iov_len,
+            });
+            len -= iov_len;
+        }
+    }
+    range
+}
+
+/// Map the error of a reply to a negative errno.
+fn nbd_error(error: u32) -> i64 {
+    let errno = match error {
+        1 => libc::EPERM,
+        12 => libc::ENOMEM,
+        22 => libc::EINVAL,
+        28 => libc::ENOSPC,
+        75 => libc::EOVERFLOW,
+        95 => libc::EOPNOTSUPP,
+        108 => libc::ESHUTDOWN,
+        _ => libc::EIO,
+    };
+    -i64::from(errno)
+}
+
+/// State shared by a client and the threads serving it.
+struct NbdShared {
+    /// Requests not answered yet, by handle.
+    inflight: Mutex<HashMap<u64, NbdRequest>>,
+    /// Handles and results of the requests answered.
+    completed: Mutex<Vec<(u64, i64)>>,
+    /// Notifies the client of the requests answered.
+    complete_evt: Arc<EventFd>,
+    /// The client is closed.
+    closed: AtomicBool,
+}
+
+impl NbdShared {
+    fn request(&self, handle: u64) -> Result<NbdRequest> {
+        self.inflight
+            .lock()
+            .unwrap()
+            .get(&handle)
+            .cloned()
+            .with_context(|| format!("Reply to unknown handle {}", handle))
+    }
+
+    fn complete(&self, handle: u64, ret: i64) -> Result<()> {
+        self.inflight.lock().unwrap().remove(&handle);
+        self.completed.lock().unwrap().push((handle, ret));
+        self.complete_evt.write(1)?;
+        Ok(())
+    }
+
+    /// Fail the requests not answered yet which `expired` selects, return
+    /// whether there were any.
+    fn fail_requests<F: Fn(&NbdRequest) -> bool>(&self, expired: F) -> Result<bool> {
+        let mut inflight = self.inflight.lock().unwrap();
+        let handles: Vec<u64> = inflight
+            .iter()
+            .filter(|(_, req)| expired(req))
+            .map(|(handle, _)| *handle)
+            .collect();
+        if handles.is_empty() {
+            return Ok(false);
+        }
+        let mut completed = self.completed.lock().unwrap();
+        for handle in handles {
+            inflight.remove(&handle);
+            completed.push((handle, -i64::from(libc::EIO)));
+        }
+        self.complete_evt.write(1)?;
+        Ok(true)
+    }
+}
+
+enum NbdMessage {
+    /// Send the request of the handle.
+    Send(u64),
+    /// The connection of the given number is lost.
+    Lost(u64),
+    /// Disconnect from the server.
+    Close,
+}
+
+/// Send the requests of a client, connecting again if the connection is lost.
+fn run_sender(
+    export: NbdExport,
+    shared: Arc<NbdShared>,
+    messages: Receiver<NbdMessage>,
+    lost: Sender<NbdMessage>,
+) {
+    let mut generation = 0;
+    while let Some(mut conn) = reconnect(&export, &shared, &messages) {
+        generation += 1;
+        if conn.export.size != export.size {
+            warn!(
+                "Size of NBD export \"{}\" changed from {} to {}",
+                export.uri.export, export.size, conn.export.size
+            );
+        }
+        match serve(&mut conn, generation, &shared, &messages, &lost) {
+            Ok(false) => {
+                disconnect(&mut conn.stream);
+                return;
+            }
+            Ok(true) => {}
+            Err(e) => warn!("Failed to send requests to NBD server: {:?}", e),
+        }
+        conn.stream.shutdown();
+    }
+}
+
+/// Connect to the server, retrying until connected or the client is closed.
+/// The requests fail once `NBD_RECONNECT_ATTEMPTS` attempts failed.
+fn reconnect(
+    export: &NbdExport,
+    shared: &NbdShared,
+    messages: &Receiver<NbdMessage>,
+) -> Option<NbdConnection> {
+    let mut attempts = 0;
+    loop {
+        match NbdConnection::connect(&export.uri) {
+            Ok(conn) => return Some(conn),
+            Err(e) if attempts == 0 => warn!("Failed to connect to NBD server, retrying: {:?}", e),
+            Err(_) => {}
+        }
+        attempts += 1;
+        if attempts == NBD_RECONNECT_ATTEMPTS {
+            error!(
+                "NBD server of export \"{}\" is unreachable, failing the requests",
+                export.uri.export
+            );
+        }
+        let failing = attempts >= NBD_RECONNECT_ATTEMPTS;
+        if failing {
+            if let Err(e) = shared.fail_requests(|_| true) {
+                error!("Failed to fail the NBD requests: {:?}", e);
+            }
+        }
+        // The requests to send are all sent once connected.
+        let deadline = Instant::now() + NBD_RECONNECT_DELAY;
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            match messages.recv_timeout(timeout) {
+                Ok(NbdMessage::Close) | Err(RecvTimeoutError::Disconnected) => return None,
+                Ok(NbdMessage::Send(_)) if failing => {
+                    if let Err(e) = shared.fail_requests(|_| true) {
+                        error!("Failed to fail the NBD requests: {:?}", e);
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => break,
+            }
+        }
+    }
+}
+
+/// Send the requests on the connection `generation` until it is lost, return
+/// false once the client is closed.
+fn serve(
+    conn: &mut NbdConnection,
+    generation: u64,
+    shared: &Arc<NbdShared>,
+    messages: &Receiver<NbdMessage>,
+    lost: &Sender<NbdMessage>,
+) -> Result<bool> {
+    let stream = conn.stream.try_clone()?;
+    // The receiver checks the requests are answered in time as it waits.
+    stream.set_read_timeout(Some(NBD_REQUEST_TIMEOUT))?;
+    let structured = conn.structured;
+    let receiver_shared = shared.clone();
+    let lost = lost.clone();
+    thread::Builder::new()
+        .name("nbd-recv".to_string())
+        .spawn(move || run_receiver(stream, structured, generation, &receiver_shared, &lost))?;
+
+    // The requests not answered on the previous connection are sent again.
+    let mut handles: Vec<u64> = shared.inflight.lock().unwrap().keys().copied().collect();
+    handles.sort_unstable();
+    for handle in handles {
+        send_handle(&mut conn.stream, generation, shared, handle)?;
+    }
+    loop {
+        match messages.recv() {
+            Ok(NbdMessage::Send(handle)) => {
+                send_handle(&mut conn.stream, generation, shared, handle)?
+            }
+            Ok(NbdMessage::Lost(number)) if number == generation => return Ok(true),
+            Ok(NbdMessage::Lost(_)) => {}
+            Ok(NbdMessage::Close) | Err(_) => return Ok(false),
+        }
+    }
+}
+
+/// Send the request of `handle` if it is not answered or sent on the connection yet.
+fn send_handle(
+    stream: &mut NbdStream,
+    generation: u64,
+    shared: &NbdShared,
+    handle: u64,
+) -> Result<()> {
+    let req = match shared.inflight.lock().unwrap().get_mut(&handle) {
+        Some(req) if req.generation != generation => {
+            req.generation = generation;
+            req.sent = Some(Instant::now());
+            req.clone()
+        }
+        _ => return Ok(()),
+    };
+    send_request(stream, handle, &req)
+}
+
+/// Read the replies of the connection `generation` until it is lost.
+fn run_receiver(
+    mut stream: NbdStream,
+    structured: bool,
+    generation: u64,
+    shared: &NbdShared,
+    lost: &Sender<NbdMessage>,
+) {
+    if let Err(e) = receive_replies(&mut stream, structured, generation, shared) {
+        if !shared.closed.load(Ordering::SeqCst) {
+            warn!("Connection to NBD server is lost: {:?}", e);
+        }
+    }
+    stream.shutdown();
+    let _ = lost.send(NbdMessage::Lost(generation));
+}
+
+/// Wait for the first byte of a reply of the connection `generation`. The
+/// requests the server doesn't answer in time fail, and the connection is
+/// dropped since the server may still answer them.
+fn wait_reply<S: Read>(
+    stream: &mut S,
+    generation: u64,
+    shared: &NbdShared,
+    buf: &mut [u8],
+) -> Result<()> {
+    loop {
+        match stream.read(&mut buf[..1]) {
+            Ok(0) => bail!("Connection closed by the NBD server"),
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                let expired = shared.fail_requests(|req| {
+                    req.generation == generation
+                        && req
+                            .sent
+                            .map_or(false, |sent| sent.elapsed() >= NBD_REQUEST_TIMEOUT)
+                })?;
+                if expired {
+                    bail!("NBD server didn't answer in {:?}", NBD_REQUEST_TIMEOUT);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn receive_replies<S: Read>(
+    stream: &mut S,
+    structured: bool,
+    generation: u64,
+    shared: &NbdShared,
+) -> Result<()> {
+    // Errors of the structured replies not done yet, by handle.
+    let mut errors: HashMap<u64, i64> = HashMap::new();
+    loop {
+        let mut magic = [0_u8; 4];
+        wait_reply(stream, generation, shared, &mut magic)?;
+        stream.read_exact(&mut magic[1..])?;
+        match BigEndian::read_u32(&magic) {
+            NBD_SIMPLE_REPLY_MAGIC => {
+                let mut header = [0_u8; 12];
+                stream.read_exact(&mut header)?;
+                let error = BigEndian::read_u32(&header[0..4]);
+                let handle = BigEndian::read_u64(&header[4..12]);
+                let req = shared.request(handle)?;
+                let ret = if error != 0 {
+                    nbd_error(error)
+                } else {
+                    if req.command == NBD_CMD_READ {
+                        let mut data = vec![0; req.len as usize];
+                        stream.read_exact(&mut data)?;
+                        iov_from_buf_direct(&req.iovec, &data)?;
+                    }
+                    i64::from(req.len)
+                };
+                shared.complete(handle, ret)?;
+            }
+            NBD_STRUCTURED_REPLY_MAGIC if structured => {
+                let mut header = [0_u8; 16];
+                stream.read_exact(&mut header)?;
+                let flags = BigEndian::read_u16(&header[0..2]);
+                let reply_type = BigEndian::read_u16(&header[2..4]);
+                let handle = BigEndian::read_u64(&header[4..12]);
+                let len = BigEndian::read_u32(&header[12..16]);
+                let req = shared.request(handle)?;
+                let max_len = match reply_type {
+                    NBD_REPLY_TYPE_OFFSET_DATA => u64::from(req.len) + 8,
+                    _ => u64::from(NBD_MAX_REPLY_DATA),
+                };
+                if u64::from(len) > max_len {
+                    bail!("Reply chunk of handle {} is too long: {}", handle, len);
+                }
+                let mut payload = vec![0; len as usize];
+                stream.read_exact(&mut payload)?;
+
+                match reply_type {
+                    NBD_REPLY_TYPE_NONE => {}
+                    NBD_REPLY_TYPE_OFFSET_DATA | NBD_REPLY_TYPE_OFFSET_HOLE
+                        if req.command == NBD_CMD_READ && payload.len() >= 12 =>
+                    {
+                        let offset = BigEndian::read_u64(&payload[0..8]);
+                        let hole = reply_type == NBD_REPLY_TYPE_OFFSET_HOLE;
+                        let chunk_len = if hole {
+                            u64::from(BigEndian::read_u32(&payload[8..12]))
+                        } else {
+                            payload.len() as u64 - 8
+                        };
+                        if offset < req.offset
+                            || offset + chunk_len > req.offset + u64::from(req.len)
+                        {
+                            bail!("Reply chunk of handle {} is out of range", handle);
+                        }
+                        let iovec = iov_range(&req.iovec, offset - req.offset, chunk_len);
+                        if hole {
+                            iov_from_buf_direct(&iovec, &vec![0; chunk_len as usize])?;
+                        } else {
+                            iov_from_buf_direct(&iovec, &payload[8..])?;
+                        }
+                    }
+                    _ if reply_type & NBD_REPLY_TYPE_ERROR_BIT != 0 && payload.len() >= 4 => {
+                        errors
+                            .entry(handle)
+                            .or_insert_with(|| nbd_error(BigEndian::read_u32(&payload[0..4])));
+                    }
+                    _ => bail!(
+                        "Unexpected reply chunk type {} of handle {}",
+                        reply_type,
+                        handle
+                    ),
+                }
+                if flags & NBD_REPLY_FLAG_DONE != 0 {
+                    let ret = errors.remove(&handle).unwrap_or(i64::from(req.len));
+                    shared.complete(handle, ret)?;
+                }
+            }
+            magic => bail!("Invalid reply magic {:#x}", magic),
+        }
+    }
+}
+
+/// A block request served by the NBD server, maybe split into several requests.
+struct NbdPending<T: Clone> {
+    aiocb: AioCb<T>,
+    /// Number of requests not answered yet.
+    parts: usize,
+    /// Result of the first request failed.
+    error: Option<i64>,
+}
+
+/// Client of an NBD export, serving the requests of a block IO handler.
+pub(crate) struct NbdClient<T: Clone + 'static> {
+    export: NbdExport,
+    shared: Arc<NbdShared>,
+    messages: Sender<NbdMessage>,
+    /// Block requests by the handle of their first request.
+    pending: HashMap<u64, NbdPending<T>>,
+    /// The block request of each request sent.
+    handles: HashMap<u64, u64>,
+    next_handle: u64,
+    complete_func: Arc<AioCompleteFunc<T>>,
+}
+
+impl<T: Clone + 'static> NbdClient<T> {
+    /// Start serving requests from `export`. `complete_evt` is written once
+    /// requests are answered, they are then completed by `handle_complete`.
+    pub(crate) fn new(
+        export: NbdExport,
+        complete_func: Arc<AioCompleteFunc<T>>,
+        complete_evt: Arc<EventFd>,
+    ) -> Result<Self> {
+        let shared = Arc::new(NbdShared {
+            inflight: Mutex::new(HashMap::new()),
+            completed: Mutex::new(Vec::new()),
+            complete_evt,
+            closed: AtomicBool::new(false),
+        });
+        let (sender, receiver) = channel();
+        let sender_export = export.clone();
+        let sender_shared = shared.clone();
+        let lost = sender.clone();
+        thread::Builder::new()
+            .name("nbd-send".to_string())
+            .spawn(move || run_sender(sender_export, sender_shared, receiver, lost))
+            .with_context(|| "Failed to create thread of NBD client")?;
+
+        Ok(NbdClient {
+            export,
+            shared,
+            messages: sender,
+            pending: HashMap::new(),
+            handles: HashMap::new(),
+            next_handle: 1,
+            complete_func,
+        })
+    }
+
+    pub(crate) fn export(&self) -> &NbdExport {
+        &self.export
+    }
+
+    /// Send the request of `aiocb` as its opcode. Writes are done with FUA if
+    /// `fua`, and write zeroes may deallocate the range if `unmap`.
+    pub(crate) fn submit_request(&mut self, aiocb: AioCb<T>, fua: bool, unmap: bool) -> Result<()> {
+        let (command, flags, supported) = match aiocb.opcode {
+            OpCode::Noop => return (self.complete_func)(&aiocb, 0),
+            OpCode::Preadv => (NBD_CMD_READ, 0, true),
+            OpCode::Pwritev if fua && self.export.has_flag(NBD_FLAG_SEND_FUA) => {
+                (NBD_CMD_WRITE, NBD_CMD_FLAG_FUA, true)
+            }
+            OpCode::Pwritev => (NBD_CMD_WRITE, 0, true),
+            OpCode::Fdsync => (NBD_CMD_FLUSH, 0, self.export.has_flag(NBD_FLAG_SEND_FLUSH)),
+            OpCode::Discard => (NBD_CMD_TRIM, 0, self.export.can_trim()),
+            OpCode::WriteZeroes if unmap => {
+                (NBD_CMD_WRITE_ZEROES, 0, self.export.can_write_zeroes())
+            }
+            OpCode::WriteZeroes => (
+                NBD_CMD_WRITE_ZEROES,
+                NBD_CMD_FLAG_NO_HOLE,
+                self.export.can_write_zeroes(),
+            ),
+        };
+        if !supported {
+            // The server has no cache to flush, and discard is only a hint.
+            let ret = match command {
+                NBD_CMD_WRITE_ZEROES => -i64::from(libc::EOPNOTSUPP),
+                _ => aiocb.nbytes as i64,
+            };
+            return (self.complete_func)(&aiocb, ret);
+        }
+
+        let id = self.next_handle;
+        let mut parts = 0;
+        let mut done = 0;
+        loop {
+            let len = cmp::min(aiocb.nbytes - done, NBD_MAX_REQUEST_LEN);
+            let req = NbdRequest {
+                command,
+                flags,
+                offset: match command {
+                    NBD_CMD_FLUSH => 0,
+                    _ => aiocb.offset as u64 + done,
+                },
+                len: len as u32,
+                iovec: match command {
+                    NBD_CMD_READ | NBD_CMD_WRITE => iov_range(&aiocb.iovec, done, len),
+                    _ => Vec::new(),
+                },
+                generation: 0,
+                sent: None,
+            };
+            let handle = self.next_handle;
+            self.next_handle += 1;
+            self.shared.inflight.lock().unwrap().insert(handle, req);
+            self.handles.insert(handle, id);
+            self.messages
+                .send(NbdMessage::Send(handle))
+                .with_context(|| "NBD client is closed")?;
+            parts += 1;
+            done += len;
+            if done >= aiocb.nbytes {
+                break;
+            }
+        }
+        self.pending.insert(
+            id,
+            NbdPending {
+                aiocb,
+                parts,
+                error: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Complete the block requests answered by the server.
+    pub(crate) fn handle_complete(&mut self) -> Result<bool> {
+        let completed = std::mem::take(&mut *self.shared.completed.lock().unwrap());
+        let done = !completed.is_empty();
+        for (handle, ret) in completed {
+            let id = match self.handles.remove(&handle) {
+                Some(id) => id,
+                None => continue,
+            };
+            if let Some(pending) = self.pending.get_mut(&id) {
+                pending.parts -= 1;
+                if ret < 0 && pending.error.is_none() {
+                    pending.error = Some(ret);
+                }
+                if pending.parts != 0 {
+                    continue;
+                }
+            }
+            if let Some(pending) = self.pending.remove(&id) {
+                let ret = pending.error.unwrap_or(pending.aiocb.nbytes as i64);
+                (self.complete_func)(&pending.aiocb, ret)?;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Disconnect from the server, failing the requests not answered yet.
+    pub(crate) fn close(&mut self) -> Result<()> {
+        self.shutdown();
+        self.shared.inflight.lock().unwrap().clear();
+        self.handles.clear();
+        for (_, pending) in self.pending.drain() {
+            (self.complete_func)(&pending.aiocb, -i64::from(libc::EIO))?;
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        let _ = self.messages.send(NbdMessage::Close);
+    }
+}
+
+impl<T: Clone + 'static> Drop for NbdClient<T> {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const EXPORT_SIZE: usize = NBD_MAX_REQUEST_LEN as usize + (1 << 20);
+    const NBD_REPLY_TYPE_ERROR: u16 = NBD_REPLY_TYPE_ERROR_BIT | 1;
+
+    type TestCb = Arc<Mutex<Option<i64>>>;
+
+    /// NBD server of a disk in memory, which drops the connection on the
+    /// first flush request.
+    struct TestServer {
+        disk: Mutex<Vec<u8>>,
+        drop_flush: AtomicBool,
+        /// Close the connections as soon as they are accepted.
+        refuse: AtomicBool,
+        /// Don't answer the read requests.
+        silent: AtomicBool,
+    }
+
+    /// Start a server listening at `path`, in place of the file there.
+    fn start_server(path: &str, drop_flush: bool) -> Arc<TestServer> {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+        let server = Arc::new(TestServer {
+            disk: Mutex::new(vec![0; EXPORT_SIZE]),
+            drop_flush: AtomicBool::new(drop_flush),
+            refuse: AtomicBool::new(false),
+            silent: AtomicBool::new(false),
+        });
+        let server_clone = server.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !server_clone.refuse.load(Ordering::SeqCst) {
+                    let _ = serve_client(&mut stream.unwrap(), &server_clone);
+                }
+            }
+        });
+        server
+    }
+
+    fn open_client(path: &str) -> (NbdClient<TestCb>, Arc<EventFd>) {
+        let uri = format!("nbd+unix:///disk?socket={}", path)
+            .parse::<NbdUri>()
+            .unwrap();
+        let export = NbdExport::open(&uri).unwrap();
+        let complete_evt = Arc::new(EventFd::new(0).unwrap());
+        let client = NbdClient::new(export, Arc::new(complete_func), complete_evt.clone()).unwrap();
+        (client, complete_evt)
+    }
+
+    fn send_option_reply(stream: &mut UnixStream, option: u32, reply: u32, data: &[u8]) {
+        let mut header = [0_u8; 20];
+        BigEndian::write_u64(&mut header[0..8], NBD_REP_MAGIC);
+        BigEndian::write_u32(&mut header[8..12], option);
+        BigEndian::write_u32(&mut header[12..16], reply);
+        BigEndian::write_u32(&mut header[16..20], data.len() as u32);
+        stream.write_all(&header).unwrap();
+        stream.write_all(data).unwrap();
+    }
+
+    fn send_chunk(
+        stream: &mut UnixStream,
+        reply_type: u16,
+        handle: u64,
+        payload: &[u8],
+        done: bool,
+    ) {
+        let mut header = [0_u8; 20];
+        BigEndian::write_u32(&mut header[0..4], NBD_STRUCTURED_REPLY_MAGIC);
+        BigEndian::write_u16(&mut header[4..6], u16::from(done));
+        BigEndian::write_u16(&mut header[6..8], reply_type);
+        BigEndian::write_u64(&mut header[8..16], handle);
+        BigEndian::write_u32(&mut header[16..20], payload.len() as u32);
+        stream.write_all(&header).unwrap();
+        stream.write_all(payload).unwrap();
+    }
+
+    fn serve_client(stream: &mut UnixStream, server: &TestServer) -> std::io::Result<()> {
+        let mut greeting = NBD_MAGIC.to_be_bytes().to_vec();
+        greeting.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+        greeting.extend_from_slice(&NBD_FLAG_FIXED_NEWSTYLE.to_be_bytes());
+        stream.write_all(&greeting)?;
+        let mut client_flags = [0_u8; 4];
+        stream.read_exact(&mut client_flags)?;
+        loop {
+            let mut header = [0_u8; 16];
+            stream.read_exact(&mut header)?;
+            let option = BigEndian::read_u32(&header[8..12]);
+            let mut data = vec![0; BigEndian::read_u32(&header[12..16]) as usize];
+            stream.read_exact(&mut data)?;
+            if option != NBD_OPT_GO {
+                send_option_reply(stream, option, NBD_REP_ACK, &[]);
+                continue;
+            }
+            let mut info = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+            info.extend_from_slice(&(EXPORT_SIZE as u64).to_be_bytes());
+            let flags = NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_WRITE_ZEROES;
+            info.extend_from_slice(&flags.to_be_bytes());
+            send_option_reply(stream, option, NBD_REP_INFO, &info);
+            send_option_reply(stream, option, NBD_REP_ACK, &[]);
+            break;
+        }
+
+        loop {
+            let mut header = [0_u8; 28];
+            stream.read_exact(&mut header)?;
+            let command = BigEndian::read_u16(&header[6..8]);
+            let handle = BigEndian::read_u64(&header[8..16]);
+            let offset = BigEndian::read_u64(&header[16..24]) as usize;
+            let len = BigEndian::read_u32(&header[24..28]) as usize;
+            if offset + len > EXPORT_SIZE {
+                let mut error = 22_u32.to_be_bytes().to_vec();
+                error.extend_from_slice(&0_u16.to_be_bytes());
+                send_chunk(stream, NBD_REPLY_TYPE_ERROR, handle, &error, true);
+                continue;
+            }
+            let mut disk = server.disk.lock().unwrap();
+            match command {
+                NBD_CMD_READ if server.silent.load(Ordering::SeqCst) => continue,
+                NBD_CMD_READ => {
+                    let data = &disk[offset..offset + len];
+                    let mut payload = (offset as u64).to_be_bytes().to_vec();
+                    if data.iter().all(|b| *b == 0) {
+                        payload.extend_from_slice(&(len as u32).to_be_bytes());
+                        send_chunk(stream, NBD_REPLY_TYPE_OFFSET_HOLE, handle, &payload, false);
+                    } else {
+                        payload.extend_from_slice(data);
+                        send_chunk(stream, NBD_REPLY_TYPE_OFFSET_DATA, handle, &payload, false);
+                    }
+                }
+                NBD_CMD_WRITE => stream.read_exact(&mut disk[offset..offset + len])?,
+                NBD_CMD_WRITE_ZEROES => disk[offset..offset + len].fill(0),
+                NBD_CMD_FLUSH if server.drop_flush.swap(false, Ordering::SeqCst) => {
+                    return Ok(());
+                }
+                NBD_CMD_FLUSH => {}
+                _ => return Ok(()),
+            }
+            send_chunk(stream, NBD_REPLY_TYPE_NONE, handle, &[], true);
+        }
+    }
+
+    fn complete_func(aiocb: &AioCb<TestCb>, ret: i64) -> Result<()> {
+        *aiocb.iocompletecb.lock().unwrap() = Some(ret);
+        Ok(())
+    }
+
+    /// Submit a request and wait for its result.
+    fn submit(
+        client: &mut NbdClient<TestCb>,
+        complete_evt: &EventFd,
+        opcode: OpCode,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> i64 {
+        let result = Arc::new(Mutex::new(None));
+        let aiocb = AioCb {
+            direct: false,
+            req_align: 1,
+            buf_align: 1,
+            file_fd: -1,
+            opcode,
+            iovec: vec![Iovec {
+                iov_base: buf.as_mut_ptr() as u64,
+                iov_len: buf.len() as u64,
+            }],
+            offset,
+            nbytes: buf.len() as u64,
+            user_data: 0,
+            iocompletecb: result.clone(),
+        };
+        client.submit_request(aiocb, false, false).unwrap();
+        loop {
+            if let Some(ret) = *result.lock().unwrap() {
+                return ret;
+            }
+            complete_evt.read().unwrap();
+            client.handle_complete().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_nbd_client() {
+        // The socket is removed with the file.
+        let socket = TempFile::new().unwrap();
+        let path = socket.as_path().to_str().unwrap();
+        let server = start_server(path, true);
+
+        let uri = format!("nbd+unix:///disk?socket={}", path)
+            .parse::<NbdUri>()
+            .unwrap();
+        let export = NbdExport::open(&uri).unwrap();
+        assert_eq!(export.size, EXPORT_SIZE as u64);
+        assert!(!export.read_only());
+        assert!(!export.can_trim());
+        assert!(export.can_write_zeroes());
+
+        let complete_evt = Arc::new(EventFd::new(0).unwrap());
+        let mut client =
+            NbdClient::new(export, Arc::new(complete_func), complete_evt.clone()).unwrap();
+        let evt = complete_evt.as_ref();
+
+        let mut data = vec![0xa5_u8; 8192];
+        assert_eq!(
+            submit(&mut client, evt, OpCode::Pwritev, 4096, &mut data),
+            8192
+        );
+        let mut buf = vec![0_u8; 8192];
+        assert_eq!(
+            submit(&mut client, evt, OpCode::Preadv, 4096, &mut buf),
+            8192
+        );
+        assert_eq!(buf, data);
+        // Holes are read as zeroes.
+        let mut buf = vec![0xff_u8; 4096];
+        assert_eq!(
+            submit(&mut client, evt, OpCode::Preadv, 1 << 20, &mut buf),
+            4096
+        );
+        assert!(buf.iter().all(|b| *b == 0));
+
+        let mut zeroes = vec![0_u8; 4096];
+        assert_eq!(
+            submit(&mut client, evt, OpCode::WriteZeroes, 4096, &mut zeroes),
+            4096
+        );
+        let mut buf = vec![0xff_u8; 8192];
+        assert_eq!(
+            submit(&mut client, evt, OpCode::Preadv, 4096, &mut buf),
+            8192
+        );
+        assert!(buf[..4096].iter().all(|b| *b == 0));
+        assert!(buf[4096..].iter().all(|b| *b == 0xa5));
+        // Discard is a hint, ignored if the server does not support it.
+        let mut range = vec![0_u8; 4096];
+        assert_eq!(
+            submit(&mut client, evt, OpCode::Discard, 0, &mut range),
+            4096
+        );
+
+        // The flush is sent again once connected again.
+        assert_eq!(submit(&mut client, evt, OpCode::Fdsync, 0, &mut []), 0);
+        assert!(!server.drop_flush.load(Ordering::SeqCst));
+
+        // Long requests are split.
+        let len = NBD_MAX_REQUEST_LEN as usize + 8192;
+        let mut data = vec![0x5a_u8; len];
+        assert_eq!(
+            submit(&mut client, evt, OpCode::Pwritev, 4096, &mut data),
+            len as i64
+        );
+        assert!(server.disk.lock().unwrap()[4096..4096 + len]
+            .iter()
+            .all(|b| *b == 0x5a));
+
+        // Out of the export.
+        let mut buf = vec![0_u8; 8192];
+        assert_eq!(
+            submit(
+                &mut client,
+                evt,
+                OpCode::Preadv,
+                EXPORT_SIZE - 4096,
+                &mut buf
+            ),
+            -i64::from(libc::EINVAL)
+        );
+
+        drop(client);
+    }
+
+    #[test]
+    fn test_nbd_reconnect_failure() {
+        // The socket is removed with the file.
+        let socket = TempFile::new().unwrap();
+        let path = socket.as_path().to_str().unwrap();
+        let server = start_server(path, true);
+        let (mut client, complete_evt) = open_client(path);
+        let evt = complete_evt.as_ref();
+        let eio = -i64::from(libc::EIO);
+
+        let mut data = vec![0xa5_u8; 4096];
+        assert_eq!(
+            submit(&mut client, evt, OpCode::Pwritev, 0, &mut data),
+            4096
+        );
+
+        // The flush waiting for the connection fails once the server can't
+        // be reached, and so do the requests submitted until connected.
+        server.refuse.store(true, Ordering::SeqCst);
+        assert_eq!(submit(&mut client, evt, OpCode::Fdsync, 0, &mut []), eio);
+        let mut buf = vec![0_u8; 4096];
+        assert_eq!(submit(&mut client, evt, OpCode::Preadv, 0, &mut buf), eio);
+
+        server.refuse.store(false, Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let ret = submit(&mut client, evt, OpCode::Preadv, 0, &mut buf);
+            if ret != eio {
+                assert_eq!(ret, 4096);
+                break;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(NBD_RECONNECT_DELAY);
+        }
+        assert_eq!(buf, data);
+
+        drop(client);
+    }
+
+    #[test]
+    fn test_nbd_request_timeout() {
+        // The socket is removed with the file.
+        let socket = TempFile::new().unwrap();
+        let path = socket.as_path().to_str().unwrap();
+        let server = start_server(path, false);
+        let (mut client, complete_evt) = open_client(path);
+        let evt = complete_evt.as_ref();
+
+        let mut data = vec![0x5a_u8; 4096];
+        assert_eq!(
+            submit(&mut client, evt, OpCode::Pwritev, 0, &mut data),
+            4096
+        );
+
+        // The read the server doesn't answer fails.
+        server.silent.store(true, Ordering::SeqCst);
+        let mut buf = vec![0_u8; 4096];
+        let start = Instant::now();
+        assert_eq!(
+            submit(&mut client, evt, OpCode::Preadv, 0, &mut buf),
+            -i64::from(libc::EIO)
+        );
+        assert!(start.elapsed() >= NBD_REQUEST_TIMEOUT);
+
+        // The next requests are answered on a new connection.
+        server.silent.store(false, Ordering::SeqCst);
+        assert_eq!(submit(&mut client, evt, OpCode::Preadv, 0, &mut buf), 4096);
+        assert_eq!(buf, data);
+
+        drop(client);
+    }
+
+    #[test]
+    fn test_receive_simple_replies() {
+        let shared = NbdShared {
+            inflight: Mutex::new(HashMap::new()),
+            completed: Mutex::new(Vec::new()),
+            complete_evt: Arc::new(EventFd::new(0).unwrap()),
+            closed: AtomicBool::new(false),
+        };
+        let mut buf = [0_u8; 4];
+        let mut inflight = shared.inflight.lock().unwrap();
+        inflight.insert(
+            1,
+            NbdRequest {
+                command: NBD_CMD_WRITE,
+                len: 512,
+                generation: 1,
+                ..Default::default()
+            },
+        );
+        inflight.insert(
+            2,
+            NbdRequest {
+                command: NBD_CMD_READ,
+                len: 4,
+                iovec: vec![Iovec {
+                    iov_base: buf.as_mut_ptr() as u64,
+                    iov_len: 4,
+                }],
+                generation: 1,
+                ..Default::default()
+            },
+        );
+        drop(inflight);
+
+        let mut replies = Vec::new();
+        for (error, handle) in [(28_u32, 1_u64), (0, 2), (0, 3)] {
+            replies.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+            replies.extend_from_slice(&error.to_be_bytes());
+            replies.extend_from_slice(&handle.to_be_bytes());
+            if handle == 2 {
+                replies.extend_from_slice(&[1, 2, 3, 4]);
+            }
+        }
+        // The reply to the unknown handle 3 drops the connection.
+        let mut stream = std::io::Cursor::new(replies);
+        assert!(receive_replies(&mut stream, false, 1, &shared).is_err());
+        assert_eq!(
+            *shared.completed.lock().unwrap(),
+            vec![(1, -i64::from(libc::ENOSPC)), (2, 4)]
+        );
+        assert!(shared.inflight.lock().unwrap().is_empty());
+        assert_eq!(buf, [1, 2, 3, 4]);
+
+        // Only the requests selected fail.
+        shared.completed.lock().unwrap().clear();
+        for handle in 4..6 {
+            shared.inflight.lock().unwrap().insert(
+                handle,
+                NbdRequest {
+                    generation: handle - 3,
+                    ..Default::default()
+                },
+            );
+        }
+        assert!(!shared.fail_requests(|req| req.generation == 3).unwrap());
+        assert!(shared.fail_requests(|req| req.generation == 2).unwrap());
+        assert_eq!(
+            *shared.completed.lock().unwrap(),
+            vec![(5, -i64::from(libc::EIO))]
+        );
+        assert!(shared.inflight.lock().unwrap().contains_key(&4));
+    }
+}
@@ -0,0 +1,1746 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Qcow2 image format.
+//!
+//! Guest requests are mapped onto host file ranges here, the data itself is
+//! then moved by the block device through `util::aio`. Metadata, i.e. the L1
+//! and L2 tables and the refcounts, is read and written synchronously through
+//! a buffered handle of the image. New clusters are always allocated at the
+//! end of the image file, freed ones are not reused.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use machine_manager::config::DiskFormat;
+use util::aio::{raw_read, raw_write};
+use util::num_ops::round_up;
+
+/// Magic of qcow2 images, "QFI\xfb".
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+const QCOW_VERSION_2: u32 = 2;
+const QCOW_VERSION_3: u32 = 3;
+/// Size of the header of version 2 images.
+const QCOW_V2_HEADER_SIZE: usize = 72;
+/// Size of the header of version 3 images, without optional fields.
+const QCOW_V3_HEADER_SIZE: usize = 104;
+/// Cluster bits of the images created, i.e. 64KiB clusters.
+const DEFAULT_CLUSTER_BITS: u32 = 16;
+/// Offset of `refcount_table_offset` and `refcount_table_clusters` in the header.
+const HEADER_REFCOUNT_TABLE: u64 = 48;
+
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+/// Refcount order of version 2 images, i.e. 16-bit refcounts.
+const V2_REFCOUNT_ORDER: u32 = 4;
+const MAX_REFCOUNT_ORDER: u32 = 6;
+const MAX_BACKING_FILE_NAME: u32 = 1023;
+/// Longest backing chain accepted, which also stops chains looping.
+const MAX_BACKING_DEPTH: usize = 16;
+/// Largest L1 table accepted, in bytes.
+const MAX_L1_TABLE_SIZE: u64 = 32 << 20;
+/// Largest refcount table accepted, in bytes.
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 << 20;
+
+// Bits of L1 and L2 entries.
+/// The cluster is only referenced by this entry and can be written in place.
+const QCOW_OFLAG_COPIED: u64 = 1 << 63;
+const QCOW_OFLAG_COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as zeroes, version 3 only.
+const QCOW_OFLAG_ZERO: u64 = 1;
+const L1E_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2E_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFT_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+
+// Types of header extensions.
+const HEADER_EXT_END: u32 = 0;
+const HEADER_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+/// Number of L2 tables, and of refcount blocks, kept in memory.
+const TABLE_CACHE_SIZE: usize = 16;
+
+/// A piece of a guest request, mapped onto the files of the image chain.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DiskRange {
+    /// `len` bytes at `offset` of the file `fd`.
+    Data {
+        fd: RawFd,
+        direct: bool,
+        offset: u64,
+        len: u64,
+    },
+    /// `len` bytes which read as zeroes.
+    Zero { len: u64 },
+}
+
+impl DiskRange {
+    pub fn len(&self) -> u64 {
+        match self {
+            DiskRange::Data { len, .. } => *len,
+            DiskRange::Zero { len } => *len,
+        }
+    }
+}
+
+/// Append a range, merging it with the last one if they are contiguous.
+fn push_range(ranges: &mut Vec<DiskRange>, range: DiskRange) {
+    match (ranges.last_mut(), &range) {
+        (
+            Some(DiskRange::Data {
+                fd: last_fd,
+                offset: last_offset,
+                len: last_len,
+                ..
+            }),
+            DiskRange::Data {
+                fd, offset, len, ..
+            },
+        ) if *last_fd == *fd && *last_offset + *last_len == *offset => *last_len += len,
+        (Some(DiskRange::Zero { len: last_len }), DiskRange::Zero { len }) => *last_len += len,
+        _ => ranges.push(range),
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct QcowHeader {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    incompatible_features: u64,
+    refcount_order: u32,
+    header_length: u32,
+}
+
+impl QcowHeader {
+    fn read_from(file: &File) -> Result<Self> {
+        let mut buf = [0_u8; QCOW_V3_HEADER_SIZE];
+        file.read_exact_at(&mut buf[..QCOW_V2_HEADER_SIZE], 0)
+            .with_context(|| "Failed to read qcow2 header")?;
+        let magic = BigEndian::read_u32(&buf[0..]);
+        if magic != QCOW_MAGIC {
+            bail!("Invalid qcow2 magic 0x{:x}", magic);
+        }
+
+        let mut header = QcowHeader {
+            version: BigEndian::read_u32(&buf[4..]),
+            backing_file_offset: BigEndian::read_u64(&buf[8..]),
+            backing_file_size: BigEndian::read_u32(&buf[16..]),
+            cluster_bits: BigEndian::read_u32(&buf[20..]),
+            size: BigEndian::read_u64(&buf[24..]),
+            crypt_method: BigEndian::read_u32(&buf[32..]),
+            l1_size: BigEndian::read_u32(&buf[36..]),
+            l1_table_offset: BigEndian::read_u64(&buf[40..]),
+            refcount_table_offset: BigEndian::read_u64(&buf[48..]),
+            refcount_table_clusters: BigEndian::read_u32(&buf[56..]),
+            incompatible_features: 0,
+            refcount_order: V2_REFCOUNT_ORDER,
+            header_length: QCOW_V2_HEADER_SIZE as u32,
+        };
+        match header.version {
+            QCOW_VERSION_2 => {}
+            QCOW_VERSION_3 => {
+                file.read_exact_at(&mut buf[QCOW_V2_HEADER_SIZE..], QCOW_V2_HEADER_SIZE as u64)
+                    .with_context(|| "Failed to read qcow2 version 3 header")?;
+                header.incompatible_features = BigEndian::read_u64(&buf[72..]);
+                header.refcount_order = BigEndian::read_u32(&buf[96..]);
+                header.header_length = BigEndian::read_u32(&buf[100..]);
+            }
+            version => bail!("Unsupported qcow2 version {}", version),
+        }
+        header.check()?;
+        Ok(header)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.cluster_bits < MIN_CLUSTER_BITS || self.cluster_bits > MAX_CLUSTER_BITS {
+            bail!("Invalid qcow2 cluster bits {}", self.cluster_bits);
+        }
+        let cluster_size = 1_u64 << self.cluster_bits;
+        if self.version == QCOW_VERSION_3
+            && (self.header_length < QCOW_V3_HEADER_SIZE as u32
+                || self.header_length as u64 > cluster_size)
+        {
+            bail!("Invalid qcow2 header length {}", self.header_length);
+        }
+        if self.crypt_method != 0 {
+            bail!("Encrypted qcow2 images are not supported");
+        }
+        if self.incompatible_features != 0 {
+            bail!(
+                "Unsupported qcow2 incompatible features 0x{:x}",
+                self.incompatible_features
+            );
+        }
+        if self.refcount_order > MAX_REFCOUNT_ORDER {
+            bail!("Invalid qcow2 refcount order {}", self.refcount_order);
+        }
+        if self.backing_file_size > MAX_BACKING_FILE_NAME {
+            bail!("Qcow2 backing file name is too long");
+        }
+        if self.l1_table_offset & (cluster_size - 1) != 0
+            || self.refcount_table_offset == 0
+            || self.refcount_table_offset & (cluster_size - 1) != 0
+        {
+            bail!("Qcow2 metadata tables are not aligned to clusters");
+        }
+        if self.l1_size as u64 * 8 > MAX_L1_TABLE_SIZE {
+            bail!("Qcow2 L1 table is too large");
+        }
+        // Each L1 entry maps an L2 table, that is cluster_size / 8 clusters.
+        let l1_span = cluster_size * (cluster_size / 8);
+        let l1_needed = self
+            .size
+            .checked_add(l1_span - 1)
+            .with_context(|| "Qcow2 virtual size overflows")?
+            / l1_span;
+        if (self.l1_size as u64) < l1_needed {
+            bail!("Qcow2 L1 table is too small for the virtual size");
+        }
+        if self.refcount_table_clusters as u64 * cluster_size > MAX_REFCOUNT_TABLE_SIZE {
+            bail!("Qcow2 refcount table is too large");
+        }
+        Ok(())
+    }
+}
+
+/// Write-through cache of metadata clusters, i.e. L2 tables or refcount blocks.
+struct TableCache {
+    tables: HashMap<u64, Vec<u8>>,
+    /// Offsets of the cached tables, least recently used first.
+    lru: VecDeque<u64>,
+}
+
+impl TableCache {
+    fn new() -> Self {
+        TableCache {
+            tables: HashMap::new(),
+            lru: VecDeque::new(),
+        }
+    }
+
+    fn touch(&mut self, offset: u64) {
+        if let Some(pos) = self.lru.iter().position(|&o| o == offset) {
+            self.lru.remove(pos);
+        } else if self.lru.len() >= TABLE_CACHE_SIZE {
+            if let Some(evicted) = self.lru.pop_front() {
+                self.tables.remove(&evicted);
+            }
+        }
+        self.lru.push_back(offset);
+    }
+
+    /// Get the table at `offset`, reading it from `file` if it isn't cached.
+    fn get(&mut self, file: &File, offset: u64, size: usize) -> Result<&mut Vec<u8>> {
+        if !self.tables.contains_key(&offset) {
+            let mut table = vec![0; size];
+            file.read_exact_at(&mut table, offset)
+                .with_context(|| format!("Failed to read qcow2 table at 0x{:x}", offset))?;
+            self.touch(offset);
+            self.tables.insert(offset, table);
+        } else {
+            self.touch(offset);
+        }
+        Ok(self.tables.get_mut(&offset).unwrap())
+    }
+
+    /// Cache a table which was just written to the image.
+    fn insert(&mut self, offset: u64, table: Vec<u8>) {
+        self.touch(offset);
+        self.tables.insert(offset, table);
+    }
+}
+
+fn read_table(file: &File, offset: u64, entries: usize) -> Result<Vec<u64>> {
+    let mut buf = vec![0_u8; entries * 8];
+    file.read_exact_at(&mut buf, offset)
+        .with_context(|| format!("Failed to read qcow2 table at 0x{:x}", offset))?;
+    Ok(buf.chunks_exact(8).map(BigEndian::read_u64).collect())
+}
+
+/// Get the refcount at `index` of a refcount block with `1 << order` bit entries.
+fn get_refcount(block: &[u8], index: u64, order: u32) -> u64 {
+    let index = index as usize;
+    match order {
+        0..=2 => {
+            let bits = 1 << order;
+            let byte = block[index * bits / 8];
+            (byte as u64 >> (index * bits % 8)) & ((1 << bits) - 1)
+        }
+        3 => block[index] as u64,
+        4 => BigEndian::read_u16(&block[index * 2..]) as u64,
+        5 => BigEndian::read_u32(&block[index * 4..]) as u64,
+        _ => BigEndian::read_u64(&block[index * 8..]),
+    }
+}
+
+/// Set the refcount at `index` of a refcount block, the bytes changed are returned.
+fn set_refcount(block: &mut [u8], index: u64, order: u32, value: u64) -> Range<usize> {
+    let index = index as usize;
+    match order {
+        0..=2 => {
+            let bits = 1 << order;
+            let byte = index * bits / 8;
+            let shift = index * bits % 8;
+            let mask = ((1_u8 << bits) - 1) << shift;
+            block[byte] = (block[byte] & !mask) | ((value as u8) << shift & mask);
+            byte..byte + 1
+        }
+        3 => {
+            block[index] = value as u8;
+            index..index + 1
+        }
+        4 => {
+            BigEndian::write_u16(&mut block[index * 2..], value as u16);
+            index * 2..index * 2 + 2
+        }
+        5 => {
+            BigEndian::write_u32(&mut block[index * 4..], value as u32);
+            index * 4..index * 4 + 4
+        }
+        _ => {
+            BigEndian::write_u64(&mut block[index * 8..], value);
+            index * 8..index * 8 + 8
+        }
+    }
+}
+
+/// Copy of the old content of a guest cluster the guest partially writes,
+/// done without the image locked. The cluster is mapped once the copy ends,
+/// see `Qcow2Image::start_copies`.
+pub struct ClusterCopy {
+    guest_offset: u64,
+    /// Host cluster the content is copied to.
+    host: u64,
+    /// Host cluster mapped before, unreferenced once the copy ends if it's
+    /// not `host`.
+    old: u64,
+    /// Where the old content is read from.
+    sources: Vec<DiskRange>,
+    /// Buffered handle of the image.
+    file: RawFd,
+}
+
+impl ClusterCopy {
+    /// Copy the old content into the host cluster.
+    pub fn run(&self) -> Result<()> {
+        let len = self.sources.iter().map(DiskRange::len).sum::<u64>() as usize;
+        let mut buf = vec![0_u8; len];
+        let mut done = 0;
+        for range in &self.sources {
+            if let DiskRange::Data {
+                fd, offset, len, ..
+            } = range
+            {
+                let ret = raw_read(
+                    *fd,
+                    buf[done..].as_mut_ptr() as u64,
+                    *len as usize,
+                    *offset as usize,
+                );
+                if ret != *len as i64 {
+                    bail!("Failed to read qcow2 cluster to copy");
+                }
+            }
+            done += range.len() as usize;
+        }
+        let ret = raw_write(self.file, buf.as_ptr() as u64, len, self.host as usize);
+        if ret != len as i64 {
+            bail!("Failed to copy qcow2 cluster");
+        }
+        Ok(())
+    }
+}
+
+/// The image a qcow2 image reads its unallocated clusters from.
+enum BackingImage {
+    Raw { file: File, size: u64 },
+    Qcow2(Box<Qcow2Image>),
+}
+
+impl BackingImage {
+    fn open(path: &str, format: Option<DiskFormat>, depth: usize) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open qcow2 backing file {}", path))?;
+        let format = match format {
+            Some(format) => format,
+            None => {
+                let mut magic = [0_u8; 4];
+                match file.read_exact_at(&mut magic, 0) {
+                    Ok(()) if BigEndian::read_u32(&magic) == QCOW_MAGIC => DiskFormat::Qcow2,
+                    _ => DiskFormat::Raw,
+                }
+            }
+        };
+        match format {
+            DiskFormat::Raw => {
+                let size = file.metadata()?.len();
+                Ok(BackingImage::Raw { file, size })
+            }
+            DiskFormat::Qcow2 => {
+                let data = Arc::new(file.try_clone()?);
+                let image = Qcow2Image::open_chain(file, data, path, true, false, depth)?;
+                Ok(BackingImage::Qcow2(Box::new(image)))
+            }
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self {
+            BackingImage::Raw { size, .. } => *size,
+            BackingImage::Qcow2(image) => image.virtual_size(),
+        }
+    }
+
+    /// Map `len` bytes at `offset`, anything past the end of the image reads as zeroes.
+    fn map_into(&mut self, offset: u64, len: u64, ranges: &mut Vec<DiskRange>) -> Result<()> {
+        let inside = self.size().saturating_sub(offset).min(len);
+        if inside != 0 {
+            match self {
+                BackingImage::Raw { file, .. } => push_range(
+                    ranges,
+                    DiskRange::Data {
+                        fd: file.as_raw_fd(),
+                        direct: false,
+                        offset,
+                        len: inside,
+                    },
+                ),
+                BackingImage::Qcow2(image) => image.map_into(offset, inside, ranges)?,
+            }
+        }
+        if inside != len {
+            push_range(ranges, DiskRange::Zero { len: len - inside });
+        }
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let inside = self.size().saturating_sub(offset).min(buf.len() as u64) as usize;
+        let (data, zeroes) = buf.split_at_mut(inside);
+        if !data.is_empty() {
+            match self {
+                BackingImage::Raw { file, .. } => file
+                    .read_exact_at(data, offset)
+                    .with_context(|| "Failed to read qcow2 backing file")?,
+                BackingImage::Qcow2(image) => image.read_at(offset, data)?,
+            }
+        }
+        zeroes.fill(0);
+        Ok(())
+    }
+}
+
+/// A qcow2 image, together with the chain of images backing it.
+pub struct Qcow2Image {
+    /// Handle of the image used for metadata, without O_DIRECT.
+    file: File,
+    /// Handle of the image used for guest data.
+    data: Arc<File>,
+    /// If `data` is opened with O_DIRECT.
+    direct: bool,
+    read_only: bool,
+    header: QcowHeader,
+    cluster_size: u64,
+    l1_table: Vec<u64>,
+    refcount_table: Vec<u64>,
+    l2_cache: TableCache,
+    refblock_cache: TableCache,
+    /// Host offset where the next cluster gets allocated.
+    free_offset: u64,
+    backing: Option<BackingImage>,
+    /// Path of the backing file.
+    backing_path: Option<String>,
+    /// Guest offsets of the clusters written since dirty tracking started.
+    dirty: Option<BTreeSet<u64>>,
+    /// Set while a block job needs the image to stay unchanged, guest
+    /// requests are then held by the block device.
+    frozen: bool,
+    /// Guest offsets of the clusters being copied, they aren't written
+    /// until the copy ends.
+    copying: BTreeSet<u64>,
+    /// Guest offsets of the clusters whose copy failed, the next write of
+    /// the cluster fails.
+    copy_failed: BTreeSet<u64>,
+}
+
+impl Qcow2Image {
+    /// Open a qcow2 image and its backing files.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the image.
+    /// * `data` - Handle of the image the guest data is read and written through.
+    /// * `read_only` - If the image is opened read-only.
+    /// * `direct` - If `data` is opened with O_DIRECT.
+    pub fn open(path: &str, data: Arc<File>, read_only: bool, direct: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)
+            .with_context(|| format!("Failed to open qcow2 image {}", path))?;
+        Self::open_chain(file, data, path, read_only, direct, 0)
+    }
+
+    fn open_chain(
+        file: File,
+        data: Arc<File>,
+        path: &str,
+        read_only: bool,
+        direct: bool,
+        depth: usize,
+    ) -> Result<Self> {
+        let header = QcowHeader::read_from(&file)?;
+        let cluster_size = 1_u64 << header.cluster_bits;
+        let l1_table = read_table(&file, header.l1_table_offset, header.l1_size as usize)?;
+        let refcount_table = read_table(
+            &file,
+            header.refcount_table_offset,
+            (header.refcount_table_clusters as u64 * cluster_size / 8) as usize,
+        )?;
+        let free_offset = round_up(file.metadata()?.len(), cluster_size)
+            .with_context(|| "Qcow2 image size overflows")?;
+
+        let mut image = Qcow2Image {
+            file,
+            data,
+            direct,
+            read_only,
+            header,
+            cluster_size,
+            l1_table,
+            refcount_table,
+            l2_cache: TableCache::new(),
+            refblock_cache: TableCache::new(),
+            free_offset,
+            backing: None,
+            backing_path: None,
+            dirty: None,
+            frozen: false,
+            copying: BTreeSet::new(),
+            copy_failed: BTreeSet::new(),
+        };
+        if image.header.backing_file_offset != 0 {
+            if depth >= MAX_BACKING_DEPTH {
+                bail!("Qcow2 backing chain of {} is too long", path);
+            }
+            let (name, format) = image.read_backing_file()?;
+            // Relative names are relative to the directory of the image.
+            let backing_path = match Path::new(path).parent() {
+                Some(dir) if !name.starts_with('/') => dir.join(&name),
+                _ => Path::new(&name).to_path_buf(),
+            };
+            let backing_path = backing_path.to_string_lossy().to_string();
+            image.backing = Some(BackingImage::open(&backing_path, format, depth + 1)?);
+            image.backing_path = Some(backing_path);
+        }
+        Ok(image)
+    }
+
+    /// Create an empty version 3 image: the header, then the refcount table,
+    /// one refcount block and the L1 table.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the image, an existing file is truncated.
+    /// * `size` - Size of the disk seen by the guest, in bytes.
+    /// * `backing` - Path and format of the backing file.
+    pub fn create(path: &str, size: u64, backing: Option<(&str, DiskFormat)>) -> Result<()> {
+        let cluster_size = 1_u64 << DEFAULT_CLUSTER_BITS;
+        let l1_span = cluster_size * (cluster_size / 8);
+        let l1_size =
+            round_up(size, l1_span).with_context(|| "Qcow2 virtual size overflows")? / l1_span;
+        let l1_clusters = round_up(l1_size * 8, cluster_size).unwrap() / cluster_size;
+        let l1_offset = 3 * cluster_size;
+
+        let mut header = vec![0_u8; cluster_size as usize];
+        BigEndian::write_u32(&mut header[0..], QCOW_MAGIC);
+        BigEndian::write_u32(&mut header[4..], QCOW_VERSION_3);
+        BigEndian::write_u32(&mut header[20..], DEFAULT_CLUSTER_BITS);
+        BigEndian::write_u64(&mut header[24..], size);
+        BigEndian::write_u32(&mut header[36..], l1_size as u32);
+        BigEndian::write_u64(&mut header[40..], l1_offset);
+        BigEndian::write_u64(&mut header[48..], cluster_size);
+        BigEndian::write_u32(&mut header[56..], 1);
+        BigEndian::write_u32(&mut header[96..], V2_REFCOUNT_ORDER);
+        BigEndian::write_u32(&mut header[100..], QCOW_V3_HEADER_SIZE as u32);
+        if let Some((name, format)) = backing {
+            if name.len() > MAX_BACKING_FILE_NAME as usize {
+                bail!("Qcow2 backing file name is too long");
+            }
+            // The backing format extension, then the end of the extensions
+            // and the name of the backing file.
+            let format = format.to_string();
+            let mut offset = QCOW_V3_HEADER_SIZE;
+            BigEndian::write_u32(&mut header[offset..], HEADER_EXT_BACKING_FORMAT);
+            BigEndian::write_u32(&mut header[offset + 4..], format.len() as u32);
+            header[offset + 8..offset + 8 + format.len()].copy_from_slice(format.as_bytes());
+            offset += 8 + round_up(format.len() as u64, 8).unwrap() as usize + 8;
+            BigEndian::write_u64(&mut header[8..], offset as u64);
+            BigEndian::write_u32(&mut header[16..], name.len() as u32);
+            header[offset..offset + name.len()].copy_from_slice(name.as_bytes());
+        }
+        let mut refcount_table = vec![0_u8; cluster_size as usize];
+        BigEndian::write_u64(&mut refcount_table, 2 * cluster_size);
+        let mut refblock = vec![0_u8; cluster_size as usize];
+        for i in 0..3 + l1_clusters {
+            set_refcount(&mut refblock, i, V2_REFCOUNT_ORDER, 1);
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("Failed to create qcow2 image {}", path))?;
+        file.write_all_at(&header, 0)
+            .and_then(|_| file.write_all_at(&refcount_table, cluster_size))
+            .and_then(|_| file.write_all_at(&refblock, 2 * cluster_size))
+            .and_then(|_| file.set_len(l1_offset + l1_clusters * cluster_size))
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("Failed to write qcow2 image {}", path))
+    }
+
+    /// Read the backing file name, and its format from the header extensions.
+    fn read_backing_file(&self) -> Result<(String, Option<DiskFormat>)> {
+        let mut name = vec![0_u8; self.header.backing_file_size as usize];
+        self.file
+            .read_exact_at(&mut name, self.header.backing_file_offset)
+            .with_context(|| "Failed to read qcow2 backing file name")?;
+        let name = String::from_utf8(name).with_context(|| "Invalid qcow2 backing file name")?;
+
+        let mut format = None;
+        let mut offset = self.header.header_length as u64;
+        let end = self.cluster_size.min(self.header.backing_file_offset);
+        while offset + 8 <= end {
+            let mut ext = [0_u8; 8];
+            self.file
+                .read_exact_at(&mut ext, offset)
+                .with_context(|| "Failed to read qcow2 header extension")?;
+            let ext_type = BigEndian::read_u32(&ext[0..]);
+            let ext_len = BigEndian::read_u32(&ext[4..]) as u64;
+            offset += 8;
+            if ext_type == HEADER_EXT_END {
+                break;
+            }
+            if offset + ext_len > end {
+                bail!("Qcow2 header extension 0x{:x} is truncated", ext_type);
+            }
+            if ext_type == HEADER_EXT_BACKING_FORMAT {
+                let mut fmt = vec![0_u8; ext_len as usize];
+                self.file.read_exact_at(&mut fmt, offset)?;
+                let fmt = String::from_utf8_lossy(&fmt).to_string();
+                format = Some(
+                    fmt.parse::<DiskFormat>()
+                        .map_err(|_| anyhow!("Unsupported qcow2 backing format {}", fmt))?,
+                );
+            }
+            offset += round_up(ext_len, 8).unwrap();
+        }
+        Ok((name, format))
+    }
+
+    /// Size of the disk seen by the guest, in bytes.
+    pub fn virtual_size(&self) -> u64 {
+        self.header.size
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    /// Path and format of the backing file, if the image has one.
+    pub fn backing_file(&self) -> Option<(String, DiskFormat)> {
+        let format = match self.backing.as_ref()? {
+            BackingImage::Raw { .. } => DiskFormat::Raw,
+            BackingImage::Qcow2(_) => DiskFormat::Qcow2,
+        };
+        self.backing_path.clone().map(|path| (path, format))
+    }
+
+    /// Start or stop recording the clusters written.
+    pub fn track_dirty(&mut self, enable: bool) {
+        self.dirty = if enable { Some(BTreeSet::new()) } else { None };
+    }
+
+    /// Get the guest offsets of the clusters written since the last call.
+    pub fn take_dirty(&mut self) -> Vec<u64> {
+        match self.dirty.as_mut() {
+            Some(dirty) => std::mem::take(dirty).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    pub fn frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Guest offsets of the clusters allocated in this image, rather than
+    /// read from the backing file.
+    pub fn allocated_clusters(&mut self) -> Result<Vec<u64>> {
+        let mut clusters = Vec::new();
+        let l2_entries = self.cluster_size / 8;
+        for l1_index in 0..self.l1_table.len() {
+            let l2_offset = self.l1_table[l1_index] & L1E_OFFSET_MASK;
+            if l2_offset == 0 {
+                continue;
+            }
+            let table = self
+                .l2_cache
+                .get(&self.file, l2_offset, self.cluster_size as usize)?;
+            for (l2_index, entry) in table.chunks_exact(8).enumerate() {
+                let entry = BigEndian::read_u64(entry);
+                let offset = (l1_index as u64 * l2_entries + l2_index as u64) * self.cluster_size;
+                if entry & (L2E_OFFSET_MASK | QCOW_OFLAG_ZERO) != 0 && offset < self.header.size {
+                    clusters.push(offset);
+                }
+            }
+        }
+        Ok(clusters)
+    }
+
+    /// Make the data written synchronously stable.
+    pub fn sync(&self) -> Result<()> {
+        self.file
+            .sync_data()
+            .with_context(|| "Failed to sync qcow2 image")
+    }
+
+    fn l1_index(&self, guest_offset: u64) -> usize {
+        (guest_offset >> (2 * self.header.cluster_bits - 3)) as usize
+    }
+
+    fn l2_index(&self, guest_offset: u64) -> usize {
+        ((guest_offset >> self.header.cluster_bits) & (self.cluster_size / 8 - 1)) as usize
+    }
+
+    fn check_range(&self, offset: u64, len: u64) -> Result<()> {
+        if offset
+            .checked_add(len)
+            .filter(|&end| end <= self.header.size)
+            .is_none()
+        {
+            bail!(
+                "Qcow2 request at 0x{:x} of 0x{:x} bytes is beyond the image size 0x{:x}",
+                offset,
+                len,
+                self.header.size
+            );
+        }
+        Ok(())
+    }
+
+    /// Get the L2 entry mapping `guest_offset`, 0 if no L2 table is allocated.
+    fn l2_entry(&mut self, guest_offset: u64) -> Result<u64> {
+        let l2_offset = self.l1_table[self.l1_index(guest_offset)] & L1E_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        let index = self.l2_index(guest_offset);
+        let table = self
+            .l2_cache
+            .get(&self.file, l2_offset, self.cluster_size as usize)?;
+        let entry = BigEndian::read_u64(&table[index * 8..]);
+        if entry & QCOW_OFLAG_COMPRESSED != 0 {
+            bail!("Compressed qcow2 clusters are not supported");
+        }
+        Ok(entry)
+    }
+
+    /// Map `len` bytes at guest `offset` for reading.
+    pub fn map_read(&mut self, offset: u64, len: u64) -> Result<Vec<DiskRange>> {
+        self.check_range(offset, len)?;
+        let mut ranges = Vec::new();
+        self.map_into(offset, len, &mut ranges)?;
+        Ok(ranges)
+    }
+
+    fn map_into(&mut self, offset: u64, len: u64, ranges: &mut Vec<DiskRange>) -> Result<()> {
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let in_cluster = pos & (self.cluster_size - 1);
+            let chunk = (self.cluster_size - in_cluster).min(end - pos);
+            let entry = self.l2_entry(pos)?;
+            let host = entry & L2E_OFFSET_MASK;
+            if entry & QCOW_OFLAG_ZERO != 0 {
+                push_range(ranges, DiskRange::Zero { len: chunk });
+            } else if host != 0 {
+                push_range(
+                    ranges,
+                    DiskRange::Data {
+                        fd: self.data.as_raw_fd(),
+                        direct: self.direct,
+                        offset: host + in_cluster,
+                        len: chunk,
+                    },
+                );
+            } else if let Some(backing) = self.backing.as_mut() {
+                backing.map_into(pos, chunk, ranges)?;
+            } else {
+                push_range(ranges, DiskRange::Zero { len: chunk });
+            }
+            pos += chunk;
+        }
+        Ok(())
+    }
+
+    /// Read guest data synchronously, used to copy clusters on write and by
+    /// block jobs.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_cluster = pos & (self.cluster_size - 1);
+            let chunk = ((self.cluster_size - in_cluster) as usize).min(buf.len() - done);
+            let dst = &mut buf[done..done + chunk];
+            let entry = self.l2_entry(pos)?;
+            let host = entry & L2E_OFFSET_MASK;
+            if entry & QCOW_OFLAG_ZERO != 0 {
+                dst.fill(0);
+            } else if host != 0 {
+                self.file
+                    .read_exact_at(dst, host + in_cluster)
+                    .with_context(|| "Failed to read qcow2 cluster")?;
+            } else if let Some(backing) = self.backing.as_mut() {
+                backing.read_at(pos, dst)?;
+            } else {
+                dst.fill(0);
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Write guest data synchronously, used by block jobs.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let ranges = self.map_write(offset, buf.len() as u64)?;
+        let mut done = 0;
+        for range in ranges {
+            if let DiskRange::Data {
+                offset: host, len, ..
+            } = range
+            {
+                // Through the buffered handle, `data` may need aligned buffers.
+                self.file
+                    .write_all_at(&buf[done..done + len as usize], host)
+                    .with_context(|| "Failed to write qcow2 cluster")?;
+            }
+            done += range.len() as usize;
+        }
+        Ok(())
+    }
+
+    /// Make `len` bytes at guest `offset` read as zeroes. Whole clusters are
+    /// marked as zero in the L2 table, and freed if `unmap`, other clusters
+    /// are written with zeroes.
+    pub fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> Result<()> {
+        if self.read_only {
+            bail!("Failed to write read-only qcow2 image");
+        }
+        self.check_range(offset, len)?;
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let in_cluster = pos & (self.cluster_size - 1);
+            let chunk = (self.cluster_size - in_cluster).min(end - pos);
+            if !self.whole_cluster(pos, chunk) || !self.clear_cluster(pos, true, unmap)? {
+                self.write_at(pos, &vec![0; chunk as usize])?;
+            }
+            pos += chunk;
+        }
+        Ok(())
+    }
+
+    /// Free the clusters fully covered by `len` bytes at guest `offset`, their
+    /// content is undefined afterwards. Clusters partially covered are kept.
+    pub fn discard(&mut self, offset: u64, len: u64) -> Result<()> {
+        if self.read_only {
+            bail!("Failed to write read-only qcow2 image");
+        }
+        self.check_range(offset, len)?;
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let in_cluster = pos & (self.cluster_size - 1);
+            let chunk = (self.cluster_size - in_cluster).min(end - pos);
+            if self.whole_cluster(pos, chunk) {
+                self.clear_cluster(pos, false, true)?;
+            }
+            pos += chunk;
+        }
+        Ok(())
+    }
+
+    /// If `len` bytes at guest `pos` cover the whole cluster, the last one
+    /// possibly cut by the end of the disk.
+    fn whole_cluster(&self, pos: u64, len: u64) -> bool {
+        pos & (self.cluster_size - 1) == 0
+            && (len == self.cluster_size || pos + len == self.header.size)
+    }
+
+    /// Drop the content of the guest cluster at `guest_offset`, it reads as
+    /// zeroes afterwards if `zero`. The host cluster is kept allocated unless
+    /// `unmap`. Returns false if only writing zeroes can clear the cluster,
+    /// version 2 images having no zero flag.
+    fn clear_cluster(&mut self, guest_offset: u64, zero: bool, unmap: bool) -> Result<bool> {
+        let entry = self.l2_entry(guest_offset)?;
+        let host = entry & L2E_OFFSET_MASK;
+        let keep = !unmap && host != 0 && entry & QCOW_OFLAG_COPIED != 0;
+        let new_entry = if self.header.version >= QCOW_VERSION_3 {
+            if keep {
+                host | QCOW_OFLAG_COPIED | QCOW_OFLAG_ZERO
+            } else {
+                QCOW_OFLAG_ZERO
+            }
+        } else if !zero || (unmap && self.backing.is_none()) {
+            0
+        } else {
+            return Ok(false);
+        };
+        // Nothing to free, or the cluster already reads as zeroes.
+        let unchanged = entry == new_entry
+            || (host == 0 && entry & QCOW_OFLAG_ZERO == 0 && !zero)
+            || (entry == 0 && self.backing.is_none());
+        if unchanged {
+            return Ok(true);
+        }
+
+        let l2_offset = self.l2_table_for_write(guest_offset)?;
+        let index = self.l2_index(guest_offset);
+        self.set_l2_entry(l2_offset, index, new_entry)?;
+        if host != 0 && !keep {
+            self.update_refcount(host, false)?;
+        }
+        if let Some(dirty) = self.dirty.as_mut() {
+            dirty.insert(guest_offset);
+        }
+        Ok(true)
+    }
+
+    /// If a cluster covered by `len` bytes at guest `offset` is being copied.
+    pub fn copying(&self, offset: u64, len: u64) -> bool {
+        let first = offset & !(self.cluster_size - 1);
+        self.copying.range(first..offset + len).next().is_some()
+    }
+
+    /// Start copying the old content of the clusters partially covered by a
+    /// guest write of `len` bytes at `offset`. The copies are run without the
+    /// image locked, the write is mapped once they end, with no data to copy
+    /// then.
+    pub fn start_copies(&mut self, offset: u64, len: u64) -> Result<Vec<ClusterCopy>> {
+        if self.read_only {
+            bail!("Failed to write read-only qcow2 image");
+        }
+        self.check_range(offset, len)?;
+        let end = offset + len;
+        let first = offset & !(self.cluster_size - 1);
+        if let Some(&failed) = self.copy_failed.range(first..end).next() {
+            self.copy_failed.remove(&failed);
+            bail!("Failed to copy qcow2 cluster at {:#x}", failed);
+        }
+        let mut copies = Vec::new();
+        let mut pos = offset;
+        while pos < end {
+            let in_cluster = pos & (self.cluster_size - 1);
+            let chunk = (self.cluster_size - in_cluster).min(end - pos);
+            if !self.whole_cluster(pos, chunk) {
+                match self.start_copy(pos - in_cluster) {
+                    Ok(Some(copy)) => copies.push(copy),
+                    Ok(None) => {}
+                    Err(e) => {
+                        for copy in copies {
+                            self.drop_copy(copy)?;
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            pos += chunk;
+        }
+        Ok(copies)
+    }
+
+    /// Prepare the copy of the guest cluster at `guest_offset`, None if it's
+    /// written in place or its new cluster reads as zeroes.
+    fn start_copy(&mut self, guest_offset: u64) -> Result<Option<ClusterCopy>> {
+        let entry = self.l2_entry(guest_offset)?;
+        let old = entry & L2E_OFFSET_MASK;
+        let mut sources = Vec::new();
+        let host = if old != 0 && entry & QCOW_OFLAG_COPIED != 0 {
+            if entry & QCOW_OFLAG_ZERO == 0 {
+                return Ok(None);
+            }
+            // Preallocated cluster reading as zeroes, it's cleared.
+            sources.push(DiskRange::Zero {
+                len: self.cluster_size,
+            });
+            old
+        } else {
+            if entry & QCOW_OFLAG_ZERO != 0 || (old == 0 && self.backing.is_none()) {
+                return Ok(None);
+            }
+            self.map_into(guest_offset, self.cluster_size, &mut sources)?;
+            self.alloc_clusters(1)?
+        };
+        for range in sources.iter_mut() {
+            if let DiskRange::Data { fd, direct, .. } = range {
+                if *direct {
+                    // Through the buffered handle, `data` may need aligned buffers.
+                    *fd = self.file.as_raw_fd();
+                    *direct = false;
+                }
+            }
+        }
+        self.copying.insert(guest_offset);
+        Ok(Some(ClusterCopy {
+            guest_offset,
+            host,
+            old,
+            sources,
+            file: self.file.as_raw_fd(),
+        }))
+    }
+
+    /// Map the cluster of `copy` once it's copied. If the copy failed, the
+    /// next write of the cluster fails instead.
+    pub fn end_copy(&mut self, copy: ClusterCopy, copied: bool) -> Result<()> {
+        if !copied {
+            self.copy_failed.insert(copy.guest_offset);
+            return self.drop_copy(copy);
+        }
+        self.copying.remove(&copy.guest_offset);
+        let mapped = self.map_copy(&copy);
+        if mapped.is_err() {
+            self.copy_failed.insert(copy.guest_offset);
+        }
+        mapped
+    }
+
+    fn map_copy(&mut self, copy: &ClusterCopy) -> Result<()> {
+        let l2_offset = self.l2_table_for_write(copy.guest_offset)?;
+        let index = self.l2_index(copy.guest_offset);
+        self.set_l2_entry(l2_offset, index, copy.host | QCOW_OFLAG_COPIED)?;
+        if copy.old != 0 && copy.old != copy.host {
+            // The shared cluster lost the reference of this image.
+            self.update_refcount(copy.old, false)?;
+        }
+        Ok(())
+    }
+
+    /// Give up `copy`, freeing the cluster allocated for it.
+    fn drop_copy(&mut self, copy: ClusterCopy) -> Result<()> {
+        self.copying.remove(&copy.guest_offset);
+        if copy.host != copy.old {
+            self.update_refcount(copy.host, false)?;
+        }
+        Ok(())
+    }
+
+    /// Map `len` bytes at guest `offset` for writing, allocating the clusters
+    /// which can't be written in place.
+    ///
+    /// The metadata is updated before the guest data is written, so the
+    /// clusters partially written are filled with their old content before
+    /// the L2 entries point at them. Until the guest data lands, a concurrent
+    /// reader or a crash sees the old content there, while the clusters the
+    /// guest writes whole may read as zeroes, as a write not completed leaves
+    /// its range undefined.
+    pub fn map_write(&mut self, offset: u64, len: u64) -> Result<Vec<DiskRange>> {
+        if self.read_only {
+            bail!("Failed to write read-only qcow2 image");
+        }
+        self.check_range(offset, len)?;
+        let mut ranges = Vec::new();
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let in_cluster = pos & (self.cluster_size - 1);
+            let chunk = (self.cluster_size - in_cluster).min(end - pos);
+            let whole = self.whole_cluster(pos, chunk);
+            let host = self.cluster_for_write(pos - in_cluster, whole)?;
+            if let Some(dirty) = self.dirty.as_mut() {
+                dirty.insert(pos - in_cluster);
+            }
+            push_range(
+                &mut ranges,
+                DiskRange::Data {
+                    fd: self.data.as_raw_fd(),
+                    direct: self.direct,
+                    offset: host + in_cluster,
+                    len: chunk,
+                },
+            );
+            pos += chunk;
+        }
+        Ok(ranges)
+    }
+
+    /// Get the host cluster the guest cluster at `guest_offset` is written to.
+    /// Unless the guest writes the `whole` cluster, the old content is copied
+    /// to a newly allocated cluster before it is mapped, synchronously if the
+    /// copy isn't done by `start_copies` beforehand.
+    fn cluster_for_write(&mut self, guest_offset: u64, whole: bool) -> Result<u64> {
+        let l2_offset = self.l2_table_for_write(guest_offset)?;
+        let index = self.l2_index(guest_offset);
+        let entry = self.l2_entry(guest_offset)?;
+        let host = entry & L2E_OFFSET_MASK;
+        if host != 0 && entry & QCOW_OFLAG_COPIED != 0 {
+            if entry & QCOW_OFLAG_ZERO != 0 && !whole {
+                // Preallocated cluster reading as zeroes, clear it before the
+                // guest writes part of it.
+                self.file
+                    .write_all_at(&vec![0; self.cluster_size as usize], host)
+                    .with_context(|| "Failed to zero qcow2 cluster")?;
+            }
+            if entry & QCOW_OFLAG_ZERO != 0 {
+                self.set_l2_entry(l2_offset, index, host | QCOW_OFLAG_COPIED)?;
+            }
+            return Ok(host);
+        }
+
+        let new_host = self.alloc_clusters(1)?;
+        let has_content = entry & QCOW_OFLAG_ZERO == 0 && (host != 0 || self.backing.is_some());
+        if has_content && !whole {
+            let mut buf = vec![0; self.cluster_size as usize];
+            self.read_at(guest_offset, &mut buf)?;
+            self.file
+                .write_all_at(&buf, new_host)
+                .with_context(|| "Failed to copy qcow2 cluster")?;
+        }
+        self.set_l2_entry(l2_offset, index, new_host | QCOW_OFLAG_COPIED)?;
+        if host != 0 {
+            // The shared cluster lost the reference of this image.
+            self.update_refcount(host, false)?;
+        }
+        Ok(new_host)
+    }
+
+    /// Get the L2 table mapping `guest_offset`, allocating it or copying a
+    /// shared one if needed.
+    fn l2_table_for_write(&mut self, guest_offset: u64) -> Result<u64> {
+        let l1_index = self.l1_index(guest_offset);
+        let entry = self.l1_table[l1_index];
+        let old = entry & L1E_OFFSET_MASK;
+        if old != 0 && entry & QCOW_OFLAG_COPIED != 0 {
+            return Ok(old);
+        }
+
+        let new = self.alloc_clusters(1)?;
+        let table = if old != 0 {
+            self.l2_cache
+                .get(&self.file, old, self.cluster_size as usize)?
+                .clone()
+        } else {
+            vec![0; self.cluster_size as usize]
+        };
+        self.file
+            .write_all_at(&table, new)
+            .with_context(|| "Failed to write qcow2 L2 table")?;
+        self.l2_cache.insert(new, table);
+
+        let new_entry = new | QCOW_OFLAG_COPIED;
+        self.file
+            .write_all_at(
+                &new_entry.to_be_bytes(),
+                self.header.l1_table_offset + l1_index as u64 * 8,
+            )
+            .with_context(|| "Failed to write qcow2 L1 table")?;
+        self.l1_table[l1_index] = new_entry;
+        if old != 0 {
+            self.update_refcount(old, false)?;
+        }
+        Ok(new)
+    }
+
+    fn set_l2_entry(&mut self, l2_offset: u64, index: usize, entry: u64) -> Result<()> {
+        let table = self
+            .l2_cache
+            .get(&self.file, l2_offset, self.cluster_size as usize)?;
+        BigEndian::write_u64(&mut table[index * 8..], entry);
+        self.file
+            .write_all_at(&entry.to_be_bytes(), l2_offset + index as u64 * 8)
+            .with_context(|| "Failed to write qcow2 L2 table")
+    }
+
+    /// Allocate `count` contiguous clusters at the end of the image.
+    fn alloc_clusters(&mut self, count: u64) -> Result<u64> {
+        let offset = self.free_offset;
+        self.free_offset += count * self.cluster_size;
+        for i in 0..count {
+            self.update_refcount(offset + i * self.cluster_size, true)?;
+        }
+        // Clusters partially written by the guest must still read in full.
+        self.file
+            .set_len(self.free_offset)
+            .with_context(|| "Failed to extend qcow2 image")?;
+        Ok(offset)
+    }
+
+    /// Number of refcounts in a refcount block.
+    fn refblock_entries(&self) -> u64 {
+        (self.cluster_size * 8) >> self.header.refcount_order
+    }
+
+    fn update_refcount(&mut self, host_offset: u64, increase: bool) -> Result<()> {
+        let cluster = host_offset >> self.header.cluster_bits;
+        let table_index = (cluster / self.refblock_entries()) as usize;
+        let block_index = cluster % self.refblock_entries();
+        if table_index >= self.refcount_table.len() {
+            self.grow_refcount_table(table_index)?;
+        }
+        let mut block = self.refcount_table[table_index] & REFT_OFFSET_MASK;
+        if block == 0 {
+            block = self.alloc_refcount_block(table_index)?;
+        }
+
+        let order = self.header.refcount_order;
+        let max = u64::MAX >> (64 - (1 << order));
+        let table = self
+            .refblock_cache
+            .get(&self.file, block, self.cluster_size as usize)?;
+        let refcount = get_refcount(table, block_index, order);
+        let refcount = if increase {
+            refcount.checked_add(1).filter(|&r| r <= max)
+        } else {
+            refcount.checked_sub(1)
+        }
+        .with_context(|| format!("Invalid refcount of qcow2 cluster 0x{:x}", host_offset))?;
+        let changed = set_refcount(table, block_index, order, refcount);
+        self.file
+            .write_all_at(&table[changed.clone()], block + changed.start as u64)
+            .with_context(|| "Failed to write qcow2 refcount block")
+    }
+
+    fn alloc_refcount_block(&mut self, table_index: usize) -> Result<u64> {
+        let block = self.free_offset;
+        self.free_offset += self.cluster_size;
+        let table = vec![0; self.cluster_size as usize];
+        self.file
+            .write_all_at(&table, block)
+            .with_context(|| "Failed to write qcow2 refcount block")?;
+        self.refblock_cache.insert(block, table);
+
+        self.file
+            .write_all_at(
+                &block.to_be_bytes(),
+                self.header.refcount_table_offset + table_index as u64 * 8,
+            )
+            .with_context(|| "Failed to write qcow2 refcount table")?;
+        self.refcount_table[table_index] = block;
+        // The new block is counted like any other cluster, maybe by itself.
+        self.update_refcount(block, true)?;
+        Ok(block)
+    }
+
+    /// Move the refcount table to a larger place at the end of the image.
+    fn grow_refcount_table(&mut self, min_index: usize) -> Result<()> {
+        let entries_per_cluster = self.cluster_size / 8;
+        let wanted = (min_index as u64 + 1).max(self.refcount_table.len() as u64 * 2);
+        let mut clusters = round_up(wanted, entries_per_cluster).unwrap() / entries_per_cluster;
+        // The new table must also cover itself and the refcount blocks
+        // allocated right after it.
+        loop {
+            let end = self.free_offset + (clusters + 1) * self.cluster_size;
+            let needed = (end >> self.header.cluster_bits) / self.refblock_entries() + 2;
+            if needed <= clusters * entries_per_cluster {
+                break;
+            }
+            clusters += 1;
+        }
+        if clusters * self.cluster_size > MAX_REFCOUNT_TABLE_SIZE {
+            bail!("Qcow2 refcount table is too large");
+        }
+
+        let new_offset = self.free_offset;
+        self.free_offset += clusters * self.cluster_size;
+        let mut table = self.refcount_table.clone();
+        table.resize((clusters * entries_per_cluster) as usize, 0);
+        let mut buf = vec![0_u8; table.len() * 8];
+        BigEndian::write_u64_into(&table, &mut buf);
+        self.file
+            .write_all_at(&buf, new_offset)
+            .with_context(|| "Failed to write qcow2 refcount table")?;
+
+        let mut header = [0_u8; 12];
+        BigEndian::write_u64(&mut header[0..], new_offset);
+        BigEndian::write_u32(&mut header[8..], clusters as u32);
+        self.file
+            .write_all_at(&header, HEADER_REFCOUNT_TABLE)
+            .with_context(|| "Failed to update qcow2 header")?;
+
+        let old_offset = self.header.refcount_table_offset;
+        let old_clusters = self.header.refcount_table_clusters as u64;
+        self.header.refcount_table_offset = new_offset;
+        self.header.refcount_table_clusters = clusters as u32;
+        self.refcount_table = table;
+        for i in 0..clusters {
+            self.update_refcount(new_offset + i * self.cluster_size, true)?;
+        }
+        for i in 0..old_clusters {
+            self.update_refcount(old_offset + i * self.cluster_size, false)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const CLUSTER_BITS: u32 = 12;
+    const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+
+    /// Create a version 3 image: the header, then one cluster each for the
+    /// refcount table, the refcount block and the L1 table.
+    fn create_image(path: &str, size: u64, backing: Option<&str>) {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        let mut header = vec![0_u8; CLUSTER_SIZE as usize];
+        BigEndian::write_u32(&mut header[0..], QCOW_MAGIC);
+        BigEndian::write_u32(&mut header[4..], QCOW_VERSION_3);
+        if let Some(backing) = backing {
+            // After the header and the end of the extensions.
+            let name_offset = QCOW_V3_HEADER_SIZE + 8;
+            BigEndian::write_u64(&mut header[8..], name_offset as u64);
+            BigEndian::write_u32(&mut header[16..], backing.len() as u32);
+            header[name_offset..name_offset + backing.len()].copy_from_slice(backing.as_bytes());
+        }
+        BigEndian::write_u32(&mut header[20..], CLUSTER_BITS);
+        BigEndian::write_u64(&mut header[24..], size);
+        BigEndian::write_u32(&mut header[36..], 1);
+        BigEndian::write_u64(&mut header[40..], 3 * CLUSTER_SIZE);
+        BigEndian::write_u64(&mut header[48..], CLUSTER_SIZE);
+        BigEndian::write_u32(&mut header[56..], 1);
+        BigEndian::write_u32(&mut header[96..], V2_REFCOUNT_ORDER);
+        BigEndian::write_u32(&mut header[100..], QCOW_V3_HEADER_SIZE as u32);
+        file.write_all_at(&header, 0).unwrap();
+
+        file.write_all_at(&(2 * CLUSTER_SIZE).to_be_bytes(), CLUSTER_SIZE)
+            .unwrap();
+        let mut refblock = vec![0_u8; CLUSTER_SIZE as usize];
+        for i in 0..4 {
+            set_refcount(&mut refblock, i, V2_REFCOUNT_ORDER, 1);
+        }
+        file.write_all_at(&refblock, 2 * CLUSTER_SIZE).unwrap();
+        file.set_len(4 * CLUSTER_SIZE).unwrap();
+    }
+
+    fn open_image(path: &str) -> Qcow2Image {
+        let data = Arc::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .unwrap(),
+        );
+        Qcow2Image::open(path, data, false, false).unwrap()
+    }
+
+    fn refcount(image: &mut Qcow2Image, host_offset: u64) -> u64 {
+        let cluster = host_offset >> image.header.cluster_bits;
+        let entries = image.refblock_entries();
+        let block = image.refcount_table[(cluster / entries) as usize];
+        let table = image
+            .refblock_cache
+            .get(&image.file, block, image.cluster_size as usize)
+            .unwrap();
+        get_refcount(table, cluster % entries, image.header.refcount_order)
+    }
+
+    fn write_ranges(ranges: &[DiskRange], data: &[u8]) {
+        let mut done = 0;
+        for range in ranges {
+            if let DiskRange::Data {
+                fd, offset, len, ..
+            } = range
+            {
+                let ret = util::aio::raw_write(
+                    *fd,
+                    data[done..].as_ptr() as u64,
+                    *len as usize,
+                    *offset as usize,
+                );
+                assert_eq!(ret, *len as i64);
+            }
+            done += range.len() as usize;
+        }
+    }
+
+    #[test]
+    fn test_open_invalid_image() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap().to_string();
+        file.as_file().set_len(CLUSTER_SIZE).unwrap();
+        let data = Arc::new(file.as_file().try_clone().unwrap());
+        assert!(Qcow2Image::open(&path, data.clone(), false, false).is_err());
+
+        create_image(&path, 1 << 20, None);
+        assert!(Qcow2Image::open(&path, data.clone(), false, false).is_ok());
+        // Encrypted.
+        data.write_all_at(&1_u32.to_be_bytes(), 32).unwrap();
+        assert!(Qcow2Image::open(&path, data.clone(), false, false).is_err());
+        data.write_all_at(&0_u32.to_be_bytes(), 32).unwrap();
+        // L1 table too small for 1TiB.
+        data.write_all_at(&(1_u64 << 40).to_be_bytes(), 24).unwrap();
+        assert!(Qcow2Image::open(&path, data, false, false).is_err());
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap().to_string();
+        create_image(&path, 1 << 20, None);
+        let mut image = open_image(&path);
+        assert_eq!(image.virtual_size(), 1 << 20);
+
+        // Nothing is allocated yet.
+        let ranges = image.map_read(0, 3 * CLUSTER_SIZE).unwrap();
+        assert_eq!(
+            ranges,
+            vec![DiskRange::Zero {
+                len: 3 * CLUSTER_SIZE
+            }]
+        );
+        assert!(image.map_read(1 << 20, 512).is_err());
+
+        // Spans two clusters, which are allocated after the L2 table.
+        let data = vec![0xa5_u8; CLUSTER_SIZE as usize];
+        let offset = 5 * CLUSTER_SIZE + 512;
+        let ranges = image.map_write(offset, CLUSTER_SIZE).unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(
+            ranges[0],
+            DiskRange::Data {
+                fd: image.data.as_raw_fd(),
+                direct: false,
+                offset: 5 * CLUSTER_SIZE + 512,
+                len: CLUSTER_SIZE,
+            }
+        );
+        write_ranges(&ranges, &data);
+        assert_eq!(refcount(&mut image, 4 * CLUSTER_SIZE), 1);
+        assert_eq!(refcount(&mut image, 5 * CLUSTER_SIZE), 1);
+        assert_eq!(refcount(&mut image, 6 * CLUSTER_SIZE), 1);
+        assert_eq!(refcount(&mut image, 7 * CLUSTER_SIZE), 0);
+
+        // Written in place now.
+        assert_eq!(image.map_write(offset, CLUSTER_SIZE).unwrap(), ranges);
+
+        // The mapping survives reopening.
+        drop(image);
+        let mut image = open_image(&path);
+        let ranges = image.map_read(4 * CLUSTER_SIZE, 3 * CLUSTER_SIZE).unwrap();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[0], DiskRange::Zero { len: CLUSTER_SIZE });
+        assert_eq!(ranges[1].len(), 2 * CLUSTER_SIZE);
+        let mut buf = vec![0_u8; 2 * CLUSTER_SIZE as usize];
+        image.read_at(5 * CLUSTER_SIZE, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0));
+        assert!(buf[512..512 + CLUSTER_SIZE as usize]
+            .iter()
+            .all(|&b| b == 0xa5));
+        assert!(buf[512 + CLUSTER_SIZE as usize..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_backing_file() {
+        let backing = TempFile::new().unwrap();
+        let backing_path = backing.as_path().to_str().unwrap().to_string();
+        // The backing file is shorter than the image.
+        backing
+            .as_file()
+            .write_all_at(&vec![0x5a_u8; 2 * CLUSTER_SIZE as usize], 0)
+            .unwrap();
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap().to_string();
+        create_image(&path, 1 << 20, Some(&backing_path));
+        let mut image = open_image(&path);
+
+        let ranges = image.map_read(CLUSTER_SIZE, 2 * CLUSTER_SIZE).unwrap();
+        assert_eq!(ranges.len(), 2);
+        match ranges[0] {
+            DiskRange::Data {
+                fd, offset, len, ..
+            } => {
+                assert_ne!(fd, image.data.as_raw_fd());
+                assert_eq!((offset, len), (CLUSTER_SIZE, CLUSTER_SIZE));
+            }
+            _ => panic!("Unallocated cluster not read from the backing file"),
+        }
+        assert_eq!(ranges[1], DiskRange::Zero { len: CLUSTER_SIZE });
+
+        // A partial write copies the rest of the cluster from the backing file.
+        let ranges = image.map_write(512, 512).unwrap();
+        write_ranges(&ranges, &[0xff_u8; 512]);
+        let mut buf = vec![0_u8; CLUSTER_SIZE as usize];
+        image.read_at(0, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0x5a));
+        assert!(buf[512..1024].iter().all(|&b| b == 0xff));
+        assert!(buf[1024..].iter().all(|&b| b == 0x5a));
+        match image.map_read(0, CLUSTER_SIZE).unwrap()[0] {
+            DiskRange::Data { fd, .. } => assert_eq!(fd, image.data.as_raw_fd()),
+            _ => panic!("Written cluster not read from the image"),
+        }
+
+        // The backing content of a cluster mapped for a full write isn't
+        // copied.
+        let ranges = image.map_write(CLUSTER_SIZE, CLUSTER_SIZE).unwrap();
+        image.read_at(CLUSTER_SIZE, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        write_ranges(&ranges, &vec![0xff_u8; CLUSTER_SIZE as usize]);
+        image.read_at(CLUSTER_SIZE, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn test_copy_before_partial_write() {
+        let backing = TempFile::new().unwrap();
+        let backing_path = backing.as_path().to_str().unwrap().to_string();
+        backing
+            .as_file()
+            .write_all_at(&vec![0x5a_u8; 2 * CLUSTER_SIZE as usize], 0)
+            .unwrap();
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap().to_string();
+        create_image(&path, 1 << 20, Some(&backing_path));
+        let mut image = open_image(&path);
+
+        // Whole clusters are written without copy.
+        assert!(image.start_copies(0, 2 * CLUSTER_SIZE).unwrap().is_empty());
+
+        let copies = image.start_copies(512, 512).unwrap();
+        assert_eq!(copies.len(), 1);
+        assert!(image.copying(0, 512));
+        assert!(!image.copying(CLUSTER_SIZE, 512));
+        // The cluster reads from the backing file until the copy ends.
+        match image.map_read(0, CLUSTER_SIZE).unwrap()[0] {
+            DiskRange::Data { fd, .. } => assert_ne!(fd, image.data.as_raw_fd()),
+            _ => panic!("Cluster being copied not read from the backing file"),
+        }
+        for copy in copies {
+            copy.run().unwrap();
+            image.end_copy(copy, true).unwrap();
+        }
+        assert!(!image.copying(0, 512));
+        assert!(image.start_copies(512, 512).unwrap().is_empty());
+        let ranges = image.map_write(512, 512).unwrap();
+        write_ranges(&ranges, &[0xff_u8; 512]);
+        let mut buf = vec![0_u8; CLUSTER_SIZE as usize];
+        image.read_at(0, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0x5a));
+        assert!(buf[512..1024].iter().all(|&b| b == 0xff));
+        assert!(buf[1024..].iter().all(|&b| b == 0x5a));
+
+        // A failed copy frees its cluster and fails the next write.
+        let mut copies = image.start_copies(CLUSTER_SIZE + 512, 512).unwrap();
+        let copy = copies.pop().unwrap();
+        let host = copy.host;
+        image.end_copy(copy, false).unwrap();
+        assert_eq!(refcount(&mut image, host), 0);
+        assert!(!image.copying(CLUSTER_SIZE, CLUSTER_SIZE));
+        assert!(image.start_copies(CLUSTER_SIZE + 512, 512).is_err());
+        assert_eq!(
+            image.start_copies(CLUSTER_SIZE + 512, 512).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_write_zeroes_and_discard() {
+        let backing = TempFile::new().unwrap();
+        let backing_path = backing.as_path().to_str().unwrap().to_string();
+        backing
+            .as_file()
+            .write_all_at(&vec![0x5a_u8; 3 * CLUSTER_SIZE as usize], 0)
+            .unwrap();
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap().to_string();
+        create_image(&path, 1 << 20, Some(&backing_path));
+        let mut image = open_image(&path);
+        let mut buf = vec![0_u8; CLUSTER_SIZE as usize];
+
+        // A whole cluster keeps its host cluster, marked as zero.
+        image
+            .write_at(0, &vec![0xff_u8; CLUSTER_SIZE as usize])
+            .unwrap();
+        let host = image.l2_entry(0).unwrap() & L2E_OFFSET_MASK;
+        image.write_zeroes(0, CLUSTER_SIZE, false).unwrap();
+        assert_eq!(
+            image.l2_entry(0).unwrap(),
+            host | QCOW_OFLAG_COPIED | QCOW_OFLAG_ZERO
+        );
+        assert_eq!(
+            image.map_read(0, CLUSTER_SIZE).unwrap(),
+            vec![DiskRange::Zero { len: CLUSTER_SIZE }]
+        );
+        // Then freed with unmap.
+        image.write_zeroes(0, CLUSTER_SIZE, true).unwrap();
+        assert_eq!(image.l2_entry(0).unwrap(), QCOW_OFLAG_ZERO);
+        assert_eq!(refcount(&mut image, host), 0);
+
+        // Unallocated clusters hide the backing file, partial ones are written.
+        image
+            .write_zeroes(CLUSTER_SIZE + 512, 2 * CLUSTER_SIZE - 512, false)
+            .unwrap();
+        image.read_at(CLUSTER_SIZE, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0x5a));
+        assert!(buf[512..].iter().all(|&b| b == 0));
+        image.read_at(2 * CLUSTER_SIZE, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        assert_eq!(image.l2_entry(2 * CLUSTER_SIZE).unwrap(), QCOW_OFLAG_ZERO);
+
+        // Only the clusters fully covered are discarded.
+        let host = image.l2_entry(CLUSTER_SIZE).unwrap() & L2E_OFFSET_MASK;
+        assert_ne!(host, 0);
+        image.discard(512, CLUSTER_SIZE).unwrap();
+        assert_eq!(
+            image.l2_entry(CLUSTER_SIZE).unwrap() & L2E_OFFSET_MASK,
+            host
+        );
+        image.discard(CLUSTER_SIZE, CLUSTER_SIZE).unwrap();
+        assert_eq!(image.l2_entry(CLUSTER_SIZE).unwrap(), QCOW_OFLAG_ZERO);
+        assert_eq!(refcount(&mut image, host), 0);
+        assert!(image.discard(1 << 20, 512).is_err());
+    }
+
+    #[test]
+    fn test_grow_refcount_table() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap().to_string();
+        create_image(&path, 1 << 20, None);
+        let mut image = open_image(&path);
+
+        // One cluster of refcount table covers 512 blocks of 2048 clusters.
+        let covered = 512 * 2048 * CLUSTER_SIZE;
+        image.free_offset = covered;
+        let offset = image.alloc_clusters(1).unwrap();
+        assert_eq!(offset, covered);
+        assert!(image.header.refcount_table_clusters > 1);
+        let table_offset = image.header.refcount_table_offset;
+        assert_eq!(refcount(&mut image, table_offset), 1);
+        assert_eq!(refcount(&mut image, CLUSTER_SIZE), 0);
+        assert_eq!(refcount(&mut image, offset), 1);
+
+        drop(image);
+        let mut image = open_image(&path);
+        assert_eq!(image.header.refcount_table_offset, table_offset);
+        assert_eq!(refcount(&mut image, offset), 1);
+    }
+
+    #[test]
+    fn test_create_image() {
+        let backing = TempFile::new().unwrap();
+        let backing_path = backing.as_path().to_str().unwrap().to_string();
+        backing
+            .as_file()
+            .write_all_at(&[0x5a_u8; 512], 1 << 20)
+            .unwrap();
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap().to_string();
+        Qcow2Image::create(&path, 4 << 20, Some((&backing_path, DiskFormat::Raw))).unwrap();
+
+        let mut image = open_image(&path);
+        assert_eq!(image.virtual_size(), 4 << 20);
+        assert_eq!(image.cluster_size(), 1 << DEFAULT_CLUSTER_BITS);
+        assert_eq!(
+            image.backing_file(),
+            Some((backing_path.clone(), DiskFormat::Raw))
+        );
+        let mut buf = vec![0_u8; 1024];
+        image.read_at((1 << 20) - 512, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0));
+        assert!(buf[512..].iter().all(|&b| b == 0x5a));
+
+        // Metadata clusters are counted, the L2 table and the data cluster go
+        // after them.
+        assert_eq!(refcount(&mut image, 3 << DEFAULT_CLUSTER_BITS), 1);
+        image.write_at(0, &[0xff_u8; 512]).unwrap();
+        assert_eq!(image.allocated_clusters().unwrap(), vec![0]);
+        match image.map_read(0, 512).unwrap()[0] {
+            DiskRange::Data { offset, .. } => assert_eq!(offset, 5 << DEFAULT_CLUSTER_BITS),
+            _ => panic!("Written cluster not read from the image"),
+        }
+
+        Qcow2Image::create(&path, 4 << 20, None).unwrap();
+        let image = open_image(&path);
+        assert_eq!(image.backing_file(), None);
+    }
+
+    #[test]
+    fn test_dirty_clusters() {
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_str().unwrap().to_string();
+        create_image(&path, 1 << 20, None);
+        let mut image = open_image(&path);
+
+        image.write_at(CLUSTER_SIZE, &[1_u8; 512]).unwrap();
+        assert!(image.take_dirty().is_empty());
+        image.track_dirty(true);
+        image
+            .write_at(3 * CLUSTER_SIZE - 512, &[2_u8; 1024])
+            .unwrap();
+        image.write_at(CLUSTER_SIZE, &[3_u8; 512]).unwrap();
+        assert_eq!(
+            image.take_dirty(),
+            vec![CLUSTER_SIZE, 2 * CLUSTER_SIZE, 3 * CLUSTER_SIZE]
+        );
+        assert!(image.take_dirty().is_empty());
+        image.track_dirty(false);
+        image.write_at(0, &[4_u8; 512]).unwrap();
+        assert!(image.take_dirty().is_empty());
+
+        assert_eq!(
+            image.allocated_clusters().unwrap(),
+            vec![0, CLUSTER_SIZE, 2 * CLUSTER_SIZE, 3 * CLUSTER_SIZE]
+        );
+    }
+
+    #[test]
+    fn test_refcount_order() {
+        let mut block = vec![0_u8; 32];
+        for order in 0..=MAX_REFCOUNT_ORDER {
+            let max = u64::MAX >> (64 - (1 << order));
+            block.fill(0);
+            let changed = set_refcount(&mut block, 1, order, max);
+            assert_eq!(get_refcount(&block, 0, order), 0);
+            assert_eq!(get_refcount(&block, 1, order), max);
+            assert_eq!(get_refcount(&block, 2, order), 0);
+            assert_eq!(changed.len(), ((1 << order) / 8).max(1));
+        }
+    }
+}
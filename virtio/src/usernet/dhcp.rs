@@ -0,0 +1,210 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! DHCP server of the user-mode network, refer to RFC 2131. There is only one
+//! guest in the network, so the `dhcp_start` address is always allocated.
+
+use std::net::Ipv4Addr;
+
+use machine_manager::config::UserNetConfig;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTP_REQUEST: u8 = 1;
+const BOOTP_REPLY: u8 = 2;
+/// Length of the fixed fields before the magic cookie.
+const BOOTP_HDR_LEN: usize = 236;
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const DHCP_OPT_PAD: u8 = 0;
+const DHCP_OPT_SUBNET_MASK: u8 = 1;
+const DHCP_OPT_ROUTER: u8 = 3;
+const DHCP_OPT_DNS: u8 = 6;
+const DHCP_OPT_REQUESTED_IP: u8 = 50;
+const DHCP_OPT_LEASE_TIME: u8 = 51;
+const DHCP_OPT_MSG_TYPE: u8 = 53;
+const DHCP_OPT_SERVER_ID: u8 = 54;
+const DHCP_OPT_END: u8 = 255;
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+
+/// Lease time in seconds.
+const DHCP_LEASE_TIME: u32 = 86400;
+
+/// Find the option `code` in the options of a DHCP message.
+fn find_option(mut options: &[u8], code: u8) -> Option<&[u8]> {
+    while let Some(&opt) = options.first() {
+        match opt {
+            DHCP_OPT_END => break,
+            DHCP_OPT_PAD => options = &options[1..],
+            _ => {
+                let len = *options.get(1)? as usize;
+                let data = options.get(2..2 + len)?;
+                if opt == code {
+                    return Some(data);
+                }
+                options = &options[2 + len..];
+            }
+        }
+    }
+    None
+}
+
+fn push_option(buf: &mut Vec<u8>, code: u8, data: &[u8]) {
+    buf.push(code);
+    buf.push(data.len() as u8);
+    buf.extend_from_slice(data);
+}
+
+/// Handle the DHCP message `msg` sent by the guest, return the reply which
+/// should be broadcast to the client port.
+pub fn handle_dhcp(cfg: &UserNetConfig, msg: &[u8]) -> Option<Vec<u8>> {
+    if msg.len() < BOOTP_HDR_LEN + DHCP_MAGIC_COOKIE.len()
+        || msg[0] != BOOTP_REQUEST
+        || msg[BOOTP_HDR_LEN..BOOTP_HDR_LEN + 4] != DHCP_MAGIC_COOKIE
+    {
+        return None;
+    }
+    let options = &msg[BOOTP_HDR_LEN + 4..];
+    let msg_type = *find_option(options, DHCP_OPT_MSG_TYPE)?.first()?;
+
+    let reply_type = match msg_type {
+        DHCP_DISCOVER => DHCP_OFFER,
+        DHCP_REQUEST => {
+            // The client selects another server.
+            if let Some(server) = find_option(options, DHCP_OPT_SERVER_ID) {
+                if server != cfg.host.octets() {
+                    return None;
+                }
+            }
+            let requested = match find_option(options, DHCP_OPT_REQUESTED_IP) {
+                Some(addr) if addr.len() == 4 => Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]),
+                // The client in the renewing state fills its address in "ciaddr".
+                _ => Ipv4Addr::new(msg[12], msg[13], msg[14], msg[15]),
+            };
+            if requested == cfg.dhcp_start {
+                DHCP_ACK
+            } else {
+                DHCP_NAK
+            }
+        }
+        _ => return None,
+    };
+
+    let mut reply = vec![0_u8; BOOTP_HDR_LEN];
+    reply[0] = BOOTP_REPLY;
+    // Hardware type and address length.
+    reply[1..3].copy_from_slice(&msg[1..3]);
+    // Transaction id.
+    reply[4..8].copy_from_slice(&msg[4..8]);
+    // Flags.
+    reply[10..12].copy_from_slice(&msg[10..12]);
+    if reply_type != DHCP_NAK {
+        reply[16..20].copy_from_slice(&cfg.dhcp_start.octets());
+        reply[20..24].copy_from_slice(&cfg.host.octets());
+    }
+    // Relay agent address and client hardware address.
+    reply[24..44].copy_from_slice(&msg[24..44]);
+    reply.extend_from_slice(&DHCP_MAGIC_COOKIE);
+
+    push_option(&mut reply, DHCP_OPT_MSG_TYPE, &[reply_type]);
+    push_option(&mut reply, DHCP_OPT_SERVER_ID, &cfg.host.octets());
+    if reply_type != DHCP_NAK {
+        push_option(
+            &mut reply,
+            DHCP_OPT_LEASE_TIME,
+            &DHCP_LEASE_TIME.to_be_bytes(),
+        );
+        push_option(&mut reply, DHCP_OPT_SUBNET_MASK, &cfg.netmask().octets());
+        push_option(&mut reply, DHCP_OPT_ROUTER, &cfg.host.octets());
+        push_option(&mut reply, DHCP_OPT_DNS, &cfg.dns.octets());
+    }
+    reply.push(DHCP_OPT_END);
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_request(msg_type: u8, requested: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut msg = vec![0_u8; BOOTP_HDR_LEN];
+        msg[0] = BOOTP_REQUEST;
+        msg[1] = 1;
+        msg[2] = 6;
+        msg[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        msg[28..34].copy_from_slice(&[0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        msg.extend_from_slice(&DHCP_MAGIC_COOKIE);
+        push_option(&mut msg, DHCP_OPT_MSG_TYPE, &[msg_type]);
+        if let Some(addr) = requested {
+            push_option(&mut msg, DHCP_OPT_REQUESTED_IP, &addr.octets());
+        }
+        msg.push(DHCP_OPT_END);
+        msg
+    }
+
+    #[test]
+    fn test_dhcp_lease() {
+        let cfg = UserNetConfig::default();
+
+        let offer = handle_dhcp(&cfg, &build_request(DHCP_DISCOVER, None)).unwrap();
+        assert_eq!(offer[0], BOOTP_REPLY);
+        assert_eq!(offer[4..8], [1, 2, 3, 4]);
+        assert_eq!(offer[16..20], cfg.dhcp_start.octets());
+        assert_eq!(offer[28..34], [0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        let options = &offer[BOOTP_HDR_LEN + 4..];
+        assert_eq!(
+            find_option(options, DHCP_OPT_MSG_TYPE),
+            Some(&[DHCP_OFFER][..])
+        );
+        assert_eq!(
+            find_option(options, DHCP_OPT_SUBNET_MASK),
+            Some(&[255, 255, 255, 0][..])
+        );
+        assert_eq!(
+            find_option(options, DHCP_OPT_ROUTER),
+            Some(&cfg.host.octets()[..])
+        );
+        assert_eq!(
+            find_option(options, DHCP_OPT_DNS),
+            Some(&cfg.dns.octets()[..])
+        );
+
+        let ack = handle_dhcp(&cfg, &build_request(DHCP_REQUEST, Some(cfg.dhcp_start))).unwrap();
+        let options = &ack[BOOTP_HDR_LEN + 4..];
+        assert_eq!(
+            find_option(options, DHCP_OPT_MSG_TYPE),
+            Some(&[DHCP_ACK][..])
+        );
+
+        let nak = handle_dhcp(
+            &cfg,
+            &build_request(DHCP_REQUEST, Some(Ipv4Addr::new(10, 0, 2, 100))),
+        )
+        .unwrap();
+        let options = &nak[BOOTP_HDR_LEN + 4..];
+        assert_eq!(
+            find_option(options, DHCP_OPT_MSG_TYPE),
+            Some(&[DHCP_NAK][..])
+        );
+        assert_eq!(nak[16..20], [0, 0, 0, 0]);
+
+        // Invalid message.
+        assert!(handle_dhcp(&cfg, &[BOOTP_REQUEST; 64]).is_none());
+    }
+}
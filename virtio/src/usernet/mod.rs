@@ -0,0 +1,495 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! User-mode network backend, which needs no privilege of the host.
+//!
+//! The virtio-net device exchanges the frames with the stack through a
+//! SOCK_SEQPACKET socket pair, in the same format as a tap device with the
+//! vnet header, so the device handles the frames like the tap backend. The
+//! stack runs in its own thread and serves one guest:
+//! - It answers ARP and ICMP echo for the gateway and the DNS server.
+//! - It allocates the address of the guest by DHCP.
+//! - The TCP connections and UDP datagrams of the guest are relayed by the
+//!   sockets of the host, the ones to the gateway go to the loopback of the
+//!   host, and the ones to the DNS server go to the resolver of the host.
+//! - The connections to the listeners of `hostfwd` rules are forwarded to the
+//!   guest.
+
+mod dhcp;
+mod packet;
+mod tcp;
+mod udp;
+
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use machine_manager::config::UserNetConfig;
+
+use self::dhcp::{handle_dhcp, DHCP_CLIENT_PORT, DHCP_SERVER_PORT};
+use self::packet::{
+    build_icmp_echo_reply, build_udp, ArpPacket, EthHdr, Ipv4Packet, TcpSegment, UdpDatagram,
+    ARP_OP_REQUEST, BROADCAST_MAC, ETH_HDR_LEN, ETH_P_ARP, ETH_P_IP, IPPROTO_ICMP, IPPROTO_TCP,
+    IPPROTO_UDP,
+};
+use self::tcp::TcpNat;
+use self::udp::UdpNat;
+use crate::VirtioNetHdr;
+
+/// Address of the guest and the remote address seen by the guest.
+type FlowKey = (SocketAddrV4, SocketAddrV4);
+
+const DNS_PORT: u16 = 53;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+/// Interval of the timers of the connections and flows.
+const USER_NET_TICK_MS: i32 = 100;
+/// Size of the buffer to receive the frames of the guest.
+const FRAME_BUF_LEN: usize = 65536;
+
+/// Create the user-mode network stack of the netdev `id`, and return the
+/// file for the device to exchange the frames with it.
+pub fn create_user_net(id: &str, cfg: &UserNetConfig) -> Result<File> {
+    let mut listeners = Vec::with_capacity(cfg.hostfwds.len());
+    for fwd in cfg.hostfwds.iter() {
+        let addr = SocketAddrV4::new(fwd.host_addr, fwd.host_port);
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("Failed to listen on {} for hostfwd", addr))?;
+        listener
+            .set_nonblocking(true)
+            .with_context(|| "Failed to set hostfwd listener nonblocking")?;
+        listeners.push((listener, fwd.guest_addr, fwd.guest_port));
+    }
+
+    let mut fds = [0; 2];
+    // SAFETY: The fds array is valid for two fds.
+    let ret = unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error()).with_context(|| "Failed to create socket pair");
+    }
+    // SAFETY: The fds are just created and owned by the files only.
+    let (device, guest) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    let mut stack = UserNet::new(cfg.clone(), guest, TcpNat::new(listeners));
+    thread::Builder::new()
+        .name(format!("usernet-{}", id))
+        .spawn(move || stack.run())
+        .with_context(|| "Failed to spawn user network thread")?;
+    Ok(device)
+}
+
+/// The first IPv4 name server in the resolver configuration of the host.
+fn host_dns_server() -> Option<Ipv4Addr> {
+    let conf = fs::read_to_string(RESOLV_CONF).ok()?;
+    conf.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some("nameserver") {
+            return None;
+        }
+        fields.next()?.parse().ok()
+    })
+}
+
+/// Source of the events polled by the stack.
+enum Token {
+    Guest,
+    Listener(usize),
+    Tcp(FlowKey),
+    Udp(FlowKey),
+}
+
+struct UserNet {
+    cfg: UserNetConfig,
+    /// The end of the socket pair connected to the device.
+    guest: File,
+    /// MAC address of the gateway and the DNS server.
+    host_mac: [u8; 6],
+    /// MAC address of the guest, which is learned from its frames.
+    guest_mac: Option<[u8; 6]>,
+    /// Address of the guest, which is the address allocated by DHCP unless
+    /// the guest uses another one.
+    guest_ip: Ipv4Addr,
+    /// The resolver of the host which the DNS queries are forwarded to.
+    dns_server: Option<Ipv4Addr>,
+    tcp: TcpNat,
+    udp: UdpNat,
+}
+
+impl UserNet {
+    fn new(cfg: UserNetConfig, guest: File, tcp: TcpNat) -> Self {
+        let host = cfg.host.octets();
+        let dns_server = host_dns_server();
+        if dns_server.is_none() {
+            warn!("User network: no IPv4 name server in {}", RESOLV_CONF);
+        }
+        UserNet {
+            host_mac: [0x52, 0x55, host[0], host[1], host[2], host[3]],
+            guest_mac: None,
+            guest_ip: cfg.dhcp_start,
+            dns_server,
+            cfg,
+            guest,
+            tcp,
+            udp: UdpNat::default(),
+        }
+    }
+
+    /// Poll the guest and the sockets of the host until the device closes
+    /// its end of the socket pair.
+    fn run(&mut self) {
+        let mut last_tick = Instant::now();
+        let tick = Duration::from_millis(USER_NET_TICK_MS as u64);
+        loop {
+            let mut tokens = vec![Token::Guest];
+            let mut pollfds = vec![libc::pollfd {
+                fd: self.guest.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            }];
+            let mut add = |token, fd, events| {
+                tokens.push(token);
+                pollfds.push(libc::pollfd {
+                    fd,
+                    events,
+                    revents: 0,
+                });
+            };
+            for (index, fd) in self.tcp.listener_fds().enumerate() {
+                add(Token::Listener(index), fd, libc::POLLIN);
+            }
+            for (key, fd, events) in self.tcp.pollfds() {
+                add(Token::Tcp(key), fd, events);
+            }
+            for (key, fd) in self.udp.pollfds() {
+                add(Token::Udp(key), fd, libc::POLLIN);
+            }
+
+            // SAFETY: The pollfds are valid and the length is correct.
+            let ret = unsafe {
+                libc::poll(
+                    pollfds.as_mut_ptr(),
+                    pollfds.len() as libc::nfds_t,
+                    USER_NET_TICK_MS,
+                )
+            };
+            if ret < 0 {
+                let e = Error::last_os_error();
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("User network: failed to poll: {}", e);
+                return;
+            }
+
+            let mut out = Vec::new();
+            for (pollfd, token) in pollfds.iter().zip(tokens.iter()) {
+                if pollfd.revents == 0 {
+                    continue;
+                }
+                match token {
+                    Token::Guest => {
+                        if !self.handle_guest(&mut out) {
+                            info!("User network: the device is closed");
+                            return;
+                        }
+                    }
+                    Token::Listener(index) => {
+                        self.tcp
+                            .handle_accept(*index, self.guest_ip, self.cfg.host, &mut out)
+                    }
+                    Token::Tcp(key) => self.tcp.handle_host_event(key, &mut out),
+                    Token::Udp(key) => self.udp.handle_readable(key, &mut out),
+                }
+            }
+
+            let now = Instant::now();
+            if now.duration_since(last_tick) >= tick {
+                self.tcp.handle_timer(now, &mut out);
+                self.udp.expire(now);
+                last_tick = now;
+            }
+            for packet in out {
+                self.send_frame(ETH_P_IP, &packet);
+            }
+        }
+    }
+
+    /// Receive the frames of the guest, return false if the device is closed.
+    fn handle_guest(&mut self, out: &mut Vec<Vec<u8>>) -> bool {
+        let mut buf = vec![0_u8; FRAME_BUF_LEN];
+        let hdr_len = mem::size_of::<VirtioNetHdr>();
+        loop {
+            match self.guest.read(&mut buf) {
+                Ok(0) => return false,
+                Ok(len) if len > hdr_len => {
+                    let frame = buf[hdr_len..len].to_vec();
+                    self.handle_frame(&frame, out);
+                }
+                Ok(_) => (),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(e) => {
+                    error!("User network: failed to receive frame: {}", e);
+                    return false;
+                }
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, frame: &[u8], out: &mut Vec<Vec<u8>>) -> Option<()> {
+        let eth = EthHdr::parse(frame)?;
+        if eth.dst != self.host_mac && eth.dst != BROADCAST_MAC {
+            return None;
+        }
+        self.guest_mac = Some(eth.src);
+        let payload = &frame[ETH_HDR_LEN..];
+
+        match eth.ether_type {
+            ETH_P_ARP => {
+                let arp = ArpPacket::parse(payload)?;
+                if arp.op == ARP_OP_REQUEST
+                    && (arp.target_ip == self.cfg.host || arp.target_ip == self.cfg.dns)
+                {
+                    if self.cfg.contains(arp.sender_ip) {
+                        self.guest_ip = arp.sender_ip;
+                    }
+                    self.send_frame(ETH_P_ARP, &arp.reply(self.host_mac));
+                }
+            }
+            ETH_P_IP => self.handle_ipv4(&Ipv4Packet::parse(payload)?, out)?,
+            _ => (),
+        }
+        Some(())
+    }
+
+    fn handle_ipv4(&mut self, packet: &Ipv4Packet, out: &mut Vec<Vec<u8>>) -> Option<()> {
+        if self.cfg.contains(packet.src) {
+            self.guest_ip = packet.src;
+        }
+
+        match packet.protocol {
+            // Only the gateway and the DNS server answer ping, as sending ICMP
+            // to others needs privilege on the host.
+            IPPROTO_ICMP if packet.dst == self.cfg.host || packet.dst == self.cfg.dns => {
+                out.push(build_icmp_echo_reply(packet)?);
+            }
+            IPPROTO_UDP => {
+                let datagram = UdpDatagram::parse(packet.payload)?;
+                if datagram.dst_port == DHCP_SERVER_PORT {
+                    let reply = handle_dhcp(&self.cfg, datagram.payload)?;
+                    out.push(build_udp(
+                        SocketAddrV4::new(self.cfg.host, DHCP_SERVER_PORT),
+                        SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT),
+                        &reply,
+                    ));
+                    return Some(());
+                }
+                let key = (
+                    SocketAddrV4::new(packet.src, datagram.src_port),
+                    SocketAddrV4::new(packet.dst, datagram.dst_port),
+                );
+                let peer = self.translate(key.1)?;
+                self.udp.send(key, peer, datagram.payload);
+            }
+            IPPROTO_TCP => {
+                let seg = TcpSegment::parse(packet.payload)?;
+                let key = (
+                    SocketAddrV4::new(packet.src, seg.src_port),
+                    SocketAddrV4::new(packet.dst, seg.dst_port),
+                );
+                self.tcp
+                    .handle_segment(key, self.translate(key.1), &seg, out);
+            }
+            _ => (),
+        }
+        Some(())
+    }
+
+    /// Translate the destination `dst` of the guest to the address of the host.
+    fn translate(&self, dst: SocketAddrV4) -> Option<SocketAddrV4> {
+        let addr = *dst.ip();
+        if addr == self.cfg.dns {
+            if dst.port() != DNS_PORT {
+                return None;
+            }
+            return self
+                .dns_server
+                .map(|server| SocketAddrV4::new(server, DNS_PORT));
+        }
+        if addr == self.cfg.host {
+            return Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, dst.port()));
+        }
+
+        let mask = u32::from(self.cfg.netmask());
+        if u32::from(addr) & mask == u32::from(self.cfg.net)
+            || addr.is_broadcast()
+            || addr.is_multicast()
+            || addr.is_unspecified()
+            || addr.is_loopback()
+        {
+            return None;
+        }
+        Some(dst)
+    }
+
+    /// Send the `payload` to the guest in an Ethernet frame with the vnet header.
+    fn send_frame(&mut self, ether_type: u16, payload: &[u8]) {
+        let hdr_len = mem::size_of::<VirtioNetHdr>();
+        let mut frame = Vec::with_capacity(hdr_len + ETH_HDR_LEN + payload.len());
+        frame.resize(hdr_len, 0);
+        EthHdr {
+            dst: self.guest_mac.unwrap_or(BROADCAST_MAC),
+            src: self.host_mac,
+            ether_type,
+        }
+        .write(&mut frame);
+        frame.extend_from_slice(payload);
+
+        // The frame is dropped if the device does not receive in time, just
+        // like a tap device.
+        if let Err(e) = self.guest.write(&frame) {
+            if e.kind() != ErrorKind::WouldBlock {
+                warn!("User network: failed to send frame: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usernet::packet::{TCP_ACK, TCP_SYN};
+    use std::net::TcpListener;
+
+    const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn send(device: &mut File, ether_type: u16, dst: [u8; 6], payload: &[u8]) {
+        let mut frame = vec![0_u8; mem::size_of::<VirtioNetHdr>()];
+        EthHdr {
+            dst,
+            src: GUEST_MAC,
+            ether_type,
+        }
+        .write(&mut frame);
+        frame.extend_from_slice(payload);
+        device.write_all(&frame).unwrap();
+    }
+
+    /// Receive a frame of the stack, and return its Ethernet header and payload.
+    fn recv(device: &mut File) -> (EthHdr, Vec<u8>) {
+        let mut pollfd = libc::pollfd {
+            fd: device.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: The pollfd is valid.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 5000) };
+        assert_eq!(ret, 1);
+
+        let mut buf = vec![0_u8; FRAME_BUF_LEN];
+        let len = device.read(&mut buf).unwrap();
+        let frame = &buf[mem::size_of::<VirtioNetHdr>()..len];
+        (EthHdr::parse(frame).unwrap(), frame[ETH_HDR_LEN..].to_vec())
+    }
+
+    #[test]
+    fn test_user_net_arp_and_tcp() {
+        let cfg = UserNetConfig::default();
+        let mut device = create_user_net("net0", &cfg).unwrap();
+        let guest_ip = cfg.dhcp_start;
+
+        // ARP request of the gateway.
+        let mut arp = vec![0, 1, 0x08, 0x00, 6, 4, 0, 1];
+        arp.extend_from_slice(&GUEST_MAC);
+        arp.extend_from_slice(&guest_ip.octets());
+        arp.extend_from_slice(&[0; 6]);
+        arp.extend_from_slice(&cfg.host.octets());
+        send(&mut device, ETH_P_ARP, BROADCAST_MAC, &arp);
+        let (eth, payload) = recv(&mut device);
+        assert_eq!(eth.ether_type, ETH_P_ARP);
+        assert_eq!(eth.dst, GUEST_MAC);
+        let reply = ArpPacket::parse(&payload).unwrap();
+        assert_eq!(reply.sender_ip, cfg.host);
+        assert_eq!(reply.target_ip, guest_ip);
+        let host_mac = reply.sender_mac;
+
+        // Connect to the listener on the loopback of the host by the gateway.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let guest = SocketAddrV4::new(guest_ip, 40000);
+        let gateway = SocketAddrV4::new(cfg.host, port);
+        let mut seg = TcpSegment {
+            src_port: guest.port(),
+            dst_port: gateway.port(),
+            seq: 1000,
+            ack: 0,
+            flags: TCP_SYN,
+            window: 65535,
+            mss: Some(1460),
+            payload: &[],
+        };
+        send(
+            &mut device,
+            ETH_P_IP,
+            host_mac,
+            &seg.build(guest_ip, cfg.host),
+        );
+        let (mut host, _) = listener.accept().unwrap();
+
+        let (_, payload) = recv(&mut device);
+        let packet = Ipv4Packet::parse(&payload).unwrap();
+        assert_eq!(packet.src, cfg.host);
+        assert_eq!(packet.dst, guest_ip);
+        let syn_ack = TcpSegment::parse(packet.payload).unwrap();
+        assert_eq!(syn_ack.flags, TCP_SYN | TCP_ACK);
+        assert_eq!(syn_ack.ack, 1001);
+
+        // Send data to the host.
+        seg.seq = 1001;
+        seg.ack = syn_ack.seq.wrapping_add(1);
+        seg.flags = TCP_ACK;
+        seg.mss = None;
+        seg.payload = b"hello";
+        send(
+            &mut device,
+            ETH_P_IP,
+            host_mac,
+            &seg.build(guest_ip, cfg.host),
+        );
+        let mut buf = [0_u8; 5];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        let (_, payload) = recv(&mut device);
+        let ack = TcpSegment::parse(Ipv4Packet::parse(&payload).unwrap().payload).unwrap();
+        assert_eq!(ack.ack, 1006);
+
+        // Receive data from the host.
+        host.write_all(b"world").unwrap();
+        let (_, payload) = recv(&mut device);
+        let data = TcpSegment::parse(Ipv4Packet::parse(&payload).unwrap().payload).unwrap();
+        assert_eq!(data.seq, syn_ack.seq.wrapping_add(1));
+        assert_eq!(data.payload, b"world");
+    }
+}
@@ -0,0 +1,455 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Parsing and building of the Ethernet, ARP, IPv4, ICMP, UDP and TCP headers.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub const ETH_HDR_LEN: usize = 14;
+pub const ETH_P_IP: u16 = 0x0800;
+pub const ETH_P_ARP: u16 = 0x0806;
+pub const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+pub const ARP_PKT_LEN: usize = 28;
+const ARP_HTYPE_ETH: u16 = 1;
+pub const ARP_OP_REQUEST: u16 = 1;
+const ARP_OP_REPLY: u16 = 2;
+
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+const IPV4_HDR_LEN: usize = 20;
+const IPV4_DEFAULT_TTL: u8 = 64;
+const IPV4_FLAG_DF: u16 = 0x4000;
+const IPV4_FLAG_MF: u16 = 0x2000;
+const IPV4_FRAG_OFFSET_MASK: u16 = 0x1fff;
+/// MTU of the virtual network.
+pub const MTU: usize = 1500;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+
+pub const UDP_HDR_LEN: usize = 8;
+/// Max payload of a UDP datagram which is not fragmented.
+pub const UDP_MAX_PAYLOAD: usize = MTU - IPV4_HDR_LEN - UDP_HDR_LEN;
+
+const TCP_HDR_LEN: usize = 20;
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+/// Max segment size of TCP in the virtual network.
+pub const TCP_MSS: usize = MTU - IPV4_HDR_LEN - TCP_HDR_LEN;
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn read_ipv4(buf: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::new(
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    )
+}
+
+/// Add `data` to the one's complement sum `sum`.
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+fn checksum_fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Internet checksum of `data`, refer to RFC 1071.
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_fold(checksum_add(0, data))
+}
+
+/// Checksum of a TCP or UDP `segment` with the IPv4 pseudo header.
+fn l4_checksum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, segment: &[u8]) -> u16 {
+    let mut sum = checksum_add(0, &src.octets());
+    sum = checksum_add(sum, &dst.octets());
+    sum += protocol as u32 + segment.len() as u32;
+    checksum_fold(checksum_add(sum, segment))
+}
+
+/// Header of an Ethernet frame.
+pub struct EthHdr {
+    pub dst: [u8; 6],
+    pub src: [u8; 6],
+    pub ether_type: u16,
+}
+
+impl EthHdr {
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        if frame.len() < ETH_HDR_LEN {
+            return None;
+        }
+        let mut dst = [0_u8; 6];
+        let mut src = [0_u8; 6];
+        dst.copy_from_slice(&frame[0..6]);
+        src.copy_from_slice(&frame[6..12]);
+        Some(EthHdr {
+            dst,
+            src,
+            ether_type: read_u16(frame, 12),
+        })
+    }
+
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.dst);
+        buf.extend_from_slice(&self.src);
+        buf.extend_from_slice(&self.ether_type.to_be_bytes());
+    }
+}
+
+/// ARP packet of IPv4 over Ethernet.
+pub struct ArpPacket {
+    pub op: u16,
+    pub sender_mac: [u8; 6],
+    pub sender_ip: Ipv4Addr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < ARP_PKT_LEN
+            || read_u16(buf, 0) != ARP_HTYPE_ETH
+            || read_u16(buf, 2) != ETH_P_IP
+            || buf[4] != 6
+            || buf[5] != 4
+        {
+            return None;
+        }
+        let mut sender_mac = [0_u8; 6];
+        sender_mac.copy_from_slice(&buf[8..14]);
+        Some(ArpPacket {
+            op: read_u16(buf, 6),
+            sender_mac,
+            sender_ip: read_ipv4(buf, 14),
+            target_ip: read_ipv4(buf, 24),
+        })
+    }
+
+    /// Build the reply of this request, which tells `mac` owns the target address.
+    pub fn reply(&self, mac: [u8; 6]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(ARP_PKT_LEN);
+        buf.extend_from_slice(&ARP_HTYPE_ETH.to_be_bytes());
+        buf.extend_from_slice(&ETH_P_IP.to_be_bytes());
+        buf.extend_from_slice(&[6, 4]);
+        buf.extend_from_slice(&ARP_OP_REPLY.to_be_bytes());
+        buf.extend_from_slice(&mac);
+        buf.extend_from_slice(&self.target_ip.octets());
+        buf.extend_from_slice(&self.sender_mac);
+        buf.extend_from_slice(&self.sender_ip.octets());
+        buf
+    }
+}
+
+/// IPv4 packet which is not fragmented.
+pub struct Ipv4Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < IPV4_HDR_LEN || buf[0] >> 4 != 4 {
+            return None;
+        }
+        let hdr_len = ((buf[0] & 0xf) as usize) * 4;
+        let total_len = read_u16(buf, 2) as usize;
+        if hdr_len < IPV4_HDR_LEN || total_len < hdr_len || total_len > buf.len() {
+            return None;
+        }
+        // Fragments are dropped, which are never sent with the MTU of the network.
+        let frag = read_u16(buf, 6);
+        if frag & IPV4_FLAG_MF != 0 || frag & IPV4_FRAG_OFFSET_MASK != 0 {
+            return None;
+        }
+        Some(Ipv4Packet {
+            src: read_ipv4(buf, 12),
+            dst: read_ipv4(buf, 16),
+            protocol: buf[9],
+            payload: &buf[hdr_len..total_len],
+        })
+    }
+}
+
+/// Build an IPv4 packet with `payload`.
+pub fn build_ipv4(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let total_len = IPV4_HDR_LEN + payload.len();
+    let mut buf = Vec::with_capacity(total_len);
+    buf.extend_from_slice(&[0x45, 0]);
+    buf.extend_from_slice(&(total_len as u16).to_be_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    buf.extend_from_slice(&IPV4_FLAG_DF.to_be_bytes());
+    buf.extend_from_slice(&[IPV4_DEFAULT_TTL, protocol, 0, 0]);
+    buf.extend_from_slice(&src.octets());
+    buf.extend_from_slice(&dst.octets());
+    let csum = checksum(&buf);
+    buf[10..12].copy_from_slice(&csum.to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Build the echo reply of the ICMP echo request `request`.
+pub fn build_icmp_echo_reply(request: &Ipv4Packet) -> Option<Vec<u8>> {
+    let icmp = request.payload;
+    if icmp.len() < 8 || icmp[0] != ICMP_ECHO_REQUEST {
+        return None;
+    }
+    let mut reply = icmp.to_vec();
+    reply[0] = ICMP_ECHO_REPLY;
+    reply[2..4].copy_from_slice(&[0, 0]);
+    let csum = checksum(&reply);
+    reply[2..4].copy_from_slice(&csum.to_be_bytes());
+    Some(build_ipv4(request.dst, request.src, IPPROTO_ICMP, &reply))
+}
+
+/// UDP datagram.
+pub struct UdpDatagram<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> UdpDatagram<'a> {
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < UDP_HDR_LEN {
+            return None;
+        }
+        let len = read_u16(buf, 4) as usize;
+        if len < UDP_HDR_LEN || len > buf.len() {
+            return None;
+        }
+        Some(UdpDatagram {
+            src_port: read_u16(buf, 0),
+            dst_port: read_u16(buf, 2),
+            payload: &buf[UDP_HDR_LEN..len],
+        })
+    }
+}
+
+/// Build an IPv4 packet of the UDP datagram with `payload`.
+pub fn build_udp(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let len = UDP_HDR_LEN + payload.len();
+    let mut buf = Vec::with_capacity(len);
+    buf.extend_from_slice(&src.port().to_be_bytes());
+    buf.extend_from_slice(&dst.port().to_be_bytes());
+    buf.extend_from_slice(&(len as u16).to_be_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    buf.extend_from_slice(payload);
+    let csum = match l4_checksum(*src.ip(), *dst.ip(), IPPROTO_UDP, &buf) {
+        // Zero means no checksum for UDP, it is sent as all ones.
+        0 => 0xffff,
+        csum => csum,
+    };
+    buf[6..8].copy_from_slice(&csum.to_be_bytes());
+    build_ipv4(*src.ip(), *dst.ip(), IPPROTO_UDP, &buf)
+}
+
+/// TCP segment.
+pub struct TcpSegment<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    /// Max segment size in the options of SYN.
+    pub mss: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> TcpSegment<'a> {
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < TCP_HDR_LEN {
+            return None;
+        }
+        let hdr_len = ((buf[12] >> 4) as usize) * 4;
+        if hdr_len < TCP_HDR_LEN || hdr_len > buf.len() {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &buf[TCP_HDR_LEN..hdr_len];
+        while let Some(&kind) = options.first() {
+            match kind {
+                TCP_OPT_END => break,
+                TCP_OPT_NOP => options = &options[1..],
+                _ => {
+                    let len = *options.get(1)? as usize;
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+                    if kind == TCP_OPT_MSS && len == 4 {
+                        mss = Some(read_u16(options, 2));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+
+        Some(TcpSegment {
+            src_port: read_u16(buf, 0),
+            dst_port: read_u16(buf, 2),
+            seq: read_u32(buf, 4),
+            ack: read_u32(buf, 8),
+            flags: buf[13],
+            window: read_u16(buf, 14),
+            mss,
+            payload: &buf[hdr_len..],
+        })
+    }
+
+    /// Build an IPv4 packet of the segment, the MSS option is added if set.
+    pub fn build(&self, src: Ipv4Addr, dst: Ipv4Addr) -> Vec<u8> {
+        let hdr_len = if self.mss.is_some() {
+            TCP_HDR_LEN + 4
+        } else {
+            TCP_HDR_LEN
+        };
+        let mut buf = Vec::with_capacity(hdr_len + self.payload.len());
+        buf.extend_from_slice(&self.src_port.to_be_bytes());
+        buf.extend_from_slice(&self.dst_port.to_be_bytes());
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        buf.extend_from_slice(&self.ack.to_be_bytes());
+        buf.extend_from_slice(&[((hdr_len / 4) as u8) << 4, self.flags]);
+        buf.extend_from_slice(&self.window.to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        if let Some(mss) = self.mss {
+            buf.extend_from_slice(&[TCP_OPT_MSS, 4]);
+            buf.extend_from_slice(&mss.to_be_bytes());
+        }
+        buf.extend_from_slice(self.payload);
+        let csum = l4_checksum(src, dst, IPPROTO_TCP, &buf);
+        buf[16..18].copy_from_slice(&csum.to_be_bytes());
+        build_ipv4(src, dst, IPPROTO_TCP, &buf)
+    }
+
+    /// Length in the sequence space, SYN and FIN take one each.
+    pub fn seq_len(&self) -> u32 {
+        let mut len = self.payload.len() as u32;
+        if self.flags & TCP_SYN != 0 {
+            len += 1;
+        }
+        if self.flags & TCP_FIN != 0 {
+            len += 1;
+        }
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        // The example in RFC 1071.
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&data), !0xddf2);
+        assert_eq!(checksum(&[0x01]), !0x0100);
+
+        let packet = build_ipv4(
+            Ipv4Addr::new(10, 0, 2, 2),
+            Ipv4Addr::new(10, 0, 2, 15),
+            IPPROTO_UDP,
+            &[],
+        );
+        // The checksum of a header with its checksum is zero.
+        assert_eq!(checksum(&packet), 0);
+    }
+
+    #[test]
+    fn test_tcp_segment() {
+        let src = Ipv4Addr::new(10, 0, 2, 15);
+        let dst = Ipv4Addr::new(10, 0, 2, 2);
+        let packet = TcpSegment {
+            src_port: 40000,
+            dst_port: 22,
+            seq: 100,
+            ack: 200,
+            flags: TCP_SYN | TCP_ACK,
+            window: 8192,
+            mss: Some(1460),
+            payload: b"hi",
+        }
+        .build(src, dst);
+
+        let ip = Ipv4Packet::parse(&packet).unwrap();
+        assert_eq!(ip.src, src);
+        assert_eq!(ip.dst, dst);
+        assert_eq!(ip.protocol, IPPROTO_TCP);
+        assert_eq!(l4_checksum(ip.src, ip.dst, IPPROTO_TCP, ip.payload), 0);
+
+        let seg = TcpSegment::parse(ip.payload).unwrap();
+        assert_eq!(seg.src_port, 40000);
+        assert_eq!(seg.dst_port, 22);
+        assert_eq!(seg.seq, 100);
+        assert_eq!(seg.ack, 200);
+        assert_eq!(seg.flags, TCP_SYN | TCP_ACK);
+        assert_eq!(seg.window, 8192);
+        assert_eq!(seg.mss, Some(1460));
+        assert_eq!(seg.payload, b"hi");
+        assert_eq!(seg.seq_len(), 3);
+    }
+
+    #[test]
+    fn test_udp_datagram() {
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 3), 53);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 5353);
+        let packet = build_udp(src, dst, b"query");
+
+        let ip = Ipv4Packet::parse(&packet).unwrap();
+        assert_eq!(l4_checksum(ip.src, ip.dst, IPPROTO_UDP, ip.payload), 0);
+        let udp = UdpDatagram::parse(ip.payload).unwrap();
+        assert_eq!(udp.src_port, 53);
+        assert_eq!(udp.dst_port, 5353);
+        assert_eq!(udp.payload, b"query");
+
+        // Fragments are not supported.
+        let mut fragment = packet.clone();
+        fragment[6] |= (IPV4_FLAG_MF >> 8) as u8;
+        assert!(Ipv4Packet::parse(&fragment).is_none());
+    }
+}
@@ -0,0 +1,1046 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! NAT of the TCP connections of the guest. The stack terminates the TCP
+//! connection of the guest, and relays its data through a TCP socket of the
+//! host. The connections to the guest are accepted by the listeners of the
+//! port forwarding rules.
+//!
+//! The link to the guest never reorders the segments, so the segments out of
+//! order are dropped and the lost ones are retransmitted by go-back-N.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Read, Result as IoResult, Write};
+use std::mem;
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use super::packet::{TcpSegment, TCP_ACK, TCP_FIN, TCP_MSS, TCP_PSH, TCP_RST, TCP_SYN};
+use super::FlowKey;
+
+/// Max bytes buffered in each direction of a connection, it is also the max
+/// window advertised to the guest as the window scale is not used.
+const TCP_BUF_SIZE: usize = 65535;
+/// Default MSS if the guest does not set it, refer to RFC 879.
+const TCP_DEFAULT_MSS: u16 = 536;
+const TCP_RTO_INIT: Duration = Duration::from_secs(1);
+const TCP_RTO_MAX: Duration = Duration::from_secs(16);
+/// The connection is reset if the guest does not answer the retransmissions.
+const TCP_MAX_RETRIES: u32 = 8;
+/// Max number of the connections.
+const TCP_MAX_CONNS: usize = 4096;
+/// The first port of the gateway used by the connections forwarded to the guest.
+const TCP_FWD_PORT_START: u16 = 49152;
+
+fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+/// Connect to `addr` without blocking, the result is reported by POLLOUT.
+fn connect_nonblocking(addr: SocketAddrV4) -> IoResult<TcpStream> {
+    // SAFETY: The arguments are valid, and the fd is owned by the stream.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // SAFETY: The fd is just created and not owned by others.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let sin = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: The address is valid and its length is correct.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &sin as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let e = Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(e);
+        }
+    }
+    Ok(stream)
+}
+
+/// Build the RST answering the segment `seg` of the flow `key`, which has no
+/// connection.
+fn reset_segment(key: &FlowKey, seg: &TcpSegment) -> Vec<u8> {
+    let (seq, ack, flags) = if seg.flags & TCP_ACK != 0 {
+        (seg.ack, 0, TCP_RST)
+    } else {
+        (0, seg.seq.wrapping_add(seg.seq_len()), TCP_RST | TCP_ACK)
+    };
+    TcpSegment {
+        src_port: key.1.port(),
+        dst_port: key.0.port(),
+        seq,
+        ack,
+        flags,
+        window: 0,
+        mss: None,
+        payload: &[],
+    }
+    .build(*key.1.ip(), *key.0.ip())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TcpState {
+    /// The guest sends SYN, and the host socket is connecting.
+    Connecting,
+    /// SYN-ACK is sent to the guest, waiting for its ACK.
+    SynReceived,
+    /// SYN is sent to the guest for a forwarded connection, waiting for SYN-ACK.
+    SynSent,
+    /// The data can be relayed in both directions.
+    Established,
+}
+
+struct TcpConn {
+    host: TcpStream,
+    state: TcpState,
+    /// The oldest sequence number sent to the guest and not acknowledged,
+    /// which is the sequence number of the first byte of `send_buf`.
+    snd_una: u32,
+    /// Next sequence number sent to the guest.
+    snd_nxt: u32,
+    /// Window advertised by the guest.
+    snd_wnd: u32,
+    /// Data read from the host, and not acknowledged by the guest.
+    send_buf: VecDeque<u8>,
+    /// The host closes the writing side of the connection.
+    host_eof: bool,
+    /// FIN is sent to the guest.
+    fin_sent: bool,
+    /// Next sequence number expected from the guest.
+    rcv_nxt: u32,
+    /// Data sent by the guest, and not written to the host yet.
+    recv_buf: VecDeque<u8>,
+    /// FIN is received from the guest.
+    guest_fin: bool,
+    /// The writing side of the host socket is shutdown.
+    host_shutdown: bool,
+    /// MSS of the guest.
+    mss: u16,
+    rto: Duration,
+    retries: u32,
+    /// Time to retransmit the segments not acknowledged, or to probe the zero
+    /// window of the guest.
+    retransmit_at: Option<Instant>,
+}
+
+impl TcpConn {
+    fn new(host: TcpStream, state: TcpState, iss: u32) -> Self {
+        TcpConn {
+            host,
+            state,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: 0,
+            send_buf: VecDeque::new(),
+            host_eof: false,
+            fin_sent: false,
+            rcv_nxt: 0,
+            recv_buf: VecDeque::new(),
+            guest_fin: false,
+            host_shutdown: false,
+            mss: TCP_DEFAULT_MSS,
+            rto: TCP_RTO_INIT,
+            retries: 0,
+            retransmit_at: None,
+        }
+    }
+
+    /// Window advertised to the guest.
+    fn recv_window(&self) -> u16 {
+        (TCP_BUF_SIZE - self.recv_buf.len()) as u16
+    }
+
+    fn send(&self, key: &FlowKey, seq: u32, flags: u8, payload: &[u8], out: &mut Vec<Vec<u8>>) {
+        let mss = if flags & TCP_SYN != 0 {
+            Some(TCP_MSS as u16)
+        } else {
+            None
+        };
+        let seg = TcpSegment {
+            src_port: key.1.port(),
+            dst_port: key.0.port(),
+            seq,
+            ack: self.rcv_nxt,
+            flags,
+            window: self.recv_window(),
+            mss,
+            payload,
+        };
+        out.push(seg.build(*key.1.ip(), *key.0.ip()));
+    }
+
+    fn send_ack(&self, key: &FlowKey, out: &mut Vec<Vec<u8>>) {
+        self.send(key, self.snd_nxt, TCP_ACK, &[], out);
+    }
+
+    fn send_reset(&self, key: &FlowKey, out: &mut Vec<Vec<u8>>) {
+        self.send(key, self.snd_nxt, TCP_RST | TCP_ACK, &[], out);
+    }
+
+    fn arm_retransmit(&mut self) {
+        if self.retransmit_at.is_none() {
+            self.retransmit_at = Some(Instant::now() + self.rto);
+        }
+    }
+
+    /// Send the data of the host in the window of the guest, and FIN after
+    /// all the data if the host is closed.
+    fn output(&mut self, key: &FlowKey, out: &mut Vec<Vec<u8>>) {
+        if self.state != TcpState::Established {
+            return;
+        }
+        while !self.fin_sent {
+            let sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let unsent = self.send_buf.len() - sent;
+            if unsent == 0 {
+                if self.host_eof {
+                    self.send(key, self.snd_nxt, TCP_FIN | TCP_ACK, &[], out);
+                    self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                    self.fin_sent = true;
+                    self.arm_retransmit();
+                }
+                break;
+            }
+            let window = self.snd_wnd as usize;
+            if sent >= window {
+                // Probe the window later if it is closed.
+                self.arm_retransmit();
+                break;
+            }
+
+            let len = cmp::min(cmp::min(unsent, window - sent), self.mss as usize);
+            let payload: Vec<u8> = self.send_buf.range(sent..sent + len).copied().collect();
+            let flags = if len == unsent {
+                TCP_ACK | TCP_PSH
+            } else {
+                TCP_ACK
+            };
+            self.send(key, self.snd_nxt, flags, &payload, out);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+            self.arm_retransmit();
+        }
+    }
+
+    /// Read the data of the host if there is room in the send buffer.
+    fn read_host(&mut self) -> IoResult<()> {
+        let mut buf = [0_u8; TCP_BUF_SIZE];
+        loop {
+            let room = TCP_BUF_SIZE - self.send_buf.len();
+            if self.host_eof || room == 0 {
+                return Ok(());
+            }
+            match self.host.read(&mut buf[..room]) {
+                Ok(0) => self.host_eof = true,
+                Ok(len) => self.send_buf.extend(&buf[..len]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Write the data of the guest to the host, and shutdown the writing side
+    /// of the host after the guest sends FIN.
+    fn flush_host(&mut self) -> IoResult<()> {
+        while !self.recv_buf.is_empty() {
+            let (data, _) = self.recv_buf.as_slices();
+            match self.host.write(data) {
+                Ok(len) => {
+                    self.recv_buf.drain(..len);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        if self.guest_fin && !self.host_shutdown {
+            self.host.shutdown(Shutdown::Write)?;
+            self.host_shutdown = true;
+        }
+        Ok(())
+    }
+
+    /// Both directions are closed and all the data is acknowledged.
+    fn is_closed(&self) -> bool {
+        self.fin_sent && self.snd_una == self.snd_nxt && self.host_shutdown
+    }
+
+    /// Handle the segment `seg` of the guest in the established state, return
+    /// false if the connection is closed.
+    fn handle_segment(
+        &mut self,
+        key: &FlowKey,
+        seg: &TcpSegment,
+        out: &mut Vec<Vec<u8>>,
+    ) -> IoResult<bool> {
+        if seg.flags & TCP_ACK != 0
+            && seq_le(self.snd_una, seg.ack)
+            && seq_le(seg.ack, self.snd_nxt)
+        {
+            let acked = seg.ack.wrapping_sub(self.snd_una) as usize;
+            let len = cmp::min(acked, self.send_buf.len());
+            self.send_buf.drain(..len);
+            self.snd_una = seg.ack;
+            self.snd_wnd = seg.window as u32;
+            // The guest is alive.
+            self.retries = 0;
+            self.rto = TCP_RTO_INIT;
+            self.retransmit_at = None;
+            if self.snd_una != self.snd_nxt {
+                self.arm_retransmit();
+            }
+        }
+
+        if !seg.payload.is_empty() || seg.flags & TCP_FIN != 0 {
+            // Take the new data in the segment, the segments out of order are
+            // dropped and acknowledged with the expected sequence number.
+            let offset = self.rcv_nxt.wrapping_sub(seg.seq) as usize;
+            if !self.guest_fin && seq_le(seg.seq, self.rcv_nxt) && offset <= seg.payload.len() {
+                let data = &seg.payload[offset..];
+                let len = cmp::min(data.len(), TCP_BUF_SIZE - self.recv_buf.len());
+                self.recv_buf.extend(&data[..len]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+                if len == data.len() && seg.flags & TCP_FIN != 0 {
+                    self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                    self.guest_fin = true;
+                }
+            }
+            self.send_ack(key, out);
+        }
+
+        self.flush_host()?;
+        self.read_host()?;
+        self.output(key, out);
+        Ok(!self.is_closed())
+    }
+
+    /// Handle the events of the host socket, return false if the connection
+    /// is closed.
+    fn handle_host_event(&mut self, key: &FlowKey, out: &mut Vec<Vec<u8>>) -> IoResult<bool> {
+        if self.state == TcpState::Connecting {
+            if let Some(e) = self.host.take_error()? {
+                return Err(e);
+            }
+            self.state = TcpState::SynReceived;
+            self.send(key, self.snd_nxt, TCP_SYN | TCP_ACK, &[], out);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.arm_retransmit();
+            return Ok(true);
+        }
+
+        let window = self.recv_window();
+        self.flush_host()?;
+        // Tell the guest the window is open again.
+        if (window as usize) < TCP_MSS && self.recv_window() != window {
+            self.send_ack(key, out);
+        }
+        self.read_host()?;
+        self.output(key, out);
+        Ok(!self.is_closed())
+    }
+
+    /// Poll events wanted on the host socket.
+    fn poll_events(&self) -> i16 {
+        match self.state {
+            TcpState::Connecting => libc::POLLOUT,
+            TcpState::Established => {
+                let mut events = 0;
+                if !self.host_eof && self.send_buf.len() < TCP_BUF_SIZE {
+                    events |= libc::POLLIN;
+                }
+                if !self.recv_buf.is_empty() {
+                    events |= libc::POLLOUT;
+                }
+                events
+            }
+            _ => 0,
+        }
+    }
+
+    /// Retransmit the segments not acknowledged, return false if the guest does
+    /// not answer.
+    fn handle_timer(&mut self, key: &FlowKey, now: Instant, out: &mut Vec<Vec<u8>>) -> bool {
+        match self.retransmit_at {
+            Some(at) if now >= at => (),
+            _ => return true,
+        }
+        self.retries += 1;
+        if self.retries > TCP_MAX_RETRIES {
+            self.send_reset(key, out);
+            return false;
+        }
+        self.rto = cmp::min(self.rto * 2, TCP_RTO_MAX);
+        self.retransmit_at = None;
+
+        match self.state {
+            TcpState::SynReceived => {
+                self.send(key, self.snd_una, TCP_SYN | TCP_ACK, &[], out);
+                self.arm_retransmit();
+            }
+            TcpState::SynSent => {
+                self.send(key, self.snd_una, TCP_SYN, &[], out);
+                self.arm_retransmit();
+            }
+            TcpState::Established => {
+                if self.snd_una == self.snd_nxt && self.snd_wnd == 0 {
+                    // Probe the zero window with one byte.
+                    self.snd_wnd = 1;
+                }
+                self.snd_nxt = self.snd_una;
+                self.fin_sent = false;
+                self.output(key, out);
+            }
+            TcpState::Connecting => (),
+        }
+        true
+    }
+}
+
+/// TCP connections keyed by the address of the guest and the remote seen by it.
+pub struct TcpNat {
+    conns: HashMap<FlowKey, TcpConn>,
+    /// Listeners of the port forwarding rules, and the guest address and port
+    /// to forward to. The address allocated by DHCP is used if it is not set.
+    listeners: Vec<(TcpListener, Option<Ipv4Addr>, u16)>,
+    next_port: u16,
+    isn: u32,
+}
+
+impl TcpNat {
+    pub fn new(listeners: Vec<(TcpListener, Option<Ipv4Addr>, u16)>) -> Self {
+        let isn = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos());
+        TcpNat {
+            conns: HashMap::new(),
+            listeners,
+            next_port: TCP_FWD_PORT_START,
+            isn,
+        }
+    }
+
+    fn next_isn(&mut self) -> u32 {
+        self.isn = self.isn.wrapping_add(64000);
+        self.isn
+    }
+
+    fn reset(&mut self, key: &FlowKey, out: &mut Vec<Vec<u8>>) {
+        if let Some(conn) = self.conns.remove(key) {
+            conn.send_reset(key, out);
+        }
+    }
+
+    /// Handle the segment `seg` of the flow `key` sent by the guest. The
+    /// connection is made to `host_dst` for a SYN, or reset if it is None.
+    pub fn handle_segment(
+        &mut self,
+        key: FlowKey,
+        host_dst: Option<SocketAddrV4>,
+        seg: &TcpSegment,
+        out: &mut Vec<Vec<u8>>,
+    ) {
+        let conn = match self.conns.get_mut(&key) {
+            Some(conn) => conn,
+            None => {
+                self.handle_new_segment(key, host_dst, seg, out);
+                return;
+            }
+        };
+        if seg.flags & TCP_RST != 0 {
+            self.conns.remove(&key);
+            return;
+        }
+
+        match conn.state {
+            TcpState::Connecting => return,
+            TcpState::SynSent => {
+                if seg.flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK && seg.ack == conn.snd_nxt {
+                    conn.state = TcpState::Established;
+                    conn.rcv_nxt = seg.seq.wrapping_add(1);
+                    conn.snd_una = seg.ack;
+                    conn.snd_wnd = seg.window as u32;
+                    conn.mss = cmp::min(seg.mss.unwrap_or(TCP_DEFAULT_MSS), TCP_MSS as u16);
+                    conn.retries = 0;
+                    conn.retransmit_at = None;
+                    conn.send_ack(&key, out);
+                } else if seg.flags & TCP_ACK != 0 {
+                    out.push(reset_segment(&key, seg));
+                }
+                return;
+            }
+            TcpState::SynReceived => {
+                if seg.flags & TCP_SYN != 0 {
+                    // The SYN-ACK may be lost.
+                    conn.send(&key, conn.snd_una, TCP_SYN | TCP_ACK, &[], out);
+                    return;
+                }
+                if seg.flags & TCP_ACK == 0 || seg.ack != conn.snd_nxt {
+                    return;
+                }
+                conn.state = TcpState::Established;
+                conn.snd_una = seg.ack;
+            }
+            TcpState::Established => (),
+        }
+
+        match conn.handle_segment(&key, seg, out) {
+            Ok(true) => (),
+            Ok(false) => {
+                self.conns.remove(&key);
+            }
+            Err(e) => {
+                warn!("User network: TCP connection {} error: {}", key.1, e);
+                self.reset(&key, out);
+            }
+        }
+    }
+
+    fn handle_new_segment(
+        &mut self,
+        key: FlowKey,
+        host_dst: Option<SocketAddrV4>,
+        seg: &TcpSegment,
+        out: &mut Vec<Vec<u8>>,
+    ) {
+        if seg.flags & TCP_RST != 0 {
+            return;
+        }
+        if seg.flags & (TCP_SYN | TCP_ACK) != TCP_SYN {
+            out.push(reset_segment(&key, seg));
+            return;
+        }
+        let host_dst = match host_dst {
+            Some(addr) if self.conns.len() < TCP_MAX_CONNS => addr,
+            _ => {
+                out.push(reset_segment(&key, seg));
+                return;
+            }
+        };
+        let host = match connect_nonblocking(host_dst) {
+            Ok(host) => host,
+            Err(e) => {
+                warn!("User network: failed to connect to {}: {}", host_dst, e);
+                out.push(reset_segment(&key, seg));
+                return;
+            }
+        };
+
+        let mut conn = TcpConn::new(host, TcpState::Connecting, self.next_isn());
+        conn.rcv_nxt = seg.seq.wrapping_add(1);
+        conn.snd_wnd = seg.window as u32;
+        conn.mss = cmp::min(seg.mss.unwrap_or(TCP_DEFAULT_MSS), TCP_MSS as u16);
+        self.conns.insert(key, conn);
+    }
+
+    /// Fds of the listeners of the port forwarding rules.
+    pub fn listener_fds(&self) -> impl Iterator<Item = RawFd> + '_ {
+        self.listeners
+            .iter()
+            .map(|(listener, _, _)| listener.as_raw_fd())
+    }
+
+    /// Fds of the host sockets and the events wanted.
+    pub fn pollfds(&self) -> impl Iterator<Item = (FlowKey, RawFd, i16)> + '_ {
+        self.conns.iter().filter_map(|(key, conn)| {
+            let events = conn.poll_events();
+            if events == 0 {
+                None
+            } else {
+                Some((*key, conn.host.as_raw_fd(), events))
+            }
+        })
+    }
+
+    /// Accept the connections of the listener `index`, and connect to the
+    /// guest from the `gateway`.
+    pub fn handle_accept(
+        &mut self,
+        index: usize,
+        guest_ip: Ipv4Addr,
+        gateway: Ipv4Addr,
+        out: &mut Vec<Vec<u8>>,
+    ) {
+        loop {
+            let (listener, fwd_addr, fwd_port) = &self.listeners[index];
+            let guest = SocketAddrV4::new(fwd_addr.unwrap_or(guest_ip), *fwd_port);
+            let host = match listener.accept() {
+                Ok((host, _)) => host,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("User network: failed to accept connection: {}", e);
+                    return;
+                }
+            };
+            if self.conns.len() >= TCP_MAX_CONNS {
+                continue;
+            }
+            if let Err(e) = host.set_nonblocking(true) {
+                warn!("User network: failed to set nonblocking: {}", e);
+                continue;
+            }
+
+            let key = match self.alloc_key(guest, gateway) {
+                Some(key) => key,
+                None => continue,
+            };
+            let mut conn = TcpConn::new(host, TcpState::SynSent, self.next_isn());
+            conn.send(&key, conn.snd_nxt, TCP_SYN, &[], out);
+            conn.snd_nxt = conn.snd_nxt.wrapping_add(1);
+            conn.arm_retransmit();
+            self.conns.insert(key, conn);
+        }
+    }
+
+    /// Allocate a port of the gateway for the connection to `guest`.
+    fn alloc_key(&mut self, guest: SocketAddrV4, gateway: Ipv4Addr) -> Option<FlowKey> {
+        for _ in TCP_FWD_PORT_START..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = port.checked_add(1).unwrap_or(TCP_FWD_PORT_START);
+            let key = (guest, SocketAddrV4::new(gateway, port));
+            if !self.conns.contains_key(&key) {
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    /// Handle the events of the host socket of the connection `key`.
+    pub fn handle_host_event(&mut self, key: &FlowKey, out: &mut Vec<Vec<u8>>) {
+        let conn = match self.conns.get_mut(key) {
+            Some(conn) => conn,
+            None => return,
+        };
+        match conn.handle_host_event(key, out) {
+            Ok(true) => (),
+            Ok(false) => {
+                self.conns.remove(key);
+            }
+            Err(e) => {
+                warn!("User network: TCP connection {} error: {}", key.1, e);
+                self.reset(key, out);
+            }
+        }
+    }
+
+    /// Retransmit the segments of the connections.
+    pub fn handle_timer(&mut self, now: Instant, out: &mut Vec<Vec<u8>>) {
+        self.conns
+            .retain(|key, conn| conn.handle_timer(key, now, out));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usernet::packet::Ipv4Packet;
+
+    const GUEST_ISN: u32 = 1000;
+
+    fn guest_addr() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 40000)
+    }
+
+    fn gateway_addr(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 2), port)
+    }
+
+    /// Segment sent by the guest of the flow `key`.
+    fn guest_segment<'a>(
+        key: &FlowKey,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        payload: &'a [u8],
+    ) -> TcpSegment<'a> {
+        TcpSegment {
+            src_port: key.0.port(),
+            dst_port: key.1.port(),
+            seq,
+            ack,
+            flags,
+            window: 65535,
+            mss: if flags & TCP_SYN != 0 {
+                Some(1460)
+            } else {
+                None
+            },
+            payload,
+        }
+    }
+
+    /// The segment of an IPv4 packet sent to the guest of the flow `key`.
+    fn parse_segment<'a>(key: &FlowKey, packet: &'a [u8]) -> TcpSegment<'a> {
+        let packet = Ipv4Packet::parse(packet).unwrap();
+        assert_eq!(packet.src, *key.1.ip());
+        assert_eq!(packet.dst, *key.0.ip());
+        let seg = TcpSegment::parse(packet.payload).unwrap();
+        assert_eq!(seg.src_port, key.1.port());
+        assert_eq!(seg.dst_port, key.0.port());
+        seg
+    }
+
+    /// Wait for the host socket of the connection `key` to be readable.
+    fn wait_host(nat: &TcpNat, key: &FlowKey) {
+        let mut pollfd = libc::pollfd {
+            fd: nat.conns[key].host.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: The pollfd is valid.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 5000) };
+        assert_eq!(ret, 1);
+    }
+
+    /// Connect the guest to `listener` through `nat`, return the flow, the
+    /// host side of the connection and the next sequence number of the stack.
+    fn establish(nat: &mut TcpNat, listener: &TcpListener) -> (FlowKey, TcpStream, u32) {
+        let port = listener.local_addr().unwrap().port();
+        let key = (guest_addr(), gateway_addr(port));
+        let host_dst = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        let mut out = Vec::new();
+
+        // SYN-ACK is sent once the host socket is connected.
+        let syn = guest_segment(&key, GUEST_ISN, 0, TCP_SYN, &[]);
+        nat.handle_segment(key, Some(host_dst), &syn, &mut out);
+        assert!(out.is_empty());
+        assert_eq!(nat.conns[&key].state, TcpState::Connecting);
+        let (host, _) = listener.accept().unwrap();
+        nat.handle_host_event(&key, &mut out);
+        assert_eq!(out.len(), 1);
+        let syn_ack = parse_segment(&key, &out[0]);
+        assert_eq!(syn_ack.flags, TCP_SYN | TCP_ACK);
+        assert_eq!(syn_ack.ack, GUEST_ISN + 1);
+        assert_eq!(syn_ack.mss, Some(TCP_MSS as u16));
+        let isn = syn_ack.seq;
+        assert_eq!(nat.conns[&key].state, TcpState::SynReceived);
+
+        // The SYN-ACK is sent again if the guest sends SYN again.
+        let mut dup = Vec::new();
+        nat.handle_segment(key, Some(host_dst), &syn, &mut dup);
+        assert_eq!(dup.len(), 1);
+        assert_eq!(parse_segment(&key, &dup[0]).seq, isn);
+
+        let ack = guest_segment(&key, GUEST_ISN + 1, isn.wrapping_add(1), TCP_ACK, &[]);
+        let mut out = Vec::new();
+        nat.handle_segment(key, None, &ack, &mut out);
+        assert!(out.is_empty());
+        assert_eq!(nat.conns[&key].state, TcpState::Established);
+        host.set_nonblocking(false).unwrap();
+        (key, host, isn.wrapping_add(1))
+    }
+
+    #[test]
+    fn test_tcp_data_and_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut nat = TcpNat::new(Vec::new());
+        let (key, mut host, snd_nxt) = establish(&mut nat, &listener);
+        let mut rcv_nxt = GUEST_ISN + 1;
+
+        // The data of the guest is written to the host and acknowledged.
+        let mut out = Vec::new();
+        let seg = guest_segment(&key, rcv_nxt, snd_nxt, TCP_ACK | TCP_PSH, b"hello");
+        nat.handle_segment(key, None, &seg, &mut out);
+        rcv_nxt += 5;
+        assert_eq!(out.len(), 1);
+        let ack = parse_segment(&key, &out[0]);
+        assert_eq!(ack.flags, TCP_ACK);
+        assert_eq!(ack.seq, snd_nxt);
+        assert_eq!(ack.ack, rcv_nxt);
+        let mut buf = [0_u8; 5];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // The data of the host is sent to the guest.
+        host.write_all(b"world").unwrap();
+        wait_host(&nat, &key);
+        let mut out = Vec::new();
+        nat.handle_host_event(&key, &mut out);
+        assert_eq!(out.len(), 1);
+        let data = parse_segment(&key, &out[0]);
+        assert_eq!(data.flags, TCP_ACK | TCP_PSH);
+        assert_eq!(data.seq, snd_nxt);
+        assert_eq!(data.ack, rcv_nxt);
+        assert_eq!(data.payload, b"world");
+        let snd_nxt = snd_nxt.wrapping_add(5);
+        assert!(nat.conns[&key].retransmit_at.is_some());
+        let mut out = Vec::new();
+        let ack = guest_segment(&key, rcv_nxt, snd_nxt, TCP_ACK, &[]);
+        nat.handle_segment(key, None, &ack, &mut out);
+        assert!(out.is_empty());
+        assert!(nat.conns[&key].send_buf.is_empty());
+        assert!(nat.conns[&key].retransmit_at.is_none());
+
+        // FIN of the guest shuts the host socket down for writing.
+        let fin = guest_segment(&key, rcv_nxt, snd_nxt, TCP_FIN | TCP_ACK, &[]);
+        nat.handle_segment(key, None, &fin, &mut out);
+        rcv_nxt += 1;
+        assert_eq!(out.len(), 1);
+        assert_eq!(parse_segment(&key, &out[0]).ack, rcv_nxt);
+        assert_eq!(host.read(&mut buf).unwrap(), 0);
+
+        // FIN is sent once the host closes, the connection is gone once the
+        // guest acknowledges it.
+        drop(host);
+        wait_host(&nat, &key);
+        let mut out = Vec::new();
+        nat.handle_host_event(&key, &mut out);
+        assert_eq!(out.len(), 1);
+        let fin = parse_segment(&key, &out[0]);
+        assert_eq!(fin.flags, TCP_FIN | TCP_ACK);
+        assert_eq!(fin.seq, snd_nxt);
+        let mut out = Vec::new();
+        let ack = guest_segment(&key, rcv_nxt, snd_nxt.wrapping_add(1), TCP_ACK, &[]);
+        nat.handle_segment(key, None, &ack, &mut out);
+        assert!(out.is_empty());
+        assert!(nat.conns.is_empty());
+    }
+
+    #[test]
+    fn test_tcp_out_of_order_segments() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut nat = TcpNat::new(Vec::new());
+        let (key, mut host, snd_nxt) = establish(&mut nat, &listener);
+        let rcv_nxt = GUEST_ISN + 1;
+
+        // A segment after a lost one is dropped, and the expected sequence
+        // number is acknowledged.
+        let mut out = Vec::new();
+        let seg = guest_segment(&key, rcv_nxt + 2, snd_nxt, TCP_ACK, b"cd");
+        nat.handle_segment(key, None, &seg, &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(parse_segment(&key, &out[0]).ack, rcv_nxt);
+        assert_eq!(nat.conns[&key].rcv_nxt, rcv_nxt);
+
+        // Only the new data of a retransmitted segment is taken.
+        for payload in [&b"ab"[..], &b"abcd"[..]] {
+            let mut out = Vec::new();
+            let seg = guest_segment(&key, rcv_nxt, snd_nxt, TCP_ACK, payload);
+            nat.handle_segment(key, None, &seg, &mut out);
+            assert_eq!(out.len(), 1);
+            assert_eq!(
+                parse_segment(&key, &out[0]).ack,
+                rcv_nxt + payload.len() as u32
+            );
+        }
+        let mut buf = [0_u8; 4];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+
+        // An old duplicate is acknowledged and ignored.
+        let mut out = Vec::new();
+        let seg = guest_segment(&key, rcv_nxt, snd_nxt, TCP_ACK, b"ab");
+        nat.handle_segment(key, None, &seg, &mut out);
+        assert_eq!(parse_segment(&key, &out[0]).ack, rcv_nxt + 4);
+        assert!(nat.conns[&key].recv_buf.is_empty());
+
+        // A RST of the guest drops the connection silently.
+        let mut out = Vec::new();
+        let rst = guest_segment(&key, rcv_nxt + 4, 0, TCP_RST, &[]);
+        nat.handle_segment(key, None, &rst, &mut out);
+        assert!(out.is_empty());
+        assert!(nat.conns.is_empty());
+    }
+
+    #[test]
+    fn test_tcp_retransmit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut nat = TcpNat::new(Vec::new());
+        let (key, mut host, snd_nxt) = establish(&mut nat, &listener);
+        let rcv_nxt = GUEST_ISN + 1;
+
+        // The data waits while the window of the guest is closed.
+        let mut out = Vec::new();
+        let mut seg = guest_segment(&key, rcv_nxt, snd_nxt, TCP_ACK, &[]);
+        seg.window = 0;
+        nat.handle_segment(key, None, &seg, &mut out);
+        host.write_all(b"data").unwrap();
+        wait_host(&nat, &key);
+        nat.handle_host_event(&key, &mut out);
+        assert!(out.is_empty());
+
+        // The zero window is probed with one byte.
+        let later = Instant::now() + TCP_RTO_MAX * 2;
+        nat.handle_timer(later, &mut out);
+        assert_eq!(out.len(), 1);
+        let probe = parse_segment(&key, &out[0]);
+        assert_eq!(probe.seq, snd_nxt);
+        assert_eq!(probe.payload, b"d");
+
+        // The rest is sent once the window opens.
+        let mut out = Vec::new();
+        let ack = guest_segment(&key, rcv_nxt, snd_nxt.wrapping_add(1), TCP_ACK, &[]);
+        nat.handle_segment(key, None, &ack, &mut out);
+        assert_eq!(out.len(), 1);
+        let data = parse_segment(&key, &out[0]);
+        assert_eq!(data.seq, snd_nxt.wrapping_add(1));
+        assert_eq!(data.payload, b"ata");
+        assert_eq!(nat.conns[&key].rto, TCP_RTO_INIT);
+
+        // The unacknowledged data is sent again with the timeout backed off.
+        let mut rto = TCP_RTO_INIT;
+        for _ in 0..TCP_MAX_RETRIES {
+            let mut out = Vec::new();
+            nat.handle_timer(later, &mut out);
+            assert_eq!(out.len(), 1);
+            let data = parse_segment(&key, &out[0]);
+            assert_eq!(data.seq, snd_nxt.wrapping_add(1));
+            assert_eq!(data.payload, b"ata");
+            rto = cmp::min(rto * 2, TCP_RTO_MAX);
+            assert_eq!(nat.conns[&key].rto, rto);
+        }
+
+        // The connection is reset when the guest never answers.
+        let mut out = Vec::new();
+        nat.handle_timer(later, &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!(parse_segment(&key, &out[0]).flags, TCP_RST | TCP_ACK);
+        assert!(nat.conns.is_empty());
+    }
+
+    #[test]
+    fn test_tcp_reset() {
+        let mut nat = TcpNat::new(Vec::new());
+        let key = (guest_addr(), gateway_addr(80));
+
+        // ACK of an unknown connection.
+        let mut out = Vec::new();
+        let ack = guest_segment(&key, GUEST_ISN, 5000, TCP_ACK, b"x");
+        nat.handle_segment(key, None, &ack, &mut out);
+        assert_eq!(out.len(), 1);
+        let rst = parse_segment(&key, &out[0]);
+        assert_eq!(rst.flags, TCP_RST);
+        assert_eq!(rst.seq, 5000);
+
+        // SYN to a remote without host address.
+        let mut out = Vec::new();
+        let syn = guest_segment(&key, GUEST_ISN, 0, TCP_SYN, &[]);
+        nat.handle_segment(key, None, &syn, &mut out);
+        assert_eq!(out.len(), 1);
+        let rst = parse_segment(&key, &out[0]);
+        assert_eq!(rst.flags, TCP_RST | TCP_ACK);
+        assert_eq!(rst.ack, GUEST_ISN + 1);
+        assert!(nat.conns.is_empty());
+
+        // RST of an unknown connection is ignored.
+        let mut out = Vec::new();
+        let rst = guest_segment(&key, GUEST_ISN, 0, TCP_RST, &[]);
+        nat.handle_segment(key, None, &rst, &mut out);
+        assert!(out.is_empty());
+
+        // SYN to a closed port of the host.
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let key = (guest_addr(), gateway_addr(port));
+        let host_dst = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        let mut out = Vec::new();
+        let syn = guest_segment(&key, GUEST_ISN, 0, TCP_SYN, &[]);
+        nat.handle_segment(key, Some(host_dst), &syn, &mut out);
+        if out.is_empty() {
+            wait_host(&nat, &key);
+            nat.handle_host_event(&key, &mut out);
+        }
+        assert_eq!(out.len(), 1);
+        let rst = parse_segment(&key, &out[0]);
+        assert_eq!(rst.flags & TCP_RST, TCP_RST);
+        assert_eq!(rst.ack, GUEST_ISN + 1);
+        assert!(nat.conns.is_empty());
+    }
+
+    #[test]
+    fn test_tcp_forwarded_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut nat = TcpNat::new(vec![(listener, None, 22)]);
+        let guest_ip = *guest_addr().ip();
+        let gateway = *gateway_addr(0).ip();
+
+        // The connection of the client is forwarded to the guest with a SYN.
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut out = Vec::new();
+        nat.handle_accept(0, guest_ip, gateway, &mut out);
+        assert_eq!(out.len(), 1);
+        let key = (
+            SocketAddrV4::new(guest_ip, 22),
+            SocketAddrV4::new(gateway, TCP_FWD_PORT_START),
+        );
+        let syn = parse_segment(&key, &out[0]);
+        assert_eq!(syn.flags, TCP_SYN);
+        assert_eq!(syn.mss, Some(TCP_MSS as u16));
+        let isn = syn.seq;
+        assert_eq!(nat.conns[&key].state, TcpState::SynSent);
+
+        // SYN-ACK with a wrong acknowledgment number is reset.
+        let mut out = Vec::new();
+        let bad = guest_segment(&key, GUEST_ISN, isn, TCP_SYN | TCP_ACK, &[]);
+        nat.handle_segment(key, None, &bad, &mut out);
+        assert_eq!(out.len(), 1);
+        let rst = parse_segment(&key, &out[0]);
+        assert_eq!(rst.flags, TCP_RST);
+        assert_eq!(rst.seq, isn);
+        assert_eq!(nat.conns[&key].state, TcpState::SynSent);
+
+        // The SYN is sent again on timeout.
+        let mut out = Vec::new();
+        nat.handle_timer(Instant::now() + TCP_RTO_MAX, &mut out);
+        assert_eq!(out.len(), 1);
+        let syn = parse_segment(&key, &out[0]);
+        assert_eq!(syn.flags, TCP_SYN);
+        assert_eq!(syn.seq, isn);
+
+        let mut out = Vec::new();
+        let snd_nxt = isn.wrapping_add(1);
+        let syn_ack = guest_segment(&key, GUEST_ISN, snd_nxt, TCP_SYN | TCP_ACK, &[]);
+        nat.handle_segment(key, None, &syn_ack, &mut out);
+        assert_eq!(out.len(), 1);
+        let ack = parse_segment(&key, &out[0]);
+        assert_eq!(ack.flags, TCP_ACK);
+        assert_eq!(ack.ack, GUEST_ISN + 1);
+        assert_eq!(nat.conns[&key].state, TcpState::Established);
+
+        // The data of the guest reaches the client.
+        let mut out = Vec::new();
+        let seg = guest_segment(&key, GUEST_ISN + 1, snd_nxt, TCP_ACK | TCP_PSH, b"ssh");
+        nat.handle_segment(key, None, &seg, &mut out);
+        let mut buf = [0_u8; 3];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ssh");
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! NAT of the UDP datagrams sent by the guest. Each flow of the guest is sent
+//! by a socket of the host, and the replies received by the socket are sent
+//! back to the guest.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use super::packet::{build_udp, UDP_MAX_PAYLOAD};
+use super::FlowKey;
+
+/// The flows idle for this time are removed.
+const UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+/// Max number of the flows.
+const UDP_MAX_FLOWS: usize = 1024;
+/// Size of the buffer to receive datagrams.
+const UDP_RECV_BUF_LEN: usize = 65536;
+
+struct UdpFlow {
+    socket: UdpSocket,
+    /// The peer of the flow on the host side.
+    peer: SocketAddrV4,
+    last_active: Instant,
+}
+
+/// UDP flows keyed by the address of the guest and the destination seen by it.
+#[derive(Default)]
+pub struct UdpNat {
+    flows: HashMap<FlowKey, UdpFlow>,
+}
+
+impl UdpNat {
+    /// Send the `payload` of the flow `key` to `peer`.
+    pub fn send(&mut self, key: FlowKey, peer: SocketAddrV4, payload: &[u8]) {
+        if !self.flows.contains_key(&key) {
+            if self.flows.len() >= UDP_MAX_FLOWS {
+                warn!(
+                    "User network: too many UDP flows, drop datagram to {}",
+                    peer
+                );
+                return;
+            }
+            let socket = match UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+                .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+            {
+                Ok(socket) => socket,
+                Err(e) => {
+                    warn!("User network: failed to create UDP socket: {}", e);
+                    return;
+                }
+            };
+            let flow = UdpFlow {
+                socket,
+                peer,
+                last_active: Instant::now(),
+            };
+            self.flows.insert(key, flow);
+        }
+
+        let flow = self.flows.get_mut(&key).unwrap();
+        flow.last_active = Instant::now();
+        if let Err(e) = flow.socket.send_to(payload, flow.peer) {
+            if e.kind() != ErrorKind::WouldBlock {
+                warn!(
+                    "User network: failed to send UDP datagram to {}: {}",
+                    peer, e
+                );
+            }
+        }
+    }
+
+    /// Fds of the sockets of all flows.
+    pub fn pollfds(&self) -> impl Iterator<Item = (FlowKey, RawFd)> + '_ {
+        self.flows
+            .iter()
+            .map(|(key, flow)| (*key, flow.socket.as_raw_fd()))
+    }
+
+    /// Receive the replies of the flow `key` and send them to the guest.
+    pub fn handle_readable(&mut self, key: &FlowKey, out: &mut Vec<Vec<u8>>) {
+        let flow = match self.flows.get_mut(key) {
+            Some(flow) => flow,
+            None => return,
+        };
+        let mut buf = vec![0_u8; UDP_RECV_BUF_LEN];
+        loop {
+            match flow.socket.recv_from(&mut buf) {
+                Ok((len, from)) => {
+                    // Only the replies of the peer are sent back to the guest.
+                    if from != SocketAddr::V4(flow.peer) {
+                        continue;
+                    }
+                    if len > UDP_MAX_PAYLOAD {
+                        warn!(
+                            "User network: drop UDP datagram of {} bytes from {}",
+                            len, from
+                        );
+                        continue;
+                    }
+                    flow.last_active = Instant::now();
+                    out.push(build_udp(key.1, key.0, &buf[..len]));
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    // Such as ICMP port unreachable of the peer.
+                    warn!("User network: failed to receive from {}: {}", flow.peer, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Remove the flows idle for a while.
+    pub fn expire(&mut self, now: Instant) {
+        self.flows
+            .retain(|_, flow| now.duration_since(flow.last_active) < UDP_FLOW_TIMEOUT);
+    }
+}
@@ -462,6 +462,22 @@ pub struct VhostIoHandler {
     device_broken: Arc<AtomicBool>,
 }
 
+impl VhostIoHandler {
+    /// Create a handler which injects the vring interrupt when the backend
+    /// signals any of the `host_notifies`.
+    pub fn new(
+        interrupt_cb: Arc<VirtioInterrupt>,
+        host_notifies: Vec<VhostNotify>,
+        device_broken: Arc<AtomicBool>,
+    ) -> Self {
+        VhostIoHandler {
+            interrupt_cb,
+            host_notifies,
+            device_broken,
+        }
+    }
+}
+
 impl EventNotifierHelper for VhostIoHandler {
     fn internal_notifiers(vhost_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
         let mut notifiers = Vec::new();
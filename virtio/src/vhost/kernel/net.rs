@@ -455,6 +455,8 @@ mod tests {
             mq: false,
             socket_path: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            user: None,
+            packed: false,
         };
         let conf = vec![net1];
         let confs = Some(conf);
@@ -477,6 +479,8 @@ mod tests {
             mq: false,
             socket_path: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            user: None,
+            packed: false,
         };
         let conf = vec![net1];
         let confs = Some(conf);
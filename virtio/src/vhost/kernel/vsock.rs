@@ -328,7 +328,9 @@ impl VirtioDevice for Vsock {
         register_event_helper(notifiers, None, &mut self.deactivate_evts)?;
         self.broken.store(false, Ordering::SeqCst);
 
-        Ok(())
+        // A driver which used another device behind the transport fetches
+        // the cid again. The event queue is still empty at first activation.
+        self.transport_reset()
     }
 
     fn deactivate(&mut self) -> Result<()> {
@@ -14,17 +14,24 @@ use crate::VirtioError;
 use anyhow::{anyhow, bail, Context, Result};
 use std::cmp;
 use std::io::Write;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 use address_space::AddressSpace;
 use machine_manager::config::BlkDevConfig;
+use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
 use util::byte_code::ByteCode;
+use util::loop_context::EventNotifierHelper;
 use util::num_ops::read_u32;
 use vmm_sys_util::eventfd::EventFd;
 
 use super::client::VhostUserClient;
-use crate::vhost::VhostOps;
-use crate::VhostUser::client::{VHOST_USER_PROTOCOL_F_CONFIG, VHOST_USER_PROTOCOL_F_MQ};
+use crate::vhost::kernel::VhostIoHandler;
+use crate::vhost::{VhostNotify, VhostOps};
+use crate::VhostUser::client::{
+    VHOST_USER_PROTOCOL_F_CONFIG, VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD, VHOST_USER_PROTOCOL_F_MQ,
+};
 use crate::VhostUser::message::VHOST_USER_F_PROTOCOL_FEATURES;
 use crate::{
     virtio_has_feature, BlockState, VirtioDevice, VirtioInterrupt, VIRTIO_BLK_F_BLK_SIZE,
@@ -44,6 +51,10 @@ pub struct Block {
     client: Option<Arc<Mutex<VhostUserClient>>>,
     /// The notifier events from host.
     call_events: Vec<Arc<EventFd>>,
+    /// Eventfds of the vring call handler, used to deactivate the device.
+    deactivate_evts: Vec<RawFd>,
+    /// Device is broken or not.
+    broken: Arc<AtomicBool>,
 }
 
 impl Block {
@@ -54,6 +65,8 @@ impl Block {
             mem_space: mem_space.clone(),
             client: None,
             call_events: Vec::<Arc<EventFd>>::new(),
+            deactivate_evts: Vec::new(),
+            broken: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -88,7 +101,7 @@ impl Block {
 
     /// Negotiate features with spdk.
     fn negotiate_features(&mut self) -> Result<()> {
-        let locked_client = self.client.as_ref().unwrap().lock().unwrap();
+        let mut locked_client = self.client.as_ref().unwrap().lock().unwrap();
         let features = locked_client
             .get_features()
             .with_context(|| "Failed to get features for vhost-user blk")?;
@@ -97,8 +110,10 @@ impl Block {
             let protocol_features = locked_client
                 .get_protocol_features()
                 .with_context(|| "Failed to get protocol features for vhost-user blk")?;
-            let supported_protocol_features =
-                1 << VHOST_USER_PROTOCOL_F_MQ | 1 << VHOST_USER_PROTOCOL_F_CONFIG;
+            // Inflight tracking lets the backend resume IO after a reconnection.
+            let supported_protocol_features = 1 << VHOST_USER_PROTOCOL_F_MQ
+                | 1 << VHOST_USER_PROTOCOL_F_CONFIG
+                | 1 << VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD;
             locked_client
                 .set_protocol_features(supported_protocol_features & protocol_features)
                 .with_context(|| "Failed to set protocol features for vhost-user blk")?;
@@ -251,7 +266,7 @@ impl VirtioDevice for Block {
     fn activate(
         &mut self,
         _mem_space: Arc<AddressSpace>,
-        _interrupt_cb: Arc<VirtioInterrupt>,
+        interrupt_cb: Arc<VirtioInterrupt>,
         queues: &[Arc<Mutex<crate::Queue>>],
         queue_evts: Vec<Arc<EventFd>>,
     ) -> Result<()> {
@@ -263,11 +278,32 @@ impl VirtioDevice for Block {
         client.set_queues(queues);
         client.set_queue_evts(&queue_evts);
         client.activate_vhost_user()?;
+        drop(client);
+
+        // The transport hands plain eventfds to the backend, inject the
+        // interrupt for the guest when one of them is signaled.
+        let host_notifies = self
+            .call_events
+            .iter()
+            .zip(queues.iter())
+            .map(|(evt, queue)| VhostNotify {
+                notify_evt: evt.clone(),
+                queue: queue.clone(),
+            })
+            .collect();
+        let handler = VhostIoHandler::new(interrupt_cb, host_notifies, self.broken.clone());
+        let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
+        register_event_helper(notifiers, None, &mut self.deactivate_evts)?;
+        self.broken.store(false, Ordering::SeqCst);
+
         Ok(())
     }
 
     /// Deactivate device.
     fn deactivate(&mut self) -> Result<()> {
+        unregister_event_helper(None, &mut self.deactivate_evts)?;
+        // Keep the socket event, so that the client still reconnects when the
+        // backend restarts before the guest activates the device again.
         self.client
             .as_ref()
             .ok_or_else(|| anyhow!("Failed to get client when deactivating device"))?
@@ -275,11 +311,12 @@ impl VirtioDevice for Block {
             .unwrap()
             .reset_vhost_user()?;
         self.call_events.clear();
-        self.delete_event()
+        Ok(())
     }
 
     /// Unrealize device.
     fn unrealize(&mut self) -> Result<()> {
+        unregister_event_helper(None, &mut self.deactivate_evts)?;
         self.delete_event()?;
         self.call_events.clear();
         self.client = None;
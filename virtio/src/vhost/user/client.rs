@@ -37,7 +37,7 @@ use super::message::{
 use super::sock::VhostUserSock;
 use crate::block::VirtioBlkConfig;
 use crate::virtio_has_feature;
-use crate::VhostUser::message::VhostUserConfig;
+use crate::VhostUser::message::{VhostUserConfig, VHOST_USER_F_PROTOCOL_FEATURES};
 use anyhow::{anyhow, bail, Context, Result};
 use util::unix::do_mmap;
 
@@ -101,7 +101,7 @@ fn vhost_user_reconnect(client: &Arc<Mutex<VhostUserClient>>) {
         vhost_user_reconnect(&cloned_client);
     });
 
-    info!("Try to reconnect vhost-user net.");
+    info!("Try to reconnect vhost-user device.");
     if let Err(_e) = client
         .lock()
         .unwrap()
@@ -126,10 +126,17 @@ fn vhost_user_reconnect(client: &Arc<Mutex<VhostUserClient>>) {
         error!("Failed to update event for client sock, {:?}", e);
     }
 
-    if let Err(e) = client.lock().unwrap().activate_vhost_user() {
-        error!("Failed to reactivate vhost-user net, {:?}", e);
+    let mut locked_client = client.lock().unwrap();
+    // The device is not activated by the guest yet, the backend will be set
+    // up when the guest driver is ready.
+    if locked_client.queues.is_empty() {
+        info!("Reconnecting vhost-user device succeed.");
+        return;
+    }
+    if let Err(e) = locked_client.activate_vhost_user() {
+        error!("Failed to reactivate vhost-user device, {:?}", e);
     } else {
-        info!("Reconnecting vhost-user net succeed.");
+        info!("Reconnecting vhost-user device succeed.");
     }
 }
 
@@ -356,6 +363,8 @@ pub struct VhostUserClient {
     queue_evts: Vec<Arc<EventFd>>,
     call_events: Vec<Arc<EventFd>>,
     pub features: u64,
+    // Protocol features acked by the backend, None if they are not negotiated.
+    protocol_features: Option<u64>,
     reconnecting: bool,
     inflight: Option<VhostInflight>,
 }
@@ -385,6 +394,7 @@ impl VhostUserClient {
             queue_evts: Vec::new(),
             call_events: Vec::new(),
             features: 0,
+            protocol_features: None,
             reconnecting: false,
             inflight: None,
         })
@@ -456,7 +466,15 @@ impl VhostUserClient {
         self.set_owner()
             .with_context(|| "Failed to set owner for vhost-user")?;
 
-        self.set_features(self.features)
+        // A reconnected backend has forgotten the negotiated protocol features.
+        let mut features = self.features;
+        if let Some(protocol_features) = self.protocol_features {
+            self.set_value(VhostUserMsgReq::SetProtocolFeatures, protocol_features)
+                .with_context(|| "Failed to set protocol features for vhost-user")?;
+            features |= 1 << VHOST_USER_F_PROTOCOL_FEATURES;
+        }
+
+        self.set_features(features)
             .with_context(|| "Failed to set features for vhost-user")?;
 
         self.set_mem_table()
@@ -475,7 +493,13 @@ impl VhostUserClient {
             .unwrap()
             .vring
             .actual_size();
-        self.set_inflight(queue_num as u16, queue_size)?;
+        // The backend can only resume the inflight IO after reconnecting if it
+        // tracks them in the shared inflight buffer.
+        if self.protocol_features.map_or(false, |features| {
+            virtio_has_feature(features, VHOST_USER_PROTOCOL_F_INFLIGHT_SHMFD as u32)
+        }) {
+            self.set_inflight(queue_num as u16, queue_size)?;
+        }
         // Set all vring num to notify ovs/dpdk how many queues it needs to poll
         // before setting vring info.
         for (queue_index, queue_mutex) in self.queues.iter().enumerate().take(queue_num) {
@@ -599,9 +623,11 @@ impl VhostUserClient {
         Ok(())
     }
 
-    /// Set protocol features to vhost.
-    pub fn set_protocol_features(&self, features: u64) -> Result<()> {
-        self.set_value(VhostUserMsgReq::SetProtocolFeatures, features)
+    /// Set protocol features to vhost, they are sent again when the client reconnects.
+    pub fn set_protocol_features(&mut self, features: u64) -> Result<()> {
+        self.set_value(VhostUserMsgReq::SetProtocolFeatures, features)?;
+        self.protocol_features = Some(features);
+        Ok(())
     }
 
     /// Get virtio blk config from vhost.
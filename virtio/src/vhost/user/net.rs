@@ -28,15 +28,19 @@ use vmm_sys_util::eventfd::EventFd;
 use super::super::super::{
     net::{build_device_config_space, CtrlInfo, VirtioNetState, MAC_ADDR_LEN},
     CtrlVirtio, NetCtrlHandler, Queue, VirtioDevice, VirtioInterrupt, VIRTIO_F_RING_EVENT_IDX,
-    VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
-    VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM,
-    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ, VIRTIO_NET_F_MRG_RXBUF, VIRTIO_TYPE_NET,
+    VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX,
+    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_CTRL_MAC_ADDR,
+    VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN, VIRTIO_NET_F_GUEST_TSO4,
+    VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4,
+    VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ,
+    VIRTIO_NET_F_MRG_RXBUF, VIRTIO_TYPE_NET,
 };
-use super::super::VhostOps;
-use super::VhostUserClient;
+use super::super::kernel::VhostIoHandler;
+use super::super::{VhostNotify, VhostOps};
+use super::message::VHOST_USER_F_PROTOCOL_FEATURES;
+use super::{VhostUserClient, VHOST_USER_PROTOCOL_F_MQ};
 use crate::error::VirtioError;
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
 /// Number of virtqueues.
 const QUEUE_NUM_NET: usize = 2;
@@ -57,6 +61,8 @@ pub struct Net {
     deactivate_evts: Vec<RawFd>,
     /// Device is broken or not.
     broken: Arc<AtomicBool>,
+    /// Features supported by the vhost-user backend.
+    backend_features: u64,
 }
 
 impl Net {
@@ -69,6 +75,7 @@ impl Net {
             call_events: Vec::<Arc<EventFd>>::new(),
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
+            backend_features: 0,
         }
     }
 
@@ -83,11 +90,7 @@ impl Net {
             }
             None => return Err(anyhow!("Failed to get client when stoping event")),
         };
-        if ((self.state.lock().unwrap().driver_features & (1 << VIRTIO_NET_F_CTRL_VQ)) != 0)
-            && self.net_cfg.mq
-        {
-            unregister_event_helper(self.net_cfg.iothread.as_ref(), &mut self.deactivate_evts)?;
-        }
+        unregister_event_helper(self.net_cfg.iothread.as_ref(), &mut self.deactivate_evts)?;
 
         Ok(())
     }
@@ -120,24 +123,58 @@ impl VirtioDevice for Net {
         let client = Arc::new(Mutex::new(client));
         VhostUserClient::add_event(&client)?;
 
-        let mut locked_state = self.state.lock().unwrap();
-        locked_state.device_features = client
-            .lock()
-            .unwrap()
+        let mut locked_client = client.lock().unwrap();
+        self.backend_features = locked_client
             .get_features()
             .with_context(|| "Failed to get features for vhost-user net")?;
 
+        let queue_pairs = self.net_cfg.queues / 2;
+        let mut backend_mq = false;
+        if virtio_has_feature(self.backend_features, VHOST_USER_F_PROTOCOL_FEATURES) {
+            let protocol_features = locked_client
+                .get_protocol_features()
+                .with_context(|| "Failed to get protocol features for vhost-user net")?;
+            locked_client
+                .set_protocol_features(protocol_features & 1 << VHOST_USER_PROTOCOL_F_MQ)
+                .with_context(|| "Failed to set protocol features for vhost-user net")?;
+
+            if virtio_has_feature(protocol_features, VHOST_USER_PROTOCOL_F_MQ as u32) {
+                let max_queue_pairs = locked_client
+                    .get_max_queue_num()
+                    .with_context(|| "Failed to get queue num for vhost-user net")?;
+                if queue_pairs as u64 > max_queue_pairs {
+                    bail!(
+                        "Exceed the max queue pairs that vhost-user backend supported ({} queue pairs)",
+                        max_queue_pairs
+                    );
+                }
+                backend_mq = true;
+            }
+        }
+        drop(locked_client);
+        if self.net_cfg.mq && queue_pairs > 1 && !backend_mq {
+            bail!(
+                "vhost-user backend of net {} doesn't support multi queue",
+                self.net_cfg.id
+            );
+        }
+
+        let mut locked_state = self.state.lock().unwrap();
         let features = 1 << VIRTIO_F_VERSION_1
+            | 1 << VIRTIO_NET_F_CSUM
             | 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_GUEST_TSO4
+            | 1 << VIRTIO_NET_F_GUEST_TSO6
+            | 1 << VIRTIO_NET_F_GUEST_ECN
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
+            | 1 << VIRTIO_NET_F_HOST_TSO6
             | 1 << VIRTIO_NET_F_HOST_UFO
             | 1 << VIRTIO_NET_F_MRG_RXBUF
+            | 1 << VIRTIO_F_RING_INDIRECT_DESC
             | 1 << VIRTIO_F_RING_EVENT_IDX;
-        locked_state.device_features &= features;
+        locked_state.device_features = self.backend_features & features;
 
-        let queue_pairs = self.net_cfg.queues / 2;
         if self.net_cfg.mq
             && (VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN..=VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX)
                 .contains(&queue_pairs)
@@ -235,11 +272,12 @@ impl VirtioDevice for Net {
         queues: &[Arc<Mutex<Queue>>],
         mut queue_evts: Vec<Arc<EventFd>>,
     ) -> Result<()> {
-        let queue_num = queues.len();
+        let mut queue_num = queues.len();
         let driver_features = self.state.lock().unwrap().driver_features;
         if ((driver_features & (1 << VIRTIO_NET_F_CTRL_VQ)) != 0) && (queue_num % 2 != 0) {
-            let ctrl_queue = queues[queue_num - 1].clone();
-            let ctrl_queue_evt = queue_evts.remove(queue_num - 1);
+            queue_num -= 1;
+            let ctrl_queue = queues[queue_num].clone();
+            let ctrl_queue_evt = queue_evts.remove(queue_num);
             let ctrl_info = Arc::new(Mutex::new(CtrlInfo::new(self.state.clone())));
 
             let ctrl_handler = NetCtrlHandler {
@@ -264,11 +302,33 @@ impl VirtioDevice for Net {
             None => return Err(anyhow!("Failed to get client for vhost-user net")),
         };
 
-        let features = driver_features & !(1 << VIRTIO_NET_F_MAC);
+        // The control queue and the mac address are handled by us, only pass
+        // the data queues and the features the backend supports.
+        let features = driver_features & !(1 << VIRTIO_NET_F_MAC) & self.backend_features;
         client.features = features;
-        client.set_queues(queues);
+        client.set_queues(&queues[..queue_num]);
         client.set_queue_evts(&queue_evts);
         client.activate_vhost_user()?;
+        drop(client);
+
+        // The transport hands plain eventfds to the backend, inject the
+        // interrupt for the guest when one of them is signaled.
+        let host_notifies = self
+            .call_events
+            .iter()
+            .zip(queues[..queue_num].iter())
+            .map(|(evt, queue)| VhostNotify {
+                notify_evt: evt.clone(),
+                queue: queue.clone(),
+            })
+            .collect();
+        let handler = VhostIoHandler::new(interrupt_cb, host_notifies, self.broken.clone());
+        let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
+        register_event_helper(
+            notifiers,
+            self.net_cfg.iothread.as_ref(),
+            &mut self.deactivate_evts,
+        )?;
         self.broken.store(false, Ordering::SeqCst);
 
         Ok(())
@@ -24,6 +24,7 @@ use address_space::{AddressRange, AddressSpace, GuestAddress, RegionIoEventFd};
 use byteorder::{ByteOrder, LittleEndian};
 use devices::InterruptController;
 use log::{error, warn};
+use machine_manager::config::DEFAULT_VIRTQUEUE_SIZE;
 #[cfg(target_arch = "x86_64")]
 use machine_manager::config::{BootSource, Param};
 use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
@@ -36,8 +37,9 @@ use super::{
     virtio_has_feature, Queue, QueueConfig, VirtioDevice, VirtioInterrupt, VirtioInterruptType,
     CONFIG_STATUS_ACKNOWLEDGE, CONFIG_STATUS_DRIVER, CONFIG_STATUS_DRIVER_OK, CONFIG_STATUS_FAILED,
     CONFIG_STATUS_FEATURES_OK, CONFIG_STATUS_NEEDS_RESET, NOTIFY_REG_OFFSET,
-    QUEUE_TYPE_PACKED_VRING, QUEUE_TYPE_SPLIT_VRING, VIRTIO_F_RING_PACKED, VIRTIO_MMIO_INT_CONFIG,
-    VIRTIO_MMIO_INT_VRING,
+    QUEUE_TYPE_PACKED_VRING, QUEUE_TYPE_SPLIT_VRING, VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1,
+    VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING, VIRTIO_TYPE_BLOCK, VIRTIO_TYPE_CONSOLE,
+    VIRTIO_TYPE_NET, VIRTIO_TYPE_VSOCK,
 };
 use anyhow::{anyhow, bail, Context, Result};
 
@@ -111,10 +113,87 @@ impl HostNotifyInfo {
     }
 }
 
+/// The device behind an empty replaceable transport. It has the type of the
+/// devices the transport accepts, so that the guest driver binds to it at
+/// boot. No feature is offered, any device of that type can then take over
+/// the queues set up by the driver.
+struct VirtioPlaceholder {
+    device_type: u32,
+    queue_num: usize,
+}
+
+impl VirtioPlaceholder {
+    fn new(device_type: u32) -> Result<Self> {
+        let queue_num = match device_type {
+            VIRTIO_TYPE_BLOCK => 1,
+            VIRTIO_TYPE_NET | VIRTIO_TYPE_CONSOLE => 2,
+            VIRTIO_TYPE_VSOCK => 3,
+            _ => bail!("Unsupported type {} of replaceable device", device_type),
+        };
+        Ok(VirtioPlaceholder {
+            device_type,
+            queue_num,
+        })
+    }
+}
+
+impl VirtioDevice for VirtioPlaceholder {
+    fn realize(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    fn queue_num(&self) -> usize {
+        self.queue_num
+    }
+
+    fn queue_size(&self) -> u16 {
+        DEFAULT_VIRTQUEUE_SIZE
+    }
+
+    fn get_device_features(&self, _features_select: u32) -> u32 {
+        0
+    }
+
+    fn set_driver_features(&mut self, _page: u32, _value: u32) {}
+
+    fn get_driver_features(&self, _features_select: u32) -> u32 {
+        0
+    }
+
+    /// The driver reads a disk of no sectors, or a vsock of no cid.
+    fn read_config(&self, _offset: u64, data: &mut [u8]) -> Result<()> {
+        data.fill(0);
+        Ok(())
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// The requests of the driver wait in the queues for a device.
+    fn activate(
+        &mut self,
+        _mem_space: Arc<AddressSpace>,
+        _interrupt_cb: Arc<VirtioInterrupt>,
+        _queues: &[Arc<Mutex<Queue>>],
+        _queue_evts: Vec<Arc<EventFd>>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn deactivate(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
 /// The state of virtio-mmio device.
 #[repr(C)]
 #[derive(Copy, Clone, Desc, ByteCode)]
-#[desc_version(compat_version = "0.1.0")]
+#[desc_version(current_version = "0.2.0", compat_version = "0.1.0")]
 pub struct VirtioMmioState {
     /// Identify if this device is activated by frontend driver.
     activated: bool,
@@ -374,6 +453,114 @@ impl VirtioMmioDevice {
         }
     }
 
+    /// Create a virtio-mmio transport with no device behind it. A device of
+    /// `device_type` can be plugged in later with `replace_device`.
+    pub fn new_replaceable(
+        mem_space: &Arc<AddressSpace>,
+        device_type: u32,
+        irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> Result<Self> {
+        let placeholder = VirtioPlaceholder::new(device_type)?;
+        let mut dev = Self::new(mem_space, Arc::new(Mutex::new(placeholder)), irq_chip);
+        // The queue notifiers are registered once at realize time, so reserve
+        // one for every queue any device may need.
+        dev.host_notify_info = HostNotifyInfo::new(MAXIMUM_NR_QUEUES);
+        Ok(dev)
+    }
+
+    /// Replace the device behind the transport, `None` leaves the transport empty.
+    /// The new device must be of the type of the transport. If the driver is
+    /// using the transport, the new device takes over the queues and the
+    /// features negotiated, and the driver is told to read its config again.
+    /// Otherwise the transport is reset.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The new virtio device.
+    pub fn replace_device(&mut self, device: Option<Arc<Mutex<dyn VirtioDevice>>>) -> Result<()> {
+        let device_type = self.device.lock().unwrap().device_type();
+        let placeholder = device.is_none();
+        let device = match device {
+            Some(device) => device,
+            None => Arc::new(Mutex::new(VirtioPlaceholder::new(device_type)?)),
+        };
+        let mut locked_device = device.lock().unwrap();
+        if locked_device.device_type() != device_type {
+            bail!(
+                "Device of type {} can't be plugged into a transport of type {}",
+                locked_device.device_type(),
+                device_type
+            );
+        }
+        let queue_num = locked_device.queue_num();
+        if queue_num > self.host_notify_info.events.len() {
+            bail!(
+                "The device needs {} queues, but the transport supports {} at most",
+                queue_num,
+                self.host_notify_info.events.len()
+            );
+        }
+        locked_device
+            .realize()
+            .with_context(|| "Failed to realize virtio.")?;
+
+        if !self.state.lock().unwrap().activated {
+            drop(locked_device);
+            self.device = device;
+            self.queues.clear();
+            self.interrupt_status.store(0, Ordering::SeqCst);
+            self.state.lock().unwrap().config_space = VirtioMmioCommonConfig::new(&self.device);
+            return Ok(());
+        }
+
+        let driver_features = {
+            let old_device = self.device.lock().unwrap();
+            u64::from(old_device.get_driver_features(0))
+                | u64::from(old_device.get_driver_features(1)) << 32
+        };
+        // The placeholder ignores the queues, whatever was negotiated.
+        if !placeholder {
+            let device_features = u64::from(locked_device.get_device_features(0))
+                | u64::from(locked_device.get_device_features(1)) << 32
+                | 1 << VIRTIO_F_VERSION_1;
+            if driver_features & !device_features != 0 {
+                bail!(
+                    "The device doesn't support the features 0x{:x} negotiated by the driver",
+                    driver_features & !device_features
+                );
+            }
+            if self.queues.len() > queue_num {
+                bail!(
+                    "The device supports {} queues, but the driver set up {}",
+                    queue_num,
+                    self.queues.len()
+                );
+            }
+        }
+        locked_device.set_driver_features(0, driver_features as u32);
+        locked_device.set_driver_features(1, (driver_features >> 32) as u32);
+        drop(locked_device);
+
+        self.device
+            .lock()
+            .unwrap()
+            .deactivate()
+            .with_context(|| "Failed to deactivate virtio device")?;
+        if let Err(e) = self.activate_device(&device) {
+            // Keep the driver working with the previous device.
+            if let Err(e) = self.activate_device(&self.device) {
+                error!("Failed to activate the previous virtio device, {:?}", e);
+            }
+            return Err(e);
+        }
+        self.device = device;
+        if let Some(cb) = self.interrupt_cb.as_ref() {
+            cb(&VirtioInterruptType::Config, None, false)
+                .with_context(|| "Failed to notify the config change of virtio device")?;
+        }
+        Ok(())
+    }
+
     pub fn realize(
         mut self,
         sysbus: &mut SysBus,
@@ -391,8 +578,9 @@ impl VirtioMmioDevice {
         if region_base >= sysbus.mmio_region.1 {
             bail!("Mmio region space exhausted.");
         }
-        self.set_sys_resource(sysbus, region_base, region_size)?;
-        self.assign_interrupt_cb();
+        self.set_sys_resource(sysbus, region_base, region_size)
+            .with_context(|| "Failed to allocate resources for virtio-mmio device")?;
+        self.assign_interrupt_cb()?;
         let dev = Arc::new(Mutex::new(self));
         sysbus.attach_device(&dev, region_base, region_size)?;
 
@@ -435,20 +623,27 @@ impl VirtioMmioDevice {
         }
         drop(locked_state);
 
+        self.activate_device(&self.device)
+    }
+
+    /// Hand the queues set up by the driver to `device`.
+    fn activate_device(&self, device: &Arc<Mutex<dyn VirtioDevice>>) -> Result<()> {
+        // A replaceable transport owns an eventfd for every possible queue,
+        // only hand the ones in use to the device.
         let mut queue_evts = Vec::<Arc<EventFd>>::new();
-        for fd in self.host_notify_info.events.iter() {
+        for fd in self.host_notify_info.events.iter().take(self.queues.len()) {
             queue_evts.push(fd.clone());
         }
 
         let mut events = Vec::new();
-        for _i in 0..self.device.lock().unwrap().queue_num() {
+        for _i in 0..device.lock().unwrap().queue_num() {
             events.push(Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap()));
         }
 
-        self.device.lock().unwrap().set_guest_notifiers(&events)?;
+        device.lock().unwrap().set_guest_notifiers(&events)?;
 
         if let Some(cb) = self.interrupt_cb.clone() {
-            self.device.lock().unwrap().activate(
+            device.lock().unwrap().activate(
                 self.mem_space.clone(),
                 cb,
                 &self.queues,
@@ -461,12 +656,19 @@ impl VirtioMmioDevice {
         Ok(())
     }
 
-    fn assign_interrupt_cb(&mut self) {
+    fn assign_interrupt_cb(&mut self) -> Result<()> {
         let interrupt_status = self.interrupt_status.clone();
         let interrupt_evt = self.interrupt_evt.clone();
         let cloned_state = self.state.clone();
         let irq_chip = self.irq_chip.clone();
-        let irq = self.get_sys_resource().unwrap().irq as u8;
+        let irq = self.get_sys_resource().unwrap().irq;
+        // An in-kernel interrupt controller injects the interrupt by itself
+        // once `interrupt_evt` is written.
+        let irqfd = irq_chip
+            .lock()
+            .unwrap()
+            .register_irqfd(&interrupt_evt, irq)
+            .with_context(|| "Failed to register irqfd for virtio-mmio device")?;
         let cb = Arc::new(Box::new(
             move |int_type: &VirtioInterruptType, _queue: Option<&Queue>, needs_reset: bool| {
                 let status = match int_type {
@@ -491,12 +693,15 @@ impl VirtioMmioDevice {
                 interrupt_evt
                     .write(1)
                     .with_context(|| anyhow!(VirtioError::EventFdWrite))?;
-                irq_chip.lock().unwrap().kvm_irq_trigger(irq);
+                if !irqfd {
+                    irq_chip.lock().unwrap().kvm_irq_trigger(irq);
+                }
                 Ok(())
             },
         ) as VirtioInterrupt);
 
         self.interrupt_cb = Some(cb);
+        Ok(())
     }
 }
 
@@ -655,12 +860,39 @@ impl SysBusDevOps for VirtioMmioDevice {
     fn get_type(&self) -> SysBusDevType {
         SysBusDevType::VirtioMmio
     }
+
+    fn reset(&mut self) -> sysbus::Result<()> {
+        if self.state.lock().unwrap().activated {
+            self.device
+                .lock()
+                .unwrap()
+                .deactivate()
+                .with_context(|| "Failed to deactivate virtio device")?;
+        }
+        self.device
+            .lock()
+            .unwrap()
+            .reset()
+            .with_context(|| "Failed to reset virtio device")?;
+
+        self.queues.clear();
+        self.interrupt_status.store(0, Ordering::SeqCst);
+        let mut locked_state = self.state.lock().unwrap();
+        locked_state.activated = false;
+        locked_state.config_space = VirtioMmioCommonConfig::new(&self.device);
+        Ok(())
+    }
 }
 
 
 impl StateTransfer for VirtioMmioDevice {
     fn get_state_vec(&self) -> migration::Result<Vec<u8>> {
         let mut state = self.state.lock().unwrap();
+        // The descriptor chains in flight of the packed virtqueues are not saved,
+        // packed virtqueues are only offered with the `packed` device property.
+        if state.activated && state.config_space.queue_type == QUEUE_TYPE_PACKED_VRING {
+            bail!("Migration of virtio-mmio device with packed virtqueues is not supported");
+        }
 
         for (index, queue) in self.queues.iter().enumerate() {
             state.config_space.queues_config[index] =
@@ -716,9 +948,13 @@ impl StateTransfer for VirtioMmioDevice {
 
 impl MigrationHook for VirtioMmioDevice {
     fn resume(&mut self) -> migration::Result<()> {
-        if self.state.lock().unwrap().activated {
+        let (activated, queue_num) = {
+            let locked_state = self.state.lock().unwrap();
+            (locked_state.activated, locked_state.config_space.queue_num)
+        };
+        if activated {
             let mut queue_evts = Vec::<Arc<EventFd>>::new();
-            for fd in self.host_notify_info.events.iter() {
+            for fd in self.host_notify_info.events.iter().take(queue_num) {
                 queue_evts.push(fd.clone());
             }
 
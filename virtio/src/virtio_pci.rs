@@ -0,0 +1,1337 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use address_space::{AddressRange, AddressSpace, GuestAddress, Region, RegionIoEventFd, RegionOps};
+use anyhow::{anyhow, bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use devices::InterruptController;
+use log::{error, warn};
+use pci::config::{
+    PciConfig, RegionType, BAR_NUM_MAX_FOR_ENDPOINT, CAP_ID_VNDR, DEVICE_ID, HEADER_TYPE,
+    HEADER_TYPE_ENDPOINT, HEADER_TYPE_MULTIFUNC, INTERRUPT_PIN, INTERRUPT_PIN_INTA,
+    PCIE_CONFIG_SPACE_SIZE, REVISION_ID, SUBSYSTEM_ID, SUBSYSTEM_VENDOR_ID, SUB_CLASS_CODE,
+    VENDOR_ID,
+};
+use pci::{init_msix, le_write_u16, le_write_u32, Intx, PciBus, PciDevOps};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::error::VirtioError;
+use crate::{
+    virtio_has_feature, NotifyEventFds, Queue, QueueConfig, VirtioDevice, VirtioInterrupt,
+    VirtioInterruptType, CONFIG_STATUS_ACKNOWLEDGE, CONFIG_STATUS_DRIVER, CONFIG_STATUS_DRIVER_OK,
+    CONFIG_STATUS_FAILED, CONFIG_STATUS_FEATURES_OK, CONFIG_STATUS_NEEDS_RESET, INVALID_VECTOR_NUM,
+    QUEUE_TYPE_PACKED_VRING, QUEUE_TYPE_SPLIT_VRING, VIRTIO_F_RING_PACKED, VIRTIO_TYPE_BLOCK,
+    VIRTIO_TYPE_CONSOLE, VIRTIO_TYPE_NET,
+};
+
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+/// Device ID of a modern device is this base plus its virtio device type.
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+/// Revision ID of a device without the legacy interface.
+const VIRTIO_PCI_ABI_VERSION: u8 = 1;
+const VIRTIO_PCI_SUBSYSTEM_ID: u16 = 0x40;
+
+// Class codes of the devices.
+const VIRTIO_PCI_CLASS_ID_NET: u16 = 0x0200;
+const VIRTIO_PCI_CLASS_ID_BLOCK: u16 = 0x0100;
+const VIRTIO_PCI_CLASS_ID_COMMUNICATION_OTHER: u16 = 0x0780;
+const VIRTIO_PCI_CLASS_ID_OTHERS: u16 = 0x00ff;
+
+/// BAR holding the MSI-X table and pending bits.
+const VIRTIO_PCI_MSIX_BAR_IDX: usize = 1;
+/// 64-bit BAR holding the virtio structures.
+const VIRTIO_PCI_MEM_BAR_IDX: usize = 2;
+
+// Types of the virtio structures, refer to Virtio Spec.
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// Layout of a virtio vendor-specific capability, the notification one has an
+// extra notify_off_multiplier field.
+const VIRTIO_PCI_CAP_LEN: usize = 2;
+const VIRTIO_PCI_CAP_CFG_TYPE: usize = 3;
+const VIRTIO_PCI_CAP_BAR: usize = 4;
+const VIRTIO_PCI_CAP_OFFSET: usize = 8;
+const VIRTIO_PCI_CAP_LENGTH: usize = 12;
+const VIRTIO_PCI_CAP_NOTIFY_OFF_MULTIPLIER: usize = 16;
+const VIRTIO_PCI_CAP_SIZE: usize = 16;
+const VIRTIO_PCI_NOTIFY_CAP_SIZE: usize = 20;
+
+// Layout of the virtio structures in their BAR, a page each.
+const VIRTIO_PCI_COMMON_OFFSET: u64 = 0x0;
+const VIRTIO_PCI_ISR_OFFSET: u64 = 0x1000;
+const VIRTIO_PCI_DEVICE_OFFSET: u64 = 0x2000;
+const VIRTIO_PCI_NOTIFY_OFFSET: u64 = 0x3000;
+const VIRTIO_PCI_REGION_SIZE: u64 = 0x1000;
+const VIRTIO_PCI_BAR_SIZE: u64 = 0x4000;
+/// The notification address of a queue is its index times this multiplier.
+const VIRTIO_PCI_NOTIFY_MULTIPLIER: u32 = 4;
+
+/// Registers of the common configuration structure, refer to Virtio Spec.
+/// Device feature set selector - Read Write.
+const COMMON_DFSELECT_REG: u64 = 0x00;
+/// Bitmask of the features supported by the device - Read Only.
+const COMMON_DF_REG: u64 = 0x04;
+/// Driver feature set selector - Read Write.
+const COMMON_GFSELECT_REG: u64 = 0x08;
+/// Bitmask of the features activated by the driver - Read Write.
+const COMMON_GF_REG: u64 = 0x0c;
+/// MSI-X vector of configuration change notifications - Read Write.
+const COMMON_MSIX_REG: u64 = 0x10;
+/// Number of queues - Read Only.
+const COMMON_NUMQ_REG: u64 = 0x12;
+/// Device status - Read Write.
+const COMMON_STATUS_REG: u64 = 0x14;
+/// Configuration atomicity value - Read Only.
+const COMMON_CFGGENERATION_REG: u64 = 0x15;
+/// Queue selector - Read Write.
+const COMMON_Q_SELECT_REG: u64 = 0x16;
+/// Size of the selected queue - Read Write.
+const COMMON_Q_SIZE_REG: u64 = 0x18;
+/// MSI-X vector of the selected queue - Read Write.
+const COMMON_Q_MSIX_REG: u64 = 0x1a;
+/// Ready bit of the selected queue - Read Write.
+const COMMON_Q_ENABLE_REG: u64 = 0x1c;
+/// Notification offset of the selected queue - Read Only.
+const COMMON_Q_NOFF_REG: u64 = 0x1e;
+/// The low 32 bit of the selected queue's Descriptor Table address.
+const COMMON_Q_DESCLO_REG: u64 = 0x20;
+/// The high 32 bit of the selected queue's Descriptor Table address.
+const COMMON_Q_DESCHI_REG: u64 = 0x24;
+/// The low 32 bit of the selected queue's Available Ring address.
+const COMMON_Q_AVAILLO_REG: u64 = 0x28;
+/// The high 32 bit of the selected queue's Available Ring address.
+const COMMON_Q_AVAILHI_REG: u64 = 0x2c;
+/// The low 32 bit of the selected queue's Used Ring address.
+const COMMON_Q_USEDLO_REG: u64 = 0x30;
+/// The high 32 bit of the selected queue's Used Ring address.
+const COMMON_Q_USEDHI_REG: u64 = 0x34;
+
+/// Bits of the ISR status.
+const VIRTIO_PCI_ISR_QUEUE: u32 = 0x01;
+const VIRTIO_PCI_ISR_CONFIG: u32 = 0x02;
+
+/// The common configuration of virtio-pci device, the fields refer to Virtio Spec.
+#[derive(Clone, Default)]
+struct VirtioPciCommonConfig {
+    /// Device (host) feature-setting selector.
+    features_select: u32,
+    /// Driver (guest) feature-setting selector.
+    acked_features_select: u32,
+    /// Device status.
+    device_status: u32,
+    /// Configuration atomicity value.
+    config_generation: u32,
+    /// MSI-X vector of configuration change notifications.
+    msix_config: u16,
+    /// Queue selector.
+    queue_select: u16,
+    /// The configuration of queues.
+    queues_config: Vec<QueueConfig>,
+    /// The type of queue, either be split ring or packed ring.
+    queue_type: u16,
+    /// Number of MSI-X vectors of the device, 0 if it has no MSI-X.
+    msix_vectors: u16,
+}
+
+impl VirtioPciCommonConfig {
+    fn new(device: &Arc<Mutex<dyn VirtioDevice>>, msix_vectors: u16) -> Self {
+        let locked_device = device.lock().unwrap();
+        let queues_config =
+            vec![QueueConfig::new(locked_device.queue_size()); locked_device.queue_num()];
+
+        VirtioPciCommonConfig {
+            msix_config: INVALID_VECTOR_NUM,
+            queues_config,
+            queue_type: QUEUE_TYPE_SPLIT_VRING,
+            msix_vectors,
+            ..Default::default()
+        }
+    }
+
+    /// Check whether virtio device status is as expected.
+    fn check_device_status(&self, set: u32, clr: u32) -> bool {
+        self.device_status & (set | clr) == set
+    }
+
+    /// The vector the driver asked for, or no vector if the device hasn't it.
+    fn check_vector(&self, vector: u16) -> u16 {
+        if vector < self.msix_vectors {
+            vector
+        } else {
+            INVALID_VECTOR_NUM
+        }
+    }
+
+    /// Get mutable QueueConfig structure of virtio device.
+    fn get_mut_queue_config(&mut self) -> Result<&mut QueueConfig> {
+        if self.check_device_status(
+            CONFIG_STATUS_FEATURES_OK,
+            CONFIG_STATUS_DRIVER_OK | CONFIG_STATUS_FAILED,
+        ) {
+            let queue_select = self.queue_select;
+            self.queues_config
+                .get_mut(queue_select as usize)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Queue_select {} overflows for mutable queue config",
+                        queue_select,
+                    )
+                })
+        } else {
+            Err(anyhow!(VirtioError::DevStatErr(self.device_status)))
+        }
+    }
+
+    /// Get immutable QueueConfig structure of virtio device.
+    fn get_queue_config(&self) -> Result<&QueueConfig> {
+        let queue_select = self.queue_select;
+        self.queues_config
+            .get(queue_select as usize)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Queue_select {} overflows for immutable queue config",
+                    queue_select,
+                )
+            })
+    }
+
+    /// Read data from the common config of virtio device.
+    /// Return the config value in u32.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - Virtio device entity.
+    /// * `offset` - The offset of common config.
+    fn read_common_config(
+        &self,
+        device: &Arc<Mutex<dyn VirtioDevice>>,
+        offset: u64,
+    ) -> Result<u32> {
+        let value = match offset {
+            COMMON_DFSELECT_REG => self.features_select,
+            COMMON_DF_REG => {
+                let mut features = device
+                    .lock()
+                    .unwrap()
+                    .get_device_features(self.features_select);
+                if self.features_select == 1 {
+                    features |= 0x1; // enable support of VirtIO Version 1
+                }
+                features
+            }
+            COMMON_GFSELECT_REG => self.acked_features_select,
+            COMMON_GF_REG => device
+                .lock()
+                .unwrap()
+                .get_driver_features(self.acked_features_select),
+            COMMON_MSIX_REG => u32::from(self.msix_config),
+            COMMON_NUMQ_REG => self.queues_config.len() as u32,
+            COMMON_STATUS_REG => self.device_status,
+            COMMON_CFGGENERATION_REG => self.config_generation,
+            COMMON_Q_SELECT_REG => u32::from(self.queue_select),
+            COMMON_Q_SIZE_REG => self
+                .get_queue_config()
+                .map(|config| u32::from(config.size))?,
+            COMMON_Q_MSIX_REG => self
+                .get_queue_config()
+                .map(|config| u32::from(config.vector))?,
+            COMMON_Q_ENABLE_REG => self.get_queue_config().map(|config| config.ready as u32)?,
+            COMMON_Q_NOFF_REG => u32::from(self.queue_select),
+            COMMON_Q_DESCLO_REG => self
+                .get_queue_config()
+                .map(|config| config.desc_table.0 as u32)?,
+            COMMON_Q_DESCHI_REG => self
+                .get_queue_config()
+                .map(|config| (config.desc_table.0 >> 32) as u32)?,
+            COMMON_Q_AVAILLO_REG => self
+                .get_queue_config()
+                .map(|config| config.avail_ring.0 as u32)?,
+            COMMON_Q_AVAILHI_REG => self
+                .get_queue_config()
+                .map(|config| (config.avail_ring.0 >> 32) as u32)?,
+            COMMON_Q_USEDLO_REG => self
+                .get_queue_config()
+                .map(|config| config.used_ring.0 as u32)?,
+            COMMON_Q_USEDHI_REG => self
+                .get_queue_config()
+                .map(|config| (config.used_ring.0 >> 32) as u32)?,
+            _ => {
+                return Err(anyhow!(VirtioError::PciRegErr(offset)));
+            }
+        };
+
+        Ok(value)
+    }
+
+    /// Write data to the common config of virtio device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - Virtio device entity.
+    /// * `offset` - The offset of common config.
+    /// * `value` - The value to write.
+    ///
+    /// # Errors
+    ///
+    /// Returns Error if the offset is out of bound.
+    fn write_common_config(
+        &mut self,
+        device: &Arc<Mutex<dyn VirtioDevice>>,
+        offset: u64,
+        value: u32,
+    ) -> Result<()> {
+        match offset {
+            COMMON_DFSELECT_REG => self.features_select = value,
+            COMMON_GFSELECT_REG => self.acked_features_select = value,
+            COMMON_GF_REG => {
+                if self.check_device_status(
+                    CONFIG_STATUS_DRIVER,
+                    CONFIG_STATUS_FEATURES_OK | CONFIG_STATUS_FAILED,
+                ) {
+                    device
+                        .lock()
+                        .unwrap()
+                        .set_driver_features(self.acked_features_select, value);
+                    if self.acked_features_select == 1
+                        && virtio_has_feature(u64::from(value) << 32, VIRTIO_F_RING_PACKED)
+                    {
+                        self.queue_type = QUEUE_TYPE_PACKED_VRING;
+                    }
+                } else {
+                    return Err(anyhow!(VirtioError::DevStatErr(self.device_status)));
+                }
+            }
+            COMMON_MSIX_REG => self.msix_config = self.check_vector(value as u16),
+            COMMON_STATUS_REG => self.device_status = value,
+            COMMON_Q_SELECT_REG => self.queue_select = value as u16,
+            COMMON_Q_SIZE_REG => self
+                .get_mut_queue_config()
+                .map(|config| config.size = value as u16)?,
+            COMMON_Q_MSIX_REG => {
+                let vector = self.check_vector(value as u16);
+                self.get_mut_queue_config()
+                    .map(|config| config.vector = vector)?
+            }
+            COMMON_Q_ENABLE_REG => self
+                .get_mut_queue_config()
+                .map(|config| config.ready = value == 1)?,
+            COMMON_Q_DESCLO_REG => self.get_mut_queue_config().map(|config| {
+                config.desc_table = GuestAddress(set_low32(config.desc_table.0, value));
+            })?,
+            COMMON_Q_DESCHI_REG => self.get_mut_queue_config().map(|config| {
+                config.desc_table = GuestAddress(set_high32(config.desc_table.0, value));
+            })?,
+            COMMON_Q_AVAILLO_REG => self.get_mut_queue_config().map(|config| {
+                config.avail_ring = GuestAddress(set_low32(config.avail_ring.0, value));
+            })?,
+            COMMON_Q_AVAILHI_REG => self.get_mut_queue_config().map(|config| {
+                config.avail_ring = GuestAddress(set_high32(config.avail_ring.0, value));
+            })?,
+            COMMON_Q_USEDLO_REG => self.get_mut_queue_config().map(|config| {
+                config.used_ring = GuestAddress(set_low32(config.used_ring.0, value));
+            })?,
+            COMMON_Q_USEDHI_REG => self.get_mut_queue_config().map(|config| {
+                config.used_ring = GuestAddress(set_high32(config.used_ring.0, value));
+            })?,
+            _ => {
+                return Err(anyhow!(VirtioError::PciRegErr(offset)));
+            }
+        };
+        Ok(())
+    }
+}
+
+fn set_low32(addr: u64, value: u32) -> u64 {
+    (addr & 0xffff_ffff_0000_0000) | u64::from(value)
+}
+
+fn set_high32(addr: u64, value: u32) -> u64 {
+    (addr & 0xffff_ffff) | (u64::from(value) << 32)
+}
+
+/// The state of virtio-pci device.
+struct VirtioPciState {
+    /// Identify if this device is activated by frontend driver.
+    activated: bool,
+    /// Common configuration of the device.
+    common_config: VirtioPciCommonConfig,
+}
+
+/// virtio-pci device structure, it exposes a virtio device through the
+/// modern virtio 1.x PCI capabilities.
+pub struct VirtioPciDevice {
+    /// Name of the device.
+    name: String,
+    /// The entity of low level device.
+    device: Arc<Mutex<dyn VirtioDevice>>,
+    /// Device/function number of the device on its parent bus.
+    devfn: u8,
+    /// Configuration space of the device.
+    config: PciConfig,
+    /// System address space.
+    sys_mem: Arc<AddressSpace>,
+    /// The state of virtio pci device.
+    state: Arc<Mutex<VirtioPciState>>,
+    /// Interrupt status reported through the ISR structure.
+    interrupt_status: Arc<AtomicU32>,
+    /// Eventfds the guest notifies the queues through.
+    notify_eventfds: NotifyEventFds,
+    /// Virtio queues.
+    queues: Vec<Arc<Mutex<Queue>>>,
+    /// The function for interrupt triggering.
+    interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    /// The bus the device is attached to.
+    parent_bus: Weak<Mutex<PciBus>>,
+    /// Whether the device is part of a multi-function device.
+    multi_func: bool,
+    irq_chip: Arc<Mutex<InterruptController>>,
+}
+
+impl VirtioPciDevice {
+    /// Construct a new `VirtioPciDevice`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the device.
+    /// * `devfn` - Device/function number of the device on its parent bus.
+    /// * `sys_mem` - System address space.
+    /// * `device` - The virtio device behind the transport.
+    /// * `parent_bus` - The bus the device is attached to.
+    /// * `multi_func` - Whether the device is part of a multi-function device.
+    /// * `irq_chip` - Interrupt controller of the machine.
+    pub fn new(
+        name: String,
+        devfn: u8,
+        sys_mem: Arc<AddressSpace>,
+        device: Arc<Mutex<dyn VirtioDevice>>,
+        parent_bus: Weak<Mutex<PciBus>>,
+        multi_func: bool,
+        irq_chip: Arc<Mutex<InterruptController>>,
+    ) -> Self {
+        let queue_num = device.lock().unwrap().queue_num();
+        VirtioPciDevice {
+            name,
+            state: Arc::new(Mutex::new(VirtioPciState {
+                activated: false,
+                common_config: VirtioPciCommonConfig::new(&device, 0),
+            })),
+            device,
+            devfn,
+            config: PciConfig::new(PCIE_CONFIG_SPACE_SIZE, BAR_NUM_MAX_FOR_ENDPOINT),
+            sys_mem,
+            interrupt_status: Arc::new(AtomicU32::new(0)),
+            notify_eventfds: NotifyEventFds::new(queue_num),
+            queues: Vec::new(),
+            interrupt_cb: None,
+            parent_bus,
+            multi_func,
+            irq_chip,
+        }
+    }
+
+    fn init_config_header(&mut self) -> Result<()> {
+        let device_type = self.device.lock().unwrap().device_type();
+        let class_id = match device_type {
+            VIRTIO_TYPE_NET => VIRTIO_PCI_CLASS_ID_NET,
+            VIRTIO_TYPE_BLOCK => VIRTIO_PCI_CLASS_ID_BLOCK,
+            VIRTIO_TYPE_CONSOLE => VIRTIO_PCI_CLASS_ID_COMMUNICATION_OTHER,
+            _ => VIRTIO_PCI_CLASS_ID_OTHERS,
+        };
+
+        let config = &mut self.config.config;
+        le_write_u16(config, VENDOR_ID, VIRTIO_PCI_VENDOR_ID)?;
+        le_write_u16(
+            config,
+            DEVICE_ID,
+            VIRTIO_PCI_DEVICE_ID_BASE + device_type as u16,
+        )?;
+        config[REVISION_ID] = VIRTIO_PCI_ABI_VERSION;
+        le_write_u16(config, SUB_CLASS_CODE, class_id)?;
+        le_write_u16(config, SUBSYSTEM_VENDOR_ID, VIRTIO_PCI_VENDOR_ID)?;
+        le_write_u16(config, SUBSYSTEM_ID, VIRTIO_PCI_SUBSYSTEM_ID)?;
+        config[HEADER_TYPE] = if self.multi_func {
+            HEADER_TYPE_ENDPOINT | HEADER_TYPE_MULTIFUNC
+        } else {
+            HEADER_TYPE_ENDPOINT
+        };
+        Ok(())
+    }
+
+    /// Add a vendor-specific capability describing where a virtio structure
+    /// is in the BAR.
+    fn add_virtio_cap(&mut self, cfg_type: u8, offset: u64, length: u64) -> Result<()> {
+        let cap_size = if cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG {
+            VIRTIO_PCI_NOTIFY_CAP_SIZE
+        } else {
+            VIRTIO_PCI_CAP_SIZE
+        };
+        let cap_offset = self.config.add_pci_cap(CAP_ID_VNDR, cap_size)?;
+
+        let config = &mut self.config.config;
+        config[cap_offset + VIRTIO_PCI_CAP_LEN] = cap_size as u8;
+        config[cap_offset + VIRTIO_PCI_CAP_CFG_TYPE] = cfg_type;
+        config[cap_offset + VIRTIO_PCI_CAP_BAR] = VIRTIO_PCI_MEM_BAR_IDX as u8;
+        le_write_u32(config, cap_offset + VIRTIO_PCI_CAP_OFFSET, offset as u32)?;
+        le_write_u32(config, cap_offset + VIRTIO_PCI_CAP_LENGTH, length as u32)?;
+        if cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG {
+            le_write_u32(
+                config,
+                cap_offset + VIRTIO_PCI_CAP_NOTIFY_OFF_MULTIPLIER,
+                VIRTIO_PCI_NOTIFY_MULTIPLIER,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Build the region of one virtio structure, its accesses are forwarded
+    /// to the device as long as it exists.
+    fn init_structure_region(
+        dev: &Arc<Mutex<VirtioPciDevice>>,
+        read: fn(&mut VirtioPciDevice, &mut [u8], u64) -> bool,
+        write: fn(&mut VirtioPciDevice, &[u8], u64) -> bool,
+    ) -> Region {
+        let cloned_dev = Arc::downgrade(dev);
+        let read_ops = move |data: &mut [u8], _: GuestAddress, offset: u64| -> bool {
+            match cloned_dev.upgrade() {
+                Some(dev) => read(&mut dev.lock().unwrap(), data, offset),
+                None => false,
+            }
+        };
+
+        let cloned_dev = Arc::downgrade(dev);
+        let write_ops = move |data: &[u8], _: GuestAddress, offset: u64| -> bool {
+            match cloned_dev.upgrade() {
+                Some(dev) => write(&mut dev.lock().unwrap(), data, offset),
+                None => false,
+            }
+        };
+
+        let ops = RegionOps {
+            read: Arc::new(read_ops),
+            write: Arc::new(write_ops),
+        };
+        Region::init_io_region(VIRTIO_PCI_REGION_SIZE, ops)
+    }
+
+    /// Register the BAR holding the common, ISR, device and notification
+    /// structures.
+    fn init_modern_bar(dev: &Arc<Mutex<VirtioPciDevice>>) -> Result<()> {
+        let bar_region = Region::init_container_region(VIRTIO_PCI_BAR_SIZE);
+        let common_region = Self::init_structure_region(dev, Self::read_common, Self::write_common);
+        bar_region.add_subregion(common_region, VIRTIO_PCI_COMMON_OFFSET)?;
+        let isr_region = Self::init_structure_region(dev, Self::read_isr, Self::write_isr);
+        bar_region.add_subregion(isr_region, VIRTIO_PCI_ISR_OFFSET)?;
+        let device_region = Self::init_structure_region(dev, Self::read_device, Self::write_device);
+        bar_region.add_subregion(device_region, VIRTIO_PCI_DEVICE_OFFSET)?;
+        let notify_region = Self::init_structure_region(dev, Self::read_notify, Self::write_notify);
+        notify_region.set_ioeventfds(&dev.lock().unwrap().ioeventfds());
+        bar_region.add_subregion(notify_region, VIRTIO_PCI_NOTIFY_OFFSET)?;
+
+        dev.lock().unwrap().config.register_bar(
+            VIRTIO_PCI_MEM_BAR_IDX,
+            bar_region,
+            RegionType::Mem64Bit,
+            true,
+            VIRTIO_PCI_BAR_SIZE,
+        )
+    }
+
+    /// The queues are notified by writing their index at their notification
+    /// address, KVM signals the eventfds without exiting to the VMM.
+    fn ioeventfds(&self) -> Vec<RegionIoEventFd> {
+        let mut ret = Vec::new();
+        for (index, eventfd) in self.notify_eventfds.events.iter().enumerate() {
+            let addr = index as u64 * u64::from(VIRTIO_PCI_NOTIFY_MULTIPLIER);
+            ret.push(RegionIoEventFd {
+                fd: eventfd.clone(),
+                addr_range: AddressRange::from((addr, std::mem::size_of::<u16>() as u64)),
+                data_match: false,
+                data: index as u64,
+            })
+        }
+        ret
+    }
+
+    fn read_common(&mut self, data: &mut [u8], offset: u64) -> bool {
+        let value = match self
+            .state
+            .lock()
+            .unwrap()
+            .common_config
+            .read_common_config(&self.device, offset)
+        {
+            Ok(v) => v,
+            Err(ref e) => {
+                error!(
+                    "Failed to read common config {}, type: {}, {:?}",
+                    offset,
+                    self.device.lock().unwrap().device_type(),
+                    e,
+                );
+                return false;
+            }
+        };
+        match data.len() {
+            1 => data[0] = value as u8,
+            2 => LittleEndian::write_u16(data, value as u16),
+            4 => LittleEndian::write_u32(data, value),
+            _ => {
+                error!(
+                    "Invalid access size {} of common config {}",
+                    data.len(),
+                    offset
+                );
+                return false;
+            }
+        }
+        true
+    }
+
+    fn write_common(&mut self, data: &[u8], offset: u64) -> bool {
+        let value = match data.len() {
+            1 => u32::from(data[0]),
+            2 => u32::from(LittleEndian::read_u16(data)),
+            4 => LittleEndian::read_u32(data),
+            _ => {
+                error!(
+                    "Invalid access size {} of common config {}",
+                    data.len(),
+                    offset
+                );
+                return false;
+            }
+        };
+
+        let mut locked_state = self.state.lock().unwrap();
+        if let Err(ref e) =
+            locked_state
+                .common_config
+                .write_common_config(&self.device, offset, value)
+        {
+            error!(
+                "Failed to write common config {}, type: {}, {:?}",
+                offset,
+                self.device.lock().unwrap().device_type(),
+                e,
+            );
+            return false;
+        }
+        if offset != COMMON_STATUS_REG {
+            return true;
+        }
+
+        // Writing 0 to the device status resets the device.
+        if value == 0 {
+            drop(locked_state);
+            if let Err(ref e) = self.reset_virtio() {
+                error!("Failed to reset virtio-pci device {}, {:?}", self.name, e);
+                return false;
+            }
+            return true;
+        }
+        if locked_state.common_config.check_device_status(
+            CONFIG_STATUS_ACKNOWLEDGE
+                | CONFIG_STATUS_DRIVER
+                | CONFIG_STATUS_DRIVER_OK
+                | CONFIG_STATUS_FEATURES_OK,
+            CONFIG_STATUS_FAILED,
+        ) && !locked_state.activated
+        {
+            drop(locked_state);
+            if let Err(ref e) = self.activate() {
+                error!(
+                    "Failed to activate dev, type: {}, {:?}",
+                    self.device.lock().unwrap().device_type(),
+                    e,
+                );
+                return false;
+            }
+            self.state.lock().unwrap().activated = true;
+        }
+        true
+    }
+
+    fn read_isr(&mut self, data: &mut [u8], offset: u64) -> bool {
+        data.fill(0);
+        if offset != 0 || data.is_empty() {
+            return true;
+        }
+        // Reading the ISR status clears it and deasserts INTx, both under
+        // the pin lock so that no interrupt raised meanwhile is lost.
+        let intx = self.config.intx.clone();
+        let locked_intx = intx.as_ref().map(|intx| intx.lock().unwrap());
+        data[0] = self.interrupt_status.swap(0, Ordering::SeqCst) as u8;
+        if let Some(mut locked_intx) = locked_intx {
+            locked_intx.notify(0);
+        }
+        true
+    }
+
+    fn write_isr(&mut self, _data: &[u8], _offset: u64) -> bool {
+        // The ISR status is read-only.
+        true
+    }
+
+    fn read_device(&mut self, data: &mut [u8], offset: u64) -> bool {
+        if let Err(ref e) = self.device.lock().unwrap().read_config(offset, data) {
+            error!(
+                "Failed to read virtio-dev config space {} type: {} {:?}",
+                offset,
+                self.device.lock().unwrap().device_type(),
+                e,
+            );
+            return false;
+        }
+        true
+    }
+
+    fn write_device(&mut self, data: &[u8], offset: u64) -> bool {
+        let device_status = self.state.lock().unwrap().common_config.device_status;
+        if device_status & (CONFIG_STATUS_DRIVER | CONFIG_STATUS_FAILED) != CONFIG_STATUS_DRIVER {
+            error!(
+                "Failed to write virtio-dev config space: driver is not ready 0x{:X}, type: {}",
+                device_status,
+                self.device.lock().unwrap().device_type(),
+            );
+            return false;
+        }
+        if let Err(ref e) = self.device.lock().unwrap().write_config(offset, data) {
+            error!(
+                "Failed to write virtio-dev config space {}, type: {}, {:?}",
+                offset,
+                self.device.lock().unwrap().device_type(),
+                e,
+            );
+            return false;
+        }
+        true
+    }
+
+    fn read_notify(&mut self, data: &mut [u8], _offset: u64) -> bool {
+        data.fill(0);
+        true
+    }
+
+    fn write_notify(&mut self, _data: &[u8], offset: u64) -> bool {
+        // Only reached when the notification isn't handled by KVM through
+        // the ioeventfds.
+        let index = (offset / u64::from(VIRTIO_PCI_NOTIFY_MULTIPLIER)) as usize;
+        match self.notify_eventfds.events.get(index) {
+            Some(eventfd) => {
+                if let Err(ref e) = eventfd.write(1) {
+                    error!("Failed to notify queue {} of {}, {:?}", index, self.name, e);
+                    return false;
+                }
+            }
+            None => warn!("Notification of invalid queue {} of {}", index, self.name),
+        }
+        true
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(&mut self) -> Result<()> {
+        let mut locked_state = self.state.lock().unwrap();
+        let queue_type = locked_state.common_config.queue_type;
+        let cloned_mem_space = self.sys_mem.clone();
+        for q_config in locked_state.common_config.queues_config.iter_mut() {
+            q_config.addr_cache.desc_table_host = cloned_mem_space
+                .get_host_address(q_config.desc_table)
+                .unwrap_or(0);
+            q_config.addr_cache.avail_ring_host = cloned_mem_space
+                .get_host_address(q_config.avail_ring)
+                .unwrap_or(0);
+            q_config.addr_cache.used_ring_host = cloned_mem_space
+                .get_host_address(q_config.used_ring)
+                .unwrap_or(0);
+            let queue = Queue::new(*q_config, queue_type)?;
+            if !queue.is_valid(&self.sys_mem) {
+                bail!("Invalid queue");
+            }
+            self.queues.push(Arc::new(Mutex::new(queue)));
+        }
+        drop(locked_state);
+
+        let mut events = Vec::new();
+        for index in 0..self.device.lock().unwrap().queue_num() {
+            let event = EventFd::new(libc::EFD_NONBLOCK)
+                .with_context(|| format!("Failed to create guest notifier {}", index))?;
+            events.push(Arc::new(event));
+        }
+
+        self.device.lock().unwrap().set_guest_notifiers(&events)?;
+
+        if let Some(cb) = self.interrupt_cb.clone() {
+            self.device.lock().unwrap().activate(
+                self.sys_mem.clone(),
+                cb,
+                &self.queues,
+                self.notify_eventfds.events.clone(),
+            )?;
+        } else {
+            bail!("Failed to activate device: No interrupt callback");
+        }
+
+        Ok(())
+    }
+
+    /// Deactivate and reset the virtio device, as the driver asks for by
+    /// writing 0 to the device status.
+    fn reset_virtio(&mut self) -> Result<()> {
+        if self.state.lock().unwrap().activated {
+            self.device
+                .lock()
+                .unwrap()
+                .deactivate()
+                .with_context(|| "Failed to deactivate virtio device")?;
+        }
+        self.device
+            .lock()
+            .unwrap()
+            .reset()
+            .with_context(|| "Failed to reset virtio device")?;
+
+        self.queues.clear();
+        self.interrupt_status.store(0, Ordering::SeqCst);
+        self.config.set_intx_level(0);
+        let mut locked_state = self.state.lock().unwrap();
+        let msix_vectors = locked_state.common_config.msix_vectors;
+        locked_state.activated = false;
+        locked_state.common_config = VirtioPciCommonConfig::new(&self.device, msix_vectors);
+        Ok(())
+    }
+
+    fn assign_interrupt_cb(&mut self) {
+        let interrupt_status = self.interrupt_status.clone();
+        let cloned_state = self.state.clone();
+        let msix = self.config.msix.clone();
+        let intx = self.config.intx.clone();
+        let cb = Arc::new(Box::new(
+            move |int_type: &VirtioInterruptType, queue: Option<&Queue>, needs_reset: bool| {
+                let vector = match int_type {
+                    VirtioInterruptType::Config => {
+                        let mut locked_state = cloned_state.lock().unwrap();
+                        let common_config = &mut locked_state.common_config;
+                        if needs_reset {
+                            common_config.device_status |= CONFIG_STATUS_NEEDS_RESET;
+                            if common_config.device_status & CONFIG_STATUS_DRIVER_OK == 0 {
+                                return Ok(());
+                            }
+                        }
+                        // The configuration atomicity value is 8-bit wide.
+                        common_config.config_generation =
+                            (common_config.config_generation + 1) & 0xff;
+                        common_config.msix_config
+                    }
+                    VirtioInterruptType::Vring => {
+                        queue.map_or(INVALID_VECTOR_NUM, |q| q.vring.get_queue_config().vector)
+                    }
+                };
+
+                if let Some(msix) = &msix {
+                    let mut locked_msix = msix.lock().unwrap();
+                    if locked_msix.enabled() {
+                        if vector != INVALID_VECTOR_NUM {
+                            locked_msix.notify(vector);
+                        }
+                        return Ok(());
+                    }
+                }
+
+                let status = match int_type {
+                    VirtioInterruptType::Config => VIRTIO_PCI_ISR_CONFIG,
+                    VirtioInterruptType::Vring => VIRTIO_PCI_ISR_QUEUE,
+                };
+                let locked_intx = intx.as_ref().map(|intx| intx.lock().unwrap());
+                interrupt_status.fetch_or(status, Ordering::SeqCst);
+                if let Some(mut locked_intx) = locked_intx {
+                    locked_intx.notify(1);
+                }
+                Ok(())
+            },
+        ) as VirtioInterrupt);
+
+        self.interrupt_cb = Some(cb);
+    }
+
+    fn parent_mem_region(&self) -> Option<Region> {
+        self.parent_bus
+            .upgrade()
+            .map(|bus| bus.lock().unwrap().mem_region.clone())
+    }
+}
+
+impl PciDevOps for VirtioPciDevice {
+    fn init_write_mask(&mut self) -> Result<()> {
+        self.config.init_common_write_mask()
+    }
+
+    fn init_write_clear_mask(&mut self) -> Result<()> {
+        self.config.init_common_write_clear_mask()
+    }
+
+    fn realize(mut self) -> Result<()> {
+        self.init_write_mask()?;
+        self.init_write_clear_mask()?;
+        self.init_config_header()?;
+        self.device
+            .lock()
+            .unwrap()
+            .realize()
+            .with_context(|| "Failed to realize virtio.")?;
+
+        let parent_bus = self
+            .parent_bus
+            .upgrade()
+            .with_context(|| format!("The parent bus of {} is gone", self.name))?;
+        let intx_state = parent_bus.lock().unwrap().intx_state.clone();
+        if let Some(intx_state) = intx_state {
+            self.config.config[INTERRUPT_PIN] = INTERRUPT_PIN_INTA;
+            self.config.intx = Some(Arc::new(Mutex::new(Intx::new(self.devfn, 0, intx_state))));
+        }
+        // MSI-X needs an interrupt controller which takes messages, the
+        // device falls back to INTx otherwise.
+        if self.irq_chip.lock().unwrap().msi_supported() {
+            // A vector for each queue plus one for configuration changes.
+            let msix_vectors = self.device.lock().unwrap().queue_num() as u16 + 1;
+            init_msix(
+                VIRTIO_PCI_MSIX_BAR_IDX,
+                msix_vectors,
+                &mut self.config,
+                self.irq_chip.clone(),
+            )?;
+            self.state.lock().unwrap().common_config.msix_vectors = msix_vectors;
+        }
+        self.add_virtio_cap(
+            VIRTIO_PCI_CAP_COMMON_CFG,
+            VIRTIO_PCI_COMMON_OFFSET,
+            VIRTIO_PCI_REGION_SIZE,
+        )?;
+        self.add_virtio_cap(
+            VIRTIO_PCI_CAP_ISR_CFG,
+            VIRTIO_PCI_ISR_OFFSET,
+            VIRTIO_PCI_REGION_SIZE,
+        )?;
+        self.add_virtio_cap(
+            VIRTIO_PCI_CAP_DEVICE_CFG,
+            VIRTIO_PCI_DEVICE_OFFSET,
+            VIRTIO_PCI_REGION_SIZE,
+        )?;
+        self.add_virtio_cap(
+            VIRTIO_PCI_CAP_NOTIFY_CFG,
+            VIRTIO_PCI_NOTIFY_OFFSET,
+            VIRTIO_PCI_REGION_SIZE,
+        )?;
+        self.assign_interrupt_cb();
+
+        let devfn = self.devfn;
+        let dev = Arc::new(Mutex::new(self));
+        Self::init_modern_bar(&dev)?;
+        let mut locked_bus = parent_bus.lock().unwrap();
+        locked_bus.attach_device(devfn, dev)
+    }
+
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        self.config.read(offset, data);
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        let mem_region = self.parent_mem_region();
+        self.config.write(offset, data, mem_region.as_ref());
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn devfn(&self) -> Option<u8> {
+        Some(self.devfn)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.reset_virtio()?;
+        let mem_region = self.parent_mem_region();
+        self.config.reset_common_regs(mem_region.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use devices::{InterruptControllerConfig, InterruptControllerVersion};
+    use sysbus::SysBus;
+    use util::num_ops::read_u32;
+
+    use super::*;
+
+    const VIRTIO_DEVICE_TEST_TYPE: u32 = 1;
+    const VIRTIO_DEVICE_QUEUE_NUM: usize = 2;
+    const VIRTIO_DEVICE_QUEUE_SIZE: u16 = 256;
+    const VIRTIO_DEVICE_FEATURES: u64 = 1_u64 << VIRTIO_F_RING_PACKED | 0x20;
+
+    struct VirtioDeviceTest {
+        device_features: u64,
+        driver_features: u64,
+    }
+
+    impl VirtioDevice for VirtioDeviceTest {
+        fn realize(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn device_type(&self) -> u32 {
+            VIRTIO_DEVICE_TEST_TYPE
+        }
+
+        fn queue_num(&self) -> usize {
+            VIRTIO_DEVICE_QUEUE_NUM
+        }
+
+        fn queue_size(&self) -> u16 {
+            VIRTIO_DEVICE_QUEUE_SIZE
+        }
+
+        fn get_device_features(&self, features_select: u32) -> u32 {
+            read_u32(self.device_features, features_select)
+        }
+
+        fn set_driver_features(&mut self, page: u32, value: u32) {
+            self.driver_features = self.checked_driver_features(page, value);
+        }
+
+        fn get_driver_features(&self, features_select: u32) -> u32 {
+            read_u32(self.driver_features, features_select)
+        }
+
+        fn read_config(&self, _offset: u64, _data: &mut [u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn write_config(&mut self, _offset: u64, _data: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn activate(
+            &mut self,
+            _mem_space: Arc<AddressSpace>,
+            _interrupt_cb: Arc<VirtioInterrupt>,
+            _queues: &[Arc<Mutex<Queue>>],
+            _queue_evts: Vec<Arc<EventFd>>,
+        ) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_device() -> Arc<Mutex<dyn VirtioDevice>> {
+        Arc::new(Mutex::new(VirtioDeviceTest {
+            device_features: VIRTIO_DEVICE_FEATURES,
+            driver_features: 0,
+        }))
+    }
+
+    fn pci_device_init() -> VirtioPciDevice {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        // An APLIC emulated in userspace, so that no vcpu is needed.
+        let mut sysbus = SysBus::new(&sys_mem, (1, 8), (0x1000_0000, 0x2000_0000));
+        let intc_conf = InterruptControllerConfig {
+            version: Some(InterruptControllerVersion::APLIC),
+            vcpu_count: 1,
+            region_base: 0x0c00_0000,
+            region_size: 0x8000,
+            imsic_base: 0,
+            imsic_size: 0,
+        };
+        let irq_chip = InterruptController::new(Vec::new(), &mut sysbus, &intc_conf).unwrap();
+        VirtioPciDevice::new(
+            "virtio-pci".to_string(),
+            0,
+            sys_mem,
+            test_device(),
+            Weak::new(),
+            false,
+            Arc::new(Mutex::new(irq_chip)),
+        )
+    }
+
+    fn common_read(dev: &mut VirtioPciDevice, offset: u64, len: usize) -> u32 {
+        let mut data = [0_u8; 4];
+        assert!(dev.read_common(&mut data[..len], offset));
+        LittleEndian::read_u32(&data)
+    }
+
+    fn common_write(dev: &mut VirtioPciDevice, offset: u64, len: usize, value: u32) -> bool {
+        let mut data = [0_u8; 4];
+        LittleEndian::write_u32(&mut data, value);
+        dev.write_common(&data[..len], offset)
+    }
+
+    #[test]
+    fn test_common_config_features() {
+        let device = test_device();
+        let mut cmn_cfg = VirtioPciCommonConfig::new(&device, 0);
+
+        // The device features are read a page at a time, VERSION_1 is always
+        // offered in the high page.
+        cmn_cfg
+            .write_common_config(&device, COMMON_DFSELECT_REG, 0)
+            .unwrap();
+        assert_eq!(
+            cmn_cfg.read_common_config(&device, COMMON_DF_REG).unwrap(),
+            0x20
+        );
+        cmn_cfg
+            .write_common_config(&device, COMMON_DFSELECT_REG, 1)
+            .unwrap();
+        assert_eq!(
+            cmn_cfg
+                .read_common_config(&device, COMMON_DFSELECT_REG)
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            cmn_cfg.read_common_config(&device, COMMON_DF_REG).unwrap(),
+            (VIRTIO_DEVICE_FEATURES >> 32) as u32 | 0x1
+        );
+
+        // The driver features can only be written between DRIVER and FEATURES_OK.
+        cmn_cfg
+            .write_common_config(&device, COMMON_GFSELECT_REG, 1)
+            .unwrap();
+        let packed = 1_u32 << (VIRTIO_F_RING_PACKED - 32);
+        assert!(cmn_cfg
+            .write_common_config(&device, COMMON_GF_REG, packed)
+            .is_err());
+        cmn_cfg.device_status = CONFIG_STATUS_ACKNOWLEDGE | CONFIG_STATUS_DRIVER;
+        cmn_cfg
+            .write_common_config(&device, COMMON_GF_REG, packed)
+            .unwrap();
+        assert_eq!(
+            cmn_cfg.read_common_config(&device, COMMON_GF_REG).unwrap(),
+            packed
+        );
+        assert_eq!(cmn_cfg.queue_type, QUEUE_TYPE_PACKED_VRING);
+
+        // Features the device doesn't offer are dropped.
+        cmn_cfg
+            .write_common_config(&device, COMMON_GFSELECT_REG, 0)
+            .unwrap();
+        cmn_cfg
+            .write_common_config(&device, COMMON_GF_REG, 0x21)
+            .unwrap();
+        assert_eq!(
+            cmn_cfg.read_common_config(&device, COMMON_GF_REG).unwrap(),
+            0x20
+        );
+
+        cmn_cfg.device_status |= CONFIG_STATUS_FEATURES_OK;
+        assert!(cmn_cfg
+            .write_common_config(&device, COMMON_GF_REG, 0)
+            .is_err());
+        assert_eq!(
+            cmn_cfg.read_common_config(&device, COMMON_GF_REG).unwrap(),
+            0x20
+        );
+    }
+
+    #[test]
+    fn test_common_config_queue_setup() {
+        let mut dev = pci_device_init();
+
+        assert_eq!(
+            common_read(&mut dev, COMMON_NUMQ_REG, 2),
+            VIRTIO_DEVICE_QUEUE_NUM as u32
+        );
+        assert!(common_write(&mut dev, COMMON_Q_SELECT_REG, 2, 1));
+        assert_eq!(common_read(&mut dev, COMMON_Q_SELECT_REG, 2), 1);
+        assert_eq!(
+            common_read(&mut dev, COMMON_Q_SIZE_REG, 2),
+            u32::from(VIRTIO_DEVICE_QUEUE_SIZE)
+        );
+        assert_eq!(common_read(&mut dev, COMMON_Q_NOFF_REG, 2), 1);
+
+        // The queues can't be set up before the features are negotiated.
+        assert!(!common_write(&mut dev, COMMON_Q_SIZE_REG, 2, 128));
+        let status = CONFIG_STATUS_ACKNOWLEDGE | CONFIG_STATUS_DRIVER | CONFIG_STATUS_FEATURES_OK;
+        assert!(common_write(&mut dev, COMMON_STATUS_REG, 1, status));
+        assert_eq!(common_read(&mut dev, COMMON_STATUS_REG, 1), status);
+
+        assert!(common_write(&mut dev, COMMON_Q_SIZE_REG, 2, 128));
+        assert!(common_write(&mut dev, COMMON_Q_DESCLO_REG, 4, 0x2000));
+        assert!(common_write(&mut dev, COMMON_Q_DESCHI_REG, 4, 0x1));
+        assert!(common_write(&mut dev, COMMON_Q_AVAILLO_REG, 4, 0x3000));
+        assert!(common_write(&mut dev, COMMON_Q_AVAILHI_REG, 4, 0x1));
+        assert!(common_write(&mut dev, COMMON_Q_USEDLO_REG, 4, 0x4000));
+        assert!(common_write(&mut dev, COMMON_Q_USEDHI_REG, 4, 0x1));
+        assert!(common_write(&mut dev, COMMON_Q_ENABLE_REG, 2, 1));
+        assert_eq!(common_read(&mut dev, COMMON_Q_SIZE_REG, 2), 128);
+        assert_eq!(common_read(&mut dev, COMMON_Q_DESCLO_REG, 4), 0x2000);
+        assert_eq!(common_read(&mut dev, COMMON_Q_DESCHI_REG, 4), 0x1);
+        assert_eq!(common_read(&mut dev, COMMON_Q_AVAILLO_REG, 4), 0x3000);
+        assert_eq!(common_read(&mut dev, COMMON_Q_AVAILHI_REG, 4), 0x1);
+        assert_eq!(common_read(&mut dev, COMMON_Q_USEDLO_REG, 4), 0x4000);
+        assert_eq!(common_read(&mut dev, COMMON_Q_USEDHI_REG, 4), 0x1);
+        assert_eq!(common_read(&mut dev, COMMON_Q_ENABLE_REG, 2), 1);
+
+        let locked_state = dev.state.lock().unwrap();
+        let queues_config = &locked_state.common_config.queues_config;
+        assert_eq!(queues_config[0].size, VIRTIO_DEVICE_QUEUE_SIZE);
+        assert!(!queues_config[0].ready);
+        assert_eq!(queues_config[1].size, 128);
+        assert_eq!(queues_config[1].desc_table, GuestAddress(0x1_0000_2000));
+        assert_eq!(queues_config[1].avail_ring, GuestAddress(0x1_0000_3000));
+        assert_eq!(queues_config[1].used_ring, GuestAddress(0x1_0000_4000));
+        assert!(queues_config[1].ready);
+        drop(locked_state);
+
+        // A queue beyond the last one can't be accessed.
+        assert!(common_write(&mut dev, COMMON_Q_SELECT_REG, 2, 2));
+        let mut data = [0_u8; 2];
+        assert!(!dev.read_common(&mut data, COMMON_Q_SIZE_REG));
+        assert!(!common_write(&mut dev, COMMON_Q_SIZE_REG, 2, 128));
+
+        // Invalid access sizes and offsets are refused.
+        let mut data = [0_u8; 8];
+        assert!(!dev.read_common(&mut data, COMMON_Q_DESCLO_REG));
+        assert!(!common_write(&mut dev, 0x38, 4, 0));
+    }
+
+    #[test]
+    fn test_isr_read_to_clear() {
+        let mut dev = pci_device_init();
+        dev.assign_interrupt_cb();
+        let cb = dev.interrupt_cb.clone().unwrap();
+
+        let mut isr = [0_u8; 1];
+        assert!(dev.read_isr(&mut isr, 0));
+        assert_eq!(isr[0], 0);
+
+        // Without MSI-X the interrupts are reported through the ISR status.
+        cb(&VirtioInterruptType::Config, None, false).unwrap();
+        cb(&VirtioInterruptType::Vring, None, false).unwrap();
+        assert_eq!(dev.state.lock().unwrap().common_config.config_generation, 1);
+        assert!(dev.read_isr(&mut isr, 1));
+        assert_eq!(isr[0], 0);
+        assert!(dev.write_isr(&[0], 0));
+        assert!(dev.read_isr(&mut isr, 0));
+        assert_eq!(isr[0] as u32, VIRTIO_PCI_ISR_CONFIG | VIRTIO_PCI_ISR_QUEUE);
+        assert!(dev.read_isr(&mut isr, 0));
+        assert_eq!(isr[0], 0);
+
+        // A device needing reset isn't reported before the driver is ready.
+        cb(&VirtioInterruptType::Config, None, true).unwrap();
+        assert!(dev.read_isr(&mut isr, 0));
+        assert_eq!(isr[0], 0);
+        let status = dev.state.lock().unwrap().common_config.device_status;
+        assert_eq!(status, CONFIG_STATUS_NEEDS_RESET);
+    }
+
+    #[test]
+    fn test_notify_dispatch() {
+        let mut dev = pci_device_init();
+
+        let notify_off = u64::from(VIRTIO_PCI_NOTIFY_MULTIPLIER);
+        assert!(dev.write_notify(&[1, 0], notify_off));
+        assert!(dev.notify_eventfds.events[0].read().is_err());
+        assert_eq!(dev.notify_eventfds.events[1].read().unwrap(), 1);
+
+        assert!(dev.write_notify(&[0, 0], 0));
+        assert!(dev.write_notify(&[0, 0], 0));
+        assert_eq!(dev.notify_eventfds.events[0].read().unwrap(), 2);
+        assert!(dev.notify_eventfds.events[1].read().is_err());
+
+        // Notifications of queues the device hasn't are dropped.
+        assert!(dev.write_notify(&[2, 0], 2 * notify_off));
+        for eventfd in dev.notify_eventfds.events.iter() {
+            assert!(eventfd.read().is_err());
+        }
+
+        // The ioeventfds match the notify offsets of the queues.
+        let ioeventfds = dev.ioeventfds();
+        assert_eq!(ioeventfds.len(), VIRTIO_DEVICE_QUEUE_NUM);
+        assert_eq!(ioeventfds[1].addr_range.base, GuestAddress(notify_off));
+        assert_eq!(ioeventfds[1].data, 1);
+    }
+
+    #[test]
+    fn test_msix_vector_assignment() {
+        let mut dev = pci_device_init();
+
+        // No vector can be assigned while the device has no MSI-X.
+        assert!(common_write(&mut dev, COMMON_MSIX_REG, 2, 0));
+        assert_eq!(
+            common_read(&mut dev, COMMON_MSIX_REG, 2),
+            u32::from(INVALID_VECTOR_NUM)
+        );
+
+        let msix_vectors = VIRTIO_DEVICE_QUEUE_NUM as u16 + 1;
+        init_msix(
+            VIRTIO_PCI_MSIX_BAR_IDX,
+            msix_vectors,
+            &mut dev.config,
+            dev.irq_chip.clone(),
+        )
+        .unwrap();
+        dev.state.lock().unwrap().common_config.msix_vectors = msix_vectors;
+
+        assert!(common_write(&mut dev, COMMON_MSIX_REG, 2, 2));
+        assert_eq!(common_read(&mut dev, COMMON_MSIX_REG, 2), 2);
+        assert!(common_write(&mut dev, COMMON_MSIX_REG, 2, 3));
+        assert_eq!(
+            common_read(&mut dev, COMMON_MSIX_REG, 2),
+            u32::from(INVALID_VECTOR_NUM)
+        );
+
+        let status = CONFIG_STATUS_ACKNOWLEDGE | CONFIG_STATUS_DRIVER | CONFIG_STATUS_FEATURES_OK;
+        assert!(common_write(&mut dev, COMMON_STATUS_REG, 1, status));
+        assert!(common_write(&mut dev, COMMON_Q_SELECT_REG, 2, 1));
+        assert!(common_write(&mut dev, COMMON_Q_MSIX_REG, 2, 1));
+        assert_eq!(common_read(&mut dev, COMMON_Q_MSIX_REG, 2), 1);
+        let vector = u32::from(msix_vectors);
+        assert!(common_write(&mut dev, COMMON_Q_MSIX_REG, 2, vector));
+        assert_eq!(
+            common_read(&mut dev, COMMON_Q_MSIX_REG, 2),
+            u32::from(INVALID_VECTOR_NUM)
+        );
+        assert!(common_write(&mut dev, COMMON_Q_MSIX_REG, 2, 1));
+        assert!(common_write(&mut dev, COMMON_MSIX_REG, 2, 2));
+        let locked_state = dev.state.lock().unwrap();
+        assert_eq!(locked_state.common_config.queues_config[1].vector, 1);
+        assert_eq!(locked_state.common_config.msix_config, 2);
+        drop(locked_state);
+
+        // Once the guest enables MSI-X, the ISR status isn't used anymore.
+        let msix = dev.config.msix.clone().unwrap();
+        let cap_offset = msix.lock().unwrap().cap_offset();
+        dev.config.write(cap_offset + 2, &[0x00, 0xc0], None);
+        assert!(msix.lock().unwrap().enabled());
+        dev.assign_interrupt_cb();
+        let cb = dev.interrupt_cb.clone().unwrap();
+        cb(&VirtioInterruptType::Config, None, false).unwrap();
+        cb(&VirtioInterruptType::Vring, None, false).unwrap();
+        assert_eq!(dev.state.lock().unwrap().common_config.config_generation, 1);
+        let mut isr = [0_u8; 1];
+        assert!(dev.read_isr(&mut isr, 0));
+        assert_eq!(isr[0], 0);
+
+        // Resetting the device keeps the number of vectors.
+        dev.reset_virtio().unwrap();
+        let locked_state = dev.state.lock().unwrap();
+        assert_eq!(locked_state.common_config.msix_vectors, msix_vectors);
+        assert_eq!(locked_state.common_config.msix_config, INVALID_VECTOR_NUM);
+    }
+}
@@ -10,6 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+mod packed;
 mod split;
 
 use address_space::{AddressSpace, GuestAddress, RegionCache};
@@ -18,6 +19,7 @@ use std::sync::Arc;
 use vmm_sys_util::eventfd::EventFd;
 
 use crate::VirtioError;
+pub use packed::*;
 pub use split::*;
 
 /// Split Virtqueue.
@@ -166,7 +168,7 @@ pub trait VringOps {
     /// Get the avail index of the vring.
     fn get_avail_idx(&self, sys_mem: &Arc<AddressSpace>) -> Result<u16>;
 
-    /// Get the region cache information of the SplitVring.
+    /// Get the region cache information of the vring.
     fn get_cache(&self) -> &Option<RegionCache>;
 }
 
@@ -186,6 +188,7 @@ impl Queue {
     pub fn new(queue_config: QueueConfig, queue_type: u16) -> Result<Self> {
         let vring: Box<dyn VringOps + Send> = match queue_type {
             QUEUE_TYPE_SPLIT_VRING => Box::new(SplitVring::new(queue_config)),
+            QUEUE_TYPE_PACKED_VRING => Box::new(PackedVring::new(queue_config)),
             _ => {
                 bail!("Unsupported queue type {}", queue_type);
             }
@@ -0,0 +1,951 @@
+// Copyright (c) 2023 China Telecom Co.,Ltd. All rights reserved.
+//
+// TeleVM is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+//
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp::min;
+use std::mem::size_of;
+use std::num::Wrapping;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
+use address_space::{AddressSpace, GuestAddress, RegionCache, RegionType};
+use anyhow::{anyhow, bail, Context, Result};
+use log::error;
+use util::byte_code::ByteCode;
+
+use super::{
+    checked_offset_mem, ElemIovec, Element, QueueConfig, VringOps, VIRTQ_DESC_F_INDIRECT,
+    VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
+};
+use crate::{virtio_has_feature, VirtioError, VIRTIO_F_RING_EVENT_IDX};
+
+/// Marks a descriptor as available, together with the wrap counter of the driver.
+const VRING_PACKED_DESC_F_AVAIL: u16 = 1 << 7;
+/// Marks a descriptor as used, together with the wrap counter of the device.
+const VRING_PACKED_DESC_F_USED: u16 = 1 << 15;
+
+/// Enable events.
+const VRING_PACKED_EVENT_FLAG_ENABLE: u16 = 0x0;
+/// Disable events.
+const VRING_PACKED_EVENT_FLAG_DISABLE: u16 = 0x1;
+/// Only enable events for the descriptor at the offset and wrap counter of
+/// `off_wrap`, valid if VIRTIO_F_RING_EVENT_IDX is negotiated.
+const VRING_PACKED_EVENT_FLAG_DESC: u16 = 0x2;
+/// The bit of the wrap counter in `off_wrap`.
+const VRING_PACKED_EVENT_WRAP_SHIFT: u16 = 15;
+
+/// Max total len of a descriptor chain.
+const DESC_CHAIN_MAX_TOTAL_LEN: u64 = 1u64 << 32;
+/// The length of virtio descriptor.
+const DESCRIPTOR_LEN: u64 = size_of::<PackedVringDesc>() as u64;
+/// The position of flags in the descriptor.
+const DESC_FLAGS_POSITION: u64 = 14;
+/// The length of the event suppression structure.
+const EVENT_SUPPRESS_LEN: u64 = size_of::<PackedVringEvent>() as u64;
+
+/// Descriptor of packed vring.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct PackedVringDesc {
+    /// Address (guest-physical).
+    pub addr: GuestAddress,
+    /// Length.
+    pub len: u32,
+    /// Buffer id, taken from the last descriptor of a chain.
+    pub id: u16,
+    /// The flags as indicated above.
+    pub flags: u16,
+}
+
+impl ByteCode for PackedVringDesc {}
+
+impl PackedVringDesc {
+    /// Return true if the driver made the descriptor available in the lap of
+    /// `wrap_counter`.
+    fn is_avail(flags: u16, wrap_counter: bool) -> bool {
+        let avail = flags & VRING_PACKED_DESC_F_AVAIL != 0;
+        let used = flags & VRING_PACKED_DESC_F_USED != 0;
+        avail == wrap_counter && used != wrap_counter
+    }
+
+    /// Return true if the descriptor is valid.
+    fn is_valid(&self, sys_mem: &Arc<AddressSpace>, cache: &mut Option<RegionCache>) -> bool {
+        if self.len == 0 {
+            error!("Zero sized buffers are not allowed");
+            return false;
+        }
+        let mut miss_cached = true;
+        if let Some(reg_cache) = cache {
+            let base = self.addr.0;
+            let offset = self.len as u64;
+            if base > reg_cache.start && base + offset < reg_cache.end {
+                if base.checked_add(offset).is_none() {
+                    error!("The memory of descriptor is invalid, range overflows");
+                    return false;
+                }
+                miss_cached = false;
+            }
+        } else {
+            let gotten_cache = sys_mem.get_region_cache(self.addr);
+            if let Some(obtained_cache) = gotten_cache {
+                if obtained_cache.reg_type == RegionType::Ram {
+                    *cache = gotten_cache;
+                }
+            }
+        }
+
+        if miss_cached {
+            if let Err(ref e) = checked_offset_mem(sys_mem, self.addr, u64::from(self.len)) {
+                error!("The memory of descriptor is invalid, {:?} ", e);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Return true if this descriptor has next descriptor.
+    fn has_next(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_NEXT != 0
+    }
+
+    /// Check whether this descriptor is write-only or read-only.
+    /// Write-only means that the emulated device can write and the driver can read.
+    fn write_only(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_WRITE != 0
+    }
+
+    /// Return true if this descriptor is a indirect descriptor.
+    fn is_indirect_desc(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_INDIRECT != 0
+    }
+
+    /// Return true if the indirect descriptor is valid.
+    /// The len can be divided evenly by the size of descriptor and can not be zero.
+    fn is_valid_indirect_desc(&self) -> bool {
+        if self.len == 0 || u64::from(self.len) % DESCRIPTOR_LEN != 0 {
+            error!("The indirect descriptor is invalid, len: {}", self.len);
+            return false;
+        }
+        if self.has_next() {
+            error!("INDIRECT and NEXT flag should not be used together");
+            return false;
+        }
+        true
+    }
+}
+
+/// Event suppression structure of packed vring, the driver area holds the
+/// one written by the driver and the device area the one written by the device.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct PackedVringEvent {
+    /// Offset of the descriptor and wrap counter to be notified of.
+    off_wrap: u16,
+    /// Events enabled, disabled or limited to `off_wrap`.
+    flags: u16,
+}
+
+impl ByteCode for PackedVringEvent {}
+
+/// Packed vring, the driver and the device share a single descriptor ring:
+/// the driver makes descriptors available in place, and the device
+/// overwrites them with used descriptors in the order the buffers are used.
+#[derive(Default, Clone)]
+pub struct PackedVring {
+    /// Region cache information.
+    cache: Option<RegionCache>,
+    /// The configuration of virtqueue. `avail_ring` is the driver area and
+    /// `used_ring` is the device area.
+    queue_config: QueueConfig,
+    /// The number of ring descriptors taken by each buffer in flight,
+    /// indexed by buffer id.
+    chain_slots: Vec<u16>,
+    /// The number of ring descriptors taken by the last popped buffer.
+    last_chain_slots: u16,
+}
+
+impl Deref for PackedVring {
+    type Target = QueueConfig;
+    fn deref(&self) -> &Self::Target {
+        &self.queue_config
+    }
+}
+
+impl DerefMut for PackedVring {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.queue_config
+    }
+}
+
+impl PackedVring {
+    /// Create a packed vring.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue_config` - Configuration of the vring.
+    pub fn new(queue_config: QueueConfig) -> Self {
+        let size = min(queue_config.size, queue_config.max_size);
+        PackedVring {
+            cache: None,
+            queue_config,
+            chain_slots: vec![0; size as usize],
+            last_chain_slots: 0,
+        }
+    }
+
+    /// The actual size of the queue.
+    fn actual_size(&self) -> u16 {
+        min(self.size, self.max_size)
+    }
+
+    /// Move a position of the ring forward, flipping the wrap counter when it
+    /// goes past the end of the ring.
+    fn advance(&self, index: &mut Wrapping<u16>, wrap_counter: &mut bool, count: u16) {
+        let size = self.actual_size();
+        let next = u32::from(index.0) + u32::from(count);
+        if next >= u32::from(size) {
+            *index = Wrapping((next - u32::from(size)) as u16);
+            *wrap_counter = !*wrap_counter;
+        } else {
+            *index = Wrapping(next as u16);
+        }
+    }
+
+    /// Get the host address of a descriptor of the ring.
+    fn desc_host_addr(&self, index: u16) -> Result<u64> {
+        let offset = u64::from(index) * DESCRIPTOR_LEN;
+        self.addr_cache
+            .desc_table_host
+            .checked_add(offset)
+            .with_context(|| {
+                anyhow!(VirtioError::AddressOverflow(
+                    "getting a packed descriptor",
+                    self.desc_table.raw_value(),
+                    offset,
+                ))
+            })
+    }
+
+    /// Get the flags of a descriptor of the ring from guest memory.
+    fn get_desc_flags(&self, sys_mem: &Arc<AddressSpace>, index: u16) -> Result<u16> {
+        let flags_addr = self.desc_host_addr(index)? + DESC_FLAGS_POSITION;
+        sys_mem
+            .read_object_direct::<u16>(flags_addr)
+            .with_context(|| anyhow!(VirtioError::ReadObjectErr("descriptor flags", flags_addr)))
+    }
+
+    /// Get a descriptor, of the ring or of an indirect table, from guest memory.
+    fn get_desc(
+        sys_mem: &Arc<AddressSpace>,
+        desc_addr: u64,
+        cache: &mut Option<RegionCache>,
+    ) -> Result<PackedVringDesc> {
+        let desc = sys_mem
+            .read_object_direct::<PackedVringDesc>(desc_addr)
+            .with_context(|| anyhow!(VirtioError::ReadObjectErr("a descriptor", desc_addr)))?;
+        if !desc.is_valid(sys_mem, cache) {
+            return Err(anyhow!(VirtioError::QueueDescInvalid));
+        }
+        Ok(desc)
+    }
+
+    /// Return true if the descriptor at the next available position is
+    /// available.
+    fn is_next_avail(&self, sys_mem: &Arc<AddressSpace>) -> Result<bool> {
+        let flags = self.get_desc_flags(sys_mem, self.next_avail.0)?;
+        Ok(PackedVringDesc::is_avail(flags, self.avail_wrap_counter))
+    }
+
+    /// Get the event suppression structure written by the driver.
+    fn get_driver_event(&self, sys_mem: &Arc<AddressSpace>) -> Result<PackedVringEvent> {
+        // Make sure the event read from sys_mem is new.
+        fence(Ordering::SeqCst);
+        sys_mem
+            .read_object_direct::<PackedVringEvent>(self.addr_cache.avail_ring_host)
+            .with_context(|| {
+                anyhow!(VirtioError::ReadObjectErr(
+                    "driver event suppression",
+                    self.avail_ring.raw_value()
+                ))
+            })
+    }
+
+    /// Set the event suppression structure read by the driver.
+    fn set_device_event(&self, sys_mem: &Arc<AddressSpace>, event: PackedVringEvent) -> Result<()> {
+        sys_mem
+            .write_object_direct::<PackedVringEvent>(&event, self.addr_cache.used_ring_host)
+            .with_context(|| {
+                format!(
+                    "Failed to set device event suppression, device area: 0x{:X}",
+                    self.used_ring.raw_value()
+                )
+            })?;
+        // Make sure the data has been set.
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// The `off_wrap` pointing to the next available position.
+    fn avail_off_wrap(&self) -> u16 {
+        self.next_avail.0 | (u16::from(self.avail_wrap_counter) << VRING_PACKED_EVENT_WRAP_SHIFT)
+    }
+
+    /// Return true if it's required to trigger interrupt for the used vring.
+    fn used_ring_need_event(&mut self, sys_mem: &Arc<AddressSpace>, features: u64) -> bool {
+        let event = match self.get_driver_event(sys_mem) {
+            Ok(event) => event,
+            Err(ref e) => {
+                error!("Failed to get the status for notifying used vring {:?}", e);
+                return false;
+            }
+        };
+
+        let old = self.last_signal_used;
+        let new = self.next_used;
+        let valid = self.signal_used_valid;
+        self.signal_used_valid = true;
+        self.last_signal_used = new;
+
+        match event.flags {
+            VRING_PACKED_EVENT_FLAG_ENABLE => true,
+            VRING_PACKED_EVENT_FLAG_DISABLE => false,
+            VRING_PACKED_EVENT_FLAG_DESC
+                if virtio_has_feature(features, VIRTIO_F_RING_EVENT_IDX) =>
+            {
+                // The offset is relative to the lap of the device wrap counter.
+                let mut off = Wrapping(event.off_wrap & !(1 << VRING_PACKED_EVENT_WRAP_SHIFT));
+                let wrap = event.off_wrap >> VRING_PACKED_EVENT_WRAP_SHIFT != 0;
+                if wrap != self.used_wrap_counter {
+                    off -= Wrapping(self.actual_size());
+                }
+                !valid || (new - off - Wrapping(1)) < (new - old)
+            }
+            _ => true,
+        }
+    }
+
+    fn is_invalid_memory(&self, sys_mem: &Arc<AddressSpace>, actual_size: u64) -> bool {
+        if let Err(ref e) =
+            checked_offset_mem(sys_mem, self.desc_table, DESCRIPTOR_LEN * actual_size)
+        {
+            error!(
+                "descriptor ring is out of bounds: start:0x{:X} size:{} {:?}",
+                self.desc_table.raw_value(),
+                DESCRIPTOR_LEN * actual_size,
+                e
+            );
+            return true;
+        }
+        if let Err(ref e) = checked_offset_mem(sys_mem, self.avail_ring, EVENT_SUPPRESS_LEN) {
+            error!(
+                "driver area is out of bounds: start:0x{:X} {:?}",
+                self.avail_ring.raw_value(),
+                e
+            );
+            return true;
+        }
+        if let Err(ref e) = checked_offset_mem(sys_mem, self.used_ring, EVENT_SUPPRESS_LEN) {
+            error!(
+                "device area is out of bounds: start:0x{:X} {:?}",
+                self.used_ring.raw_value(),
+                e
+            );
+            return true;
+        }
+
+        if self.desc_table.0 & 0xf != 0 {
+            error!(
+                "descriptor ring: 0x{:X} is not aligned",
+                self.desc_table.raw_value()
+            );
+            true
+        } else if self.avail_ring.0 & 0x3 != 0 {
+            error!(
+                "driver area: 0x{:X} is not aligned",
+                self.avail_ring.raw_value()
+            );
+            true
+        } else if self.used_ring.0 & 0x3 != 0 {
+            error!(
+                "device area: 0x{:X} is not aligned",
+                self.used_ring.raw_value()
+            );
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Add the descriptors of an indirect table to the element.
+    fn get_indirect_element(
+        &mut self,
+        sys_mem: &Arc<AddressSpace>,
+        desc: &PackedVringDesc,
+        elem: &mut Element,
+        write_elem_count: &mut u32,
+    ) -> Result<u64> {
+        if !desc.is_valid_indirect_desc() {
+            return Err(anyhow!(VirtioError::QueueDescInvalid));
+        }
+        let table_host = sys_mem
+            .get_host_address_from_cache(desc.addr, &self.cache)
+            .unwrap_or(0);
+        if table_host == 0 {
+            bail!("Failed to get descriptor table entry host address");
+        }
+
+        // The descriptors of an indirect table are chained in order, their
+        // NEXT flags are ignored.
+        let mut total_len = 0_u64;
+        for index in 0..u64::from(desc.len) / DESCRIPTOR_LEN {
+            let desc = Self::get_desc(
+                sys_mem,
+                table_host + index * DESCRIPTOR_LEN,
+                &mut self.cache,
+            )
+            .with_context(|| format!("Failed to find indirect descriptor {}", index))?;
+            if desc.is_indirect_desc() {
+                bail!("Found two indirect descriptor elem in one request");
+            }
+            Self::add_iovec(&desc, elem, write_elem_count)?;
+            total_len += u64::from(desc.len);
+        }
+        Ok(total_len)
+    }
+
+    fn add_iovec(
+        desc: &PackedVringDesc,
+        elem: &mut Element,
+        write_elem_count: &mut u32,
+    ) -> Result<()> {
+        let iovec = ElemIovec {
+            addr: desc.addr,
+            len: desc.len,
+        };
+        if desc.write_only() {
+            elem.in_iovec.push(iovec);
+            *write_elem_count += 1;
+        } else {
+            if *write_elem_count > 0 {
+                bail!("Invalid order of the descriptor elem");
+            }
+            elem.out_iovec.push(iovec);
+        }
+        elem.desc_num += 1;
+        Ok(())
+    }
+
+    fn get_vring_element(
+        &mut self,
+        sys_mem: &Arc<AddressSpace>,
+        features: u64,
+        elem: &mut Element,
+    ) -> Result<()> {
+        let size = self.actual_size();
+        let mut index = self.next_avail;
+        let mut wrap_counter = self.avail_wrap_counter;
+        let mut slots: u16 = 0;
+        let mut write_elem_count: u32 = 0;
+        let mut desc_total_len: u64 = 0;
+
+        loop {
+            if slots >= size {
+                bail!("The element desc number exceeds max allowed");
+            }
+            // The driver makes the head available last, the rest of the
+            // chain must be available already.
+            if slots > 0 {
+                let flags = self.get_desc_flags(sys_mem, index.0)?;
+                if !PackedVringDesc::is_avail(flags, wrap_counter) {
+                    bail!("Descriptor {} of the chain is not available", index.0);
+                }
+            }
+            let desc = Self::get_desc(sys_mem, self.desc_host_addr(index.0)?, &mut self.cache)
+                .with_context(|| format!("Failed to find descriptor {}", index.0))?;
+            self.advance(&mut index, &mut wrap_counter, 1);
+            slots += 1;
+            elem.index = desc.id;
+
+            if desc.is_indirect_desc() {
+                desc_total_len +=
+                    self.get_indirect_element(sys_mem, &desc, elem, &mut write_elem_count)?;
+                break;
+            }
+            Self::add_iovec(&desc, elem, &mut write_elem_count)?;
+            desc_total_len += u64::from(desc.len);
+            if !desc.has_next() {
+                break;
+            }
+        }
+
+        if desc_total_len > DESC_CHAIN_MAX_TOTAL_LEN {
+            bail!("Find a descriptor chain longer than 4GB in total");
+        }
+        if elem.index >= size {
+            return Err(anyhow!(VirtioError::QueueIndex(elem.index, size)));
+        }
+
+        self.chain_slots[elem.index as usize] = slots;
+        self.last_chain_slots = slots;
+        self.next_avail = index;
+        self.avail_wrap_counter = wrap_counter;
+
+        // Suppress queue notification related to current processing desc chain.
+        if virtio_has_feature(features, VIRTIO_F_RING_EVENT_IDX) {
+            sys_mem
+                .write_object_direct(&self.avail_off_wrap(), self.addr_cache.used_ring_host)
+                .with_context(|| "Failed to set device event for popping avail ring")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl VringOps for PackedVring {
+    fn is_enabled(&self) -> bool {
+        self.ready
+    }
+
+    fn is_valid(&self, sys_mem: &Arc<AddressSpace>) -> bool {
+        let size = u64::from(self.actual_size());
+        if !self.ready {
+            error!("The configuration of vring is not ready\n");
+            false
+        } else if self.size > self.max_size || self.size == 0 {
+            error!(
+                "vring with invalid size:{} max size:{}",
+                self.size, self.max_size
+            );
+            false
+        } else {
+            !self.is_invalid_memory(sys_mem, size)
+        }
+    }
+
+    fn pop_avail(&mut self, sys_mem: &Arc<AddressSpace>, features: u64) -> Result<Element> {
+        let mut element = Element::new(0);
+        if !self.is_next_avail(sys_mem)? {
+            return Ok(element);
+        }
+
+        // Make sure descriptor read does not bypass the flags read.
+        fence(Ordering::Acquire);
+
+        self.get_vring_element(sys_mem, features, &mut element)
+            .with_context(|| "Failed to get vring element")?;
+
+        Ok(element)
+    }
+
+    fn push_back(&mut self) {
+        let size = self.actual_size();
+        let slots = self.last_chain_slots;
+        if self.next_avail.0 < slots {
+            self.next_avail = Wrapping(self.next_avail.0 + size - slots);
+            self.avail_wrap_counter = !self.avail_wrap_counter;
+        } else {
+            self.next_avail -= Wrapping(slots);
+        }
+        self.last_chain_slots = 0;
+    }
+
+    fn add_used(&mut self, sys_mem: &Arc<AddressSpace>, index: u16, len: u32) -> Result<()> {
+        if index >= self.actual_size() {
+            return Err(anyhow!(VirtioError::QueueIndex(index, self.size)));
+        }
+
+        let used_desc_addr = self.desc_host_addr(self.next_used.0)?;
+        let used_desc = PackedVringDesc {
+            addr: GuestAddress(0),
+            len,
+            id: index,
+            flags: 0,
+        };
+        sys_mem
+            .write_object_direct::<PackedVringDesc>(&used_desc, used_desc_addr)
+            .with_context(|| "Failed to write object for used descriptor")?;
+        // Make sure the id and len are filled before the flags mark the
+        // descriptor as used.
+        fence(Ordering::Release);
+
+        let flags = if self.used_wrap_counter {
+            VRING_PACKED_DESC_F_AVAIL | VRING_PACKED_DESC_F_USED
+        } else {
+            0
+        };
+        sys_mem
+            .write_object_direct(&flags, used_desc_addr + DESC_FLAGS_POSITION)
+            .with_context(|| "Failed to write flags of used descriptor")?;
+        // Make sure the used descriptor is exposed before notifying guest.
+        fence(Ordering::SeqCst);
+
+        // The used descriptor stands for the whole chain, the next one is
+        // written after the ring descriptors the chain took.
+        let slots = self.chain_slots[index as usize].max(1);
+        let mut next_used = self.next_used;
+        let mut wrap_counter = self.used_wrap_counter;
+        self.advance(&mut next_used, &mut wrap_counter, slots);
+        self.next_used = next_used;
+        self.used_wrap_counter = wrap_counter;
+        self.chain_slots[index as usize] = 0;
+        Ok(())
+    }
+
+    fn should_notify(&mut self, sys_mem: &Arc<AddressSpace>, features: u64) -> bool {
+        self.used_ring_need_event(sys_mem, features)
+    }
+
+    fn suppress_queue_notify(
+        &mut self,
+        sys_mem: &Arc<AddressSpace>,
+        features: u64,
+        suppress: bool,
+    ) -> Result<()> {
+        let event = if suppress {
+            PackedVringEvent {
+                off_wrap: 0,
+                flags: VRING_PACKED_EVENT_FLAG_DISABLE,
+            }
+        } else if virtio_has_feature(features, VIRTIO_F_RING_EVENT_IDX) {
+            PackedVringEvent {
+                off_wrap: self.avail_off_wrap(),
+                flags: VRING_PACKED_EVENT_FLAG_DESC,
+            }
+        } else {
+            PackedVringEvent {
+                off_wrap: 0,
+                flags: VRING_PACKED_EVENT_FLAG_ENABLE,
+            }
+        };
+        self.set_device_event(sys_mem, event)
+    }
+
+    fn actual_size(&self) -> u16 {
+        self.actual_size()
+    }
+
+    fn get_queue_config(&self) -> QueueConfig {
+        let mut config = self.queue_config;
+        config.signal_used_valid = false;
+        config
+    }
+
+    /// The packed ring has no index of the available descriptors, so only
+    /// tell whether there is at least one.
+    fn avail_ring_len(&mut self, sys_mem: &Arc<AddressSpace>) -> Result<u16> {
+        Ok(u16::from(self.is_next_avail(sys_mem)?))
+    }
+
+    /// The next available position with the wrap counter in bit 15, as the
+    /// vhost protocols expect for packed rings.
+    fn get_avail_idx(&self, _sys_mem: &Arc<AddressSpace>) -> Result<u16> {
+        Ok(self.avail_off_wrap())
+    }
+
+    fn get_cache(&self) -> &Option<RegionCache> {
+        &self.cache
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Queue, QUEUE_TYPE_PACKED_VRING};
+    use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
+
+    const SYSTEM_SPACE_SIZE: u64 = (1024 * 1024) as u64;
+    const QUEUE_SIZE: u16 = 4;
+    const DRIVER_AREA: u64 = 0x1000;
+    const DEVICE_AREA: u64 = 0x2000;
+    const BUF_BASE: u64 = 0x10000;
+
+    fn address_space_init() -> Arc<AddressSpace> {
+        let root = Region::init_container_region(1 << 36);
+        let sys_space = AddressSpace::new(root).unwrap();
+        let host_mmap = Arc::new(
+            HostMemMapping::new(
+                GuestAddress(0),
+                None,
+                SYSTEM_SPACE_SIZE,
+                None,
+                false,
+                false,
+                false,
+            )
+            .unwrap(),
+        );
+        sys_space
+            .root()
+            .add_subregion(
+                Region::init_ram_region(host_mmap.clone()),
+                host_mmap.start_address().raw_value(),
+            )
+            .unwrap();
+        sys_space
+    }
+
+    fn queue_config_init(sys_space: &Arc<AddressSpace>) -> QueueConfig {
+        let mut queue_config = QueueConfig::new(QUEUE_SIZE);
+        queue_config.desc_table = GuestAddress(0);
+        queue_config.avail_ring = GuestAddress(DRIVER_AREA);
+        queue_config.used_ring = GuestAddress(DEVICE_AREA);
+        queue_config.addr_cache.desc_table_host =
+            sys_space.get_host_address(queue_config.desc_table).unwrap();
+        queue_config.addr_cache.avail_ring_host =
+            sys_space.get_host_address(queue_config.avail_ring).unwrap();
+        queue_config.addr_cache.used_ring_host =
+            sys_space.get_host_address(queue_config.used_ring).unwrap();
+        queue_config.ready = true;
+        queue_config
+    }
+
+    /// Make a descriptor available the way the driver does in the lap of
+    /// `wrap_counter`.
+    fn set_avail_desc(
+        sys_mem: &Arc<AddressSpace>,
+        index: u16,
+        len: u32,
+        id: u16,
+        flags: u16,
+        wrap_counter: bool,
+    ) {
+        let flags = if wrap_counter {
+            flags | VRING_PACKED_DESC_F_AVAIL
+        } else {
+            flags | VRING_PACKED_DESC_F_USED
+        };
+        let desc = PackedVringDesc {
+            addr: GuestAddress(BUF_BASE + u64::from(index) * 0x1000),
+            len,
+            id,
+            flags,
+        };
+        sys_mem
+            .write_object(&desc, GuestAddress(u64::from(index) * DESCRIPTOR_LEN))
+            .unwrap();
+    }
+
+    fn get_desc(sys_mem: &Arc<AddressSpace>, index: u16) -> PackedVringDesc {
+        sys_mem
+            .read_object::<PackedVringDesc>(GuestAddress(u64::from(index) * DESCRIPTOR_LEN))
+            .unwrap()
+    }
+
+    fn set_driver_event(sys_mem: &Arc<AddressSpace>, off_wrap: u16, flags: u16) {
+        let event = PackedVringEvent { off_wrap, flags };
+        sys_mem
+            .write_object(&event, GuestAddress(DRIVER_AREA))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_valid_queue() {
+        let sys_space = address_space_init();
+        let mut queue_config = queue_config_init(&sys_space);
+        let queue = Queue::new(queue_config, QUEUE_TYPE_PACKED_VRING).unwrap();
+        assert!(queue.is_valid(&sys_space));
+
+        // The size of a packed ring doesn't have to be a power of 2.
+        queue_config.size = 3;
+        let queue = Queue::new(queue_config, QUEUE_TYPE_PACKED_VRING).unwrap();
+        assert!(queue.is_valid(&sys_space));
+        queue_config.size = QUEUE_SIZE;
+
+        // it is invalid when the descriptor ring is not aligned
+        queue_config.desc_table = GuestAddress(0x8);
+        let queue = Queue::new(queue_config, QUEUE_TYPE_PACKED_VRING).unwrap();
+        assert!(!queue.is_valid(&sys_space));
+        queue_config.desc_table = GuestAddress(0);
+
+        // it is invalid when the device area is out of bound
+        queue_config.used_ring = GuestAddress(SYSTEM_SPACE_SIZE - 2);
+        let queue = Queue::new(queue_config, QUEUE_TYPE_PACKED_VRING).unwrap();
+        assert!(!queue.is_valid(&sys_space));
+    }
+
+    #[test]
+    fn test_pop_avail_and_add_used() {
+        let sys_space = address_space_init();
+        let mut vring = PackedVring::new(queue_config_init(&sys_space));
+
+        // Nothing is available yet.
+        let elem = vring.pop_avail(&sys_space, 0).unwrap();
+        assert_eq!(elem.desc_num, 0);
+
+        // A chain of two descriptors, the buffer id is in the last one.
+        set_avail_desc(&sys_space, 0, 0x100, 0, VIRTQ_DESC_F_NEXT, true);
+        set_avail_desc(&sys_space, 1, 0x200, 1, VIRTQ_DESC_F_WRITE, true);
+        assert_eq!(vring.avail_ring_len(&sys_space).unwrap(), 1);
+        let elem = vring.pop_avail(&sys_space, 0).unwrap();
+        assert_eq!(elem.index, 1);
+        assert_eq!(elem.desc_num, 2);
+        assert_eq!(elem.out_iovec.len(), 1);
+        assert_eq!(elem.out_iovec[0].len, 0x100);
+        assert_eq!(elem.in_iovec.len(), 1);
+        assert_eq!(elem.in_iovec[0].addr, GuestAddress(BUF_BASE + 0x1000));
+        assert_eq!(vring.next_avail.0, 2);
+        assert_eq!(vring.avail_ring_len(&sys_space).unwrap(), 0);
+
+        // The popped chain can be given back.
+        vring.push_back();
+        assert_eq!(vring.next_avail.0, 0);
+        let elem = vring.pop_avail(&sys_space, 0).unwrap();
+        assert_eq!(elem.index, 1);
+
+        // The used descriptor overwrites the head of the chain.
+        vring.add_used(&sys_space, 1, 0x200).unwrap();
+        let used = get_desc(&sys_space, 0);
+        assert_eq!(used.id, 1);
+        assert_eq!(used.len, 0x200);
+        assert_eq!(
+            used.flags,
+            VRING_PACKED_DESC_F_AVAIL | VRING_PACKED_DESC_F_USED
+        );
+        assert_eq!(vring.next_used.0, 2);
+        // A used descriptor isn't available any more.
+        assert!(!PackedVringDesc::is_avail(used.flags, true));
+
+        // The buffer id must be in range.
+        assert!(vring.add_used(&sys_space, QUEUE_SIZE, 0).is_err());
+    }
+
+    #[test]
+    fn test_wrap_around() {
+        let sys_space = address_space_init();
+        let mut vring = PackedVring::new(queue_config_init(&sys_space));
+
+        // A chain of three descriptors, then a single one filling the ring.
+        set_avail_desc(&sys_space, 0, 0x100, 0, VIRTQ_DESC_F_NEXT, true);
+        set_avail_desc(&sys_space, 1, 0x100, 0, VIRTQ_DESC_F_NEXT, true);
+        set_avail_desc(&sys_space, 2, 0x100, 0, 0, true);
+        set_avail_desc(&sys_space, 3, 0x100, 1, 0, true);
+        assert_eq!(vring.pop_avail(&sys_space, 0).unwrap().index, 0);
+        assert_eq!(vring.pop_avail(&sys_space, 0).unwrap().index, 1);
+        assert_eq!(vring.next_avail.0, 0);
+        assert!(!vring.avail_wrap_counter);
+
+        // Descriptors of the previous lap are not available.
+        assert_eq!(vring.pop_avail(&sys_space, 0).unwrap().desc_num, 0);
+
+        // Buffers may be used out of order.
+        vring.add_used(&sys_space, 1, 0).unwrap();
+        assert_eq!(vring.next_used.0, 1);
+        vring.add_used(&sys_space, 0, 0).unwrap();
+        assert_eq!(vring.next_used.0, 0);
+        assert!(!vring.used_wrap_counter);
+
+        // The driver makes a descriptor available in the second lap.
+        set_avail_desc(&sys_space, 0, 0x100, 2, 0, false);
+        assert_eq!(vring.get_avail_idx(&sys_space).unwrap(), 0);
+        let elem = vring.pop_avail(&sys_space, 0).unwrap();
+        assert_eq!(elem.index, 2);
+        assert_eq!(elem.desc_num, 1);
+
+        // Used descriptors of the second lap have both flags clear.
+        vring.add_used(&sys_space, 2, 0).unwrap();
+        assert_eq!(get_desc(&sys_space, 0).flags, 0);
+    }
+
+    #[test]
+    fn test_indirect_desc() {
+        let sys_space = address_space_init();
+        let mut vring = PackedVring::new(queue_config_init(&sys_space));
+
+        // An indirect table of three descriptors.
+        let table = GuestAddress(0x8000);
+        for i in 0..3_u64 {
+            let desc = PackedVringDesc {
+                addr: GuestAddress(BUF_BASE + i * 0x1000),
+                len: 0x100,
+                id: 0,
+                flags: if i == 0 { 0 } else { VIRTQ_DESC_F_WRITE },
+            };
+            sys_space
+                .write_object(&desc, GuestAddress(table.0 + i * DESCRIPTOR_LEN))
+                .unwrap();
+        }
+        let desc = PackedVringDesc {
+            addr: table,
+            len: 3 * DESCRIPTOR_LEN as u32,
+            id: 3,
+            flags: VIRTQ_DESC_F_INDIRECT | VRING_PACKED_DESC_F_AVAIL,
+        };
+        sys_space.write_object(&desc, GuestAddress(0)).unwrap();
+
+        let elem = vring.pop_avail(&sys_space, 0).unwrap();
+        assert_eq!(elem.index, 3);
+        assert_eq!(elem.desc_num, 3);
+        assert_eq!(elem.out_iovec.len(), 1);
+        assert_eq!(elem.in_iovec.len(), 2);
+        // The indirect table takes a single descriptor of the ring.
+        assert_eq!(vring.next_avail.0, 1);
+        vring.add_used(&sys_space, 3, 0x200).unwrap();
+        assert_eq!(vring.next_used.0, 1);
+
+        // it is invalid when the length isn't a multiple of the descriptor size
+        let desc = PackedVringDesc {
+            addr: table,
+            len: 0x18,
+            id: 0,
+            flags: VIRTQ_DESC_F_INDIRECT | VRING_PACKED_DESC_F_AVAIL,
+        };
+        sys_space
+            .write_object(&desc, GuestAddress(DESCRIPTOR_LEN))
+            .unwrap();
+        assert!(vring.pop_avail(&sys_space, 0).is_err());
+    }
+
+    #[test]
+    fn test_should_notify() {
+        let sys_space = address_space_init();
+        let mut vring = PackedVring::new(queue_config_init(&sys_space));
+        let features = 1_u64 << VIRTIO_F_RING_EVENT_IDX;
+
+        set_driver_event(&sys_space, 0, VRING_PACKED_EVENT_FLAG_ENABLE);
+        assert!(vring.should_notify(&sys_space, 0));
+        set_driver_event(&sys_space, 0, VRING_PACKED_EVENT_FLAG_DISABLE);
+        assert!(!vring.should_notify(&sys_space, 0));
+
+        // Only notify once the used descriptor at the offset is written.
+        for i in 0..3 {
+            set_avail_desc(&sys_space, i, 0x100, i, 0, true);
+        }
+        set_driver_event(&sys_space, 1 | 1 << 15, VRING_PACKED_EVENT_FLAG_DESC);
+        for i in 0..3 {
+            vring.pop_avail(&sys_space, features).unwrap();
+            vring.add_used(&sys_space, i, 0).unwrap();
+            assert_eq!(vring.should_notify(&sys_space, features), i == 1);
+            if i == 1 {
+                // Wait for the offset in the next lap.
+                set_driver_event(&sys_space, 1, VRING_PACKED_EVENT_FLAG_DESC);
+            }
+        }
+
+        // The device area tells the driver when to notify.
+        vring
+            .suppress_queue_notify(&sys_space, features, true)
+            .unwrap();
+        let event = sys_space
+            .read_object::<PackedVringEvent>(GuestAddress(DEVICE_AREA))
+            .unwrap();
+        assert_eq!(event.flags, VRING_PACKED_EVENT_FLAG_DISABLE);
+        vring
+            .suppress_queue_notify(&sys_space, features, false)
+            .unwrap();
+        let event = sys_space
+            .read_object::<PackedVringEvent>(GuestAddress(DEVICE_AREA))
+            .unwrap();
+        assert_eq!(event.flags, VRING_PACKED_EVENT_FLAG_DESC);
+        assert_eq!(event.off_wrap, 3 | 1 << 15);
+    }
+}
@@ -80,13 +80,17 @@ pub struct QueueConfig {
     /// Interrupt vector index of the queue for msix
     pub vector: u16,
     /// The next index which can be popped in the available vring.
-    next_avail: Wrapping<u16>,
+    pub(super) next_avail: Wrapping<u16>,
     /// The next index which can be pushed in the used vring.
-    next_used: Wrapping<u16>,
+    pub(super) next_used: Wrapping<u16>,
     /// The index of last descriptor used which has triggered interrupt.
-    last_signal_used: Wrapping<u16>,
+    pub(super) last_signal_used: Wrapping<u16>,
     /// The last_signal_used is valid or not.
-    signal_used_valid: bool,
+    pub(super) signal_used_valid: bool,
+    /// Wrap counter of the driver for the packed vring.
+    pub(super) avail_wrap_counter: bool,
+    /// Wrap counter of the device for the packed vring.
+    pub(super) used_wrap_counter: bool,
 }
 
 impl QueueConfig {
@@ -111,6 +115,8 @@ impl QueueConfig {
             next_used: Wrapping(0),
             last_signal_used: Wrapping(0),
             signal_used_valid: false,
+            avail_wrap_counter: true,
+            used_wrap_counter: true,
         }
     }
 
@@ -864,7 +870,7 @@ impl VringOps for SplitVring {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Queue, QUEUE_TYPE_PACKED_VRING, QUEUE_TYPE_SPLIT_VRING};
+    use crate::{Queue, QUEUE_TYPE_SPLIT_VRING};
     use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
 
     fn address_space_init() -> Arc<AddressSpace> {
@@ -1058,8 +1064,6 @@ mod tests {
         // failed when the type of queue is invalid
         let queue = Queue::new(queue_config, 0);
         assert!(queue.is_err());
-        let queue = Queue::new(queue_config, QUEUE_TYPE_PACKED_VRING);
-        assert!(queue.is_err());
 
         // it is valid
         queue_config.desc_table = GuestAddress(0);